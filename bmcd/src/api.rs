@@ -11,9 +11,75 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+pub mod activity;
+pub mod batch;
+pub mod cluster;
+pub mod configuration;
+pub mod console_shares;
+pub mod diagnostics;
+pub mod discovery;
+pub mod enrollment;
+pub mod expansion;
+pub mod factory_reset;
+pub mod failover;
+#[cfg(feature = "fault-injection")]
+pub mod fault_injection;
+pub mod firmware;
+pub mod flash_history;
+pub mod graphql;
+pub mod http_policy;
+pub mod i2c;
+pub mod idempotency;
+pub mod identify;
+pub mod identity;
+pub mod image_cache;
+pub mod image_inspection;
+pub mod image_sharing;
 pub mod into_legacy_response;
+pub mod inventory;
+pub mod jobs;
+pub mod kv_store;
+pub mod kvm;
 pub mod legacy;
-use self::into_legacy_response::{LegacyResponse, LegacyResult};
+pub mod lights_out;
+pub mod logging;
+pub mod messages;
+pub mod metrics;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod nbd;
+pub mod netboot;
+pub mod network;
+pub mod node_agent;
+pub mod node_backup;
+pub mod node_pins;
+pub mod node_state;
+pub mod pipelines;
+pub mod plugins;
+pub mod power_presets;
+pub mod power_supply;
+pub mod provisioning;
+pub mod readiness;
+pub mod resources;
+pub mod retention;
+pub mod rtc;
+pub mod rules;
+pub mod safe_mode;
+pub mod schemas;
+pub mod scripting;
+pub mod sd_card;
+pub mod selftest;
+pub mod shutdown;
+pub mod storage_health;
+pub mod storage_manager;
+pub mod time;
+pub mod traces;
+pub mod updates;
+pub mod usb_console;
+pub mod web_ui;
+pub mod wifi;
+use self::into_legacy_response::LegacyResult;
+use crate::error::BmcError;
 use crate::hal::NodeId;
 use actix_web::web;
 use std::str::FromStr;
@@ -22,19 +88,15 @@ pub fn get_node_param(
     query: &web::Query<std::collections::HashMap<String, String>>,
 ) -> LegacyResult<NodeId> {
     let Some(node_str) = query.get("node") else {
-        return Err(LegacyResponse::bad_request("Missing `node` parameter"));
+        return Err(BmcError::invalid_parameter("node", "missing").into());
     };
 
     let Ok(node_num) = i32::from_str(node_str) else {
-        return Err(LegacyResponse::bad_request(
-            "Parameter `node` is not a number",
-        ));
+        return Err(BmcError::invalid_parameter("node", "not a number").into());
     };
 
     let Ok(node) = node_num.try_into() else {
-        return Err(LegacyResponse::bad_request(
-            "Parameter `node` is out of range 0..3 of node IDs",
-        ));
+        return Err(BmcError::invalid_parameter("node", "out of range 0..3 of node IDs").into());
     };
 
     Ok(node)
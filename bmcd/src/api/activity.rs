@@ -0,0 +1,36 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Route that reports whether powered nodes are running, as inferred from
+//! their current draw.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::activity::ActivityMonitor;
+use crate::app::capabilities::{Capabilities, Capability};
+use actix_web::{get, web};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_activity);
+}
+
+/// Lists the nodes that have a current sensor configured.
+#[get("/activity")]
+async fn get_activity(
+    monitor: web::Data<ActivityMonitor>,
+    capabilities: web::Data<Capabilities>,
+) -> LegacyResponse {
+    capabilities
+        .ensure(Capability::CurrentSensing)
+        .map(|_| json!(monitor.status()))
+        .into()
+}
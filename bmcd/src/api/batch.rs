@@ -0,0 +1,34 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Route to run a batch of operations, see [`crate::app::batch`].
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::batch::Batch;
+use crate::app::bmc_application::BmcApplication;
+use actix_web::{post, web};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(run_batch);
+}
+
+/// Answers `200 OK` with the result of every operation, also when some of
+/// them failed. The batch is rejected as a whole when it does not validate.
+#[post("/batch")]
+async fn run_batch(bmc: web::Data<BmcApplication>, batch: web::Json<Batch>) -> LegacyResponse {
+    if let Err(e) = batch.validate(bmc.board().node_count) {
+        return e.into();
+    }
+    let results = batch.run(&bmc).await;
+    json!({ "results": results }).into()
+}
@@ -0,0 +1,195 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to manage the peers of the `cluster` configuration from this
+//! board. `/cluster/peers/{peer}/..` proxies the API of a peer; websockets
+//! and request bodies over the default payload limit are not proxied, so
+//! flash images have to be sent to the peer itself.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::cluster::{Cluster, LOCAL};
+use crate::app::inventory::get_inventory;
+use crate::app::node_agent::NodeAgents;
+use crate::error::BmcError;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use reqwest::header::HeaderValue;
+use reqwest::Method;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_peers)
+        .service(combined_inventory)
+        .service(bulk_power)
+        .service(web::resource("/cluster/peers/{peer}/{path:.*}").to(proxy));
+}
+
+#[derive(Debug, Deserialize)]
+struct BoardsQuery {
+    /// comma separated board names, all boards when omitted
+    boards: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PowerRequest {
+    /// desired power state by node number, counting from 1
+    nodes: BTreeMap<u8, bool>,
+    /// boards to apply the states to, all boards when omitted
+    boards: Option<Vec<String>>,
+}
+
+/// Splits `boards` into whether the local board is included and the peers.
+async fn select_boards(cluster: &Cluster, boards: Option<Vec<String>>) -> (bool, Vec<String>) {
+    match boards {
+        Some(mut boards) => {
+            let local = boards.iter().any(|b| b == LOCAL);
+            boards.retain(|b| b != LOCAL);
+            (local, boards)
+        }
+        None => (true, cluster.peer_names().await),
+    }
+}
+
+fn board_result(result: anyhow::Result<Value>) -> Value {
+    match result {
+        Ok(result) => json!({ "result": result }),
+        Err(e) => json!({ "error": format!("{:#}", e) }),
+    }
+}
+
+#[get("/cluster")]
+async fn list_peers(cluster: web::Data<Cluster>) -> LegacyResponse {
+    json!(cluster.status().await).into()
+}
+
+#[get("/cluster/inventory")]
+async fn combined_inventory(
+    cluster: web::Data<Cluster>,
+    bmc: web::Data<BmcApplication>,
+    agents: web::Data<NodeAgents>,
+    query: web::Query<BoardsQuery>,
+) -> LegacyResponse {
+    let boards = query
+        .into_inner()
+        .boards
+        .map(|b| b.split(',').map(str::to_string).collect());
+    let (local, peers) = select_boards(&cluster, boards).await;
+
+    let mut combined = Map::new();
+    if local {
+        combined.insert(
+            LOCAL.to_string(),
+            board_result(Ok(json!(get_inventory(&bmc, &agents).await))),
+        );
+    }
+    for (name, result) in cluster
+        .call_all(Some(&peers), Method::GET, "/inventory")
+        .await
+    {
+        combined.insert(name, board_result(result));
+    }
+    Value::Object(combined).into()
+}
+
+/// Sets the power of the same nodes on several boards. A board that fails
+/// does not stop the others; the result of every board is returned.
+#[post("/cluster/power")]
+async fn bulk_power(
+    cluster: web::Data<Cluster>,
+    bmc: web::Data<BmcApplication>,
+    request: web::Json<PowerRequest>,
+) -> LegacyResponse {
+    let request = request.into_inner();
+    let mut states = 0u8;
+    let mut mask = 0u8;
+    let mut query = "?opt=set&type=power".to_string();
+    for (node, on) in &request.nodes {
+        if !(1..=4).contains(node) {
+            return BmcError::invalid_parameter("nodes", format!("node {} does not exist", node))
+                .into();
+        }
+        let bit = 1 << (node - 1);
+        mask |= bit;
+        if *on {
+            states |= bit;
+        }
+        query.push_str(&format!("&node{}={}", node, u8::from(*on)));
+    }
+    if mask == 0 {
+        return BmcError::invalid_parameter("nodes", "no nodes given").into();
+    }
+
+    let (local, peers) = select_boards(&cluster, request.boards).await;
+    let mut combined = Map::new();
+    if local {
+        let result = bmc.activate_slot(states, mask).await;
+        combined.insert(
+            LOCAL.to_string(),
+            board_result(result.map(|_| Value::from("ok"))),
+        );
+    }
+    for (name, result) in cluster.call_all(Some(&peers), Method::GET, &query).await {
+        combined.insert(name, board_result(result));
+    }
+    Value::Object(combined).into()
+}
+
+async fn proxy(
+    cluster: web::Data<Cluster>,
+    request: HttpRequest,
+    route: web::Path<(String, String)>,
+    body: web::Bytes,
+) -> HttpResponse {
+    let (peer, path) = route.into_inner();
+    let mut path = if path.is_empty() {
+        path
+    } else {
+        format!("/{}", path)
+    };
+    if !request.query_string().is_empty() {
+        path = format!("{}?{}", path, request.query_string());
+    }
+    let Ok(method) = Method::from_bytes(request.method().as_str().as_bytes()) else {
+        return LegacyResponse::bad_request("unsupported method").into();
+    };
+    let content_type = request
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|v| HeaderValue::from_bytes(v.as_bytes()).ok());
+
+    match cluster
+        .forward(&peer, method, &path, content_type, body)
+        .await
+    {
+        Ok(response) => {
+            let mut builder = HttpResponse::build(
+                actix_web::http::StatusCode::from_u16(response.status().as_u16())
+                    .unwrap_or(actix_web::http::StatusCode::BAD_GATEWAY),
+            );
+            if let Some(content_type) = response.headers().get(reqwest::header::CONTENT_TYPE) {
+                builder.insert_header((
+                    actix_web::http::header::CONTENT_TYPE,
+                    content_type.as_bytes(),
+                ));
+            }
+            builder.streaming(response.bytes_stream())
+        }
+        Err(e) if e.downcast_ref::<BmcError>().is_some() => LegacyResponse::from(e).into(),
+        Err(e) => LegacyResponse::Error(
+            actix_web::http::StatusCode::BAD_GATEWAY,
+            format!("{:#}", e).into(),
+        )
+        .into(),
+    }
+}
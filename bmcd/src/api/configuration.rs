@@ -0,0 +1,117 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to inspect and reload the configuration file of bmcd.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::config_archive::ConfigArchive;
+use crate::app::config_service::ConfigService;
+use crate::authentication::roles::role;
+use crate::utils::restart_daemon;
+use actix_web::http::{header, StatusCode};
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use futures::StreamExt;
+use serde_json::json;
+
+/// Upper limit of the size of an archive passed to `/config/import`.
+const MAX_ARCHIVE_SIZE: usize = 16 * 1024 * 1024;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(config_status)
+        .service(reload_config)
+        .service(export_config)
+        .service(import_config);
+}
+
+/// Returns the state of the last (re)load of the configuration file,
+/// including validation errors.
+#[get("/config")]
+async fn config_status(config: web::Data<ConfigService>) -> LegacyResponse {
+    serde_json::to_value(config.status()).into()
+}
+
+#[post("/config/reload")]
+async fn reload_config(request: HttpRequest, config: web::Data<ConfigService>) -> LegacyResponse {
+    if let Err(e) = operators_only(&request) {
+        return e;
+    }
+    match config.reload().await {
+        Ok(status) => serde_json::to_value(status).into(),
+        Err(e) => (StatusCode::UNPROCESSABLE_ENTITY, format!("{:#}", e)).into(),
+    }
+}
+
+/// Download a signed archive of all settings of this board.
+#[get("/config/export")]
+async fn export_config(request: HttpRequest, config: web::Data<ConfigService>) -> impl Responder {
+    if let Err(e) = operators_only(&request) {
+        return e.into();
+    }
+    let archive = ConfigArchive::new(&config.status().path, &config.current());
+    match archive.export().await {
+        Ok(bytes) => {
+            let now = chrono::Local::now();
+            let content_disposition = format!(
+                r#"attachment; filename="bmcd-config-{}.tar.gz""#,
+                now.format("%d-%m-%Y")
+            );
+            HttpResponse::Ok()
+                .content_type("application/gzip")
+                .insert_header((header::CONTENT_DISPOSITION, content_disposition))
+                .body(bytes)
+        }
+        Err(e) => LegacyResponse::from(e.context("export configuration")).into(),
+    }
+}
+
+/// Restores the settings of an archive created by `/config/export`. bmcd
+/// restarts after a successful import, so that all settings take effect.
+#[post("/config/import")]
+async fn import_config(
+    request: HttpRequest,
+    config: web::Data<ConfigService>,
+    mut payload: web::Payload,
+) -> LegacyResponse {
+    if let Err(e) = operators_only(&request) {
+        return e;
+    }
+    let mut buffer = Vec::new();
+    while let Some(chunk) = payload.next().await {
+        let Ok(chunk) = chunk else {
+            return LegacyResponse::bad_request("error receiving archive");
+        };
+        if buffer.len() + chunk.len() > MAX_ARCHIVE_SIZE {
+            return (StatusCode::PAYLOAD_TOO_LARGE, "archive too large").into();
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+
+    let archive = ConfigArchive::new(&config.status().path, &config.current());
+    match archive.import(&buffer).await {
+        Ok(restored) => {
+            restart_daemon();
+            json!({ "restored": restored }).into()
+        }
+        Err(e) => (StatusCode::UNPROCESSABLE_ENTITY, format!("{:#}", e)).into(),
+    }
+}
+
+fn operators_only(request: &HttpRequest) -> Result<(), LegacyResponse> {
+    if role(request).can_manage_config() {
+        Ok(())
+    } else {
+        Err(LegacyResponse::Error(
+            StatusCode::FORBIDDEN,
+            "only operators manage the configuration".into(),
+        ))
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes for operators to share the console of a node through a link, and
+//! the routes behind the links, which are served outside of the
+//! authenticated API. Nodes are numbered from 1.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::config_service::ConfigService;
+use crate::app::console_shares::{ConsoleShares, ShareError, SharedConsole, SHARE_PATH};
+use crate::authentication::roles::{role, user_name};
+use crate::error::{BmcError, ErrorCode};
+use crate::hal::NodeId;
+use crate::serial_service::serial::SerialConnections;
+use crate::serial_service::{console_log, console_websocket};
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_shares)
+        .service(create_share)
+        .service(revoke_share);
+}
+
+/// Routes of the links, where the token takes the place of a user.
+pub fn shared_config(
+    cfg: &mut web::ServiceConfig,
+    shares: web::Data<ConsoleShares>,
+    config: web::Data<ConfigService>,
+    serials: web::Data<SerialConnections>,
+) {
+    cfg.service(
+        web::scope(SHARE_PATH)
+            .app_data(shares)
+            .app_data(config)
+            .app_data(serials)
+            .route("/{token}", web::get().to(shared_console))
+            .route("/{token}/log", web::get().to(shared_log)),
+    );
+}
+
+impl ErrorCode for ShareError {
+    fn code(&self) -> &'static str {
+        match self {
+            ShareError::Disabled => "disabled",
+            ShareError::NotFound(_) => "not_found",
+            ShareError::InvalidToken => "invalid_token",
+            ShareError::TooManyShares(_) => "limit_reached",
+            ShareError::InvalidTtl(_) => "invalid_parameter",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            ShareError::Disabled => StatusCode::FORBIDDEN,
+            ShareError::NotFound(_) | ShareError::InvalidToken => StatusCode::NOT_FOUND,
+            ShareError::TooManyShares(_) => StatusCode::TOO_MANY_REQUESTS,
+            ShareError::InvalidTtl(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn details(&self) -> Value {
+        match self {
+            ShareError::Disabled => json!({ "feature": "console_sharing" }),
+            ShareError::NotFound(id) => json!({ "name": id }),
+            ShareError::InvalidToken => Value::Null,
+            ShareError::TooManyShares(limit) => json!({ "limit": limit }),
+            ShareError::InvalidTtl(max) => json!({ "parameter": "ttl", "max": max }),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ShareRequest {
+    /// seconds until the link expires, defaults to `console_sharing.default_ttl`
+    ttl: Option<u64>,
+}
+
+fn operators_only(request: &HttpRequest) -> Result<(), LegacyResponse> {
+    if role(request).can_share_console() {
+        Ok(())
+    } else {
+        Err(LegacyResponse::Error(
+            StatusCode::FORBIDDEN,
+            "only operators manage console shares".into(),
+        ))
+    }
+}
+
+#[get("/console/shares")]
+async fn list_shares(request: HttpRequest, shares: web::Data<ConsoleShares>) -> LegacyResponse {
+    if let Err(e) = operators_only(&request) {
+        return e;
+    }
+    json!(shares.list()).into()
+}
+
+/// Answers the token of the link, which is not shown again.
+#[post("/nodes/{node}/console/shares")]
+async fn create_share(
+    request: HttpRequest,
+    shares: web::Data<ConsoleShares>,
+    config: web::Data<ConfigService>,
+    node: web::Path<u8>,
+    body: Option<web::Json<ShareRequest>>,
+) -> LegacyResponse {
+    if let Err(e) = operators_only(&request) {
+        return e;
+    }
+    let Some(node) = node.checked_sub(1).and_then(|n| NodeId::try_from(n).ok()) else {
+        return BmcError::invalid_parameter("node", "must be 1 to 4").into();
+    };
+    let ttl = body.and_then(|b| b.ttl).map(Duration::from_secs);
+    shares
+        .create(
+            &config.current().console_sharing,
+            node,
+            ttl,
+            user_name(&request),
+        )
+        .map(|share| json!(share))
+        .into()
+}
+
+#[delete("/console/shares/{id}")]
+async fn revoke_share(
+    request: HttpRequest,
+    shares: web::Data<ConsoleShares>,
+    id: web::Path<String>,
+) -> LegacyResponse {
+    if let Err(e) = operators_only(&request) {
+        return e;
+    }
+    shares.revoke(&id).into()
+}
+
+/// Read-only websocket of the console, closed when the link expires or is
+/// revoked.
+async fn shared_console(
+    request: HttpRequest,
+    stream: web::Payload,
+    shares: web::Data<ConsoleShares>,
+    config: web::Data<ConfigService>,
+    serials: web::Data<SerialConnections>,
+    token: web::Path<String>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let console = match authorize(&shares, &config, &token) {
+        Ok(console) => console,
+        Err(e) => return Ok(e.into()),
+    };
+    let end = async move {
+        tokio::select! {
+            _ = console.revoked.cancelled() => {}
+            _ = tokio::time::sleep(console.remaining) => {}
+        }
+    };
+    console_websocket(&request, stream, &serials, console.node, true, end).await
+}
+
+/// Console output as JSON lines, like `/api/bmc/serial/log`.
+async fn shared_log(
+    shares: web::Data<ConsoleShares>,
+    config: web::Data<ConfigService>,
+    serials: web::Data<SerialConnections>,
+    token: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+) -> HttpResponse {
+    let log = authorize(&shares, &config, &token)
+        .and_then(|console| console_log(&serials, console.node, &query));
+    match log {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("application/x-ndjson")
+            .insert_header(("Cache-Control", "no-store"))
+            .body(body),
+        Err(e) => e.into(),
+    }
+}
+
+fn authorize(
+    shares: &ConsoleShares,
+    config: &ConfigService,
+    token: &str,
+) -> Result<SharedConsole, LegacyResponse> {
+    // turning sharing off also closes the existing links
+    if !config.current().console_sharing.enabled {
+        return Err(ShareError::Disabled.into());
+    }
+    Ok(shares.authorize(token)?)
+}
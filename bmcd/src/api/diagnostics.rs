@@ -0,0 +1,62 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to download a diagnostics bundle for bug reports and to read the
+//! report of the last crash.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::crash_report::CrashReporter;
+use crate::app::diagnostics::create_diagnostics_bundle;
+use crate::app::notifier::Notifier;
+use actix_web::http::{header, StatusCode};
+use actix_web::{delete, get, web, HttpResponse, Responder};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(diagnostics)
+        .service(last_crash)
+        .service(clear_last_crash);
+}
+
+/// Tarball with logs, kernel messages, hardware state, persistency metadata,
+/// recent events, the last crash report and version information.
+#[get("/diagnostics")]
+async fn diagnostics(notifier: web::Data<Notifier>) -> impl Responder {
+    match create_diagnostics_bundle(&notifier).await {
+        Ok(bundle) => {
+            let now = chrono::Local::now();
+            let content_disposition = format!(
+                r#"attachment; filename="bmcd-diagnostics-{}.tar.gz""#,
+                now.format("%d-%m-%Y-%H%M%S")
+            );
+            HttpResponse::Ok()
+                .content_type("application/gzip")
+                .insert_header((header::CONTENT_DISPOSITION, content_disposition))
+                .body(bundle)
+        }
+        Err(e) => LegacyResponse::from(e.context("create diagnostics bundle")).into(),
+    }
+}
+
+#[get("/about/last-crash")]
+async fn last_crash(reporter: web::Data<CrashReporter>) -> LegacyResponse {
+    match reporter.last().await {
+        Ok(Some(report)) => json!(report).into(),
+        Ok(None) => LegacyResponse::Error(StatusCode::NOT_FOUND, "no crash was recorded".into()),
+        Err(e) => e.into(),
+    }
+}
+
+#[delete("/about/last-crash")]
+async fn clear_last_crash(reporter: web::Data<CrashReporter>) -> LegacyResponse {
+    reporter.clear().await.into()
+}
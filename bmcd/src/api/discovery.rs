@@ -11,12 +11,17 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-mod pin_controller;
-mod power_controller;
-mod serial;
-pub mod usbboot;
+//! Routes that expose other bmcd instances found on the network via mDNS.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::mdns::Mdns;
+use actix_web::{get, web};
+use serde_json::json;
 
-pub use pin_controller::*;
-pub use power_controller::*;
-pub use serial::*;
-pub use usbboot as usb;
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_discovered);
+}
+
+#[get("/discovery")]
+async fn get_discovered(mdns: web::Data<Mdns>) -> LegacyResponse {
+    json!(mdns.peers()).into()
+}
@@ -0,0 +1,27 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Status of the enrollment with a management server.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::enrollment::Enrollment;
+use actix_web::{get, web};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_enrollment);
+}
+
+#[get("/enrollment")]
+async fn get_enrollment(enrollment: web::Data<Enrollment>) -> LegacyResponse {
+    json!(enrollment.status()).into()
+}
@@ -0,0 +1,81 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to list the boards on the expansion connector and operate them
+//! through their drivers.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::hal::expansion::Expansions;
+use actix_web::http::StatusCode;
+use actix_web::{get, put, web};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_expansions)
+        .service(get_expansion)
+        .service(set_control);
+}
+
+#[get("/expansion")]
+async fn list_expansions(expansions: web::Data<Expansions>) -> LegacyResponse {
+    let modules: Vec<_> = expansions.modules().iter().map(|m| m.info()).collect();
+    json!(modules).into()
+}
+
+#[get("/expansion/{id}")]
+async fn get_expansion(expansions: web::Data<Expansions>, id: web::Path<String>) -> LegacyResponse {
+    let Some(module) = expansions.get(&id) else {
+        return not_found(&id);
+    };
+    let driver = match module.driver() {
+        Ok(driver) => driver,
+        Err(e) => return LegacyResponse::Error(StatusCode::CONFLICT, format!("{:#}", e).into()),
+    };
+    match driver.status().await {
+        Ok(status) => json!({ "info": module.info(), "status": status }).into(),
+        Err(e) => e.context(format!("read status of {}", module.id)).into(),
+    }
+}
+
+#[put("/expansion/{id}/{control}")]
+async fn set_control(
+    expansions: web::Data<Expansions>,
+    path: web::Path<(String, String)>,
+    value: web::Json<serde_json::Value>,
+) -> LegacyResponse {
+    let (id, control) = path.into_inner();
+    let Some(module) = expansions.get(&id) else {
+        return not_found(&id);
+    };
+    let driver = match module.driver() {
+        Ok(driver) => driver,
+        Err(e) => return LegacyResponse::Error(StatusCode::CONFLICT, format!("{:#}", e).into()),
+    };
+    if !driver.controls().contains(&control.as_str()) {
+        return LegacyResponse::Error(
+            StatusCode::NOT_FOUND,
+            format!("{} has no control {}", id, control).into(),
+        );
+    }
+    driver
+        .set(&control, value.into_inner())
+        .await
+        .map_err(|e| LegacyResponse::Error(StatusCode::BAD_REQUEST, format!("{:#}", e).into()))
+        .into()
+}
+
+fn not_found(id: &str) -> LegacyResponse {
+    LegacyResponse::Error(
+        StatusCode::NOT_FOUND,
+        format!("no expansion board {}", id).into(),
+    )
+}
@@ -0,0 +1,77 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to reset (parts of) the BMC to its factory defaults.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::config_service::ConfigService;
+use crate::app::factory_reset::{execute_factory_reset, FactoryReset, ResetScope};
+use actix_web::http::StatusCode;
+use actix_web::{post, web};
+use serde::Deserialize;
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(request_factory_reset)
+        .service(confirm_factory_reset);
+}
+
+#[derive(Debug, Deserialize)]
+struct ResetRequest {
+    scopes: Vec<ResetScope>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResetConfirmation {
+    token: String,
+}
+
+/// First step of a factory reset. Returns a token that needs to be passed to
+/// `/factory-reset/confirm` to execute the reset.
+#[post("/factory-reset")]
+async fn request_factory_reset(
+    reset: web::Data<FactoryReset>,
+    request: web::Json<ResetRequest>,
+) -> LegacyResponse {
+    let scopes = request.into_inner().scopes;
+    if scopes.is_empty() {
+        return LegacyResponse::bad_request("`scopes` cannot be empty");
+    }
+
+    let (token, expires) = reset.request(scopes.clone());
+    json!({
+        "token": token,
+        "scopes": scopes,
+        "expires_in": expires.as_secs(),
+    })
+    .into()
+}
+
+/// Executes the pending factory reset and reboots the BMC.
+#[post("/factory-reset/confirm")]
+async fn confirm_factory_reset(
+    reset: web::Data<FactoryReset>,
+    bmc: web::Data<BmcApplication>,
+    config: web::Data<ConfigService>,
+    confirmation: web::Json<ResetConfirmation>,
+) -> LegacyResponse {
+    let scopes = match reset.confirm(&confirmation.token) {
+        Ok(scopes) => scopes,
+        Err(e) => return (StatusCode::FORBIDDEN, e.to_string()).into(),
+    };
+
+    execute_factory_reset(&bmc, &config.current(), &scopes)
+        .await
+        .map_err(|e| e.context("execute factory reset"))
+        .into()
+}
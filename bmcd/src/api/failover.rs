@@ -0,0 +1,44 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes that report the health probes and recoveries of nodes, see
+//! [`crate::app::failover`].
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::failover::Failover;
+use actix_web::{get, post, web};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_failover)
+        .service(enable_failover)
+        .service(disable_failover);
+}
+
+/// Lists the nodes listed in the `failover` section of the configuration.
+#[get("/failover")]
+async fn get_failover(failover: web::Data<Failover>) -> LegacyResponse {
+    json!(failover.status()).into()
+}
+
+/// Resumes the recovery of a node that was disabled at runtime.
+#[post("/failover/{node}/enable")]
+async fn enable_failover(failover: web::Data<Failover>, node: web::Path<u8>) -> LegacyResponse {
+    failover.set_enabled(*node, true).into()
+}
+
+/// Stops recovering a node until bmcd restarts, e.g. during maintenance. The
+/// node is still probed.
+#[post("/failover/{node}/disable")]
+async fn disable_failover(failover: web::Data<Failover>, node: web::Path<u8>) -> LegacyResponse {
+    failover.set_enabled(*node, false).into()
+}
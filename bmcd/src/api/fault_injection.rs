@@ -0,0 +1,50 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to manage injected delays and failures. Only compiled with the
+//! `fault-injection` feature.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::error::BmcError;
+use crate::fault_injection::{clear, rules, set, FaultRule, Site};
+use actix_web::{delete, get, put, web};
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_faults)
+        .service(set_fault)
+        .service(clear_fault)
+        .service(clear_faults);
+}
+
+#[get("/debug/faults")]
+async fn get_faults() -> LegacyResponse {
+    serde_json::to_value(rules()).into()
+}
+
+#[put("/debug/faults/{site}")]
+async fn set_fault(site: web::Path<Site>, rule: web::Json<FaultRule>) -> LegacyResponse {
+    set(site.into_inner(), rule.into_inner())
+        .map_err(|e| BmcError::invalid_parameter("rule", e))
+        .into()
+}
+
+#[delete("/debug/faults/{site}")]
+async fn clear_fault(site: web::Path<Site>) -> LegacyResponse {
+    clear(Some(site.into_inner()));
+    ().into()
+}
+
+#[delete("/debug/faults")]
+async fn clear_faults() -> LegacyResponse {
+    clear(None);
+    ().into()
+}
@@ -0,0 +1,131 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to inspect and control the A/B firmware slots of the BMC, to follow
+//! the progress of firmware upgrades and to request a physical presence
+//! override for unsigned firmware.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::firmware_slots::FirmwareSlots;
+use crate::app::physical_presence::PhysicalPresence;
+use crate::app::upgrade_progress::UpgradeStatus;
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, HttpResponse};
+use bytes::Bytes;
+use futures::StreamExt;
+use serde_json::json;
+use tokio_stream::wrappers::WatchStream;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_slots)
+        .service(confirm_firmware)
+        .service(rollback_firmware)
+        .service(get_override)
+        .service(request_override)
+        .service(upgrade_progress)
+        .service(upgrade_events)
+        .service(upgrade_recovery);
+}
+
+fn slots_configured(
+    slots: Option<web::Data<FirmwareSlots>>,
+) -> Result<web::Data<FirmwareSlots>, LegacyResponse> {
+    slots.ok_or(LegacyResponse::Error(
+        StatusCode::NOT_FOUND,
+        "firmware slots are not configured".into(),
+    ))
+}
+
+#[get("/firmware")]
+async fn get_slots(slots: Option<web::Data<FirmwareSlots>>) -> LegacyResponse {
+    let slots = match slots_configured(slots) {
+        Ok(slots) => slots,
+        Err(e) => return e,
+    };
+
+    match slots.status().await {
+        Ok(status) => json!(status).into(),
+        Err(e) => e.context("read firmware slots").into(),
+    }
+}
+
+/// Marks the running firmware as good before the health check does.
+#[post("/firmware/confirm")]
+async fn confirm_firmware(slots: Option<web::Data<FirmwareSlots>>) -> LegacyResponse {
+    match slots_configured(slots) {
+        Ok(slots) => slots.confirm().await.into(),
+        Err(e) => e,
+    }
+}
+
+/// Boots the firmware of the other slot.
+#[post("/firmware/rollback")]
+async fn rollback_firmware(
+    slots: Option<web::Data<FirmwareSlots>>,
+    bmc: web::Data<BmcApplication>,
+) -> LegacyResponse {
+    let slots = match slots_configured(slots) {
+        Ok(slots) => slots,
+        Err(e) => return e,
+    };
+
+    if let Err(e) = slots.rollback().await {
+        return e.context("rollback").into();
+    }
+    bmc.reboot(false).await.into()
+}
+
+#[get("/firmware/override")]
+async fn get_override(presence: web::Data<PhysicalPresence>) -> LegacyResponse {
+    json!({ "state": presence.state() }).into()
+}
+
+/// Allows the next firmware upgrade to skip signature verification. Takes
+/// effect only when KEY1 on the board is pressed within the returned number
+/// of seconds.
+#[post("/firmware/override")]
+async fn request_override(presence: web::Data<PhysicalPresence>) -> LegacyResponse {
+    let window = presence.arm();
+    json!({ "press_within": window.as_secs() }).into()
+}
+
+/// Phase and progress of the running BMC firmware upgrade. `phase` is null
+/// when no upgrade is in progress.
+#[get("/firmware/upgrade")]
+async fn upgrade_progress(status: web::Data<UpgradeStatus>) -> LegacyResponse {
+    match status.current() {
+        Some(progress) => json!(progress).into(),
+        None => json!({ "phase": null, "percent": null }).into(),
+    }
+}
+
+/// Stream of server-sent events with the upgrade progress. Progress is
+/// state, not a log: a client that reads slower than the progress changes
+/// receives only the latest value, so nothing queues up per client.
+#[get("/firmware/upgrade/events")]
+async fn upgrade_events(status: web::Data<UpgradeStatus>) -> HttpResponse {
+    let events = WatchStream::new(status.subscribe()).map(|progress| {
+        let data = serde_json::to_string(&progress).unwrap_or_default();
+        Ok::<_, std::convert::Infallible>(Bytes::from(format!("data: {}\n\n", data)))
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .streaming(events)
+}
+
+/// Outcome of the recovery of a firmware upgrade that was interrupted by a
+/// power cut or crash. `null` when the last upgrade was not interrupted.
+#[get("/firmware/recovery")]
+async fn upgrade_recovery(status: web::Data<UpgradeStatus>) -> LegacyResponse {
+    json!(status.recovery()).into()
+}
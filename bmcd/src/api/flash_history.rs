@@ -0,0 +1,37 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Route to list the recent flashes of the nodes. Nodes are numbered from 1.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::flash_history::history;
+use actix_web::{get, web};
+use serde::Deserialize;
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_flashes);
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    node: Option<u8>,
+}
+
+#[get("/flash-history")]
+async fn list_flashes(
+    bmc: web::Data<BmcApplication>,
+    query: web::Query<HistoryQuery>,
+) -> LegacyResponse {
+    json!(history(&bmc, query.node).await).into()
+}
@@ -0,0 +1,83 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Route to query the inventory, sensors, events and jobs with GraphQL. The
+//! response is the GraphQL `{data, errors}` body, not the legacy envelope.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::activity::ActivityMonitor;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::config_service::ConfigService;
+use crate::app::graphql::{execute, QuerySources};
+use crate::app::jobs::Jobs;
+use crate::app::node_agent::NodeAgents;
+use crate::app::notifier::Notifier;
+use crate::app::plugins::Plugins;
+use actix_web::http::StatusCode;
+use actix_web::{post, web, Either, HttpResponse};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(query);
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryRequest {
+    query: String,
+    #[serde(default)]
+    variables: Option<Map<String, Value>>,
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post("/graphql")]
+async fn query(
+    config: web::Data<ConfigService>,
+    bmc: web::Data<BmcApplication>,
+    agents: web::Data<NodeAgents>,
+    notifier: web::Data<Notifier>,
+    jobs: web::Data<Jobs>,
+    activity: web::Data<ActivityMonitor>,
+    plugins: web::Data<Plugins>,
+    request: web::Json<QueryRequest>,
+) -> Either<LegacyResponse, HttpResponse> {
+    let settings = config.current().graphql.clone();
+    if !settings.enabled {
+        return Either::Left(LegacyResponse::Error(
+            StatusCode::NOT_FOUND,
+            "the GraphQL endpoint is disabled, set graphql.enabled in the configuration".into(),
+        ));
+    }
+    let sources = QuerySources {
+        bmc: &bmc,
+        agents: &agents,
+        notifier: &notifier,
+        jobs: &jobs,
+        activity: &activity,
+        plugins: &plugins,
+    };
+    let request = request.into_inner();
+    let response = execute(
+        &sources,
+        &request.query,
+        &request.variables.unwrap_or_default(),
+        settings.max_depth,
+    )
+    .await;
+    // a query that could not be executed is a bad request
+    let mut builder = if response.data.is_none() {
+        HttpResponse::BadRequest()
+    } else {
+        HttpResponse::Ok()
+    };
+    Either::Right(builder.json(response))
+}
@@ -0,0 +1,199 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Middleware that applies the [`HttpPolicy`]: it removes the base path of a
+//! reverse proxy, records the client address of the request and answers
+//! cross-origin requests. Wrap it around the whole application, so that it
+//! runs before routing and preflight requests do not need authentication.
+use crate::app::http_policy::HttpPolicy;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::http::{Method, Uri};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse};
+use std::net::IpAddr;
+
+const ALLOWED_METHODS: &str = "GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS";
+const EXPOSED_HEADERS: &str = "Deprecation, Link, Location, Retry-After";
+
+/// Address of the client of a request, stored in the request extensions.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddress(pub IpAddr);
+
+/// Address of the client that sent `request`. Behind a trusted proxy, this
+/// is the address that the proxy forwarded the request for.
+pub fn client_address(request: &HttpRequest) -> Option<IpAddr> {
+    if let Some(ClientAddress(address)) = request.extensions().get::<ClientAddress>() {
+        return Some(*address);
+    }
+    request
+        .peer_addr()
+        .map(|address| address.ip().to_canonical())
+}
+
+/// Needs [`HttpPolicy`] in the application data.
+pub async fn apply_http_policy(
+    mut request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(policy) = request.app_data::<web::Data<HttpPolicy>>().cloned() else {
+        return next.call(request).await.map(|r| r.map_into_boxed_body());
+    };
+
+    let stripped =
+        policy
+            .strip_base_path(request.path())
+            .map(|path| match request.query_string() {
+                "" => path.to_string(),
+                query => format!("{}?{}", path, query),
+            });
+    if let Some(path) = stripped {
+        if let Ok(uri) = path.parse::<Uri>() {
+            request.match_info_mut().get_mut().update(&uri);
+            request.head_mut().uri = uri;
+        }
+    }
+
+    if let Some(peer) = request.peer_addr() {
+        let headers = request.headers();
+        let client = policy.client_address(
+            peer.ip(),
+            header_str(headers, &header::FORWARDED),
+            header_str(headers, &header::X_FORWARDED_FOR),
+        );
+        request.extensions_mut().insert(ClientAddress(client));
+    }
+
+    let Some(origin) = header_str(request.headers(), &header::ORIGIN).map(str::to_string) else {
+        return next.call(request).await.map(|r| r.map_into_boxed_body());
+    };
+    let Some(grant) = policy.cors(&origin) else {
+        return next.call(request).await.map(|r| r.map_into_boxed_body());
+    };
+
+    let preflight = request.method() == Method::OPTIONS
+        && request
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    let mut response = if preflight {
+        let mut response = HttpResponse::NoContent();
+        response
+            .insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, ALLOWED_METHODS))
+            .insert_header((header::ACCESS_CONTROL_MAX_AGE, grant.max_age.as_secs()));
+        if let Some(requested) = request
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+        {
+            response.insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, requested.clone()));
+        }
+        request.into_response(response.finish())
+    } else {
+        next.call(request).await?.map_into_boxed_body()
+    };
+
+    let headers = response.headers_mut();
+    if let Ok(origin) = HeaderValue::from_str(&grant.origin) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    }
+    headers.insert(
+        header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static(EXPOSED_HEADERS),
+    );
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    if grant.credentials {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+    Ok(response)
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &header::HeaderName) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Cors, Http};
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App};
+
+    #[actix_web::test]
+    async fn proxied_requests() {
+        let policy = web::Data::new(HttpPolicy::new(&Http {
+            base_path: Some("/bmc".to_string()),
+            trusted_proxies: vec!["127.0.0.1".to_string()],
+            cors: Cors {
+                allowed_origins: vec!["https://dashboard.lab".to_string()],
+                ..Default::default()
+            },
+        }));
+        let app = test::init_service(
+            App::new()
+                .app_data(policy)
+                .wrap(from_fn(apply_http_policy))
+                .route(
+                    "/api/bmc/client",
+                    web::get().to(|request: HttpRequest| async move {
+                        client_address(&request).unwrap().to_string()
+                    }),
+                ),
+        )
+        .await;
+
+        let request = test::TestRequest::get()
+            .uri("/bmc/api/bmc/client")
+            .peer_addr("127.0.0.1:4000".parse().unwrap())
+            .insert_header((header::X_FORWARDED_FOR, "203.0.113.7"))
+            .insert_header((header::ORIGIN, "https://dashboard.lab"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(
+            response
+                .headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            "https://dashboard.lab"
+        );
+        assert_eq!(test::read_body(response).await, "203.0.113.7");
+
+        let preflight = test::TestRequest::default()
+            .method(Method::OPTIONS)
+            .uri("/api/bmc/client")
+            .insert_header((header::ORIGIN, "https://dashboard.lab"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "POST"))
+            .to_request();
+        let response = test::call_service(&app, preflight).await;
+        assert_eq!(response.status(), 204);
+        assert_eq!(
+            response
+                .headers()
+                .get(header::ACCESS_CONTROL_ALLOW_METHODS)
+                .unwrap(),
+            ALLOWED_METHODS
+        );
+
+        let foreign = test::TestRequest::get()
+            .uri("/api/bmc/client")
+            .peer_addr("127.0.0.1:4000".parse().unwrap())
+            .insert_header((header::ORIGIN, "https://evil.example"))
+            .to_request();
+        let response = test::call_service(&app, foreign).await;
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to read and write registers of allowlisted I2C devices. Addresses
+//! and registers in the path are decimal or hexadecimal with a `0x` prefix.
+use crate::api::http_policy::client_address;
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::i2c_access::I2cAccess;
+use actix_web::http::StatusCode;
+use actix_web::{get, put, web, HttpRequest};
+use serde::Deserialize;
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_devices)
+        .service(read_registers)
+        .service(write_registers);
+}
+
+#[derive(Debug, Deserialize)]
+struct ReadQuery {
+    #[serde(default = "default_length")]
+    length: usize,
+}
+
+fn default_length() -> usize {
+    1
+}
+
+#[derive(Debug, Deserialize)]
+struct WriteRequest {
+    data: Vec<u8>,
+}
+
+fn parse_number<T: TryFrom<u32>>(value: &str) -> Result<T, LegacyResponse> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed
+        .ok()
+        .and_then(|v| T::try_from(v).ok())
+        .ok_or_else(|| LegacyResponse::bad_request(format!("`{}` is out of range", value)))
+}
+
+fn parse_target(path: &(String, String, String)) -> Result<(u32, u16, u8), LegacyResponse> {
+    Ok((
+        parse_number(&path.0)?,
+        parse_number(&path.1)?,
+        parse_number(&path.2)?,
+    ))
+}
+
+#[get("/i2c")]
+async fn list_devices(access: web::Data<I2cAccess>) -> LegacyResponse {
+    let devices: Vec<_> = access
+        .devices()
+        .iter()
+        .map(|d| json!({ "bus": d.bus, "address": d.address, "writable": d.writable }))
+        .collect();
+    json!(devices).into()
+}
+
+#[get("/i2c/{bus}/{address}/{register}")]
+async fn read_registers(
+    access: web::Data<I2cAccess>,
+    path: web::Path<(String, String, String)>,
+    query: web::Query<ReadQuery>,
+) -> LegacyResponse {
+    let (bus, address, register) = match parse_target(&path) {
+        Ok(target) => target,
+        Err(e) => return e,
+    };
+    match access.read(bus, address, register, query.length).await {
+        Ok(data) => json!({ "data": data }).into(),
+        Err(e) => LegacyResponse::Error(StatusCode::BAD_REQUEST, format!("{:#}", e).into()),
+    }
+}
+
+#[put("/i2c/{bus}/{address}/{register}")]
+async fn write_registers(
+    request: HttpRequest,
+    access: web::Data<I2cAccess>,
+    path: web::Path<(String, String, String)>,
+    body: web::Json<WriteRequest>,
+) -> LegacyResponse {
+    let (bus, address, register) = match parse_target(&path) {
+        Ok(target) => target,
+        Err(e) => return e,
+    };
+    let peer = client_address(&request)
+        .map(|address| address.to_string())
+        .unwrap_or_default();
+    access
+        .write(&peer, bus, address, register, body.into_inner().data)
+        .await
+        .map_err(|e| LegacyResponse::Error(StatusCode::BAD_REQUEST, format!("{:#}", e).into()))
+        .into()
+}
@@ -0,0 +1,272 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Middleware that replays the response of a request that is retried with
+//! the same `Idempotency-Key` header, see [`crate::app::idempotency`]. Only
+//! requests that change state are considered. Keys are kept per user, and a
+//! key that is reused for a different method, URI or body is refused.
+use crate::api::shutdown::changes_state;
+use crate::app::idempotency::{IdempotencyCache, Lookup, StoredResponse, MAX_STORED_BODY};
+use crate::authentication::roles::user_name;
+use actix_web::body::{self, BodySize, BoxBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::{ErrorBadRequest, ErrorConflict, ErrorUnprocessableEntity};
+use actix_web::http::header::{HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use bytes::BytesMut;
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+const REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+const MAX_KEY_LEN: usize = 255;
+/// Larger bodies, e.g. images, are not buffered to be hashed. Such requests
+/// are told apart by the length of their body.
+const MAX_HASHED_BODY: usize = 64 * 1024;
+
+/// Abandons the key when the request does not complete, e.g. because the
+/// client disconnected, so that a retry is not refused as in flight.
+struct Reservation {
+    cache: web::Data<IdempotencyCache>,
+    key: String,
+    completed: bool,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.cache.abandon(&self.key);
+        }
+    }
+}
+
+/// Needs [`IdempotencyCache`] in the application data. Wrap it inside the
+/// authentication so that only authenticated requests are replayed.
+pub async fn replay_idempotent(
+    mut request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let key = request.headers().get(IDEMPOTENCY_KEY).cloned();
+    let cache = request.app_data::<web::Data<IdempotencyCache>>().cloned();
+    let (Some(key), Some(cache)) = (key, cache) else {
+        return next.call(request).await.map(|r| r.map_into_boxed_body());
+    };
+    if !changes_state(&request) {
+        return next.call(request).await.map(|r| r.map_into_boxed_body());
+    }
+    let key = valid_key(&key).ok_or_else(|| ErrorBadRequest("invalid Idempotency-Key"))?;
+
+    let body = body_fingerprint(&mut request).await?;
+    let fingerprint = format!("{} {} {}", request.method(), request.uri(), body);
+    let user = user_name(request.request()).unwrap_or_default();
+    // a key of one user cannot replay or block the requests of another
+    let key = format!("{}\n{}", user, key);
+    match cache.begin(&key, &fingerprint) {
+        Lookup::New => {}
+        Lookup::InFlight => {
+            return Err(ErrorConflict(
+                "a request with this Idempotency-Key is in progress",
+            ))
+        }
+        Lookup::Mismatch => {
+            return Err(ErrorUnprocessableEntity(
+                "Idempotency-Key was used for a different request",
+            ))
+        }
+        Lookup::Replay(stored) => {
+            tracing::debug!("replaying response for idempotency key {:?}", key);
+            let response = replay(stored);
+            return Ok(request.into_response(response));
+        }
+    }
+
+    let mut reservation = Reservation {
+        cache,
+        key,
+        completed: false,
+    };
+    let (request, response) = next.call(request).await?.into_parts();
+    let storable = !response.status().is_server_error()
+        && match response.body().size() {
+            BodySize::None => true,
+            BodySize::Sized(size) => size as usize <= MAX_STORED_BODY,
+            BodySize::Stream => false,
+        };
+    if !storable {
+        return Ok(ServiceResponse::new(
+            request,
+            response.map_into_boxed_body(),
+        ));
+    }
+
+    let (response, body) = response.into_parts();
+    let body = body::to_bytes(body).await.map_err(Into::into)?;
+    let stored = StoredResponse {
+        status: response.status().as_u16(),
+        content_type: response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string),
+        body: body.clone(),
+    };
+    reservation.cache.complete(&reservation.key, stored);
+    reservation.completed = true;
+    Ok(ServiceResponse::new(
+        request,
+        response.set_body(body).map_into_boxed_body(),
+    ))
+}
+
+/// Hash of the body of `request`, whose payload is put back for the handler.
+/// Bodies over [`MAX_HASHED_BODY`] are identified by their length, if known.
+async fn body_fingerprint(request: &mut ServiceRequest) -> Result<String, Error> {
+    let length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if let Some(length) = length.filter(|l| *l > MAX_HASHED_BODY) {
+        return Ok(format!("{} bytes", length));
+    }
+
+    let mut payload = request.take_payload();
+    let mut body = BytesMut::new();
+    let mut complete = false;
+    while body.len() <= MAX_HASHED_BODY {
+        match payload.next().await {
+            Some(chunk) => body.extend_from_slice(&chunk?),
+            None => {
+                complete = true;
+                break;
+            }
+        }
+    }
+
+    let body = body.freeze();
+    let fingerprint = if complete {
+        hex::encode(Sha256::digest(&body))
+    } else {
+        String::new()
+    };
+    let payload = futures::stream::once(async move { Ok(body) }).chain(payload);
+    request.set_payload(Payload::Stream {
+        payload: Box::pin(payload),
+    });
+    Ok(fingerprint)
+}
+
+fn valid_key(key: &HeaderValue) -> Option<String> {
+    let key = key.to_str().ok()?;
+    let valid =
+        !key.is_empty() && key.len() <= MAX_KEY_LEN && key.chars().all(|c| c.is_ascii_graphic());
+    valid.then(|| key.to_string())
+}
+
+fn replay(stored: StoredResponse) -> HttpResponse {
+    let status = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+    let mut response = HttpResponse::build(status);
+    if let Some(content_type) = stored.content_type {
+        response.insert_header((CONTENT_TYPE, content_type));
+    }
+    response.insert_header((REPLAYED, "true")).body(stored.body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[actix_web::test]
+    async fn retried_request_runs_once() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(IdempotencyCache::default()))
+                .wrap(from_fn(replay_idempotent))
+                .route(
+                    "/reset",
+                    web::post().to(move || {
+                        let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                        async move { HttpResponse::Ok().body(n.to_string()) }
+                    }),
+                ),
+        )
+        .await;
+
+        let request = || {
+            test::TestRequest::post()
+                .uri("/reset")
+                .insert_header((IDEMPOTENCY_KEY, "abc"))
+        };
+        let first = test::call_service(&app, request().to_request()).await;
+        assert_eq!(test::read_body(first).await, "1");
+        let retry = test::call_service(&app, request().to_request()).await;
+        assert_eq!(retry.headers().get(REPLAYED).unwrap(), "true");
+        assert_eq!(test::read_body(retry).await, "1");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let other = test::TestRequest::post()
+            .uri("/reset?node=2")
+            .insert_header((IDEMPOTENCY_KEY, "abc"))
+            .to_request();
+        let error = test::try_call_service(&app, other).await.unwrap_err();
+        assert_eq!(
+            error.as_response_error().status_code(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+
+        let other_body = test::TestRequest::post()
+            .uri("/reset")
+            .insert_header((IDEMPOTENCY_KEY, "abc"))
+            .set_payload("{\"node\": 2}")
+            .to_request();
+        let error = test::try_call_service(&app, other_body).await.unwrap_err();
+        assert_eq!(
+            error.as_response_error().status_code(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+
+        let without_key = test::TestRequest::post().uri("/reset").to_request();
+        let response = test::call_service(&app, without_key).await;
+        assert_eq!(test::read_body(response).await, "2");
+    }
+
+    #[actix_web::test]
+    async fn body_reaches_handler() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(IdempotencyCache::default()))
+                .wrap(from_fn(replay_idempotent))
+                .route(
+                    "/echo",
+                    web::post().to(|body: web::Bytes| async move { body }),
+                ),
+        )
+        .await;
+
+        let request = test::TestRequest::post()
+            .uri("/echo")
+            .insert_header((IDEMPOTENCY_KEY, "abc"))
+            .set_payload("{\"node\": 1}")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(test::read_body(response).await, "{\"node\": 1}");
+    }
+}
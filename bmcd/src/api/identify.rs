@@ -0,0 +1,65 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to blink the status LED in the pattern of a node, so the node can
+//! be found at the rack.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::identify::{Identify, DEFAULT_DURATION};
+use crate::error::BmcError;
+use crate::hal::NodeId;
+use actix_web::{delete, get, put, web};
+use serde::Deserialize;
+use serde_json::json;
+use std::time::Duration;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_identify)
+        .service(start_identify)
+        .service(stop_identify);
+}
+
+#[derive(Debug, Deserialize)]
+struct IdentifyRequest {
+    /// seconds to blink, defaults to [`DEFAULT_DURATION`]
+    duration: Option<u64>,
+}
+
+#[get("/identify")]
+async fn get_identify(identify: web::Data<Identify>) -> LegacyResponse {
+    json!(identify.status()).into()
+}
+
+/// `node` counts from 1, like the node numbers printed on the board.
+#[put("/identify/{node}")]
+async fn start_identify(
+    identify: web::Data<Identify>,
+    node: web::Path<u8>,
+    request: Option<web::Json<IdentifyRequest>>,
+) -> LegacyResponse {
+    let node = node
+        .checked_sub(1)
+        .and_then(|n| NodeId::try_from(n).ok())
+        .ok_or_else(|| BmcError::invalid_parameter("node", "must be 1 to 4"));
+    let duration = request
+        .and_then(|r| r.duration)
+        .map_or(DEFAULT_DURATION, Duration::from_secs);
+    node.and_then(|node| identify.start(node, duration))
+        .map(|_| json!(identify.status()))
+        .into()
+}
+
+#[delete("/identify")]
+async fn stop_identify(identify: web::Data<Identify>) -> LegacyResponse {
+    identify.stop().await;
+    ().into()
+}
@@ -0,0 +1,59 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to read the identity of the board from its ID EEPROM and to set the
+//! user fields in it, and to read the capabilities of the board.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::capabilities::Capabilities;
+use crate::hal::eeprom::{BoardIdentity, UserFields};
+use actix_web::http::StatusCode;
+use actix_web::{get, put, web};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_identity)
+        .service(set_user_fields)
+        .service(get_capabilities);
+}
+
+#[get("/identity")]
+async fn get_identity(identity: web::Data<BoardIdentity>) -> LegacyResponse {
+    match identity.identity() {
+        Some(identity) => json!(identity).into(),
+        None => LegacyResponse::Error(StatusCode::NOT_FOUND, "board has no ID EEPROM".into()),
+    }
+}
+
+#[put("/identity")]
+async fn set_user_fields(
+    identity: web::Data<BoardIdentity>,
+    fields: web::Json<UserFields>,
+) -> LegacyResponse {
+    let fields = fields.into_inner();
+    if let Err(e) = fields.validate() {
+        return LegacyResponse::Error(StatusCode::BAD_REQUEST, format!("{:#}", e).into());
+    }
+    match identity.set_user_fields(fields).await {
+        Ok(identity) => json!(identity).into(),
+        Err(e) => {
+            LegacyResponse::Error(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e).into())
+        }
+    }
+}
+
+/// Features of the board and the running kernel, see
+/// [`crate::app::capabilities`].
+#[get("/about/capabilities")]
+async fn get_capabilities(capabilities: web::Data<Capabilities>) -> LegacyResponse {
+    json!(capabilities.as_ref()).into()
+}
@@ -0,0 +1,49 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to inspect and clear the cache of node images, see
+//! `app::image_cache`.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::image_cache::ImageCache;
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, web};
+use anyhow::Context;
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_images)
+        .service(clear_images)
+        .service(remove_image);
+}
+
+#[get("/image-cache")]
+async fn list_images(cache: web::Data<ImageCache>) -> LegacyResponse {
+    match cache.list().context("read image cache") {
+        Ok(images) => json!(images).into(),
+        Err(e) => e.into(),
+    }
+}
+
+#[delete("/image-cache")]
+async fn clear_images(cache: web::Data<ImageCache>) -> LegacyResponse {
+    cache.clear().context("clear image cache").into()
+}
+
+#[delete("/image-cache/{sha256}")]
+async fn remove_image(cache: web::Data<ImageCache>, sha256: web::Path<String>) -> LegacyResponse {
+    match cache.remove(&sha256).context("remove cached image") {
+        Ok(true) => ().into(),
+        Ok(false) => (StatusCode::NOT_FOUND, "image is not cached").into(),
+        Err(e) => e.into(),
+    }
+}
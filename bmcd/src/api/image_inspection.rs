@@ -0,0 +1,83 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes that describe an OS image without flashing it, see
+//! `app::image_inspection`.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::factory_reset::IMAGES_DIR;
+use crate::app::image_cache::ImageCache;
+use crate::app::image_inspection::inspect;
+use crate::utils::resolve;
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web};
+use futures::TryStreamExt;
+use serde::Deserialize;
+use serde_json::json;
+use std::path::Path;
+use tokio_util::io::StreamReader;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(inspect_stored).service(inspect_upload);
+}
+
+#[derive(Debug, Deserialize)]
+struct StoredImage {
+    /// path of an image relative to the images directory of the BMC
+    image: Option<String>,
+    /// SHA-256 of an image in the image cache
+    sha256: Option<String>,
+}
+
+/// Inspects an image that is stored on the BMC, either in the images
+/// directory or in the image cache.
+#[get("/images/inspect")]
+async fn inspect_stored(
+    cache: web::Data<ImageCache>,
+    query: web::Query<StoredImage>,
+) -> LegacyResponse {
+    let path = match (&query.image, &query.sha256) {
+        (Some(image), None) => match resolve(Path::new(IMAGES_DIR), image) {
+            Some(path) if path.is_file() => path,
+            _ => return (StatusCode::NOT_FOUND, format!("no image `{}`", image)).into(),
+        },
+        (None, Some(sha256)) => match cache.lookup(sha256) {
+            Some(path) => path,
+            None => return (StatusCode::NOT_FOUND, "image is not cached").into(),
+        },
+        _ => return LegacyResponse::bad_request("pass either `image` or `sha256`"),
+    };
+
+    let file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            return anyhow::Error::from(e)
+                .context(path.display().to_string())
+                .into()
+        }
+    };
+    match inspect(file).await {
+        Ok(info) => json!(info).into(),
+        Err(e) => e.context("inspect image").into(),
+    }
+}
+
+/// Inspects the image in the request body, which is not stored. Use e.g.
+/// `curl --data-binary @image.img.xz`.
+#[post("/images/inspect")]
+async fn inspect_upload(payload: web::Payload) -> LegacyResponse {
+    let reader = StreamReader::new(payload.map_err(std::io::Error::other));
+    match inspect(reader).await {
+        Ok(info) => json!(info).into(),
+        Err(e) => (StatusCode::UNPROCESSABLE_ENTITY, format!("{:#}", e)).into(),
+    }
+}
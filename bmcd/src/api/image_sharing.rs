@@ -0,0 +1,43 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Route that serves the image cache to other boards, outside of the
+//! authenticated API, see `app::image_sharing`.
+use crate::app::image_cache::ImageCache;
+use crate::app::image_sharing::IMAGES_PATH;
+use actix_files::NamedFile;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+
+pub fn config(cfg: &mut web::ServiceConfig, cache: web::Data<ImageCache>) {
+    cfg.service(
+        web::resource(format!("{}/{{sha256}}", IMAGES_PATH))
+            .app_data(cache)
+            .route(web::get().to(serve_image))
+            .route(web::head().to(serve_image)),
+    );
+}
+
+/// Range requests are answered by [`NamedFile`].
+async fn serve_image(
+    request: HttpRequest,
+    cache: web::Data<ImageCache>,
+    sha256: web::Path<String>,
+) -> HttpResponse {
+    let Some(path) = cache.lookup(&sha256) else {
+        return HttpResponse::NotFound().finish();
+    };
+    match NamedFile::open_async(path).await {
+        Ok(file) => file.respond_to(&request).map_into_boxed_body(),
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
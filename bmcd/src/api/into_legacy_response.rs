@@ -15,6 +15,7 @@ use actix_web::{http::StatusCode, HttpResponse, HttpResponseBuilder, Responder,
 use serde_json::json;
 use std::{borrow::Cow, fmt::Display};
 
+use crate::error::{ApiError, ErrorCode};
 use crate::serial_service::serial_handler::SerialError;
 
 /// Specifies the different repsonses that this legacy API can return. Implements
@@ -23,6 +24,8 @@ use crate::serial_service::serial_handler::SerialError;
 pub enum LegacyResponse {
     Success(Option<serde_json::Value>),
     Error(StatusCode, Cow<'static, str>),
+    /// Error with a specific code, see [`crate::error`].
+    Failure(ApiError),
     UartData(String),
 }
 
@@ -76,10 +79,13 @@ impl From<()> for LegacyResponse {
 
 impl From<anyhow::Error> for LegacyResponse {
     fn from(e: anyhow::Error) -> Self {
-        LegacyResponse::Error(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to {}: {}", e, e.root_cause()).into(),
-        )
+        LegacyResponse::Failure(ApiError::from_anyhow(&e))
+    }
+}
+
+impl<E: ErrorCode> From<E> for LegacyResponse {
+    fn from(e: E) -> Self {
+        LegacyResponse::Failure(ApiError::from(&e))
     }
 }
 
@@ -89,9 +95,21 @@ impl From<serde_json::Error> for LegacyResponse {
     }
 }
 
-impl From<SerialError> for LegacyResponse {
-    fn from(value: SerialError) -> Self {
-        LegacyResponse::Error(StatusCode::INTERNAL_SERVER_ERROR, value.to_string().into())
+impl ErrorCode for SerialError {
+    fn code(&self) -> &'static str {
+        match self {
+            SerialError::NotStarted | SerialError::Stopped => "console_unavailable",
+            SerialError::AlreadyRunning => "busy",
+            _ => "console_error",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            SerialError::NotStarted | SerialError::Stopped => StatusCode::SERVICE_UNAVAILABLE,
+            SerialError::AlreadyRunning => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
     }
 }
 
@@ -107,6 +125,7 @@ impl Display for LegacyResponse {
             ),
             LegacyResponse::UartData(s) => write!(f, "{}", s),
             LegacyResponse::Error(_, msg) => write!(f, "{}", msg),
+            LegacyResponse::Failure(e) => write!(f, "{}", e.message),
         }
     }
 }
@@ -123,28 +142,36 @@ pub type LegacyResult<T> = Result<T, LegacyResponse>;
 
 impl From<LegacyResponse> for HttpResponse {
     fn from(value: LegacyResponse) -> Self {
-        let (response, result, is_uart) = match value {
+        let (status, keyname, result, error) = match value {
             LegacyResponse::Success(None) => (
                 StatusCode::OK,
+                "result",
                 serde_json::Value::String("ok".to_string()),
-                false,
-            ),
-            LegacyResponse::Success(Some(body)) => (StatusCode::OK, body, false),
-            LegacyResponse::UartData(d) => (StatusCode::OK, serde_json::Value::String(d), true),
-            LegacyResponse::Error(status_code, msg) => (
-                status_code,
-                serde_json::Value::String(msg.into_owned()),
-                false,
+                None,
             ),
+            LegacyResponse::Success(Some(body)) => (StatusCode::OK, "result", body, None),
+            LegacyResponse::UartData(d) => {
+                (StatusCode::OK, "uart", serde_json::Value::String(d), None)
+            }
+            LegacyResponse::Error(status_code, msg) => {
+                let msg = msg.into_owned();
+                let error = (!status_code.is_success())
+                    .then(|| ApiError::from_status(status_code, msg.clone()));
+                (status_code, "result", msg.into(), error)
+            }
+            LegacyResponse::Failure(e) => (e.status, "result", e.message.clone().into(), Some(e)),
         };
 
-        let keyname = if is_uart { "uart" } else { "result" };
-
+        let mut body = serde_json::Map::new();
+        body.insert(keyname.to_string(), result);
+        if let Some(error) = error {
+            body.insert("error".to_string(), json!(error));
+        }
         let msg = json! {{
-            "response": [{ keyname: result }]
+            "response": [body]
         }};
 
-        HttpResponseBuilder::new(response).json(msg)
+        HttpResponseBuilder::new(status).json(msg)
     }
 }
 
@@ -0,0 +1,124 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to list the modules in the node slots and to probe them. Nodes are
+//! numbered from 1.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::asset_export::{self, AssetSources};
+use crate::app::bmc_application::BmcApplication;
+use crate::app::config_service::ConfigService;
+use crate::app::firmware_slots::FirmwareSlots;
+use crate::app::inventory::{get_inventory, probe_modules};
+use crate::app::node_agent::NodeAgents;
+use crate::app::storage_manager::StorageManager;
+use crate::hal::eeprom::BoardIdentity;
+use crate::hal::NodeId;
+use actix_web::http::header;
+use actix_web::{get, post, web, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_slots)
+        .service(export_inventory)
+        .service(probe);
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeQuery {
+    node: Option<u8>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+#[get("/inventory")]
+async fn list_slots(
+    bmc: web::Data<BmcApplication>,
+    agents: web::Data<NodeAgents>,
+) -> LegacyResponse {
+    json!(get_inventory(&bmc, &agents).await).into()
+}
+
+/// Exports the inventory of the whole board for asset management systems,
+/// as JSON or, with `?format=csv`, as one row per asset.
+#[get("/inventory/export")]
+async fn export_inventory(
+    bmc: web::Data<BmcApplication>,
+    agents: web::Data<NodeAgents>,
+    identity: web::Data<BoardIdentity>,
+    storage: web::Data<StorageManager>,
+    firmware_slots: Option<web::Data<FirmwareSlots>>,
+    config: web::Data<ConfigService>,
+    query: web::Query<ExportQuery>,
+) -> HttpResponse {
+    let config = config.current();
+    let document = asset_export::collect(AssetSources {
+        bmc: &bmc,
+        agents: &agents,
+        identity: &identity,
+        storage: &storage,
+        firmware_slots: firmware_slots.as_ref().map(|slots| slots.get_ref()),
+        config: &config,
+    })
+    .await;
+    let serial = document.board.serial.as_deref().unwrap_or("board");
+    let (content_type, extension, body) = match query.format {
+        ExportFormat::Json => (
+            "application/json",
+            "json",
+            serde_json::to_string_pretty(&document).unwrap_or_default(),
+        ),
+        ExportFormat::Csv => ("text/csv", "csv", document.to_csv()),
+    };
+    // the serial is read from the EEPROM, keep the header well-formed anyway
+    let name: String = serial
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        .collect();
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!(r#"attachment; filename="inventory-{}.{}""#, name, extension),
+        ))
+        .body(body)
+}
+
+/// Probes the node in the query, or all nodes that are powered off.
+#[post("/inventory/probe")]
+async fn probe(bmc: web::Data<BmcApplication>, query: web::Query<ProbeQuery>) -> LegacyResponse {
+    let node = match query.node {
+        Some(node) => match node
+            .checked_sub(1)
+            .and_then(|idx| NodeId::try_from(idx).ok())
+            .filter(|n| (*n as usize) < bmc.board().node_count)
+        {
+            Some(node) => Some(node),
+            None => return LegacyResponse::bad_request(format!("node {} does not exist", node)),
+        },
+        None => None,
+    };
+    json!(probe_modules(&bmc, node).await).into()
+}
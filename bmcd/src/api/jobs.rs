@@ -0,0 +1,108 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to follow and cancel jobs, see [`crate::app::jobs`]. The handle
+//! that the legacy API returns for a flash or firmware upgrade is the id of
+//! its job.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::backup::{self, start_backup_job};
+use crate::app::jobs::{JobId, Jobs};
+use crate::error::BmcError;
+use actix_files::NamedFile;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use bytes::Bytes;
+use futures::StreamExt;
+use serde_json::json;
+use tokio_stream::wrappers::BroadcastStream;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    // `/jobs/events` before `/jobs/{id}`, which would reject it as an id
+    cfg.service(list_jobs)
+        .service(job_events)
+        .service(start_backup)
+        .service(get_job)
+        .service(cancel_job)
+        .service(download);
+}
+
+fn unknown_job(id: JobId) -> LegacyResponse {
+    BmcError::invalid_parameter("id", format!("no job {}", id)).into()
+}
+
+#[get("/jobs")]
+async fn list_jobs(jobs: web::Data<Jobs>) -> LegacyResponse {
+    json!(jobs.list()).into()
+}
+
+/// Stream of server-sent events with every job that starts or finishes. A
+/// client that falls behind misses events; `/jobs` has the current state.
+#[get("/jobs/events")]
+async fn job_events(jobs: web::Data<Jobs>) -> HttpResponse {
+    let events = BroadcastStream::new(jobs.subscribe()).filter_map(|job| async move {
+        let data = serde_json::to_string(&job.ok()?).ok()?;
+        Some(Ok::<_, std::convert::Infallible>(Bytes::from(format!(
+            "data: {}\n\n",
+            data
+        ))))
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .streaming(events)
+}
+
+#[post("/jobs/backup")]
+async fn start_backup(jobs: web::Data<Jobs>) -> LegacyResponse {
+    let id = start_backup_job(jobs.into_inner());
+    json!({ "id": id }).into()
+}
+
+#[get("/jobs/{id}")]
+async fn get_job(jobs: web::Data<Jobs>, id: web::Path<JobId>) -> LegacyResponse {
+    match jobs.get(*id) {
+        Some(job) => json!(job).into(),
+        None => unknown_job(*id),
+    }
+}
+
+#[post("/jobs/{id}/cancel")]
+async fn cancel_job(jobs: web::Data<Jobs>, id: web::Path<JobId>) -> LegacyResponse {
+    jobs.cancel(*id).into()
+}
+
+/// The archive of a finished backup job.
+#[get("/jobs/{id}/download")]
+async fn download(
+    jobs: web::Data<Jobs>,
+    id: web::Path<JobId>,
+    request: HttpRequest,
+) -> HttpResponse {
+    let Some(path) = jobs.artifact(*id) else {
+        if jobs.get(*id).is_some() {
+            let error =
+                BmcError::NotSupported(format!("job {} has nothing to download", id).into());
+            return LegacyResponse::from(error).into();
+        }
+        return unknown_job(*id).into();
+    };
+    match NamedFile::open_async(path).await {
+        Ok(file) => file
+            .set_content_disposition(ContentDisposition {
+                disposition: DispositionType::Attachment,
+                parameters: vec![DispositionParam::Filename(backup::file_name())],
+            })
+            .respond_to(&request)
+            .map_into_boxed_body(),
+        Err(e) => LegacyResponse::from(anyhow::Error::from(e)).into(),
+    }
+}
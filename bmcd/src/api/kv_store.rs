@@ -0,0 +1,128 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes of the namespaced key/value store. Values can be any JSON value.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::kv_store::{self, KvError, MAX_NAMESPACES, NAMESPACE_QUOTA};
+use crate::error::ErrorCode;
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, put, web};
+use serde_json::{json, Map, Value};
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_namespaces)
+        .service(get_namespace)
+        .service(delete_namespace)
+        .service(get_value)
+        .service(set_value)
+        .service(delete_value);
+}
+
+impl ErrorCode for KvError {
+    fn code(&self) -> &'static str {
+        match self {
+            KvError::NotFound(_) => "not_found",
+            KvError::QuotaExceeded(_) => "quota_exceeded",
+            KvError::TooManyNamespaces => "limit_reached",
+            KvError::InvalidName(_) => "invalid_name",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            KvError::NotFound(_) => StatusCode::NOT_FOUND,
+            KvError::QuotaExceeded(_) | KvError::TooManyNamespaces => {
+                StatusCode::INSUFFICIENT_STORAGE
+            }
+            KvError::InvalidName(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn details(&self) -> Value {
+        match self {
+            KvError::NotFound(name) | KvError::InvalidName(name) => json!({ "name": name }),
+            KvError::QuotaExceeded(namespace) => json!({
+                "feature": "kv",
+                "quota": NAMESPACE_QUOTA,
+                "namespace": namespace,
+            }),
+            KvError::TooManyNamespaces => json!({ "limit": MAX_NAMESPACES }),
+        }
+    }
+}
+
+#[get("/kv")]
+async fn list_namespaces(bmc: web::Data<BmcApplication>) -> LegacyResponse {
+    serde_json::to_value(kv_store::list_namespaces(&bmc).await).into()
+}
+
+#[get("/kv/{namespace}")]
+async fn get_namespace(bmc: web::Data<BmcApplication>, path: web::Path<String>) -> LegacyResponse {
+    kv_store::get_namespace(&bmc, &path)
+        .await
+        .map(|namespace| {
+            let map: Map<String, Value> = namespace
+                .into_iter()
+                .map(|(k, v)| (k, stored_to_json(v)))
+                .collect();
+            Value::Object(map)
+        })
+        .into()
+}
+
+#[delete("/kv/{namespace}")]
+async fn delete_namespace(
+    bmc: web::Data<BmcApplication>,
+    path: web::Path<String>,
+) -> LegacyResponse {
+    kv_store::delete(&bmc, &path, None).await.into()
+}
+
+#[get("/kv/{namespace}/{key}")]
+async fn get_value(
+    bmc: web::Data<BmcApplication>,
+    path: web::Path<(String, String)>,
+) -> LegacyResponse {
+    let (namespace, key) = path.into_inner();
+    kv_store::get_value(&bmc, &namespace, &key)
+        .await
+        .map(stored_to_json)
+        .into()
+}
+
+#[put("/kv/{namespace}/{key}")]
+async fn set_value(
+    bmc: web::Data<BmcApplication>,
+    path: web::Path<(String, String)>,
+    value: web::Json<Value>,
+) -> LegacyResponse {
+    let (namespace, key) = path.into_inner();
+    let value = value.into_inner().to_string();
+    kv_store::set_value(&bmc, &namespace, &key, value)
+        .await
+        .into()
+}
+
+#[delete("/kv/{namespace}/{key}")]
+async fn delete_value(
+    bmc: web::Data<BmcApplication>,
+    path: web::Path<(String, String)>,
+) -> LegacyResponse {
+    let (namespace, key) = path.into_inner();
+    kv_store::delete(&bmc, &namespace, Some(&key)).await.into()
+}
+
+fn stored_to_json(value: String) -> Value {
+    serde_json::from_str(&value).unwrap_or(Value::String(value))
+}
@@ -0,0 +1,129 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes for KVM over IP of nodes with an HDMI capture add-on, see
+//! [`crate::app::kvm`].
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::kvm::Kvm;
+use crate::authentication::roles::role;
+use crate::error::BmcError;
+use crate::hal::NodeId;
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use bytes::{Bytes, BytesMut};
+use serde::Deserialize;
+use serde_json::json;
+use std::convert::Infallible;
+
+const BOUNDARY: &str = "frame";
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(kvm_status)
+        .service(kvm_stream)
+        .service(kvm_snapshot)
+        .service(kvm_keyboard);
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyboardRequest {
+    /// characters to type, `\n` presses enter
+    #[serde(default)]
+    text: String,
+    /// keys to press after the text, e.g. `ctrl+alt+delete`
+    #[serde(default)]
+    keys: Vec<String>,
+}
+
+fn node_id(node: u8) -> Result<NodeId, BmcError> {
+    node.checked_sub(1)
+        .and_then(|n| NodeId::try_from(n).ok())
+        .ok_or_else(|| BmcError::invalid_parameter("node", "must be 1 to 4"))
+}
+
+#[get("/nodes/{node}/kvm")]
+async fn kvm_status(kvm: web::Data<Kvm>, node: web::Path<u8>) -> LegacyResponse {
+    let node = match node_id(*node) {
+        Ok(node) => node,
+        Err(e) => return e.into(),
+    };
+    kvm.status(node).await.map(|status| json!(status)).into()
+}
+
+/// The screen of the node as MJPEG, which browsers show in an `<img>`.
+#[get("/nodes/{node}/kvm/stream")]
+async fn kvm_stream(kvm: web::Data<Kvm>, node: web::Path<u8>) -> HttpResponse {
+    let node = match node_id(*node) {
+        Ok(node) => node,
+        Err(e) => return LegacyResponse::from(e).into(),
+    };
+    let frames = match kvm.frames(node) {
+        Ok(frames) => frames,
+        Err(e) => return LegacyResponse::from(e).into(),
+    };
+
+    let parts = futures::stream::unfold(frames, |mut frames| async move {
+        let frame = frames.next().await?;
+        let mut part = BytesMut::with_capacity(frame.len() + 96);
+        part.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                BOUNDARY,
+                frame.len()
+            )
+            .as_bytes(),
+        );
+        part.extend_from_slice(&frame);
+        part.extend_from_slice(b"\r\n");
+        Some((Ok::<Bytes, Infallible>(part.freeze()), frames))
+    });
+    HttpResponse::Ok()
+        .content_type(format!("multipart/x-mixed-replace; boundary={}", BOUNDARY))
+        .insert_header(("Cache-Control", "no-store"))
+        .streaming(parts)
+}
+
+#[get("/nodes/{node}/kvm/snapshot")]
+async fn kvm_snapshot(kvm: web::Data<Kvm>, node: web::Path<u8>) -> HttpResponse {
+    let node = match node_id(*node) {
+        Ok(node) => node,
+        Err(e) => return LegacyResponse::from(e).into(),
+    };
+    match kvm.snapshot(node).await {
+        Ok(frame) => HttpResponse::Ok()
+            .content_type("image/jpeg")
+            .insert_header(("Cache-Control", "no-store"))
+            .body(frame),
+        Err(e) => LegacyResponse::from(e).into(),
+    }
+}
+
+#[post("/nodes/{node}/kvm/keyboard")]
+async fn kvm_keyboard(
+    kvm: web::Data<Kvm>,
+    node: web::Path<u8>,
+    request: HttpRequest,
+    input: web::Json<KeyboardRequest>,
+) -> LegacyResponse {
+    // typing on the screen is typing on the console
+    if !role(&request).can_write_console() {
+        return LegacyResponse::Error(
+            StatusCode::FORBIDDEN,
+            "the keyboard is off limits for observers".into(),
+        );
+    }
+    let node = match node_id(*node) {
+        Ok(node) => node,
+        Err(e) => return e.into(),
+    };
+    kvm.type_keys(node, &input.text, &input.keys).await.into()
+}
@@ -12,42 +12,53 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 //! Routes for legacy API present in versions <= 2.0.0 of the firmware.
+//! The query-parameter API is deprecated, see [`compat`].
+mod compat;
+
 use crate::api::into_legacy_response::LegacyResponse;
 use crate::api::into_legacy_response::{LegacyResult, Null};
+use crate::app::backup;
 use crate::app::bmc_application::NodeInfo;
 use crate::app::bmc_application::{BmcApplication, UsbConfig};
 use crate::app::bmc_info::{
     get_fs_stat, get_ipv4_address, get_mac_address, get_net_interfaces, get_storage_info,
+    read_hostname, read_os_release,
 };
+use crate::app::capabilities::{legacy_requirement, Capabilities};
+use crate::app::firmware_signature::FirmwareVerifier;
+use crate::app::firmware_slots::FirmwareSlots;
+use crate::app::image_sharing::ImageSharing;
 use crate::app::transfer_action::InitializeTransfer;
 use crate::app::transfer_action::UpgradeCommand;
+use crate::app::upgrade_progress::UpgradeStatus;
+use crate::app::upgrade_worker::DryRunTarget;
+use crate::authentication::roles::role;
+use crate::hal::eeprom::BoardIdentity;
 use crate::hal::{NodeId, UsbMode, UsbRoute};
 use crate::serial_service::serial::SerialConnections;
 use crate::serial_service::{legacy_serial_get_handler, legacy_serial_set_handler};
 use crate::streaming_data_service::data_transfer::DataTransfer;
 use crate::streaming_data_service::StreamingDataService;
+use crate::utils::{restart_daemon, Checksum};
 use actix_files::file_extension_to_mime;
 use actix_multipart::Multipart;
 use actix_web::guard::{fn_guard, GuardContext};
 use actix_web::http::{header, StatusCode};
-use actix_web::{get, post, web, HttpResponse, Responder};
+use actix_web::middleware::from_fn;
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
 use anyhow::Context;
 use async_compression::tokio::bufread::GzipEncoder;
 use async_compression::Level;
-use board_info::{self, BoardInfoAttribute};
 use humansize::{format_size, DECIMAL};
 use serde_json::json;
 use std::collections::HashMap;
 use std::ffi::c_ulong;
-use std::io;
 use std::ops::Deref;
 use std::path::PathBuf;
-use std::process::Command;
 use std::str::FromStr;
 use std::time::Duration;
-use tokio::io::AsyncBufReadExt;
 use tokio_stream::StreamExt;
-use tokio_util::io::ReaderStream;
+use tokio_util::io::{ReaderStream, StreamReader};
 
 use super::get_node_param;
 type Query = web::Query<std::collections::HashMap<String, String>>;
@@ -62,29 +73,36 @@ type Query = web::Query<std::collections::HashMap<String, String>>;
 /// * chunked upload of flash images
 const API_VERSION: &str = "1.1";
 
-pub fn config(cfg: &mut web::ServiceConfig) {
-    cfg.service(
-        web::resource("")
-            .route(
-                web::get()
-                    .guard(fn_guard(flash_status_guard))
-                    .to(handle_flash_status),
-            )
-            .route(
-                web::get()
-                    .guard(fn_guard(flash_guard))
-                    .to(handle_transfer_request),
-            )
-            .route(
-                web::post()
-                    .guard(fn_guard(set_node_info_guard))
-                    .to(set_node_aux_info),
-            )
-            .route(web::get().to(api_entry)),
-    )
-    .service(handle_file_upload)
-    .service(cancel_file_upload)
-    .service(backup_handler);
+/// Registers the legacy routes. With `enabled` false, requests to the
+/// query-parameter API are refused.
+pub fn config(cfg: &mut web::ServiceConfig, enabled: bool) {
+    if enabled {
+        cfg.service(
+            web::resource("")
+                .wrap(from_fn(compat::deprecation_notice))
+                .route(
+                    web::get()
+                        .guard(fn_guard(flash_status_guard))
+                        .to(handle_flash_status),
+                )
+                .route(
+                    web::get()
+                        .guard(fn_guard(flash_guard))
+                        .to(handle_transfer_request),
+                )
+                .route(
+                    web::post()
+                        .guard(fn_guard(set_node_info_guard))
+                        .to(set_node_aux_info),
+                )
+                .route(web::get().to(api_entry)),
+        );
+    } else {
+        cfg.service(web::resource("").to(compat::disabled));
+    }
+    cfg.service(handle_file_upload)
+        .service(cancel_file_upload)
+        .service(backup_handler);
 }
 
 pub fn info_config(cfg: &mut web::ServiceConfig) {
@@ -112,34 +130,25 @@ fn set_node_info_guard(context: &GuardContext<'_>) -> bool {
     query.contains("opt=set") && query.contains("type=node_info")
 }
 
+/// The backup is streamed while it is archived, see [`backup::archive_overlay`].
 #[get("/backup")]
 async fn backup_handler() -> impl Responder {
-    let archive = tokio::task::spawn_blocking(move || {
-        let mut builder = tar::Builder::new(Vec::new());
-        builder.mode(tar::HeaderMode::Deterministic);
-        builder
-            .append_dir_all(".", "/mnt/overlay/upper/")
-            .and_then(|_| builder.finish())
-            .and_then(|_| builder.into_inner())
-    })
-    .await
-    .expect("error joining archiving task");
-
-    match archive {
-        Ok(buffer) => {
-            let now = chrono::Local::now();
-            let content_disposition = format!(
-                r#"attachment; filename="tp2-backup-{}.tar.gz""#,
-                now.format("%d-%m-%Y")
-            );
-            let encoder = GzipEncoder::with_quality(std::io::Cursor::new(buffer), Level::Best);
-            HttpResponse::Ok()
-                .insert_header(header::ContentType(file_extension_to_mime("gz")))
-                .insert_header((header::CONTENT_DISPOSITION, content_disposition))
-                .streaming(ReaderStream::new(encoder))
-        }
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
-    }
+    let mut chunks = backup::archive_overlay();
+
+    // errors before the first chunk, such as a missing overlay, can still be
+    // reported with a status code
+    let first = match chunks.next().await {
+        Some(Err(e)) => return HttpResponse::InternalServerError().body(e.to_string()),
+        first => first,
+    };
+
+    let content_disposition = format!(r#"attachment; filename="{}""#, backup::file_name());
+    let archive = StreamReader::new(tokio_stream::iter(first).chain(chunks));
+    let encoder = GzipEncoder::with_quality(archive, Level::Best);
+    HttpResponse::Ok()
+        .insert_header(header::ContentType(file_extension_to_mime("gz")))
+        .insert_header((header::CONTENT_DISPOSITION, content_disposition))
+        .streaming(ReaderStream::new(encoder))
 }
 
 #[get("/info")]
@@ -150,6 +159,9 @@ async fn info_handler() -> impl Responder {
 async fn api_entry(
     bmc: web::Data<BmcApplication>,
     serial: web::Data<SerialConnections>,
+    identity: web::Data<BoardIdentity>,
+    capabilities: web::Data<Capabilities>,
+    request: HttpRequest,
     query: Query,
 ) -> impl Responder {
     let is_set = match query.get("opt").map(String::as_str) {
@@ -161,6 +173,11 @@ async fn api_entry(
     let Some(ty) = query.get("type") else {
         return LegacyResponse::bad_request("Missing `type` parameter");
     };
+    if let Some(capability) = legacy_requirement(ty, is_set) {
+        if let Err(e) = capabilities.ensure(capability) {
+            return e.into();
+        }
+    }
 
     let bmc = bmc.as_ref();
     match (ty.as_ref(), is_set) {
@@ -180,6 +197,11 @@ async fn api_entry(
         ("sdcard", true) => format_sdcard().into(),
         ("sdcard", false) => get_sdcard_info(),
         ("uart", false) => legacy_serial_get_handler(serial, query).await.into(),
+        ("uart", true) if !role(&request).can_write_console() => (
+            StatusCode::FORBIDDEN,
+            "the console is read-only for observers".to_string(),
+        )
+            .into(),
         ("uart", true) => legacy_serial_set_handler(serial, query).await.into(),
         ("usb", true) => set_usb_mode(bmc, query).await.into(),
         ("usb", false) => get_usb_mode(bmc).await.into(),
@@ -188,7 +210,7 @@ async fn api_entry(
         ("info", false) => get_info().await.into(),
         ("cooling", false) => get_cooling_info().await.into(),
         ("cooling", true) => set_cooling_info(bmc, query).await.into(),
-        ("about", false) => get_about().await.into(),
+        ("about", false) => get_about(&identity, &capabilities).await.into(),
         _ => (
             StatusCode::BAD_REQUEST,
             format!("Invalid `type` parameter {}", ty),
@@ -199,17 +221,14 @@ async fn api_entry(
 
 #[allow(clippy::unused_unit)]
 fn reload_self() -> impl Into<LegacyResponse> {
-    tokio::task::spawn_blocking(move || {
-        Command::new("sh")
-            .arg("-c")
-            .arg("/etc/init.d/S94bmcd restart")
-            .status()
-    });
-
+    restart_daemon();
     ()
 }
 
-async fn get_about() -> impl Into<LegacyResponse> {
+async fn get_about(
+    identity: &BoardIdentity,
+    capabilities: &Capabilities,
+) -> impl Into<LegacyResponse> {
     let bmcd_version = env!("CARGO_PKG_VERSION");
     let build_time = build_time::build_time_utc!("%Y-%m-%d %H:%M:%S-00:00");
 
@@ -226,18 +245,21 @@ async fn get_about() -> impl Into<LegacyResponse> {
     }
 
     let hostname = read_hostname().await.unwrap_or_default();
-    let (board_model, board_revision) = read_board_model().await.unwrap_or_default();
+    let identity = identity.identity().unwrap_or_default();
 
     json!(
         {
-            "board_model": board_model,
-            "board_revision": board_revision,
+            "board_model": identity.product_name,
+            "board_revision": identity.hw_revision,
+            "board_serial": identity.serial,
+            "asset_tag": identity.user.asset_tag,
             "hostname": hostname,
             "api": API_VERSION,
             "version": version,
             "bmcd_version": bmcd_version,
             "buildtime": build_time,
             "buildroot": buildroot,
+            "capabilities": capabilities,
         }
     )
 }
@@ -314,35 +336,6 @@ async fn set_node_to_msd(bmc: &BmcApplication, query: Query) -> LegacyResult<()>
     Ok(())
 }
 
-async fn read_os_release() -> std::io::Result<HashMap<String, String>> {
-    let buffer = tokio::fs::read("/etc/os-release").await?;
-    let mut lines = buffer.lines();
-    let mut results = HashMap::new();
-
-    while let Some(line) = lines.next_line().await? {
-        if let Some((key, value)) = line.split_once('=') {
-            results.insert(key.to_string(), value.to_string());
-        }
-    }
-    Ok(results)
-}
-
-async fn read_hostname() -> io::Result<String> {
-    let hostname = tokio::fs::read_to_string("/proc/sys/kernel/hostname")
-        .await?
-        .trim_end_matches(['\0', '\n'])
-        .to_string();
-
-    Ok(hostname)
-}
-
-async fn read_board_model() -> io::Result<(String, String)> {
-    let info = ::board_info::BoardInfo::load()?;
-    let board_model = info.value_of(&BoardInfoAttribute::ProductName);
-    let board_revision = info.value_of(&BoardInfoAttribute::HwVersion);
-    Ok((board_model, board_revision))
-}
-
 /// function is here for backwards compliance. Data is mostly a duplication of [`get_about`]
 async fn get_system_information() -> impl Into<LegacyResponse> {
     let build_time = build_time::build_time_utc!("%Y-%m-%d %H:%M:%S-00:00");
@@ -563,19 +556,44 @@ async fn handle_flash_status(flash: web::Data<StreamingDataService>) -> LegacyRe
 async fn handle_transfer_request(
     ss: web::Data<StreamingDataService>,
     bmc: web::Data<BmcApplication>,
+    verifier: web::Data<FirmwareVerifier>,
+    slots: Option<web::Data<FirmwareSlots>>,
+    progress: web::Data<UpgradeStatus>,
+    sharing: web::Data<ImageSharing>,
     query: Query,
 ) -> LegacyResult<String> {
     let (process_name, upgrade_command) = match query.get("type").map(|c| c.as_str()) {
         Some("firmware") => (
             "firmware upgrade service".to_string(),
-            UpgradeCommand::OsUpgrade,
+            UpgradeCommand::OsUpgrade {
+                verifier: verifier.into_inner(),
+                slots: slots.map(|s| s.into_inner()),
+                progress: progress.into_inner(),
+            },
         ),
         Some("flash") => {
             let node = get_node_param(&query)?;
-            (
-                format!("{node} os install service"),
-                UpgradeCommand::Module(node, bmc.clone().into_inner()),
-            )
+            match query.get("dry_run").map(String::as_str) {
+                None => (
+                    format!("{node} os install service"),
+                    UpgradeCommand::Module(node, bmc.clone().into_inner()),
+                ),
+                Some(target) => {
+                    let target = match target {
+                        "" | "null" => DryRunTarget::Null,
+                        "file" => DryRunTarget::File,
+                        _ => {
+                            return Err(LegacyResponse::bad_request(
+                                "`dry_run` should equal 'null' or 'file'",
+                            ))
+                        }
+                    };
+                    (
+                        format!("{node} os install dry run"),
+                        UpgradeCommand::DryRun(node, target),
+                    )
+                }
+            }
         }
         _ => {
             return Err(LegacyResponse::bad_request(
@@ -584,7 +602,7 @@ async fn handle_transfer_request(
         }
     };
 
-    let data_transfer = create_data_transfer(&query).await?;
+    let data_transfer = create_data_transfer(&query, &sharing).await?;
     let do_crc = !query.contains_key("skip_crc");
     let transfer_request =
         InitializeTransfer::new(process_name, upgrade_command, data_transfer, do_crc);
@@ -594,17 +612,17 @@ async fn handle_transfer_request(
     Ok(json.to_string())
 }
 
-async fn create_data_transfer(query: &Query) -> LegacyResult<DataTransfer> {
+async fn create_data_transfer(query: &Query, sharing: &ImageSharing) -> LegacyResult<DataTransfer> {
     let file = query.get("file").ok_or(LegacyResponse::bad_request(
         "Invalid `file` query parameter",
     ))?;
 
+    let checksum = try_map_checksum(query)?;
+
     if query.contains_key("local") {
-        return Ok(DataTransfer::local(PathBuf::from(file)));
+        return Ok(DataTransfer::local(PathBuf::from(file), checksum));
     }
 
-    let sha256 = try_map_sha256(query.get("sha256"))?;
-
     if file.starts_with("http") {
         let url = reqwest::Url::parse(file).map_err(|e| {
             LegacyResponse::bad_request(format!(
@@ -612,7 +630,7 @@ async fn create_data_transfer(query: &Query) -> LegacyResult<DataTransfer> {
                 e
             ))
         })?;
-        return Ok(DataTransfer::url(url, sha256).await?);
+        return Ok(sharing.transfer(url, checksum).await?);
     }
 
     let size = query.get("length").ok_or((
@@ -623,25 +641,40 @@ async fn create_data_transfer(query: &Query) -> LegacyResult<DataTransfer> {
     let size = u64::from_str(size)
         .map_err(|_| LegacyResponse::bad_request("`length` parameter is not a number"))?;
 
-    Ok(DataTransfer::remote(PathBuf::from(&file), size, 16, sha256))
-}
-
-pub fn try_map_sha256(value: Option<&String>) -> LegacyResult<Option<bytes::Bytes>> {
-    let sha = if let Some(sha256) = value {
-        let bytes = hex::decode(sha256)
-            .map_err(|e| {
+    Ok(DataTransfer::remote(
+        PathBuf::from(&file),
+        size,
+        16,
+        checksum,
+    ))
+}
+
+/// The expected checksum of a transfer, from either the `sha256` or the
+/// `crc32` parameter.
+fn try_map_checksum(query: &Query) -> LegacyResult<Option<Checksum>> {
+    match (query.get("sha256"), query.get("crc32")) {
+        (Some(_), Some(_)) => Err(LegacyResponse::bad_request(
+            "`sha256` and `crc32` cannot be combined",
+        )),
+        (Some(sha256), None) => {
+            let bytes = hex::decode(sha256).map_err(|e| {
                 LegacyResponse::bad_request(format!(
                     "`sha256` parameter contains invalid hex values: {}",
                     e
                 ))
-            })?
-            .into();
-        Some(bytes)
-    } else {
-        None
-    };
-
-    Ok(sha)
+            })?;
+            if bytes.len() != 32 {
+                return Err(LegacyResponse::bad_request(
+                    "`sha256` parameter must be 64 hex digits",
+                ));
+            }
+            Ok(Some(Checksum::Sha256(bytes.into())))
+        }
+        (None, Some(crc32)) => u32::from_str_radix(crc32.trim_start_matches("0x"), 16)
+            .map(|crc32| Some(Checksum::Crc32(crc32)))
+            .map_err(|_| LegacyResponse::bad_request("`crc32` parameter must be 8 hex digits")),
+        (None, None) => Ok(None),
+    }
 }
 
 #[get("/upload/{handle}/cancel")]
@@ -664,6 +697,13 @@ async fn handle_file_upload(
     let mut bytes_send: u64 = 0;
     while let Some(Ok(chunk)) = field.next().await {
         let length = chunk.len();
+        if bytes_send + length as u64 > size {
+            ss.cancel_all().await;
+            return Err(LegacyResponse::bad_request(format!(
+                "upload exceeds the announced {}",
+                format_size(size, DECIMAL)
+            )));
+        }
         if sender.send(chunk).await.is_err() {
             return Err(return_transfer_error(ss).await.into());
         }
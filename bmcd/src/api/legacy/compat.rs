@@ -0,0 +1,155 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Compatibility layer of the query-parameter API (`/api/bmc?opt=..&type=..`).
+//! Responses carry a `Deprecation` header and a `Warning` that names the
+//! route that replaces the request, if one exists, which is also passed as
+//! `Link`. When `legacy_api.enabled` is false,
+//! the requests are answered with `410 Gone` instead.
+use crate::api::into_legacy_response::LegacyResponse;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+type Query = web::Query<HashMap<String, String>>;
+
+/// Route that replaces a legacy request of type `ty`.
+fn replacement(ty: &str) -> Option<&'static str> {
+    let route = match ty {
+        "about" => "/api/bmc/identity",
+        "firmware" => "/api/bmc/firmware/upgrade",
+        "info" | "other" => "/api/bmc/info",
+        "network" => "/api/bmc/network",
+        "reload" => "/api/bmc/restart",
+        "uart" => "/api/bmc/serial/ws",
+        _ => return None,
+    };
+    Some(route)
+}
+
+fn request_type(query_string: &str) -> String {
+    Query::from_query(query_string)
+        .ok()
+        .and_then(|q| q.get("type").cloned())
+        .unwrap_or_default()
+}
+
+/// Logs the first use of each request type, the web UI polls some of them.
+fn log_deprecated(ty: &str) {
+    static LOGGED: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+    let mut logged = LOGGED.lock().expect("deprecation log lock poisoned");
+    if logged
+        .get_or_insert_with(HashSet::new)
+        .insert(ty.to_string())
+    {
+        match replacement(ty) {
+            Some(route) => tracing::warn!(
+                "legacy API request type={} is deprecated, use {}",
+                ty,
+                route
+            ),
+            None => tracing::warn!("legacy API request type={} is deprecated", ty),
+        }
+    }
+}
+
+/// Marks the response to a legacy request as deprecated.
+pub async fn deprecation_notice(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let ty = request_type(request.query_string());
+    log_deprecated(&ty);
+    let mut response = next.call(request).await?;
+    let headers = response.headers_mut();
+    headers.insert(
+        HeaderName::from_static("deprecation"),
+        HeaderValue::from_static("true"),
+    );
+    let warning = match replacement(&ty) {
+        Some(route) => {
+            let link = format!("<{}>; rel=\"successor-version\"", route);
+            if let Ok(link) = HeaderValue::from_str(&link) {
+                headers.insert(header::LINK, link);
+            }
+            format!("299 bmcd \"deprecated API, use {}\"", route)
+        }
+        None => "299 bmcd \"deprecated API\"".to_string(),
+    };
+    if let Ok(warning) = HeaderValue::from_str(&warning) {
+        headers.insert(header::WARNING, warning);
+    }
+    Ok(response)
+}
+
+/// Answers legacy requests when the layer is disabled.
+pub async fn disabled(request: HttpRequest) -> HttpResponse {
+    let ty = request_type(request.query_string());
+    let msg = match replacement(&ty) {
+        Some(route) => format!("the legacy API is disabled, use {}", route),
+        None => "the legacy API is disabled".to_string(),
+    };
+    LegacyResponse::Error(StatusCode::GONE, msg.into()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App};
+
+    #[actix_web::test]
+    async fn deprecated_requests() {
+        let app = test::init_service(
+            App::new()
+                .service(
+                    web::resource("/api/bmc")
+                        .wrap(from_fn(deprecation_notice))
+                        .to(HttpResponse::Ok),
+                )
+                .route("/legacy-off", web::get().to(disabled)),
+        )
+        .await;
+
+        let request = test::TestRequest::get()
+            .uri("/api/bmc?opt=get&type=about")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.headers().get("deprecation").unwrap(), "true");
+        assert_eq!(
+            response.headers().get("link").unwrap(),
+            "</api/bmc/identity>; rel=\"successor-version\""
+        );
+        assert_eq!(
+            response.headers().get("warning").unwrap(),
+            "299 bmcd \"deprecated API, use /api/bmc/identity\""
+        );
+
+        let request = test::TestRequest::get()
+            .uri("/api/bmc?opt=get&type=power")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert!(response.headers().get("link").is_none());
+
+        let request = test::TestRequest::get()
+            .uri("/legacy-off?opt=get&type=uart&node=0")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::GONE);
+    }
+}
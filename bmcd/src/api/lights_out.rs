@@ -0,0 +1,45 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Status of the lights out mode, and the middleware that wakes the board up
+//! on API requests, see `app::lights_out`.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::lights_out::LightsOut;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{get, web, Error};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_lights_out);
+}
+
+#[get("/lights-out")]
+async fn get_lights_out(lights_out: web::Data<LightsOut>) -> LegacyResponse {
+    json!(lights_out.status()).into()
+}
+
+/// Wakes the board up before handling a request, unless its path is one of
+/// `lights_out.passive_paths`. Needs [`LightsOut`] in the application data.
+pub async fn wake_on_request(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if let Some(lights_out) = request.app_data::<web::Data<LightsOut>>() {
+        if !lights_out.is_passive(request.path()) {
+            lights_out.touch();
+        }
+    }
+    next.call(request).await
+}
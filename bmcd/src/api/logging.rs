@@ -0,0 +1,64 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to change log levels at runtime, e.g. `PUT /log/bmcd::usb_boot`
+//! with body `{"level": "trace"}`.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::logging::LogControl;
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, put, web};
+use serde::Deserialize;
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_levels)
+        .service(reset_levels)
+        .service(set_level)
+        .service(reset_level);
+}
+
+#[derive(Debug, Deserialize)]
+struct SetLevel {
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`
+    level: String,
+}
+
+#[get("/log")]
+async fn get_levels(control: web::Data<LogControl>) -> LegacyResponse {
+    json!(control.levels()).into()
+}
+
+#[delete("/log")]
+async fn reset_levels(control: web::Data<LogControl>) -> LegacyResponse {
+    control.reset(None).into()
+}
+
+#[put("/log/{target}")]
+async fn set_level(
+    control: web::Data<LogControl>,
+    target: web::Path<String>,
+    request: web::Json<SetLevel>,
+) -> LegacyResponse {
+    control
+        .set_level(&target, &request.level)
+        .map_err(|e| LegacyResponse::Error(StatusCode::BAD_REQUEST, format!("{:#}", e).into()))
+        .into()
+}
+
+#[delete("/log/{target}")]
+async fn reset_level(control: web::Data<LogControl>, target: web::Path<String>) -> LegacyResponse {
+    control
+        .reset(Some(&target))
+        .map_err(|e| LegacyResponse::Error(StatusCode::NOT_FOUND, format!("{:#}", e).into()))
+        .into()
+}
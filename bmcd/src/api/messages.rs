@@ -0,0 +1,46 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes of the message catalog, with which front-ends translate the error
+//! codes and states of the API.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::config_service::ConfigService;
+use crate::app::messages;
+use actix_web::{get, web};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_languages).service(get_catalog);
+}
+
+#[get("/messages")]
+async fn list_languages(config: web::Data<ConfigService>) -> LegacyResponse {
+    let config = config.current();
+    json!({
+        "default": messages::DEFAULT_LANGUAGE,
+        "languages": messages::languages(&config.messages.directory).await,
+    })
+    .into()
+}
+
+#[get("/messages/{language}")]
+async fn get_catalog(
+    config: web::Data<ConfigService>,
+    language: web::Path<String>,
+) -> LegacyResponse {
+    let config = config.current();
+    messages::catalog(&config.messages.directory, &language)
+        .await
+        .map(|catalog| json!(catalog))
+        .into()
+}
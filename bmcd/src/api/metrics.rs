@@ -0,0 +1,156 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Metrics in the Prometheus text format.
+use crate::app::plugins::{PluginState, Plugins};
+use crate::utils::memory::memory;
+use actix_web::{get, web, HttpResponse};
+use std::fmt::Write;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(metrics);
+}
+
+#[get("/metrics")]
+async fn metrics(plugins: web::Data<Plugins>) -> HttpResponse {
+    let mut body = String::new();
+    if let Some(resident) = resident_memory() {
+        metric(
+            &mut body,
+            "bmcd_resident_memory_bytes",
+            "gauge",
+            "Resident memory of the daemon.",
+        );
+        let _ = writeln!(body, "bmcd_resident_memory_bytes {}", resident);
+    }
+
+    let usage = memory().usage();
+    metric(
+        &mut body,
+        "bmcd_memory_used_bytes",
+        "gauge",
+        "Memory held in buffers of a subsystem.",
+    );
+    for pool in &usage {
+        let _ = writeln!(
+            body,
+            "bmcd_memory_used_bytes{{pool=\"{}\"}} {}",
+            pool.pool.name(),
+            pool.used
+        );
+    }
+    metric(
+        &mut body,
+        "bmcd_memory_peak_bytes",
+        "gauge",
+        "Most memory held in buffers of a subsystem since start.",
+    );
+    for pool in &usage {
+        let _ = writeln!(
+            body,
+            "bmcd_memory_peak_bytes{{pool=\"{}\"}} {}",
+            pool.pool.name(),
+            pool.peak
+        );
+    }
+    metric(
+        &mut body,
+        "bmcd_memory_limit_bytes",
+        "gauge",
+        "Configured cap of a subsystem, absent when unlimited.",
+    );
+    for pool in &usage {
+        if let Some(limit) = pool.limit {
+            let _ = writeln!(
+                body,
+                "bmcd_memory_limit_bytes{{pool=\"{}\"}} {}",
+                pool.pool.name(),
+                limit
+            );
+        }
+    }
+    metric(
+        &mut body,
+        "bmcd_memory_shed_total",
+        "counter",
+        "Buffers that were refused because the cap was reached.",
+    );
+    for pool in &usage {
+        let _ = writeln!(
+            body,
+            "bmcd_memory_shed_total{{pool=\"{}\"}} {}",
+            pool.pool.name(),
+            pool.shed
+        );
+    }
+
+    let plugins = plugins.list();
+    if !plugins.is_empty() {
+        metric(
+            &mut body,
+            "bmcd_plugin_up",
+            "gauge",
+            "Whether a plugin is running.",
+        );
+        for plugin in &plugins {
+            let up = matches!(plugin.state, PluginState::Running { .. });
+            let _ = writeln!(
+                body,
+                "bmcd_plugin_up{{plugin=\"{}\"}} {}",
+                plugin.name, up as u8
+            );
+        }
+        metric(
+            &mut body,
+            "bmcd_plugin_sensor",
+            "gauge",
+            "Last reading of a sensor that a plugin reports.",
+        );
+        for plugin in &plugins {
+            for (name, sensor) in &plugin.sensors {
+                let _ = writeln!(
+                    body,
+                    "bmcd_plugin_sensor{{plugin=\"{}\",sensor=\"{}\",unit=\"{}\"}} {}",
+                    plugin.name,
+                    label(name),
+                    label(&sensor.unit),
+                    sensor.value
+                );
+            }
+        }
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
+
+fn metric(body: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(body, "# HELP {} {}", name, help);
+    let _ = writeln!(body, "# TYPE {} {}", name, kind);
+}
+
+/// Escapes a label value as the text format requires.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn resident_memory() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = nix::unistd::sysconf(nix::unistd::SysconfVar::PAGE_SIZE).ok()??;
+    Some(pages * page_size as u64)
+}
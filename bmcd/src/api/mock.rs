@@ -0,0 +1,74 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to inspect the simulated board and inject faults. Only compiled
+//! with the `mock` feature.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::error::BmcError;
+use crate::hal::mock::{active_faults, board_state, clear, inject, set_present, Fault};
+use crate::hal::NodeId;
+use actix_web::{delete, get, put, web};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_mock)
+        .service(inject_fault)
+        .service(clear_fault)
+        .service(clear_faults)
+        .service(insert_module)
+        .service(remove_module);
+}
+
+fn slot(node: u8) -> Result<NodeId, BmcError> {
+    node.checked_sub(1)
+        .and_then(|n| NodeId::try_from(n).ok())
+        .ok_or_else(|| BmcError::invalid_parameter("node", "must be 1 to 4"))
+}
+
+#[get("/mock")]
+async fn get_mock() -> LegacyResponse {
+    json!({
+        "board": board_state(),
+        "faults": active_faults(),
+    })
+    .into()
+}
+
+#[put("/mock/faults/{fault}")]
+async fn inject_fault(fault: web::Path<Fault>) -> LegacyResponse {
+    inject(fault.into_inner());
+    ().into()
+}
+
+#[delete("/mock/faults/{fault}")]
+async fn clear_fault(fault: web::Path<Fault>) -> LegacyResponse {
+    clear(Some(fault.into_inner()));
+    ().into()
+}
+
+#[delete("/mock/faults")]
+async fn clear_faults() -> LegacyResponse {
+    clear(None);
+    ().into()
+}
+
+/// Seats a module in the slot of `node`, counting from 1.
+#[put("/mock/slots/{node}")]
+async fn insert_module(node: web::Path<u8>) -> LegacyResponse {
+    slot(*node).map(|node| set_present(node, true)).into()
+}
+
+#[delete("/mock/slots/{node}")]
+async fn remove_module(node: web::Path<u8>) -> LegacyResponse {
+    slot(*node).map(|node| set_present(node, false)).into()
+}
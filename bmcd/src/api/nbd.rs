@@ -0,0 +1,56 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to manage the network block device exports of the nodes.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::nbd_server::{NbdExport, NbdServer};
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, put, web};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_exports)
+        .service(set_export)
+        .service(remove_export);
+}
+
+#[get("/nbd")]
+async fn get_exports(nbd: web::Data<NbdServer>) -> LegacyResponse {
+    json!(nbd.exports()).into()
+}
+
+#[put("/nbd/{node}")]
+async fn set_export(
+    nbd: web::Data<NbdServer>,
+    bmc: web::Data<BmcApplication>,
+    node: web::Path<u8>,
+    export: web::Json<NbdExport>,
+) -> LegacyResponse {
+    nbd.set_export(&bmc, *node, Some(export.into_inner()))
+        .await
+        .map_err(|e| LegacyResponse::Error(StatusCode::BAD_REQUEST, format!("{:#}", e).into()))
+        .into()
+}
+
+#[delete("/nbd/{node}")]
+async fn remove_export(
+    nbd: web::Data<NbdServer>,
+    bmc: web::Data<BmcApplication>,
+    node: web::Path<u8>,
+) -> LegacyResponse {
+    nbd.set_export(&bmc, *node, None)
+        .await
+        .map_err(|e| LegacyResponse::Error(StatusCode::BAD_REQUEST, format!("{:#}", e).into()))
+        .into()
+}
@@ -0,0 +1,68 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to assign network boot files to the nodes.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::netboot::Netboot;
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, put, web};
+use serde::Deserialize;
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_netboot)
+        .service(set_boot_file)
+        .service(clear_boot_file);
+}
+
+#[derive(Debug, Deserialize)]
+struct BootFileRequest {
+    /// path relative to the netboot directory
+    boot_file: String,
+}
+
+#[get("/netboot")]
+async fn get_netboot(netboot: web::Data<Netboot>) -> LegacyResponse {
+    match netboot.status().await {
+        Ok(status) => json!(status).into(),
+        Err(e) => e.context("read netboot status").into(),
+    }
+}
+
+#[put("/netboot/{node}")]
+async fn set_boot_file(
+    netboot: web::Data<Netboot>,
+    bmc: web::Data<BmcApplication>,
+    node: web::Path<u8>,
+    request: web::Json<BootFileRequest>,
+) -> LegacyResponse {
+    netboot
+        .set_boot_file(&bmc, *node, Some(request.into_inner().boot_file))
+        .await
+        .map_err(|e| LegacyResponse::Error(StatusCode::BAD_REQUEST, format!("{:#}", e).into()))
+        .into()
+}
+
+#[delete("/netboot/{node}")]
+async fn clear_boot_file(
+    netboot: web::Data<Netboot>,
+    bmc: web::Data<BmcApplication>,
+    node: web::Path<u8>,
+) -> LegacyResponse {
+    netboot
+        .set_boot_file(&bmc, *node, None)
+        .await
+        .map_err(|e| LegacyResponse::Error(StatusCode::BAD_REQUEST, format!("{:#}", e).into()))
+        .into()
+}
@@ -0,0 +1,71 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to view and configure the network of the BMC.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::network_config::{NetworkConfigurator, NetworkSettings, DEFAULT_ROLLBACK_TIMEOUT};
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web};
+use serde::Deserialize;
+use serde_json::json;
+use std::time::Duration;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_network)
+        .service(set_network)
+        .service(confirm_network);
+}
+
+#[derive(Debug, Deserialize)]
+struct ApplyQuery {
+    /// seconds before an unconfirmed change is rolled back.
+    rollback_timeout: Option<u64>,
+}
+
+#[get("/network")]
+async fn get_network(network: web::Data<NetworkConfigurator>) -> LegacyResponse {
+    match network.current().await {
+        Ok(settings) => json!({
+            "settings": settings,
+            "rollback_at": network.rollback_deadline(),
+        })
+        .into(),
+        Err(e) => e.context("read network settings").into(),
+    }
+}
+
+/// Applies new network settings. The change is reverted unless it gets
+/// confirmed with `/network/confirm` before the rollback timeout expires.
+#[post("/network")]
+async fn set_network(
+    network: web::Data<NetworkConfigurator>,
+    query: web::Query<ApplyQuery>,
+    settings: web::Json<NetworkSettings>,
+) -> LegacyResponse {
+    let timeout = query
+        .rollback_timeout
+        .map_or(DEFAULT_ROLLBACK_TIMEOUT, Duration::from_secs);
+
+    match network.apply(settings.into_inner(), timeout).await {
+        Ok(()) => json!({ "rollback_at": network.rollback_deadline() }).into(),
+        Err(e) => (StatusCode::BAD_REQUEST, format!("{:#}", e)).into(),
+    }
+}
+
+#[post("/network/confirm")]
+async fn confirm_network(network: web::Data<NetworkConfigurator>) -> LegacyResponse {
+    network
+        .confirm()
+        .map_err(|e| LegacyResponse::Error(StatusCode::CONFLICT, e.to_string().into()))
+        .into()
+}
@@ -0,0 +1,62 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to talk to the agent in the OS of a node, see
+//! [`crate::app::node_agent`].
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::node_agent::NodeAgents;
+use crate::error::BmcError;
+use crate::hal::NodeId;
+use actix_web::{get, post, web};
+use serde::Deserialize;
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(query_agent).service(shutdown_node);
+}
+
+#[derive(Debug, Deserialize)]
+struct ShutdownQuery {
+    #[serde(default)]
+    reboot: bool,
+}
+
+fn node_id(node: u8) -> Result<NodeId, BmcError> {
+    node.checked_sub(1)
+        .and_then(|n| NodeId::try_from(n).ok())
+        .ok_or_else(|| BmcError::invalid_parameter("node", "must be 1 to 4"))
+}
+
+/// Asks the agent for a fresh report.
+#[get("/nodes/{node}/agent")]
+async fn query_agent(agents: web::Data<NodeAgents>, node: web::Path<u8>) -> LegacyResponse {
+    let node = match node_id(*node) {
+        Ok(node) => node,
+        Err(e) => return e.into(),
+    };
+    agents.query(node).await.map(|status| json!(status)).into()
+}
+
+/// Shuts the OS of the node down through its agent. The node stays powered.
+#[post("/nodes/{node}/agent/shutdown")]
+async fn shutdown_node(
+    agents: web::Data<NodeAgents>,
+    node: web::Path<u8>,
+    query: web::Query<ShutdownQuery>,
+) -> LegacyResponse {
+    let node = match node_id(*node) {
+        Ok(node) => node,
+        Err(e) => return e.into(),
+    };
+    agents.shutdown(node, query.reboot).await.into()
+}
@@ -0,0 +1,121 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes that stream a backup of the storage of a node or clone it to
+//! another node, see `app::node_backup`.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::node_backup::{BackupOptions, Compression, NodeBackups, DEFAULT_ZSTD_LEVEL};
+use crate::error::BmcError;
+use crate::hal::NodeId;
+use crate::streaming_data_service::{StreamingDataService, StreamingState};
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{get, post, web, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+
+/// Header with the id of the job of the backup, whose result holds the
+/// checksums once the stream ended.
+pub const JOB_ID: &str = "x-job-id";
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(backup_storage).service(clone_storage);
+}
+
+#[derive(Debug, Deserialize)]
+struct BackupQuery {
+    #[serde(default)]
+    compression: Compression,
+    level: Option<i32>,
+    hash: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CloneRequest {
+    target: u8,
+}
+
+fn node_id(node: u8) -> Result<NodeId, BmcError> {
+    node.checked_sub(1)
+        .and_then(|n| NodeId::try_from(n).ok())
+        .ok_or_else(|| BmcError::invalid_parameter("node", "must be 1 to 4"))
+}
+
+/// Powers the node into flashing mode and streams its storage, e.g.
+/// `curl -OJ '.../nodes/1/storage?compression=zstd&level=6'`. The node is
+/// off afterwards.
+#[get("/nodes/{node}/storage")]
+async fn backup_storage(
+    backups: web::Data<NodeBackups>,
+    streaming: web::Data<StreamingDataService>,
+    node: web::Path<u8>,
+    query: web::Query<BackupQuery>,
+) -> HttpResponse {
+    let node = match node_id(*node) {
+        Ok(node) => node,
+        Err(e) => return LegacyResponse::from(e).into(),
+    };
+    // flashing and backups share the USB port of the BMC
+    if matches!(*streaming.status().await, StreamingState::Transferring(_)) {
+        return LegacyResponse::from(BmcError::Busy("a flash".into())).into();
+    }
+    let options = BackupOptions {
+        compression: query.compression,
+        level: query.level.unwrap_or(DEFAULT_ZSTD_LEVEL),
+        hash: query.hash.unwrap_or(true),
+    };
+    let backup = match backups.start(node, options).await {
+        Ok(backup) => backup,
+        Err(e) => return LegacyResponse::from(e.context("start backup")).into(),
+    };
+
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(match options.compression {
+            Compression::None => "application/octet-stream",
+            Compression::Zstd => "application/zstd",
+        })
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(options.file_name(node))],
+        })
+        .insert_header((JOB_ID, backup.job.to_string()));
+    if options.compression == Compression::None {
+        response.no_chunking(backup.size);
+    }
+    response.streaming(backup.stream)
+}
+
+/// Copies the storage of the node to `target` on the board, e.g.
+/// `{"target": 3}`. Answers with the id of the job right away. The target
+/// ends up with the hostname and machine-id of the source; changing them
+/// after its first boot is left to the user.
+#[post("/nodes/{node}/clone")]
+async fn clone_storage(
+    backups: web::Data<NodeBackups>,
+    streaming: web::Data<StreamingDataService>,
+    node: web::Path<u8>,
+    request: web::Json<CloneRequest>,
+) -> LegacyResponse {
+    let (source, target) = match (node_id(*node), node_id(request.target)) {
+        (Ok(source), Ok(target)) => (source, target),
+        (Err(e), _) => return e.into(),
+        (_, Err(_)) => return BmcError::invalid_parameter("target", "must be 1 to 4").into(),
+    };
+    if matches!(*streaming.status().await, StreamingState::Transferring(_)) {
+        return BmcError::Busy("a flash".into()).into();
+    }
+    match backups.start_clone(source, target).await {
+        Ok(id) => json!({ "id": id }).into(),
+        Err(e) => e.context("start clone").into(),
+    }
+}
@@ -0,0 +1,60 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to read and drive the sideband pins of a node.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::node_pins::{list_pins, set_pin};
+use crate::error::BmcError;
+use crate::hal::NodeId;
+use actix_web::{get, put, web};
+use serde::Deserialize;
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_pins).service(put_pin);
+}
+
+#[derive(Debug, Deserialize)]
+struct PinRequest {
+    value: bool,
+}
+
+fn node_id(node: u8) -> Result<NodeId, BmcError> {
+    node.checked_sub(1)
+        .and_then(|n| NodeId::try_from(n).ok())
+        .ok_or_else(|| BmcError::invalid_parameter("node", "must be 1 to 4"))
+}
+
+#[get("/nodes/{node}/pins")]
+async fn get_pins(bmc: web::Data<BmcApplication>, node: web::Path<u8>) -> LegacyResponse {
+    let node = match node_id(*node) {
+        Ok(node) => node,
+        Err(e) => return e.into(),
+    };
+    list_pins(&bmc, node).map(|pins| json!(pins)).into()
+}
+
+#[put("/nodes/{node}/pins/{pin}")]
+async fn put_pin(
+    bmc: web::Data<BmcApplication>,
+    path: web::Path<(u8, String)>,
+    request: web::Json<PinRequest>,
+) -> LegacyResponse {
+    let (node, pin) = path.into_inner();
+    let node = match node_id(node) {
+        Ok(node) => node,
+        Err(e) => return e.into(),
+    };
+    set_pin(&bmc, node, &pin, request.value).await.into()
+}
@@ -0,0 +1,55 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to read and reconcile the declarative state of the nodes, see
+//! [`crate::app::node_state`].
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::node_state::{current_state, reconcile, State};
+use actix_web::{get, put, web};
+use serde::Deserialize;
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_state).service(put_state);
+}
+
+#[derive(Debug, Deserialize)]
+struct StateQuery {
+    /// report what would change without changing it
+    #[serde(default)]
+    check: bool,
+}
+
+#[get("/state")]
+async fn get_state(bmc: web::Data<BmcApplication>) -> LegacyResponse {
+    current_state(&bmc).await.map(|state| json!(state)).into()
+}
+
+/// Answers `200 OK` with the result of every field, also when some of them
+/// failed to change. The document is rejected as a whole when it does not
+/// validate.
+#[put("/state")]
+async fn put_state(
+    bmc: web::Data<BmcApplication>,
+    query: web::Query<StateQuery>,
+    desired: web::Json<State>,
+) -> LegacyResponse {
+    if let Err(e) = desired.validate(bmc.board().node_count) {
+        return e.into();
+    }
+    reconcile(&bmc, &desired, query.check)
+        .await
+        .map(|report| json!(report))
+        .into()
+}
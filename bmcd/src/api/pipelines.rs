@@ -0,0 +1,187 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to manage pipelines and run them as a job, and the webhook route
+//! of the configured pipelines. The webhook route is served outside of
+//! `/api/bmc` and without authentication, as the signature of the body
+//! authenticates the CI system.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::config_service::ConfigService;
+use crate::app::pipelines::{
+    self, HookError, PipelineError, Pipelines, Trigger, MAX_PIPELINES, SIGNATURE_HEADER,
+};
+use crate::error::ErrorCode;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, put, web, HttpRequest};
+use serde_json::{json, Value};
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_pipelines)
+        .service(get_pipeline)
+        .service(put_pipeline)
+        .service(delete_pipeline)
+        .service(run_pipeline);
+}
+
+pub fn hooks_config(
+    cfg: &mut web::ServiceConfig,
+    pipelines: web::Data<Pipelines>,
+    config: web::Data<ConfigService>,
+) {
+    cfg.service(
+        web::resource("/hooks/{pipeline}")
+            .app_data(pipelines)
+            .app_data(config)
+            .route(web::post().to(receive_event)),
+    );
+}
+
+impl ErrorCode for PipelineError {
+    fn code(&self) -> &'static str {
+        match self {
+            PipelineError::NotFound(_) => "not_found",
+            PipelineError::TooManyPipelines => "limit_reached",
+            PipelineError::InvalidName(_) => "invalid_name",
+            PipelineError::Invalid(_) => "invalid_definition",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            PipelineError::NotFound(_) => StatusCode::NOT_FOUND,
+            PipelineError::TooManyPipelines => StatusCode::INSUFFICIENT_STORAGE,
+            PipelineError::InvalidName(_) | PipelineError::Invalid(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn details(&self) -> Value {
+        match self {
+            PipelineError::NotFound(name) | PipelineError::InvalidName(name) => {
+                json!({ "name": name })
+            }
+            PipelineError::TooManyPipelines => json!({ "limit": MAX_PIPELINES }),
+            PipelineError::Invalid(reason) => json!({ "reason": reason }),
+        }
+    }
+}
+
+impl ErrorCode for HookError {
+    fn code(&self) -> &'static str {
+        match self {
+            HookError::UnknownPipeline(_) => "not_found",
+            HookError::BadSignature => "bad_signature",
+            HookError::InvalidEvent(_) => "invalid_event",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            HookError::UnknownPipeline(_) => StatusCode::NOT_FOUND,
+            HookError::BadSignature => StatusCode::UNAUTHORIZED,
+            HookError::InvalidEvent(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn details(&self) -> Value {
+        match self {
+            HookError::UnknownPipeline(name) => json!({ "name": name }),
+            HookError::BadSignature => json!({ "header": SIGNATURE_HEADER }),
+            HookError::InvalidEvent(reason) => json!({ "reason": reason }),
+        }
+    }
+}
+
+#[get("/pipelines")]
+async fn list_pipelines(bmc: web::Data<BmcApplication>) -> LegacyResponse {
+    json!(pipelines::list_pipelines(&bmc).await).into()
+}
+
+#[get("/pipelines/{name}")]
+async fn get_pipeline(bmc: web::Data<BmcApplication>, name: web::Path<String>) -> LegacyResponse {
+    pipelines::get_pipeline(&bmc, &name)
+        .await
+        .map(|definition| json!(definition))
+        .into()
+}
+
+/// Takes the definition as JSON, or as YAML with a YAML content type.
+#[put("/pipelines/{name}")]
+async fn put_pipeline(
+    request: HttpRequest,
+    bmc: web::Data<BmcApplication>,
+    name: web::Path<String>,
+    body: web::Bytes,
+) -> LegacyResponse {
+    let yaml = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("yaml"));
+    let definition = match pipelines::parse_definition(&body, yaml) {
+        Ok(definition) => definition,
+        Err(e) => return e.into(),
+    };
+    pipelines::save_pipeline(&bmc, &name, &definition)
+        .await
+        .into()
+}
+
+#[delete("/pipelines/{name}")]
+async fn delete_pipeline(
+    bmc: web::Data<BmcApplication>,
+    name: web::Path<String>,
+) -> LegacyResponse {
+    pipelines::delete_pipeline(&bmc, &name).await.into()
+}
+
+/// Starts a job that runs the pipeline; poll `/jobs/{id}` for the state of
+/// every step.
+#[post("/pipelines/{name}/run")]
+async fn run_pipeline(pipelines: web::Data<Pipelines>, name: web::Path<String>) -> LegacyResponse {
+    pipelines
+        .run(&name)
+        .await
+        .map(|id| json!({ "id": id }))
+        .into()
+}
+
+/// Starts the pipeline when the event matches; poll `/api/bmc/jobs/{id}`
+/// for its outcome.
+async fn receive_event(
+    request: HttpRequest,
+    pipelines: web::Data<Pipelines>,
+    config: web::Data<ConfigService>,
+    name: web::Path<String>,
+    body: web::Bytes,
+) -> LegacyResponse {
+    // the configuration is read per event, so that reloads apply
+    let config = config.current();
+    let Some(pipeline) = config.pipelines.iter().find(|p| p.name == *name) else {
+        return HookError::UnknownPipeline(name.into_inner()).into();
+    };
+    let signature = request
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .and_then(|v| v.to_str().ok());
+
+    match pipelines.trigger(pipeline, &body, signature) {
+        Ok(Trigger::Started(id)) => json!({ "id": id }).into(),
+        Ok(Trigger::Ignored) => json!({ "ignored": true }).into(),
+        Err(e) => {
+            tracing::warn!("webhook of pipeline {}: {}", pipeline.name, e);
+            e.into()
+        }
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to follow the plugins and to start, stop and restart them.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::plugins::Plugins;
+use actix_web::{get, post, web};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_plugins)
+        .service(get_plugin)
+        .service(start_plugin)
+        .service(stop_plugin)
+        .service(restart_plugin);
+}
+
+#[get("/plugins")]
+async fn list_plugins(plugins: web::Data<Plugins>) -> LegacyResponse {
+    json!(plugins.list()).into()
+}
+
+#[get("/plugins/{name}")]
+async fn get_plugin(plugins: web::Data<Plugins>, name: web::Path<String>) -> LegacyResponse {
+    plugins.get(&name).map(|status| json!(status)).into()
+}
+
+#[post("/plugins/{name}/start")]
+async fn start_plugin(plugins: web::Data<Plugins>, name: web::Path<String>) -> LegacyResponse {
+    plugins.start(&name).into()
+}
+
+#[post("/plugins/{name}/stop")]
+async fn stop_plugin(plugins: web::Data<Plugins>, name: web::Path<String>) -> LegacyResponse {
+    plugins.stop(&name).into()
+}
+
+#[post("/plugins/{name}/restart")]
+async fn restart_plugin(plugins: web::Data<Plugins>, name: web::Path<String>) -> LegacyResponse {
+    plugins.restart(&name).into()
+}
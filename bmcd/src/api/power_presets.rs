@@ -0,0 +1,104 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to manage power presets and apply them as a job.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::jobs::Jobs;
+use crate::app::power_presets::{self, PowerPreset, PresetError, MAX_PRESETS};
+use crate::error::ErrorCode;
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, put, web};
+use serde_json::{json, Value};
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_presets)
+        .service(get_preset)
+        .service(put_preset)
+        .service(delete_preset)
+        .service(apply_preset);
+}
+
+impl ErrorCode for PresetError {
+    fn code(&self) -> &'static str {
+        match self {
+            PresetError::NotFound(_) => "not_found",
+            PresetError::TooManyPresets => "limit_reached",
+            PresetError::BuiltIn(_) => "built_in",
+            PresetError::InvalidName(_) => "invalid_name",
+            PresetError::Invalid(_) => "invalid_definition",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            PresetError::NotFound(_) => StatusCode::NOT_FOUND,
+            PresetError::TooManyPresets => StatusCode::INSUFFICIENT_STORAGE,
+            PresetError::BuiltIn(_) => StatusCode::FORBIDDEN,
+            PresetError::InvalidName(_) | PresetError::Invalid(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn details(&self) -> Value {
+        match self {
+            PresetError::NotFound(name)
+            | PresetError::BuiltIn(name)
+            | PresetError::InvalidName(name) => json!({ "name": name }),
+            PresetError::TooManyPresets => json!({ "limit": MAX_PRESETS }),
+            PresetError::Invalid(reason) => json!({ "reason": reason }),
+        }
+    }
+}
+
+#[get("/power-presets")]
+async fn list_presets(bmc: web::Data<BmcApplication>) -> LegacyResponse {
+    json!(power_presets::list_presets(&bmc).await).into()
+}
+
+#[get("/power-presets/{name}")]
+async fn get_preset(bmc: web::Data<BmcApplication>, name: web::Path<String>) -> LegacyResponse {
+    power_presets::get_preset(&bmc, &name)
+        .await
+        .map(|preset| json!(preset))
+        .into()
+}
+
+#[put("/power-presets/{name}")]
+async fn put_preset(
+    bmc: web::Data<BmcApplication>,
+    name: web::Path<String>,
+    preset: web::Json<PowerPreset>,
+) -> LegacyResponse {
+    power_presets::save_preset(&bmc, &name, preset.into_inner())
+        .await
+        .into()
+}
+
+#[delete("/power-presets/{name}")]
+async fn delete_preset(bmc: web::Data<BmcApplication>, name: web::Path<String>) -> LegacyResponse {
+    power_presets::delete_preset(&bmc, &name).await.into()
+}
+
+/// Starts a job that applies the preset; poll `/jobs/{id}` for the outcome
+/// of every step.
+#[post("/power-presets/{name}/apply")]
+async fn apply_preset(
+    bmc: web::Data<BmcApplication>,
+    jobs: web::Data<Jobs>,
+    name: web::Path<String>,
+) -> LegacyResponse {
+    power_presets::start_preset_job(bmc.into_inner(), jobs.into_inner(), &name)
+        .await
+        .map(|id| json!({ "id": id }))
+        .into()
+}
@@ -0,0 +1,52 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to switch the ATX power supply of the board.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::bmc_application::BmcApplication;
+use crate::error::BmcError;
+use actix_web::{get, put, web};
+use serde::Deserialize;
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_power_supply).service(set_power_supply);
+}
+
+#[derive(Debug, Deserialize)]
+struct PowerSupplyRequest {
+    enabled: bool,
+}
+
+#[get("/power-supply")]
+async fn get_power_supply(bmc: web::Data<BmcApplication>) -> LegacyResponse {
+    match bmc.power_supply() {
+        Ok(Some(state)) => json!(state).into(),
+        Ok(None) => {
+            BmcError::NotSupported("this board cannot switch its power supply".into()).into()
+        }
+        Err(e) => e.into(),
+    }
+}
+
+/// Switching the supply off powers all nodes off first.
+#[put("/power-supply")]
+async fn set_power_supply(
+    bmc: web::Data<BmcApplication>,
+    request: web::Json<PowerSupplyRequest>,
+) -> LegacyResponse {
+    bmc.set_power_supply(request.enabled)
+        .await
+        .map(|_| json!(bmc.power_supply().ok().flatten()))
+        .into()
+}
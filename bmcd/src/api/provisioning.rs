@@ -0,0 +1,191 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes of the first-boot setup, see `app::provisioning`. They are served
+//! without authentication, to local clients only, and only until
+//! provisioning completes. The middleware [`refuse_until_provisioned`] locks
+//! the rest of the API in the meantime.
+use crate::api::http_policy::client_address;
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::network_config::{NetworkConfigurator, NetworkSettings};
+use crate::app::provisioning::{is_local_client, Provisioning, ProvisioningError};
+use crate::app::time_sync::TimeSettings;
+use crate::authentication::authentication_service::LocalConnection;
+use crate::utils::local_networks;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::{ErrorForbidden, ErrorServiceUnavailable};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{get, post, web, Error};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_provisioning)
+        .service(set_credentials)
+        .service(set_hostname)
+        .service(set_time)
+        .service(set_network)
+        .service(complete);
+}
+
+#[derive(Debug, Deserialize)]
+struct Credentials {
+    password: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Hostname {
+    hostname: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SetTime {
+    /// RFC 3339 timestamp, e.g. `2024-01-31T12:00:00Z`
+    time: Option<DateTime<Utc>>,
+    ntp: Option<TimeSettings>,
+}
+
+#[get("")]
+async fn get_provisioning(provisioning: web::Data<Provisioning>) -> LegacyResponse {
+    json!(provisioning.status()).into()
+}
+
+#[post("/credentials")]
+async fn set_credentials(
+    provisioning: web::Data<Provisioning>,
+    credentials: web::Json<Credentials>,
+) -> LegacyResponse {
+    provisioning
+        .set_credentials(&credentials.password)
+        .await
+        .map_err(error_response)
+        .into()
+}
+
+#[post("/hostname")]
+async fn set_hostname(
+    provisioning: web::Data<Provisioning>,
+    request: web::Json<Hostname>,
+) -> LegacyResponse {
+    provisioning
+        .set_hostname(&request.hostname)
+        .await
+        .map_err(error_response)
+        .into()
+}
+
+#[post("/time")]
+async fn set_time(
+    provisioning: web::Data<Provisioning>,
+    bmc: web::Data<BmcApplication>,
+    request: web::Json<SetTime>,
+) -> LegacyResponse {
+    let SetTime { time, ntp } = request.into_inner();
+    if time.is_none() && ntp.is_none() {
+        return LegacyResponse::bad_request("set `time`, `ntp` or both");
+    }
+    provisioning
+        .set_time(&bmc, time, ntp)
+        .await
+        .map_err(error_response)
+        .into()
+}
+
+/// Applies network settings, which are confirmed by `/provisioning/complete`.
+#[post("/network")]
+async fn set_network(
+    provisioning: web::Data<Provisioning>,
+    network: web::Data<NetworkConfigurator>,
+    settings: web::Json<NetworkSettings>,
+) -> LegacyResponse {
+    match provisioning
+        .set_network(&network, settings.into_inner())
+        .await
+    {
+        Ok(()) => json!({ "rollback_at": network.rollback_deadline() }).into(),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/complete")]
+async fn complete(
+    provisioning: web::Data<Provisioning>,
+    network: web::Data<NetworkConfigurator>,
+) -> LegacyResponse {
+    provisioning
+        .complete(&network)
+        .await
+        .map_err(error_response)
+        .into()
+}
+
+fn error_response(e: anyhow::Error) -> LegacyResponse {
+    let status = match e.downcast_ref::<ProvisioningError>() {
+        Some(ProvisioningError::NotActive | ProvisioningError::CredentialsMissing) => {
+            StatusCode::CONFLICT
+        }
+        Some(_) => StatusCode::BAD_REQUEST,
+        None => return e.into(),
+    };
+    LegacyResponse::Error(status, e.to_string().into())
+}
+
+/// The BMC itself, over loopback or the unix socket.
+fn is_on_bmc(request: &ServiceRequest) -> bool {
+    request.conn_data::<LocalConnection>().is_some()
+        || client_address(request.request()).is_some_and(|address| address.is_loopback())
+}
+
+/// Refuses clients that are not on a network of the BMC with
+/// `403 Forbidden`.
+pub async fn only_local_clients(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let local = is_on_bmc(&request)
+        || client_address(request.request()).is_some_and(|address| {
+            let networks = local_networks().unwrap_or_else(|e| {
+                tracing::warn!("listing networks of the BMC: {}", e);
+                Vec::new()
+            });
+            is_local_client(address, &networks)
+        });
+    if !local {
+        return Err(ErrorForbidden(
+            "provisioning is only possible from a network of the BMC",
+        ));
+    }
+    next.call(request).await
+}
+
+/// Requests of clients other than the BMC itself are refused with
+/// `503 Service Unavailable` until provisioning completes. Needs
+/// [`Provisioning`] in the application data.
+pub async fn refuse_until_provisioned(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let locked = request
+        .app_data::<web::Data<Provisioning>>()
+        .is_some_and(|provisioning| provisioning.is_active());
+    if locked && !is_on_bmc(&request) {
+        return Err(ErrorServiceUnavailable(
+            "the BMC is not provisioned, see /api/bmc/provisioning",
+        ));
+    }
+    next.call(request).await
+}
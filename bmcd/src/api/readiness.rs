@@ -0,0 +1,36 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Readiness of the subsystems that initialize in the background.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::readiness::Readiness;
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpResponse};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(ready);
+}
+
+/// Answers as soon as the API is up. The status is `503 Service
+/// Unavailable` until every subsystem is initialized.
+#[get("/ready")]
+async fn ready(readiness: web::Data<Readiness>) -> HttpResponse {
+    let status = readiness.status();
+    let ready = status.ready;
+    let mut response: HttpResponse = LegacyResponse::ok(json!(status)).into();
+    if !ready {
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    response
+}
@@ -0,0 +1,484 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Resource-style routes for infrastructure-as-code tools such as a Terraform
+//! or OpenTofu provider, see [`crate::app::resources`]. Responses carry the
+//! ETag of the resource in the `ETag` header, lists carry it in an `etag`
+//! field. `PUT` replaces the whole resource and answers `201 Created` when
+//! it did not exist before.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::batch::node_id;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::netboot::Netboot;
+use crate::app::resources::{
+    etag, node_config, set_node_config, NodeConfig, Precondition, Resources,
+};
+use crate::app::users;
+use crate::app::wake_alarm::{clear_wake_alarm, get_wake_alarm, set_wake_alarm, WakeAlarm};
+use crate::error::BmcError;
+use crate::hal::rtc::Rtc;
+use crate::hal::NodeId;
+use actix_web::http::header::{HeaderName, HeaderValue, ETAG, IF_MATCH, IF_NONE_MATCH};
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, put, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_nodes)
+        .service(get_node)
+        .service(put_node)
+        .service(delete_node)
+        .service(list_netboot)
+        .service(get_netboot)
+        .service(put_netboot)
+        .service(delete_netboot)
+        .service(get_wake_alarm_resource)
+        .service(put_wake_alarm)
+        .service(delete_wake_alarm)
+        .service(list_users)
+        .service(get_user)
+        .service(put_user)
+        .service(delete_user);
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NetbootEntry {
+    /// path relative to the netboot directory
+    boot_file: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserRequest {
+    /// required to create an account, left unchanged when omitted
+    password: Option<String>,
+}
+
+fn precondition(request: &HttpRequest) -> Precondition {
+    let tags = |name: HeaderName| -> Vec<String> {
+        request
+            .headers()
+            .get_all(name)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|tag| tag.trim().trim_start_matches("W/").to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect()
+    };
+    let if_match = tags(IF_MATCH);
+    if !if_match.is_empty() {
+        Precondition::IfMatch(if_match)
+    } else if tags(IF_NONE_MATCH).iter().any(|tag| tag == "*") {
+        Precondition::IfNoneMatch
+    } else {
+        Precondition::None
+    }
+}
+
+fn with_etag(response: impl Into<LegacyResponse>, etag: &str, status: StatusCode) -> HttpResponse {
+    let mut response = HttpResponse::from(response.into());
+    *response.status_mut() = status;
+    if let Ok(value) = HeaderValue::from_str(etag) {
+        response.headers_mut().insert(ETAG, value);
+    }
+    response
+}
+
+fn respond(response: impl Into<LegacyResponse>) -> HttpResponse {
+    HttpResponse::from(response.into())
+}
+
+fn created(existed: bool) -> StatusCode {
+    if existed {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    }
+}
+
+fn slot(bmc: &BmcApplication, node: u8) -> Result<NodeId, HttpResponse> {
+    node_id(node, bmc.board().node_count).map_err(respond)
+}
+
+#[get("/resources/nodes")]
+async fn list_nodes(bmc: web::Data<BmcApplication>) -> LegacyResponse {
+    let mut nodes = Vec::new();
+    for idx in 0..bmc.board().node_count as u8 {
+        let node = NodeId::try_from(idx).expect("index is a node");
+        let config = node_config(&bmc, node).await;
+        nodes.push(json!({
+            "node": idx + 1,
+            "etag": etag(&config),
+            "name": config.name,
+            "module_name": config.module_name,
+            "uart_baud": config.uart_baud,
+        }));
+    }
+    json!(nodes).into()
+}
+
+#[get("/resources/nodes/{node}")]
+async fn get_node(bmc: web::Data<BmcApplication>, node: web::Path<u8>) -> HttpResponse {
+    let node = match slot(&bmc, *node) {
+        Ok(node) => node,
+        Err(response) => return response,
+    };
+    let config = node_config(&bmc, node).await;
+    with_etag(json!(config), &etag(&config), StatusCode::OK)
+}
+
+#[put("/resources/nodes/{node}")]
+async fn put_node(
+    bmc: web::Data<BmcApplication>,
+    resources: web::Data<Resources>,
+    request: HttpRequest,
+    node: web::Path<u8>,
+    config: web::Json<NodeConfig>,
+) -> HttpResponse {
+    let node = match slot(&bmc, *node) {
+        Ok(node) => node,
+        Err(response) => return response,
+    };
+    let _lock = resources.lock().await;
+    let current = etag(&node_config(&bmc, node).await);
+    if let Err(e) = precondition(&request).check(&node.to_string(), Some(&current)) {
+        return respond(e);
+    }
+    let config = config.into_inner();
+    let etag = etag(&config);
+    set_node_config(&bmc, node, config.clone()).await;
+    with_etag(json!(config), &etag, StatusCode::OK)
+}
+
+/// Clears the meta-data of the node.
+#[delete("/resources/nodes/{node}")]
+async fn delete_node(
+    bmc: web::Data<BmcApplication>,
+    resources: web::Data<Resources>,
+    request: HttpRequest,
+    node: web::Path<u8>,
+) -> HttpResponse {
+    let node = match slot(&bmc, *node) {
+        Ok(node) => node,
+        Err(response) => return response,
+    };
+    let _lock = resources.lock().await;
+    let current = etag(&node_config(&bmc, node).await);
+    if let Err(e) = precondition(&request).check(&node.to_string(), Some(&current)) {
+        return respond(e);
+    }
+    set_node_config(&bmc, node, NodeConfig::default()).await;
+    with_etag((), &etag(&NodeConfig::default()), StatusCode::OK)
+}
+
+fn netboot_entry(netboot: &Netboot, node: NodeId) -> Option<NetbootEntry> {
+    netboot.boot_files()[node as usize]
+        .clone()
+        .map(|boot_file| NetbootEntry { boot_file })
+}
+
+fn netboot_resource(node: NodeId) -> String {
+    format!("netboot entry of {}", node)
+}
+
+#[get("/resources/netboot")]
+async fn list_netboot(
+    bmc: web::Data<BmcApplication>,
+    netboot: web::Data<Netboot>,
+) -> LegacyResponse {
+    let entries: Vec<_> = (0..bmc.board().node_count as u8)
+        .filter_map(|idx| {
+            let node = NodeId::try_from(idx).ok()?;
+            let entry = netboot_entry(&netboot, node)?;
+            Some(json!({
+                "node": idx + 1,
+                "etag": etag(&entry),
+                "boot_file": entry.boot_file,
+            }))
+        })
+        .collect();
+    json!(entries).into()
+}
+
+#[get("/resources/netboot/{node}")]
+async fn get_netboot(
+    bmc: web::Data<BmcApplication>,
+    netboot: web::Data<Netboot>,
+    node: web::Path<u8>,
+) -> HttpResponse {
+    let node = match slot(&bmc, *node) {
+        Ok(node) => node,
+        Err(response) => return response,
+    };
+    match netboot_entry(&netboot, node) {
+        Some(entry) => with_etag(json!(entry), &etag(&entry), StatusCode::OK),
+        None => respond(BmcError::NotFound(netboot_resource(node).into())),
+    }
+}
+
+#[put("/resources/netboot/{node}")]
+async fn put_netboot(
+    bmc: web::Data<BmcApplication>,
+    netboot: web::Data<Netboot>,
+    resources: web::Data<Resources>,
+    request: HttpRequest,
+    node: web::Path<u8>,
+    entry: web::Json<NetbootEntry>,
+) -> HttpResponse {
+    let node = match slot(&bmc, *node) {
+        Ok(node) => node,
+        Err(response) => return response,
+    };
+    let _lock = resources.lock().await;
+    let current = netboot_entry(&netboot, node).map(|entry| etag(&entry));
+    if let Err(e) = precondition(&request).check(&netboot_resource(node), current.as_deref()) {
+        return respond(e);
+    }
+    let entry = entry.into_inner();
+    let result = netboot
+        .set_boot_file(&bmc, node as u8 + 1, Some(entry.boot_file.clone()))
+        .await;
+    match result {
+        Ok(()) => with_etag(json!(entry), &etag(&entry), created(current.is_some())),
+        Err(e) => respond(LegacyResponse::bad_request(format!("{:#}", e))),
+    }
+}
+
+#[delete("/resources/netboot/{node}")]
+async fn delete_netboot(
+    bmc: web::Data<BmcApplication>,
+    netboot: web::Data<Netboot>,
+    resources: web::Data<Resources>,
+    request: HttpRequest,
+    node: web::Path<u8>,
+) -> HttpResponse {
+    let node = match slot(&bmc, *node) {
+        Ok(node) => node,
+        Err(response) => return response,
+    };
+    let _lock = resources.lock().await;
+    let Some(current) = netboot_entry(&netboot, node).map(|entry| etag(&entry)) else {
+        return respond(BmcError::NotFound(netboot_resource(node).into()));
+    };
+    if let Err(e) = precondition(&request).check(&netboot_resource(node), Some(&current)) {
+        return respond(e);
+    }
+    respond(netboot.set_boot_file(&bmc, node as u8 + 1, None).await)
+}
+
+const WAKE_ALARM: &str = "wake alarm";
+
+async fn current_wake_alarm(
+    bmc: &BmcApplication,
+    rtc: &Rtc,
+) -> Result<Option<WakeAlarm>, HttpResponse> {
+    if !rtc.is_available() {
+        return Err(respond(BmcError::NotSupported("board has no RTC".into())));
+    }
+    get_wake_alarm(bmc, rtc)
+        .await
+        .map(|status| status.alarm)
+        .map_err(|e| respond(e.context("read wake alarm")))
+}
+
+#[get("/resources/schedules/wake-alarm")]
+async fn get_wake_alarm_resource(
+    bmc: web::Data<BmcApplication>,
+    rtc: web::Data<Rtc>,
+) -> HttpResponse {
+    match current_wake_alarm(&bmc, &rtc).await {
+        Ok(Some(alarm)) => with_etag(json!(alarm), &etag(&alarm), StatusCode::OK),
+        Ok(None) => respond(BmcError::NotFound(WAKE_ALARM.into())),
+        Err(response) => response,
+    }
+}
+
+#[put("/resources/schedules/wake-alarm")]
+async fn put_wake_alarm(
+    bmc: web::Data<BmcApplication>,
+    rtc: web::Data<Rtc>,
+    resources: web::Data<Resources>,
+    request: HttpRequest,
+    alarm: web::Json<WakeAlarm>,
+) -> HttpResponse {
+    let _lock = resources.lock().await;
+    let current = match current_wake_alarm(&bmc, &rtc).await {
+        Ok(alarm) => alarm.map(|alarm| etag(&alarm)),
+        Err(response) => return response,
+    };
+    if let Err(e) = precondition(&request).check(WAKE_ALARM, current.as_deref()) {
+        return respond(e);
+    }
+    let alarm = alarm.into_inner();
+    match set_wake_alarm(&bmc, &rtc, alarm.clone()).await {
+        Ok(()) => with_etag(json!(alarm), &etag(&alarm), created(current.is_some())),
+        Err(e) => respond(LegacyResponse::bad_request(format!("{:#}", e))),
+    }
+}
+
+#[delete("/resources/schedules/wake-alarm")]
+async fn delete_wake_alarm(
+    bmc: web::Data<BmcApplication>,
+    rtc: web::Data<Rtc>,
+    resources: web::Data<Resources>,
+    request: HttpRequest,
+) -> HttpResponse {
+    let _lock = resources.lock().await;
+    let current = match current_wake_alarm(&bmc, &rtc).await {
+        Ok(Some(alarm)) => etag(&alarm),
+        Ok(None) => return respond(BmcError::NotFound(WAKE_ALARM.into())),
+        Err(response) => return response,
+    };
+    if let Err(e) = precondition(&request).check(WAKE_ALARM, Some(&current)) {
+        return respond(e);
+    }
+    respond(clear_wake_alarm(&bmc, &rtc).await)
+}
+
+fn account_resource(name: &str) -> String {
+    format!("account {}", name)
+}
+
+#[get("/resources/users")]
+async fn list_users() -> LegacyResponse {
+    match users::accounts().await {
+        Ok(accounts) => {
+            let accounts: Vec<_> = accounts
+                .into_iter()
+                .map(|(account, hash)| {
+                    json!({
+                        "etag": etag(&(&account, &hash)),
+                        "name": account.name,
+                        "uid": account.uid,
+                        "password_set": account.password_set,
+                    })
+                })
+                .collect();
+            json!(accounts).into()
+        }
+        Err(e) => e.context("list accounts").into(),
+    }
+}
+
+async fn current_account(name: &str) -> Result<Option<(users::Account, String)>, HttpResponse> {
+    if let Err(e) = users::validate_name(name) {
+        return Err(respond(LegacyResponse::bad_request(format!("{:#}", e))));
+    }
+    users::account(name)
+        .await
+        .map_err(|e| respond(e.context("read accounts")))
+}
+
+#[get("/resources/users/{name}")]
+async fn get_user(name: web::Path<String>) -> HttpResponse {
+    match current_account(&name).await {
+        Ok(Some((account, hash))) => {
+            with_etag(json!(account), &etag(&(&account, &hash)), StatusCode::OK)
+        }
+        Ok(None) => respond(BmcError::NotFound(account_resource(&name).into())),
+        Err(response) => response,
+    }
+}
+
+/// Creates the account or changes its password.
+#[put("/resources/users/{name}")]
+async fn put_user(
+    resources: web::Data<Resources>,
+    request: HttpRequest,
+    name: web::Path<String>,
+    user: web::Json<UserRequest>,
+) -> HttpResponse {
+    let _lock = resources.lock().await;
+    let current = match current_account(&name).await {
+        Ok(account) => account.map(|(account, hash)| etag(&(&account, &hash))),
+        Err(response) => return response,
+    };
+    if let Err(e) = precondition(&request).check(&account_resource(&name), current.as_deref()) {
+        return respond(e);
+    }
+    match (&user.password, &current) {
+        (Some(password), _) => {
+            if let Err(e) = users::set_password(&name, password, current.is_none()).await {
+                return respond(e.context(account_resource(&name)));
+            }
+        }
+        (None, Some(_)) => {}
+        (None, None) => {
+            let e = BmcError::invalid_parameter("password", "required to create an account");
+            return respond(e);
+        }
+    }
+    match current_account(&name).await {
+        Ok(Some((account, hash))) => with_etag(
+            json!(account),
+            &etag(&(&account, &hash)),
+            created(current.is_some()),
+        ),
+        Ok(None) => respond(BmcError::NotFound(account_resource(&name).into())),
+        Err(response) => response,
+    }
+}
+
+#[delete("/resources/users/{name}")]
+async fn delete_user(
+    resources: web::Data<Resources>,
+    request: HttpRequest,
+    name: web::Path<String>,
+) -> HttpResponse {
+    let _lock = resources.lock().await;
+    let current = match current_account(&name).await {
+        Ok(Some((account, hash))) => etag(&(&account, &hash)),
+        Ok(None) => return respond(BmcError::NotFound(account_resource(&name).into())),
+        Err(response) => return response,
+    };
+    if let Err(e) = precondition(&request).check(&account_resource(&name), Some(&current)) {
+        return respond(e);
+    }
+    respond(users::delete(&name).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn precondition_headers() {
+        let request = TestRequest::default()
+            .insert_header((IF_MATCH, "\"a\", W/\"b\""))
+            .to_http_request();
+        assert_eq!(
+            precondition(&request),
+            Precondition::IfMatch(vec!["\"a\"".to_string(), "\"b\"".to_string()])
+        );
+        let request = TestRequest::default()
+            .insert_header((IF_NONE_MATCH, "*"))
+            .to_http_request();
+        assert_eq!(precondition(&request), Precondition::IfNoneMatch);
+        let request = TestRequest::default().to_http_request();
+        assert_eq!(precondition(&request), Precondition::None);
+    }
+
+    #[test]
+    fn etag_header() {
+        let response = with_etag(
+            json!({ "boot_file": "rk1/boot.ipxe" }),
+            "\"abc\"",
+            StatusCode::CREATED,
+        );
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers().get(ETAG).unwrap(), "\"abc\"");
+    }
+}
@@ -0,0 +1,32 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Route that reports the files bmcd keeps on flash per category, see
+//! `app::retention`.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::retention::Reaper;
+use actix_web::{get, web};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_retention);
+}
+
+#[get("/retention")]
+async fn get_retention(reaper: web::Data<Reaper>) -> LegacyResponse {
+    let reaper = reaper.into_inner();
+    tokio::task::spawn_blocking(move || json!({ "categories": reaper.usage() }))
+        .await
+        .map_err(anyhow::Error::from)
+        .into()
+}
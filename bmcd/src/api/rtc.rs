@@ -0,0 +1,195 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to read and set the RTC and to schedule wake alarms and recurring
+//! wake schedules.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::wake_alarm::{
+    clear_wake_alarm, get_wake_alarm, power_down, set_wake_alarm, WakeAlarm,
+};
+use crate::app::wake_schedule::{
+    add_schedule, list_schedules, remove_schedule, WakeSchedule, DEFAULT_CATCH_UP,
+};
+use crate::error::BmcError;
+use crate::hal::rtc::Rtc;
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, put, web};
+use chrono::{DateTime, Local, NaiveTime, Utc, Weekday};
+use serde::Deserialize;
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_rtc)
+        .service(set_rtc)
+        .service(get_alarm)
+        .service(set_alarm)
+        .service(delete_alarm)
+        .service(power_down_until_alarm)
+        .service(get_schedules)
+        .service(post_schedule)
+        .service(delete_schedule);
+}
+
+#[derive(Debug, Deserialize)]
+struct SetRtc {
+    /// RFC 3339 timestamp, the system time when omitted
+    time: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewSchedule {
+    nodes: Vec<u8>,
+    /// local time of day, `HH:MM`
+    time: String,
+    /// e.g. `["Mon", "Fri"]`, every day when omitted
+    #[serde(default)]
+    days: Vec<Weekday>,
+    /// seconds
+    catch_up: Option<u64>,
+}
+
+fn unavailable() -> LegacyResponse {
+    LegacyResponse::Error(StatusCode::NOT_FOUND, "board has no RTC".into())
+}
+
+#[get("/rtc")]
+async fn get_rtc(rtc: web::Data<Rtc>) -> LegacyResponse {
+    if !rtc.is_available() {
+        return unavailable();
+    }
+    let status = rtc.time().and_then(|time| {
+        Ok(json!({
+            "device": rtc.device(),
+            "time": time,
+            "system_time": Utc::now(),
+            "wake_alarm": rtc.wake_alarm()?,
+        }))
+    });
+    match status {
+        Ok(status) => status.into(),
+        Err(e) => e.context("read RTC").into(),
+    }
+}
+
+#[put("/rtc")]
+async fn set_rtc(rtc: web::Data<Rtc>, request: web::Json<SetRtc>) -> LegacyResponse {
+    if !rtc.is_available() {
+        return unavailable();
+    }
+    rtc.set_time(request.time.unwrap_or_else(Utc::now)).into()
+}
+
+#[get("/rtc/alarm")]
+async fn get_alarm(bmc: web::Data<BmcApplication>, rtc: web::Data<Rtc>) -> LegacyResponse {
+    if !rtc.is_available() {
+        return unavailable();
+    }
+    match get_wake_alarm(&bmc, &rtc).await {
+        Ok(status) => json!(status).into(),
+        Err(e) => e.context("read wake alarm").into(),
+    }
+}
+
+#[put("/rtc/alarm")]
+async fn set_alarm(
+    bmc: web::Data<BmcApplication>,
+    rtc: web::Data<Rtc>,
+    alarm: web::Json<WakeAlarm>,
+) -> LegacyResponse {
+    if !rtc.is_available() {
+        return unavailable();
+    }
+    set_wake_alarm(&bmc, &rtc, alarm.into_inner())
+        .await
+        .map_err(|e| LegacyResponse::Error(StatusCode::BAD_REQUEST, format!("{:#}", e).into()))
+        .into()
+}
+
+#[delete("/rtc/alarm")]
+async fn delete_alarm(bmc: web::Data<BmcApplication>, rtc: web::Data<Rtc>) -> LegacyResponse {
+    if !rtc.is_available() {
+        return unavailable();
+    }
+    clear_wake_alarm(&bmc, &rtc).await.into()
+}
+
+/// Powers off the nodes and the BMC until the wake alarm fires.
+#[post("/rtc/alarm/power-down")]
+async fn power_down_until_alarm(
+    bmc: web::Data<BmcApplication>,
+    rtc: web::Data<Rtc>,
+) -> LegacyResponse {
+    if !rtc.is_available() {
+        return unavailable();
+    }
+    power_down(&bmc, &rtc)
+        .await
+        .map_err(|e| LegacyResponse::Error(StatusCode::CONFLICT, format!("{:#}", e).into()))
+        .into()
+}
+
+#[get("/rtc/schedules")]
+async fn get_schedules(bmc: web::Data<BmcApplication>) -> LegacyResponse {
+    let now = Local::now();
+    let schedules: Vec<_> = list_schedules(&bmc)
+        .await
+        .into_iter()
+        .map(|(id, schedule)| {
+            json!({
+                "id": id,
+                "nodes": schedule.nodes,
+                "time": schedule.time.format("%H:%M").to_string(),
+                "days": schedule.days,
+                "catch_up": schedule.catch_up,
+                "next": schedule.next(&now),
+                "last_run": schedule.last_run,
+            })
+        })
+        .collect();
+    json!({ "schedules": schedules }).into()
+}
+
+/// Adds a schedule, e.g. `{"nodes": [1], "time": "07:00"}`. Without an RTC it
+/// only applies while the BMC is running.
+#[post("/rtc/schedules")]
+async fn post_schedule(
+    bmc: web::Data<BmcApplication>,
+    rtc: web::Data<Rtc>,
+    request: web::Json<NewSchedule>,
+) -> LegacyResponse {
+    let request = request.into_inner();
+    let Ok(time) = NaiveTime::parse_from_str(&request.time, "%H:%M") else {
+        return BmcError::invalid_parameter("time", "must be formatted as HH:MM").into();
+    };
+    let schedule = WakeSchedule {
+        nodes: request.nodes,
+        time,
+        days: request.days,
+        catch_up: request.catch_up.unwrap_or(DEFAULT_CATCH_UP),
+        last_run: None,
+    };
+    match add_schedule(&bmc, &rtc, schedule).await {
+        Ok(id) => json!({ "id": id }).into(),
+        Err(e) => LegacyResponse::Error(StatusCode::BAD_REQUEST, format!("{:#}", e).into()),
+    }
+}
+
+#[delete("/rtc/schedules/{id}")]
+async fn delete_schedule(
+    bmc: web::Data<BmcApplication>,
+    rtc: web::Data<Rtc>,
+    id: web::Path<u32>,
+) -> LegacyResponse {
+    remove_schedule(&bmc, &rtc, *id).await.into()
+}
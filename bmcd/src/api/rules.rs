@@ -0,0 +1,78 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to manage the alert rules and inspect their evaluation state.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::rules::{Rule, RuleError, Rules, MAX_RULES};
+use crate::error::ErrorCode;
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, put, web};
+use serde_json::{json, Value};
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_rules)
+        .service(get_rule)
+        .service(put_rule)
+        .service(delete_rule);
+}
+
+impl ErrorCode for RuleError {
+    fn code(&self) -> &'static str {
+        match self {
+            RuleError::NotFound(_) => "not_found",
+            RuleError::TooManyRules => "limit_reached",
+            RuleError::InvalidName(_) => "invalid_name",
+            RuleError::Invalid(_) => "invalid_definition",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            RuleError::NotFound(_) => StatusCode::NOT_FOUND,
+            RuleError::TooManyRules => StatusCode::INSUFFICIENT_STORAGE,
+            RuleError::InvalidName(_) | RuleError::Invalid(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn details(&self) -> Value {
+        match self {
+            RuleError::NotFound(name) | RuleError::InvalidName(name) => json!({ "name": name }),
+            RuleError::TooManyRules => json!({ "limit": MAX_RULES }),
+            RuleError::Invalid(reason) => json!({ "reason": reason }),
+        }
+    }
+}
+
+#[get("/rules")]
+async fn list_rules(rules: web::Data<Rules>) -> LegacyResponse {
+    json!(rules.list().await).into()
+}
+
+#[get("/rules/{name}")]
+async fn get_rule(rules: web::Data<Rules>, name: web::Path<String>) -> LegacyResponse {
+    rules.get(&name).await.map(|rule| json!(rule)).into()
+}
+
+#[put("/rules/{name}")]
+async fn put_rule(
+    rules: web::Data<Rules>,
+    name: web::Path<String>,
+    rule: web::Json<Rule>,
+) -> LegacyResponse {
+    rules.save(&name, rule.into_inner()).await.into()
+}
+
+#[delete("/rules/{name}")]
+async fn delete_rule(rules: web::Data<Rules>, name: web::Path<String>) -> LegacyResponse {
+    rules.delete(&name).await.into()
+}
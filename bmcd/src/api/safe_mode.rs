@@ -0,0 +1,70 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes of safe mode, see [`crate::app::safe_mode`]. They are the only
+//! routes that are served in safe mode.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::safe_mode::SafeMode;
+use actix_web::{get, post, put, web};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_safe_mode)
+        .service(get_config)
+        .service(put_config)
+        .service(reset_store)
+        .service(exit);
+}
+
+#[get("/safe-mode")]
+async fn get_safe_mode(safe_mode: web::Data<SafeMode>) -> LegacyResponse {
+    json!(safe_mode.status()).into()
+}
+
+#[get("/safe-mode/config")]
+async fn get_config(safe_mode: web::Data<SafeMode>) -> LegacyResponse {
+    safe_mode
+        .read_config()
+        .await
+        .map(|content| {
+            json!({
+                "path": safe_mode.status().config_path,
+                "content": content,
+            })
+        })
+        .into()
+}
+
+/// Takes the new configuration file as request body. It is only written when
+/// it is valid.
+#[put("/safe-mode/config")]
+async fn put_config(safe_mode: web::Data<SafeMode>, content: String) -> LegacyResponse {
+    match safe_mode.write_config(&content).await {
+        Ok(()) => ().into(),
+        Err(e) => LegacyResponse::bad_request(format!("{:#}", e)),
+    }
+}
+
+#[post("/safe-mode/reset-store")]
+async fn reset_store(safe_mode: web::Data<SafeMode>) -> LegacyResponse {
+    safe_mode
+        .reset_store()
+        .await
+        .map(|backup| json!({ "backup": backup }))
+        .into()
+}
+
+#[post("/safe-mode/exit")]
+async fn exit(safe_mode: web::Data<SafeMode>) -> LegacyResponse {
+    safe_mode.exit().into()
+}
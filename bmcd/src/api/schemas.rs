@@ -0,0 +1,54 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes that serve the JSON schemas of the API, see [`crate::app::schemas`].
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::schemas;
+use crate::error::BmcError;
+use actix_web::http::header::{ETAG, IF_NONE_MATCH};
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_schemas).service(get_schema);
+}
+
+#[get("/schemas")]
+async fn list_schemas() -> LegacyResponse {
+    json!(schemas::index()).into()
+}
+
+/// The document as shipped. Its digest is the ETag, so that clients can
+/// cheaply check whether they are still in sync.
+#[get("/schemas/{name}")]
+async fn get_schema(request: HttpRequest, name: web::Path<String>) -> HttpResponse {
+    let Some(schema) = schemas::get(&name) else {
+        let error = BmcError::NotFound(format!("schema {}", name).into());
+        return LegacyResponse::from(error).into();
+    };
+    let etag = format!("\"{}\"", schema.info.sha256);
+    let cached = request
+        .headers()
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == etag));
+    if cached {
+        return HttpResponse::NotModified()
+            .insert_header((ETAG, etag))
+            .finish();
+    }
+    HttpResponse::Ok()
+        .content_type("application/schema+json")
+        .insert_header((ETAG, etag))
+        .body(schema.text)
+}
@@ -0,0 +1,107 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to install scripts and run them.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::scripting::{Script, ScriptError, Scripts, MAX_SCRIPTS};
+use crate::error::ErrorCode;
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, put, web};
+use serde_json::{json, Value};
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_scripts)
+        .service(get_script)
+        .service(put_script)
+        .service(delete_script)
+        .service(run_script);
+}
+
+impl ErrorCode for ScriptError {
+    fn code(&self) -> &'static str {
+        match self {
+            ScriptError::NotFound(_) => "not_found",
+            ScriptError::TooManyScripts => "limit_reached",
+            ScriptError::Disabled => "disabled",
+            ScriptError::Failed(_) => "script_failed",
+            ScriptError::InvalidName(_) => "invalid_name",
+            ScriptError::Invalid(_) => "invalid_definition",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            ScriptError::NotFound(_) => StatusCode::NOT_FOUND,
+            ScriptError::TooManyScripts => StatusCode::INSUFFICIENT_STORAGE,
+            ScriptError::Disabled => StatusCode::SERVICE_UNAVAILABLE,
+            ScriptError::Failed(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ScriptError::InvalidName(_) | ScriptError::Invalid(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn details(&self) -> Value {
+        match self {
+            ScriptError::NotFound(name) | ScriptError::InvalidName(name) => {
+                json!({ "name": name })
+            }
+            ScriptError::TooManyScripts => json!({ "limit": MAX_SCRIPTS }),
+            ScriptError::Disabled => json!({ "feature": "scripting" }),
+            ScriptError::Failed(reason) | ScriptError::Invalid(reason) => {
+                json!({ "reason": reason })
+            }
+        }
+    }
+}
+
+#[get("/scripts")]
+async fn list_scripts(scripts: web::Data<Scripts>) -> LegacyResponse {
+    json!(scripts.list().await).into()
+}
+
+#[get("/scripts/{name}")]
+async fn get_script(scripts: web::Data<Scripts>, name: web::Path<String>) -> LegacyResponse {
+    scripts.get(&name).await.map(|script| json!(script)).into()
+}
+
+#[put("/scripts/{name}")]
+async fn put_script(
+    scripts: web::Data<Scripts>,
+    name: web::Path<String>,
+    script: web::Json<Script>,
+) -> LegacyResponse {
+    scripts.save(&name, script.into_inner()).await.into()
+}
+
+#[delete("/scripts/{name}")]
+async fn delete_script(scripts: web::Data<Scripts>, name: web::Path<String>) -> LegacyResponse {
+    scripts.delete(&name).await.into()
+}
+
+/// Runs the script with the JSON body of the request as `args`, and answers
+/// with its result and output.
+#[post("/scripts/{name}/run")]
+async fn run_script(
+    scripts: web::Data<Scripts>,
+    name: web::Path<String>,
+    body: web::Bytes,
+) -> LegacyResponse {
+    let args = if body.is_empty() {
+        Value::Null
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(args) => args,
+            Err(e) => return LegacyResponse::bad_request(format!("invalid arguments: {}", e)),
+        }
+    };
+    scripts.run(&name, args).await.map(|run| json!(run)).into()
+}
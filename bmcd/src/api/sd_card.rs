@@ -0,0 +1,70 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to switch the microSD slot between the BMC and the nodes, see
+//! `app::sd_card`.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::capabilities::{Capabilities, Capability};
+use crate::app::sd_card::{select, status};
+use crate::error::BmcError;
+use crate::hal::{NodeId, SdRoute};
+use actix_web::{get, put, web};
+use serde::Deserialize;
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_sd_card).service(put_sd_card);
+}
+
+#[derive(Debug, Deserialize)]
+struct SdCardRequest {
+    /// node that gets the card, numbered from 1. `None` gives it back to the
+    /// BMC.
+    node: Option<u8>,
+    /// switches the card away from a node that is powered on
+    #[serde(default)]
+    force: bool,
+}
+
+#[get("/sd-card")]
+async fn get_sd_card(
+    bmc: web::Data<BmcApplication>,
+    capabilities: web::Data<Capabilities>,
+) -> LegacyResponse {
+    if let Err(e) = capabilities.ensure(Capability::SdMux) {
+        return e.into();
+    }
+    status(&bmc).map(|status| json!(status)).into()
+}
+
+/// Switches the card, e.g. `{"node": 2}` or `{"node": null}`. Unmount the
+/// card on the BMC before giving it to a node.
+#[put("/sd-card")]
+async fn put_sd_card(
+    bmc: web::Data<BmcApplication>,
+    capabilities: web::Data<Capabilities>,
+    request: web::Json<SdCardRequest>,
+) -> LegacyResponse {
+    if let Err(e) = capabilities.ensure(Capability::SdMux) {
+        return e.into();
+    }
+    let route = match request.node {
+        None => SdRoute::Bmc,
+        Some(node) => match node.checked_sub(1).and_then(|n| NodeId::try_from(n).ok()) {
+            Some(node) => SdRoute::Node(node),
+            None => return BmcError::invalid_parameter("node", "must be 1 to 4").into(),
+        },
+    };
+    select(&bmc, route, request.force).await.into()
+}
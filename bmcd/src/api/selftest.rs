@@ -0,0 +1,48 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Route that runs the hardware self-test.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::identify::Identify;
+use crate::app::selftest::SelfTest;
+use crate::hal::expansion::Expansions;
+use crate::hal::rtc::Rtc;
+use crate::serial_service::serial::SerialConnections;
+use actix_web::{post, web};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(selftest);
+}
+
+/// Answers with a report per component once all components were tested,
+/// which takes a few seconds. A failed component does not fail the request,
+/// see `passed` in the report.
+#[post("/selftest")]
+async fn selftest(
+    bmc: web::Data<BmcApplication>,
+    identify: web::Data<Identify>,
+    serial: web::Data<SerialConnections>,
+    rtc: web::Data<Rtc>,
+    expansions: web::Data<Expansions>,
+) -> LegacyResponse {
+    let test = SelfTest {
+        bmc: &bmc,
+        identify: &identify,
+        serial: &serial,
+        rtc: &rtc,
+        expansions: &expansions,
+    };
+    json!(test.run().await).into()
+}
@@ -0,0 +1,81 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Restart of the daemon, and the middleware that refuses requests that
+//! change state while it drains.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::shutdown::Shutdown;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::ErrorServiceUnavailable;
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{post, web, Error};
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(restart);
+}
+
+/// Drains and restarts bmcd. Answers right away; draining waits for a
+/// running flash for up to `shutdown.drain_timeout`.
+#[post("/restart")]
+async fn restart(shutdown: web::Data<Shutdown>) -> LegacyResponse {
+    if shutdown.is_draining() {
+        return LegacyResponse::Error(StatusCode::CONFLICT, "bmcd is already shutting down".into());
+    }
+    let shutdown = shutdown.into_inner();
+    tokio::spawn(async move { shutdown.restart().await });
+    LegacyResponse::Success(None)
+}
+
+/// Requests other than reads are refused with `503 Service Unavailable` once
+/// draining started. Needs [`Shutdown`] in the application data.
+pub async fn refuse_while_draining(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let draining = request
+        .app_data::<web::Data<Shutdown>>()
+        .is_some_and(|shutdown| shutdown.is_draining());
+    if draining && changes_state(&request) {
+        return Err(ErrorServiceUnavailable("bmcd is shutting down"));
+    }
+    next.call(request).await
+}
+
+pub(crate) fn changes_state(request: &ServiceRequest) -> bool {
+    let read = matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+    // the legacy API changes state through GET requests
+    !read || request.query_string().contains("opt=set")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn state_changing_requests() {
+        let get = TestRequest::get().uri("/api/bmc/info").to_srv_request();
+        assert!(!changes_state(&get));
+        let post = TestRequest::post().uri("/api/bmc/restart").to_srv_request();
+        assert!(changes_state(&post));
+        let legacy = TestRequest::get()
+            .uri("/api/bmc?opt=set&type=power&node1=1")
+            .to_srv_request();
+        assert!(changes_state(&legacy));
+    }
+}
@@ -0,0 +1,115 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to manage the storage devices of the BMC and to report the space
+//! its features use, see `app::storage_manager`.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::storage_manager::{FormatError, FsType, StorageManager};
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web};
+use serde::Deserialize;
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_storage)
+        .service(confirm_format)
+        .service(request_format)
+        .service(mount)
+        .service(unmount);
+}
+
+#[derive(Debug, Deserialize)]
+struct FormatRequest {
+    fs_type: FsType,
+    label: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FormatConfirmation {
+    token: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct MountRequest {
+    /// below `/mnt`, defaults to the name of the device
+    mount_point: Option<String>,
+}
+
+#[get("/storage")]
+async fn get_storage(storage: web::Data<StorageManager>) -> LegacyResponse {
+    let storage = storage.into_inner();
+    tokio::task::spawn_blocking(move || {
+        Ok::<_, anyhow::Error>(json!({
+            "devices": storage.devices()?,
+            "features": storage.features(),
+        }))
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.into()))
+    .into()
+}
+
+/// Requests to format a device, e.g. `{"fs_type": "ext4", "label": "data"}`.
+/// All data on it is lost once the returned token is confirmed.
+#[post("/storage/{device}/format")]
+async fn request_format(
+    storage: web::Data<StorageManager>,
+    device: web::Path<String>,
+    request: web::Json<FormatRequest>,
+) -> LegacyResponse {
+    let FormatRequest { fs_type, label } = request.into_inner();
+    match storage.request_format(&device, fs_type, label) {
+        Ok((token, expires)) => json!({
+            "token": token,
+            "device": device.as_str(),
+            "expires_in": expires.as_secs(),
+        })
+        .into(),
+        Err(e) => e.into(),
+    }
+}
+
+#[post("/storage/format/confirm")]
+async fn confirm_format(
+    storage: web::Data<StorageManager>,
+    confirmation: web::Json<FormatConfirmation>,
+) -> LegacyResponse {
+    match storage.confirm_format(&confirmation.token).await {
+        Ok(device) => json!({ "device": device }).into(),
+        Err(e) if e.is::<FormatError>() => (StatusCode::FORBIDDEN, e.to_string()).into(),
+        Err(e) => e.context("format").into(),
+    }
+}
+
+#[post("/storage/{device}/mount")]
+async fn mount(
+    storage: web::Data<StorageManager>,
+    device: web::Path<String>,
+    request: Option<web::Json<MountRequest>>,
+) -> LegacyResponse {
+    let request = request.map(web::Json::into_inner).unwrap_or_default();
+    storage
+        .mount(&device, request.mount_point.as_deref())
+        .await
+        .map(|path| json!({ "mount_point": path }))
+        .into()
+}
+
+#[post("/storage/{device}/unmount")]
+async fn unmount(storage: web::Data<StorageManager>, device: web::Path<String>) -> LegacyResponse {
+    storage
+        .unmount(&device)
+        .await
+        .map(|paths| json!({ "unmounted": paths }))
+        .into()
+}
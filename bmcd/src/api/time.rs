@@ -0,0 +1,64 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to configure the clock of the BMC and inspect its synchronization.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::time_sync::{get_time_status, set_ntp, set_time, TimeSettings};
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_time)
+        .service(set_manual_time)
+        .service(set_ntp_settings);
+}
+
+#[derive(Debug, Deserialize)]
+struct SetTime {
+    /// RFC 3339 timestamp, e.g. `2024-01-31T12:00:00Z`
+    time: DateTime<Utc>,
+}
+
+#[get("/time")]
+async fn get_time(bmc: web::Data<BmcApplication>) -> LegacyResponse {
+    match get_time_status(&bmc).await {
+        Ok(status) => json!(status).into(),
+        Err(e) => e.context("read time status").into(),
+    }
+}
+
+#[post("/time")]
+async fn set_manual_time(
+    bmc: web::Data<BmcApplication>,
+    request: web::Json<SetTime>,
+) -> LegacyResponse {
+    set_time(&bmc, request.time)
+        .await
+        .map_err(|e| LegacyResponse::Error(StatusCode::CONFLICT, format!("{:#}", e).into()))
+        .into()
+}
+
+#[post("/time/ntp")]
+async fn set_ntp_settings(
+    bmc: web::Data<BmcApplication>,
+    settings: web::Json<TimeSettings>,
+) -> LegacyResponse {
+    set_ntp(&bmc, settings.into_inner())
+        .await
+        .map_err(|e| LegacyResponse::Error(StatusCode::BAD_REQUEST, format!("{:#}", e).into()))
+        .into()
+}
@@ -0,0 +1,157 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Middleware that assigns a trace ID to every API request, and the routes to
+//! inspect the timelines of recent requests.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::request_trace::{new_trace_id, valid_trace_id, RequestTraces, REQUEST_SPAN};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{get, web, Error};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use serde_json::json;
+use std::rc::Rc;
+use std::sync::Arc;
+use tracing::Instrument;
+
+/// Carries the trace ID in requests and responses. Clients may hand in their
+/// own ID to correlate with their logs.
+pub const TRACE_ID_HEADER: HeaderName = HeaderName::from_static("x-trace-id");
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_traces).service(get_trace);
+}
+
+#[get("/traces")]
+async fn list_traces(traces: web::Data<RequestTraces>) -> LegacyResponse {
+    let summaries: Vec<_> = traces
+        .recent()
+        .into_iter()
+        .map(|t| {
+            json!({
+                "id": t.id,
+                "method": t.method,
+                "path": t.path,
+                "started": t.started,
+                "status": t.status,
+                "duration_ms": t.duration_ms,
+                "events": t.events.len() + t.dropped_events,
+            })
+        })
+        .collect();
+    json!(summaries).into()
+}
+
+#[get("/traces/{id}")]
+async fn get_trace(traces: web::Data<RequestTraces>, id: web::Path<String>) -> LegacyResponse {
+    match traces.get(&id) {
+        Some(trace) => json!(trace).into(),
+        None => LegacyResponse::Error(
+            StatusCode::NOT_FOUND,
+            format!("no trace with id {}", id).into(),
+        ),
+    }
+}
+
+#[derive(Clone)]
+pub struct RequestTracing {
+    traces: Arc<RequestTraces>,
+}
+
+impl RequestTracing {
+    pub fn new(traces: Arc<RequestTraces>) -> Self {
+        Self { traces }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequestTracing
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestTracingService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestTracingService {
+            service: Rc::new(service),
+            traces: self.traces.clone(),
+        }))
+    }
+}
+
+pub struct RequestTracingService<S> {
+    service: Rc<S>,
+    traces: Arc<RequestTraces>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestTracingService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let trace_id = request
+            .headers()
+            .get(&TRACE_ID_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .filter(|id| valid_trace_id(id))
+            .map(ToString::to_string)
+            .unwrap_or_else(new_trace_id);
+
+        // querying the traces should not push the traces of interest out
+        let path = request.path().to_string();
+        let traced = !path.contains("/traces");
+        if traced {
+            self.traces
+                .begin(&trace_id, request.method().as_str(), &path);
+        }
+
+        let span = tracing::info_span!(REQUEST_SPAN, trace_id = %trace_id);
+        let service = self.service.clone();
+        let traces = self.traces.clone();
+        Box::pin(
+            async move {
+                let result = service.call(request).await;
+                let status = match &result {
+                    Ok(response) => response.status(),
+                    Err(e) => e.as_response_error().status_code(),
+                };
+                if traced {
+                    traces.finish(&trace_id, status.as_u16());
+                }
+                tracing::debug!("{} {}", path, status);
+
+                result.map(|mut response| {
+                    if let Ok(value) = HeaderValue::from_str(&trace_id) {
+                        response.headers_mut().insert(TRACE_ID_HEADER, value);
+                    }
+                    response
+                })
+            }
+            .instrument(span),
+        )
+    }
+}
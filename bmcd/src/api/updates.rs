@@ -0,0 +1,77 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to list available BMC firmware updates and to download them. A
+//! staged update is applied with the firmware upgrade route, passing its path
+//! as local file.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::update_checker::UpdateChecker;
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_updates)
+        .service(check_updates)
+        .service(stage_update);
+}
+
+fn checker_configured(
+    checker: Option<web::Data<UpdateChecker>>,
+) -> Result<web::Data<UpdateChecker>, LegacyResponse> {
+    checker.ok_or(LegacyResponse::Error(
+        StatusCode::NOT_FOUND,
+        "update checks are not configured".into(),
+    ))
+}
+
+#[get("/updates")]
+async fn get_updates(checker: Option<web::Data<UpdateChecker>>) -> LegacyResponse {
+    match checker_configured(checker) {
+        Ok(checker) => json!(checker.status()).into(),
+        Err(e) => e,
+    }
+}
+
+/// Queries the release feed right away.
+#[post("/updates/check")]
+async fn check_updates(checker: Option<web::Data<UpdateChecker>>) -> LegacyResponse {
+    let checker = match checker_configured(checker) {
+        Ok(checker) => checker,
+        Err(e) => return e,
+    };
+
+    checker
+        .check()
+        .await
+        .map(|status| json!(status))
+        .map_err(|e| LegacyResponse::Error(StatusCode::BAD_GATEWAY, format!("{:#}", e).into()))
+        .into()
+}
+
+/// Downloads the given version, without applying it.
+#[post("/updates/{version}/stage")]
+async fn stage_update(
+    checker: Option<web::Data<UpdateChecker>>,
+    version: web::Path<String>,
+) -> LegacyResponse {
+    let checker = match checker_configured(checker) {
+        Ok(checker) => checker,
+        Err(e) => return e,
+    };
+
+    match checker.stage(&version).await {
+        Ok(staged) => json!(staged).into(),
+        Err(e) => e.context("stage update").into(),
+    }
+}
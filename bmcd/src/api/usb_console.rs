@@ -0,0 +1,30 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! How to reach the BMC over the USB console, see `app::usb_console`.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::usb_console::UsbConsole;
+use actix_web::{get, web};
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(status);
+}
+
+#[get("/usb-console")]
+async fn status(console: Option<web::Data<UsbConsole>>) -> LegacyResponse {
+    match console {
+        Some(console) => json!({ "enabled": true, "console": console.status() }).into(),
+        None => json!({ "enabled": false }).into(),
+    }
+}
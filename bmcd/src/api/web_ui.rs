@@ -0,0 +1,54 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Serves the web UI, see [`WebUi`]. Registered as default service, so it
+//! answers every request that no other route takes.
+use crate::app::web_ui::{Encoding, WebUi};
+use actix_files::{file_extension_to_mime, NamedFile};
+use actix_web::http::header::{self, ContentEncoding, HeaderValue};
+use actix_web::http::Method;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+pub async fn serve(request: HttpRequest, ui: web::Data<WebUi>) -> actix_web::Result<HttpResponse> {
+    if !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return Ok(HttpResponse::MethodNotAllowed()
+            .insert_header((header::ALLOW, "GET, HEAD"))
+            .finish());
+    }
+    let accept_encoding = request
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let Some(asset) = ui.asset(request.path(), accept_encoding).await else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    let mut file = NamedFile::open_async(&asset.path)
+        .await?
+        .set_content_type(file_extension_to_mime(&asset.extension))
+        .disable_content_disposition();
+    if let Some(encoding) = asset.encoding {
+        file = file.set_content_encoding(match encoding {
+            Encoding::Brotli => ContentEncoding::Brotli,
+            Encoding::Gzip => ContentEncoding::Gzip,
+        });
+    }
+    let mut response = file.into_response(&request);
+    let headers = response.headers_mut();
+    if let Ok(cache_control) = HeaderValue::from_str(&asset.cache_control) {
+        headers.insert(header::CACHE_CONTROL, cache_control);
+    }
+    headers.append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+    Ok(response)
+}
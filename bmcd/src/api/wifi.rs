@@ -0,0 +1,102 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routes to manage the Wi-Fi connection of the BMC. Only available on board
+//! revisions with a Wi-Fi radio.
+use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::bmc_application::BmcApplication;
+use crate::app::wifi::WifiManager;
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, web};
+use serde::Deserialize;
+use serde_json::json;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_wifi)
+        .service(scan)
+        .service(join)
+        .service(forget);
+}
+
+#[derive(Debug, Deserialize)]
+struct JoinRequest {
+    ssid: String,
+    passphrase: String,
+}
+
+fn ensure_radio(wifi: &WifiManager) -> Result<(), LegacyResponse> {
+    if wifi.is_available() {
+        Ok(())
+    } else {
+        Err(LegacyResponse::Error(
+            StatusCode::NOT_FOUND,
+            "this board has no Wi-Fi radio".into(),
+        ))
+    }
+}
+
+#[get("/wifi")]
+async fn get_wifi(wifi: web::Data<WifiManager>, bmc: web::Data<BmcApplication>) -> LegacyResponse {
+    if let Err(e) = ensure_radio(&wifi) {
+        return e;
+    }
+
+    match wifi.status(&bmc).await {
+        Ok(status) => json!(status).into(),
+        Err(e) => e.context("read Wi-Fi status").into(),
+    }
+}
+
+#[get("/wifi/scan")]
+async fn scan(wifi: web::Data<WifiManager>) -> LegacyResponse {
+    if let Err(e) = ensure_radio(&wifi) {
+        return e;
+    }
+
+    match wifi.scan().await {
+        Ok(networks) => json!(networks).into(),
+        Err(e) => e.context("Wi-Fi scan").into(),
+    }
+}
+
+#[post("/wifi/networks")]
+async fn join(
+    wifi: web::Data<WifiManager>,
+    bmc: web::Data<BmcApplication>,
+    request: web::Json<JoinRequest>,
+) -> LegacyResponse {
+    if let Err(e) = ensure_radio(&wifi) {
+        return e;
+    }
+
+    wifi.join(&bmc, &request.ssid, &request.passphrase)
+        .await
+        .map_err(|e| LegacyResponse::Error(StatusCode::BAD_REQUEST, format!("{:#}", e).into()))
+        .into()
+}
+
+#[delete("/wifi/networks/{ssid}")]
+async fn forget(
+    wifi: web::Data<WifiManager>,
+    bmc: web::Data<BmcApplication>,
+    ssid: web::Path<String>,
+) -> LegacyResponse {
+    if let Err(e) = ensure_radio(&wifi) {
+        return e;
+    }
+
+    wifi.forget(&bmc, &ssid)
+        .await
+        .map_err(|e| LegacyResponse::Error(StatusCode::NOT_FOUND, format!("{:#}", e).into()))
+        .into()
+}
@@ -11,10 +11,86 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+pub mod activity;
+pub mod asset_export;
+pub mod backup;
+pub mod batch;
 pub mod bmc_application;
 pub mod bmc_info;
+pub mod capabilities;
+pub mod cluster;
+pub mod config_archive;
+pub mod config_service;
+pub mod console_shares;
 pub mod cooling_device;
+pub mod crash_report;
+pub mod dhcp_server;
+pub mod diagnostics;
+pub mod enrollment;
 pub mod event_application;
+pub mod factory_reset;
+pub mod failover;
+pub mod firmware_signature;
+pub mod firmware_slots;
+pub mod flash_history;
+pub mod graphql;
+pub mod http_policy;
+pub mod i2c_access;
+pub mod idempotency;
+pub mod identify;
+pub mod image_cache;
+pub mod image_inspection;
+pub mod image_sharing;
+pub mod inventory;
+pub mod jobs;
+pub mod kubernetes;
+pub mod kv_store;
+pub mod kvm;
+pub mod lights_out;
+pub mod listeners;
+pub mod logging;
+pub mod mdns;
+pub mod messages;
+pub mod nbd_server;
+pub mod netboot;
+pub mod network_config;
+pub mod node_agent;
+pub mod node_backup;
+pub mod node_pins;
+pub mod node_state;
+pub mod notifier;
+pub mod physical_presence;
+pub mod pipelines;
+pub mod plugins;
+pub mod power_presets;
+pub mod power_supply;
+pub mod provisioning;
+pub mod readiness;
+pub mod request_trace;
+pub mod resources;
+pub mod retention;
+pub mod rules;
+pub mod safe_mode;
+pub mod schemas;
+pub mod scripting;
+pub mod sd_card;
+pub mod selftest;
+pub mod shutdown;
+pub mod storage_health;
+pub mod storage_manager;
+pub mod systemd;
+pub mod time_sync;
 pub mod transfer_action;
+pub mod update_checker;
+pub mod update_scheduler;
+pub mod upgrade_journal;
+pub mod upgrade_progress;
 pub mod upgrade_worker;
+pub mod usb_console;
 pub mod usb_gadget;
+pub mod users;
+pub mod wake_alarm;
+pub mod wake_schedule;
+pub mod watchdog;
+pub mod web_ui;
+pub mod wifi;
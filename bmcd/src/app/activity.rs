@@ -0,0 +1,311 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Infers from the current draw of powered nodes whether they are running.
+//! A running node draws a load that varies with its workload; a node that
+//! hung in a kernel panic or a busy loop draws a flat current. The draw is
+//! read from hwmon attributes, e.g. of an INA2xx on an add-on board, listed
+//! per node in the `activity` section of the configuration.
+//!
+//! The state is a heuristic: a node that idles with all clocks gated can look
+//! stalled too. Tune `tolerance` and `stall_after` to the modules in use.
+use super::bmc_application::{BmcApplication, ACTIVATED_NODES_KEY};
+use super::lights_out::LightsOut;
+use super::notifier::Notifier;
+use crate::config::Activity;
+use crate::hal::NodeId;
+use crate::utils::get_timestamp_unix;
+use anyhow::Context;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivityState {
+    Off,
+    /// powered, but not drawing enough current to be running
+    NoLoad,
+    Running,
+    /// the draw has been flat for `stall_after`
+    Stalled,
+    /// not enough samples yet, or the sensor cannot be read
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeActivity {
+    pub node: u8,
+    pub state: ActivityState,
+    /// last reading in mA
+    pub current: Option<u32>,
+    /// unix timestamp of the last change of `state`
+    pub since: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+struct History {
+    samples: VecDeque<(Instant, u32)>,
+    activity: NodeActivity,
+}
+
+pub struct ActivityMonitor {
+    config: Activity,
+    history: Mutex<HashMap<u8, History>>,
+}
+
+impl ActivityMonitor {
+    pub fn new(config: Activity) -> Self {
+        let since = get_timestamp_unix().unwrap_or_default();
+        let history = config
+            .sensors
+            .iter()
+            .map(|sensor| {
+                let activity = NodeActivity {
+                    node: sensor.node,
+                    state: ActivityState::Unknown,
+                    current: None,
+                    since,
+                    error: None,
+                };
+                let samples = VecDeque::new();
+                (sensor.node, History { samples, activity })
+            })
+            .collect();
+        Self {
+            config,
+            history: Mutex::new(history),
+        }
+    }
+
+    /// Activity of the nodes that have a sensor, ordered by node.
+    pub fn status(&self) -> Vec<NodeActivity> {
+        let history = self.history.lock().expect("activity history poisoned");
+        let mut status: Vec<_> = history.values().map(|h| h.activity.clone()).collect();
+        status.sort_by_key(|a| a.node);
+        status
+    }
+
+    /// Samples the sensors every `interval`. Does nothing when no sensors
+    /// are configured.
+    pub fn run(
+        self: Arc<Self>,
+        bmc: Arc<BmcApplication>,
+        notifier: Arc<Notifier>,
+        lights_out: Arc<LightsOut>,
+    ) {
+        if self.config.sensors.is_empty() {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.config.interval);
+            loop {
+                lights_out.tick(&mut interval).await;
+                let powered = bmc.app_db.get::<u8>(ACTIVATED_NODES_KEY).await;
+                for sensor in &self.config.sensors {
+                    let reading = read_current(&sensor.path).await;
+                    let powered = powered & (1 << (sensor.node - 1)) != 0;
+                    if let Some(state) = self.record(sensor.node, powered, reading) {
+                        self.on_change(sensor.node, state, &bmc, &notifier).await;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Adds a sample and returns the new state if it changed.
+    fn record(
+        &self,
+        node: u8,
+        powered: bool,
+        reading: anyhow::Result<u32>,
+    ) -> Option<ActivityState> {
+        let now = Instant::now();
+        let mut history = self.history.lock().expect("activity history poisoned");
+        let history = history.get_mut(&node)?;
+        if !powered {
+            // a power cycle starts a new history
+            history.samples.clear();
+        }
+        let state = match reading {
+            Ok(current) => {
+                history.activity.current = Some(current);
+                history.activity.error = None;
+                if powered {
+                    history.samples.push_back((now, current));
+                }
+                while history
+                    .samples
+                    .front()
+                    .is_some_and(|(at, _)| now.duration_since(*at) > self.config.stall_after)
+                {
+                    history.samples.pop_front();
+                }
+                let span = history
+                    .samples
+                    .front()
+                    .map(|(at, _)| now.duration_since(*at))
+                    .unwrap_or_default();
+                // samples are taken every interval, the oldest one is up to an
+                // interval younger than `stall_after`
+                let complete = span + self.config.interval >= self.config.stall_after;
+                let samples: Vec<_> = history.samples.iter().map(|(_, c)| *c).collect();
+                classify(powered, &samples, complete, &self.config)
+            }
+            Err(e) => {
+                history.activity.current = None;
+                history.activity.error = Some(format!("{:#}", e));
+                if powered {
+                    ActivityState::Unknown
+                } else {
+                    ActivityState::Off
+                }
+            }
+        };
+        if state == history.activity.state {
+            return None;
+        }
+        history.activity.state = state;
+        history.activity.since = get_timestamp_unix().unwrap_or_default();
+        Some(state)
+    }
+
+    async fn on_change(
+        &self,
+        node: u8,
+        state: ActivityState,
+        bmc: &BmcApplication,
+        notifier: &Notifier,
+    ) {
+        tracing::info!("node {} activity: {:?}", node, state);
+        if state != ActivityState::Stalled {
+            return;
+        }
+        let message = format!(
+            "node {} has drawn a flat current for {}s, it may have hung",
+            node,
+            self.config.stall_after.as_secs()
+        );
+        tracing::warn!("{}", message);
+        notifier.notify("node_stalled", message).await;
+        if self.config.reset_stalled {
+            let id = NodeId::try_from(node - 1).expect("validated node");
+            match bmc.reset_node(id).await {
+                Ok(()) => {
+                    notifier
+                        .notify("node_reset", format!("reset stalled node {}", node))
+                        .await
+                }
+                Err(e) => tracing::error!("resetting stalled node {}: {:#}", node, e),
+            }
+        }
+    }
+}
+
+async fn read_current(path: &Path) -> anyhow::Result<u32> {
+    let value = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| path.display().to_string())?;
+    let milli_amps: i64 = value
+        .trim()
+        .parse()
+        .with_context(|| format!("{}: not a number", path.display()))?;
+    // shunt monitors report small negative offsets at no load
+    Ok(milli_amps.clamp(0, u32::MAX.into()) as u32)
+}
+
+/// `samples` are the readings in mA of the last `stall_after`, `complete`
+/// tells whether they cover that whole period.
+fn classify(powered: bool, samples: &[u32], complete: bool, config: &Activity) -> ActivityState {
+    if !powered {
+        return ActivityState::Off;
+    }
+    let (Some(min), Some(max), Some(last)) =
+        (samples.iter().min(), samples.iter().max(), samples.last())
+    else {
+        return ActivityState::Unknown;
+    };
+    if *last < config.min_running {
+        ActivityState::NoLoad
+    } else if max - min > config.tolerance {
+        ActivityState::Running
+    } else if complete {
+        ActivityState::Stalled
+    } else {
+        ActivityState::Unknown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CurrentSensor;
+    use std::time::Duration;
+
+    #[test]
+    fn classification() {
+        let config = Activity::default();
+        assert_eq!(classify(false, &[500], true, &config), ActivityState::Off);
+        assert_eq!(classify(true, &[], false, &config), ActivityState::Unknown);
+        assert_eq!(
+            classify(true, &[20, 10], true, &config),
+            ActivityState::NoLoad
+        );
+        assert_eq!(
+            classify(true, &[400, 900, 450], false, &config),
+            ActivityState::Running
+        );
+        assert_eq!(
+            classify(true, &[600, 605, 598], false, &config),
+            ActivityState::Unknown
+        );
+        assert_eq!(
+            classify(true, &[600, 605, 598], true, &config),
+            ActivityState::Stalled
+        );
+    }
+
+    #[test]
+    fn state_changes() {
+        let monitor = ActivityMonitor::new(Activity {
+            sensors: vec![CurrentSensor {
+                node: 2,
+                path: "/dev/null".into(),
+            }],
+            interval: Duration::from_secs(1),
+            stall_after: Duration::from_secs(2),
+            ..Activity::default()
+        });
+        assert_eq!(monitor.record(1, true, Ok(500)), None);
+        assert_eq!(monitor.record(2, false, Ok(0)), Some(ActivityState::Off));
+        assert_eq!(
+            monitor.record(2, true, Ok(500)),
+            Some(ActivityState::Unknown)
+        );
+        assert_eq!(
+            monitor.record(2, true, Ok(800)),
+            Some(ActivityState::Running)
+        );
+        assert_eq!(monitor.record(2, true, Ok(820)), None);
+        assert_eq!(
+            monitor.record(2, true, Err(anyhow::anyhow!("gone"))),
+            Some(ActivityState::Unknown)
+        );
+        let status = monitor.status();
+        assert_eq!(status[0].current, None);
+        assert_eq!(status[0].error.as_deref(), Some("gone"));
+    }
+}
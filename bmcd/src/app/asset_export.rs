@@ -0,0 +1,381 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Machine-readable inventory of the whole board for asset management
+//! systems: the board itself, its firmware, network interfaces, storage and
+//! the modules in the node slots. [`AssetDocument::to_csv`] flattens the
+//! document to one row per asset, each carrying the board serial so that
+//! exports of several boards can be concatenated.
+use super::bmc_application::BmcApplication;
+use super::bmc_info::{get_mac_address, read_hostname, read_os_release};
+use super::firmware_slots::{FirmwareSlots, Slot};
+use super::inventory::{get_inventory, Presence};
+use super::node_agent::NodeAgents;
+use super::storage_manager::StorageManager;
+use crate::config::Config;
+use crate::hal::eeprom::BoardIdentity;
+use crate::utils::get_timestamp_unix;
+use serde::Serialize;
+use std::fmt::Write;
+
+const CSV_HEADER: &str = "board_serial,kind,id,model,serial,version,mac,size_bytes";
+
+#[derive(Debug, Serialize)]
+pub struct AssetDocument {
+    /// unix timestamp
+    pub generated_at: u64,
+    pub board: BoardAsset,
+    pub firmware: FirmwareAsset,
+    pub interfaces: Vec<InterfaceAsset>,
+    pub storage: Vec<StorageAsset>,
+    pub slots: Vec<SlotAsset>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct BoardAsset {
+    pub model: Option<String>,
+    pub revision: Option<String>,
+    pub serial: Option<String>,
+    pub asset_tag: Option<String>,
+    /// MAC address programmed in the factory
+    pub mac: Option<String>,
+    pub hostname: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct FirmwareAsset {
+    pub bmcd: String,
+    pub build_time: String,
+    /// e.g. `Buildroot 2023.02`
+    pub os: Option<String>,
+    pub os_version: Option<String>,
+    /// A/B slot that runs, if the board has firmware slots
+    pub active_slot: Option<Slot>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InterfaceAsset {
+    pub name: String,
+    pub mac: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StorageAsset {
+    pub name: String,
+    pub model: Option<String>,
+    pub size: u64,
+    pub removable: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SlotAsset {
+    pub node: u8,
+    /// name of the node in the configuration
+    pub name: Option<String>,
+    pub presence: Presence,
+    pub module: Option<String>,
+    pub soc: Option<String>,
+    pub serial: Option<String>,
+    /// on-module storage in bytes
+    pub storage: Option<u64>,
+    /// MAC address of the DHCP lease of the node
+    pub mac: Option<String>,
+    /// as reported by the node agent
+    pub hostname: Option<String>,
+    pub addresses: Vec<String>,
+}
+
+/// Sources of the document.
+pub struct AssetSources<'a> {
+    pub bmc: &'a BmcApplication,
+    pub agents: &'a NodeAgents,
+    pub identity: &'a BoardIdentity,
+    pub storage: &'a StorageManager,
+    pub firmware_slots: Option<&'a FirmwareSlots>,
+    pub config: &'a Config,
+}
+
+/// Collects the document. Parts that cannot be read are left out rather than
+/// failing the export.
+pub async fn collect(sources: AssetSources<'_>) -> AssetDocument {
+    let identity = sources.identity.identity();
+    let board = BoardAsset {
+        model: identity.as_ref().map(|i| i.product_name.clone()),
+        revision: identity.as_ref().map(|i| i.hw_revision.clone()),
+        serial: sources.identity.serial(),
+        asset_tag: identity
+            .as_ref()
+            .map(|i| i.user.asset_tag.clone())
+            .filter(|tag| !tag.is_empty()),
+        mac: identity.map(|i| i.mac).filter(|mac| !mac.is_empty()),
+        hostname: read_hostname().await.unwrap_or_default(),
+    };
+
+    let os_release = read_os_release().await.unwrap_or_default();
+    let os_field = |key| {
+        os_release
+            .get(key)
+            .map(|value: &String| value.trim_matches('"').to_string())
+    };
+    let active_slot = match sources.firmware_slots {
+        Some(slots) => slots.status().await.ok().map(|status| status.active),
+        None => None,
+    };
+    let firmware = FirmwareAsset {
+        bmcd: env!("CARGO_PKG_VERSION").to_string(),
+        build_time: build_time::build_time_utc!("%Y-%m-%d %H:%M:%S-00:00").to_string(),
+        os: os_field("PRETTY_NAME"),
+        os_version: os_field("VERSION"),
+        active_slot,
+    };
+
+    let storage = sources
+        .storage
+        .devices()
+        .unwrap_or_else(|e| {
+            tracing::warn!("listing storage devices: {}", e);
+            Vec::new()
+        })
+        .into_iter()
+        .map(|device| StorageAsset {
+            name: device.name,
+            model: device.model,
+            size: device.size,
+            removable: device.removable,
+        })
+        .collect();
+
+    let slots = get_inventory(sources.bmc, sources.agents)
+        .await
+        .into_iter()
+        .map(|slot| {
+            let identity = slot.module.map(|m| m.identity);
+            let agent = slot.agent.map(|a| a.info);
+            SlotAsset {
+                node: slot.node,
+                name: sources
+                    .config
+                    .nodes
+                    .iter()
+                    .find(|n| n.node == slot.node)
+                    .and_then(|n| n.name.clone()),
+                presence: slot.presence,
+                module: identity.as_ref().map(|i| i.module.clone()),
+                soc: identity.as_ref().map(|i| i.soc.clone()),
+                serial: identity.as_ref().and_then(|i| i.serial.clone()),
+                storage: identity.and_then(|i| i.storage),
+                mac: sources.config.dhcp.as_ref().and_then(|dhcp| {
+                    dhcp.leases
+                        .iter()
+                        .find(|lease| lease.node == slot.node)
+                        .map(|lease| lease.mac.to_lowercase())
+                }),
+                hostname: agent.as_ref().map(|a| a.hostname.clone()),
+                addresses: agent.map(|a| a.addresses).unwrap_or_default(),
+            }
+        })
+        .collect();
+
+    AssetDocument {
+        generated_at: get_timestamp_unix().unwrap_or_default(),
+        board,
+        firmware,
+        interfaces: list_interfaces().await,
+        storage,
+        slots,
+    }
+}
+
+/// Network interfaces of the BMC with a MAC address, by name.
+async fn list_interfaces() -> Vec<InterfaceAsset> {
+    let mut names: Vec<String> = match std::fs::read_dir("/sys/class/net") {
+        Ok(entries) => entries
+            .flatten()
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .filter(|name| name != "lo")
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    names.sort();
+    let mut interfaces = Vec::new();
+    for name in names {
+        let mac = get_mac_address(&name).await;
+        if mac != "Unknown" && !mac.is_empty() {
+            interfaces.push(InterfaceAsset { name, mac });
+        }
+    }
+    interfaces
+}
+
+impl AssetDocument {
+    /// One row per asset, with the columns of [`CSV_HEADER`]. Columns that do
+    /// not apply to a kind of asset are empty.
+    pub fn to_csv(&self) -> String {
+        let board_serial = self.board.serial.as_deref().unwrap_or_default();
+        let mut csv = String::new();
+        let _ = writeln!(csv, "{}", CSV_HEADER);
+        let mut row = |kind: &str, id: &str, fields: [Option<&str>; 5]| {
+            let mut line = [board_serial, kind, id]
+                .into_iter()
+                .chain(fields.into_iter().map(Option::unwrap_or_default))
+                .map(csv_field)
+                .collect::<Vec<_>>()
+                .join(",");
+            line.push('\n');
+            csv.push_str(&line);
+        };
+
+        let board = &self.board;
+        row(
+            "board",
+            &board.hostname,
+            [
+                board.model.as_deref(),
+                board.asset_tag.as_deref(),
+                board.revision.as_deref(),
+                board.mac.as_deref(),
+                None,
+            ],
+        );
+        let firmware = &self.firmware;
+        row(
+            "firmware",
+            "bmcd",
+            [None, None, Some(&firmware.bmcd), None, None],
+        );
+        row(
+            "firmware",
+            "os",
+            [
+                firmware.os.as_deref(),
+                None,
+                firmware.os_version.as_deref(),
+                None,
+                None,
+            ],
+        );
+        for interface in &self.interfaces {
+            row(
+                "interface",
+                &interface.name,
+                [None, None, None, Some(&interface.mac), None],
+            );
+        }
+        for device in &self.storage {
+            let size = device.size.to_string();
+            row(
+                "storage",
+                &device.name,
+                [device.model.as_deref(), None, None, None, Some(&size)],
+            );
+        }
+        for slot in &self.slots {
+            let id = format!("node{}", slot.node);
+            let model = match (&slot.module, &slot.soc) {
+                (Some(module), Some(soc)) => Some(format!("{} ({})", module, soc)),
+                (module, _) => module.clone(),
+            };
+            let size = slot.storage.map(|s| s.to_string());
+            row(
+                "module",
+                slot.name.as_deref().unwrap_or(&id),
+                [
+                    model.as_deref(),
+                    slot.serial.as_deref(),
+                    None,
+                    slot.mac.as_deref(),
+                    size.as_deref(),
+                ],
+            );
+        }
+        csv
+    }
+}
+
+/// Quotes a field when needed. Fields that a spreadsheet would evaluate as a
+/// formula are prefixed with `'`, asset tags and names are set by users.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_fields() {
+        assert_eq!(csv_field("sda"), "sda");
+        assert_eq!(csv_field("rack 4, shelf 2"), "\"rack 4, shelf 2\"");
+        assert_eq!(csv_field("the \"big\" one"), "\"the \"\"big\"\" one\"");
+        assert_eq!(csv_field("=HYPERLINK(1)"), "'=HYPERLINK(1)");
+    }
+
+    #[test]
+    fn csv_rows() {
+        let document = AssetDocument {
+            generated_at: 0,
+            board: BoardAsset {
+                model: Some("Turing Pi 2".to_string()),
+                serial: Some("TP2-0001".to_string()),
+                hostname: "turingpi".to_string(),
+                ..Default::default()
+            },
+            firmware: FirmwareAsset {
+                bmcd: "2.1.0".to_string(),
+                ..Default::default()
+            },
+            interfaces: vec![InterfaceAsset {
+                name: "eth0".to_string(),
+                mac: "02:00:00:00:00:01".to_string(),
+            }],
+            storage: vec![StorageAsset {
+                name: "mmcblk0".to_string(),
+                model: None,
+                size: 32_000_000_000,
+                removable: true,
+            }],
+            slots: vec![SlotAsset {
+                node: 2,
+                name: None,
+                presence: Presence::Present,
+                module: Some("RK1".to_string()),
+                soc: Some("RK3588".to_string()),
+                serial: None,
+                storage: Some(32_000_000_000),
+                mac: Some("02:00:00:00:00:22".to_string()),
+                hostname: None,
+                addresses: Vec::new(),
+            }],
+        };
+        let csv = document.to_csv();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 7);
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(lines[1], "TP2-0001,board,turingpi,Turing Pi 2,,,,");
+        assert_eq!(lines[2], "TP2-0001,firmware,bmcd,,,2.1.0,,");
+        assert_eq!(lines[4], "TP2-0001,interface,eth0,,,,02:00:00:00:00:01,");
+        assert_eq!(lines[5], "TP2-0001,storage,mmcblk0,,,,,32000000000");
+        assert!(
+            csv.ends_with("TP2-0001,module,node2,RK1 (RK3588),,,02:00:00:00:00:22,32000000000\n")
+        );
+    }
+}
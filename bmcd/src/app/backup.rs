@@ -0,0 +1,116 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Backups of the files on the writable overlay, which hold the settings of
+//! the BMC. A backup is either streamed to the client while it is archived or
+//! written to a file by a backup job.
+use super::jobs::{JobId, JobKind, Jobs, Outcome};
+use crate::utils::ChannelWriter;
+use anyhow::Context;
+use async_compression::tokio::bufread::GzipEncoder;
+use async_compression::Level;
+use bytes::Bytes;
+use serde_json::json;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::io::StreamReader;
+use tokio_util::sync::CancellationToken;
+
+const OVERLAY: &str = "/mnt/overlay/upper/";
+
+/// At most `CHUNK_SIZE * CHUNK_DEPTH` bytes of the archive are buffered, the
+/// archiver waits for a slow reader instead of holding the whole overlay in
+/// memory.
+const CHUNK_SIZE: usize = 64 * 1024;
+const CHUNK_DEPTH: usize = 4;
+
+/// Archives the overlay on a blocking thread and returns the uncompressed
+/// tar archive as it is produced.
+pub fn archive_overlay() -> ReceiverStream<io::Result<Bytes>> {
+    let (writer, chunks) = ChannelWriter::new(CHUNK_SIZE, CHUNK_DEPTH);
+    tokio::task::spawn_blocking(move || {
+        let mut builder = tar::Builder::new(writer);
+        builder.mode(tar::HeaderMode::Deterministic);
+        let result = builder
+            .append_dir_all(".", OVERLAY)
+            .and_then(|_| builder.finish())
+            .and_then(|_| builder.get_mut().flush());
+        if let Err(e) = result {
+            tracing::warn!("backup aborted: {}", e);
+            builder.get_mut().fail(e);
+        }
+    });
+    chunks
+}
+
+pub fn file_name() -> String {
+    format!(
+        "tp2-backup-{}.tar.gz",
+        chrono::Local::now().format("%d-%m-%Y")
+    )
+}
+
+/// Writes a compressed backup to `path` and returns its size in bytes.
+async fn write_backup(path: &Path) -> anyhow::Result<u64> {
+    let archive = StreamReader::new(archive_overlay());
+    let mut encoder = GzipEncoder::with_quality(archive, Level::Best);
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .with_context(|| path.display().to_string())?;
+    let size = tokio::io::copy(&mut encoder, &mut file).await?;
+    file.sync_all().await?;
+    Ok(size)
+}
+
+/// Starts a job that writes a backup to a temporary file. The file can be
+/// downloaded until the job expires.
+pub fn start_backup_job(jobs: Arc<Jobs>) -> JobId {
+    let id = jobs.next_id();
+    let cancel = CancellationToken::new();
+    jobs.add(
+        id,
+        JobKind::Backup,
+        "backup of the BMC settings".to_string(),
+        cancel.clone(),
+        None,
+    );
+
+    let path = backup_path(id);
+    tokio::spawn(async move {
+        let outcome = tokio::select! {
+            result = write_backup(&path) => match result {
+                Ok(size) => {
+                    jobs.set_artifact(id, path);
+                    Outcome::Succeeded(Some(json!({
+                        "file_name": file_name(),
+                        "size": size,
+                        "download": format!("/api/bmc/jobs/{}/download", id),
+                    })))
+                }
+                Err(e) => Outcome::Failed(format!("{:#}", e)),
+            },
+            _ = cancel.cancelled() => Outcome::Cancelled,
+        };
+        if !matches!(outcome, Outcome::Succeeded(_)) {
+            let _ = tokio::fs::remove_file(backup_path(id)).await;
+        }
+        jobs.finish(id, outcome).await;
+    });
+    id
+}
+
+fn backup_path(id: JobId) -> PathBuf {
+    std::env::temp_dir().join(format!("bmcd-backup-{}.tar.gz", id))
+}
@@ -0,0 +1,277 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Batches of operations that run one after the other in a single request,
+//! for orchestration tools on links where every round trip counts. The whole
+//! batch is validated before the first operation runs. Each operation
+//! reports its own result; by default the batch stops at the first failure
+//! and the remaining operations are reported as skipped.
+use super::bmc_application::{BmcApplication, UsbConfig};
+use crate::error::{ApiError, BmcError};
+use crate::hal::{NodeId, UsbRoute};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+pub const MAX_OPERATIONS: usize = 64;
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsbSetting {
+    /// the node is USB host
+    Host,
+    /// the node is a USB device
+    Device,
+    /// the node is a USB device in its boot ROM's flashing mode
+    Flash,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Operation {
+    Power {
+        /// node numbers, starting from 1
+        nodes: Vec<u8>,
+        on: bool,
+    },
+    Reset {
+        /// node number, starting from 1
+        node: u8,
+    },
+    Usb {
+        /// node number, starting from 1
+        node: u8,
+        mode: UsbSetting,
+        /// route the USB bus to the BMC instead of the USB-A port
+        #[serde(default)]
+        bmc: bool,
+    },
+    UsbBoot {
+        /// node number, starting from 1
+        node: u8,
+    },
+    ClearUsbBoot,
+    /// waits before the next operation, e.g. for a node to shut down
+    Delay {
+        ms: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnError {
+    #[default]
+    Stop,
+    Continue,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Batch {
+    pub operations: Vec<Operation>,
+    #[serde(default)]
+    pub on_error: OnError,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationStatus {
+    Ok,
+    Failed,
+    Skipped,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OperationResult {
+    pub index: usize,
+    pub status: OperationStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ApiError>,
+}
+
+impl UsbSetting {
+    /// USB configuration for `node` in this mode, with the bus routed to the
+    /// BMC or to the USB-A port.
+    pub fn usb_config(self, node: NodeId, to_bmc: bool) -> UsbConfig {
+        let route = if to_bmc {
+            UsbRoute::Bmc
+        } else {
+            UsbRoute::AlternativePort
+        };
+        match (self, route) {
+            (UsbSetting::Device, UsbRoute::AlternativePort) => UsbConfig::UsbA(node),
+            (UsbSetting::Device, UsbRoute::Bmc) => UsbConfig::Bmc(node),
+            (UsbSetting::Host, route) => UsbConfig::Node(node, route),
+            (UsbSetting::Flash, route) => UsbConfig::Flashing(node, route),
+        }
+    }
+}
+
+pub fn node_id(node: u8, board_nodes: usize) -> Result<NodeId, BmcError> {
+    node.checked_sub(1)
+        .and_then(|n| NodeId::try_from(n).ok())
+        .filter(|n| (*n as usize) < board_nodes)
+        .ok_or_else(|| BmcError::invalid_parameter("node", format!("node {} does not exist", node)))
+}
+
+impl Operation {
+    fn validate(&self, board_nodes: usize) -> Result<(), BmcError> {
+        match self {
+            Operation::Power { nodes, .. } => {
+                if nodes.is_empty() {
+                    return Err(BmcError::invalid_parameter("nodes", "no nodes given"));
+                }
+                for node in nodes {
+                    node_id(*node, board_nodes)?;
+                }
+            }
+            Operation::Reset { node }
+            | Operation::Usb { node, .. }
+            | Operation::UsbBoot { node } => {
+                node_id(*node, board_nodes)?;
+            }
+            Operation::ClearUsbBoot => {}
+            Operation::Delay { ms } => {
+                if Duration::from_millis(*ms) > MAX_DELAY {
+                    return Err(BmcError::invalid_parameter(
+                        "ms",
+                        format!("delay exceeds {} seconds", MAX_DELAY.as_secs()),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn run(&self, bmc: &BmcApplication) -> anyhow::Result<()> {
+        let nodes = bmc.board().node_count;
+        match self {
+            Operation::Power { nodes: list, on } => {
+                let mask = list.iter().try_fold(0u8, |mask, n| {
+                    node_id(*n, nodes).map(|n| mask | n.to_bitfield())
+                })?;
+                bmc.activate_slot(if *on { mask } else { 0 }, mask).await
+            }
+            Operation::Reset { node } => bmc.reset_node(node_id(*node, nodes)?).await,
+            Operation::Usb {
+                node,
+                mode,
+                bmc: to_bmc,
+            } => {
+                let node = node_id(*node, nodes)?;
+                bmc.configure_usb(mode.usb_config(node, *to_bmc)).await
+            }
+            Operation::UsbBoot { node } => bmc.usb_boot(node_id(*node, nodes)?, true).await,
+            Operation::ClearUsbBoot => bmc.clear_usb_boot(),
+            Operation::Delay { ms } => {
+                tokio::time::sleep(Duration::from_millis(*ms)).await;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Batch {
+    pub fn validate(&self, board_nodes: usize) -> Result<(), BmcError> {
+        if self.operations.len() > MAX_OPERATIONS {
+            return Err(BmcError::invalid_parameter(
+                "operations",
+                format!("a batch holds at most {} operations", MAX_OPERATIONS),
+            ));
+        }
+        for (index, operation) in self.operations.iter().enumerate() {
+            operation.validate(board_nodes).map_err(|e| match e {
+                BmcError::InvalidParameter { parameter, reason } => BmcError::InvalidParameter {
+                    parameter,
+                    reason: format!("operation {}: {}", index, reason).into(),
+                },
+                other => other,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Runs the operations in order. Call [`Batch::validate`] first.
+    pub async fn run(&self, bmc: &BmcApplication) -> Vec<OperationResult> {
+        let mut failed = false;
+        let mut results = Vec::with_capacity(self.operations.len());
+        for (index, operation) in self.operations.iter().enumerate() {
+            if failed && self.on_error == OnError::Stop {
+                results.push(OperationResult {
+                    index,
+                    status: OperationStatus::Skipped,
+                    error: None,
+                });
+                continue;
+            }
+            let result = match operation.run(bmc).await {
+                Ok(()) => OperationResult {
+                    index,
+                    status: OperationStatus::Ok,
+                    error: None,
+                },
+                Err(e) => {
+                    tracing::warn!("batch operation {} ({:?}): {:#}", index, operation, e);
+                    failed = true;
+                    OperationResult {
+                        index,
+                        status: OperationStatus::Failed,
+                        error: Some(ApiError::from_anyhow(&e)),
+                    }
+                }
+            };
+            results.push(result);
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> Batch {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn parse_and_validate() {
+        let batch = parse(
+            r#"{"operations": [
+                {"op": "power", "nodes": [1, 2, 3], "on": false},
+                {"op": "delay", "ms": 500},
+                {"op": "usb", "node": 4, "mode": "host"},
+                {"op": "clear_usb_boot"}
+            ]}"#,
+        );
+        assert_eq!(batch.on_error, OnError::Stop);
+        assert_eq!(
+            batch.operations[2],
+            Operation::Usb {
+                node: 4,
+                mode: UsbSetting::Host,
+                bmc: false
+            }
+        );
+        assert!(batch.validate(4).is_ok());
+        // a board with two nodes has no node 4
+        let error = batch.validate(2).unwrap_err();
+        assert!(error.to_string().contains("operation 0"));
+
+        let batch = parse(r#"{"operations": [{"op": "delay", "ms": 600000}]}"#);
+        assert!(batch.validate(4).is_err());
+        let batch = parse(r#"{"operations": [{"op": "power", "nodes": [], "on": true}]}"#);
+        assert!(batch.validate(4).is_err());
+        assert!(serde_json::from_str::<Batch>(r#"{"operations": [{"op": "format"}]}"#).is_err());
+    }
+}
@@ -11,12 +11,15 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+use crate::config::{Config, PowerRestorePolicy};
+use crate::error::BmcError;
+use crate::hal::board_profile::BoardProfile;
 use crate::hal::helpers::bit_iterator;
-use crate::hal::{NodeId, PinController, UsbMode, UsbRoute};
-use crate::hal::{PowerController, UsbArchitecture};
+use crate::hal::{NodeId, PinControl, SdRoute, UsbMode, UsbRoute};
+use crate::hal::{PowerControl, PsuState, UsbArchitecture};
 use crate::persistency::app_persistency::ApplicationPersistency;
 use crate::persistency::app_persistency::PersistencyBuilder;
-use crate::usb_boot::NodeDrivers;
+use crate::usb_boot::{ModuleIdentity, NodeDrivers};
 use crate::utils::{self, get_timestamp_unix};
 use crate::{
     app::usb_gadget::append_msd_config_to_usb_gadget,
@@ -30,13 +33,28 @@ use std::ffi::c_ulong;
 use std::hash::{DefaultHasher, Hash, Hasher};
 use std::path::PathBuf;
 use std::process::Command;
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
 use tokio::fs::OpenOptions;
-use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 use tokio::time::sleep;
 use tracing::{debug, info, instrument, trace};
 
 use super::cooling_device::{get_cooling_state, set_cooling_state, CoolingDevice};
+use super::flash_history::{FlashHistory, FLASH_HISTORY_KEY};
+use super::inventory::{Inventory, INVENTORY_KEY};
+use super::kubernetes::Kubernetes;
+use super::kv_store::{Namespaces, KV_STORE_KEY};
+use super::nbd_server::{NbdExports, NBD_EXPORTS_KEY};
+use super::netboot::{BootFiles, NETBOOT_KEY};
+use super::pipelines::{StoredPipelines, PIPELINES_KEY};
+use super::power_presets::{PowerPresets, POWER_PRESETS_KEY};
+use super::rules::{StoredRules, RULES_KEY};
+use super::scripting::{StoredScripts, SCRIPTS_KEY};
+use super::time_sync::{TimeSettings, TIME_SETTINGS_KEY};
+use super::wake_alarm::{WakeAlarm, WAKE_ALARM_KEY};
+use super::wake_schedule::{WakeSchedules, WAKE_SCHEDULES_KEY};
+use super::wifi::{StoredNetworks, WIFI_NETWORKS_KEY};
 
 pub type NodeInfos = [NodeInfo; 4];
 type CoolingMap = HashMap<u64, c_ulong>;
@@ -74,18 +92,22 @@ pub struct NodeInfo {
 }
 
 pub struct BmcApplication {
-    pub(super) pin_controller: PinController,
-    pub(super) power_controller: PowerController,
+    pub(super) pin_controller: Box<dyn PinControl>,
+    pub(super) power_controller: Box<dyn PowerControl>,
     pub(super) app_db: ApplicationPersistency,
     node_drivers: NodeDrivers,
+    power_restore_policy: PowerRestorePolicy,
+    board: &'static BoardProfile,
+    kubernetes: OnceLock<Arc<Kubernetes>>,
 }
 
 impl BmcApplication {
-    pub async fn new(database_write_timeout: Option<Duration>) -> anyhow::Result<Self> {
-        let model_string = std::fs::read_to_string("/proc/device-tree/model");
-        let is_legacy_dts = matches!(model_string, Ok(model) if model.contains("v2.4"));
-        let pin_controller = PinController::new(is_legacy_dts).context("pin_controller")?;
-        let power_controller = PowerController::new(is_legacy_dts).context("power_controller")?;
+    pub async fn new(
+        profile: &'static BoardProfile,
+        database_write_timeout: Option<Duration>,
+        power_restore_policy: PowerRestorePolicy,
+    ) -> anyhow::Result<Self> {
+        let (pin_controller, power_controller) = crate::hal::init(profile)?;
         let app_db = PersistencyBuilder::default()
             .register_key(ACTIVATED_NODES_KEY, &0u8)
             .register_key(USB_CONFIG, &UsbConfig::UsbA(NodeId::Node1))
@@ -95,21 +117,40 @@ impl BmcApplication {
                 COOLING_DEVICES,
                 &CoolingMap::with_capacity(COOLING_CAPACITY),
             )
+            .register_key(KV_STORE_KEY, &Namespaces::new())
+            .register_key(WIFI_NETWORKS_KEY, &StoredNetworks::new())
+            .register_key(TIME_SETTINGS_KEY, &TimeSettings::default())
+            .register_key(NETBOOT_KEY, &BootFiles::default())
+            .register_key(NBD_EXPORTS_KEY, &NbdExports::default())
+            .register_key(WAKE_ALARM_KEY, &None::<WakeAlarm>)
+            .register_key(WAKE_SCHEDULES_KEY, &WakeSchedules::new())
+            .register_key(INVENTORY_KEY, &Inventory::default())
+            .register_key(POWER_PRESETS_KEY, &PowerPresets::new())
+            .register_key(FLASH_HISTORY_KEY, &FlashHistory::new())
+            .register_key(PIPELINES_KEY, &StoredPipelines::new())
+            .register_key(SCRIPTS_KEY, &StoredScripts::new())
+            .register_key(RULES_KEY, &StoredRules::new())
             .write_timeout(database_write_timeout)
             .build()
             .await?;
 
         let node_drivers = NodeDrivers::new();
 
-        let instance = Self {
+        Ok(Self {
             pin_controller,
             power_controller,
             app_db,
             node_drivers,
-        };
+            power_restore_policy,
+            board: profile,
+            kubernetes: OnceLock::new(),
+        })
+    }
 
-        instance.initialize().await?;
-        Ok(instance)
+    /// Drains the Kubernetes nodes of the slots that [`Self::activate_slot`]
+    /// and [`Self::reset_node`] power off, see [`Kubernetes`].
+    pub fn set_kubernetes(&self, kubernetes: Arc<Kubernetes>) {
+        let _ = self.kubernetes.set(kubernetes);
     }
 
     /// toggles the power state of the nodes. When `inverse_toggle` == true, and
@@ -135,19 +176,35 @@ impl BmcApplication {
         let node_values = self.app_db.get::<u8>(ACTIVATED_NODES_KEY).await;
 
         let mut on = node_values == 0;
-        if inverse_toggle && node_values != 0 && node_values != 0b1111 {
+        if inverse_toggle && node_values != 0 && node_values != self.board.node_mask() {
             on = !on;
         }
 
-        let node_values = if on { 0b1111 } else { 0b0000 };
-        self.activate_slot(node_values, 0b1111).await
+        let node_values = if on {
+            self.board.node_mask() & !self.empty_slots()
+        } else {
+            0b0000
+        };
+        self.activate_slot(node_values, self.board.node_mask())
+            .await
     }
 
-    async fn initialize(&self) -> anyhow::Result<()> {
+    /// Restores the USB routing and applies the power restore policy. Runs
+    /// after construction so the API does not wait for the power sequencing
+    /// of the nodes.
+    pub async fn initialize_power(&self) -> anyhow::Result<()> {
         self.initialize_usb_mode().await?;
-        let power_state = self.app_db.try_get::<u8>(ACTIVATED_NODES_KEY).await?;
-        self.activate_slot(power_state, 0b1111).await?;
-        self.initialize_cooling().await
+        let power_state = match self.power_restore_policy {
+            PowerRestorePolicy::Restore => self.app_db.try_get::<u8>(ACTIVATED_NODES_KEY).await?,
+            PowerRestorePolicy::AlwaysOn => self.board.node_mask(),
+            PowerRestorePolicy::AlwaysOff => 0b0000,
+        };
+        let empty = self.empty_slots();
+        if power_state & empty != 0 {
+            tracing::warn!("not powering empty slots {:#06b}", power_state & empty);
+        }
+        self.activate_slot(power_state & !empty, self.board.node_mask())
+            .await
     }
 
     #[instrument(skip(self), fields(alternative_port, config))]
@@ -163,7 +220,8 @@ impl BmcApplication {
         self.configure_usb(config).await.context("USB configure")
     }
 
-    async fn initialize_cooling(&self) -> anyhow::Result<()> {
+    /// Restores the stored speeds of the cooling devices.
+    pub async fn initialize_cooling(&self) -> anyhow::Result<()> {
         let store = self.app_db.get::<CoolingMap>(COOLING_DEVICES).await;
         let devices = get_cooling_state().await;
 
@@ -184,13 +242,13 @@ impl BmcApplication {
             let hash = hasher.finish();
 
             if let Some(speed) = store.get(&hash) {
-                set_cooling_state(&dev.device, speed).await?;
+                set_cooling_state(self.board.system_fan, &dev.device, speed).await?;
                 set_devices.push((hash, *speed));
             }
         }
 
         // cleanup storage
-        let map: CoolingMap = HashMap::from_iter(set_devices.into_iter());
+        let map: CoolingMap = HashMap::from_iter(set_devices);
         info!("loaded cooling devices: {:?}", map);
         self.app_db.set(COOLING_DEVICES, map).await;
 
@@ -204,6 +262,83 @@ impl BmcApplication {
         )
     }
 
+    /// Bit-field of the slots that hold a module, `None` when the board
+    /// cannot detect modules.
+    pub fn slot_presence(&self) -> anyhow::Result<Option<u8>> {
+        self.pin_controller.read_presence()
+    }
+
+    /// State of the ATX power supply, `None` when the board cannot switch it.
+    pub fn power_supply(&self) -> anyhow::Result<Option<PsuState>> {
+        self.power_controller.read_psu()
+    }
+
+    /// Follows PS_OK of the power supply, `None` when it has to be polled.
+    pub fn watch_power_good(&self) -> Option<tokio::sync::watch::Receiver<bool>> {
+        self.power_controller.watch_power_good()
+    }
+
+    fn power_supply_off(&self) -> bool {
+        match self.power_supply() {
+            Ok(state) => state.is_some_and(|s| !s.enabled),
+            Err(e) => {
+                tracing::warn!("reading power supply state: {:#}", e);
+                false
+            }
+        }
+    }
+
+    /// Switches the ATX power supply. Nodes are powered off before the
+    /// supply goes down, and stay off when it comes back up.
+    pub async fn set_power_supply(&self, on: bool) -> anyhow::Result<()> {
+        if !on {
+            let activated = self.app_db.get::<u8>(ACTIVATED_NODES_KEY).await;
+            if activated != 0 {
+                self.activate_slot(0, activated)
+                    .await
+                    .context("powering off nodes")?;
+            }
+        }
+        info!("switching power supply {}", if on { "on" } else { "off" });
+        self.power_controller.set_psu(on).await
+    }
+
+    /// Reads the pin called `pin` of `node`, see [`BoardProfile::pins`].
+    pub fn read_pin(&self, node: NodeId, pin: &str) -> anyhow::Result<bool> {
+        self.pin_controller.read_pin(node, pin)
+    }
+
+    /// Drives the pin called `pin` of `node`, regardless of its access
+    /// policy.
+    pub fn write_pin(&self, node: NodeId, pin: &str, value: bool) -> anyhow::Result<()> {
+        self.pin_controller.write_pin(node, pin, value)
+    }
+
+    /// Where the microSD slot is connected to, `None` when the board cannot
+    /// switch it.
+    pub fn sd_route(&self) -> anyhow::Result<Option<SdRoute>> {
+        self.pin_controller.read_sd_route()
+    }
+
+    /// Connects the microSD slot, see `app::sd_card` for the checks that
+    /// belong before.
+    pub fn select_sd(&self, route: SdRoute) -> anyhow::Result<()> {
+        self.pin_controller.select_sd(route)
+    }
+
+    /// Slots that are known to be empty. Nothing is known to be empty when
+    /// the board cannot detect modules.
+    fn empty_slots(&self) -> u8 {
+        match self.slot_presence() {
+            Ok(Some(present)) => !present & self.board.node_mask(),
+            Ok(None) => 0,
+            Err(e) => {
+                tracing::warn!("reading slot presence: {:#}", e);
+                0
+            }
+        }
+    }
+
     /// routine to support legacy API
     pub async fn get_node_power(&self, node: NodeId) -> anyhow::Result<bool> {
         let state = self.app_db.try_get::<u8>(ACTIVATED_NODES_KEY).await?;
@@ -220,7 +355,37 @@ impl BmcApplication {
             mask
         );
         ensure!(mask != 0);
+        let empty = node_states & mask & self.empty_slots();
+        if empty != 0 {
+            let node = NodeId::try_from(empty.trailing_zeros() as u8).expect("bit is a node");
+            return Err(BmcError::EmptySlot(node).into());
+        }
+        if node_states & mask != 0 && self.power_supply_off() {
+            return Err(BmcError::PowerSupplyOff.into());
+        }
+
+        let state = self.app_db.get::<u8>(ACTIVATED_NODES_KEY).await;
+        let kubernetes = self.kubernetes.get();
+        if let Some(kubernetes) = kubernetes {
+            kubernetes
+                .prepare_power_off(state & mask & !node_states)
+                .await?;
+        }
+        self.set_slot_power(node_states, mask).await?;
+        if let Some(kubernetes) = kubernetes {
+            kubernetes.after_power_on(!state & mask & node_states);
+        }
+        Ok(())
+    }
+
+    /// Powers off the slots in `mask` right away, without draining their
+    /// Kubernetes nodes first. Meant for emergencies such as a brownout.
+    pub async fn cut_slot_power(&self, mask: u8) -> anyhow::Result<()> {
+        ensure!(mask != 0);
+        self.set_slot_power(0, mask).await
+    }
 
+    async fn set_slot_power(&self, node_states: u8, mask: u8) -> anyhow::Result<()> {
         let state = self.app_db.get::<u8>(ACTIVATED_NODES_KEY).await;
         let new_state = (state & !mask) | (node_states & mask);
 
@@ -313,7 +478,7 @@ impl BmcApplication {
         } else {
             (0u8, node_bits)
         };
-        Ok(self.pin_controller.set_usb_boot(state, mask)?)
+        self.pin_controller.set_usb_boot(state, mask)
     }
 
     pub async fn rtl_reset(&self) -> anyhow::Result<()> {
@@ -335,7 +500,16 @@ impl BmcApplication {
     }
 
     pub async fn reset_node(&self, node: NodeId) -> anyhow::Result<()> {
-        self.power_controller.reset_node(node).await
+        let powered = self.get_node_power(node).await.unwrap_or(true);
+        let kubernetes = self.kubernetes.get().filter(|_| powered);
+        if let Some(kubernetes) = kubernetes {
+            kubernetes.prepare_power_off(node.to_bitfield()).await?;
+        }
+        self.power_controller.reset_node(node).await?;
+        if let Some(kubernetes) = kubernetes {
+            kubernetes.after_power_on(node.to_bitfield());
+        }
+        Ok(())
     }
 
     pub async fn node_in_msd(&self, node: NodeId) -> anyhow::Result<PathBuf> {
@@ -354,14 +528,26 @@ impl BmcApplication {
         Ok(blk_dev)
     }
 
-    pub async fn node_in_flash(
-        &self,
-        node: NodeId,
-        router: UsbRoute,
-    ) -> anyhow::Result<impl 'static + AsyncRead + AsyncWrite + AsyncSeek + Unpin> {
+    /// Boots `node` into its USB flashing mode and returns the block device
+    /// that exposes its storage.
+    pub async fn node_in_flash(&self, node: NodeId, router: UsbRoute) -> anyhow::Result<PathBuf> {
         self.reboot_into_usb(node, UsbConfig::Flashing(node, router))
             .await?;
-        Ok(self.node_drivers.load_as_stream().await?)
+        Ok(self.node_drivers.load_as_block_device().await?)
+    }
+
+    /// Boots `node` into its USB boot ROM to find out which module is
+    /// installed and powers it off again. Nodes that are on are left alone.
+    pub async fn identify_module(&self, node: NodeId) -> anyhow::Result<ModuleIdentity> {
+        ensure!(!self.get_node_power(node).await?, "{} is powered on", node);
+        let usb_config = self.app_db.get::<UsbConfig>(USB_CONFIG).await;
+        self.reboot_into_usb(node, UsbConfig::Flashing(node, UsbRoute::Bmc))
+            .await?;
+        let identity = self.node_drivers.identify().await;
+
+        self.activate_slot(0, node.to_bitfield()).await?;
+        self.configure_usb_internal(usb_config).await?;
+        Ok(identity?)
     }
 
     async fn reboot_into_usb(&self, node: NodeId, config: UsbConfig) -> anyhow::Result<()> {
@@ -381,10 +567,42 @@ impl BmcApplication {
 
     pub fn clear_usb_boot(&self) -> anyhow::Result<()> {
         self.pin_controller
-            .set_usb_boot(0u8, 0b1111)
+            .set_usb_boot(0u8, self.board.node_mask())
             .context("error clearing usbboot")
     }
 
+    pub fn usb_architecture(&self) -> UsbArchitecture {
+        self.pin_controller.usb_bus_type()
+    }
+
+    pub fn board(&self) -> &'static BoardProfile {
+        self.board
+    }
+
+    /// Verifies that the GPIO lines of the board can be accessed.
+    pub fn check_hal(&self) -> anyhow::Result<()> {
+        self.power_controller.read_node_states().map(|_| ())
+    }
+
+    /// Persists pending changes and takes back the USB resources that were
+    /// handed to the nodes, before the daemon exits.
+    pub async fn shutdown(&self) -> anyhow::Result<()> {
+        self.app_db.sync_all().await?;
+        // a node must not be left waiting in its USB boot ROM
+        self.clear_usb_boot()?;
+        remove_msd_function_from_usb_gadget().await
+    }
+
+    /// The power LED is lit while a node is powered, see
+    /// [`Self::activate_slot`].
+    pub async fn power_led(&self, on: bool) -> anyhow::Result<()> {
+        self.power_controller.power_led(on).await
+    }
+
+    pub async fn status_led(&self, on: bool) -> anyhow::Result<()> {
+        self.power_controller.status_led(on).await
+    }
+
     pub async fn reboot(&self, fel: bool) -> anyhow::Result<()> {
         if fel {
             let mut mem = OpenOptions::new().write(true).open("/dev/mem").await?;
@@ -429,6 +647,34 @@ impl BmcApplication {
         Ok(())
     }
 
+    /// Applies the declarative parts of the configuration that are owned by
+    /// the [`BmcApplication`]: node meta-data and the hostname.
+    pub async fn apply_config(&self, config: &Config) -> anyhow::Result<()> {
+        let mut node_infos = HashMap::new();
+        for node in &config.nodes {
+            let id = NodeId::try_from(node.node - 1).map_err(anyhow::Error::msg)?;
+            node_infos.insert(
+                id,
+                NodeInfo {
+                    name: node.name.clone(),
+                    module_name: node.module_name.clone(),
+                    power_on_time: None,
+                    uart_baud: node.uart_baud,
+                },
+            );
+        }
+
+        if !node_infos.is_empty() {
+            self.set_node_info(node_infos).await?;
+        }
+
+        if let Some(hostname) = &config.network.hostname {
+            set_hostname(hostname).await?;
+        }
+
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     pub async fn get_node_infos(&self) -> anyhow::Result<NodeInfos> {
         let Some(current_time) = utils::get_timestamp_unix() else {
@@ -446,7 +692,7 @@ impl BmcApplication {
     }
 
     pub async fn set_cooling_speed(&self, device: &str, speed: c_ulong) -> anyhow::Result<()> {
-        let res = set_cooling_state(device, &speed).await;
+        let res = set_cooling_state(self.board.system_fan, device, &speed).await;
 
         if res.is_ok() {
             let mut cooling = self.app_db.get::<CoolingMap>(COOLING_DEVICES).await;
@@ -471,3 +717,20 @@ impl BmcApplication {
         Ok(get_cooling_state().await)
     }
 }
+
+pub async fn set_hostname(hostname: &str) -> anyhow::Result<()> {
+    let current = tokio::fs::read_to_string("/proc/sys/kernel/hostname")
+        .await
+        .unwrap_or_default();
+    if current.trim_end() == hostname {
+        return Ok(());
+    }
+
+    info!("changing hostname to {}", hostname);
+    tokio::fs::write("/etc/hostname", format!("{}\n", hostname))
+        .await
+        .context("/etc/hostname")?;
+    let status = Command::new("hostname").arg(hostname).status()?;
+    ensure!(status.success(), "hostname returned: {}", status);
+    Ok(())
+}
@@ -12,7 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::utils::{is_link_local, ScopedIp};
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
 use std::path::Path;
+use tokio::io::AsyncBufReadExt;
 
 use serde::Serialize;
 
@@ -49,9 +54,18 @@ pub async fn get_net_interfaces() -> Vec<NetInfo> {
         .iter()
         .filter(|i| !i.is_loopback() || i.is_link_local())
     {
+        // link-local addresses are shown with their zone, e.g. `fe80::1%br0`
+        let zone = match interface.ip() {
+            IpAddr::V6(ip) if is_link_local(&ip) => Some(interface.name.clone()),
+            _ => None,
+        };
+        let ip = ScopedIp {
+            ip: interface.ip(),
+            zone,
+        };
         result.push(NetInfo {
             device: interface.name.clone(),
-            ip: interface.ip().to_string(),
+            ip: ip.to_string(),
             mac: get_mac_address(&interface.name).await,
         });
     }
@@ -104,3 +118,26 @@ pub fn get_storage_info() -> Vec<StorageInfo> {
     }
     info
 }
+
+/// Fields of `/etc/os-release`, values keep their quotes.
+pub async fn read_os_release() -> std::io::Result<HashMap<String, String>> {
+    let buffer = tokio::fs::read("/etc/os-release").await?;
+    let mut lines = buffer.lines();
+    let mut results = HashMap::new();
+
+    while let Some(line) = lines.next_line().await? {
+        if let Some((key, value)) = line.split_once('=') {
+            results.insert(key.to_string(), value.to_string());
+        }
+    }
+    Ok(results)
+}
+
+pub async fn read_hostname() -> io::Result<String> {
+    let hostname = tokio::fs::read_to_string("/proc/sys/kernel/hostname")
+        .await?
+        .trim_end_matches(['\0', '\n'])
+        .to_string();
+
+    Ok(hostname)
+}
@@ -0,0 +1,185 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Features that depend on the revision of the board and on what the running
+//! kernel provides. They are detected once at startup and reported at
+//! `/about`, so clients can hide what the board cannot do. Requests for a
+//! missing feature are refused with `not_supported`.
+use super::bmc_application::BmcApplication;
+use super::kvm::capture_devices;
+use super::usb_gadget::BMC_USB_OTG;
+use super::wifi::WifiManager;
+use crate::config::Config;
+use crate::error::BmcError;
+use crate::hal::eeprom::BoardIdentity;
+use crate::hal::expansion::Expansions;
+use crate::hal::rtc::Rtc;
+use crate::hal::UsbArchitecture;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// detect pins tell whether a module is seated in a slot
+    NodePresence,
+    /// every node has its own USB port on a hub, instead of a shared bus
+    UsbPerNode,
+    /// the USB of node 1 can be routed to the alternative port
+    Node1UsbRoute,
+    /// nodes can mount images of the BMC as USB mass storage
+    UsbMassStorage,
+    Rtc,
+    IdEeprom,
+    Wifi,
+    ExpansionBoards,
+    /// node activity is inferred from current sensors
+    CurrentSensing,
+    /// A/B firmware slots with rollback
+    FirmwareSlots,
+    HardwareWatchdog,
+    /// an HDMI capture dongle is plugged into the BMC, see `app::kvm`
+    Kvm,
+    /// the microSD slot can be connected to the nodes, see `app::sd_card`
+    SdMux,
+}
+
+impl Capability {
+    fn missing(self) -> &'static str {
+        match self {
+            Capability::NodePresence => "this board cannot detect modules",
+            Capability::UsbPerNode => "the nodes of this board share one USB bus",
+            Capability::Node1UsbRoute => "this board has no alternative USB port for node 1",
+            Capability::UsbMassStorage => "the kernel provides no USB gadget for mass storage",
+            Capability::Rtc => "this board has no RTC",
+            Capability::IdEeprom => "this board has no ID EEPROM",
+            Capability::Wifi => "this board has no Wi-Fi radio",
+            Capability::ExpansionBoards => "no expansion boards are fitted",
+            Capability::CurrentSensing => "no current sensors are configured",
+            Capability::FirmwareSlots => "no firmware slots are configured",
+            Capability::HardwareWatchdog => "the kernel provides no hardware watchdog",
+            Capability::Kvm => "no supported HDMI capture dongle is plugged in",
+            Capability::SdMux => "this board cannot switch its microSD slot",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(transparent)]
+pub struct Capabilities(BTreeMap<Capability, bool>);
+
+impl Capabilities {
+    pub fn detect(
+        bmc: &BmcApplication,
+        identity: &BoardIdentity,
+        rtc: &Rtc,
+        wifi: &WifiManager,
+        expansions: &Expansions,
+        config: &Config,
+    ) -> Self {
+        let usb_hub = bmc.usb_architecture() == UsbArchitecture::UsbHub;
+        let flags = [
+            (
+                Capability::NodePresence,
+                bmc.slot_presence().ok().flatten().is_some(),
+            ),
+            (Capability::UsbPerNode, usb_hub),
+            (Capability::Node1UsbRoute, usb_hub),
+            (Capability::UsbMassStorage, Path::new(BMC_USB_OTG).exists()),
+            (Capability::Rtc, rtc.is_available()),
+            (Capability::IdEeprom, identity.identity().is_some()),
+            (Capability::Wifi, wifi.is_available()),
+            (
+                Capability::ExpansionBoards,
+                !expansions.modules().is_empty(),
+            ),
+            (
+                Capability::CurrentSensing,
+                !config.activity.sensors.is_empty(),
+            ),
+            (Capability::FirmwareSlots, config.firmware.is_some()),
+            (
+                Capability::HardwareWatchdog,
+                config.watchdog.device.exists(),
+            ),
+            (Capability::Kvm, !capture_devices().is_empty()),
+            (Capability::SdMux, bmc.sd_route().ok().flatten().is_some()),
+        ];
+        let capabilities = Self(flags.into_iter().collect());
+        tracing::info!("capabilities: {}", capabilities);
+        capabilities
+    }
+
+    pub fn has(&self, capability: Capability) -> bool {
+        self.0.get(&capability).copied().unwrap_or(false)
+    }
+
+    pub fn ensure(&self, capability: Capability) -> Result<(), BmcError> {
+        if self.has(capability) {
+            Ok(())
+        } else {
+            Err(BmcError::NotSupported(capability.missing().into()))
+        }
+    }
+}
+
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let present: Vec<_> = self
+            .0
+            .iter()
+            .filter(|(_, present)| **present)
+            .filter_map(|(capability, _)| serde_json::to_value(capability).ok())
+            .filter_map(|name| name.as_str().map(str::to_string))
+            .collect();
+        f.write_str(&present.join(", "))
+    }
+}
+
+/// Capability that a request of the legacy API needs.
+pub fn legacy_requirement(ty: &str, is_set: bool) -> Option<Capability> {
+    match (ty, is_set) {
+        ("node_to_msd", true) => Some(Capability::UsbMassStorage),
+        ("usb_node1", true) => Some(Capability::Node1UsbRoute),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+
+    #[test]
+    fn gating() {
+        let capabilities = Capabilities(BTreeMap::from([
+            (Capability::Rtc, true),
+            (Capability::Node1UsbRoute, false),
+        ]));
+        assert!(capabilities.ensure(Capability::Rtc).is_ok());
+        let error = capabilities.ensure(Capability::Node1UsbRoute).unwrap_err();
+        assert_eq!(error.code(), "not_supported");
+        assert!(!capabilities.has(Capability::Wifi));
+        assert_eq!(capabilities.to_string(), "rtc");
+        assert_eq!(
+            serde_json::to_value(&capabilities).unwrap(),
+            serde_json::json!({ "node1_usb_route": false, "rtc": true })
+        );
+        assert_eq!(
+            legacy_requirement("usb_node1", true),
+            Some(Capability::Node1UsbRoute)
+        );
+        assert_eq!(legacy_requirement("usb_node1", false), None);
+    }
+}
@@ -0,0 +1,253 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Management of several boards through a single bmcd. The peers are other
+//! bmcd instances declared in the `cluster` section of the configuration.
+//! Requests are made with the account configured for the peer, so a client
+//! only needs credentials for this board.
+use crate::config::{self, ClusterPeer};
+use crate::error::BmcError;
+use anyhow::{bail, Context};
+use bytes::Bytes;
+use reqwest::header::{HeaderValue, CONTENT_TYPE};
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::Serialize;
+use serde_json::Value;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+/// Name of this board in combined responses. Peers cannot use it.
+pub const LOCAL: &str = "local";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+pub struct PeerStatus {
+    pub name: String,
+    pub url: String,
+    /// the peer answered with its credentials accepted
+    pub reachable: bool,
+    pub ready: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub struct Cluster {
+    config: RwLock<config::Cluster>,
+    client: reqwest::Client,
+    insecure_client: reqwest::Client,
+}
+
+impl Cluster {
+    pub fn new(config: config::Cluster) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()?;
+        let insecure_client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .danger_accept_invalid_certs(true)
+            .build()?;
+        Ok(Self {
+            config: RwLock::new(config),
+            client,
+            insecure_client,
+        })
+    }
+
+    pub async fn set_config(&self, config: config::Cluster) {
+        *self.config.write().await = config;
+    }
+
+    /// Base URLs of the peers, e.g. `https://10.0.0.12`.
+    pub async fn peer_urls(&self) -> Vec<String> {
+        let config = self.config.read().await;
+        config
+            .peers
+            .iter()
+            .map(|p| p.url.trim_end_matches('/').to_string())
+            .collect()
+    }
+
+    pub async fn peer_names(&self) -> Vec<String> {
+        let config = self.config.read().await;
+        config.peers.iter().map(|p| p.name.clone()).collect()
+    }
+
+    async fn peer(&self, name: &str) -> Result<ClusterPeer, BmcError> {
+        let config = self.config.read().await;
+        config
+            .peers
+            .iter()
+            .find(|p| p.name == name)
+            .cloned()
+            .ok_or_else(|| BmcError::invalid_parameter("peer", format!("no peer named {}", name)))
+    }
+
+    /// `path` is relative to `/api/bmc` of the peer and includes the query.
+    fn request(&self, peer: &ClusterPeer, method: Method, path: &str) -> RequestBuilder {
+        let client = if peer.insecure {
+            &self.insecure_client
+        } else {
+            &self.client
+        };
+        let url = format!("{}/api/bmc{}", peer.url.trim_end_matches('/'), path);
+        client
+            .request(method, url)
+            .basic_auth(&peer.user, Some(&peer.password))
+    }
+
+    /// Passes a request on to a peer as is and returns its response. Only
+    /// the content type of the request is forwarded.
+    pub async fn forward(
+        &self,
+        name: &str,
+        method: Method,
+        path: &str,
+        content_type: Option<HeaderValue>,
+        body: Bytes,
+    ) -> anyhow::Result<reqwest::Response> {
+        let peer = self.peer(name).await?;
+        let mut request = self.request(&peer, method, path).body(body);
+        if let Some(content_type) = content_type {
+            request = request.header(CONTENT_TYPE, content_type);
+        }
+        request
+            .send()
+            .await
+            .with_context(|| format!("peer {}", name))
+    }
+
+    /// Makes the same request to `names`, or to every peer when `None`, and
+    /// returns the results of the peers in the same order.
+    pub async fn call_all(
+        &self,
+        names: Option<&[String]>,
+        method: Method,
+        path: &str,
+    ) -> Vec<(String, anyhow::Result<Value>)> {
+        let (peers, timeout) = {
+            let config = self.config.read().await;
+            let peers: Vec<_> = match names {
+                Some(names) => names.to_vec(),
+                None => config.peers.iter().map(|p| p.name.clone()).collect(),
+            };
+            (peers, config.timeout)
+        };
+        let calls = peers.into_iter().map(|name| {
+            let method = method.clone();
+            async move {
+                let result = async {
+                    let peer = self.peer(&name).await?;
+                    let response = self
+                        .request(&peer, method, path)
+                        .timeout(timeout)
+                        .send()
+                        .await?;
+                    let status = response.status();
+                    let body = response.bytes().await?;
+                    unwrap_envelope(status, &body)
+                }
+                .await;
+                (name, result)
+            }
+        });
+        futures::future::join_all(calls).await
+    }
+
+    pub async fn status(&self) -> Vec<PeerStatus> {
+        let urls: Vec<_> = {
+            let config = self.config.read().await;
+            config.peers.iter().map(|p| p.url.clone()).collect()
+        };
+        self.call_all(None, Method::GET, "/ready")
+            .await
+            .into_iter()
+            .zip(urls)
+            .map(|((name, result), url)| match result {
+                Ok(status) => PeerStatus {
+                    name,
+                    url,
+                    reachable: true,
+                    ready: status["ready"].as_bool().unwrap_or_default(),
+                    error: None,
+                },
+                Err(e) => PeerStatus {
+                    name,
+                    url,
+                    // a peer that is still starting answers with 503
+                    reachable: e.downcast_ref::<PeerError>().is_some_and(|e| {
+                        e.status != StatusCode::UNAUTHORIZED && e.status != StatusCode::FORBIDDEN
+                    }),
+                    ready: false,
+                    error: Some(format!("{:#}", e)),
+                },
+            })
+            .collect()
+    }
+}
+
+/// A peer answered with an error status.
+#[derive(Debug, thiserror::Error)]
+#[error("{message} ({status})")]
+pub struct PeerError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+/// Extracts the result from the `{"response": [{"result": ..}]}` envelope
+/// of the API.
+fn unwrap_envelope(status: StatusCode, body: &[u8]) -> anyhow::Result<Value> {
+    let envelope: Value = match serde_json::from_slice(body) {
+        Ok(envelope) => envelope,
+        Err(_) if !status.is_success() => bail!(PeerError {
+            status,
+            message: String::from_utf8_lossy(body).trim().to_string(),
+        }),
+        Err(e) => return Err(e).context("peer sent an invalid response"),
+    };
+    let result = envelope
+        .pointer("/response/0/result")
+        .cloned()
+        .unwrap_or(envelope);
+    if !status.is_success() {
+        let message = match result {
+            Value::String(message) => message,
+            other => other.to_string(),
+        };
+        bail!(PeerError { status, message });
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn envelope() {
+        let body = br#"{"response":[{"result":{"ready":true}}]}"#;
+        assert_eq!(
+            unwrap_envelope(StatusCode::OK, body).unwrap(),
+            serde_json::json!({"ready": true})
+        );
+
+        let body = br#"{"response":[{"result":"node 5 does not exist","error":{}}]}"#;
+        let error = unwrap_envelope(StatusCode::BAD_REQUEST, body).unwrap_err();
+        let error = error.downcast_ref::<PeerError>().unwrap();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        assert_eq!(error.message, "node 5 does not exist");
+
+        let error = unwrap_envelope(StatusCode::UNAUTHORIZED, b"Unauthorized").unwrap_err();
+        assert_eq!(error.to_string(), "Unauthorized (401 Unauthorized)");
+        assert!(unwrap_envelope(StatusCode::OK, b"<html>").is_err());
+    }
+}
@@ -0,0 +1,424 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use crate::config::Config;
+use crate::persistency::app_persistency::{staged_restore, BIN_DATA};
+use crate::utils::{get_timestamp_unix, hmac_sha256};
+use anyhow::{bail, ensure, Context};
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder};
+use openssl::memcmp;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const ARCHIVE_VERSION: u32 = 1;
+const MANIFEST: &str = "manifest.json";
+const SIGNATURE: &str = "manifest.sig";
+const FILES_DIR: &str = "files/";
+const SIGNING_KEY_SIZE: usize = 32;
+/// Upper limit of the decompressed size of an archive.
+const MAX_TAR_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    bmcd_version: String,
+    created: Option<u64>,
+    /// name of the setting mapped to the hex encoded sha256 of its content.
+    files: HashMap<String, String>,
+}
+
+/// A single signed archive of all bmcd settings. The archive is a gzipped tar
+/// containing a manifest with the sha256 of every file, a HMAC-SHA256
+/// signature over that manifest and the files themselves.
+///
+/// Only settings known to bmcd are restored on import. Their destination is
+/// always taken from the running configuration, never from the archive.
+pub struct ConfigArchive {
+    entries: Vec<Entry>,
+    signing_key: PathBuf,
+    shared_key: Option<PathBuf>,
+}
+
+struct Entry {
+    name: &'static str,
+    /// file that is exported
+    path: PathBuf,
+    /// file an import writes to
+    restore_to: PathBuf,
+    /// only accessible by its owner
+    private: bool,
+}
+
+impl Entry {
+    fn new(name: &'static str, path: PathBuf) -> Self {
+        Self {
+            name,
+            restore_to: path.clone(),
+            path,
+            private: false,
+        }
+    }
+
+    fn private(mut self) -> Self {
+        self.private = true;
+        self
+    }
+
+    fn restore_to(mut self, path: PathBuf) -> Self {
+        self.restore_to = path;
+        self
+    }
+}
+
+impl ConfigArchive {
+    pub fn new(config_path: &Path, config: &Config) -> Self {
+        let entries = vec![
+            Entry::new("config", config_path.to_path_buf()),
+            // the running daemon writes the store, it takes the restored
+            // store on the next start.
+            Entry::new("persistency", PathBuf::from(BIN_DATA))
+                .restore_to(staged_restore(Path::new(BIN_DATA))),
+            Entry::new("tls_certificate", config.tls.certificate.clone()),
+            Entry::new("tls_private_key", config.tls.private_key.clone()).private(),
+        ];
+
+        Self {
+            entries,
+            signing_key: config.backup.signing_key.clone(),
+            shared_key: config.backup.shared_key.clone(),
+        }
+    }
+
+    /// Creates the archive. Settings that are not present on the file-system
+    /// are left out.
+    pub async fn export(&self) -> anyhow::Result<Vec<u8>> {
+        let mut files = Vec::new();
+        for entry in &self.entries {
+            match tokio::fs::read(&entry.path).await {
+                Ok(content) => files.push((entry.name, content)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => tracing::debug!(
+                    "{} not exported, {} does not exist",
+                    entry.name,
+                    entry.path.display()
+                ),
+                Err(e) => return Err(e).context(entry.path.display().to_string()),
+            }
+        }
+
+        let manifest = Manifest {
+            version: ARCHIVE_VERSION,
+            bmcd_version: env!("CARGO_PKG_VERSION").to_string(),
+            created: get_timestamp_unix(),
+            files: files
+                .iter()
+                .map(|(name, content)| (name.to_string(), sha256_hex(content)))
+                .collect(),
+        };
+        let manifest = serde_json::to_vec_pretty(&manifest)?;
+        let key = match &self.shared_key {
+            Some(shared_key) => load_shared_key(shared_key).await?,
+            None => self.load_signing_key().await?,
+        };
+        let signature = hex::encode(hmac_sha256(&key, &manifest)?);
+
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.mode(tar::HeaderMode::Deterministic);
+        append_file(&mut builder, MANIFEST, &manifest)?;
+        append_file(&mut builder, SIGNATURE, signature.as_bytes())?;
+        for (name, content) in &files {
+            append_file(&mut builder, &format!("{FILES_DIR}{name}"), content)?;
+        }
+        let tar = builder.into_inner()?;
+
+        let mut archive = Vec::new();
+        GzipEncoder::new(Cursor::new(tar))
+            .read_to_end(&mut archive)
+            .await?;
+        Ok(archive)
+    }
+
+    /// Verifies the signature and integrity of the given archive and writes
+    /// its settings to the file-system. Nothing is written when any of the
+    /// checks fail. Returns the names of the restored settings.
+    ///
+    /// The archive is accepted when it is signed with the shared key or with
+    /// the key of this board.
+    pub async fn import(&self, archive: &[u8]) -> anyhow::Result<Vec<String>> {
+        let mut tar = Vec::new();
+        GzipDecoder::new(archive)
+            .take(MAX_TAR_SIZE as u64 + 1)
+            .read_to_end(&mut tar)
+            .await
+            .context("archive is not gzip compressed")?;
+        ensure!(
+            tar.len() <= MAX_TAR_SIZE,
+            "archive exceeds {} bytes when decompressed",
+            MAX_TAR_SIZE
+        );
+
+        let mut contents = read_tar(&tar)?;
+        let manifest = contents
+            .remove(MANIFEST)
+            .context("archive contains no manifest")?;
+        let signature = contents
+            .remove(SIGNATURE)
+            .context("archive is not signed")?;
+        let signature = hex::decode(signature).context("invalid signature encoding")?;
+        let mut keys = Vec::new();
+        if let Some(shared_key) = &self.shared_key {
+            keys.push(load_shared_key(shared_key).await?);
+        }
+        keys.push(self.load_signing_key().await?);
+        let mut verified = false;
+        for key in keys {
+            let expected = hmac_sha256(&key, &manifest)?;
+            verified |= signature.len() == expected.len() && memcmp::eq(&signature, &expected);
+        }
+        ensure!(
+            verified,
+            "archive signature does not match a signing key of this board"
+        );
+
+        let manifest: Manifest = serde_json::from_slice(&manifest)?;
+        ensure!(
+            manifest.version == ARCHIVE_VERSION,
+            "archive version {} not supported",
+            manifest.version
+        );
+
+        let mut restore = Vec::new();
+        for (name, sha256) in &manifest.files {
+            let Some(entry) = self.entries.iter().find(|e| e.name == name) else {
+                bail!("archive contains unknown setting `{}`", name);
+            };
+            let content = contents
+                .remove(&format!("{FILES_DIR}{name}"))
+                .with_context(|| format!("{} is missing from the archive", name))?;
+            ensure!(
+                &sha256_hex(&content) == sha256,
+                "checksum mismatch for {}",
+                name
+            );
+            restore.push((entry, content));
+        }
+
+        let mut restored = Vec::new();
+        for (entry, content) in restore {
+            if entry.private {
+                write_private(&entry.restore_to, &content).await?;
+            } else {
+                write_atomic(&entry.restore_to, &content).await?;
+            }
+            tracing::info!("restored {} to {}", entry.name, entry.restore_to.display());
+            restored.push(entry.name.to_string());
+        }
+
+        Ok(restored)
+    }
+
+    async fn load_signing_key(&self) -> anyhow::Result<Vec<u8>> {
+        match tokio::fs::read(&self.signing_key).await {
+            Ok(key) => {
+                ensure!(!key.is_empty(), "{} is empty", self.signing_key.display());
+                Ok(key)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::info!("generating signing key {}", self.signing_key.display());
+                let key: [u8; SIGNING_KEY_SIZE] = rand::random();
+                write_private(&self.signing_key, &key).await?;
+                Ok(key.to_vec())
+            }
+            Err(e) => Err(e).context(self.signing_key.display().to_string()),
+        }
+    }
+}
+
+async fn load_shared_key(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let key = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("shared key {}", path.display()))?;
+    ensure!(!key.is_empty(), "{} is empty", path.display());
+    Ok(key)
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn append_file(
+    builder: &mut tar::Builder<Vec<u8>>,
+    name: &str,
+    content: &[u8],
+) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o600);
+    header.set_cksum();
+    builder.append_data(&mut header, name, content)
+}
+
+fn read_tar(tar: &[u8]) -> anyhow::Result<HashMap<String, Vec<u8>>> {
+    let mut contents = HashMap::new();
+    let mut archive = tar::Archive::new(tar);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        contents.insert(name, content);
+    }
+    Ok(contents)
+}
+
+/// Replaces the file at `path` with `content` in one step.
+pub async fn write_atomic(path: &Path, content: &[u8]) -> anyhow::Result<()> {
+    replace_file(path, content, 0o666).await
+}
+
+/// Like [`write_atomic`], but the file is only accessible by its owner.
+pub async fn write_private(path: &Path, content: &[u8]) -> anyhow::Result<()> {
+    replace_file(path, content, 0o600).await
+}
+
+async fn replace_file(path: &Path, content: &[u8], mode: u32) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let mut new = path.to_path_buf();
+    new.set_extension("new");
+    // a left-over file keeps its permissions, create it anew with `mode`.
+    let _ = tokio::fs::remove_file(&new).await;
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&new)
+        .await
+        .with_context(|| new.display().to_string())?;
+    file.write_all(content).await?;
+    file.flush().await?;
+    drop(file);
+    tokio::fs::rename(&new, path)
+        .await
+        .with_context(|| path.display().to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempdir::TempDir;
+
+    fn archive_in(dir: &Path) -> ConfigArchive {
+        ConfigArchive {
+            entries: vec![
+                Entry::new("config", dir.join("config.yaml")),
+                Entry::new("persistency", dir.join("bmcd.bin"))
+                    .restore_to(staged_restore(&dir.join("bmcd.bin"))),
+                Entry::new("tls_private_key", dir.join("key.pem")).private(),
+            ],
+            signing_key: dir.join("backup.key"),
+            shared_key: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn export_import_roundtrip() {
+        let dir = TempDir::new("config_archive").unwrap();
+        let config_archive = archive_in(dir.path());
+        std::fs::write(dir.path().join("config.yaml"), "port: 8443\n").unwrap();
+
+        let archive = config_archive.export().await.unwrap();
+        std::fs::write(dir.path().join("config.yaml"), "port: 1\n").unwrap();
+
+        let restored = config_archive.import(&archive).await.unwrap();
+        assert_eq!(restored, vec!["config".to_string()]);
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("config.yaml")).unwrap(),
+            "port: 8443\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_foreign_signing_key() {
+        let dir = TempDir::new("config_archive").unwrap();
+        let config_archive = archive_in(dir.path());
+        std::fs::write(dir.path().join("config.yaml"), "port: 8443\n").unwrap();
+        let archive = config_archive.export().await.unwrap();
+
+        std::fs::write(dir.path().join("backup.key"), b"another key").unwrap();
+        std::fs::write(dir.path().join("config.yaml"), "port: 1\n").unwrap();
+        assert!(config_archive.import(&archive).await.is_err());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("config.yaml")).unwrap(),
+            "port: 1\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn stage_persistency_and_protect_keys() {
+        let dir = TempDir::new("config_archive").unwrap();
+        let config_archive = archive_in(dir.path());
+        std::fs::write(dir.path().join("bmcd.bin"), b"store").unwrap();
+        std::fs::write(dir.path().join("key.pem"), b"key").unwrap();
+        let archive = config_archive.export().await.unwrap();
+
+        std::fs::write(dir.path().join("bmcd.bin"), b"newer store").unwrap();
+        config_archive.import(&archive).await.unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join("bmcd.bin")).unwrap(),
+            b"newer store"
+        );
+        assert_eq!(
+            std::fs::read(staged_restore(&dir.path().join("bmcd.bin"))).unwrap(),
+            b"store"
+        );
+
+        for file in ["key.pem", "backup.key"] {
+            let mode = std::fs::metadata(dir.path().join(file))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600, "{}", file);
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_shared_key() {
+        let dir = TempDir::new("config_archive").unwrap();
+        std::fs::write(dir.path().join("shared.key"), b"fleet key").unwrap();
+        let board_a = TempDir::new("config_archive").unwrap();
+        let board_b = TempDir::new("config_archive").unwrap();
+        let mut archive_a = archive_in(board_a.path());
+        archive_a.shared_key = Some(dir.path().join("shared.key"));
+        let mut archive_b = archive_in(board_b.path());
+        archive_b.shared_key = Some(dir.path().join("shared.key"));
+
+        std::fs::write(board_a.path().join("config.yaml"), "port: 8443\n").unwrap();
+        let archive = archive_a.export().await.unwrap();
+        archive_b.import(&archive).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(board_b.path().join("config.yaml")).unwrap(),
+            "port: 8443\n"
+        );
+
+        archive_b.shared_key = None;
+        assert!(archive_b.import(&archive).await.is_err());
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use super::bmc_application::BmcApplication;
+use super::cluster::Cluster;
+use super::http_policy::HttpPolicy;
+use super::notifier::Notifier;
+use crate::authentication::linux_authenticator::LinuxAuthenticator;
+use crate::config::Config;
+use crate::utils::get_timestamp_unix;
+use crate::utils::memory::memory;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConfigStatus {
+    pub path: PathBuf,
+    /// unix timestamp of the last successful (re)load
+    pub loaded_at: Option<u64>,
+    /// validation error of the last reload attempt, if it failed.
+    pub error: Option<String>,
+    /// sections that changed during the last reload, but require a restart
+    /// of the daemon before they take effect.
+    pub restart_required: Vec<&'static str>,
+}
+
+/// Owns the active configuration of the daemon. Subsystems that support hot
+/// reloading subscribe to changes using [`ConfigService::subscribe`]. A
+/// configuration that fails to load or validate is never published; the
+/// daemon keeps running with the previous configuration and the error is
+/// reported through [`ConfigService::status`].
+pub struct ConfigService {
+    sender: watch::Sender<Arc<Config>>,
+    status: Mutex<ConfigStatus>,
+    notifier: Arc<Notifier>,
+}
+
+impl ConfigService {
+    pub fn new(path: PathBuf, config: Config, notifier: Arc<Notifier>) -> Self {
+        let status = ConfigStatus {
+            path,
+            loaded_at: get_timestamp_unix(),
+            ..Default::default()
+        };
+
+        Self {
+            sender: watch::Sender::new(Arc::new(config)),
+            status: Mutex::new(status),
+            notifier,
+        }
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.sender.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Config>> {
+        self.sender.subscribe()
+    }
+
+    pub fn status(&self) -> ConfigStatus {
+        self.status.lock().expect("status lock poisoned").clone()
+    }
+
+    /// Reads and validates the configuration file. On success, the new
+    /// configuration is published to all subscribers.
+    pub async fn reload(&self) -> anyhow::Result<ConfigStatus> {
+        let path = self.status().path;
+        let config = match Config::load(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                let message = format!("{:#}", e);
+                tracing::error!("configuration not reloaded: {}", message);
+                self.status.lock().expect("status lock poisoned").error = Some(message.clone());
+                self.notifier.notify("config_reload_failed", message).await;
+                return Err(e);
+            }
+        };
+
+        let mut status = self.status.lock().expect("status lock poisoned");
+        status.restart_required = self.current().restart_required(&config);
+        status.loaded_at = get_timestamp_unix();
+        status.error = None;
+        if !status.restart_required.is_empty() {
+            tracing::warn!(
+                "changes in {} require a restart to take effect",
+                status.restart_required.join(", ")
+            );
+        }
+
+        self.sender.send_replace(Arc::new(config));
+        tracing::info!("reloaded configuration {}", path.to_string_lossy());
+        Ok(status.clone())
+    }
+
+    /// Reload the configuration every time the daemon receives a SIGHUP.
+    pub fn reload_on_sighup(self: Arc<Self>) -> std::io::Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                tracing::info!("SIGHUP received, reloading configuration");
+                // errors are already reported by `reload`
+                let _ = self.reload().await;
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Applies the hot-reloadable sections of the configuration to the running
+/// subsystems, once on startup and subsequently on every published change.
+pub fn run_config_watcher(
+    mut receiver: watch::Receiver<Arc<Config>>,
+    bmc: Arc<BmcApplication>,
+    authenticator: Arc<LinuxAuthenticator>,
+    notifier: Arc<Notifier>,
+    cluster: Arc<Cluster>,
+    http_policy: Arc<HttpPolicy>,
+) {
+    tokio::spawn(async move {
+        loop {
+            let config = receiver.borrow_and_update().clone();
+
+            authenticator.set_allowed_users(config.users.clone()).await;
+            authenticator
+                .set_roles(config.roles.iter().map(|r| (r.user.clone(), r.role)))
+                .await;
+            memory().set_limits(&config.memory);
+            notifier.set_targets(config.notifications.clone()).await;
+            cluster.set_config(config.cluster.clone()).await;
+            http_policy.set_config(&config.http);
+            if let Err(e) = bmc.apply_config(&config).await {
+                tracing::error!("error applying configuration: {:#}", e);
+            }
+
+            if receiver.changed().await.is_err() {
+                break;
+            }
+        }
+    });
+}
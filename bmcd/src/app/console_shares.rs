@@ -0,0 +1,299 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Links that share the console of one node with someone without an account,
+//! e.g. in a chat when asking for help. A link carries a random token that
+//! grants read-only access to the console stream and log of that node, until
+//! the link expires or an operator revokes it. Connections that are open at
+//! that moment are closed.
+//!
+//! Only the SHA-256 of a token is kept. Links do not survive a restart of
+//! bmcd.
+use crate::config;
+use crate::hal::NodeId;
+use crate::utils::get_timestamp_unix;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+
+/// Path under which shared consoles are served, outside of the authenticated
+/// API, followed by the token.
+pub const SHARE_PATH: &str = "/share/console";
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ShareError {
+    #[error("console sharing is disabled")]
+    Disabled,
+    #[error("no console share with id {0}")]
+    NotFound(String),
+    #[error("at most {0} console shares may be active at once")]
+    TooManyShares(usize),
+    #[error("a console share lasts 1 to {0} seconds")]
+    InvalidTtl(u64),
+    #[error("the link is invalid, expired or revoked")]
+    InvalidToken,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConsoleShare {
+    pub id: String,
+    /// numbered from 1
+    pub node: u8,
+    /// user that created the share, absent for local requests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    /// unix timestamps
+    pub created: u64,
+    pub expires: u64,
+}
+
+/// A share as its creator sees it once, with the token of the link.
+#[derive(Debug, Serialize)]
+pub struct NewShare {
+    #[serde(flatten)]
+    pub share: ConsoleShare,
+    pub token: String,
+    /// path of the console stream, the log is at `<path>/log`
+    pub path: String,
+}
+
+/// Access that a valid token grants.
+#[derive(Debug, Clone)]
+pub struct SharedConsole {
+    pub node: NodeId,
+    /// cancelled when the share is revoked
+    pub revoked: CancellationToken,
+    /// time left until the share expires
+    pub remaining: Duration,
+}
+
+struct Entry {
+    share: ConsoleShare,
+    token_sha256: [u8; 32],
+    expires: Instant,
+    revoked: CancellationToken,
+}
+
+#[derive(Default)]
+pub struct ConsoleShares {
+    entries: Mutex<BTreeMap<String, Entry>>,
+}
+
+impl ConsoleShares {
+    /// Shares the console of `node` for `ttl`, or the configured default.
+    pub fn create(
+        &self,
+        settings: &config::ConsoleSharing,
+        node: NodeId,
+        ttl: Option<Duration>,
+        created_by: Option<String>,
+    ) -> Result<NewShare, ShareError> {
+        self.create_at(settings, node, ttl, created_by, Instant::now())
+    }
+
+    fn create_at(
+        &self,
+        settings: &config::ConsoleSharing,
+        node: NodeId,
+        ttl: Option<Duration>,
+        created_by: Option<String>,
+        now: Instant,
+    ) -> Result<NewShare, ShareError> {
+        if !settings.enabled {
+            return Err(ShareError::Disabled);
+        }
+        let ttl = ttl.unwrap_or(settings.default_ttl);
+        if ttl.is_zero() || ttl > settings.max_ttl {
+            return Err(ShareError::InvalidTtl(settings.max_ttl.as_secs()));
+        }
+        let mut entries = self.entries.lock().expect("console shares lock poisoned");
+        prune(&mut entries, now);
+        if entries.len() >= settings.max_shares {
+            return Err(ShareError::TooManyShares(settings.max_shares));
+        }
+
+        let token = hex::encode(rand::random::<[u8; 16]>());
+        let id = hex::encode(rand::random::<[u8; 4]>());
+        let created = get_timestamp_unix().unwrap_or_default();
+        let share = ConsoleShare {
+            id: id.clone(),
+            node: node as u8 + 1,
+            created_by,
+            created,
+            expires: created + ttl.as_secs(),
+        };
+        tracing::info!(
+            "console of {} shared for {}s as {}",
+            node,
+            ttl.as_secs(),
+            id
+        );
+        entries.insert(
+            id,
+            Entry {
+                share: share.clone(),
+                token_sha256: Sha256::digest(&token).into(),
+                expires: now + ttl,
+                revoked: CancellationToken::new(),
+            },
+        );
+        Ok(NewShare {
+            share,
+            path: format!("{}/{}", SHARE_PATH, token),
+            token,
+        })
+    }
+
+    /// Active shares, without their tokens.
+    pub fn list(&self) -> Vec<ConsoleShare> {
+        let mut entries = self.entries.lock().expect("console shares lock poisoned");
+        prune(&mut entries, Instant::now());
+        entries.values().map(|entry| entry.share.clone()).collect()
+    }
+
+    /// Ends a share and disconnects whoever watches the console through it.
+    pub fn revoke(&self, id: &str) -> Result<(), ShareError> {
+        let mut entries = self.entries.lock().expect("console shares lock poisoned");
+        let entry = entries
+            .remove(id)
+            .ok_or_else(|| ShareError::NotFound(id.to_string()))?;
+        entry.revoked.cancel();
+        tracing::info!("console share {} revoked", id);
+        Ok(())
+    }
+
+    /// The console that `token` grants access to.
+    pub fn authorize(&self, token: &str) -> Result<SharedConsole, ShareError> {
+        self.authorize_at(token, Instant::now())
+    }
+
+    fn authorize_at(&self, token: &str, now: Instant) -> Result<SharedConsole, ShareError> {
+        let token_sha256: [u8; 32] = Sha256::digest(token).into();
+        let mut entries = self.entries.lock().expect("console shares lock poisoned");
+        prune(&mut entries, now);
+        let entry = entries
+            .values()
+            .find(|entry| entry.token_sha256 == token_sha256)
+            .ok_or(ShareError::InvalidToken)?;
+        Ok(SharedConsole {
+            node: NodeId::try_from(entry.share.node - 1).expect("nodes of shares are valid"),
+            revoked: entry.revoked.clone(),
+            remaining: entry.expires - now,
+        })
+    }
+}
+
+/// Drops the expired shares. Their connections end on their own, see
+/// [`SharedConsole::remaining`].
+fn prune(entries: &mut BTreeMap<String, Entry>, now: Instant) {
+    entries.retain(|_, entry| entry.expires > now);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> config::ConsoleSharing {
+        config::ConsoleSharing {
+            enabled: true,
+            default_ttl: Duration::from_secs(60),
+            max_ttl: Duration::from_secs(600),
+            max_shares: 2,
+        }
+    }
+
+    #[test]
+    fn sharing() {
+        let shares = ConsoleShares::default();
+        let now = Instant::now();
+        let new = shares
+            .create_at(&settings(), NodeId::Node3, None, Some("alice".into()), now)
+            .unwrap();
+        assert_eq!(new.share.node, 3);
+        assert_eq!(new.share.expires - new.share.created, 60);
+        assert_eq!(new.path, format!("/share/console/{}", new.token));
+        assert_eq!(shares.list(), vec![new.share.clone()]);
+
+        let console = shares
+            .authorize_at(&new.token, now + Duration::from_secs(59))
+            .unwrap();
+        assert_eq!(console.node, NodeId::Node3);
+        assert_eq!(console.remaining, Duration::from_secs(1));
+        assert_eq!(
+            shares.authorize_at("0123", now).unwrap_err(),
+            ShareError::InvalidToken
+        );
+        assert_eq!(
+            shares
+                .authorize_at(&new.token, now + Duration::from_secs(60))
+                .unwrap_err(),
+            ShareError::InvalidToken
+        );
+        assert!(shares.list().is_empty());
+    }
+
+    #[test]
+    fn revocation() {
+        let shares = ConsoleShares::default();
+        let now = Instant::now();
+        let new = shares
+            .create_at(&settings(), NodeId::Node1, None, None, now)
+            .unwrap();
+        let console = shares.authorize_at(&new.token, now).unwrap();
+        shares.revoke(&new.share.id).unwrap();
+        assert!(console.revoked.is_cancelled());
+        assert_eq!(
+            shares.authorize_at(&new.token, now).unwrap_err(),
+            ShareError::InvalidToken
+        );
+        assert_eq!(
+            shares.revoke(&new.share.id),
+            Err(ShareError::NotFound(new.share.id))
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let shares = ConsoleShares::default();
+        let now = Instant::now();
+        let create = |ttl, now| shares.create_at(&settings(), NodeId::Node2, ttl, None, now);
+        assert_eq!(
+            create(Some(Duration::from_secs(601)), now).unwrap_err(),
+            ShareError::InvalidTtl(600)
+        );
+        assert_eq!(
+            create(Some(Duration::ZERO), now).unwrap_err(),
+            ShareError::InvalidTtl(600)
+        );
+        create(Some(Duration::from_secs(600)), now).unwrap();
+        create(None, now).unwrap();
+        assert_eq!(create(None, now).unwrap_err(), ShareError::TooManyShares(2));
+        // the expired share no longer counts
+        create(None, now + Duration::from_secs(60)).unwrap();
+
+        let disabled = config::ConsoleSharing {
+            enabled: false,
+            ..settings()
+        };
+        assert_eq!(
+            shares
+                .create_at(&disabled, NodeId::Node2, None, None, now)
+                .unwrap_err(),
+            ShareError::Disabled
+        );
+    }
+}
@@ -17,6 +17,54 @@ use serde::Serialize;
 use std::{ffi::c_ulong, fs, io, path::Path};
 use tracing::{instrument, warn};
 
+#[cfg(feature = "mock")]
+pub(super) use crate::hal::mock::thermal_root;
+
+#[cfg(not(feature = "mock"))]
+pub(super) fn thermal_root() -> std::path::PathBuf {
+    Path::new("/sys/class/thermal").to_path_buf()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThermalZone {
+    /// e.g. `thermal_zone0`
+    pub name: String,
+    /// e.g. `cpu-thermal`
+    pub zone_type: Option<String>,
+    pub celsius: f64,
+}
+
+/// Thermal zones below `root` that can be read, usually [`thermal_root`].
+pub fn thermal_zones(root: &Path) -> Vec<ThermalZone> {
+    let Ok(entries) = fs::read_dir(root) else {
+        return Vec::new();
+    };
+    let mut zones: Vec<_> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with("thermal_zone") {
+                return None;
+            }
+            let milli_celsius: i32 = fs::read_to_string(entry.path().join("temp"))
+                .ok()?
+                .trim()
+                .parse()
+                .ok()?;
+            let zone_type = fs::read_to_string(entry.path().join("type"))
+                .ok()
+                .map(|t| t.trim().to_string());
+            Some(ThermalZone {
+                name,
+                zone_type,
+                celsius: f64::from(milli_celsius) / 1000.0,
+            })
+        })
+        .collect();
+    zones.sort_by(|a, b| a.name.cmp(&b.name));
+    zones
+}
+
 #[derive(Debug, Serialize)]
 pub struct CoolingDevice {
     pub device: String,
@@ -27,7 +75,7 @@ pub struct CoolingDevice {
 pub async fn get_cooling_state() -> Vec<CoolingDevice> {
     let mut result = Vec::new();
 
-    if let Ok(mut dir) = tokio::fs::read_dir("/sys/class/thermal").await {
+    if let Ok(mut dir) = tokio::fs::read_dir(thermal_root()).await {
         while let Some(device) = dir.next_entry().await.unwrap_or(None) {
             let mut device_name = device.file_name().to_string_lossy().into_owned();
             if !device_name.starts_with("cooling_device") {
@@ -101,17 +149,18 @@ fn get_pwm_fan_nodes() -> io::Result<Vec<String>> {
 }
 
 #[instrument(err)]
-pub async fn set_cooling_state(device: &str, speed: &c_ulong) -> anyhow::Result<()> {
-    // quick and dirty workaround
+pub async fn set_cooling_state(
+    system_fan: &str,
+    device: &str,
+    speed: &c_ulong,
+) -> anyhow::Result<()> {
     let dev_name = if device == "system fan" {
-        "cooling_device0"
+        system_fan
     } else {
         device
     };
 
-    let device_path = Path::new("/sys/class/thermal")
-        .join(dev_name)
-        .join("cur_state");
+    let device_path = thermal_root().join(dev_name).join("cur_state");
 
     let devices = get_cooling_state().await;
     let device = devices
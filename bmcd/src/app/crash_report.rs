@@ -0,0 +1,203 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Reports of panics, to debug failures in the field that cannot be
+//! reproduced. A panic hook writes the last panic to [`CRASH_REPORT`] with the
+//! backtrace, the version and the latest notifications and API requests, and
+//! then runs the default hook. A panic in a spawned task does not terminate
+//! bmcd; it is reported all the same.
+//!
+//! Release builds are stripped, so backtrace frames are addresses that have
+//! to be resolved against an unstripped build of the same version.
+use super::notifier::Notifier;
+use super::request_trace::{RequestTrace, RequestTraces};
+use crate::utils::get_timestamp_unix;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+
+pub const CRASH_REPORT: &str = "/var/lib/bmcd/last_crash.json";
+/// Number of API requests included in a report.
+const RECENT_REQUESTS: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CrashReport {
+    pub version: String,
+    pub buildtime: String,
+    /// unix timestamp
+    pub timestamp: Option<u64>,
+    /// seconds since bmcd started
+    pub uptime: u64,
+    pub thread: Option<String>,
+    pub message: String,
+    /// `file:line:column` of the panic
+    pub location: Option<String>,
+    pub backtrace: Vec<String>,
+    /// the last notifications, oldest first
+    pub notifications: Vec<Value>,
+    /// the last API requests with their log events, most recent first
+    pub requests: Vec<Value>,
+}
+
+pub struct CrashReporter {
+    path: PathBuf,
+    started: Instant,
+    traces: Arc<RequestTraces>,
+    notifier: OnceLock<Arc<Notifier>>,
+}
+
+impl CrashReporter {
+    pub fn new(path: PathBuf, traces: Arc<RequestTraces>) -> Self {
+        Self {
+            path,
+            started: Instant::now(),
+            traces,
+            notifier: OnceLock::new(),
+        }
+    }
+
+    /// Reports every following panic.
+    pub fn install(self: &Arc<Self>) {
+        let reporter = self.clone();
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let report = reporter.report(
+                payload_message(info.payload()),
+                info.location().map(|l| l.to_string()),
+                Backtrace::force_capture(),
+            );
+            if let Err(e) = reporter.write(&report) {
+                // the logger may be the one that panicked
+                eprintln!("writing {}: {}", reporter.path.display(), e);
+            }
+            default_hook(info);
+        }));
+    }
+
+    /// Includes the notifications of `notifier` in later reports.
+    pub fn set_notifier(&self, notifier: Arc<Notifier>) {
+        let _ = self.notifier.set(notifier);
+    }
+
+    fn report(
+        &self,
+        message: String,
+        location: Option<String>,
+        backtrace: Backtrace,
+    ) -> CrashReport {
+        // the hook can run while a lock of the sources is held, those are
+        // skipped rather than waited for
+        let notifications = self
+            .notifier
+            .get()
+            .and_then(|n| n.try_recent())
+            .unwrap_or_default();
+        let requests = self
+            .traces
+            .try_recent()
+            .unwrap_or_default()
+            .iter()
+            .take(RECENT_REQUESTS)
+            .filter_map(|t: &RequestTrace| serde_json::to_value(t).ok())
+            .collect();
+        CrashReport {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            buildtime: build_time::build_time_utc!("%Y-%m-%d %H:%M:%S-00:00").to_string(),
+            timestamp: get_timestamp_unix(),
+            uptime: self.started.elapsed().as_secs(),
+            thread: std::thread::current().name().map(str::to_string),
+            message,
+            location,
+            backtrace: backtrace.to_string().lines().map(str::to_string).collect(),
+            notifications,
+            requests,
+        }
+    }
+
+    fn write(&self, report: &CrashReport) -> io::Result<()> {
+        let staged = self.path.with_extension("json.new");
+        std::fs::write(&staged, serde_json::to_vec_pretty(report)?)?;
+        std::fs::rename(&staged, &self.path)
+    }
+
+    pub async fn last(&self) -> anyhow::Result<Option<CrashReport>> {
+        match tokio::fs::read(&self.path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .with_context(|| self.path.display().to_string()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| self.path.display().to_string()),
+        }
+    }
+
+    pub async fn clear(&self) -> anyhow::Result<()> {
+        match tokio::fs::remove_file(&self.path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(e).with_context(|| self.path.display().to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn payload_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "panic with a non-string payload".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn write_and_clear() {
+        let dir = TempDir::new("crash_report").unwrap();
+        let traces = Arc::new(RequestTraces::default());
+        traces.begin("abc", "POST", "/api/bmc/flash");
+        let reporter = CrashReporter::new(dir.path().join("last_crash.json"), traces);
+        assert_eq!(reporter.last().await.unwrap(), None);
+
+        let report = reporter.report(
+            payload_message(&"index out of bounds"),
+            Some("src/main.rs:1:1".to_string()),
+            Backtrace::disabled(),
+        );
+        reporter.write(&report).unwrap();
+        let last = reporter.last().await.unwrap().unwrap();
+        assert_eq!(last, report);
+        assert_eq!(last.message, "index out of bounds");
+        assert_eq!(last.requests[0]["path"], "/api/bmc/flash");
+
+        reporter.clear().await.unwrap();
+        assert_eq!(reporter.last().await.unwrap(), None);
+        reporter.clear().await.unwrap();
+    }
+
+    #[test]
+    fn payloads() {
+        assert_eq!(payload_message(&String::from("boom")), "boom");
+        assert_eq!(payload_message(&42), "panic with a non-string payload");
+    }
+}
@@ -0,0 +1,418 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Minimal DHCP server (RFC 2131) for the node network. It only answers
+//! clients that have a fixed address configured, and never hands out
+//! addresses from a dynamic pool.
+use super::netboot::Netboot;
+use crate::config::Dhcp;
+use crate::utils::parse_mac_address;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// size of the fixed BOOTP part of a message, excluding the magic cookie.
+const BOOTP_SIZE: usize = 236;
+const FILE_OFFSET: usize = 108;
+const FILE_SIZE: usize = 128;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_TFTP_SERVER: u8 = 66;
+const OPTION_BOOT_FILE: u8 = 67;
+const OPTION_END: u8 = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum MessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Ack = 5,
+    Nak = 6,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct DhcpMessage {
+    op: u8,
+    xid: [u8; 4],
+    flags: [u8; 2],
+    ciaddr: Ipv4Addr,
+    yiaddr: Ipv4Addr,
+    /// address of the server to boot from
+    siaddr: Ipv4Addr,
+    giaddr: Ipv4Addr,
+    chaddr: [u8; 6],
+    /// boot file name
+    file: String,
+    options: HashMap<u8, Vec<u8>>,
+}
+
+impl DhcpMessage {
+    fn decode(packet: &[u8]) -> Option<Self> {
+        if packet.len() < BOOTP_SIZE + MAGIC_COOKIE.len()
+            || packet[1] != HTYPE_ETHERNET
+            || packet[2] != 6
+            || packet[BOOTP_SIZE..BOOTP_SIZE + 4] != MAGIC_COOKIE
+        {
+            return None;
+        }
+
+        let addr = |offset: usize| {
+            Ipv4Addr::new(
+                packet[offset],
+                packet[offset + 1],
+                packet[offset + 2],
+                packet[offset + 3],
+            )
+        };
+        let mut options = HashMap::new();
+        let mut pos = BOOTP_SIZE + 4;
+        while let Some(&code) = packet.get(pos) {
+            match code {
+                OPTION_PAD => pos += 1,
+                OPTION_END => break,
+                code => {
+                    let len = usize::from(*packet.get(pos + 1)?);
+                    let value = packet.get(pos + 2..pos + 2 + len)?;
+                    options.insert(code, value.to_vec());
+                    pos += 2 + len;
+                }
+            }
+        }
+
+        Some(Self {
+            op: packet[0],
+            xid: packet[4..8].try_into().ok()?,
+            flags: packet[10..12].try_into().ok()?,
+            ciaddr: addr(12),
+            yiaddr: addr(16),
+            siaddr: addr(20),
+            giaddr: addr(24),
+            chaddr: packet[28..34].try_into().ok()?,
+            file: String::from_utf8_lossy(&packet[FILE_OFFSET..FILE_OFFSET + FILE_SIZE])
+                .trim_end_matches('\0')
+                .to_string(),
+            options,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut packet = vec![0u8; BOOTP_SIZE];
+        packet[0] = self.op;
+        packet[1] = HTYPE_ETHERNET;
+        packet[2] = 6;
+        packet[4..8].copy_from_slice(&self.xid);
+        packet[10..12].copy_from_slice(&self.flags);
+        packet[12..16].copy_from_slice(&self.ciaddr.octets());
+        packet[16..20].copy_from_slice(&self.yiaddr.octets());
+        packet[20..24].copy_from_slice(&self.siaddr.octets());
+        packet[24..28].copy_from_slice(&self.giaddr.octets());
+        packet[28..34].copy_from_slice(&self.chaddr);
+        let file = &self.file.as_bytes()[..self.file.len().min(FILE_SIZE - 1)];
+        packet[FILE_OFFSET..FILE_OFFSET + file.len()].copy_from_slice(file);
+        packet.extend_from_slice(&MAGIC_COOKIE);
+
+        // message type first, as some clients expect it to be.
+        let mut codes: Vec<&u8> = self.options.keys().collect();
+        codes.sort_by_key(|c| (**c != OPTION_MESSAGE_TYPE, **c));
+        for code in codes {
+            let value = &self.options[code];
+            packet.push(*code);
+            packet.push(value.len() as u8);
+            packet.extend_from_slice(value);
+        }
+        packet.push(OPTION_END);
+        packet
+    }
+
+    fn message_type(&self) -> Option<u8> {
+        self.options.get(&OPTION_MESSAGE_TYPE)?.first().copied()
+    }
+
+    fn ipv4_option(&self, code: u8) -> Option<Ipv4Addr> {
+        let octets: [u8; 4] = self.options.get(&code)?.as_slice().try_into().ok()?;
+        Some(Ipv4Addr::from(octets))
+    }
+}
+
+pub struct DhcpServer {
+    config: Dhcp,
+    /// MAC address mapped to the slot number and address of the node.
+    leases: HashMap<[u8; 6], (u8, Ipv4Addr)>,
+    netboot: Option<Arc<Netboot>>,
+}
+
+impl DhcpServer {
+    pub fn new(config: Dhcp) -> Self {
+        let leases = config
+            .leases
+            .iter()
+            .filter_map(|l| Some((parse_mac_address(&l.mac)?, (l.node, l.address))))
+            .collect();
+        Self {
+            config,
+            leases,
+            netboot: None,
+        }
+    }
+
+    /// Announce the boot files of [`Netboot`] to the nodes.
+    pub fn with_netboot(mut self, netboot: Arc<Netboot>) -> Self {
+        self.netboot = Some(netboot);
+        self
+    }
+
+    /// Binds to the configured interface and serves requests in the
+    /// background.
+    pub fn run(self) -> std::io::Result<()> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_broadcast(true)?;
+        socket.bind_device(Some(self.config.interface.as_bytes()))?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, SERVER_PORT).into())?;
+        let socket = UdpSocket::from_std(socket.into())?;
+
+        tracing::info!(
+            "DHCP server listening on {} for {} node(s)",
+            self.config.interface,
+            self.leases.len()
+        );
+
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 1500];
+            let broadcast = SocketAddrV4::new(Ipv4Addr::BROADCAST, CLIENT_PORT);
+            loop {
+                let len = match socket.recv_from(&mut buf).await {
+                    Ok((len, _)) => len,
+                    Err(e) => {
+                        tracing::warn!("DHCP server: {}", e);
+                        continue;
+                    }
+                };
+
+                let Some(reply) = DhcpMessage::decode(&buf[..len]).and_then(|m| self.handle(&m))
+                else {
+                    continue;
+                };
+
+                // replies to relayed requests go back to the relay agent
+                let destination = if reply.giaddr.is_unspecified() {
+                    broadcast
+                } else {
+                    SocketAddrV4::new(reply.giaddr, SERVER_PORT)
+                };
+                if let Err(e) = socket.send_to(&reply.encode(), destination).await {
+                    tracing::warn!("DHCP server: {}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn handle(&self, request: &DhcpMessage) -> Option<DhcpMessage> {
+        if request.op != BOOTREQUEST {
+            return None;
+        }
+
+        let (node, address) = *self.leases.get(&request.chaddr)?;
+        let reply_type = match request.message_type()? {
+            t if t == MessageType::Discover as u8 => MessageType::Offer,
+            t if t == MessageType::Request as u8 => {
+                // the client selected another server
+                if request
+                    .ipv4_option(OPTION_SERVER_ID)
+                    .is_some_and(|id| id != self.config.server_address)
+                {
+                    return None;
+                }
+
+                let requested = request
+                    .ipv4_option(OPTION_REQUESTED_IP)
+                    .unwrap_or(request.ciaddr);
+                if requested == address {
+                    MessageType::Ack
+                } else {
+                    MessageType::Nak
+                }
+            }
+            _ => return None,
+        };
+
+        let mut options = HashMap::new();
+        let mut boot_file = None;
+        options.insert(OPTION_MESSAGE_TYPE, vec![reply_type as u8]);
+        options.insert(
+            OPTION_SERVER_ID,
+            self.config.server_address.octets().to_vec(),
+        );
+
+        if reply_type != MessageType::Nak {
+            let lease_time = u32::try_from(self.config.lease_time.as_secs()).unwrap_or(u32::MAX);
+            options.insert(OPTION_LEASE_TIME, lease_time.to_be_bytes().to_vec());
+            options.insert(OPTION_SUBNET_MASK, self.config.netmask.octets().to_vec());
+            if let Some(router) = self.config.router {
+                options.insert(OPTION_ROUTER, router.octets().to_vec());
+            }
+            if !self.config.dns.is_empty() {
+                options.insert(
+                    OPTION_DNS,
+                    self.config.dns.iter().flat_map(|d| d.octets()).collect(),
+                );
+            }
+            boot_file = self.netboot.as_ref().and_then(|n| n.boot_file(node));
+            if let Some(file) = &boot_file {
+                options.insert(
+                    OPTION_TFTP_SERVER,
+                    self.config.server_address.to_string().into_bytes(),
+                );
+                options.insert(OPTION_BOOT_FILE, file.clone().into_bytes());
+            }
+            tracing::debug!(
+                "DHCP {:?} {} to {}",
+                reply_type,
+                address,
+                hex::encode(request.chaddr)
+            );
+        }
+
+        Some(DhcpMessage {
+            op: BOOTREPLY,
+            xid: request.xid,
+            flags: request.flags,
+            ciaddr: Ipv4Addr::UNSPECIFIED,
+            yiaddr: if reply_type == MessageType::Nak {
+                Ipv4Addr::UNSPECIFIED
+            } else {
+                address
+            },
+            siaddr: if boot_file.is_some() {
+                self.config.server_address
+            } else {
+                Ipv4Addr::UNSPECIFIED
+            },
+            giaddr: request.giaddr,
+            chaddr: request.chaddr,
+            file: boot_file.unwrap_or_default(),
+            options,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DhcpLease;
+    use std::time::Duration;
+
+    const MAC: [u8; 6] = [2, 0, 0, 0, 0, 1];
+
+    fn server() -> DhcpServer {
+        DhcpServer::new(Dhcp {
+            interface: "br0".to_string(),
+            server_address: Ipv4Addr::new(10, 0, 0, 1),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            router: Some(Ipv4Addr::new(10, 0, 0, 1)),
+            dns: vec![Ipv4Addr::new(10, 0, 0, 1)],
+            lease_time: Duration::from_secs(600),
+            leases: vec![DhcpLease {
+                node: 1,
+                mac: "02:00:00:00:00:01".to_string(),
+                address: Ipv4Addr::new(10, 0, 0, 11),
+            }],
+        })
+    }
+
+    fn request(
+        mac: [u8; 6],
+        message_type: MessageType,
+        requested: Option<Ipv4Addr>,
+    ) -> DhcpMessage {
+        let mut options = HashMap::new();
+        options.insert(OPTION_MESSAGE_TYPE, vec![message_type as u8]);
+        if let Some(requested) = requested {
+            options.insert(OPTION_REQUESTED_IP, requested.octets().to_vec());
+        }
+        DhcpMessage {
+            op: BOOTREQUEST,
+            xid: [1, 2, 3, 4],
+            flags: [0x80, 0],
+            ciaddr: Ipv4Addr::UNSPECIFIED,
+            yiaddr: Ipv4Addr::UNSPECIFIED,
+            siaddr: Ipv4Addr::UNSPECIFIED,
+            giaddr: Ipv4Addr::UNSPECIFIED,
+            chaddr: mac,
+            file: String::new(),
+            options,
+        }
+    }
+
+    #[test]
+    fn codec_roundtrip() {
+        let mut message = request(MAC, MessageType::Discover, None);
+        message.file = "pxelinux.0".to_string();
+        assert_eq!(DhcpMessage::decode(&message.encode()).unwrap(), message);
+    }
+
+    #[test]
+    fn offer_and_ack_fixed_address() {
+        let server = server();
+        let offer = server
+            .handle(&request(MAC, MessageType::Discover, None))
+            .unwrap();
+        assert_eq!(offer.message_type(), Some(MessageType::Offer as u8));
+        assert_eq!(offer.yiaddr, Ipv4Addr::new(10, 0, 0, 11));
+        assert_eq!(offer.xid, [1, 2, 3, 4]);
+
+        let ack = server
+            .handle(&request(MAC, MessageType::Request, Some(offer.yiaddr)))
+            .unwrap();
+        assert_eq!(ack.message_type(), Some(MessageType::Ack as u8));
+        assert_eq!(
+            ack.ipv4_option(OPTION_SUBNET_MASK),
+            Some(Ipv4Addr::new(255, 255, 255, 0))
+        );
+
+        let nak = server
+            .handle(&request(
+                MAC,
+                MessageType::Request,
+                Some(Ipv4Addr::new(10, 0, 0, 99)),
+            ))
+            .unwrap();
+        assert_eq!(nak.message_type(), Some(MessageType::Nak as u8));
+    }
+
+    #[test]
+    fn ignore_unknown_clients() {
+        let server = server();
+        assert!(server
+            .handle(&request([2, 0, 0, 0, 0, 9], MessageType::Discover, None))
+            .is_none());
+    }
+}
@@ -0,0 +1,241 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Assembles a gzipped tarball with the state of the BMC, to be attached to
+//! bug reports. Sources that cannot be read are listed in `errors.txt`
+//! instead of failing the whole bundle.
+use super::crash_report::CRASH_REPORT;
+use super::notifier::Notifier;
+use super::power_supply::LAST_BROWNOUT;
+use crate::persistency::app_persistency::BIN_DATA;
+use crate::persistency::binary_persistency::PersistencyStore;
+use crate::utils::get_timestamp_unix;
+use async_compression::tokio::bufread::GzipEncoder;
+use serde_json::json;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+
+/// Single files copied into `system/`.
+const SYSTEM_FILES: &[&str] = &[
+    "/etc/os-release",
+    "/proc/version",
+    "/proc/uptime",
+    "/proc/loadavg",
+    "/proc/meminfo",
+    "/proc/mounts",
+    "/proc/device-tree/model",
+    "/sys/kernel/debug/gpio",
+];
+
+/// Directories of which every `<entry>/<attribute>` is dumped into `sysfs/`.
+const SYSFS_ATTRIBUTES: &[(&str, &str)] = &[
+    ("/sys/class/leds", "brightness"),
+    ("/sys/class/thermal", "temp"),
+    ("/sys/class/thermal", "type"),
+    ("/sys/class/thermal", "cur_state"),
+    ("/sys/class/gpio", "value"),
+    ("/sys/class/gpio", "direction"),
+];
+
+#[derive(Default)]
+struct Bundle {
+    files: Vec<(String, Vec<u8>)>,
+    errors: Vec<String>,
+}
+
+impl Bundle {
+    fn add(&mut self, name: impl Into<String>, content: impl Into<Vec<u8>>) {
+        self.files.push((name.into(), content.into()));
+    }
+
+    fn add_result<E: std::fmt::Display>(
+        &mut self,
+        name: impl Into<String>,
+        content: Result<Vec<u8>, E>,
+    ) {
+        let name = name.into();
+        match content {
+            Ok(content) => self.add(name, content),
+            Err(e) => self.errors.push(format!("{}: {}", name, e)),
+        }
+    }
+}
+
+pub async fn create_diagnostics_bundle(notifier: &Notifier) -> anyhow::Result<Vec<u8>> {
+    let mut bundle = Bundle::default();
+
+    bundle.add(
+        "version.json",
+        serde_json::to_vec_pretty(&json!({
+            "bmcd_version": env!("CARGO_PKG_VERSION"),
+            "buildtime": build_time::build_time_utc!("%Y-%m-%d %H:%M:%S-00:00"),
+            "created": get_timestamp_unix(),
+        }))?,
+    );
+
+    for path in SYSTEM_FILES {
+        let name = format!("system{}", path);
+        bundle.add_result(name, tokio::fs::read(path).await);
+    }
+
+    for (dir, attribute) in SYSFS_ATTRIBUTES {
+        let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path().join(attribute);
+            if let Ok(value) = tokio::fs::read(&path).await {
+                bundle.add(format!("sysfs{}", path.display()), value);
+            }
+        }
+    }
+
+    bundle.add_result("dmesg.txt", command_output("dmesg", &[]).await);
+
+    for log in log_files(&std::env::temp_dir()).await {
+        let name = format!(
+            "logs/{}",
+            log.file_name().unwrap_or_default().to_string_lossy()
+        );
+        bundle.add_result(name, tokio::fs::read(&log).await);
+    }
+
+    bundle.add_result(
+        "persistency.json",
+        persistency_metadata(Path::new(BIN_DATA)).map(|m| m.to_string().into_bytes()),
+    );
+    bundle.add(
+        "events.json",
+        serde_json::to_vec_pretty(&notifier.recent())?,
+    );
+    match tokio::fs::read(CRASH_REPORT).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        content => bundle.add_result("last_crash.json", content),
+    }
+    match tokio::fs::read(LAST_BROWNOUT).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        content => bundle.add_result("last_brownout.json", content),
+    }
+
+    let errors = bundle.errors.join("\n");
+    bundle.add("errors.txt", errors);
+    archive(bundle.files).await
+}
+
+async fn command_output(program: &str, args: &[&str]) -> anyhow::Result<Vec<u8>> {
+    let output = Command::new(program).args(args).output().await?;
+    anyhow::ensure!(
+        output.status.success(),
+        "{} returned {}",
+        program,
+        output.status
+    );
+    Ok(output.stdout)
+}
+
+/// Log files written by the rolling appender of bmcd.
+async fn log_files(dir: &Path) -> Vec<PathBuf> {
+    let mut logs = Vec::new();
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return logs;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with("bmcd") && name.ends_with("log") {
+            logs.push(entry.path());
+        }
+    }
+    logs.sort();
+    logs
+}
+
+/// Describes the persistency store without exposing its content.
+fn persistency_metadata(path: &Path) -> anyhow::Result<serde_json::Value> {
+    let file = std::fs::File::open(path)?;
+    let metadata = file.metadata()?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+
+    let report = match PersistencyStore::inspect(file) {
+        Ok(report) => json!({
+            "version": report.version,
+            "entries": report.entries,
+            "data_size": report.data_size,
+        }),
+        Err(e) => json!({ "error": e.to_string() }),
+    };
+
+    Ok(json!({
+        "path": path,
+        "size": metadata.len(),
+        "modified": modified,
+        "store": report,
+    }))
+}
+
+async fn archive(files: Vec<(String, Vec<u8>)>) -> anyhow::Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(Vec::new());
+    let now = get_timestamp_unix().unwrap_or_default();
+    for (name, content) in &files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(now);
+        header.set_cksum();
+        builder.append_data(&mut header, name, content.as_slice())?;
+    }
+    let tar = builder.into_inner()?;
+
+    let mut archive = Vec::new();
+    GzipEncoder::new(Cursor::new(tar))
+        .read_to_end(&mut archive)
+        .await?;
+    Ok(archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_compression::tokio::bufread::GzipDecoder;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn only_bmcd_logs() {
+        let dir = TempDir::new("diagnostics").unwrap();
+        for name in ["bmcd.2024-05-01-10.log", "other.log", "bmcd.pid"] {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+        let logs = log_files(dir.path()).await;
+        assert_eq!(logs, vec![dir.path().join("bmcd.2024-05-01-10.log")]);
+    }
+
+    #[tokio::test]
+    async fn archive_roundtrip() {
+        let files = vec![("events.json".to_string(), b"[]".to_vec())];
+        let gz = archive(files).await.unwrap();
+
+        let mut tar = Vec::new();
+        GzipDecoder::new(gz.as_slice())
+            .read_to_end(&mut tar)
+            .await
+            .unwrap();
+        let mut archive = tar::Archive::new(tar.as_slice());
+        let entry = archive.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap(), Path::new("events.json"));
+    }
+}
@@ -0,0 +1,350 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Zero-touch enrollment of a freshly flashed BMC in a fleet. The BMC looks
+//! for a one-time token, which an operator puts on the SD card or the DHCP
+//! server hands out (the `udhcpc` hook writes it to a file), and posts it to
+//! the management server together with its serial number. The server answers
+//! with the configuration bundle of the board: the configuration file of
+//! bmcd and optionally a TLS certificate and the root password. The bundle is
+//! written to disk and bmcd restarts to apply it. Enrollment happens once,
+//! [`ENROLLED_FLAG`] keeps a BMC from enrolling again.
+use super::config_archive::{write_atomic, write_private};
+use super::network_config::NetworkConfigurator;
+use super::provisioning::Provisioning;
+use super::shutdown::Shutdown;
+use super::users;
+use crate::config::{self, Config};
+use crate::utils::get_timestamp_unix;
+use anyhow::{ensure, Context};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Written once the configuration bundle is applied.
+pub const ENROLLED_FLAG: &str = "/var/lib/bmcd/enrolled";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+#[error("the server rejected the enrollment token: {0}")]
+struct Rejected(StatusCode);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnrollmentState {
+    #[default]
+    Disabled,
+    WaitingForToken,
+    Enrolling,
+    /// The last attempt failed, enrollment is retried.
+    Failed,
+    /// The server refused the token, enrollment is not retried.
+    Rejected,
+    Enrolled,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct EnrollmentStatus {
+    pub state: EnrollmentState,
+    pub url: Option<String>,
+    /// file the token was read from
+    pub token_file: Option<PathBuf>,
+    /// unix timestamp of the last request to the server
+    pub last_attempt: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct EnrollmentRequest<'a> {
+    token: &'a str,
+    serial: Option<&'a str>,
+    hostname: String,
+    version: &'static str,
+}
+
+/// The answer of the management server.
+#[derive(Debug, Deserialize)]
+pub struct Bundle {
+    /// configuration file of bmcd, in the format of the file it replaces
+    pub config: String,
+    /// PEM certificate and private key, written to the paths of the `tls`
+    /// section of the new configuration.
+    pub tls_certificate: Option<String>,
+    pub tls_private_key: Option<String>,
+    /// New password of root. Completes provisioning mode when it is active.
+    pub root_password: Option<String>,
+}
+
+pub struct Enrollment {
+    config: Option<config::Enrollment>,
+    flag: PathBuf,
+    status: Mutex<EnrollmentStatus>,
+}
+
+impl Enrollment {
+    pub fn new(config: Option<config::Enrollment>) -> Self {
+        let status = EnrollmentStatus {
+            url: config.as_ref().map(|c| c.url.clone()),
+            ..Default::default()
+        };
+        Self {
+            config,
+            flag: PathBuf::from(ENROLLED_FLAG),
+            status: Mutex::new(status),
+        }
+    }
+
+    pub fn status(&self) -> EnrollmentStatus {
+        self.status.lock().expect("status lock poisoned").clone()
+    }
+
+    /// Enrolls in the background until it succeeds or the token is rejected,
+    /// then restarts bmcd with the new configuration.
+    pub fn run(
+        self: Arc<Self>,
+        config_path: PathBuf,
+        serial: Option<String>,
+        provisioning: Arc<Provisioning>,
+        network: Arc<NetworkConfigurator>,
+        shutdown: Arc<Shutdown>,
+    ) -> anyhow::Result<()> {
+        let Some(config) = self.config.clone() else {
+            return Ok(());
+        };
+        if self.flag.exists() {
+            self.set_state(EnrollmentState::Enrolled);
+            return Ok(());
+        }
+
+        let mut client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT);
+        if let Some(ca) = &config.ca_certificate {
+            let pem = std::fs::read(ca).with_context(|| ca.display().to_string())?;
+            client = client.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        let client = client.build()?;
+
+        tokio::spawn(async move {
+            loop {
+                let Some((token_file, token)) = read_token(&config.token_files).await else {
+                    self.set_state(EnrollmentState::WaitingForToken);
+                    tokio::time::sleep(config.retry_interval).await;
+                    continue;
+                };
+
+                self.update(|status| {
+                    status.state = EnrollmentState::Enrolling;
+                    status.token_file = Some(token_file.clone());
+                    status.last_attempt = get_timestamp_unix();
+                });
+                let result = async {
+                    let bundle =
+                        request_bundle(&client, &config.url, &token, serial.as_deref()).await?;
+                    self.apply(bundle, &config_path, &provisioning, &network)
+                        .await
+                }
+                .await;
+
+                match result {
+                    Ok(()) => {
+                        tracing::info!("enrolled with {}, restarting", config.url);
+                        // the token is spent
+                        let _ = tokio::fs::remove_file(&token_file).await;
+                        self.update(|status| {
+                            status.state = EnrollmentState::Enrolled;
+                            status.last_error = None;
+                        });
+                        shutdown.restart().await;
+                        return;
+                    }
+                    Err(e) => {
+                        let rejected = e.downcast_ref::<Rejected>().is_some();
+                        tracing::error!("enrollment with {} failed: {:#}", config.url, e);
+                        self.update(|status| {
+                            status.state = if rejected {
+                                EnrollmentState::Rejected
+                            } else {
+                                EnrollmentState::Failed
+                            };
+                            status.last_error = Some(format!("{:#}", e));
+                        });
+                        if rejected {
+                            return;
+                        }
+                    }
+                }
+                tokio::time::sleep(config.retry_interval).await;
+            }
+        });
+        Ok(())
+    }
+
+    async fn apply(
+        &self,
+        bundle: Bundle,
+        config_path: &Path,
+        provisioning: &Provisioning,
+        network: &NetworkConfigurator,
+    ) -> anyhow::Result<()> {
+        apply_bundle(&bundle, config_path).await?;
+        if let Some(password) = &bundle.root_password {
+            if provisioning.is_active() {
+                provisioning.set_credentials(password).await?;
+                provisioning.complete(network).await?;
+            } else {
+                users::set_password("root", password, false).await?;
+            }
+        }
+        if let Some(parent) = self.flag.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&self.flag, b"")
+            .await
+            .with_context(|| self.flag.display().to_string())
+    }
+
+    fn set_state(&self, state: EnrollmentState) {
+        self.update(|status| status.state = state);
+    }
+
+    fn update(&self, change: impl FnOnce(&mut EnrollmentStatus)) {
+        change(&mut self.status.lock().expect("status lock poisoned"));
+    }
+}
+
+/// The first token file that exists and is not empty.
+async fn read_token(files: &[PathBuf]) -> Option<(PathBuf, String)> {
+    for file in files {
+        if let Ok(content) = tokio::fs::read_to_string(file).await {
+            let token = content.trim();
+            if !token.is_empty() {
+                return Some((file.clone(), token.to_string()));
+            }
+        }
+    }
+    None
+}
+
+async fn request_bundle(
+    client: &reqwest::Client,
+    url: &str,
+    token: &str,
+    serial: Option<&str>,
+) -> anyhow::Result<Bundle> {
+    let hostname = tokio::fs::read_to_string("/proc/sys/kernel/hostname")
+        .await
+        .unwrap_or_default();
+    let request = EnrollmentRequest {
+        token,
+        serial,
+        hostname: hostname.trim_end().to_string(),
+        version: env!("CARGO_PKG_VERSION"),
+    };
+    let response = client.post(url).json(&request).send().await?;
+    match response.status() {
+        status @ (StatusCode::UNAUTHORIZED
+        | StatusCode::FORBIDDEN
+        | StatusCode::NOT_FOUND
+        | StatusCode::GONE) => Err(Rejected(status).into()),
+        _ => Ok(response.error_for_status()?.json().await?),
+    }
+}
+
+/// Validates the configuration of the bundle and writes it and the TLS
+/// files. The configuration file is left untouched when the new one does
+/// not load.
+pub async fn apply_bundle(bundle: &Bundle, config_path: &Path) -> anyhow::Result<()> {
+    ensure!(
+        bundle.tls_certificate.is_some() == bundle.tls_private_key.is_some(),
+        "bundle holds only one of the TLS certificate and private key"
+    );
+
+    let extension = config_path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("yaml");
+    let staged = config_path.with_extension(format!("enrollment.{}", extension));
+    write_atomic(&staged, bundle.config.as_bytes()).await?;
+    let config = match Config::load(&staged) {
+        Ok(config) => config,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&staged).await;
+            return Err(e.context("configuration of the bundle"));
+        }
+    };
+
+    if let (Some(certificate), Some(private_key)) =
+        (&bundle.tls_certificate, &bundle.tls_private_key)
+    {
+        write_atomic(&config.tls.certificate, certificate.as_bytes()).await?;
+        write_private(&config.tls.private_key, private_key.as_bytes()).await?;
+    }
+    tokio::fs::rename(&staged, config_path)
+        .await
+        .with_context(|| config_path.display().to_string())?;
+    tracing::info!(
+        "configuration of the bundle written to {}",
+        config_path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn bundle(config: &str) -> Bundle {
+        Bundle {
+            config: config.to_string(),
+            tls_certificate: None,
+            tls_private_key: None,
+            root_password: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn token_files() {
+        let dir = TempDir::new("enrollment").unwrap();
+        let files = [dir.path().join("sdcard"), dir.path().join("dhcp")];
+        assert!(read_token(&files).await.is_none());
+
+        std::fs::write(&files[0], "\n").unwrap();
+        std::fs::write(&files[1], "s3cr3t\n").unwrap();
+        let (file, token) = read_token(&files).await.unwrap();
+        assert_eq!(file, files[1]);
+        assert_eq!(token, "s3cr3t");
+    }
+
+    #[tokio::test]
+    async fn invalid_bundle_keeps_config() {
+        let dir = TempDir::new("enrollment").unwrap();
+        let path = dir.path().join("config.yaml");
+        std::fs::write(&path, "port: 443\n").unwrap();
+
+        assert!(apply_bundle(&bundle("nodes:\n  - node: 5\n"), &path)
+            .await
+            .is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "port: 443\n");
+        assert!(!path.with_extension("enrollment.yaml").exists());
+
+        let mut partial = bundle("port: 8443\n");
+        partial.tls_certificate = Some("-----BEGIN CERTIFICATE-----".to_string());
+        assert!(apply_bundle(&partial, &path).await.is_err());
+
+        apply_bundle(&bundle("port: 8443\n"), &path).await.unwrap();
+        assert_eq!(Config::load(&path).unwrap().port, 8443);
+    }
+}
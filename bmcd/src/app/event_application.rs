@@ -12,18 +12,28 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 use super::bmc_application::BmcApplication;
+use super::physical_presence::PhysicalPresence;
 use crate::utils::EventListener;
 use anyhow::Context;
 use evdev::KeyCode;
 use std::{sync::Arc, time::Duration};
 use tokio::sync::oneshot;
 
-pub fn run_event_listener(instance: Arc<BmcApplication>) -> anyhow::Result<()> {
+pub fn run_event_listener(
+    instance: Arc<BmcApplication>,
+    presence: Arc<PhysicalPresence>,
+) -> anyhow::Result<()> {
     EventListener::new(
-        (instance, Option::<oneshot::Sender<()>>::None),
+        (instance, presence, Option::<oneshot::Sender<()>>::None),
         "/dev/input/event0",
     )
-    .add_action(KeyCode::KEY_1, 1, |(app, s)| {
+    .add_action(KeyCode::KEY_1, 1, |(app, presence, s)| {
+        // a pending physical presence request takes precedence over the
+        // power toggle
+        if presence.press() {
+            return;
+        }
+
         let (sender, receiver) = oneshot::channel();
         *s = Some(sender);
 
@@ -35,14 +45,14 @@ pub fn run_event_listener(instance: Arc<BmcApplication>) -> anyhow::Result<()> {
             bmc.toggle_power_states(long_press).await
         });
     })
-    .add_action(KeyCode::KEY_1, 0, |(_, sender)| {
+    .add_action(KeyCode::KEY_1, 0, |(_, _, sender)| {
         let _ = sender.take().and_then(|s| s.send(()).ok());
     })
-    .add_action(KeyCode::KEY_POWER, 1, move |(app, _)| {
+    .add_action(KeyCode::KEY_POWER, 1, move |(app, _, _)| {
         let bmc = app.clone();
         tokio::spawn(async move { bmc.toggle_power_states(false).await });
     })
-    .add_action(KeyCode::KEY_RESTART, 1, |(app, _)| {
+    .add_action(KeyCode::KEY_RESTART, 1, |(app, _, _)| {
         let bmc = app.clone();
         tokio::spawn(async move { bmc.reboot(false).await });
     })
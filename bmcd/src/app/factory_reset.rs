@@ -0,0 +1,231 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use super::bmc_application::{BmcApplication, NodeInfos, NODE_INFO_KEY};
+use super::enrollment::ENROLLED_FLAG;
+use super::provisioning::PROVISIONED_FLAG;
+use crate::config::Config;
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::Instant;
+
+/// Upper directory of the overlay file-system that is mounted on top of the
+/// read-only root file-system. Removing files from this directory restores
+/// the version that shipped with the firmware.
+pub const OVERLAY_UPPER: &str = "/mnt/overlay/upper";
+/// Directory where images are stored on the BMC.
+pub const IMAGES_DIR: &str = "/var/lib/bmcd/images";
+/// Time a confirmation token stays valid.
+const TOKEN_EXPIRY: Duration = Duration::from_secs(60);
+
+const AUTH_FILES: [&str; 3] = ["etc/shadow", "etc/passwd", "etc/group"];
+const NETWORK_FILES: [&str; 3] = ["etc/network", "etc/hostname", "etc/resolv.conf"];
+
+/// Categories of settings that can be wiped by a factory reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResetScope {
+    /// Linux accounts, TLS certificate and the configuration signing key.
+    Auth,
+    /// Network configuration and hostname.
+    Network,
+    /// Names and other meta-data of the nodes.
+    NodeMetadata,
+    /// Images stored on the BMC.
+    Images,
+    /// All of the above, including every other setting of bmcd.
+    Everything,
+}
+
+#[derive(Debug, Error)]
+pub enum FactoryResetError {
+    #[error("no factory reset was requested")]
+    NotRequested,
+    #[error("confirmation token is invalid")]
+    InvalidToken,
+    #[error("confirmation token expired")]
+    Expired,
+}
+
+#[derive(Debug)]
+struct PendingReset {
+    token: String,
+    scopes: Vec<ResetScope>,
+    expires: Instant,
+}
+
+/// Factory reset is a two-step operation. A reset is first requested, which
+/// hands out a confirmation token. Only when this token is passed back within
+/// [`TOKEN_EXPIRY`] the reset is executed.
+#[derive(Debug, Default)]
+pub struct FactoryReset {
+    pending: Mutex<Option<PendingReset>>,
+}
+
+impl FactoryReset {
+    /// Request a reset of the given scopes. Any previously pending request is
+    /// discarded. Returns the token that is required to confirm the reset.
+    pub fn request(&self, scopes: Vec<ResetScope>) -> (String, Duration) {
+        let token = hex::encode(rand::random::<[u8; 16]>());
+        tracing::warn!("factory reset of {:?} requested", scopes);
+        *self.pending.lock().expect("pending lock poisoned") = Some(PendingReset {
+            token: token.clone(),
+            scopes,
+            expires: Instant::now() + TOKEN_EXPIRY,
+        });
+        (token, TOKEN_EXPIRY)
+    }
+
+    /// Validates and consumes the confirmation token. Returns the scopes that
+    /// should be reset.
+    pub fn confirm(&self, token: &str) -> Result<Vec<ResetScope>, FactoryResetError> {
+        let mut pending = self.pending.lock().expect("pending lock poisoned");
+        let request = pending.as_ref().ok_or(FactoryResetError::NotRequested)?;
+
+        if request.expires < Instant::now() {
+            *pending = None;
+            return Err(FactoryResetError::Expired);
+        }
+
+        let (expected, token) = (request.token.as_bytes(), token.as_bytes());
+        if expected.len() != token.len() || !openssl::memcmp::eq(expected, token) {
+            return Err(FactoryResetError::InvalidToken);
+        }
+
+        Ok(pending.take().map(|p| p.scopes).unwrap_or_default())
+    }
+}
+
+/// Wipes the given scopes and reboots the BMC.
+pub async fn execute_factory_reset(
+    bmc: &BmcApplication,
+    config: &Config,
+    scopes: &[ResetScope],
+) -> anyhow::Result<()> {
+    let everything = scopes.contains(&ResetScope::Everything);
+    let includes = |scope| everything || scopes.contains(&scope);
+
+    if everything {
+        bmc.app_db.reset_to_defaults().await;
+        remove_dir_contents(Path::new(OVERLAY_UPPER)).await?;
+        // a wiped BMC enrolls again with a new token
+        remove_path(Path::new(ENROLLED_FLAG)).await?;
+    }
+
+    if includes(ResetScope::Auth) {
+        remove_overlay_files(&AUTH_FILES).await?;
+        // the next boot starts in provisioning mode
+        remove_path(Path::new(PROVISIONED_FLAG)).await?;
+        remove_path(&config.tls.certificate).await?;
+        remove_path(&config.tls.private_key).await?;
+        remove_path(&config.backup.signing_key).await?;
+    }
+
+    if includes(ResetScope::Network) {
+        remove_overlay_files(&NETWORK_FILES).await?;
+    }
+
+    if includes(ResetScope::NodeMetadata) {
+        bmc.app_db
+            .set::<NodeInfos>(NODE_INFO_KEY, NodeInfos::default())
+            .await;
+    }
+
+    if includes(ResetScope::Images) {
+        remove_dir_contents(Path::new(IMAGES_DIR)).await?;
+    }
+
+    tracing::warn!("factory reset of {:?} executed, rebooting", scopes);
+    bmc.app_db.sync_all().await?;
+    bmc.reboot(false).await
+}
+
+async fn remove_overlay_files(files: &[&str]) -> anyhow::Result<()> {
+    for file in files {
+        remove_path(&PathBuf::from(OVERLAY_UPPER).join(file)).await?;
+    }
+    Ok(())
+}
+
+async fn remove_path(path: &Path) -> anyhow::Result<()> {
+    let result = match tokio::fs::symlink_metadata(path).await {
+        Ok(meta) if meta.is_dir() => tokio::fs::remove_dir_all(path).await,
+        Ok(_) => tokio::fs::remove_file(path).await,
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(()) => {
+            tracing::info!("removed {}", path.display());
+            Ok(())
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(anyhow::anyhow!("{}: {}", path.display(), e)),
+    }
+}
+
+async fn remove_dir_contents(dir: &Path) -> anyhow::Result<()> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(anyhow::anyhow!("{}: {}", dir.display(), e)),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        remove_path(&entry.path()).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confirmation_token() {
+        let reset = FactoryReset::default();
+        assert!(matches!(
+            reset.confirm("abc"),
+            Err(FactoryResetError::NotRequested)
+        ));
+
+        let (token, _) = reset.request(vec![ResetScope::Network]);
+        assert!(matches!(
+            reset.confirm("abc"),
+            Err(FactoryResetError::InvalidToken)
+        ));
+        assert_eq!(reset.confirm(&token).unwrap(), vec![ResetScope::Network]);
+
+        // tokens can only be used once
+        assert!(matches!(
+            reset.confirm(&token),
+            Err(FactoryResetError::NotRequested)
+        ));
+    }
+
+    #[test]
+    fn token_expires() {
+        let reset = FactoryReset::default();
+        let (token, _) = reset.request(vec![ResetScope::Everything]);
+        reset.pending.lock().unwrap().as_mut().unwrap().expires =
+            Instant::now() - Duration::from_secs(1);
+        assert!(matches!(
+            reset.confirm(&token),
+            Err(FactoryResetError::Expired)
+        ));
+    }
+}
@@ -0,0 +1,467 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Recovers nodes that stop answering health probes. Every listed node is
+//! probed while it is powered; once probes fail for `fail_after`, the first
+//! step of its playbook runs, e.g. a warning. Each time the probes keep
+//! failing for another `fail_after`, the next step runs, until the node
+//! answers again or the playbook is exhausted. A node that needed recovery
+//! is only reported, not recovered, when it fails again within `cooldown`,
+//! so that a node that cannot boot is not power cycled in a loop.
+use super::bmc_application::{BmcApplication, ACTIVATED_NODES_KEY};
+use super::node_agent::NodeAgents;
+use super::notifier::Notifier;
+use crate::config::{self, FailoverNode, Probe, RecoveryStep};
+use crate::error::BmcError;
+use crate::hal::NodeId;
+use crate::utils::get_timestamp_unix;
+use anyhow::Context;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::net::TcpStream;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailoverState {
+    Off,
+    /// powered, not probed yet
+    Unknown,
+    Healthy,
+    /// probes fail, no recovery is running
+    Failing,
+    /// steps of the playbook are running
+    Recovering,
+    /// the playbook is exhausted and the node still fails
+    GaveUp,
+    /// powered off by the playbook, until it is powered on again
+    LeftOff,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeFailover {
+    pub node: u8,
+    pub enabled: bool,
+    pub state: FailoverState,
+    /// unix timestamp since which probes fail
+    pub failing_since: Option<u64>,
+    pub last_error: Option<String>,
+    /// steps of the current or last recovery that ran
+    pub steps: Vec<RecoveryStep>,
+    /// unix timestamp at which the last recovery started
+    pub recovery_started: Option<u64>,
+    #[serde(skip)]
+    powered: bool,
+    #[serde(skip)]
+    since: Option<Instant>,
+    #[serde(skip)]
+    started: Option<Instant>,
+    /// the failure was reported as cooling down
+    #[serde(skip)]
+    reported: bool,
+}
+
+/// What a probe result leads to.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    None,
+    Run(RecoveryStep),
+    Recovered,
+    /// failing long enough, but recovered too recently
+    CoolingDown,
+}
+
+impl NodeFailover {
+    fn new(config: &FailoverNode) -> Self {
+        Self {
+            node: config.node,
+            enabled: config.enabled,
+            state: FailoverState::Off,
+            failing_since: None,
+            last_error: None,
+            steps: Vec::new(),
+            recovery_started: None,
+            powered: false,
+            since: None,
+            started: None,
+            reported: false,
+        }
+    }
+
+    /// Records the power state and, if powered, the result of a probe.
+    fn observe(
+        &mut self,
+        config: &FailoverNode,
+        probe: Option<Result<(), String>>,
+        now: Instant,
+    ) -> Outcome {
+        let Some(probe) = probe else {
+            self.powered = false;
+            self.since = None;
+            self.failing_since = None;
+            self.reported = false;
+            if self.state != FailoverState::LeftOff {
+                self.state = FailoverState::Off;
+            }
+            return Outcome::None;
+        };
+        if !self.powered {
+            // powered on, by the user or a schedule: a new life
+            self.powered = true;
+            self.state = FailoverState::Unknown;
+        }
+
+        if let Err(e) = probe {
+            self.last_error = Some(e);
+        } else {
+            self.since = None;
+            self.failing_since = None;
+            self.last_error = None;
+            self.reported = false;
+            let recovering = matches!(
+                self.state,
+                FailoverState::Recovering | FailoverState::GaveUp
+            );
+            self.state = FailoverState::Healthy;
+            return if recovering {
+                Outcome::Recovered
+            } else {
+                Outcome::None
+            };
+        }
+
+        let since = *self.since.get_or_insert(now);
+        if self.failing_since.is_none() {
+            self.failing_since = get_timestamp_unix();
+        }
+        if matches!(
+            self.state,
+            FailoverState::Unknown | FailoverState::Healthy | FailoverState::Off
+        ) {
+            self.state = FailoverState::Failing;
+        }
+        if now.duration_since(since) < config.fail_after {
+            return Outcome::None;
+        }
+
+        match self.state {
+            FailoverState::Recovering => {
+                let Some(step) = config.playbook.get(self.steps.len()) else {
+                    self.state = FailoverState::GaveUp;
+                    return Outcome::None;
+                };
+                self.since = Some(now);
+                Outcome::Run(*step)
+            }
+            FailoverState::Failing if self.enabled => {
+                if self
+                    .started
+                    .is_some_and(|started| now.duration_since(started) < config.cooldown)
+                {
+                    // report once per failure
+                    let reported = std::mem::replace(&mut self.reported, true);
+                    return if reported {
+                        Outcome::None
+                    } else {
+                        Outcome::CoolingDown
+                    };
+                }
+                self.state = FailoverState::Recovering;
+                self.started = Some(now);
+                self.recovery_started = get_timestamp_unix();
+                self.steps.clear();
+                self.since = Some(now);
+                Outcome::Run(config.playbook[0])
+            }
+            _ => Outcome::None,
+        }
+    }
+
+    /// Records that `step` ran.
+    fn ran(&mut self, step: RecoveryStep) {
+        self.steps.push(step);
+        if step == RecoveryStep::PowerOff {
+            self.state = FailoverState::LeftOff;
+            self.powered = false;
+            self.since = None;
+        }
+    }
+}
+
+pub struct Failover {
+    config: config::Failover,
+    bmc: Arc<BmcApplication>,
+    agents: Arc<NodeAgents>,
+    notifier: Arc<Notifier>,
+    nodes: Mutex<BTreeMap<u8, NodeFailover>>,
+}
+
+impl Failover {
+    pub fn new(
+        config: config::Failover,
+        bmc: Arc<BmcApplication>,
+        agents: Arc<NodeAgents>,
+        notifier: Arc<Notifier>,
+    ) -> Self {
+        let nodes = config
+            .nodes
+            .iter()
+            .map(|node| (node.node, NodeFailover::new(node)))
+            .collect();
+        Self {
+            config,
+            bmc,
+            agents,
+            notifier,
+            nodes: Mutex::new(nodes),
+        }
+    }
+
+    /// State of the listed nodes, ordered by node.
+    pub fn status(&self) -> Vec<NodeFailover> {
+        self.lock_nodes().values().cloned().collect()
+    }
+
+    /// Enables or disables the recovery of `node` until bmcd restarts.
+    /// Disabled nodes are still probed.
+    pub fn set_enabled(&self, node: u8, enabled: bool) -> anyhow::Result<()> {
+        let mut nodes = self.lock_nodes();
+        let state = nodes
+            .get_mut(&node)
+            .ok_or_else(|| BmcError::NotFound(format!("failover of node {}", node).into()))?;
+        state.enabled = enabled;
+        tracing::info!(
+            "failover of node {} {}",
+            node,
+            if enabled { "enabled" } else { "disabled" }
+        );
+        Ok(())
+    }
+
+    /// Probes the listed nodes every `interval`. Does nothing when no nodes
+    /// are listed.
+    pub fn run(self: Arc<Self>) {
+        if self.config.nodes.is_empty() {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.config.interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let powered = self.bmc.app_db.get::<u8>(ACTIVATED_NODES_KEY).await;
+                for node in &self.config.nodes {
+                    let probe = if powered & (1 << (node.node - 1)) != 0 {
+                        Some(self.probe(node).await.map_err(|e| format!("{:#}", e)))
+                    } else {
+                        None
+                    };
+                    let outcome = self
+                        .lock_nodes()
+                        .get_mut(&node.node)
+                        .map(|state| state.observe(node, probe, Instant::now()));
+                    match outcome {
+                        Some(Outcome::Run(step)) => self.execute(node, step).await,
+                        Some(Outcome::Recovered) => {
+                            let message = format!("node {} answers health probes again", node.node);
+                            tracing::info!("{}", message);
+                            self.notifier.notify("node_recovered", message).await;
+                        }
+                        Some(Outcome::CoolingDown) => {
+                            let message = format!(
+                                "node {} fails health probes, not recovered within {}s of its last recovery",
+                                node.node,
+                                node.cooldown.as_secs()
+                            );
+                            tracing::warn!("{}", message);
+                            self.notifier.notify("node_unreachable", message).await;
+                        }
+                        Some(Outcome::None) | None => {}
+                    }
+                }
+            }
+        });
+    }
+
+    async fn probe(&self, node: &FailoverNode) -> anyhow::Result<()> {
+        let id = node_id(node.node);
+        let probe = async {
+            match &node.probe {
+                Probe::Agent => self.agents.query(id).await.map(|_| ()),
+                Probe::Tcp(address) => TcpStream::connect(address)
+                    .await
+                    .map(|_| ())
+                    .with_context(|| address.clone()),
+            }
+        };
+        tokio::time::timeout(self.config.timeout, probe)
+            .await
+            .context("probe timed out")?
+    }
+
+    async fn execute(&self, node: &FailoverNode, step: RecoveryStep) {
+        let id = node_id(node.node);
+        let bit = id.to_bitfield();
+        let result = match step {
+            RecoveryStep::Warn => Ok(()),
+            RecoveryStep::Shutdown => self.agents.shutdown(id, false).await,
+            RecoveryStep::PowerCycle => self.bmc.reset_node(id).await,
+            RecoveryStep::PowerOff => self.bmc.activate_slot(0, bit).await,
+        };
+        if let Some(state) = self.lock_nodes().get_mut(&node.node) {
+            state.ran(step);
+        }
+
+        let failing = node.fail_after.as_secs();
+        let message = match (&result, step) {
+            (Err(e), _) => format!("node {}: {:?} failed: {:#}", node.node, step, e),
+            (Ok(()), RecoveryStep::Warn) => format!(
+                "node {} has not answered health probes for {}s",
+                node.node, failing
+            ),
+            (Ok(()), RecoveryStep::Shutdown) => {
+                format!(
+                    "node {} did not answer health probes, shutting it down",
+                    node.node
+                )
+            }
+            (Ok(()), RecoveryStep::PowerCycle) => {
+                format!(
+                    "node {} did not answer health probes, power cycled it",
+                    node.node
+                )
+            }
+            (Ok(()), RecoveryStep::PowerOff) => {
+                format!("node {} did not recover, it is left powered off", node.node)
+            }
+        };
+        tracing::warn!("{}", message);
+        let event = if step == RecoveryStep::Warn {
+            "node_unreachable"
+        } else {
+            "node_recovery"
+        };
+        self.notifier.notify(event, message).await;
+    }
+
+    fn lock_nodes(&self) -> std::sync::MutexGuard<'_, BTreeMap<u8, NodeFailover>> {
+        self.nodes.lock().expect("failover state poisoned")
+    }
+}
+
+fn node_id(node: u8) -> NodeId {
+    NodeId::try_from(node - 1).expect("validated node")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config() -> FailoverNode {
+        FailoverNode {
+            node: 2,
+            enabled: true,
+            probe: Probe::Agent,
+            fail_after: Duration::from_secs(60),
+            cooldown: Duration::from_secs(600),
+            playbook: vec![
+                RecoveryStep::Warn,
+                RecoveryStep::PowerCycle,
+                RecoveryStep::PowerOff,
+            ],
+        }
+    }
+
+    fn fail() -> Option<Result<(), String>> {
+        Some(Err("timed out".to_string()))
+    }
+
+    #[test]
+    fn escalates_through_the_playbook() {
+        let config = config();
+        let mut node = NodeFailover::new(&config);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(node.observe(&config, Some(Ok(())), at(0)), Outcome::None);
+        assert_eq!(node.state, FailoverState::Healthy);
+        assert_eq!(node.observe(&config, fail(), at(10)), Outcome::None);
+        assert_eq!(node.state, FailoverState::Failing);
+        assert_eq!(node.observe(&config, fail(), at(69)), Outcome::None);
+        assert_eq!(
+            node.observe(&config, fail(), at(70)),
+            Outcome::Run(RecoveryStep::Warn)
+        );
+        node.ran(RecoveryStep::Warn);
+        assert_eq!(node.observe(&config, fail(), at(100)), Outcome::None);
+        assert_eq!(
+            node.observe(&config, fail(), at(130)),
+            Outcome::Run(RecoveryStep::PowerCycle)
+        );
+        node.ran(RecoveryStep::PowerCycle);
+        assert_eq!(
+            node.observe(&config, fail(), at(190)),
+            Outcome::Run(RecoveryStep::PowerOff)
+        );
+        node.ran(RecoveryStep::PowerOff);
+        assert_eq!(node.state, FailoverState::LeftOff);
+        assert_eq!(node.observe(&config, None, at(200)), Outcome::None);
+        assert_eq!(node.state, FailoverState::LeftOff);
+
+        // powered on again by the user, within the cooldown
+        assert_eq!(node.observe(&config, fail(), at(300)), Outcome::None);
+        assert_eq!(node.state, FailoverState::Failing);
+        assert_eq!(node.observe(&config, fail(), at(360)), Outcome::CoolingDown);
+        assert_eq!(node.observe(&config, fail(), at(400)), Outcome::None);
+        assert_eq!(node.observe(&config, Some(Ok(())), at(410)), Outcome::None);
+        assert_eq!(node.state, FailoverState::Healthy);
+    }
+
+    #[test]
+    fn recovers() {
+        let config = config();
+        let mut node = NodeFailover::new(&config);
+        let start = Instant::now();
+        node.observe(&config, fail(), start);
+        assert_eq!(
+            node.observe(&config, fail(), start + config.fail_after),
+            Outcome::Run(RecoveryStep::Warn)
+        );
+        node.ran(RecoveryStep::Warn);
+        assert_eq!(
+            node.observe(&config, Some(Ok(())), start + config.fail_after),
+            Outcome::Recovered
+        );
+        assert_eq!(node.steps, [RecoveryStep::Warn]);
+        assert!(node.failing_since.is_none());
+    }
+
+    #[test]
+    fn disabled_nodes_are_only_probed() {
+        let config = FailoverNode {
+            enabled: false,
+            ..config()
+        };
+        let mut node = NodeFailover::new(&config);
+        let start = Instant::now();
+        node.observe(&config, fail(), start);
+        assert_eq!(
+            node.observe(&config, fail(), start + Duration::from_secs(3600)),
+            Outcome::None
+        );
+        assert_eq!(node.state, FailoverState::Failing);
+        assert_eq!(node.last_error.as_deref(), Some("timed out"));
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Verification of signed BMC firmware images. A signed image carries its
+//! signature in a trailer at the end of the file:
+//!
+//! `<image> <signature (DER)> <signature length (u32 BE)> "BMCDSIG1"`
+//!
+//! The signature is a SHA-256 RSA or ECDSA signature over `<image>`. It must
+//! verify against one of the public keys in the trusted keys directory.
+use super::physical_presence::PhysicalPresence;
+use anyhow::{bail, Context};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Public};
+use openssl::sign::Verifier;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::watch;
+
+const SIGNATURE_MAGIC: &[u8; 8] = b"BMCDSIG1";
+const TRAILER_SIZE: u64 = SIGNATURE_MAGIC.len() as u64 + 4;
+const MAX_SIGNATURE_SIZE: u32 = 4096;
+
+pub struct FirmwareVerifier {
+    trusted_keys: PathBuf,
+    presence: Arc<PhysicalPresence>,
+}
+
+impl FirmwareVerifier {
+    pub fn new(trusted_keys: PathBuf, presence: Arc<PhysicalPresence>) -> Self {
+        Self {
+            trusted_keys,
+            presence,
+        }
+    }
+
+    /// Verifies the signature of the image at `path` and strips the trailer
+    /// so that only the image remains. Images without a valid signature are
+    /// only accepted when physical presence was confirmed. The number of
+    /// bytes checked is published on `processed`.
+    pub async fn verify(&self, path: &Path, processed: &watch::Sender<u64>) -> anyhow::Result<()> {
+        let result = self.verify_signature(path, processed).await;
+        let image_size = match result {
+            Ok(size) => {
+                tracing::info!("firmware signature verified");
+                size
+            }
+            Err(e) if self.presence.take_grant() => {
+                tracing::warn!(
+                    "accepting firmware without valid signature ({:#}), physical presence was confirmed",
+                    e
+                );
+                read_trailer(path)
+                    .await?
+                    .map_or(tokio::fs::metadata(path).await?.len(), |(size, _)| size)
+            }
+            Err(e) => return Err(e),
+        };
+
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .await?
+            .set_len(image_size)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the size of the image without trailer when the signature is
+    /// valid.
+    async fn verify_signature(
+        &self,
+        path: &Path,
+        processed: &watch::Sender<u64>,
+    ) -> anyhow::Result<u64> {
+        let Some((image_size, signature)) = read_trailer(path).await? else {
+            bail!("firmware image is not signed");
+        };
+
+        let keys = load_trusted_keys(&self.trusted_keys).await?;
+        for (name, key) in &keys {
+            if verify_with_key(path, image_size, &signature, key, processed).await? {
+                tracing::debug!("firmware signed by {}", name);
+                return Ok(image_size);
+            }
+        }
+
+        bail!(
+            "firmware signature does not match any of the {} trusted key(s)",
+            keys.len()
+        )
+    }
+}
+
+/// Returns the size of the signed image and the signature, or `None` when
+/// the file has no signature trailer.
+async fn read_trailer(path: &Path) -> anyhow::Result<Option<(u64, Vec<u8>)>> {
+    let mut file = File::open(path).await?;
+    let size = file.metadata().await?.len();
+    if size < TRAILER_SIZE {
+        return Ok(None);
+    }
+
+    file.seek(SeekFrom::Start(size - TRAILER_SIZE)).await?;
+    let signature_size = file.read_u32().await?;
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic).await?;
+    if &magic != SIGNATURE_MAGIC {
+        return Ok(None);
+    }
+
+    if signature_size > MAX_SIGNATURE_SIZE || u64::from(signature_size) + TRAILER_SIZE > size {
+        bail!("invalid signature trailer");
+    }
+
+    let image_size = size - TRAILER_SIZE - u64::from(signature_size);
+    let mut signature = vec![0u8; signature_size as usize];
+    file.seek(SeekFrom::Start(image_size)).await?;
+    file.read_exact(&mut signature).await?;
+    Ok(Some((image_size, signature)))
+}
+
+async fn load_trusted_keys(dir: &Path) -> anyhow::Result<Vec<(String, PKey<Public>)>> {
+    let mut keys = Vec::new();
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(keys),
+        Err(e) => return Err(e).context(dir.display().to_string()),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let pem = tokio::fs::read(entry.path()).await?;
+        match PKey::public_key_from_pem(&pem) {
+            Ok(key) => keys.push((entry.file_name().to_string_lossy().to_string(), key)),
+            Err(e) => tracing::warn!("ignoring {}: {}", entry.path().display(), e),
+        }
+    }
+    Ok(keys)
+}
+
+async fn verify_with_key(
+    path: &Path,
+    image_size: u64,
+    signature: &[u8],
+    key: &PKey<Public>,
+    processed: &watch::Sender<u64>,
+) -> anyhow::Result<bool> {
+    let mut verifier = Verifier::new(MessageDigest::sha256(), key)?;
+    let mut file = File::open(path).await?.take(image_size);
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut total = 0;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        verifier.update(&buf[..n])?;
+        total += n as u64;
+        processed.send_replace(total);
+    }
+    Ok(verifier.verify(signature).unwrap_or(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::pkey::Private;
+    use openssl::rsa::Rsa;
+    use openssl::sign::Signer;
+    use tempdir::TempDir;
+
+    fn sign(key: &PKey<Private>, image: &[u8]) -> Vec<u8> {
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        let signature = signer.sign_oneshot_to_vec(image).unwrap();
+        let mut signed = image.to_vec();
+        signed.extend_from_slice(&signature);
+        signed.extend_from_slice(&(signature.len() as u32).to_be_bytes());
+        signed.extend_from_slice(SIGNATURE_MAGIC);
+        signed
+    }
+
+    fn setup() -> (
+        TempDir,
+        PKey<Private>,
+        FirmwareVerifier,
+        Arc<PhysicalPresence>,
+    ) {
+        let dir = TempDir::new("firmware_signature").unwrap();
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        std::fs::create_dir(dir.path().join("keys")).unwrap();
+        std::fs::write(
+            dir.path().join("keys/vendor.pem"),
+            key.public_key_to_pem().unwrap(),
+        )
+        .unwrap();
+        let presence = Arc::new(PhysicalPresence::default());
+        let verifier = FirmwareVerifier::new(dir.path().join("keys"), presence.clone());
+        (dir, key, verifier, presence)
+    }
+
+    #[tokio::test]
+    async fn accept_trusted_signature() {
+        let (dir, key, verifier, _) = setup();
+        let image = dir.path().join("firmware.img");
+        std::fs::write(&image, sign(&key, b"firmware contents")).unwrap();
+
+        verifier
+            .verify(&image, &watch::Sender::new(0))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&image).unwrap(), b"firmware contents");
+    }
+
+    #[tokio::test]
+    async fn reject_tampered_or_unsigned() {
+        let (dir, key, verifier, _) = setup();
+        let image = dir.path().join("firmware.img");
+        let mut signed = sign(&key, b"firmware contents");
+        signed[0] ^= 0xff;
+        std::fs::write(&image, signed).unwrap();
+        assert!(verifier
+            .verify(&image, &watch::Sender::new(0))
+            .await
+            .is_err());
+
+        std::fs::write(&image, b"unsigned firmware").unwrap();
+        assert!(verifier
+            .verify(&image, &watch::Sender::new(0))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn physical_presence_overrides() {
+        let (dir, _, verifier, presence) = setup();
+        let image = dir.path().join("firmware.img");
+        std::fs::write(&image, b"unsigned firmware").unwrap();
+
+        presence.arm();
+        presence.press();
+        verifier
+            .verify(&image, &watch::Sender::new(0))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&image).unwrap(), b"unsigned firmware");
+    }
+}
@@ -0,0 +1,296 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Dual (A/B) firmware slots of the BMC. Upgrades are written to the inactive
+//! slot, after which the bootloader is instructed to try that slot once. The
+//! new firmware must confirm its health within a timeout, otherwise bmcd
+//! rolls back to the previous slot. When bmcd does not come up at all, the
+//! `bootcount`/`bootlimit` mechanism of U-Boot boots the previous slot.
+use crate::config::Firmware;
+use anyhow::{bail, ensure, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::sync::watch;
+
+const ENV_SLOT: &str = "bmc_slot";
+const ENV_UPGRADE_AVAILABLE: &str = "upgrade_available";
+const ENV_BOOTCOUNT: &str = "bootcount";
+/// Interval of the health probes of a freshly booted slot.
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    fn other(self) -> Self {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    fn env_value(self) -> &'static str {
+        match self {
+            Slot::A => "a",
+            Slot::B => "b",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SlotStatus {
+    pub active: Slot,
+    /// the active slot runs a new firmware that is not confirmed yet
+    pub pending_confirmation: bool,
+    pub boot_count: u32,
+    pub confirm_timeout: u64,
+}
+
+pub struct FirmwareSlots {
+    devices: [PathBuf; 2],
+    confirm_timeout: Duration,
+}
+
+impl FirmwareSlots {
+    pub fn new(config: &Firmware) -> Self {
+        Self {
+            devices: config.slots.clone(),
+            confirm_timeout: config.confirm_timeout,
+        }
+    }
+
+    pub async fn status(&self) -> anyhow::Result<SlotStatus> {
+        let env = read_env().await?;
+        Ok(SlotStatus {
+            active: active_slot(&env),
+            pending_confirmation: upgrade_pending(&env),
+            boot_count: env
+                .get(ENV_BOOTCOUNT)
+                .and_then(|c| c.parse().ok())
+                .unwrap_or_default(),
+            confirm_timeout: self.confirm_timeout.as_secs(),
+        })
+    }
+
+    /// Returns the slot an upgrade is written to, the inactive one.
+    pub async fn upgrade_target(&self) -> anyhow::Result<Slot> {
+        let env = read_env().await?;
+        ensure!(
+            !upgrade_pending(&env),
+            "the running firmware is not confirmed yet"
+        );
+        Ok(active_slot(&env).other())
+    }
+
+    /// Writes the given root file-system image to `slot`. Writing the same
+    /// image again is harmless, which allows an interrupted write to be
+    /// resumed. The number of bytes written is published on `written`.
+    pub async fn write(
+        &self,
+        slot: Slot,
+        image: &Path,
+        written: &watch::Sender<u64>,
+    ) -> anyhow::Result<()> {
+        let device = &self.devices[slot.index()];
+        tracing::info!("writing firmware to slot {:?} ({})", slot, device.display());
+        write_slot(image, device, written).await
+    }
+
+    /// Arms the bootloader to try `slot` once on the next boot.
+    pub async fn activate(&self, slot: Slot) -> anyhow::Result<()> {
+        set_env(&[
+            (ENV_SLOT, slot.env_value()),
+            (ENV_UPGRADE_AVAILABLE, "1"),
+            (ENV_BOOTCOUNT, "0"),
+        ])
+        .await
+    }
+
+    /// Marks the running firmware as good.
+    pub async fn confirm(&self) -> anyhow::Result<()> {
+        set_env(&[(ENV_UPGRADE_AVAILABLE, "0"), (ENV_BOOTCOUNT, "0")]).await?;
+        tracing::info!("firmware confirmed");
+        Ok(())
+    }
+
+    /// Selects the previous slot for the next boot. The caller is expected
+    /// to reboot the BMC.
+    pub async fn rollback(&self) -> anyhow::Result<Slot> {
+        let env = read_env().await?;
+        let previous = active_slot(&env).other();
+        set_env(&[
+            (ENV_SLOT, previous.env_value()),
+            (ENV_UPGRADE_AVAILABLE, "0"),
+            (ENV_BOOTCOUNT, "0"),
+        ])
+        .await?;
+        tracing::warn!("rolled back to firmware slot {:?}", previous);
+        Ok(previous)
+    }
+
+    /// When the running firmware awaits confirmation, probes the API on
+    /// `api` until it responds and confirms the firmware. Rolls back
+    /// and reboots when the API does not come up within the timeout.
+    pub async fn confirm_when_healthy(&self, api: SocketAddr) -> anyhow::Result<()> {
+        if !upgrade_pending(&read_env().await?) {
+            return Ok(());
+        }
+
+        tracing::info!(
+            "new firmware awaits confirmation, rollback in {}s",
+            self.confirm_timeout.as_secs()
+        );
+        let probe = async {
+            while TcpStream::connect(api).await.is_err() {
+                tokio::time::sleep(PROBE_INTERVAL).await;
+            }
+        };
+
+        if tokio::time::timeout(self.confirm_timeout, probe)
+            .await
+            .is_ok()
+        {
+            return self.confirm().await;
+        }
+
+        tracing::error!("new firmware did not become healthy, rolling back");
+        self.rollback().await?;
+        Command::new("shutdown").args(["-r", "now"]).spawn()?;
+        Ok(())
+    }
+}
+
+async fn write_slot(
+    image: &Path,
+    device: &Path,
+    written: &watch::Sender<u64>,
+) -> anyhow::Result<()> {
+    // UBI volumes must be written through the UBI layer
+    if device.to_string_lossy().starts_with("/dev/ubi") {
+        let status = Command::new("ubiupdatevol")
+            .arg(device)
+            .arg(image)
+            .status()
+            .await
+            .context("ubiupdatevol")?;
+        ensure!(status.success(), "ubiupdatevol returned {}", status);
+        return Ok(());
+    }
+
+    let mut source = tokio::fs::File::open(image).await?;
+    let mut target = OpenOptions::new()
+        .write(true)
+        .open(device)
+        .await
+        .with_context(|| device.display().to_string())?;
+    let mut buf = vec![0u8; 512 * 1024];
+    let mut total = 0;
+    loop {
+        let n = source.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        target.write_all(&buf[..n]).await?;
+        total += n as u64;
+        written.send_replace(total);
+    }
+    target.sync_all().await?;
+    Ok(())
+}
+
+async fn read_env() -> anyhow::Result<HashMap<String, String>> {
+    let output = Command::new("fw_printenv")
+        .output()
+        .await
+        .context("fw_printenv")?;
+    ensure!(
+        output.status.success(),
+        "fw_printenv returned {}",
+        output.status
+    );
+    Ok(parse_env(&String::from_utf8_lossy(&output.stdout)))
+}
+
+async fn set_env(values: &[(&str, &str)]) -> anyhow::Result<()> {
+    // fw_setenv applies a script atomically, in a single write of the env
+    let script: String = values
+        .iter()
+        .map(|(key, value)| format!("{} {}\n", key, value))
+        .collect();
+    let mut child = Command::new("fw_setenv")
+        .args(["-s", "-"])
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .context("fw_setenv")?;
+
+    let Some(mut stdin) = child.stdin.take() else {
+        bail!("fw_setenv has no stdin");
+    };
+    stdin.write_all(script.as_bytes()).await?;
+    drop(stdin);
+
+    let status = child.wait().await?;
+    ensure!(status.success(), "fw_setenv returned {}", status);
+    Ok(())
+}
+
+fn parse_env(output: &str) -> HashMap<String, String> {
+    output
+        .lines()
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+fn active_slot(env: &HashMap<String, String>) -> Slot {
+    match env.get(ENV_SLOT).map(String::as_str) {
+        Some("b") => Slot::B,
+        _ => Slot::A,
+    }
+}
+
+fn upgrade_pending(env: &HashMap<String, String>) -> bool {
+    env.get(ENV_UPGRADE_AVAILABLE).is_some_and(|v| v == "1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slot_from_env() {
+        let env = parse_env("bootcmd=run distro_bootcmd\nbmc_slot=b\nupgrade_available=1\n");
+        assert_eq!(active_slot(&env), Slot::B);
+        assert_eq!(active_slot(&env).other(), Slot::A);
+        assert!(upgrade_pending(&env));
+
+        let env = parse_env("bootdelay=2\n");
+        assert_eq!(active_slot(&env), Slot::A);
+        assert!(!upgrade_pending(&env));
+    }
+}
@@ -0,0 +1,98 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! History of the flashes of the nodes. Each record carries the SHA-256 of
+//! the image as it was received, so it can be told afterwards which image a
+//! node was flashed with, also when the client did not provide a checksum.
+use super::bmc_application::BmcApplication;
+use serde::{Deserialize, Serialize};
+
+pub const FLASH_HISTORY_KEY: &str = "flash_history";
+/// Records that are kept, the oldest are dropped first.
+const MAX_RECORDS: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FlashRecord {
+    pub node: u8,
+    pub file_name: String,
+    pub size: u64,
+    /// unix time at which the flash started
+    pub started: u64,
+    pub duration_ms: u64,
+    /// SHA-256 of the received image, none when the transfer did not complete
+    pub sha256: Option<String>,
+    /// checksum the client provided, e.g. `crc32 1a2b3c4d`
+    pub expected: Option<String>,
+    /// the reason a flash failed
+    pub error: Option<String>,
+}
+
+pub type FlashHistory = Vec<FlashRecord>;
+
+pub async fn record(bmc: &BmcApplication, record: FlashRecord) {
+    let mut history = bmc.app_db.get::<FlashHistory>(FLASH_HISTORY_KEY).await;
+    push(&mut history, record);
+    bmc.app_db.set(FLASH_HISTORY_KEY, history).await;
+}
+
+/// Records of `node` (numbered from 1), or of all nodes, the most recent
+/// first.
+pub async fn history(bmc: &BmcApplication, node: Option<u8>) -> FlashHistory {
+    let history = bmc.app_db.get::<FlashHistory>(FLASH_HISTORY_KEY).await;
+    history
+        .into_iter()
+        .rev()
+        .filter(|r| node.map_or(true, |node| r.node == node))
+        .collect()
+}
+
+fn push(history: &mut FlashHistory, record: FlashRecord) {
+    history.push(record);
+    if history.len() > MAX_RECORDS {
+        history.drain(..history.len() - MAX_RECORDS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(node: u8, started: u64) -> FlashRecord {
+        FlashRecord {
+            node,
+            file_name: "image.img.xz".to_string(),
+            size: 1024,
+            started,
+            duration_ms: 1500,
+            sha256: Some("ab".repeat(32)),
+            expected: None,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn oldest_records_are_dropped() {
+        let mut history = FlashHistory::new();
+        for started in 0..MAX_RECORDS as u64 + 3 {
+            push(&mut history, record(1, started));
+        }
+        assert_eq!(history.len(), MAX_RECORDS);
+        assert_eq!(history[0].started, 3);
+
+        let bytes = bincode::serialize(&history).unwrap();
+        assert_eq!(
+            bincode::deserialize::<FlashHistory>(&bytes).unwrap(),
+            history
+        );
+    }
+}
@@ -0,0 +1,746 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Read-only GraphQL queries over the inventory, sensors, events and jobs, so
+//! that a dashboard fetches exactly the fields it renders in one request.
+//!
+//! This is the query subset of GraphQL: one operation with fields, aliases,
+//! arguments and variables. Fragments, directives and mutations are refused.
+//! Objects have the fields of the REST payloads of `bmcd/schemas`; a field
+//! that a payload omits resolves to `null`. Arguments of the root fields
+//! filter the lists by equal values, `events(last: n)` keeps the newest `n`.
+use super::activity::ActivityMonitor;
+use super::bmc_application::BmcApplication;
+use super::cooling_device::{get_cooling_state, thermal_root, thermal_zones};
+use super::inventory::get_inventory;
+use super::jobs::Jobs;
+use super::node_agent::NodeAgents;
+use super::notifier::Notifier;
+use super::plugins::Plugins;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+
+/// Root fields with the arguments each takes.
+const ROOT_FIELDS: &[(&str, &[&str])] = &[
+    ("inventory", &["node"]),
+    ("sensors", &["kind", "node"]),
+    ("events", &["event", "last"]),
+    ("jobs", &["id", "kind", "state"]),
+];
+
+#[derive(Debug, Error, PartialEq)]
+pub enum QueryError {
+    #[error("syntax error at offset {0}: {1}")]
+    Syntax(usize, String),
+    #[error("{0} are not supported")]
+    Unsupported(&'static str),
+    #[error("variable `${0}` is not defined")]
+    UndefinedVariable(String),
+    #[error("the query nests deeper than {0} levels")]
+    TooDeep(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub alias: Option<String>,
+    pub name: String,
+    pub arguments: Vec<(String, Value)>,
+    pub selection: Vec<Field>,
+}
+
+impl Field {
+    /// Name of the field in the response.
+    fn key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    pub message: String,
+    pub path: Vec<String>,
+}
+
+/// Body of a GraphQL response. `data` is absent when the query was not
+/// executed at all.
+#[derive(Debug, Serialize)]
+pub struct QueryResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Sensor {
+    /// `temperature`, `fan`, `current` or `plugin`
+    pub kind: &'static str,
+    pub name: String,
+    pub node: Option<u8>,
+    pub value: f64,
+    pub unit: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+}
+
+pub struct QuerySources<'a> {
+    pub bmc: &'a BmcApplication,
+    pub agents: &'a NodeAgents,
+    pub notifier: &'a Notifier,
+    pub jobs: &'a Jobs,
+    pub activity: &'a ActivityMonitor,
+    pub plugins: &'a Plugins,
+}
+
+pub async fn execute(
+    sources: &QuerySources<'_>,
+    query: &str,
+    variables: &Map<String, Value>,
+    max_depth: usize,
+) -> QueryResponse {
+    let fields = match parse(query, variables, max_depth) {
+        Ok(fields) => fields,
+        Err(e) => {
+            return QueryResponse {
+                data: None,
+                errors: vec![FieldError {
+                    message: e.to_string(),
+                    path: Vec::new(),
+                }],
+            }
+        }
+    };
+    let mut data = Map::new();
+    let mut errors = Vec::new();
+    for field in &fields {
+        // only the requested sources are read
+        let items = match field.name.as_str() {
+            "inventory" => json!(get_inventory(sources.bmc, sources.agents).await),
+            "sensors" => json!(read_sensors(sources).await),
+            "events" => json!(sources.notifier.recent()),
+            "jobs" => json!(sources.jobs.list()),
+            _ => Value::Null,
+        };
+        let value = resolve(field, items).unwrap_or_else(|message| {
+            errors.push(FieldError {
+                message,
+                path: vec![field.key().to_string()],
+            });
+            Value::Null
+        });
+        data.insert(field.key().to_string(), value);
+    }
+    QueryResponse {
+        data: Some(Value::Object(data)),
+        errors,
+    }
+}
+
+async fn read_sensors(sources: &QuerySources<'_>) -> Vec<Sensor> {
+    let mut sensors: Vec<_> = thermal_zones(&thermal_root())
+        .into_iter()
+        .map(|zone| Sensor {
+            kind: "temperature",
+            name: zone.zone_type.unwrap_or(zone.name),
+            node: None,
+            value: zone.celsius,
+            unit: "°C".to_string(),
+            max: None,
+        })
+        .collect();
+    sensors.extend(get_cooling_state().await.into_iter().map(|fan| Sensor {
+        kind: "fan",
+        name: fan.device,
+        node: None,
+        value: fan.speed as f64,
+        unit: String::new(),
+        max: Some(fan.max_speed as f64),
+    }));
+    sensors.extend(sources.activity.status().into_iter().filter_map(|node| {
+        Some(Sensor {
+            kind: "current",
+            name: format!("node{}", node.node),
+            node: Some(node.node),
+            value: f64::from(node.current?),
+            unit: "mA".to_string(),
+            max: None,
+        })
+    }));
+    for plugin in sources.plugins.list() {
+        sensors.extend(plugin.sensors.into_iter().map(|(name, sensor)| Sensor {
+            kind: "plugin",
+            name: format!("{}/{}", plugin.name, name),
+            node: None,
+            value: sensor.value,
+            unit: sensor.unit,
+            max: None,
+        }));
+    }
+    sensors
+}
+
+/// Filters the `items` of a root field by its arguments and selects the
+/// requested fields of each.
+fn resolve(field: &Field, items: Value) -> Result<Value, String> {
+    let Some((_, arguments)) = ROOT_FIELDS.iter().find(|(name, _)| *name == field.name) else {
+        let names: Vec<_> = ROOT_FIELDS.iter().map(|(name, _)| *name).collect();
+        return Err(format!(
+            "no field `{}` on the query root, there are {}",
+            field.name,
+            names.join(", ")
+        ));
+    };
+    let Value::Array(mut items) = items else {
+        return Err(format!("`{}` is not a list", field.name));
+    };
+    for (name, value) in &field.arguments {
+        if !arguments.contains(&name.as_str()) {
+            return Err(format!("`{}` has no argument `{}`", field.name, name));
+        }
+        if name == "last" {
+            let last = value
+                .as_u64()
+                .ok_or("`last` must be a non-negative integer")?;
+            let skip = items.len().saturating_sub(last as usize);
+            items.drain(..skip);
+        } else if !value.is_null() {
+            items.retain(|item| item.get(name) == Some(value));
+        }
+    }
+    select(&Value::Array(items), field)
+}
+
+fn select(value: &Value, field: &Field) -> Result<Value, String> {
+    match value {
+        Value::Array(items) => items
+            .iter()
+            .map(|item| select(item, field))
+            .collect::<Result<_, _>>()
+            .map(Value::Array),
+        Value::Object(_) if field.selection.is_empty() => Err(format!(
+            "`{}` is an object, select the fields of it",
+            field.key()
+        )),
+        Value::Object(object) => {
+            let mut selected = Map::new();
+            for sub in &field.selection {
+                if !sub.arguments.is_empty() {
+                    return Err(format!(
+                        "`{}` takes no arguments, only root fields do",
+                        sub.name
+                    ));
+                }
+                let value = object.get(&sub.name).unwrap_or(&Value::Null);
+                selected.insert(sub.key().to_string(), select(value, sub)?);
+            }
+            Ok(Value::Object(selected))
+        }
+        Value::Null => Ok(Value::Null),
+        _ if !field.selection.is_empty() => {
+            Err(format!("`{}` is a scalar and has no fields", field.key()))
+        }
+        scalar => Ok(scalar.clone()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Punctuator(char),
+    Spread,
+    Name(String),
+    Value(Value),
+}
+
+fn tokenize(query: &str) -> Result<Vec<(usize, Token)>, QueryError> {
+    let mut tokens = Vec::new();
+    let mut chars = query.char_indices().peekable();
+    while let Some((offset, c)) = chars.next() {
+        let token = match c {
+            // commas are insignificant in GraphQL
+            c if c.is_whitespace() || c == ',' || c == '\u{feff}' => continue,
+            '#' => {
+                while chars.next_if(|(_, c)| *c != '\n').is_some() {}
+                continue;
+            }
+            '{' | '}' | '(' | ')' | '[' | ']' | ':' | '$' | '!' | '=' | '@' => Token::Punctuator(c),
+            '.' => {
+                if chars.next_if(|(_, c)| *c == '.').is_none()
+                    || chars.next_if(|(_, c)| *c == '.').is_none()
+                {
+                    return Err(QueryError::Syntax(offset, "expected `...`".into()));
+                }
+                Token::Spread
+            }
+            '"' => Token::Value(Value::String(string(&mut chars, offset)?)),
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut name = c.to_string();
+                while let Some((_, c)) =
+                    chars.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '_')
+                {
+                    name.push(c);
+                }
+                Token::Name(name)
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut number = c.to_string();
+                while let Some((_, c)) = chars.next_if(|(_, c)| {
+                    c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-')
+                }) {
+                    number.push(c);
+                }
+                let value = match number.parse::<i64>() {
+                    Ok(int) => Value::from(int),
+                    Err(_) => number
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(serde_json::Number::from_f64)
+                        .map(Value::Number)
+                        .ok_or_else(|| {
+                            QueryError::Syntax(offset, format!("invalid number `{}`", number))
+                        })?,
+                };
+                Token::Value(value)
+            }
+            c => return Err(QueryError::Syntax(offset, format!("unexpected `{}`", c))),
+        };
+        tokens.push((offset, token));
+    }
+    Ok(tokens)
+}
+
+fn string(
+    chars: &mut std::iter::Peekable<std::str::CharIndices<'_>>,
+    start: usize,
+) -> Result<String, QueryError> {
+    let mut text = String::new();
+    loop {
+        let Some((offset, c)) = chars.next() else {
+            return Err(QueryError::Syntax(start, "unterminated string".into()));
+        };
+        match c {
+            '"' => return Ok(text),
+            '\n' => return Err(QueryError::Syntax(offset, "unterminated string".into())),
+            '\\' => {
+                let escaped = match chars.next().map(|(_, c)| c) {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some(c @ ('"' | '\\' | '/')) => c,
+                    Some('u') => {
+                        let hex: String = (0..4)
+                            .filter_map(|_| chars.next().map(|(_, c)| c))
+                            .collect();
+                        u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| {
+                                QueryError::Syntax(offset, format!("invalid escape `\\u{}`", hex))
+                            })?
+                    }
+                    _ => return Err(QueryError::Syntax(offset, "invalid escape".into())),
+                };
+                text.push(escaped);
+            }
+            c => text.push(c),
+        }
+    }
+}
+
+struct Parser<'a> {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+    end: usize,
+    variables: Map<String, Value>,
+    provided: &'a Map<String, Value>,
+    max_depth: usize,
+}
+
+/// Parses the single query operation of `query` into its root fields, with
+/// the variables substituted.
+pub fn parse(
+    query: &str,
+    variables: &Map<String, Value>,
+    max_depth: usize,
+) -> Result<Vec<Field>, QueryError> {
+    let mut parser = Parser {
+        tokens: tokenize(query)?,
+        position: 0,
+        end: query.len(),
+        variables: Map::new(),
+        provided: variables,
+        max_depth,
+    };
+    let fields = parser.operation()?;
+    if parser.peek().is_some() {
+        return Err(QueryError::Unsupported("multiple operations"));
+    }
+    Ok(fields)
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .map_or(self.end, |(offset, _)| *offset)
+    }
+
+    fn error(&self, message: impl Into<String>) -> QueryError {
+        QueryError::Syntax(self.offset(), message.into())
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, punctuator: char) -> bool {
+        if self.peek() == Some(&Token::Punctuator(punctuator)) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, punctuator: char) -> Result<(), QueryError> {
+        if self.eat(punctuator) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{}`", punctuator)))
+        }
+    }
+
+    fn name(&mut self) -> Result<String, QueryError> {
+        match self.peek() {
+            Some(Token::Name(name)) => {
+                let name = name.clone();
+                self.position += 1;
+                Ok(name)
+            }
+            _ => Err(self.error("expected a name")),
+        }
+    }
+
+    fn operation(&mut self) -> Result<Vec<Field>, QueryError> {
+        if let Some(Token::Name(keyword)) = self.peek() {
+            match keyword.as_str() {
+                "query" => self.position += 1,
+                "mutation" | "subscription" => {
+                    return Err(QueryError::Unsupported("mutations and subscriptions"))
+                }
+                "fragment" => return Err(QueryError::Unsupported("fragments")),
+                _ => return Err(self.error("expected `query` or `{`")),
+            }
+            if matches!(self.peek(), Some(Token::Name(_))) {
+                self.position += 1;
+            }
+            if self.eat('(') {
+                while !self.eat(')') {
+                    self.variable_definition()?;
+                }
+            }
+        }
+        self.selection_set(1)
+    }
+
+    fn variable_definition(&mut self) -> Result<(), QueryError> {
+        self.expect('$')?;
+        let name = self.name()?;
+        self.expect(':')?;
+        self.variable_type(1)?;
+        let value = match self.provided.get(&name) {
+            Some(value) => Some(value.clone()),
+            None if self.eat('=') => Some(self.value(1)?),
+            None => None,
+        };
+        if self.eat('=') {
+            // the default of a provided variable
+            self.value(1)?;
+        }
+        if let Some(value) = value {
+            self.variables.insert(name, value);
+        }
+        Ok(())
+    }
+
+    /// Types are not checked, the arguments of the root fields are compared
+    /// with the data as they are.
+    fn variable_type(&mut self, depth: usize) -> Result<(), QueryError> {
+        if depth > self.max_depth {
+            return Err(QueryError::TooDeep(self.max_depth));
+        }
+        if self.eat('[') {
+            self.variable_type(depth + 1)?;
+            self.expect(']')?;
+        } else {
+            self.name()?;
+        }
+        self.eat('!');
+        Ok(())
+    }
+
+    fn selection_set(&mut self, depth: usize) -> Result<Vec<Field>, QueryError> {
+        if depth > self.max_depth {
+            return Err(QueryError::TooDeep(self.max_depth));
+        }
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        while !self.eat('}') {
+            fields.push(self.field(depth)?);
+        }
+        if fields.is_empty() {
+            return Err(self.error("empty selection"));
+        }
+        Ok(fields)
+    }
+
+    fn field(&mut self, depth: usize) -> Result<Field, QueryError> {
+        if self.peek() == Some(&Token::Spread) {
+            return Err(QueryError::Unsupported("fragments"));
+        }
+        let mut name = self.name()?;
+        let mut alias = None;
+        if self.eat(':') {
+            alias = Some(name);
+            name = self.name()?;
+        }
+        let mut arguments = Vec::new();
+        if self.eat('(') {
+            while !self.eat(')') {
+                let argument = self.name()?;
+                self.expect(':')?;
+                arguments.push((argument, self.value(1)?));
+            }
+        }
+        if self.peek() == Some(&Token::Punctuator('@')) {
+            return Err(QueryError::Unsupported("directives"));
+        }
+        let selection = if self.peek() == Some(&Token::Punctuator('{')) {
+            self.selection_set(depth + 1)?
+        } else {
+            Vec::new()
+        };
+        Ok(Field {
+            alias,
+            name,
+            arguments,
+            selection,
+        })
+    }
+
+    /// Lists and input objects count towards `max_depth` like selections.
+    fn value(&mut self, depth: usize) -> Result<Value, QueryError> {
+        if depth > self.max_depth {
+            return Err(QueryError::TooDeep(self.max_depth));
+        }
+        match self.next() {
+            Some(Token::Punctuator('$')) => {
+                let name = self.name()?;
+                self.variables
+                    .get(&name)
+                    .cloned()
+                    .ok_or(QueryError::UndefinedVariable(name))
+            }
+            Some(Token::Value(value)) => Ok(value),
+            Some(Token::Name(name)) => Ok(match name.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "null" => Value::Null,
+                // enum values compare like the strings of the data
+                _ => Value::String(name),
+            }),
+            Some(Token::Punctuator('[')) => {
+                let mut items = Vec::new();
+                while !self.eat(']') {
+                    items.push(self.value(depth + 1)?);
+                }
+                Ok(Value::Array(items))
+            }
+            Some(Token::Punctuator('{')) => {
+                let mut object = Map::new();
+                while !self.eat('}') {
+                    let key = self.name()?;
+                    self.expect(':')?;
+                    object.insert(key, self.value(depth + 1)?);
+                }
+                Ok(Value::Object(object))
+            }
+            _ => {
+                self.position -= 1;
+                Err(self.error("expected a value"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(text: &str) -> Result<Vec<Field>, QueryError> {
+        parse(text, &Map::new(), 8)
+    }
+
+    fn field(name: &str, selection: Vec<Field>) -> Field {
+        Field {
+            alias: None,
+            name: name.to_string(),
+            arguments: Vec::new(),
+            selection,
+        }
+    }
+
+    #[test]
+    fn parsing() {
+        let fields = query(
+            r#"query Dashboard($node: Int = 2) {
+                # the slot of interest
+                slot: inventory(node: $node) { node module { identity { soc } } }
+                events(event: "node_stalled", last: 5) { message, timestamp }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0].alias.as_deref(), Some("slot"));
+        assert_eq!(fields[0].name, "inventory");
+        assert_eq!(fields[0].arguments, vec![("node".to_string(), json!(2))]);
+        assert_eq!(
+            fields[0].selection[1],
+            field(
+                "module",
+                vec![field("identity", vec![field("soc", Vec::new())])]
+            )
+        );
+        assert_eq!(
+            fields[1].arguments,
+            vec![
+                ("event".to_string(), json!("node_stalled")),
+                ("last".to_string(), json!(5)),
+            ]
+        );
+
+        let variables = json!({"node": 3});
+        let fields = parse(
+            "query ($node: Int = 2) { inventory(node: $node) { node } }",
+            variables.as_object().unwrap(),
+            8,
+        )
+        .unwrap();
+        assert_eq!(fields[0].arguments[0].1, json!(3));
+        let fields = query(r#"{ jobs(state: running, kind: "a\"bé") { id } }"#).unwrap();
+        assert_eq!(fields[0].arguments[0].1, json!("running"));
+        assert_eq!(fields[0].arguments[1].1, json!("a\"bé"));
+    }
+
+    #[test]
+    fn refused_queries() {
+        assert_eq!(
+            query("mutation { jobs { id } }"),
+            Err(QueryError::Unsupported("mutations and subscriptions"))
+        );
+        assert_eq!(
+            query("{ jobs { ...parts } }"),
+            Err(QueryError::Unsupported("fragments"))
+        );
+        assert_eq!(
+            query("{ jobs @skip(if: true) { id } }"),
+            Err(QueryError::Unsupported("directives"))
+        );
+        assert_eq!(
+            query("{ jobs(id: $id) { id } }"),
+            Err(QueryError::UndefinedVariable("id".into()))
+        );
+        assert_eq!(
+            query("{ jobs { id } } { events { event } }"),
+            Err(QueryError::Unsupported("multiple operations"))
+        );
+        assert!(matches!(
+            query("{ jobs { id }"),
+            Err(QueryError::Syntax(13, _))
+        ));
+        assert_eq!(
+            parse("{ a { b { c } } }", &Map::new(), 2),
+            Err(QueryError::TooDeep(2))
+        );
+    }
+
+    #[test]
+    fn nested_values_are_bounded() {
+        assert!(query("{ jobs(id: [[1]], where: {a: {b: 1}}) { id } }").is_ok());
+        let nested = 50_000;
+        let argument = format!("{}{}", "[".repeat(nested), "]".repeat(nested));
+        assert_eq!(
+            query(&format!("{{ jobs(id: {}) {{ id }} }}", argument)),
+            Err(QueryError::TooDeep(8))
+        );
+        let object = format!("{}1{}", "{a: ".repeat(nested), "}".repeat(nested));
+        assert_eq!(
+            query(&format!("{{ jobs(id: {}) {{ id }} }}", object)),
+            Err(QueryError::TooDeep(8))
+        );
+        let list_type = format!("{}Int{}", "[".repeat(nested), "]".repeat(nested));
+        assert_eq!(
+            query(&format!("query ($v: {}) {{ jobs {{ id }} }}", list_type)),
+            Err(QueryError::TooDeep(8))
+        );
+    }
+
+    #[test]
+    fn resolution() {
+        let events = json!([
+            {"event": "node_stalled", "message": "one", "timestamp": 1},
+            {"event": "node_reset", "message": "two", "timestamp": 2},
+            {"event": "node_stalled", "message": "three", "timestamp": 3},
+            {"event": "node_stalled", "message": "four", "timestamp": 4},
+        ]);
+        let fields =
+            query(r#"{ events(event: "node_stalled", last: 2) { text: message trace_id } }"#)
+                .unwrap();
+        assert_eq!(
+            resolve(&fields[0], events.clone()).unwrap(),
+            json!([
+                {"text": "three", "trace_id": null},
+                {"text": "four", "trace_id": null},
+            ])
+        );
+
+        let fields = query("{ events }").unwrap();
+        assert!(resolve(&fields[0], events.clone()).is_err());
+        let fields = query("{ events { message { text } } }").unwrap();
+        assert!(resolve(&fields[0], events.clone()).is_err());
+        let fields = query("{ events(node: 1) { message } }").unwrap();
+        assert!(resolve(&fields[0], events.clone()).is_err());
+        let fields = query("{ events { message(full: true) } }").unwrap();
+        assert!(resolve(&fields[0], events.clone()).is_err());
+        let fields = query("{ nodes { node } }").unwrap();
+        assert!(resolve(&fields[0], Value::Null).is_err());
+
+        let inventory = json!([
+            {"node": 1, "presence": "present", "module": {"identity": {"soc": "rk3588"}}},
+            {"node": 2, "presence": "absent", "module": null},
+        ]);
+        let fields = query("{ inventory(node: 1) { node module { identity { soc } } } }").unwrap();
+        assert_eq!(
+            resolve(&fields[0], inventory).unwrap(),
+            json!([{"node": 1, "module": {"identity": {"soc": "rk3588"}}}])
+        );
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Policies for requests that pass a reverse proxy or come from web
+//! applications on other origins, see [`crate::config::Http`].
+//!
+//! The client address of a request is the address of its peer, unless the
+//! peer is a trusted proxy. Then the `Forwarded` header (RFC 7239), or else
+//! `X-Forwarded-For`, is followed from the right for as long as the addresses
+//! in it are trusted proxies as well. Headers of untrusted peers are ignored,
+//! as anyone can send them.
+use crate::config::{Cors, Http};
+use crate::utils::IpNetwork;
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// Headers of a response to a cross-origin request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorsGrant {
+    /// value of `Access-Control-Allow-Origin`
+    pub origin: String,
+    pub credentials: bool,
+    pub max_age: Duration,
+}
+
+#[derive(Default)]
+struct Policy {
+    trusted_proxies: Vec<IpNetwork>,
+    cors: Cors,
+}
+
+pub struct HttpPolicy {
+    /// fixed for the lifetime of the daemon, the routes are built on it
+    base_path: Option<String>,
+    policy: RwLock<Policy>,
+}
+
+impl HttpPolicy {
+    pub fn new(config: &Http) -> Self {
+        let policy = Self {
+            base_path: config.base_path.clone(),
+            policy: RwLock::default(),
+        };
+        policy.set_config(config);
+        policy
+    }
+
+    /// Applies the trusted proxies and CORS settings of `config`.
+    pub fn set_config(&self, config: &Http) {
+        let mut policy = self.policy.write().expect("http policy lock poisoned");
+        // validated together with the configuration
+        policy.trusted_proxies = config
+            .trusted_proxies
+            .iter()
+            .filter_map(|proxy| proxy.parse().ok())
+            .collect();
+        policy.cors = config.cors.clone();
+    }
+
+    /// `path` with the base path removed, or `None` when it does not start
+    /// with the base path.
+    pub fn strip_base_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let stripped = path.strip_prefix(self.base_path.as_deref()?)?;
+        match stripped {
+            "" => Some("/"),
+            s if s.starts_with('/') => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Address of the client of a request from `peer`, see the module
+    /// documentation.
+    pub fn client_address(
+        &self,
+        peer: IpAddr,
+        forwarded: Option<&str>,
+        x_forwarded_for: Option<&str>,
+    ) -> IpAddr {
+        let policy = self.policy.read().expect("http policy lock poisoned");
+        let trusted = |ip: IpAddr| policy.trusted_proxies.iter().any(|n| n.contains(ip));
+
+        let peer = peer.to_canonical();
+        if !trusted(peer) {
+            return peer;
+        }
+        let chain: Vec<Option<IpAddr>> = match (forwarded, x_forwarded_for) {
+            (Some(forwarded), _) => forwarded.split(',').map(forwarded_for).collect(),
+            (None, Some(forwarded)) => forwarded
+                .split(',')
+                .map(|hop| parse_node(hop.trim()))
+                .collect(),
+            (None, None) => Vec::new(),
+        };
+
+        let mut client = peer;
+        for hop in chain.into_iter().rev() {
+            // obfuscated or unknown hops end the chain at the last proxy
+            let Some(hop) = hop else {
+                break;
+            };
+            client = hop.to_canonical();
+            if !trusted(client) {
+                break;
+            }
+        }
+        client
+    }
+
+    /// The CORS headers for a request from `origin`, `None` when the origin
+    /// is not allowed.
+    pub fn cors(&self, origin: &str) -> Option<CorsGrant> {
+        let policy = self.policy.read().expect("http policy lock poisoned");
+        let cors = &policy.cors;
+        let any = cors.allowed_origins.iter().any(|o| o == "*");
+        let listed = cors
+            .allowed_origins
+            .iter()
+            .any(|o| o.eq_ignore_ascii_case(origin));
+        if !any && !listed {
+            return None;
+        }
+        Some(CorsGrant {
+            origin: if listed { origin } else { "*" }.to_string(),
+            credentials: cors.allow_credentials && listed,
+            max_age: cors.max_age,
+        })
+    }
+}
+
+/// The `for` parameter of an element of a `Forwarded` header.
+fn forwarded_for(element: &str) -> Option<IpAddr> {
+    element
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+        .and_then(|(_, node)| parse_node(node.trim_matches('"')))
+}
+
+/// A node as in `Forwarded` and `X-Forwarded-For`: `192.0.2.43`,
+/// `192.0.2.43:47011`, `[2001:db8::17]:4711` or `2001:db8::17`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.split_once(':').and_then(|(ip, _)| ip.parse().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> HttpPolicy {
+        HttpPolicy::new(&Http {
+            base_path: Some("/bmc".to_string()),
+            trusted_proxies: vec!["10.0.0.5".to_string(), "fd00::/64".to_string()],
+            cors: Cors {
+                allowed_origins: vec!["https://dashboard.lab".to_string()],
+                allow_credentials: true,
+                ..Default::default()
+            },
+        })
+    }
+
+    #[test]
+    fn client_addresses() {
+        let policy = policy();
+        let proxy: IpAddr = "10.0.0.5".parse().unwrap();
+        let stranger: IpAddr = "192.0.2.1".parse().unwrap();
+
+        // headers of untrusted peers are ignored
+        assert_eq!(
+            policy.client_address(stranger, None, Some("203.0.113.7")),
+            stranger
+        );
+        assert_eq!(
+            policy.client_address(proxy, None, Some("198.51.100.2, 203.0.113.7")),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+        // trusted hops are skipped
+        assert_eq!(
+            policy.client_address(
+                proxy,
+                Some("for=198.51.100.2, for=\"[fd00::9]:4711\";proto=https"),
+                Some("203.0.113.7")
+            ),
+            "198.51.100.2".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            policy.client_address(proxy, Some("for=_hidden"), None),
+            proxy
+        );
+        assert_eq!(
+            policy.client_address(
+                "::ffff:10.0.0.5".parse().unwrap(),
+                None,
+                Some("203.0.113.7:5000")
+            ),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn base_path() {
+        let policy = policy();
+        assert_eq!(
+            policy.strip_base_path("/bmc/api/bmc/about"),
+            Some("/api/bmc/about")
+        );
+        assert_eq!(policy.strip_base_path("/bmc"), Some("/"));
+        assert_eq!(policy.strip_base_path("/bmcd/index.html"), None);
+        assert_eq!(policy.strip_base_path("/api/bmc/about"), None);
+    }
+
+    #[test]
+    fn cors_origins() {
+        let policy = policy();
+        let grant = policy.cors("https://dashboard.lab").unwrap();
+        assert_eq!(grant.origin, "https://dashboard.lab");
+        assert!(grant.credentials);
+        assert_eq!(policy.cors("https://evil.example"), None);
+
+        policy.set_config(&Http {
+            cors: Cors {
+                allowed_origins: vec!["*".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let grant = policy.cors("https://evil.example").unwrap();
+        assert_eq!(grant.origin, "*");
+        assert!(!grant.credentials);
+    }
+}
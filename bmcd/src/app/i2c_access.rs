@@ -0,0 +1,211 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Register access to the I2C devices that are allowlisted in the `i2c`
+//! section of the configuration. Every write, including the ones that are
+//! refused, is logged and appended to the audit log.
+use crate::config::{I2c, I2cDevice};
+use crate::hal::i2c;
+use anyhow::{bail, Context};
+use serde::Serialize;
+use std::io::Write;
+use std::path::PathBuf;
+use tokio::sync::Mutex;
+
+pub struct I2cAccess {
+    allow: Vec<I2cDevice>,
+    audit_log: PathBuf,
+    /// serializes transfers and appends to the audit log
+    lock: Mutex<()>,
+}
+
+#[derive(Debug, Serialize)]
+struct AuditEntry<'a> {
+    time: chrono::DateTime<chrono::Utc>,
+    peer: &'a str,
+    bus: u32,
+    address: u16,
+    register: u8,
+    data: &'a [u8],
+    result: String,
+}
+
+impl I2cAccess {
+    pub fn new(config: &I2c) -> Self {
+        Self {
+            allow: config.allow.clone(),
+            audit_log: config.audit_log.clone(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn devices(&self) -> &[I2cDevice] {
+        &self.allow
+    }
+
+    fn device(&self, bus: u32, address: u16) -> anyhow::Result<&I2cDevice> {
+        self.allow
+            .iter()
+            .find(|d| d.bus == bus && d.address == address)
+            .with_context(|| format!("i2c-{} {:#04x} is not allowed", bus, address))
+    }
+
+    pub async fn read(
+        &self,
+        bus: u32,
+        address: u16,
+        register: u8,
+        len: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.device(bus, address)?;
+        let _guard = self.lock.lock().await;
+        tokio::task::spawn_blocking(move || i2c::read_registers(bus, address, register, len))
+            .await?
+    }
+
+    /// Writes `data` on behalf of `peer`, the address of the API client.
+    pub async fn write(
+        &self,
+        peer: &str,
+        bus: u32,
+        address: u16,
+        register: u8,
+        data: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let _guard = self.lock.lock().await;
+        let result = match self.device(bus, address) {
+            Ok(device) if !device.writable => {
+                Err(anyhow::anyhow!("i2c-{} {:#04x} is read-only", bus, address))
+            }
+            Ok(_) => {
+                let buffer = data.clone();
+                tokio::task::spawn_blocking(move || {
+                    i2c::write_registers(bus, address, register, &buffer)
+                })
+                .await?
+            }
+            Err(e) => Err(e),
+        };
+
+        let entry = AuditEntry {
+            time: chrono::Utc::now(),
+            peer,
+            bus,
+            address,
+            register,
+            data: &data,
+            result: match &result {
+                Ok(()) => "ok".to_string(),
+                Err(e) => format!("{:#}", e),
+            },
+        };
+        tracing::info!(
+            peer,
+            bus,
+            address,
+            register,
+            data = ?data,
+            result = entry.result,
+            "i2c write"
+        );
+        if let Err(e) = self.append_audit(&entry) {
+            tracing::error!("i2c audit log {}: {:#}", self.audit_log.display(), e);
+            if result.is_ok() {
+                bail!("write succeeded but could not be audited: {:#}", e);
+            }
+        }
+        result
+    }
+
+    fn append_audit(&self, entry: &AuditEntry) -> anyhow::Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.audit_log)?;
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn access(dir: &TempDir) -> I2cAccess {
+        I2cAccess::new(&I2c {
+            allow: vec![
+                I2cDevice {
+                    bus: 1,
+                    address: 0x48,
+                    writable: false,
+                },
+                I2cDevice {
+                    bus: 1,
+                    address: 0x36,
+                    writable: true,
+                },
+            ],
+            audit_log: dir.path().join("audit.log"),
+        })
+    }
+
+    fn audit_lines(dir: &TempDir) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(dir.path().join("audit.log"))
+            .unwrap_or_default()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn refused_writes_are_audited() {
+        let dir = TempDir::new("i2c").unwrap();
+        let access = access(&dir);
+
+        assert!(access.read(2, 0x48, 0, 1).await.is_err());
+        assert!(access
+            .write("10.0.0.2", 1, 0x48, 0x01, vec![0x60])
+            .await
+            .is_err());
+        assert!(access
+            .write("10.0.0.2", 1, 0x50, 0x00, vec![0xff])
+            .await
+            .is_err());
+
+        let lines = audit_lines(&dir);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["peer"], "10.0.0.2");
+        assert_eq!(lines[0]["address"], 0x48);
+        assert_eq!(lines[0]["data"], serde_json::json!([0x60]));
+        assert!(lines[0]["result"].as_str().unwrap().contains("read-only"));
+        assert!(lines[1]["result"].as_str().unwrap().contains("not allowed"));
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn write_and_read_back() {
+        let dir = TempDir::new("i2c").unwrap();
+        let access = access(&dir);
+
+        access
+            .write("::1", 1, 0x36, 0x10, vec![1, 2, 3])
+            .await
+            .unwrap();
+        assert_eq!(access.read(1, 0x36, 0x11, 2).await.unwrap(), [2, 3]);
+        assert_eq!(audit_lines(&dir)[0]["result"], "ok");
+        assert!(access.read(1, 0x36, 0, 0).await.is_err());
+    }
+}
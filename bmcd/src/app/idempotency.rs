@@ -0,0 +1,159 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Responses of requests that carried an `Idempotency-Key` header. A request
+//! that is retried with the same key gets the stored response instead of
+//! running again, so a client on a flaky link can retry a reset or a flash
+//! without triggering it twice.
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a key is remembered after its request completed.
+const TTL: Duration = Duration::from_secs(60 * 60);
+const CAPACITY: usize = 128;
+/// Larger responses are not stored; such requests run again when retried.
+pub const MAX_STORED_BODY: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: Bytes,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Lookup {
+    /// first use of the key, the request has to run
+    New,
+    /// the first request with this key has not completed yet
+    InFlight,
+    Replay(StoredResponse),
+    /// the key was used for a different request
+    Mismatch,
+}
+
+enum State {
+    InFlight,
+    Done(StoredResponse, Instant),
+}
+
+struct Entry {
+    /// method, URI and body of the request that used the key first
+    fingerprint: String,
+    state: State,
+}
+
+#[derive(Default)]
+pub struct IdempotencyCache {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl IdempotencyCache {
+    /// Looks up `key` and reserves it for this request when it is new.
+    pub fn begin(&self, key: &str, fingerprint: &str) -> Lookup {
+        let mut entries = self.entries.lock().expect("idempotency lock poisoned");
+        let now = Instant::now();
+        entries.retain(|_, e| match e.state {
+            State::InFlight => true,
+            State::Done(_, at) => now.duration_since(at) < TTL,
+        });
+
+        if let Some(entry) = entries.get(key) {
+            if entry.fingerprint != fingerprint {
+                return Lookup::Mismatch;
+            }
+            return match &entry.state {
+                State::InFlight => Lookup::InFlight,
+                State::Done(response, _) => Lookup::Replay(response.clone()),
+            };
+        }
+
+        if entries.len() >= CAPACITY {
+            let oldest = entries
+                .iter()
+                .filter_map(|(key, e)| match e.state {
+                    State::Done(_, at) => Some((at, key.clone())),
+                    State::InFlight => None,
+                })
+                .min();
+            if let Some((_, oldest)) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            key.to_string(),
+            Entry {
+                fingerprint: fingerprint.to_string(),
+                state: State::InFlight,
+            },
+        );
+        Lookup::New
+    }
+
+    pub fn complete(&self, key: &str, response: StoredResponse) {
+        let mut entries = self.entries.lock().expect("idempotency lock poisoned");
+        if let Some(entry) = entries.get_mut(key) {
+            entry.state = State::Done(response, Instant::now());
+        }
+    }
+
+    /// Releases `key` without a response, a retry runs the request again.
+    pub fn abandon(&self, key: &str) {
+        let mut entries = self.entries.lock().expect("idempotency lock poisoned");
+        entries.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay() {
+        let cache = IdempotencyCache::default();
+        assert_eq!(cache.begin("k1", "POST /batch"), Lookup::New);
+        assert_eq!(cache.begin("k1", "POST /batch"), Lookup::InFlight);
+        assert_eq!(cache.begin("k1", "POST /restart"), Lookup::Mismatch);
+
+        let response = StoredResponse {
+            status: 200,
+            content_type: Some("application/json".to_string()),
+            body: Bytes::from_static(b"{}"),
+        };
+        cache.complete("k1", response.clone());
+        assert_eq!(cache.begin("k1", "POST /batch"), Lookup::Replay(response));
+
+        assert_eq!(cache.begin("k2", "POST /batch"), Lookup::New);
+        cache.abandon("k2");
+        assert_eq!(cache.begin("k2", "POST /batch"), Lookup::New);
+    }
+
+    #[test]
+    fn capacity() {
+        let cache = IdempotencyCache::default();
+        let response = StoredResponse {
+            status: 200,
+            content_type: None,
+            body: Bytes::new(),
+        };
+        for i in 0..CAPACITY + 10 {
+            let key = i.to_string();
+            assert_eq!(cache.begin(&key, "POST /batch"), Lookup::New);
+            cache.complete(&key, response.clone());
+        }
+        assert!(cache.entries.lock().unwrap().len() <= CAPACITY);
+        assert_eq!(cache.begin("0", "POST /batch"), Lookup::New);
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Identification of a node at the rack. The boards have no LED per node, so
+//! the status LED blinks as many times as the number of the node followed by
+//! a pause, until the identification times out or is stopped. The state is
+//! reported like the `IndicatorLED` property of Redfish.
+use super::bmc_application::BmcApplication;
+use crate::error::BmcError;
+use crate::hal::NodeId;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+const BLINK: Duration = Duration::from_millis(300);
+const PAUSE: Duration = Duration::from_millis(1500);
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(15);
+pub const MAX_DURATION: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum IndicatorLed {
+    Off,
+    Blinking,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IdentifyStatus {
+    pub indicator_led: IndicatorLed,
+    /// node that is identified, counting from 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining_secs: Option<u64>,
+}
+
+struct Active {
+    node: NodeId,
+    until: Instant,
+    task: JoinHandle<()>,
+}
+
+pub struct Identify {
+    bmc: Arc<BmcApplication>,
+    active: Mutex<Option<Active>>,
+}
+
+impl Identify {
+    pub fn new(bmc: Arc<BmcApplication>) -> Self {
+        Self {
+            bmc,
+            active: Mutex::new(None),
+        }
+    }
+
+    /// Blinks the pattern of `node` for `duration`. Identifying another node
+    /// stops the current identification.
+    pub fn start(&self, node: NodeId, duration: Duration) -> Result<(), BmcError> {
+        if node as usize >= self.bmc.board().node_count {
+            return Err(BmcError::NoSuchNode(node));
+        }
+        if duration.is_zero() || duration > MAX_DURATION {
+            return Err(BmcError::invalid_parameter(
+                "duration",
+                format!("must be 1 to {} seconds", MAX_DURATION.as_secs()),
+            ));
+        }
+
+        let until = Instant::now() + duration;
+        let bmc = self.bmc.clone();
+        let task = tokio::spawn(async move {
+            blink(&bmc, node, until).await;
+            set_led(&bmc, false).await;
+        });
+        let previous = self
+            .active
+            .lock()
+            .expect("identify lock poisoned")
+            .replace(Active { node, until, task });
+        if let Some(previous) = previous {
+            previous.task.abort();
+        }
+        tracing::info!("identifying {} for {:?}", node, duration);
+        Ok(())
+    }
+
+    pub async fn stop(&self) {
+        let active = self.active.lock().expect("identify lock poisoned").take();
+        if let Some(active) = active {
+            active.task.abort();
+            set_led(&self.bmc, false).await;
+        }
+    }
+
+    pub fn status(&self) -> IdentifyStatus {
+        let active = self.active.lock().expect("identify lock poisoned");
+        let now = Instant::now();
+        match active.as_ref().filter(|a| a.until > now) {
+            Some(active) => IdentifyStatus {
+                indicator_led: IndicatorLed::Blinking,
+                node: Some(active.node as u8 + 1),
+                remaining_secs: Some((active.until - now).as_secs()),
+            },
+            None => IdentifyStatus {
+                indicator_led: IndicatorLed::Off,
+                node: None,
+                remaining_secs: None,
+            },
+        }
+    }
+}
+
+/// One round of the blink pattern of `node`: the LED state and how long it
+/// is held.
+fn pattern(node: NodeId) -> Vec<(bool, Duration)> {
+    let mut steps = Vec::new();
+    for _ in 0..=node as u8 {
+        steps.push((true, BLINK));
+        steps.push((false, BLINK));
+    }
+    if let Some(last) = steps.last_mut() {
+        last.1 = PAUSE;
+    }
+    steps
+}
+
+async fn blink(bmc: &BmcApplication, node: NodeId, until: Instant) {
+    let pattern = pattern(node);
+    for (on, hold) in pattern.iter().cycle() {
+        if Instant::now() >= until {
+            return;
+        }
+        set_led(bmc, *on).await;
+        tokio::time::sleep_until(until.min(Instant::now() + *hold)).await;
+    }
+}
+
+async fn set_led(bmc: &BmcApplication, on: bool) {
+    if let Err(e) = bmc.status_led(on).await {
+        tracing::warn!("status LED: {:#}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blink_pattern() {
+        let steps = pattern(NodeId::Node3);
+        assert_eq!(steps.len(), 6);
+        assert_eq!(steps.iter().filter(|(on, _)| *on).count(), 3);
+        assert_eq!(steps[0], (true, BLINK));
+        assert_eq!(steps[5], (false, PAUSE));
+    }
+}
@@ -0,0 +1,549 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Cache of node images that were downloaded from URLs, stored under their
+//! SHA-256. Flashing an image again takes it from the cache instead of the
+//! network: by digest when the SHA-256 is given, otherwise by URL, as long as
+//! a HEAD request shows the same `ETag`, `Last-Modified` and size as when the
+//! image was cached.
+//!
+//! Once the cache holds more than `max_images` images or `max_size` bytes, the
+//! least recently used images are removed. Use is tracked through the
+//! modification time of the files.
+use super::factory_reset::IMAGES_DIR;
+use super::storage_manager::room_in;
+use crate::config;
+use bytes::Bytes;
+use reqwest::header::{CONTENT_LENGTH, ETAG, LAST_MODIFIED};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+
+/// Images by the URLs they were downloaded from.
+const INDEX_FILE: &str = "index.json";
+const PART_SUFFIX: &str = ".part";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CachedImage {
+    pub sha256: String,
+    pub size: u64,
+    /// unix time at which the image was last flashed or served
+    pub last_used: u64,
+    /// URLs from which the image was downloaded
+    pub urls: Vec<String>,
+}
+
+/// What a URL answered when the image was downloaded, to tell whether it
+/// still serves the same image.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Validators {
+    pub size: u64,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    /// `None` when the response does not tell the size of the image.
+    pub fn from_response(response: &reqwest::Response) -> Option<Self> {
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        Some(Self {
+            size: content_length(response)?,
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        })
+    }
+
+    fn identify_content(&self) -> bool {
+        self.etag.is_some() || self.last_modified.is_some()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct UrlEntry {
+    sha256: String,
+    validators: Validators,
+}
+
+pub struct ImageCache {
+    config: config::ImageCache,
+    /// quota of all images, see `app::storage_manager`
+    images_quota: u64,
+    index: Mutex<BTreeMap<String, UrlEntry>>,
+}
+
+impl ImageCache {
+    pub fn new(config: config::ImageCache, images_quota: u64) -> Self {
+        // downloads that were interrupted by a restart
+        if let Ok(entries) = std::fs::read_dir(&config.dir) {
+            for entry in entries.flatten() {
+                if entry.file_name().to_string_lossy().ends_with(PART_SUFFIX) {
+                    let _ = std::fs::remove_file(entry.path());
+                }
+            }
+        }
+        let index = std::fs::read(config.dir.join(INDEX_FILE))
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        Self {
+            config,
+            images_quota,
+            index: Mutex::new(index),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Path of the image with the hex encoded `sha256`, if it is cached.
+    pub fn path(&self, sha256: &str) -> Option<PathBuf> {
+        if !is_digest(sha256) {
+            return None;
+        }
+        let path = self.config.dir.join(sha256);
+        path.is_file().then_some(path)
+    }
+
+    /// Like [`Self::path`], and marks the image as used.
+    pub fn lookup(&self, sha256: &str) -> Option<PathBuf> {
+        let path = self.path(sha256).filter(|_| self.enabled())?;
+        touch(&path);
+        Some(path)
+    }
+
+    /// Image that was downloaded from `url`, with its SHA-256, when `url`
+    /// still serves the same image.
+    pub async fn lookup_url(
+        &self,
+        client: &reqwest::Client,
+        url: &Url,
+    ) -> Option<(PathBuf, Bytes)> {
+        if !self.enabled() {
+            return None;
+        }
+        let entry = self.lock_index().get(url.as_str()).cloned()?;
+        let path = self.path(&entry.sha256)?;
+        let response = match client
+            .head(url.clone())
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+        {
+            Ok(response) => response,
+            Err(e) => {
+                tracing::info!("cannot revalidate cached image: {}", e);
+                return None;
+            }
+        };
+        if Validators::from_response(&response).as_ref() != Some(&entry.validators) {
+            tracing::info!("{} changed since it was cached", url);
+            return None;
+        }
+        touch(&path);
+        let sha256 = hex::decode(&entry.sha256).ok()?;
+        Some((path, sha256.into()))
+    }
+
+    /// Cached images, the most recently used first.
+    pub fn list(&self) -> io::Result<Vec<CachedImage>> {
+        let index = self.lock_index();
+        let mut images = cached_images(&self.config.dir)?;
+        for (_, image) in &mut images {
+            image.urls = index
+                .iter()
+                .filter(|(_, entry)| entry.sha256 == image.sha256)
+                .map(|(url, _)| url.clone())
+                .collect();
+        }
+        Ok(images.into_iter().map(|(_, image)| image).collect())
+    }
+
+    /// Returns false when the image is not in the cache.
+    pub fn remove(&self, sha256: &str) -> io::Result<bool> {
+        let Some(path) = self.path(sha256) else {
+            return Ok(false);
+        };
+        std::fs::remove_file(path)?;
+        self.prune_index();
+        Ok(true)
+    }
+
+    pub fn clear(&self) -> io::Result<()> {
+        for (path, _) in cached_images(&self.config.dir)? {
+            std::fs::remove_file(path)?;
+        }
+        self.prune_index();
+        Ok(())
+    }
+
+    /// File to which the image downloaded from `url` is written while it is
+    /// flashed. `None` when the cache is disabled or the image does not fit.
+    pub async fn writer(
+        self: &Arc<Self>,
+        url: &Url,
+        sha256: Option<Bytes>,
+        validators: Validators,
+    ) -> Option<CacheWriter> {
+        let config = &self.config;
+        if !config.enabled || config.max_images == 0 {
+            return None;
+        }
+        if config.max_size > 0 && validators.size > config.max_size {
+            tracing::info!("image not cached, it is larger than image_cache.max_size");
+            return None;
+        }
+        if let Some(room) = room_in(Path::new(IMAGES_DIR), self.images_quota) {
+            if validators.size > room {
+                tracing::info!("image not cached, {} bytes left in the images quota", room);
+                return None;
+            }
+        }
+        let result = async {
+            tokio::fs::create_dir_all(&config.dir).await?;
+            let stat = nix::sys::statvfs::statvfs(&config.dir)?;
+            let available = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+            if validators.size > available {
+                tracing::info!("image not cached, {} bytes available", available);
+                return Ok(None);
+            }
+            let part = config
+                .dir
+                .join(format!("{:016x}{}", rand::random::<u64>(), PART_SUFFIX));
+            let file = tokio::fs::File::create(&part).await?;
+            Ok::<_, io::Error>(Some(CacheWriter {
+                cache: self.clone(),
+                file,
+                part,
+                url: url.to_string(),
+                hasher: Sha256::new(),
+                sha256,
+                validators,
+            }))
+        };
+        result.await.unwrap_or_else(|e| {
+            tracing::warn!("image cache {}: {}", config.dir.display(), e);
+            None
+        })
+    }
+
+    fn lock_index(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, UrlEntry>> {
+        self.index.lock().expect("image cache index poisoned")
+    }
+
+    fn update_index(&self, update: impl FnOnce(&mut BTreeMap<String, UrlEntry>)) {
+        let mut index = self.lock_index();
+        update(&mut index);
+        index.retain(|_, entry| self.config.dir.join(&entry.sha256).exists());
+        let result = serde_json::to_vec(&*index)
+            .map_err(io::Error::from)
+            .and_then(|data| {
+                let tmp = self
+                    .config
+                    .dir
+                    .join(format!("{}{}", INDEX_FILE, PART_SUFFIX));
+                std::fs::write(&tmp, data)?;
+                std::fs::rename(tmp, self.config.dir.join(INDEX_FILE))
+            });
+        if let Err(e) = result {
+            tracing::warn!("image cache index: {}", e);
+        }
+    }
+
+    fn prune_index(&self) {
+        self.update_index(|_| {});
+    }
+
+    /// Removes the least recently used images that exceed the limits.
+    fn evict(&self) -> io::Result<()> {
+        let mut total = 0;
+        for (index, (path, image)) in cached_images(&self.config.dir)?.into_iter().enumerate() {
+            total += image.size;
+            let too_large = self.config.max_size > 0 && total > self.config.max_size;
+            if index >= self.config.max_images || too_large {
+                tracing::info!("removing {} from the image cache", path.display());
+                std::fs::remove_file(path)?;
+                total -= image.size;
+            }
+        }
+        self.prune_index();
+        Ok(())
+    }
+}
+
+/// An image on its way into the cache. It is added by [`Self::finish`] once
+/// all data was written and its digest is known.
+pub struct CacheWriter {
+    cache: Arc<ImageCache>,
+    file: tokio::fs::File,
+    part: PathBuf,
+    url: String,
+    hasher: Sha256,
+    /// expected digest
+    sha256: Option<Bytes>,
+    validators: Validators,
+}
+
+impl CacheWriter {
+    pub fn part(&self) -> &Path {
+        &self.part
+    }
+
+    pub async fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.hasher.update(bytes);
+        self.file.write_all(bytes).await
+    }
+
+    pub async fn discard(self) {
+        let _ = tokio::fs::remove_file(&self.part).await;
+    }
+
+    pub async fn finish(self) {
+        let CacheWriter {
+            cache,
+            file,
+            part,
+            url,
+            hasher,
+            sha256,
+            validators,
+        } = self;
+        let digest = hasher.finalize();
+        if sha256.is_some_and(|sha256| sha256[..] != digest[..]) {
+            let _ = tokio::fs::remove_file(&part).await;
+            return;
+        }
+        let digest = hex::encode(digest);
+        let path = cache.config.dir.join(&digest);
+        let result = async {
+            file.sync_all().await?;
+            tokio::fs::rename(&part, &path).await?;
+            if validators.identify_content() {
+                cache.update_index(|index| {
+                    index.insert(
+                        url,
+                        UrlEntry {
+                            sha256: digest,
+                            validators,
+                        },
+                    );
+                });
+            }
+            cache.evict()
+        };
+        match result.await {
+            Ok(()) => tracing::info!("added {} to the image cache", path.display()),
+            Err(e) => {
+                tracing::warn!("image cache {}: {}", path.display(), e);
+                let _ = tokio::fs::remove_file(&part).await;
+            }
+        }
+    }
+}
+
+fn is_digest(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// reqwest reports the size of the body, which is empty for HEAD requests.
+pub fn content_length(response: &reqwest::Response) -> Option<u64> {
+    response
+        .headers()
+        .get(CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+fn touch(path: &Path) {
+    let result = std::fs::File::options()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(e) = result {
+        tracing::warn!("{}: {}", path.display(), e);
+    }
+}
+
+/// Complete images in `dir`, the most recently used first.
+fn cached_images(dir: &Path) -> io::Result<Vec<(PathBuf, CachedImage)>> {
+    let entries = match std::fs::read_dir(dir) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        entries => entries?,
+    };
+    let mut images = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !is_digest(&name) {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        let last_used = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        images.push((
+            entry.path(),
+            CachedImage {
+                sha256: name,
+                size: metadata.len(),
+                last_used,
+                urls: Vec::new(),
+            },
+        ));
+    }
+    images.sort_by(|(_, a), (_, b)| b.last_used.cmp(&a.last_used).then(a.sha256.cmp(&b.sha256)));
+    Ok(images)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpServer, Responder};
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    fn cache(dir: &Path, max_images: usize, max_size: u64) -> Arc<ImageCache> {
+        Arc::new(ImageCache::new(
+            config::ImageCache {
+                enabled: true,
+                dir: dir.to_owned(),
+                max_images,
+                max_size,
+            },
+            0,
+        ))
+    }
+
+    fn add(dir: &Path, name: char, size: usize, age: u64) -> String {
+        let sha256 = name.to_string().repeat(64);
+        std::fs::write(dir.join(&sha256), vec![0; size]).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(dir.join(&sha256))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(age))
+            .unwrap();
+        sha256
+    }
+
+    #[test]
+    fn least_recently_used_are_evicted() {
+        let dir = TempDir::new("image_cache").unwrap();
+        let a = add(dir.path(), 'a', 10, 300);
+        let b = add(dir.path(), 'b', 10, 200);
+        let c = add(dir.path(), 'c', 10, 100);
+        std::fs::write(dir.path().join(format!("x{}", PART_SUFFIX)), b"").unwrap();
+
+        let cache = cache(dir.path(), 2, 0);
+        assert!(!dir.path().join(format!("x{}", PART_SUFFIX)).exists());
+        // using `a` makes `b` the least recently used
+        assert!(cache.lookup(&a).is_some());
+        cache.evict().unwrap();
+        let cached: Vec<_> = cache
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|i| i.sha256)
+            .collect();
+        assert_eq!(cached, [a.clone(), c]);
+        assert!(cache.path(&b).is_none());
+
+        let cache = self::cache(dir.path(), 4, 15);
+        cache.evict().unwrap();
+        let cached: Vec<_> = cache
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|i| i.sha256)
+            .collect();
+        assert_eq!(cached, [a]);
+    }
+
+    async fn image(request: HttpRequest, path: web::Data<PathBuf>) -> impl Responder {
+        actix_files::NamedFile::open_async(path.as_ref())
+            .await
+            .unwrap()
+            .respond_to(&request)
+    }
+
+    #[actix_web::test]
+    async fn lookup_by_url() {
+        let dir = TempDir::new("image_cache").unwrap();
+        let source = dir.path().join("image.img");
+        std::fs::write(&source, b"image v1").unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/image.img",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let data = web::Data::new(source.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route("/image.img", web::get().to(image))
+                .route("/image.img", web::head().to(image))
+        })
+        .listen(listener)
+        .unwrap()
+        .workers(1)
+        .run();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        let client = reqwest::Client::new();
+        let cache = cache(&dir.path().join("cache"), 4, 0);
+        assert!(cache.lookup_url(&client, &url).await.is_none());
+
+        let response = client.get(url.clone()).send().await.unwrap();
+        let validators = Validators::from_response(&response).unwrap();
+        assert!(validators.identify_content());
+        let mut writer = cache.writer(&url, None, validators).await.unwrap();
+        writer
+            .write(&response.bytes().await.unwrap())
+            .await
+            .unwrap();
+        writer.finish().await;
+
+        let (path, sha256) = cache.lookup_url(&client, &url).await.unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"image v1");
+        assert_eq!(sha256[..], Sha256::digest(b"image v1")[..]);
+        assert_eq!(cache.list().unwrap()[0].urls, [url.to_string()]);
+        // the index survives a restart
+        let cache = self::cache(&dir.path().join("cache"), 4, 0);
+        assert!(cache.lookup_url(&client, &url).await.is_some());
+
+        std::fs::write(&source, b"image v2!").unwrap();
+        assert!(cache.lookup_url(&client, &url).await.is_none());
+
+        cache.clear().unwrap();
+        assert!(cache.list().unwrap().is_empty());
+        handle.stop(false).await;
+    }
+}
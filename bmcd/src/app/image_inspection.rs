@@ -0,0 +1,643 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Inspection of an OS image without flashing it, so that users can confirm
+//! that they picked the right file. The image is decompressed and read once,
+//! from start to end, which also works for an upload that is not stored:
+//! * the partition table (MBR or GPT) is taken from the first MiB,
+//! * the superblocks at the start of each partition tell the file-system
+//!   and its label,
+//! * the first `PRETTY_NAME=` of an `os-release` file tells the distribution,
+//! * the first `Linux version` banner tells the kernel version, which is only
+//!   found when the image holds an uncompressed kernel.
+use async_compression::tokio::bufread::{GzipDecoder, XzDecoder};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio_util::io::InspectReader;
+
+const SECTOR_SIZE: u64 = 512;
+/// Part of the image that holds the partition table.
+const HEAD_SIZE: usize = 1024 * 1024;
+/// Bytes read from the start of each partition, enough for the btrfs
+/// superblock at 64 KiB.
+const PROBE_SIZE: usize = 0x11000;
+/// Longest value that is taken from a banner.
+const MAX_VALUE: usize = 128;
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageInfo {
+    pub compression: Compression,
+    /// size of the file as stored or uploaded
+    pub compressed_size: u64,
+    /// size of the disk image, after decompression
+    pub size: u64,
+    pub partition_table: Option<PartitionTable>,
+    /// `PRETTY_NAME` of the distribution
+    pub distro: Option<String>,
+    pub kernel: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PartitionScheme {
+    Mbr,
+    Gpt,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PartitionTable {
+    pub scheme: PartitionScheme,
+    pub partitions: Vec<Partition>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Partition {
+    pub number: u32,
+    /// offset in bytes
+    pub start: u64,
+    /// size in bytes
+    pub size: u64,
+    /// MBR type, e.g. `0x83`, or GPT type GUID
+    #[serde(rename = "type")]
+    pub partition_type: String,
+    pub type_name: Option<&'static str>,
+    /// GPT partition name
+    pub name: Option<String>,
+    pub filesystem: Option<Filesystem>,
+    /// the partition ends beyond the end of the image
+    pub truncated: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Filesystem {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub label: Option<String>,
+}
+
+/// Reads `reader` to the end and describes the image it holds. gzip and xz
+/// compression are recognized by their magic bytes.
+pub async fn inspect<R: AsyncRead + Unpin>(reader: R) -> anyhow::Result<ImageInfo> {
+    let compressed_size = Arc::new(AtomicU64::new(0));
+    let counter = compressed_size.clone();
+    let mut reader = BufReader::new(InspectReader::new(reader, move |bytes: &[u8]| {
+        counter.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+    }));
+
+    let magic = reader.fill_buf().await?;
+    let (compression, mut image): (_, Box<dyn AsyncRead + Unpin + '_>) =
+        if magic.starts_with(XZ_MAGIC) {
+            (Compression::Xz, Box::new(XzDecoder::new(&mut reader)))
+        } else if magic.starts_with(GZIP_MAGIC) {
+            let mut decoder = GzipDecoder::new(&mut reader);
+            decoder.multiple_members(true);
+            (Compression::Gzip, Box::new(decoder))
+        } else {
+            (Compression::None, Box::new(&mut reader))
+        };
+
+    let mut analyzer = Analyzer::default();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = image.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        analyzer.feed(&buffer[..read]);
+    }
+    drop(image);
+
+    Ok(analyzer.finish(compression, compressed_size.load(Ordering::Relaxed)))
+}
+
+/// Collects the facts about an image while it streams by.
+#[derive(Debug)]
+struct Analyzer {
+    offset: u64,
+    head: Vec<u8>,
+    table: Option<Option<(PartitionScheme, Vec<PartitionEntry>)>>,
+    probes: Vec<Probe>,
+    distro: Scanner,
+    kernel: Scanner,
+}
+
+impl Default for Analyzer {
+    fn default() -> Self {
+        Self {
+            offset: 0,
+            head: Vec::new(),
+            table: None,
+            probes: Vec::new(),
+            distro: Scanner::new(b"PRETTY_NAME=", b'\n', distro_name),
+            kernel: Scanner::new(b"Linux version ", b' ', kernel_version),
+        }
+    }
+}
+
+impl Analyzer {
+    fn feed(&mut self, chunk: &[u8]) {
+        let mut rest = chunk;
+        if self.table.is_none() {
+            let take = rest.len().min(HEAD_SIZE - self.head.len());
+            self.head.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+            if self.head.len() == HEAD_SIZE {
+                self.parse_table();
+            }
+        }
+        if self.table.is_some() && !rest.is_empty() {
+            let offset = self.offset + (chunk.len() - rest.len()) as u64;
+            for probe in &mut self.probes {
+                probe.feed(offset, rest);
+            }
+        }
+
+        self.distro.feed(chunk);
+        self.kernel.feed(chunk);
+        self.offset += chunk.len() as u64;
+    }
+
+    fn parse_table(&mut self) {
+        let table = parse_partition_table(&self.head);
+        if let Some((_, entries)) = &table {
+            self.probes = entries
+                .iter()
+                .map(|entry| Probe {
+                    start: entry.start,
+                    data: Vec::new(),
+                })
+                .collect();
+            for probe in &mut self.probes {
+                probe.feed(0, &self.head);
+            }
+        }
+        self.table = Some(table);
+    }
+
+    fn finish(mut self, compression: Compression, compressed_size: u64) -> ImageInfo {
+        if self.table.is_none() {
+            self.parse_table();
+        }
+        let size = self.offset;
+        let probes = self.probes;
+        let partition_table = self
+            .table
+            .flatten()
+            .map(|(scheme, entries)| PartitionTable {
+                scheme,
+                partitions: entries
+                    .into_iter()
+                    .zip(probes)
+                    .map(|(entry, probe)| Partition {
+                        number: entry.number,
+                        start: entry.start,
+                        size: entry.size,
+                        partition_type: entry.partition_type,
+                        type_name: entry.type_name,
+                        name: entry.name,
+                        filesystem: detect_filesystem(&probe.data),
+                        truncated: entry.start + entry.size > size,
+                    })
+                    .collect(),
+            });
+
+        ImageInfo {
+            compression,
+            compressed_size,
+            size,
+            partition_table,
+            distro: self.distro.found,
+            kernel: self.kernel.found,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PartitionEntry {
+    number: u32,
+    start: u64,
+    size: u64,
+    partition_type: String,
+    type_name: Option<&'static str>,
+    name: Option<String>,
+}
+
+/// Captures the first [`PROBE_SIZE`] bytes of a partition.
+#[derive(Debug)]
+struct Probe {
+    start: u64,
+    data: Vec<u8>,
+}
+
+impl Probe {
+    fn feed(&mut self, offset: u64, chunk: &[u8]) {
+        let wanted = self.start + self.data.len() as u64;
+        let end = offset + chunk.len() as u64;
+        if self.data.len() == PROBE_SIZE || wanted < offset || wanted >= end {
+            return;
+        }
+        let from = (wanted - offset) as usize;
+        let take = (chunk.len() - from).min(PROBE_SIZE - self.data.len());
+        self.data.extend_from_slice(&chunk[from..from + take]);
+    }
+}
+
+/// Finds the first `<pattern><value><terminator>` for which `accept` returns
+/// a value, also when it spans two chunks.
+#[derive(Debug)]
+struct Scanner {
+    pattern: &'static [u8],
+    terminator: u8,
+    accept: fn(&[u8]) -> Option<String>,
+    tail: Vec<u8>,
+    found: Option<String>,
+}
+
+impl Scanner {
+    fn new(pattern: &'static [u8], terminator: u8, accept: fn(&[u8]) -> Option<String>) -> Self {
+        Self {
+            pattern,
+            terminator,
+            accept,
+            tail: Vec::new(),
+            found: None,
+        }
+    }
+
+    fn feed(&mut self, chunk: &[u8]) {
+        if self.found.is_some() {
+            return;
+        }
+        let mut buffer = std::mem::take(&mut self.tail);
+        buffer.extend_from_slice(chunk);
+
+        let mut from = 0;
+        while let Some(position) = find(&buffer[from..], self.pattern) {
+            let start = from + position + self.pattern.len();
+            let end = (start + MAX_VALUE).min(buffer.len());
+            if let Some(len) = buffer[start..end]
+                .iter()
+                .position(|b| *b == self.terminator)
+            {
+                if let Some(value) = (self.accept)(&buffer[start..start + len]) {
+                    self.found = Some(value);
+                    return;
+                }
+            }
+            from += position + 1;
+        }
+
+        // a match at the end of this chunk completes in the next one
+        let keep = (self.pattern.len() + MAX_VALUE).min(buffer.len());
+        self.tail = buffer.split_off(buffer.len() - keep);
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn printable(value: &[u8]) -> Option<&str> {
+    let value = std::str::from_utf8(value).ok()?;
+    (!value.is_empty() && !value.chars().any(char::is_control)).then_some(value)
+}
+
+fn distro_name(value: &[u8]) -> Option<String> {
+    let value = printable(value)?;
+    let value = value.trim_matches(|c| c == '"' || c == '\'');
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+fn kernel_version(value: &[u8]) -> Option<String> {
+    // skips format strings such as `Linux version %s`
+    let value = printable(value)?;
+    value
+        .starts_with(|c: char| c.is_ascii_digit())
+        .then(|| value.to_string())
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+fn parse_partition_table(head: &[u8]) -> Option<(PartitionScheme, Vec<PartitionEntry>)> {
+    if u16_at(head, 510)? != 0xaa55 {
+        return None;
+    }
+    let mut entries = Vec::new();
+    for number in 0..4u32 {
+        let entry = &head[446 + 16 * number as usize..][..16];
+        let partition_type = entry[4];
+        if partition_type == 0xee {
+            return parse_gpt(head).map(|entries| (PartitionScheme::Gpt, entries));
+        }
+        let sectors = u64::from(u32_at(entry, 12)?);
+        if partition_type == 0 || sectors == 0 {
+            continue;
+        }
+        entries.push(PartitionEntry {
+            number: number + 1,
+            start: u64::from(u32_at(entry, 8)?) * SECTOR_SIZE,
+            size: sectors * SECTOR_SIZE,
+            partition_type: format!("{:#04x}", partition_type),
+            type_name: mbr_type_name(partition_type),
+            name: None,
+        });
+    }
+    Some((PartitionScheme::Mbr, entries))
+}
+
+fn parse_gpt(head: &[u8]) -> Option<Vec<PartitionEntry>> {
+    let header = head.get(SECTOR_SIZE as usize..)?;
+    if !header.starts_with(b"EFI PART") {
+        return None;
+    }
+    let entries_start = usize::try_from(u64_at(header, 72)? * SECTOR_SIZE).ok()?;
+    let count = u32_at(header, 80)?;
+    let entry_size = u32_at(header, 84)? as usize;
+    if entry_size < 128 {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    for number in 0..count {
+        // entries beyond the head are left out, images rarely have them
+        let Some(entry) = head.get(entries_start + number as usize * entry_size..) else {
+            break;
+        };
+        let Some(entry) = entry.get(..entry_size) else {
+            break;
+        };
+        if entry[..16].iter().all(|b| *b == 0) {
+            continue;
+        }
+        let first = u64_at(entry, 32)?;
+        let last = u64_at(entry, 40)?;
+        let name: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect();
+        let guid = format_guid(&entry[..16]);
+        entries.push(PartitionEntry {
+            number: number + 1,
+            start: first * SECTOR_SIZE,
+            size: (last + 1).saturating_sub(first) * SECTOR_SIZE,
+            type_name: gpt_type_name(&guid),
+            partition_type: guid,
+            name: Some(String::from_utf16_lossy(&name)).filter(|n| !n.is_empty()),
+        });
+    }
+    Some(entries)
+}
+
+/// GUIDs store their first three fields little-endian.
+fn format_guid(bytes: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{}-{}",
+        u32::from_le_bytes(bytes[0..4].try_into().expect("4 bytes")),
+        u16::from_le_bytes([bytes[4], bytes[5]]),
+        u16::from_le_bytes([bytes[6], bytes[7]]),
+        hex::encode_upper(&bytes[8..10]),
+        hex::encode_upper(&bytes[10..16]),
+    )
+}
+
+fn mbr_type_name(partition_type: u8) -> Option<&'static str> {
+    Some(match partition_type {
+        0x05 | 0x0f => "Extended",
+        0x06 | 0x0e => "FAT16",
+        0x0b | 0x0c => "FAT32",
+        0x82 => "Linux swap",
+        0x83 => "Linux",
+        0x8e => "Linux LVM",
+        0xef => "EFI System",
+        _ => return None,
+    })
+}
+
+fn gpt_type_name(guid: &str) -> Option<&'static str> {
+    Some(match guid {
+        "C12A7328-F81F-11D2-BA4B-00A0C93EC93B" => "EFI System",
+        "0FC63DAF-8483-4772-8E79-3D69D8477DE4" => "Linux filesystem",
+        "B921B045-1DF0-41C3-AF44-4C6F280D3FAE" => "Linux root (ARM64)",
+        "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709" => "Linux root (x86-64)",
+        "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F" => "Linux swap",
+        "E6D6D379-F507-44C2-A23C-238F2A3DF928" => "Linux LVM",
+        "BC13C2FF-59E6-4262-A352-B275FD6F7172" => "Linux extended boot",
+        "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7" => "Microsoft basic data",
+        "21686148-6449-6E6F-744E-656564454649" => "BIOS boot",
+        _ => return None,
+    })
+}
+
+/// Recognizes the file-system from the first bytes of a partition.
+fn detect_filesystem(data: &[u8]) -> Option<Filesystem> {
+    let label = |range: std::ops::Range<usize>| {
+        let bytes = data.get(range)?;
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        let label = String::from_utf8_lossy(&bytes[..end]).trim().to_string();
+        (!label.is_empty() && label != "NO NAME").then_some(label)
+    };
+    let filesystem = |kind, label| Some(Filesystem { kind, label });
+
+    if u16_at(data, 1080) == Some(0xef53) {
+        let compat = u32_at(data, 1024 + 0x5c)?;
+        let incompat = u32_at(data, 1024 + 0x60)?;
+        let kind = if incompat & 0x40 != 0 {
+            "ext4"
+        } else if compat & 0x4 != 0 {
+            "ext3"
+        } else {
+            "ext2"
+        };
+        return filesystem(kind, label(1024 + 0x78..1024 + 0x88));
+    }
+    if data.get(0x10040..0x10048) == Some(b"_BHRfS_M") {
+        return filesystem("btrfs", label(0x1012b..0x1022b));
+    }
+    if data.starts_with(b"hsqs") {
+        return filesystem("squashfs", None);
+    }
+    if data.starts_with(b"XFSB") {
+        return filesystem("xfs", label(108..120));
+    }
+    if data.get(4086..4096) == Some(b"SWAPSPACE2") {
+        return filesystem("swap", label(1024 + 28..1024 + 44));
+    }
+    if u32_at(data, 1024) == Some(0xf2f5_2010) {
+        return filesystem("f2fs", None);
+    }
+    if u16_at(data, 510) == Some(0xaa55) {
+        if data.get(82..87) == Some(b"FAT32") {
+            return filesystem("vfat", label(71..82));
+        }
+        if matches!(data.get(54..59), Some(b"FAT12" | b"FAT16")) {
+            return filesystem("vfat", label(43..54));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_compression::tokio::bufread::GzipEncoder;
+
+    const MIB: usize = 1024 * 1024;
+
+    /// Disk with a FAT32 boot partition at 1 MiB and an ext4 root partition
+    /// at 2 MiB that holds an os-release file and a kernel banner.
+    fn disk_image() -> Vec<u8> {
+        let mut image = vec![0u8; 4 * MIB];
+        let mut mbr_entry = |number: usize, kind: u8, start: u32, sectors: u32| {
+            let entry = &mut image[446 + 16 * number..][..16];
+            entry[4] = kind;
+            entry[8..12].copy_from_slice(&start.to_le_bytes());
+            entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+        };
+        mbr_entry(0, 0x0c, 2048, 2048);
+        mbr_entry(1, 0x83, 4096, 8192);
+        image[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+        let boot = &mut image[MIB..];
+        boot[82..87].copy_from_slice(b"FAT32");
+        boot[71..78].copy_from_slice(b"bootfs ");
+        boot[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+        let root = &mut image[2 * MIB..];
+        root[1080..1082].copy_from_slice(&0xef53u16.to_le_bytes());
+        root[1024 + 0x60] = 0x40;
+        root[1024 + 0x78..1024 + 0x7e].copy_from_slice(b"rootfs");
+
+        let os_release = b"ID=debian\nPRETTY_NAME=\"Debian GNU/Linux 12 (bookworm)\"\n";
+        image[3 * MIB..3 * MIB + os_release.len()].copy_from_slice(os_release);
+        // the banner spans the boundary of two read chunks
+        let banner = b"Linux version %s\0Linux version 6.1.0-rk3588 (gcc)";
+        let at = 3 * MIB + 64 * 1024 - 20;
+        image[at..at + banner.len()].copy_from_slice(banner);
+        image
+    }
+
+    #[tokio::test]
+    async fn mbr_image() {
+        let image = disk_image();
+        let info = inspect(image.as_slice()).await.unwrap();
+        assert_eq!(info.compression, Compression::None);
+        assert_eq!(info.size, 4 * MIB as u64);
+        assert_eq!(info.compressed_size, info.size);
+        assert_eq!(
+            info.distro.as_deref(),
+            Some("Debian GNU/Linux 12 (bookworm)")
+        );
+        assert_eq!(info.kernel.as_deref(), Some("6.1.0-rk3588"));
+
+        let table = info.partition_table.unwrap();
+        assert_eq!(table.scheme, PartitionScheme::Mbr);
+        let [boot, root] = table.partitions.as_slice() else {
+            panic!("expected two partitions");
+        };
+        assert_eq!(boot.start, MIB as u64);
+        assert_eq!(boot.type_name, Some("FAT32"));
+        assert_eq!(
+            boot.filesystem,
+            Some(Filesystem {
+                kind: "vfat",
+                label: Some("bootfs".to_string())
+            })
+        );
+        assert!(!boot.truncated);
+        assert_eq!(root.filesystem.as_ref().unwrap().kind, "ext4");
+        assert_eq!(
+            root.filesystem.as_ref().unwrap().label.as_deref(),
+            Some("rootfs")
+        );
+        assert!(root.truncated);
+    }
+
+    #[tokio::test]
+    async fn compressed_image() {
+        let image = disk_image();
+        let mut compressed = Vec::new();
+        GzipEncoder::new(image.as_slice())
+            .read_to_end(&mut compressed)
+            .await
+            .unwrap();
+
+        let info = inspect(compressed.as_slice()).await.unwrap();
+        assert_eq!(info.compression, Compression::Gzip);
+        assert_eq!(info.compressed_size, compressed.len() as u64);
+        assert_eq!(info.size, image.len() as u64);
+        assert_eq!(info.partition_table.unwrap().partitions.len(), 2);
+    }
+
+    #[test]
+    fn gpt() {
+        let mut head = vec![0u8; HEAD_SIZE];
+        head[446 + 4] = 0xee;
+        head[510..512].copy_from_slice(&[0x55, 0xaa]);
+        let header = &mut head[512..];
+        header[..8].copy_from_slice(b"EFI PART");
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&128u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        let entry = &mut head[1024 + 128..][..128];
+        // Linux filesystem
+        entry[..16].copy_from_slice(&[
+            0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47,
+            0x7d, 0xe4,
+        ]);
+        entry[32..40].copy_from_slice(&2048u64.to_le_bytes());
+        entry[40..48].copy_from_slice(&4095u64.to_le_bytes());
+        for (i, c) in "rootfs".encode_utf16().enumerate() {
+            entry[56 + 2 * i..58 + 2 * i].copy_from_slice(&c.to_le_bytes());
+        }
+
+        let (scheme, entries) = parse_partition_table(&head).unwrap();
+        assert_eq!(scheme, PartitionScheme::Gpt);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].number, 2);
+        assert_eq!(entries[0].start, 1024 * 1024);
+        assert_eq!(entries[0].size, 1024 * 1024);
+        assert_eq!(entries[0].type_name, Some("Linux filesystem"));
+        assert_eq!(entries[0].name.as_deref(), Some("rootfs"));
+    }
+}
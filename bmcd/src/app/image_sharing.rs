@@ -0,0 +1,466 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Sharing of node images between boards. Boards that serve images offer the
+//! images in their [`ImageCache`] to other boards at [`IMAGES_PATH`], with
+//! range requests.
+//!
+//! With fetching enabled, a board first asks the boards discovered over mDNS
+//! and the `cluster` peers whether they have the image, and downloads it in
+//! chunks from several of them at once. A chunk that no peer delivers is
+//! requested from the original URL, which must then support range requests.
+//!
+//! Peers are neither authenticated nor are their certificates checked. The
+//! image is validated against its digest at the end of the transfer, like any
+//! other download, which fails the flash when a peer delivered other data.
+use super::cluster::Cluster;
+use super::image_cache::{content_length, CacheWriter, ImageCache, Validators};
+use super::mdns::Mdns;
+use crate::config;
+use crate::streaming_data_service::data_transfer::{url_file_name, DataTransfer};
+use crate::utils::Checksum;
+use anyhow::{ensure, Context};
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt};
+use rand::seq::SliceRandom;
+use reqwest::header::RANGE;
+use reqwest::{StatusCode, Url};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_util::io::ReaderStream;
+
+/// Path under which cached images are served, followed by the hex encoded
+/// SHA-256.
+pub const IMAGES_PATH: &str = "/images/sha256";
+/// TXT key of the mDNS advertisement, the value is the URL scheme.
+pub const MDNS_TXT_KEY: &str = "images";
+/// Chunks that are buffered between the download and the flashing.
+const CHANNEL_CAPACITY: usize = 2;
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct ImageSharing {
+    config: config::ImageSharing,
+    cache: Arc<ImageCache>,
+    mdns: Arc<Mdns>,
+    cluster: Arc<Cluster>,
+    client: reqwest::Client,
+    peer_client: reqwest::Client,
+}
+
+impl ImageSharing {
+    pub fn new(
+        config: config::ImageSharing,
+        cache: Arc<ImageCache>,
+        mdns: Arc<Mdns>,
+        cluster: Arc<Cluster>,
+    ) -> anyhow::Result<Self> {
+        let client = reqwest::Client::new();
+        let peer_client = reqwest::Client::builder()
+            .connect_timeout(config.lookup_timeout)
+            .danger_accept_invalid_certs(true)
+            .build()?;
+        Ok(Self {
+            config,
+            cache,
+            mdns,
+            cluster,
+            client,
+            peer_client,
+        })
+    }
+
+    pub fn serves(&self) -> bool {
+        self.config.serve
+    }
+
+    /// Transfer of the image at `url`. The image comes from the cache or,
+    /// when its SHA-256 is known, from peers when possible. Without caching
+    /// and fetching, the image is downloaded from `url` as is.
+    pub async fn transfer(
+        &self,
+        url: Url,
+        checksum: Option<Checksum>,
+    ) -> anyhow::Result<DataTransfer> {
+        if !self.cache.enabled() && !self.config.fetch {
+            return DataTransfer::url(url, checksum).await;
+        }
+        let file_name = url_file_name(&url);
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let sha256 = match &checksum {
+            Some(Checksum::Sha256(sha256)) => Some(sha256.clone()),
+            _ => None,
+        };
+
+        let cached = match &sha256 {
+            Some(sha256) => self
+                .cache
+                .lookup(&hex::encode(sha256))
+                .map(|path| (path, sha256.clone())),
+            None => self.cache.lookup_url(&self.client, &url).await,
+        };
+        if let Some((path, sha256)) = cached {
+            tracing::info!("{} found in the image cache", file_name.display());
+            let file = tokio::fs::File::open(&path).await?;
+            let size = file.metadata().await?.len();
+            tokio::spawn(produce(ReaderStream::new(file), sender, None));
+            let checksum = checksum.unwrap_or(Checksum::Sha256(sha256));
+            return Ok(DataTransfer::stream(
+                file_name,
+                size,
+                Some(checksum),
+                receiver,
+            ));
+        }
+
+        let peers = match &sha256 {
+            Some(sha256) if self.config.fetch => self.find_peers(&hex::encode(sha256)).await,
+            _ => Vec::new(),
+        };
+        let (validators, data) = match peers.first() {
+            Some((_, size)) => {
+                let size = *size;
+                tracing::info!(
+                    "downloading {} from {} peer(s)",
+                    file_name.display(),
+                    peers.len()
+                );
+                let peers = peers.into_iter().map(|(url, _)| url).collect();
+                let data = swarm(
+                    self.peer_client.clone(),
+                    self.client.clone(),
+                    peers,
+                    url.clone(),
+                    size,
+                    self.config.chunk_size,
+                );
+                let validators = Validators {
+                    size,
+                    ..Default::default()
+                };
+                (validators, data.boxed())
+            }
+            None => {
+                let response = self
+                    .client
+                    .get(url.clone())
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                    .context("http file request error")?;
+                let validators = Validators::from_response(&response)
+                    .context("no content-length field in http response")?;
+                let data = response.bytes_stream().map(|r| r.map_err(io::Error::other));
+                (validators, data.boxed())
+            }
+        };
+
+        let size = validators.size;
+        let cache = self.cache.writer(&url, sha256, validators).await;
+        tokio::spawn(produce(data, sender, cache));
+        Ok(DataTransfer::stream(file_name, size, checksum, receiver))
+    }
+
+    /// Peers that have the image with `digest`, with the size they report.
+    async fn find_peers(&self, digest: &str) -> Vec<(Url, u64)> {
+        let mut bases: Vec<String> = self
+            .mdns
+            .peers()
+            .into_iter()
+            .filter_map(|peer| {
+                let scheme = peer.txt.get(MDNS_TXT_KEY)?;
+                // URLs cannot carry the zone of link-local addresses
+                let address = peer.addresses.iter().find(|a| a.zone.is_none())?;
+                Some(format!(
+                    "{}://{}",
+                    scheme,
+                    SocketAddr::new(address.ip, peer.port)
+                ))
+            })
+            .collect();
+        bases.extend(self.cluster.peer_urls().await);
+        bases.sort();
+        bases.dedup();
+
+        let lookups = bases
+            .iter()
+            .filter_map(|base| Url::parse(&format!("{}{}/{}", base, IMAGES_PATH, digest)).ok())
+            .map(|url| async move {
+                let request = self
+                    .peer_client
+                    .head(url.clone())
+                    .timeout(self.config.lookup_timeout)
+                    .send();
+                match request.await {
+                    Ok(response) if response.status() == StatusCode::OK => {
+                        content_length(&response).map(|size| (url, size))
+                    }
+                    Ok(_) => None,
+                    Err(e) => {
+                        tracing::debug!("image lookup at {}: {}", url, e);
+                        None
+                    }
+                }
+            });
+        let found = futures::future::join_all(lookups).await;
+        select_peers(found.into_iter().flatten().collect(), self.config.max_peers)
+    }
+}
+
+/// Keeps the peers that report the size most of them agree on, at most `max`
+/// of them. The choice is random, so that a fleet spreads over the peers.
+fn select_peers(mut found: Vec<(Url, u64)>, max: usize) -> Vec<(Url, u64)> {
+    let mut counts: BTreeMap<u64, usize> = BTreeMap::new();
+    for (_, size) in &found {
+        *counts.entry(*size).or_default() += 1;
+    }
+    let Some((&size, _)) = counts.iter().max_by_key(|(_, count)| **count) else {
+        return found;
+    };
+    found.retain(|(_, s)| *s == size);
+    found.shuffle(&mut rand::rng());
+    found.truncate(max);
+    found
+}
+
+/// Downloads `size` bytes in chunks, which are requested from the peers in
+/// turn. A chunk that a peer fails to deliver is requested from the other
+/// peers and finally from `origin`; a peer that failed once is skipped.
+fn swarm(
+    peer_client: reqwest::Client,
+    client: reqwest::Client,
+    peers: Vec<Url>,
+    origin: Url,
+    size: u64,
+    chunk_size: u64,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    let concurrency = peers.len();
+    let peers: Arc<Vec<(Url, AtomicBool)>> = Arc::new(
+        peers
+            .into_iter()
+            .map(|url| (url, AtomicBool::new(false)))
+            .collect(),
+    );
+    stream::iter(0..size.div_ceil(chunk_size))
+        .map(move |index| {
+            let (peer_client, client, peers, origin) = (
+                peer_client.clone(),
+                client.clone(),
+                peers.clone(),
+                origin.clone(),
+            );
+            async move {
+                let start = index * chunk_size;
+                let end = (start + chunk_size).min(size) - 1;
+                for i in 0..peers.len() {
+                    let (peer, failed) = &peers[(index as usize + i) % peers.len()];
+                    if failed.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    match fetch_range(&peer_client, peer, start, end).await {
+                        Ok(bytes) => return Ok(bytes),
+                        Err(e) => {
+                            tracing::warn!("{}: {:#}, skipping this peer", peer, e);
+                            failed.store(true, Ordering::Relaxed);
+                        }
+                    }
+                }
+                fetch_range(&client, &origin, start, end)
+                    .await
+                    .with_context(|| origin.to_string())
+                    .map_err(io::Error::other)
+            }
+        })
+        .buffered(concurrency)
+}
+
+async fn fetch_range(
+    client: &reqwest::Client,
+    url: &Url,
+    start: u64,
+    end: u64,
+) -> anyhow::Result<Bytes> {
+    let response = client
+        .get(url.clone())
+        .header(RANGE, format!("bytes={}-{}", start, end))
+        .timeout(CHUNK_TIMEOUT)
+        .send()
+        .await?;
+    ensure!(
+        response.status() == StatusCode::PARTIAL_CONTENT,
+        "range request answered with {}",
+        response.status()
+    );
+    let bytes = response.bytes().await?;
+    ensure!(
+        bytes.len() as u64 == end - start + 1,
+        "received {} of {} bytes",
+        bytes.len(),
+        end - start + 1
+    );
+    Ok(bytes)
+}
+
+/// Passes `data` on to the transfer, and writes it to `cache` along the way.
+async fn produce(
+    mut data: impl Stream<Item = io::Result<Bytes>> + Unpin,
+    sender: mpsc::Sender<io::Result<Bytes>>,
+    mut cache: Option<CacheWriter>,
+) {
+    while let Some(chunk) = data.next().await {
+        let failed = match (&chunk, cache.as_mut()) {
+            (Ok(bytes), Some(file)) => match file.write(bytes).await {
+                Ok(()) => false,
+                Err(e) => {
+                    tracing::warn!("image cache {}: {}", file.part().display(), e);
+                    true
+                }
+            },
+            (Err(_), Some(_)) => true,
+            _ => false,
+        };
+        if failed {
+            if let Some(file) = cache.take() {
+                file.discard().await;
+            }
+        }
+        // the transfer was cancelled
+        if sender.send(chunk).await.is_err() {
+            if let Some(file) = cache.take() {
+                file.discard().await;
+            }
+            return;
+        }
+    }
+    if let Some(file) = cache {
+        file.finish().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+    use sha2::{Digest, Sha256};
+    use std::path::PathBuf;
+    use tempdir::TempDir;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn peers_agree_on_size() {
+        let found = vec![
+            (url("https://a/"), 10),
+            (url("https://b/"), 20),
+            (url("https://c/"), 10),
+            (url("https://d/"), 10),
+        ];
+        let selected = select_peers(found, 2);
+        assert_eq!(selected.len(), 2);
+        assert!(selected
+            .iter()
+            .all(|(u, size)| *size == 10 && u.host_str() != Some("b")));
+        assert!(select_peers(Vec::new(), 2).is_empty());
+    }
+
+    async fn image(request: HttpRequest, path: web::Data<PathBuf>) -> impl Responder {
+        actix_files::NamedFile::open_async(path.as_ref())
+            .await
+            .unwrap()
+            .respond_to(&request)
+    }
+
+    #[actix_web::test]
+    async fn swarm_download_and_cache() {
+        let dir = TempDir::new("image_swarm").unwrap();
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let source = dir.path().join("image.img");
+        std::fs::write(&source, &data).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let source = web::Data::new(source);
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(source.clone())
+                .route("/peer", web::get().to(image))
+                .route("/origin", web::get().to(image))
+                .route("/broken", web::get().to(HttpResponse::InternalServerError))
+        })
+        .listen(listener)
+        .unwrap()
+        .workers(1)
+        .run();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        let client = reqwest::Client::new();
+        let peers = vec![
+            url(&format!("{}/peer", base)),
+            url(&format!("{}/broken", base)),
+        ];
+        let origin = url(&format!("{}/origin", base));
+        let chunks = swarm(
+            client.clone(),
+            client,
+            peers,
+            origin.clone(),
+            data.len() as u64,
+            4096,
+        );
+
+        let sha256 = Bytes::from(Sha256::digest(&data).to_vec());
+        let cache = Arc::new(ImageCache::new(
+            config::ImageCache {
+                enabled: true,
+                dir: dir.path().join("cache"),
+                ..Default::default()
+            },
+            0,
+        ));
+        let validators = Validators {
+            size: data.len() as u64,
+            ..Default::default()
+        };
+        let writer = cache
+            .writer(&origin, Some(sha256.clone()), validators)
+            .await
+            .unwrap();
+        let part = writer.part().to_owned();
+        let (sender, mut receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        tokio::spawn(produce(Box::pin(chunks), sender, Some(writer)));
+        let mut received = Vec::new();
+        while let Some(chunk) = receiver.recv().await {
+            received.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(received, data);
+        handle.stop(false).await;
+
+        // `produce` finishes the cache file after the last chunk was taken
+        let digest = hex::encode(&sha256);
+        for _ in 0..50 {
+            if cache.path(&digest).is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(std::fs::read(cache.path(&digest).unwrap()).unwrap(), data);
+        assert!(!part.exists());
+    }
+}
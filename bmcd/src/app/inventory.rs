@@ -0,0 +1,183 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Inventory of the modules installed in the node slots. Modules identify
+//! themselves through their USB boot ROM, see
+//! [`BmcApplication::identify_module`]. The result is cached, so probing is
+//! only needed when a module is swapped.
+//!
+//! Boards with detect pins report for every slot whether a module is seated.
+//! Without them, a slot is only known to be occupied once a module answered a
+//! probe.
+//!
+//! Slots also list the last report of the agent in the OS of the node, see
+//! [`super::node_agent`].
+use super::bmc_application::{BmcApplication, ACTIVATED_NODES_KEY};
+use super::node_agent::{AgentStatus, NodeAgents};
+use crate::hal::NodeId;
+use crate::usb_boot::ModuleIdentity;
+use serde::{Deserialize, Serialize};
+
+pub const INVENTORY_KEY: &str = "inventory";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProbedModule {
+    pub identity: ModuleIdentity,
+    /// unix time of the probe
+    pub probed_at: u64,
+}
+
+pub type Inventory = [Option<ProbedModule>; 4];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Presence {
+    Present,
+    Absent,
+    Unknown,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Slot {
+    pub node: u8,
+    pub presence: Presence,
+    /// human readable summary, e.g. `CM4 (BCM2711), serial 10000000a1b2c3d4`
+    pub description: Option<String>,
+    pub module: Option<ProbedModule>,
+    pub agent: Option<AgentStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProbeResult {
+    pub node: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module: Option<ProbedModule>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub async fn get_inventory(bmc: &BmcApplication, agents: &NodeAgents) -> Vec<Slot> {
+    let inventory = bmc.app_db.get::<Inventory>(INVENTORY_KEY).await;
+    let detected = bmc.slot_presence().unwrap_or_else(|e| {
+        tracing::warn!("reading slot presence: {:#}", e);
+        None
+    });
+    inventory
+        .into_iter()
+        .take(bmc.board().node_count)
+        .enumerate()
+        .map(|(idx, module)| Slot {
+            node: idx as u8 + 1,
+            presence: presence(detected, idx, module.is_some()),
+            description: module.as_ref().map(|m| m.identity.to_string()),
+            module,
+            agent: NodeId::try_from(idx as u8)
+                .ok()
+                .and_then(|node| agents.status(node)),
+        })
+        .collect()
+}
+
+/// Detect pins are authoritative, a cached probe result only tells that a
+/// module was seated at the time of the probe.
+fn presence(detected: Option<u8>, idx: usize, probed: bool) -> Presence {
+    match detected {
+        Some(bits) if bits & (1 << idx) != 0 => Presence::Present,
+        Some(_) => Presence::Absent,
+        None if probed => Presence::Present,
+        None => Presence::Unknown,
+    }
+}
+
+/// Probes `node`, or all occupied nodes that are powered off. Nodes are probed one
+/// after the other as they share the USB bus. A node that fails to identify
+/// keeps its cached entry.
+pub async fn probe_modules(bmc: &BmcApplication, node: Option<NodeId>) -> Vec<ProbeResult> {
+    let mask = match node {
+        Some(node) => node.to_bitfield(),
+        None => {
+            let present = bmc.slot_presence().ok().flatten().unwrap_or(0xff);
+            !bmc.app_db.get::<u8>(ACTIVATED_NODES_KEY).await & present & bmc.board().node_mask()
+        }
+    };
+    let mut results = Vec::new();
+    for idx in 0..bmc.board().node_count as u8 {
+        if mask & (1 << idx) == 0 {
+            continue;
+        }
+        let node = NodeId::try_from(idx).expect("index is a node");
+        let result = match bmc.identify_module(node).await {
+            Ok(identity) => {
+                tracing::info!("{}: {}", node, identity);
+                let module = ProbedModule {
+                    identity,
+                    probed_at: crate::utils::get_timestamp_unix().unwrap_or_default(),
+                };
+                let mut inventory = bmc.app_db.get::<Inventory>(INVENTORY_KEY).await;
+                inventory[idx as usize] = Some(module.clone());
+                bmc.app_db.set(INVENTORY_KEY, inventory).await;
+                ProbeResult {
+                    node: idx + 1,
+                    module: Some(module),
+                    error: None,
+                }
+            }
+            Err(e) => {
+                tracing::warn!("probing {}: {:#}", node, e);
+                ProbeResult {
+                    node: idx + 1,
+                    module: None,
+                    error: Some(format!("{:#}", e)),
+                }
+            }
+        };
+        results.push(result);
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persisted_inventory() {
+        let mut inventory = Inventory::default();
+        inventory[1] = Some(ProbedModule {
+            identity: ModuleIdentity {
+                module: "RK1".to_string(),
+                soc: "RK3588".to_string(),
+                serial: None,
+                storage: Some(32_000_000_000),
+            },
+            probed_at: 1_700_000_000,
+        });
+        let bytes = bincode::serialize(&inventory).unwrap();
+        assert_eq!(
+            bincode::deserialize::<Inventory>(&bytes).unwrap(),
+            inventory
+        );
+        assert_eq!(
+            inventory[1].as_ref().unwrap().identity.to_string(),
+            "RK1 (RK3588), 32 GB storage"
+        );
+    }
+
+    #[test]
+    fn slot_presence() {
+        assert_eq!(presence(Some(0b0010), 1, false), Presence::Present);
+        assert_eq!(presence(Some(0b0010), 0, true), Presence::Absent);
+        assert_eq!(presence(None, 2, true), Presence::Present);
+        assert_eq!(presence(None, 2, false), Presence::Unknown);
+    }
+}
@@ -0,0 +1,396 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Registry of long-running operations: node flashes, BMC firmware upgrades,
+//! backups and power presets. Each gets a job id when it starts; the job can be queried and
+//! cancelled while it runs, and its outcome is kept for the configured
+//! retention after it finished. Changes of jobs are published to subscribers
+//! and finished jobs are sent to the notification targets.
+use super::notifier::Notifier;
+use crate::error::BmcError;
+use crate::utils::get_timestamp_unix;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, watch};
+use tokio_util::sync::CancellationToken;
+
+pub type JobId = u32;
+
+/// Finished jobs kept at most, regardless of the retention.
+const MAX_FINISHED: usize = 100;
+const EVENT_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    Flash,
+    FirmwareUpgrade,
+    Backup,
+    Clone,
+    PowerPreset,
+    Pipeline,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: JobId,
+    pub kind: JobKind,
+    pub description: String,
+    pub state: JobState,
+    /// unix timestamps in seconds
+    pub started: Option<u64>,
+    pub finished: Option<u64>,
+    /// `None` when the job cannot report its progress
+    pub percent: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+}
+
+pub enum Outcome {
+    Succeeded(Option<Value>),
+    Failed(String),
+    Cancelled,
+}
+
+/// Bytes processed by a job out of `total`.
+pub struct Progress {
+    pub processed: watch::Receiver<u64>,
+    pub total: u64,
+}
+
+struct Entry {
+    job: Job,
+    cancel: CancellationToken,
+    progress: Option<Progress>,
+    finished_at: Option<Instant>,
+    /// file that the job produced, removed together with the job
+    artifact: Option<PathBuf>,
+}
+
+impl Entry {
+    fn snapshot(&self) -> Job {
+        let mut job = self.job.clone();
+        if job.state == JobState::Running {
+            job.percent = self
+                .progress
+                .as_ref()
+                .map(|p| percentage(*p.processed.borrow(), p.total));
+        }
+        job
+    }
+}
+
+pub struct Jobs {
+    entries: Mutex<BTreeMap<JobId, Entry>>,
+    retention: Duration,
+    events: broadcast::Sender<Job>,
+    notifier: Arc<Notifier>,
+}
+
+impl Jobs {
+    pub fn new(retention: Duration, notifier: Arc<Notifier>) -> Self {
+        Self {
+            entries: Mutex::new(BTreeMap::new()),
+            retention,
+            events: broadcast::Sender::new(EVENT_CAPACITY),
+            notifier,
+        }
+    }
+
+    /// Id for a job that has none of its own yet.
+    pub fn next_id(&self) -> JobId {
+        let entries = self.entries.lock().expect("jobs lock poisoned");
+        loop {
+            let id = rand::random();
+            if !entries.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    /// Registers a running job. Cancelling the job cancels `cancel`; the job
+    /// reports its outcome with [`Jobs::finish`].
+    pub fn add(
+        &self,
+        id: JobId,
+        kind: JobKind,
+        description: String,
+        cancel: CancellationToken,
+        progress: Option<Progress>,
+    ) {
+        let entry = Entry {
+            job: Job {
+                id,
+                kind,
+                description,
+                state: JobState::Running,
+                started: get_timestamp_unix(),
+                finished: None,
+                percent: progress.as_ref().map(|_| 0),
+                error: None,
+                result: None,
+            },
+            cancel,
+            progress,
+            finished_at: None,
+            artifact: None,
+        };
+        let job = entry.snapshot();
+        {
+            let mut entries = self.entries.lock().expect("jobs lock poisoned");
+            self.prune(&mut entries);
+            entries.insert(id, entry);
+        }
+        tracing::debug!("job {} started: {}", id, job.description);
+        let _ = self.events.send(job);
+    }
+
+    pub async fn finish(&self, id: JobId, outcome: Outcome) {
+        let job = {
+            let mut entries = self.entries.lock().expect("jobs lock poisoned");
+            let Some(entry) = entries.get_mut(&id) else {
+                return;
+            };
+            if entry.job.state != JobState::Running {
+                return;
+            }
+            let job = &mut entry.job;
+            match outcome {
+                Outcome::Succeeded(result) => {
+                    job.state = JobState::Succeeded;
+                    job.percent = Some(100);
+                    job.result = result;
+                }
+                Outcome::Failed(error) => {
+                    job.state = JobState::Failed;
+                    job.error = Some(error);
+                }
+                Outcome::Cancelled => job.state = JobState::Cancelled,
+            }
+            if job.state != JobState::Succeeded {
+                job.percent = entry
+                    .progress
+                    .as_ref()
+                    .map(|p| percentage(*p.processed.borrow(), p.total));
+            }
+            job.finished = get_timestamp_unix();
+            entry.progress = None;
+            entry.finished_at = Some(Instant::now());
+            entry.job.clone()
+        };
+
+        let (event, message) = match job.state {
+            JobState::Failed => (
+                "job_failed",
+                format!(
+                    "{} failed: {}",
+                    job.description,
+                    job.error.as_deref().unwrap_or_default()
+                ),
+            ),
+            JobState::Cancelled => ("job_cancelled", format!("{} cancelled", job.description)),
+            _ => ("job_succeeded", format!("{} succeeded", job.description)),
+        };
+        tracing::info!("job {}: {}", id, message);
+        let _ = self.events.send(job);
+        self.notifier.notify(event, message).await;
+    }
+
+    /// Intermediate result of a running job, e.g. the state of its steps. The
+    /// result is kept when the job fails or is cancelled.
+    pub fn report(&self, id: JobId, result: Value) {
+        let job = {
+            let mut entries = self.entries.lock().expect("jobs lock poisoned");
+            let Some(entry) = entries.get_mut(&id) else {
+                return;
+            };
+            if entry.job.state != JobState::Running {
+                return;
+            }
+            entry.job.result = Some(result);
+            entry.snapshot()
+        };
+        let _ = self.events.send(job);
+    }
+
+    /// Attaches a file to the job that is deleted when the job is removed.
+    pub fn set_artifact(&self, id: JobId, path: PathBuf) {
+        let mut entries = self.entries.lock().expect("jobs lock poisoned");
+        if let Some(entry) = entries.get_mut(&id) {
+            entry.artifact = Some(path);
+        }
+    }
+
+    pub fn artifact(&self, id: JobId) -> Option<PathBuf> {
+        let entries = self.entries.lock().expect("jobs lock poisoned");
+        entries.get(&id).and_then(|e| e.artifact.clone())
+    }
+
+    pub fn get(&self, id: JobId) -> Option<Job> {
+        let mut entries = self.entries.lock().expect("jobs lock poisoned");
+        self.prune(&mut entries);
+        entries.get(&id).map(Entry::snapshot)
+    }
+
+    /// All jobs, oldest first.
+    pub fn list(&self) -> Vec<Job> {
+        let mut entries = self.entries.lock().expect("jobs lock poisoned");
+        self.prune(&mut entries);
+        let mut jobs: Vec<_> = entries.values().map(Entry::snapshot).collect();
+        jobs.sort_by_key(|j| j.started);
+        jobs
+    }
+
+    pub fn cancel(&self, id: JobId) -> Result<(), BmcError> {
+        let entries = self.entries.lock().expect("jobs lock poisoned");
+        let entry = entries
+            .get(&id)
+            .ok_or_else(|| BmcError::invalid_parameter("id", format!("no job {}", id)))?;
+        if entry.job.state != JobState::Running {
+            return Err(BmcError::NotSupported(
+                format!("job {} already finished", id).into(),
+            ));
+        }
+        tracing::info!("cancelling job {}", id);
+        entry.cancel.cancel();
+        Ok(())
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Job> {
+        self.events.subscribe()
+    }
+
+    fn prune(&self, entries: &mut BTreeMap<JobId, Entry>) {
+        let now = Instant::now();
+        let mut finished: Vec<_> = entries
+            .iter()
+            .filter_map(|(id, e)| e.finished_at.map(|at| (at, *id)))
+            .collect();
+        finished.sort();
+        let excess = finished.len().saturating_sub(MAX_FINISHED);
+        for (index, (at, id)) in finished.into_iter().enumerate() {
+            if index >= excess && now.duration_since(at) < self.retention {
+                continue;
+            }
+            if let Some(path) = entries.remove(&id).and_then(|e| e.artifact) {
+                if let Err(e) = std::fs::remove_file(&path) {
+                    tracing::warn!("removing {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+fn percentage(processed: u64, total: u64) -> u8 {
+    if total == 0 {
+        return 0;
+    }
+    (processed.min(total) * 100 / total) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jobs(retention: Duration) -> Jobs {
+        Jobs::new(retention, Arc::new(Notifier::new(Vec::new())))
+    }
+
+    #[tokio::test]
+    async fn lifecycle() {
+        let jobs = jobs(Duration::from_secs(60));
+        let mut events = jobs.subscribe();
+        let cancel = CancellationToken::new();
+        let (processed, receiver) = watch::channel(0u64);
+        jobs.add(
+            7,
+            JobKind::Flash,
+            "flash node 1".to_string(),
+            cancel.clone(),
+            Some(Progress {
+                processed: receiver,
+                total: 200,
+            }),
+        );
+        assert_eq!(events.recv().await.unwrap().state, JobState::Running);
+
+        processed.send_replace(50);
+        assert_eq!(jobs.get(7).unwrap().percent, Some(25));
+        jobs.report(7, Value::from("step 1"));
+        assert_eq!(
+            events.recv().await.unwrap().result,
+            Some(Value::from("step 1"))
+        );
+
+        jobs.cancel(7).unwrap();
+        assert!(cancel.is_cancelled());
+        jobs.finish(7, Outcome::Cancelled).await;
+        let job = events.recv().await.unwrap();
+        assert_eq!(job.state, JobState::Cancelled);
+        assert_eq!(job.percent, Some(25));
+        assert_eq!(job.result, Some(Value::from("step 1")));
+        assert!(jobs.cancel(7).is_err());
+        assert!(jobs.cancel(8).is_err());
+
+        jobs.add(
+            8,
+            JobKind::Backup,
+            "backup".to_string(),
+            CancellationToken::new(),
+            None,
+        );
+        jobs.finish(8, Outcome::Succeeded(Some(Value::from("done"))))
+            .await;
+        let job = jobs.get(8).unwrap();
+        assert_eq!(job.state, JobState::Succeeded);
+        assert_eq!(job.result, Some(Value::from("done")));
+        assert_eq!(jobs.list().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn finished_jobs_expire() {
+        let dir = tempdir::TempDir::new("jobs").unwrap();
+        let artifact = dir.path().join("backup.tar.gz");
+        std::fs::write(&artifact, b"archive").unwrap();
+
+        let jobs = jobs(Duration::ZERO);
+        jobs.add(
+            1,
+            JobKind::Backup,
+            "backup".to_string(),
+            CancellationToken::new(),
+            None,
+        );
+        jobs.set_artifact(1, artifact.clone());
+        assert!(jobs.get(1).is_some());
+        jobs.finish(1, Outcome::Failed("disk full".to_string()))
+            .await;
+        assert!(jobs.get(1).is_none());
+        assert!(!artifact.exists());
+    }
+}
@@ -0,0 +1,430 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Integration with a Kubernetes cluster that runs on the node modules.
+//! Before a node is powered off or reset, its Kubernetes node is cordoned and
+//! drained the way `kubectl drain` does it: pods are evicted through the
+//! eviction API, which respects pod disruption budgets, and DaemonSet and
+//! mirror pods are left alone. Once the node is powered on again and reports
+//! `Ready`, it is uncordoned.
+//!
+//! Nodes that bmcd cordoned carry the [`CORDONED_ANNOTATION`], so a node that
+//! an administrator cordoned stays cordoned after a power cycle.
+use crate::config;
+use anyhow::{bail, Context};
+use reqwest::{Certificate, Client, Method, StatusCode, Url};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{sleep, timeout, Instant};
+
+pub const CORDONED_ANNOTATION: &str = "bmcd.turingpi.com/cordoned";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct Kubernetes {
+    config: config::Kubernetes,
+    api_server: Url,
+    client: Client,
+    poll_interval: Duration,
+}
+
+impl Kubernetes {
+    pub fn new(config: config::Kubernetes) -> anyhow::Result<Self> {
+        let mut builder = Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .danger_accept_invalid_certs(config.insecure);
+        if let Some(path) = &config.ca_certificate {
+            let pem = std::fs::read(path).with_context(|| path.display().to_string())?;
+            builder = builder.add_root_certificate(Certificate::from_pem(&pem)?);
+        }
+        Ok(Self {
+            api_server: Url::parse(&config.api_server).context("kubernetes.api_server")?,
+            client: builder.build()?,
+            config,
+            poll_interval: POLL_INTERVAL,
+        })
+    }
+
+    /// Names of the Kubernetes nodes in the slots of `mask`.
+    fn node_names(&self, mask: u8) -> Vec<String> {
+        self.config
+            .nodes
+            .iter()
+            .filter(|n| (1..=4).contains(&n.node) && mask & (1 << (n.node - 1)) != 0)
+            .map(|n| n.name.clone())
+            .collect()
+    }
+
+    /// Cordons and drains the Kubernetes nodes in the slots of `mask`. Fails
+    /// when a node could not be drained, unless `force` is configured.
+    pub async fn prepare_power_off(&self, mask: u8) -> anyhow::Result<()> {
+        for name in self.node_names(mask) {
+            let result = async {
+                self.cordon(&name).await?;
+                self.drain(&name).await
+            }
+            .await
+            .with_context(|| format!("draining Kubernetes node {}", name));
+            match result {
+                Ok(()) => tracing::info!("drained Kubernetes node {}", name),
+                Err(e) if self.config.force => tracing::warn!("{:#}, powering off anyway", e),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Uncordons the Kubernetes nodes in the slots of `mask` in the
+    /// background, as soon as they report `Ready`.
+    pub fn after_power_on(self: &Arc<Self>, mask: u8) {
+        for name in self.node_names(mask) {
+            let kubernetes = self.clone();
+            tokio::spawn(async move {
+                let ready_timeout = kubernetes.config.ready_timeout;
+                let result = match timeout(ready_timeout, kubernetes.wait_ready(&name)).await {
+                    Ok(Ok(())) => kubernetes.uncordon(&name).await,
+                    Ok(Err(e)) => Err(e),
+                    Err(_) => Err(anyhow::anyhow!("not ready after {:?}", ready_timeout)),
+                };
+                match result {
+                    Ok(()) => tracing::info!("Kubernetes node {} is back", name),
+                    Err(e) => tracing::warn!("uncordoning Kubernetes node {}: {:#}", name, e),
+                }
+            });
+        }
+    }
+
+    async fn call(&self, method: Method, path: &str, body: Option<Value>) -> anyhow::Result<Value> {
+        let url = self.api_server.join(path)?;
+        let mut request = self
+            .client
+            .request(method.clone(), url)
+            .bearer_auth(&self.config.token);
+        if let Some(body) = body {
+            let content_type = if method == Method::PATCH {
+                "application/merge-patch+json"
+            } else {
+                "application/json"
+            };
+            request = request
+                .header(reqwest::header::CONTENT_TYPE, content_type)
+                .body(body.to_string());
+        }
+        let response = request.send().await?.error_for_status()?;
+        Ok(response.json().await?)
+    }
+
+    async fn cordon(&self, name: &str) -> anyhow::Result<()> {
+        let node = self.node(name).await?;
+        if unschedulable(&node) {
+            // cordoned by someone else, or by an earlier power-off
+            return Ok(());
+        }
+        let patch = json!({
+            "metadata": { "annotations": { CORDONED_ANNOTATION: "true" } },
+            "spec": { "unschedulable": true },
+        });
+        self.call(Method::PATCH, &node_path(name), Some(patch))
+            .await?;
+        Ok(())
+    }
+
+    async fn uncordon(&self, name: &str) -> anyhow::Result<()> {
+        let node = self.node(name).await?;
+        if node["metadata"]["annotations"][CORDONED_ANNOTATION].is_null() {
+            return Ok(());
+        }
+        let patch = json!({
+            "metadata": { "annotations": { CORDONED_ANNOTATION: null } },
+            "spec": { "unschedulable": null },
+        });
+        self.call(Method::PATCH, &node_path(name), Some(patch))
+            .await?;
+        Ok(())
+    }
+
+    /// Evicts the pods of node `name` until none are left. Evictions that a
+    /// disruption budget refuses are retried until the drain timeout.
+    async fn drain(&self, name: &str) -> anyhow::Result<()> {
+        let deadline = Instant::now() + self.config.drain_timeout;
+        loop {
+            let path = format!("api/v1/pods?fieldSelector=spec.nodeName%3D{}", name);
+            let pods = evictable_pods(&self.call(Method::GET, &path, None).await?);
+            if pods.is_empty() {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                let names: Vec<_> = pods
+                    .iter()
+                    .map(|(ns, pod)| format!("{}/{}", ns, pod))
+                    .collect();
+                bail!("pods not evicted in time: {}", names.join(", "));
+            }
+            for (namespace, pod) in pods {
+                let path = format!("api/v1/namespaces/{}/pods/{}/eviction", namespace, pod);
+                let eviction = json!({
+                    "apiVersion": "policy/v1",
+                    "kind": "Eviction",
+                    "metadata": { "name": pod, "namespace": namespace },
+                });
+                match self.call(Method::POST, &path, Some(eviction)).await {
+                    Ok(_) => tracing::debug!("evicting {}/{}", namespace, pod),
+                    // refused by a disruption budget, or already gone
+                    Err(e) if is_status(&e, StatusCode::TOO_MANY_REQUESTS) => {}
+                    Err(e) if is_status(&e, StatusCode::NOT_FOUND) => {}
+                    Err(e) => return Err(e.context(format!("evicting {}/{}", namespace, pod))),
+                }
+            }
+            sleep(self.poll_interval).await;
+        }
+    }
+
+    async fn wait_ready(&self, name: &str) -> anyhow::Result<()> {
+        loop {
+            sleep(self.poll_interval).await;
+            match self.node(name).await {
+                Ok(node) if is_ready(&node) => return Ok(()),
+                Ok(_) => {}
+                Err(e) => tracing::debug!("polling Kubernetes node {}: {:#}", name, e),
+            }
+        }
+    }
+
+    async fn node(&self, name: &str) -> anyhow::Result<Value> {
+        self.call(Method::GET, &node_path(name), None).await
+    }
+}
+
+fn node_path(name: &str) -> String {
+    format!("api/v1/nodes/{}", name)
+}
+
+fn is_status(error: &anyhow::Error, status: StatusCode) -> bool {
+    error
+        .downcast_ref::<reqwest::Error>()
+        .and_then(reqwest::Error::status)
+        == Some(status)
+}
+
+fn unschedulable(node: &Value) -> bool {
+    node["spec"]["unschedulable"].as_bool().unwrap_or(false)
+}
+
+fn is_ready(node: &Value) -> bool {
+    node["status"]["conditions"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .any(|c| c["type"] == "Ready" && c["status"] == "True")
+}
+
+/// Namespace and name of the pods in `list` that a drain evicts: not owned by
+/// a DaemonSet, not a mirror pod of a static pod, and not yet finished.
+fn evictable_pods(list: &Value) -> Vec<(String, String)> {
+    list["items"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|pod| {
+            let daemon_set = pod["metadata"]["ownerReferences"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .any(|owner| owner["kind"] == "DaemonSet");
+            let mirror = !pod["metadata"]["annotations"]["kubernetes.io/config.mirror"].is_null();
+            let finished = matches!(
+                pod["status"]["phase"].as_str(),
+                Some("Succeeded" | "Failed")
+            );
+            !daemon_set && !mirror && !finished
+        })
+        .filter_map(|pod| {
+            let metadata = &pod["metadata"];
+            Some((
+                metadata["namespace"].as_str()?.to_string(),
+                metadata["name"].as_str()?.to_string(),
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct FakeCluster {
+        node: Value,
+        pods: Vec<Value>,
+        refused: bool,
+    }
+
+    type State = web::Data<Mutex<FakeCluster>>;
+
+    fn pod(name: &str, owner: &str) -> Value {
+        json!({
+            "metadata": {
+                "name": name,
+                "namespace": "default",
+                "ownerReferences": [{ "kind": owner }],
+            },
+            "status": { "phase": "Running" },
+        })
+    }
+
+    fn merge(target: &mut Value, patch: &Value) {
+        let Value::Object(patch) = patch else {
+            *target = patch.clone();
+            return;
+        };
+        if !target.is_object() {
+            *target = json!({});
+        }
+        let target = target.as_object_mut().unwrap();
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+    }
+
+    async fn get_node(state: State) -> HttpResponse {
+        HttpResponse::Ok().json(&state.lock().unwrap().node)
+    }
+
+    async fn patch_node(state: State, patch: web::Json<Value>) -> HttpResponse {
+        let mut cluster = state.lock().unwrap();
+        merge(&mut cluster.node, &patch);
+        HttpResponse::Ok().json(&cluster.node)
+    }
+
+    async fn list_pods(state: State) -> HttpResponse {
+        HttpResponse::Ok().json(json!({ "items": state.lock().unwrap().pods }))
+    }
+
+    async fn evict(state: State, path: web::Path<(String, String)>) -> HttpResponse {
+        let mut cluster = state.lock().unwrap();
+        // the disruption budget lets the guarded pod go on the second attempt
+        if path.1 == "guarded" && !cluster.refused {
+            cluster.refused = true;
+            return HttpResponse::TooManyRequests().json(json!({}));
+        }
+        cluster
+            .pods
+            .retain(|p| p["metadata"]["name"] != path.1.as_str());
+        HttpResponse::Created().json(json!({}))
+    }
+
+    #[test]
+    fn pods_to_evict() {
+        let mut mirror = pod("kube-apiserver", "Node");
+        mirror["metadata"]["annotations"] = json!({ "kubernetes.io/config.mirror": "abc" });
+        let mut finished = pod("job", "Job");
+        finished["status"]["phase"] = json!("Succeeded");
+        let list = json!({
+            "items": [pod("web", "ReplicaSet"), pod("flannel", "DaemonSet"), mirror, finished],
+        });
+        assert_eq!(
+            evictable_pods(&list),
+            vec![("default".to_string(), "web".to_string())]
+        );
+    }
+
+    #[actix_web::test]
+    async fn drain_and_uncordon() {
+        let state = web::Data::new(Mutex::new(FakeCluster {
+            node: json!({ "metadata": { "name": "worker-1" }, "spec": {}, "status": {} }),
+            pods: vec![
+                pod("web", "ReplicaSet"),
+                pod("guarded", "ReplicaSet"),
+                pod("flannel", "DaemonSet"),
+            ],
+            refused: false,
+        }));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}/", listener.local_addr().unwrap());
+        let app_state = state.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(app_state.clone())
+                .route("/api/v1/nodes/worker-1", web::get().to(get_node))
+                .route("/api/v1/nodes/worker-1", web::patch().to(patch_node))
+                .route("/api/v1/pods", web::get().to(list_pods))
+                .route(
+                    "/api/v1/namespaces/{namespace}/pods/{pod}/eviction",
+                    web::post().to(evict),
+                )
+        })
+        .listen(listener)
+        .unwrap()
+        .workers(1)
+        .run();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        let mut kubernetes = Kubernetes::new(config::Kubernetes {
+            api_server: base,
+            token: "token".to_string(),
+            ca_certificate: None,
+            insecure: false,
+            nodes: vec![config::KubernetesNode {
+                node: 2,
+                name: "worker-1".to_string(),
+            }],
+            drain_timeout: Duration::from_secs(5),
+            force: false,
+            ready_timeout: Duration::from_secs(5),
+        })
+        .unwrap();
+        kubernetes.poll_interval = Duration::from_millis(10);
+        let kubernetes = Arc::new(kubernetes);
+
+        // slot 1 has no Kubernetes node
+        kubernetes.prepare_power_off(0b0001).await.unwrap();
+        assert_eq!(state.lock().unwrap().pods.len(), 3);
+
+        kubernetes.prepare_power_off(0b0010).await.unwrap();
+        {
+            let cluster = state.lock().unwrap();
+            assert!(unschedulable(&cluster.node));
+            assert_eq!(evictable_pods(&json!({ "items": cluster.pods })), vec![]);
+            assert_eq!(cluster.pods.len(), 1);
+        }
+
+        kubernetes.after_power_on(0b0010);
+        state.lock().unwrap().node["status"]["conditions"] =
+            json!([{ "type": "Ready", "status": "True" }]);
+        let uncordoned = async {
+            while unschedulable(&state.lock().unwrap().node) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(Duration::from_secs(5), uncordoned).await.unwrap();
+        assert!(
+            state.lock().unwrap().node["metadata"]["annotations"][CORDONED_ANNOTATION].is_null()
+        );
+
+        // a node that was cordoned by an administrator stays cordoned
+        state.lock().unwrap().node["spec"]["unschedulable"] = json!(true);
+        kubernetes.prepare_power_off(0b0010).await.unwrap();
+        kubernetes.uncordon("worker-1").await.unwrap();
+        assert!(unschedulable(&state.lock().unwrap().node));
+
+        handle.stop(true).await;
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Small namespaced key/value store for use by external tooling, e.g. to keep
+//! a cluster name or inventory tags on the board. The store lives in the
+//! persistency of bmcd and is therefore subject to quotas.
+use super::bmc_application::BmcApplication;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use thiserror::Error;
+
+/// Persistency key of the key/value store.
+pub const KV_STORE_KEY: &str = "kv_store";
+/// Maximum amount of bytes of keys and values in a namespace.
+pub const NAMESPACE_QUOTA: usize = 8 * 1024;
+pub const MAX_NAMESPACES: usize = 16;
+const MAX_NAME_LENGTH: usize = 64;
+
+pub type Namespace = BTreeMap<String, String>;
+pub type Namespaces = HashMap<String, Namespace>;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum KvError {
+    #[error("`{0}` is not a valid name. Use up to 64 of the characters [a-zA-Z0-9_.-]")]
+    InvalidName(String),
+    #[error("namespace `{0}` exceeds its quota of {NAMESPACE_QUOTA} bytes")]
+    QuotaExceeded(String),
+    #[error("maximum of {MAX_NAMESPACES} namespaces reached")]
+    TooManyNamespaces,
+    #[error("`{0}` does not exist")]
+    NotFound(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct NamespaceUsage {
+    pub name: String,
+    pub keys: usize,
+    pub bytes_used: usize,
+    pub bytes_quota: usize,
+}
+
+pub async fn list_namespaces(bmc: &BmcApplication) -> Vec<NamespaceUsage> {
+    let namespaces = bmc.app_db.get::<Namespaces>(KV_STORE_KEY).await;
+    let mut usage: Vec<NamespaceUsage> = namespaces
+        .iter()
+        .map(|(name, ns)| NamespaceUsage {
+            name: name.clone(),
+            keys: ns.len(),
+            bytes_used: namespace_size(ns),
+            bytes_quota: NAMESPACE_QUOTA,
+        })
+        .collect();
+    usage.sort_by(|a, b| a.name.cmp(&b.name));
+    usage
+}
+
+pub async fn get_namespace(bmc: &BmcApplication, namespace: &str) -> Result<Namespace, KvError> {
+    bmc.app_db
+        .get::<Namespaces>(KV_STORE_KEY)
+        .await
+        .remove(namespace)
+        .ok_or_else(|| KvError::NotFound(namespace.to_string()))
+}
+
+pub async fn get_value(
+    bmc: &BmcApplication,
+    namespace: &str,
+    key: &str,
+) -> Result<String, KvError> {
+    get_namespace(bmc, namespace)
+        .await?
+        .remove(key)
+        .ok_or_else(|| KvError::NotFound(format!("{namespace}/{key}")))
+}
+
+pub async fn set_value(
+    bmc: &BmcApplication,
+    namespace: &str,
+    key: &str,
+    value: String,
+) -> Result<(), KvError> {
+    bmc.app_db
+        .update(KV_STORE_KEY, |namespaces: &mut Namespaces| {
+            insert(namespaces, namespace, key, value)
+        })
+        .await
+}
+
+/// Removes `key` from `namespace`, or the whole namespace when `key` is
+/// `None`.
+pub async fn delete(
+    bmc: &BmcApplication,
+    namespace: &str,
+    key: Option<&str>,
+) -> Result<(), KvError> {
+    let not_found = || KvError::NotFound(namespace.to_string());
+
+    bmc.app_db
+        .update(KV_STORE_KEY, |namespaces: &mut Namespaces| {
+            match key {
+                Some(key) => {
+                    let ns = namespaces.get_mut(namespace).ok_or_else(not_found)?;
+                    ns.remove(key)
+                        .ok_or_else(|| KvError::NotFound(format!("{namespace}/{key}")))?;
+                    if ns.is_empty() {
+                        namespaces.remove(namespace);
+                    }
+                }
+                None => {
+                    namespaces.remove(namespace).ok_or_else(not_found)?;
+                }
+            }
+            Ok(())
+        })
+        .await
+}
+
+fn insert(
+    namespaces: &mut Namespaces,
+    namespace: &str,
+    key: &str,
+    value: String,
+) -> Result<(), KvError> {
+    validate_name(namespace)?;
+    validate_name(key)?;
+
+    if !namespaces.contains_key(namespace) && namespaces.len() >= MAX_NAMESPACES {
+        return Err(KvError::TooManyNamespaces);
+    }
+
+    let mut ns = namespaces.get(namespace).cloned().unwrap_or_default();
+    ns.insert(key.to_string(), value);
+    if namespace_size(&ns) > NAMESPACE_QUOTA {
+        return Err(KvError::QuotaExceeded(namespace.to_string()));
+    }
+
+    namespaces.insert(namespace.to_string(), ns);
+    Ok(())
+}
+
+fn validate_name(name: &str) -> Result<(), KvError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(KvError::InvalidName(name.to_string()))
+    }
+}
+
+fn namespace_size(namespace: &Namespace) -> usize {
+    namespace.iter().map(|(k, v)| k.len() + v.len()).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotas() {
+        let mut namespaces = Namespaces::new();
+        insert(&mut namespaces, "cluster", "name", "\"lab\"".into()).unwrap();
+        assert_eq!(namespaces["cluster"]["name"], "\"lab\"");
+
+        let too_big = "x".repeat(NAMESPACE_QUOTA);
+        assert_eq!(
+            insert(&mut namespaces, "cluster", "big", too_big),
+            Err(KvError::QuotaExceeded("cluster".into()))
+        );
+        assert!(!namespaces["cluster"].contains_key("big"));
+
+        for i in 1..MAX_NAMESPACES {
+            insert(&mut namespaces, &format!("ns{i}"), "k", "1".into()).unwrap();
+        }
+        assert_eq!(
+            insert(&mut namespaces, "one-too-many", "k", "1".into()),
+            Err(KvError::TooManyNamespaces)
+        );
+        // existing namespaces can still be written to
+        insert(&mut namespaces, "ns1", "k2", "2".into()).unwrap();
+    }
+
+    #[test]
+    fn names() {
+        let mut namespaces = Namespaces::new();
+        assert!(insert(&mut namespaces, "a/b", "k", "1".into()).is_err());
+        assert!(insert(&mut namespaces, "ns", "", "1".into()).is_err());
+        assert!(insert(&mut namespaces, "ns", "inventory.tag-1_a", "1".into()).is_ok());
+    }
+}
@@ -0,0 +1,575 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! KVM over IP for nodes whose HDMI output is wired to a USB capture dongle on
+//! the BMC. The dongles deliver MJPEG, which a capture command copies to its
+//! standard output; bmcd splits that into frames and shares them among the
+//! viewers of a node. Keystrokes reach the node through a HID keyboard
+//! function of the USB gadget, while the USB of the node is routed to the BMC.
+use super::bmc_application::{BmcApplication, UsbConfig};
+use super::usb_gadget::add_gadget_function;
+use crate::config;
+use crate::error::BmcError;
+use crate::hal::{NodeId, UsbRoute};
+use anyhow::{bail, Context};
+use bytes::{Buf, Bytes, BytesMut};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::sync::watch;
+use tokio::time::timeout;
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+const VIDEO_CLASS: &str = "/sys/class/video4linux";
+/// USB vendor and product IDs of the capture dongles that are known to work.
+const SUPPORTED: &[(&str, &str, &str)] = &[
+    ("534d", "2109", "MacroSilicon MS2109"),
+    ("345f", "2130", "MacroSilicon MS2130"),
+];
+/// Frames that do not end within this size are dropped.
+const MAX_FRAME: usize = 4 * 1024 * 1024;
+const FIRST_FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+const HID_FUNCTION: &str = "hid.kvm";
+const HID_DEVICE: &str = "/dev/hidg0";
+/// How long a key report may wait for the node to poll the keyboard.
+const REPORT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Boot keyboard: a modifier byte, a reserved byte and six key codes.
+const REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, 0x09, 0x06, 0xa1, 0x01, 0x05, 0x07, 0x19, 0xe0, 0x29, 0xe7, 0x15, 0x00, 0x25, 0x01,
+    0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x95, 0x01, 0x75, 0x08, 0x81, 0x03, 0x95, 0x05, 0x75, 0x01,
+    0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x02, 0x95, 0x01, 0x75, 0x03, 0x91, 0x03, 0x95, 0x06,
+    0x75, 0x08, 0x15, 0x00, 0x25, 0x65, 0x05, 0x07, 0x19, 0x00, 0x29, 0x65, 0x81, 0x00, 0xc0,
+];
+
+const CTRL: u8 = 0x01;
+const SHIFT: u8 = 0x02;
+const ALT: u8 = 0x04;
+const META: u8 = 0x08;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CaptureDevice {
+    pub usb_port: String,
+    pub device: PathBuf,
+    pub model: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KvmStatus {
+    pub usb_port: String,
+    /// `None` when no supported dongle is plugged into the port
+    pub capture: Option<CaptureDevice>,
+    /// whether a viewer is watching
+    pub streaming: bool,
+    /// whether the USB of the node is routed to the BMC, so it can type
+    pub keyboard: bool,
+}
+
+/// Supported capture dongles that are plugged into the BMC.
+pub fn capture_devices() -> Vec<CaptureDevice> {
+    capture_devices_in(Path::new(VIDEO_CLASS))
+}
+
+fn capture_devices_in(class: &Path) -> Vec<CaptureDevice> {
+    let Ok(entries) = std::fs::read_dir(class) else {
+        return Vec::new();
+    };
+    let mut devices: Vec<_> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let video = entry.path();
+            // dongles also have a metadata node, which has index 1
+            if std::fs::read_to_string(video.join("index")).ok()?.trim() != "0" {
+                return None;
+            }
+            let interface = std::fs::canonicalize(video.join("device")).ok()?;
+            let usb = interface.parent()?;
+            let id = |name| std::fs::read_to_string(usb.join(name)).ok();
+            let (vendor, product) = (id("idVendor")?, id("idProduct")?);
+            let (_, _, model) = SUPPORTED
+                .iter()
+                .find(|(v, p, _)| *v == vendor.trim() && *p == product.trim())?;
+            Some(CaptureDevice {
+                usb_port: usb.file_name()?.to_str()?.to_string(),
+                device: Path::new("/dev").join(entry.file_name()),
+                model,
+            })
+        })
+        .collect();
+    devices.sort_by(|a, b| a.usb_port.cmp(&b.usb_port));
+    devices
+}
+
+pub struct Kvm {
+    config: Option<config::Kvm>,
+    bmc: Arc<BmcApplication>,
+    captures: Mutex<HashMap<NodeId, Weak<Capture>>>,
+    /// whether the HID function was added to the gadget
+    keyboard: tokio::sync::Mutex<bool>,
+}
+
+impl Kvm {
+    pub fn new(config: Option<config::Kvm>, bmc: Arc<BmcApplication>) -> Self {
+        Self {
+            config,
+            bmc,
+            captures: Mutex::default(),
+            keyboard: tokio::sync::Mutex::default(),
+        }
+    }
+
+    fn usb_port(&self, node: NodeId) -> Result<&str, BmcError> {
+        self.config
+            .iter()
+            .flat_map(|config| &config.nodes)
+            .find(|n| n.node == node as u8 + 1)
+            .map(|n| n.usb_port.as_str())
+            .ok_or_else(|| BmcError::NotSupported(format!("{} has no KVM add-on", node).into()))
+    }
+
+    fn capture_device(&self, node: NodeId) -> anyhow::Result<CaptureDevice> {
+        let port = self.usb_port(node)?;
+        let device = capture_devices()
+            .into_iter()
+            .find(|device| device.usb_port == port)
+            .ok_or_else(|| {
+                BmcError::NotFound(format!("capture dongle on USB port {}", port).into())
+            })?;
+        Ok(device)
+    }
+
+    pub async fn status(&self, node: NodeId) -> anyhow::Result<KvmStatus> {
+        let usb_port = self.usb_port(node)?.to_string();
+        let capture = capture_devices()
+            .into_iter()
+            .find(|device| device.usb_port == usb_port);
+        let streaming = self
+            .captures
+            .lock()
+            .expect("captures lock poisoned")
+            .get(&node)
+            .is_some_and(|capture| capture.strong_count() > 0);
+        Ok(KvmStatus {
+            usb_port,
+            capture,
+            streaming,
+            keyboard: self.keyboard_routed(node).await,
+        })
+    }
+
+    /// Frames of the node. The capture runs as long as a viewer holds on to
+    /// its [`Frames`].
+    pub fn frames(&self, node: NodeId) -> anyhow::Result<Frames> {
+        let mut captures = self.captures.lock().expect("captures lock poisoned");
+        let running = captures
+            .get(&node)
+            .and_then(Weak::upgrade)
+            .filter(|capture| capture.frames.has_changed().is_ok());
+        let capture = match running {
+            Some(capture) => capture,
+            None => {
+                let device = self.capture_device(node)?;
+                let config = self.config.as_ref().context("KVM is not configured")?;
+                let capture = Arc::new(Capture::start(&config.capture_command, &device.device)?);
+                tracing::info!("capturing {} from {}", node, device.device.display());
+                captures.insert(node, Arc::downgrade(&capture));
+                capture
+            }
+        };
+        Ok(Frames {
+            frames: capture.frames.clone(),
+            _capture: capture,
+        })
+    }
+
+    /// The next frame of the node as JPEG.
+    pub async fn snapshot(&self, node: NodeId) -> anyhow::Result<Bytes> {
+        let mut frames = self.frames(node)?;
+        match timeout(FIRST_FRAME_TIMEOUT, frames.next()).await {
+            Ok(Some(frame)) => Ok(frame),
+            Ok(None) => bail!("capture of {} stopped", node),
+            Err(_) => bail!("no frame of {} within {:?}", node, FIRST_FRAME_TIMEOUT),
+        }
+    }
+
+    /// Types `text` and then presses the `keys`, e.g. `ctrl+alt+delete`, on
+    /// the node.
+    pub async fn type_keys(&self, node: NodeId, text: &str, keys: &[String]) -> anyhow::Result<()> {
+        self.usb_port(node)?;
+        let mut strokes = Vec::new();
+        for c in text.chars() {
+            let stroke = char_key(c).ok_or_else(|| {
+                BmcError::invalid_parameter("text", format!("cannot type `{}`", c.escape_debug()))
+            })?;
+            strokes.push(stroke);
+        }
+        for key in keys {
+            let stroke = parse_key(key).ok_or_else(|| {
+                BmcError::invalid_parameter("keys", format!("unknown key `{}`", key))
+            })?;
+            strokes.push(stroke);
+        }
+
+        if !self.keyboard_routed(node).await {
+            let message = format!(
+                "route the USB of {} to the BMC, with the node as host, to type on it",
+                node
+            );
+            bail!(BmcError::NotSupported(message.into()));
+        }
+        let mut added = self.keyboard.lock().await;
+        if !*added {
+            add_gadget_function(
+                HID_FUNCTION,
+                &[
+                    ("protocol", b"1"),
+                    ("subclass", b"1"),
+                    ("report_length", b"8"),
+                    ("report_desc", REPORT_DESCRIPTOR),
+                ],
+            )
+            .await
+            .context("HID keyboard")?;
+            *added = true;
+        }
+
+        let mut hid = OpenOptions::new()
+            .write(true)
+            .open(HID_DEVICE)
+            .await
+            .with_context(|| HID_DEVICE.to_string())?;
+        for stroke in strokes {
+            for report in [stroke.report(), [0; 8]] {
+                timeout(REPORT_TIMEOUT, hid.write_all(&report))
+                    .await
+                    .with_context(|| format!("{} does not read the keyboard", node))??;
+            }
+        }
+        Ok(())
+    }
+
+    async fn keyboard_routed(&self, node: NodeId) -> bool {
+        let (usb, _) = self.bmc.get_usb_mode().await;
+        usb == UsbConfig::Node(node, UsbRoute::Bmc)
+    }
+}
+
+/// A running capture command. Dropping it stops the command.
+struct Capture {
+    frames: watch::Receiver<Option<Bytes>>,
+    _stop: DropGuard,
+}
+
+impl Capture {
+    fn start(command: &[String], device: &Path) -> anyhow::Result<Self> {
+        let (program, args) = command.split_first().context("empty capture command")?;
+        let device = device.to_string_lossy();
+        let mut child = Command::new(program)
+            .args(args.iter().map(|arg| arg.replace("{device}", &device)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("could not start {}", program))?;
+        let stdout = child
+            .stdout
+            .take()
+            .context("no output of capture command")?;
+
+        let (sender, frames) = watch::channel(None);
+        let stop = CancellationToken::new();
+        let stopped = stop.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                result = read_frames(stdout, &sender) => {
+                    if let Err(e) = result {
+                        tracing::warn!("capture: {}", e);
+                    }
+                }
+                _ = stopped.cancelled() => {}
+            }
+            // kills the command
+            drop(child);
+        });
+        Ok(Self {
+            frames,
+            _stop: stop.drop_guard(),
+        })
+    }
+}
+
+async fn read_frames(
+    mut reader: impl AsyncRead + Unpin,
+    sender: &watch::Sender<Option<Bytes>>,
+) -> std::io::Result<()> {
+    let mut splitter = JpegSplitter::default();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        for frame in splitter.push(&buffer[..n]) {
+            sender.send_replace(Some(frame));
+        }
+    }
+}
+
+/// Frames of a capture for one viewer.
+pub struct Frames {
+    frames: watch::Receiver<Option<Bytes>>,
+    _capture: Arc<Capture>,
+}
+
+impl Frames {
+    /// Waits for a frame that this viewer did not see yet. Frames that arrive
+    /// while the viewer is busy are skipped. `None` when the capture ended.
+    pub async fn next(&mut self) -> Option<Bytes> {
+        loop {
+            self.frames.changed().await.ok()?;
+            if let Some(frame) = self.frames.borrow_and_update().clone() {
+                return Some(frame);
+            }
+        }
+    }
+}
+
+/// Cuts a stream of concatenated JPEG images at their start and end markers.
+#[derive(Default)]
+struct JpegSplitter {
+    buffer: BytesMut,
+}
+
+impl JpegSplitter {
+    fn push(&mut self, data: &[u8]) -> Vec<Bytes> {
+        self.buffer.extend_from_slice(data);
+        let mut frames = Vec::new();
+        loop {
+            let Some(start) = find(&self.buffer, &[0xff, 0xd8]) else {
+                // a marker may be cut in half
+                let keep = usize::from(self.buffer.last() == Some(&0xff));
+                self.buffer.advance(self.buffer.len() - keep);
+                break;
+            };
+            self.buffer.advance(start);
+            let Some(end) = find(&self.buffer[2..], &[0xff, 0xd9]) else {
+                if self.buffer.len() > MAX_FRAME {
+                    self.buffer.clear();
+                }
+                break;
+            };
+            frames.push(self.buffer.split_to(end + 4).freeze());
+        }
+        frames
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct KeyStroke {
+    modifiers: u8,
+    usage: u8,
+}
+
+impl KeyStroke {
+    fn report(self) -> [u8; 8] {
+        [self.modifiers, 0, self.usage, 0, 0, 0, 0, 0]
+    }
+}
+
+/// Key of a character on a US keyboard.
+fn char_key(c: char) -> Option<KeyStroke> {
+    const SHIFTED: &str = "!@#$%^&*()_+{}|:\"~<>?";
+    const UNSHIFTED: &str = "1234567890-=[]\\;'`,./";
+
+    let key = |usage| {
+        Some(KeyStroke {
+            modifiers: 0,
+            usage,
+        })
+    };
+    let shifted = |usage| {
+        Some(KeyStroke {
+            modifiers: SHIFT,
+            usage,
+        })
+    };
+    match c {
+        'a'..='z' => key(0x04 + (c as u8 - b'a')),
+        'A'..='Z' => shifted(0x04 + (c as u8 - b'A')),
+        '1'..='9' => key(0x1e + (c as u8 - b'1')),
+        '0' => key(0x27),
+        '\n' => key(0x28),
+        '\t' => key(0x2b),
+        ' ' => key(0x2c),
+        _ => {
+            if let Some(i) = SHIFTED.find(c) {
+                char_key(UNSHIFTED[i..].chars().next()?).map(|k| KeyStroke {
+                    modifiers: SHIFT,
+                    ..k
+                })
+            } else {
+                let usage = match c {
+                    '-' => 0x2d,
+                    '=' => 0x2e,
+                    '[' => 0x2f,
+                    ']' => 0x30,
+                    '\\' => 0x31,
+                    ';' => 0x33,
+                    '\'' => 0x34,
+                    '`' => 0x35,
+                    ',' => 0x36,
+                    '.' => 0x37,
+                    '/' => 0x38,
+                    _ => return None,
+                };
+                key(usage)
+            }
+        }
+    }
+}
+
+/// Parses a key with modifiers, e.g. `ctrl+alt+delete` or `shift+f10`.
+fn parse_key(key: &str) -> Option<KeyStroke> {
+    let key = key.to_ascii_lowercase();
+    let (modifiers, name) = match key.rsplit_once('+') {
+        Some((modifiers, name)) if !name.is_empty() => (modifiers, name),
+        // `+` itself, or `ctrl++`
+        _ if key.ends_with('+') => (key[..key.len() - 1].trim_end_matches('+'), "+"),
+        _ => ("", key.as_str()),
+    };
+
+    let mut bits = 0;
+    for modifier in modifiers.split('+').filter(|m| !m.is_empty()) {
+        bits |= match modifier {
+            "ctrl" | "control" => CTRL,
+            "shift" => SHIFT,
+            "alt" => ALT,
+            "meta" | "super" | "win" => META,
+            _ => return None,
+        };
+    }
+
+    let usage = match name {
+        "enter" | "return" => 0x28,
+        "esc" | "escape" => 0x29,
+        "backspace" => 0x2a,
+        "tab" => 0x2b,
+        "space" => 0x2c,
+        "insert" => 0x49,
+        "home" => 0x4a,
+        "pageup" => 0x4b,
+        "delete" | "del" => 0x4c,
+        "end" => 0x4d,
+        "pagedown" => 0x4e,
+        "right" => 0x4f,
+        "left" => 0x50,
+        "down" => 0x51,
+        "up" => 0x52,
+        _ => {
+            if let Some(n) = name.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                if !(1..=12).contains(&n) {
+                    return None;
+                }
+                0x3a + n - 1
+            } else {
+                let mut chars = name.chars();
+                let stroke = char_key(chars.next()?).filter(|_| chars.next().is_none())?;
+                return Some(KeyStroke {
+                    modifiers: bits | stroke.modifiers,
+                    ..stroke
+                });
+            }
+        }
+    };
+    Some(KeyStroke {
+        modifiers: bits,
+        usage,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn splits_jpeg_frames() {
+        let mut splitter = JpegSplitter::default();
+        let frame = [0xff, 0xd8, 1, 2, 0xff, 0x00, 0xff, 0xd9];
+        assert!(splitter.push(&[0, 0, 0xff]).is_empty());
+        let frames = splitter.push(&frame[1..5]);
+        assert!(frames.is_empty());
+        let frames = splitter.push(&[&frame[5..], &frame[..], &frame[..3]].concat());
+        assert_eq!(frames, vec![Bytes::copy_from_slice(&frame); 2]);
+        let frames = splitter.push(&frame[3..]);
+        assert_eq!(frames, vec![Bytes::copy_from_slice(&frame)]);
+    }
+
+    #[test]
+    fn keys() {
+        let key = |modifiers, usage| Some(KeyStroke { modifiers, usage });
+        assert_eq!(char_key('a'), key(0, 0x04));
+        assert_eq!(char_key('Z'), key(SHIFT, 0x1d));
+        assert_eq!(char_key('0'), key(0, 0x27));
+        assert_eq!(char_key('?'), key(SHIFT, 0x38));
+        assert_eq!(char_key('"'), key(SHIFT, 0x34));
+        assert_eq!(char_key('é'), None);
+
+        assert_eq!(parse_key("ctrl+alt+delete"), key(CTRL | ALT, 0x4c));
+        assert_eq!(parse_key("Shift+F10"), key(SHIFT, 0x43));
+        assert_eq!(parse_key("ctrl+c"), key(CTRL, 0x06));
+        assert_eq!(parse_key("ctrl++"), key(CTRL | SHIFT, 0x2e));
+        assert_eq!(parse_key("f13"), None);
+        assert_eq!(parse_key("hyper+a"), None);
+    }
+
+    #[test]
+    fn detects_supported_dongles() {
+        let root = TempDir::new("kvm").unwrap();
+        let usb = root.path().join("usb");
+        for (port, vendor, product) in [("1-1.2", "534d", "2109"), ("1-1.3", "046d", "0825")] {
+            let interface = usb.join(port).join(format!("{}:1.0", port));
+            std::fs::create_dir_all(&interface).unwrap();
+            std::fs::write(usb.join(port).join("idVendor"), format!("{}\n", vendor)).unwrap();
+            std::fs::write(usb.join(port).join("idProduct"), format!("{}\n", product)).unwrap();
+            for index in 0..2 {
+                let video = root
+                    .path()
+                    .join(format!("class/video{}{}", &port[4..], index));
+                std::fs::create_dir_all(&video).unwrap();
+                std::fs::write(video.join("index"), format!("{}\n", index)).unwrap();
+                std::os::unix::fs::symlink(&interface, video.join("device")).unwrap();
+            }
+        }
+
+        let devices = capture_devices_in(&root.path().join("class"));
+        assert_eq!(
+            devices,
+            vec![CaptureDevice {
+                usb_port: "1-1.2".to_string(),
+                device: PathBuf::from("/dev/video20"),
+                model: "MacroSilicon MS2109",
+            }]
+        );
+    }
+}
@@ -0,0 +1,331 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! "Lights out" mode, which saves power while all nodes are off. Once the
+//! nodes have been off for `idle_after` without API requests, the LEDs are
+//! switched off, the configured devices such as the USB hub may suspend, and
+//! monitors poll less often. Any API request or a node powering on restores
+//! everything right away.
+use super::bmc_application::{BmcApplication, ACTIVATED_NODES_KEY};
+use crate::config;
+use crate::utils::get_timestamp_unix;
+use serde::Serialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::time::Interval;
+
+const LEDS: &str = "/sys/class/leds";
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Serialize)]
+pub struct LightsOutStatus {
+    pub enabled: bool,
+    pub active: bool,
+    /// unix timestamp of when the mode was last entered
+    pub entered: Option<u64>,
+    /// unix timestamp of when the board last woke up
+    pub woken: Option<u64>,
+}
+
+/// What was changed when entering the mode, to undo it.
+#[derive(Debug, Default)]
+struct Saved {
+    leds: Vec<SavedLed>,
+    /// sysfs devices and their previous `power/control`
+    devices: Vec<(PathBuf, String)>,
+}
+
+#[derive(Debug)]
+struct SavedLed {
+    dir: PathBuf,
+    trigger: String,
+    brightness: String,
+}
+
+struct State {
+    last_activity: Instant,
+    saved: Option<Saved>,
+    entered: Option<u64>,
+    woken: Option<u64>,
+}
+
+pub struct LightsOut {
+    config: config::LightsOut,
+    state: Mutex<State>,
+    active: watch::Sender<bool>,
+}
+
+impl LightsOut {
+    pub fn new(config: config::LightsOut) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State {
+                last_activity: Instant::now(),
+                saved: None,
+                entered: None,
+                woken: None,
+            }),
+            active: watch::Sender::new(false),
+        }
+    }
+
+    pub fn status(&self) -> LightsOutStatus {
+        let state = self.lock_state();
+        LightsOutStatus {
+            enabled: self.config.enabled,
+            active: state.saved.is_some(),
+            entered: state.entered,
+            woken: state.woken,
+        }
+    }
+
+    /// Whether a request to `path` leaves the board asleep.
+    pub fn is_passive(&self, path: &str) -> bool {
+        self.config.passive_paths.iter().any(|p| p == path)
+    }
+
+    /// Records activity and wakes up the board if it is asleep.
+    pub fn touch(&self) {
+        let mut state = self.lock_state();
+        state.last_activity = Instant::now();
+        if let Some(saved) = state.saved.take() {
+            restore_leds(&saved.leds);
+            resume_devices(&saved.devices);
+            state.woken = get_timestamp_unix();
+            self.active.send_replace(false);
+            tracing::info!("lights out mode left");
+        }
+    }
+
+    /// Enters the mode once the nodes have been off for `idle_after`.
+    pub fn run(self: Arc<Self>, bmc: Arc<BmcApplication>) {
+        if !self.config.enabled {
+            return;
+        }
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(CHECK_INTERVAL).await;
+                let powered = bmc.app_db.get::<u8>(ACTIVATED_NODES_KEY).await;
+                if powered != 0 {
+                    self.touch();
+                    continue;
+                }
+                let idle = {
+                    let state = self.lock_state();
+                    state.saved.is_none() && state.last_activity.elapsed() >= self.config.idle_after
+                };
+                if idle {
+                    self.enter();
+                }
+            }
+        });
+    }
+
+    /// Waits for the next tick of `interval`. While the board is asleep only
+    /// every `poll_factor`th tick counts, unless it wakes up in between.
+    pub async fn tick(&self, interval: &mut Interval) {
+        let mut active = self.active.subscribe();
+        for _ in 1..self.config.poll_factor {
+            if !*active.borrow_and_update() {
+                break;
+            }
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = active.changed() => return,
+            }
+        }
+        interval.tick().await;
+    }
+
+    fn enter(&self) {
+        let mut state = self.lock_state();
+        if state.saved.is_some() {
+            return;
+        }
+        let leds = if self.config.leds {
+            dim_leds(Path::new(LEDS))
+        } else {
+            Vec::new()
+        };
+        state.saved = Some(Saved {
+            leds,
+            devices: suspend_devices(&self.config.devices),
+        });
+        state.entered = get_timestamp_unix();
+        self.active.send_replace(true);
+        tracing::info!("all nodes are off, lights out mode entered");
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("lights out state poisoned")
+    }
+}
+
+/// Switches off the LEDs below `root`, returning what they were.
+fn dim_leds(root: &Path) -> Vec<SavedLed> {
+    let Ok(entries) = std::fs::read_dir(root) else {
+        return Vec::new();
+    };
+    let mut saved = Vec::new();
+    for entry in entries.flatten() {
+        let dir = entry.path();
+        let result = (|| {
+            let trigger = active_trigger(&std::fs::read_to_string(dir.join("trigger"))?);
+            let brightness = std::fs::read_to_string(dir.join("brightness"))?;
+            std::fs::write(dir.join("trigger"), "none")?;
+            std::fs::write(dir.join("brightness"), "0")?;
+            Ok::<_, io::Error>(SavedLed {
+                dir: dir.clone(),
+                trigger,
+                brightness: brightness.trim().to_string(),
+            })
+        })();
+        match result {
+            Ok(led) => saved.push(led),
+            Err(e) => tracing::debug!("LED {}: {}", dir.display(), e),
+        }
+    }
+    saved
+}
+
+fn restore_leds(leds: &[SavedLed]) {
+    for led in leds {
+        let result = if led.trigger != "none" {
+            std::fs::write(led.dir.join("trigger"), &led.trigger)
+        } else {
+            // a LED that was switched on meanwhile, e.g. the power LED by a
+            // node powering on, keeps its new state
+            match std::fs::read_to_string(led.dir.join("brightness")) {
+                Ok(current) if current.trim() != "0" => Ok(()),
+                _ => std::fs::write(led.dir.join("brightness"), &led.brightness),
+            }
+        };
+        if let Err(e) = result {
+            tracing::warn!("restoring LED {}: {}", led.dir.display(), e);
+        }
+    }
+}
+
+/// The selected trigger in the contents of a `trigger` attribute, e.g.
+/// `none [heartbeat] timer`.
+fn active_trigger(triggers: &str) -> String {
+    triggers
+        .split_whitespace()
+        .find_map(|t| t.strip_prefix('[')?.strip_suffix(']'))
+        .unwrap_or("none")
+        .to_string()
+}
+
+/// Allows runtime suspend of `devices`, returning their previous setting.
+fn suspend_devices(devices: &[PathBuf]) -> Vec<(PathBuf, String)> {
+    devices
+        .iter()
+        .filter_map(|device| {
+            let control = device.join("power/control");
+            let result = std::fs::read_to_string(&control).and_then(|previous| {
+                std::fs::write(&control, "auto")?;
+                Ok(previous.trim().to_string())
+            });
+            match result {
+                Ok(previous) => Some((device.clone(), previous)),
+                Err(e) => {
+                    tracing::warn!("suspending {}: {}", device.display(), e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn resume_devices(devices: &[(PathBuf, String)]) {
+    for (device, previous) in devices {
+        if let Err(e) = std::fs::write(device.join("power/control"), previous) {
+            tracing::warn!("resuming {}: {}", device.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn led(root: &Path, name: &str, trigger: &str, brightness: &str) -> PathBuf {
+        let dir = root.join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("trigger"), trigger).unwrap();
+        std::fs::write(dir.join("brightness"), brightness).unwrap();
+        dir
+    }
+
+    fn read(path: PathBuf) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn dims_and_restores_leds() {
+        let dir = TempDir::new("lights_out").unwrap();
+        let heartbeat = led(dir.path(), "heartbeat", "none [heartbeat] timer\n", "1\n");
+        let status = led(dir.path(), "fp::status", "[none] timer\n", "1\n");
+        let power = led(dir.path(), "fp::power", "[none] timer\n", "0\n");
+
+        let saved = dim_leds(dir.path());
+        assert_eq!(saved.len(), 3);
+        for led in [&heartbeat, &status, &power] {
+            assert_eq!(read(led.join("trigger")), "none");
+            assert_eq!(read(led.join("brightness")), "0");
+        }
+
+        // a node powered on while the lights were out
+        std::fs::write(power.join("brightness"), "1").unwrap();
+        restore_leds(&saved);
+        assert_eq!(read(heartbeat.join("trigger")), "heartbeat");
+        assert_eq!(read(status.join("brightness")), "1");
+        assert_eq!(read(power.join("brightness")), "1");
+    }
+
+    #[test]
+    fn suspends_and_resumes_devices() {
+        let dir = TempDir::new("lights_out").unwrap();
+        let hub = dir.path().join("usb1");
+        std::fs::create_dir_all(hub.join("power")).unwrap();
+        std::fs::write(hub.join("power/control"), "on\n").unwrap();
+        let missing = dir.path().join("usb2");
+
+        let saved = suspend_devices(&[hub.clone(), missing]);
+        assert_eq!(saved, [(hub.clone(), "on".to_string())]);
+        assert_eq!(read(hub.join("power/control")), "auto");
+        resume_devices(&saved);
+        assert_eq!(read(hub.join("power/control")), "on");
+    }
+
+    #[tokio::test]
+    async fn wakes_on_activity() {
+        let lights_out = LightsOut::new(config::LightsOut {
+            enabled: true,
+            leds: false,
+            devices: Vec::new(),
+            ..Default::default()
+        });
+        lights_out.enter();
+        assert!(lights_out.status().active);
+        assert!(*lights_out.active.borrow());
+        lights_out.touch();
+        let status = lights_out.status();
+        assert!(!status.active);
+        assert!(status.woken.is_some());
+    }
+}
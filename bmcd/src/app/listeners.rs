@@ -0,0 +1,182 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Listeners of the API, see [`crate::config::Listener`]. The listeners follow
+//! the configuration: on a reload, listeners that were removed or changed are
+//! stopped and new ones are started, the others keep their connections.
+//!
+//! Sockets of a socket-activated unit are served as they were passed and are
+//! not reconfigured.
+use super::shutdown::{ServerId, Shutdown};
+use crate::config::{Config, Listener, Scheme, Tls};
+use crate::utils::scoped_address;
+use actix_web::dev::Server;
+use std::net::{IpAddr, TcpListener};
+use std::sync::Arc;
+use tokio::sync::{watch, Mutex};
+
+/// Builds the server of a listener, on `socket` when the socket is passed by
+/// socket activation.
+pub type StartListener =
+    Box<dyn Fn(&Listener, Option<TcpListener>) -> anyhow::Result<Server> + Send + Sync>;
+
+pub struct ApiListeners {
+    start: StartListener,
+    shutdown: Arc<Shutdown>,
+    running: Mutex<Vec<(Listener, ServerId)>>,
+}
+
+impl ApiListeners {
+    pub fn new(start: StartListener, shutdown: Arc<Shutdown>) -> Self {
+        Self {
+            start,
+            shutdown,
+            running: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Serves the sockets of a socket-activated unit: HTTPS first, then an
+    /// HTTP socket that redirects to the configured `port`.
+    pub fn serve_activated(
+        &self,
+        sockets: Vec<TcpListener>,
+        tls: &Tls,
+        https_port: u16,
+    ) -> anyhow::Result<()> {
+        for (socket, scheme) in sockets.into_iter().zip([Scheme::Https, Scheme::Redirect]) {
+            let address = socket.local_addr()?;
+            let listener = Listener {
+                address: scoped_address(&address),
+                port: address.port(),
+                scheme,
+                tls: Some(tls.clone()),
+                redirect_port: Some(https_port),
+            };
+            self.spawn(&listener, Some(socket))?;
+        }
+        Ok(())
+    }
+
+    /// Stops the running listeners that are not in `wanted` and starts the
+    /// missing ones. A listener that fails to start is logged and does not
+    /// keep the others from starting; fails when no listener is running.
+    pub async fn apply(&self, wanted: Vec<Listener>) -> anyhow::Result<()> {
+        let mut running = self.running.lock().await;
+        let (keep, stop): (Vec<_>, Vec<_>) = running
+            .drain(..)
+            .partition(|(listener, _)| wanted.contains(listener));
+        for (listener, id) in stop {
+            if let Some(server) = self.shutdown.remove_server(id) {
+                tracing::info!("stopping listener {}", describe(&listener));
+                server.stop(true).await;
+            }
+        }
+        *running = keep;
+
+        let mut error = None;
+        for listener in wanted {
+            if running.iter().any(|(l, _)| *l == listener) {
+                continue;
+            }
+            match self.spawn(&listener, None) {
+                Ok(id) => running.push((listener, id)),
+                Err(e) => {
+                    tracing::error!("listener {}: {:#}", describe(&listener), e);
+                    error.get_or_insert(e.context(describe(&listener)));
+                }
+            }
+        }
+        match error {
+            Some(e) if running.is_empty() => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies the listeners of every configuration that is loaded after
+    /// `config`.
+    pub fn follow(self: Arc<Self>, mut config: watch::Receiver<Arc<Config>>) {
+        tokio::spawn(async move {
+            while config.changed().await.is_ok() {
+                if self.shutdown.is_draining() {
+                    break;
+                }
+                let wanted = config.borrow_and_update().listeners();
+                match wanted {
+                    Ok(wanted) => {
+                        let _ = self.apply(wanted).await;
+                    }
+                    Err(e) => tracing::error!("listeners not changed: {:#}", e),
+                }
+            }
+        });
+    }
+
+    fn spawn(&self, listener: &Listener, socket: Option<TcpListener>) -> anyhow::Result<ServerId> {
+        let server = (self.start)(listener, socket)?;
+        let id = self.shutdown.add_server(server.handle());
+        tracing::info!("listening on {}", describe(listener));
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                tracing::error!("server stopped: {}", e);
+            }
+        });
+        Ok(id)
+    }
+}
+
+fn describe(listener: &Listener) -> String {
+    let scheme = match listener.scheme {
+        Scheme::Https => "https",
+        Scheme::Http => "http",
+        Scheme::Redirect => "http (redirect)",
+    };
+    match listener.address.ip {
+        IpAddr::V4(_) => format!("{}:{} {}", listener.address, listener.port, scheme),
+        IpAddr::V6(_) => format!("[{}]:{} {}", listener.address, listener.port, scheme),
+    }
+}
+
+/// Location of the HTTPS redirect for a request to `host`, the value of the
+/// `Host` header, which may carry a port and brackets around an IPv6 address.
+pub fn redirect_location(host: &str, https_port: u16, path: &str) -> String {
+    let host = match host.find(']') {
+        Some(end) if host.starts_with('[') => &host[..=end],
+        _ => host.split(':').next().unwrap_or(host),
+    };
+    format!("https://{}:{}{}", host, https_port, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirect_locations() {
+        assert_eq!(
+            redirect_location("turingpi.local", 443, "/api/bmc"),
+            "https://turingpi.local:443/api/bmc"
+        );
+        assert_eq!(
+            redirect_location("10.0.0.2:80", 8443, "/"),
+            "https://10.0.0.2:8443/"
+        );
+        assert_eq!(
+            redirect_location("[fd00::2]:8080", 443, "/index.html"),
+            "https://[fd00::2]:443/index.html"
+        );
+        assert_eq!(
+            redirect_location("[fe80::1%25eth0]", 443, "/"),
+            "https://[fe80::1%25eth0]:443/"
+        );
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Runtime control over the log filter and the JSON output format.
+use super::request_trace::trace_id_of;
+use anyhow::{ensure, Context};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+pub type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+#[derive(Debug, Serialize)]
+pub struct LogLevels {
+    /// directive from the configuration file
+    pub base: String,
+    /// levels set at runtime, per target
+    pub overrides: BTreeMap<String, String>,
+    /// directive that is currently in effect
+    pub directive: String,
+}
+
+/// Adjusts the level of individual log targets without restarting the
+/// daemon. Overrides are appended to the configured directive and are lost
+/// on restart.
+pub struct LogControl {
+    handle: FilterHandle,
+    base: String,
+    overrides: Mutex<BTreeMap<String, LevelFilter>>,
+}
+
+impl LogControl {
+    pub fn new(handle: FilterHandle, base: String) -> Self {
+        Self {
+            handle,
+            base,
+            overrides: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn levels(&self) -> LogLevels {
+        let overrides = self.overrides.lock().expect("log overrides poisoned");
+        LogLevels {
+            base: self.base.clone(),
+            overrides: overrides
+                .iter()
+                .map(|(target, level)| (target.clone(), level.to_string()))
+                .collect(),
+            directive: directive(&self.base, &overrides),
+        }
+    }
+
+    /// Sets the level of `target`, a module path such as `bmcd::usb_boot`.
+    pub fn set_level(&self, target: &str, level: &str) -> anyhow::Result<()> {
+        ensure!(valid_target(target), "invalid log target `{}`", target);
+        let level: LevelFilter = level
+            .parse()
+            .with_context(|| format!("invalid log level `{}`", level))?;
+
+        let mut overrides = self.overrides.lock().expect("log overrides poisoned");
+        let mut updated = overrides.clone();
+        updated.insert(target.to_string(), level);
+        self.apply(&updated)?;
+        *overrides = updated;
+        tracing::info!("log level of {} set to {}", target, level);
+        Ok(())
+    }
+
+    /// Removes the override of `target`, or all overrides when `None`.
+    pub fn reset(&self, target: Option<&str>) -> anyhow::Result<()> {
+        let mut overrides = self.overrides.lock().expect("log overrides poisoned");
+        let mut updated = overrides.clone();
+        match target {
+            Some(target) => {
+                ensure!(
+                    updated.remove(target).is_some(),
+                    "no log level set for `{}`",
+                    target
+                );
+            }
+            None => updated.clear(),
+        }
+        self.apply(&updated)?;
+        *overrides = updated;
+        Ok(())
+    }
+
+    fn apply(&self, overrides: &BTreeMap<String, LevelFilter>) -> anyhow::Result<()> {
+        let filter = EnvFilter::builder().parse(directive(&self.base, overrides))?;
+        self.handle.reload(filter)?;
+        Ok(())
+    }
+}
+
+/// Later directives for the same target take precedence over the base.
+fn directive(base: &str, overrides: &BTreeMap<String, LevelFilter>) -> String {
+    let mut directive = base.to_string();
+    for (target, level) in overrides {
+        if !directive.is_empty() {
+            directive.push(',');
+        }
+        directive.push_str(&format!("{}={}", target, level));
+    }
+    directive
+}
+
+fn valid_target(target: &str) -> bool {
+    !target.is_empty()
+        && target
+            .split("::")
+            .all(|s| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+}
+
+/// Formats events as single-line JSON objects.
+pub struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let mut fields = JsonFields::default();
+        event.record(&mut fields);
+
+        let mut object = Map::new();
+        object.insert("timestamp".into(), chrono::Utc::now().to_rfc3339().into());
+        object.insert("level".into(), metadata.level().to_string().into());
+        object.insert("target".into(), metadata.target().into());
+        if let Some(message) = fields.0.remove("message") {
+            object.insert("message".into(), message);
+        }
+        if !fields.0.is_empty() {
+            object.insert("fields".into(), Value::Object(fields.0));
+        }
+        if let Some(trace_id) = ctx.parent_span().and_then(|s| trace_id_of(&s)) {
+            object.insert("trace_id".into(), trace_id.into());
+        }
+        if let Some(scope) = ctx.event_scope() {
+            let spans: Vec<Value> = scope.from_root().map(|span| span.name().into()).collect();
+            object.insert("spans".into(), spans.into());
+        }
+
+        writeln!(writer, "{}", Value::Object(object))
+    }
+}
+
+#[derive(Default)]
+struct JsonFields(Map<String, Value>);
+
+impl Visit for JsonFields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{:?}", value).into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tracing_subscriber::fmt::MakeWriter;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Buffer {
+        type Writer = Buffer;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn directive_appends_overrides() {
+        let mut overrides = BTreeMap::new();
+        assert_eq!(directive("info", &overrides), "info");
+        overrides.insert("bmcd::usb_boot".to_string(), LevelFilter::TRACE);
+        assert_eq!(
+            directive("info,actix_server=off", &overrides),
+            "info,actix_server=off,bmcd::usb_boot=trace"
+        );
+        assert_eq!(directive("", &overrides), "bmcd::usb_boot=trace");
+    }
+
+    #[test]
+    fn targets_are_module_paths() {
+        assert!(valid_target("bmcd::usb_boot"));
+        assert!(valid_target("actix_server"));
+        assert!(!valid_target(""));
+        assert!(!valid_target("bmcd::"));
+        assert!(!valid_target("info,bmcd=trace"));
+    }
+
+    #[test]
+    fn json_lines() {
+        let buffer = Buffer::default();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .event_format(JsonFormat)
+                .with_writer(buffer.clone()),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("flash").entered();
+            tracing::warn!(node = 2, "write failed");
+        });
+
+        let output = buffer.0.lock().unwrap().clone();
+        let line: Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["message"], "write failed");
+        assert_eq!(line["fields"]["node"], 2);
+        assert_eq!(line["spans"][0], "flash");
+    }
+}
@@ -0,0 +1,418 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Advertises the API of bmcd over multicast DNS as `_bmcd._tcp` service and
+//! keeps track of the other bmcd instances that are advertised on the LAN.
+//!
+//! mDNS runs over IPv4 and, on every interface that has IPv6 at start-up,
+//! over IPv6. Link-local IPv6 addresses are only announced on their own
+//! interface, and link-local addresses of peers are reported with the
+//! interface they were seen on as zone, e.g. `fe80::1%br0`.
+mod dns_message;
+
+use crate::utils::{is_link_local, scoped, ScopedIp};
+use dns_message::{Message, Question, Record, RecordData, TYPE_ANY, TYPE_PTR};
+use serde::Serialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_ADDR_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+const MDNS_PORT: u16 = 5353;
+pub const SERVICE_TYPE: &str = "_bmcd._tcp.local";
+const RECORD_TTL: u32 = 120;
+/// Interval at which the LAN is queried for other instances.
+const BROWSE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Another bmcd instance found on the network.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiscoveredBmc {
+    pub instance: String,
+    pub host: String,
+    pub port: u16,
+    pub addresses: Vec<ScopedIp>,
+    pub txt: BTreeMap<String, String>,
+    #[serde(skip)]
+    expires: Instant,
+}
+
+pub struct Mdns {
+    port: u16,
+    txt: Vec<String>,
+    peers: Mutex<HashMap<String, DiscoveredBmc>>,
+}
+
+impl Mdns {
+    /// `port` is the port of the API, `serial` the factory serial of the
+    /// board. Both are advertised.
+    pub fn new(port: u16, serial: Option<String>) -> Self {
+        let mut txt = vec![
+            format!("version={}", env!("CARGO_PKG_VERSION")),
+            "path=/api/bmc".to_string(),
+        ];
+        if let Some(serial) = serial {
+            txt.push(format!("serial={}", serial));
+        }
+
+        Self {
+            port,
+            txt,
+            peers: Mutex::default(),
+        }
+    }
+
+    /// Adds `key=value` to the TXT record of this instance.
+    pub fn advertise(&mut self, key: &str, value: &str) {
+        self.txt.push(format!("{}={}", key, value));
+    }
+
+    /// Instances that were seen on the network and did not expire yet.
+    pub fn peers(&self) -> Vec<DiscoveredBmc> {
+        let now = Instant::now();
+        let mut peers = self.peers.lock().expect("peers lock poisoned");
+        peers.retain(|_, p| p.expires > now);
+        let mut peers: Vec<DiscoveredBmc> = peers.values().cloned().collect();
+        peers.sort_by(|a, b| a.instance.cmp(&b.instance));
+        peers
+    }
+
+    /// Joins the mDNS multicast groups, announces this instance and keeps
+    /// answering queries and browsing for peers in the background.
+    pub fn run(self: Arc<Self>) -> std::io::Result<()> {
+        let socket = bind_multicast()?;
+        let group = SocketAddr::V4(SocketAddrV4::new(MDNS_ADDR, MDNS_PORT));
+        self.clone()
+            .serve(socket, vec![Group { group, scope: None }]);
+        match bind_multicast_v6() {
+            Ok((socket, groups)) => self.serve(socket, groups),
+            Err(e) => tracing::warn!("mDNS over IPv6 disabled: {}", e),
+        }
+        Ok(())
+    }
+
+    fn serve(self: Arc<Self>, socket: UdpSocket, groups: Vec<Group>) {
+        tokio::spawn(async move {
+            let mut browse = tokio::time::interval(BROWSE_INTERVAL);
+            let mut buf = vec![0u8; 9000];
+
+            for group in &groups {
+                let announcement = self.announcement(group.scope).encode();
+                if let Err(e) = socket.send_to(&announcement, group.group).await {
+                    tracing::warn!("mDNS announcement to {} failed: {}", group.group, e);
+                }
+            }
+
+            loop {
+                tokio::select! {
+                    _ = browse.tick() => {
+                        let query = Message {
+                            questions: vec![Question {
+                                name: SERVICE_TYPE.to_string(),
+                                qtype: TYPE_PTR,
+                            }],
+                            ..Default::default()
+                        };
+                        for group in &groups {
+                            if let Err(e) = socket.send_to(&query.encode(), group.group).await {
+                                tracing::debug!("mDNS query failed: {}", e);
+                            }
+                        }
+                    }
+                    received = socket.recv_from(&mut buf) => {
+                        let Ok((len, peer)) = received else {
+                            continue;
+                        };
+                        let Some(message) = Message::decode(&buf[..len]) else {
+                            continue;
+                        };
+                        let scope = match peer {
+                            SocketAddr::V4(_) => None,
+                            SocketAddr::V6(peer) => Some(peer.scope_id()),
+                        };
+
+                        if message.response {
+                            self.process_response(&message, scope.unwrap_or(0));
+                        } else if asks_for_service(&message) {
+                            let Some(group) = groups
+                                .iter()
+                                .find(|g| g.scope.is_none() || g.scope == scope)
+                            else {
+                                continue;
+                            };
+                            let answer = self.announcement(group.scope).encode();
+                            if let Err(e) = socket.send_to(&answer, group.group).await {
+                                tracing::debug!("mDNS response failed: {}", e);
+                            }
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Records of this instance, for the interface with the index `scope` or
+    /// for IPv4, which only carries global IPv6 addresses.
+    fn announcement(&self, scope: Option<u32>) -> Message {
+        let host = hostname();
+        let instance = format!("{}.{}", host, SERVICE_TYPE);
+        let target = format!("{}.local", host);
+
+        let mut records = vec![
+            Record {
+                name: SERVICE_TYPE.to_string(),
+                ttl: RECORD_TTL,
+                data: RecordData::Ptr(instance.clone()),
+            },
+            Record {
+                name: instance.clone(),
+                ttl: RECORD_TTL,
+                data: RecordData::Srv {
+                    port: self.port,
+                    target: target.clone(),
+                },
+            },
+            Record {
+                name: instance,
+                ttl: RECORD_TTL,
+                data: RecordData::Txt(self.txt.clone()),
+            },
+        ];
+
+        for (address, index) in local_addresses() {
+            let data = match address {
+                IpAddr::V4(address) => RecordData::A(address),
+                IpAddr::V6(address) if is_link_local(&address) && index != scope => continue,
+                IpAddr::V6(address) => RecordData::Aaaa(address),
+            };
+            records.push(Record {
+                name: target.clone(),
+                ttl: RECORD_TTL,
+                data,
+            });
+        }
+
+        Message {
+            response: true,
+            questions: Vec::new(),
+            records,
+        }
+    }
+
+    /// `scope` is the index of the interface on which `message` arrived, 0
+    /// for IPv4.
+    fn process_response(&self, message: &Message, scope: u32) {
+        let own_instance = format!("{}.{}", hostname(), SERVICE_TYPE);
+        let mut addresses: HashMap<&str, Vec<ScopedIp>> = HashMap::new();
+        for record in &message.records {
+            let address = match record.data {
+                RecordData::A(ip) => IpAddr::V4(ip).into(),
+                RecordData::Aaaa(ip) => scoped(ip, scope),
+                _ => continue,
+            };
+            addresses.entry(&record.name).or_default().push(address);
+        }
+
+        let mut peers = self.peers.lock().expect("peers lock poisoned");
+        for record in &message.records {
+            let RecordData::Srv { port, target } = &record.data else {
+                continue;
+            };
+            if !record.name.ends_with(SERVICE_TYPE) || record.name == own_instance {
+                continue;
+            }
+
+            // a TTL of zero is a goodbye message
+            if record.ttl == 0 {
+                peers.remove(&record.name);
+                continue;
+            }
+
+            let txt = message
+                .records
+                .iter()
+                .filter(|r| r.name == record.name)
+                .filter_map(|r| match &r.data {
+                    RecordData::Txt(entries) => Some(entries),
+                    _ => None,
+                })
+                .flatten()
+                .filter_map(|entry| entry.split_once('='))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+
+            let instance = record
+                .name
+                .strip_suffix(SERVICE_TYPE)
+                .unwrap_or(&record.name)
+                .trim_end_matches('.')
+                .to_string();
+
+            peers.insert(
+                record.name.clone(),
+                DiscoveredBmc {
+                    instance,
+                    host: target.clone(),
+                    port: *port,
+                    addresses: addresses.get(target.as_str()).cloned().unwrap_or_default(),
+                    txt,
+                    expires: Instant::now() + Duration::from_secs(record.ttl.into()),
+                },
+            );
+        }
+    }
+}
+
+fn asks_for_service(message: &Message) -> bool {
+    message
+        .questions
+        .iter()
+        .any(|q| q.name == SERVICE_TYPE && (q.qtype == TYPE_PTR || q.qtype == TYPE_ANY))
+}
+
+fn bind_multicast() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // other mDNS responders, such as avahi, may be bound to the same port.
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, MDNS_PORT).into())?;
+    socket.join_multicast_v4(&MDNS_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_ttl_v4(255)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// A multicast group that messages are sent to.
+struct Group {
+    group: SocketAddr,
+    /// index of the interface of a link-local group
+    scope: Option<u32>,
+}
+
+/// Joins the IPv6 group on every interface that has an IPv6 address.
+fn bind_multicast_v6() -> std::io::Result<(UdpSocket, Vec<Group>)> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), MDNS_PORT).into())?;
+    socket.set_multicast_hops_v6(255)?;
+
+    let mut indices: Vec<u32> = local_addresses()
+        .into_iter()
+        .filter(|(address, _)| address.is_ipv6())
+        .filter_map(|(_, index)| index)
+        .collect();
+    indices.sort_unstable();
+    indices.dedup();
+    let mut groups = Vec::new();
+    for index in indices {
+        match socket.join_multicast_v6(&MDNS_ADDR_V6, index) {
+            Ok(()) => groups.push(Group {
+                group: SocketAddr::V6(SocketAddrV6::new(MDNS_ADDR_V6, MDNS_PORT, 0, index)),
+                scope: Some(index),
+            }),
+            Err(e) => tracing::debug!(
+                "mDNS: joining {} on interface {}: {}",
+                MDNS_ADDR_V6,
+                index,
+                e
+            ),
+        }
+    }
+    if groups.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no interface with an IPv6 address",
+        ));
+    }
+    Ok((UdpSocket::from_std(socket.into())?, groups))
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .and_then(|h| h.trim().split('.').next().map(str::to_string))
+        .filter(|h| !h.is_empty())
+        .unwrap_or("turingpi".to_string())
+}
+
+/// Non-loopback addresses with the index of their interface.
+fn local_addresses() -> Vec<(IpAddr, Option<u32>)> {
+    if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|i| !i.is_loopback())
+        .map(|i| (i.ip(), i.index))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discover_peer() {
+        let mdns = Mdns::new(443, None);
+        let message = Message {
+            response: true,
+            questions: Vec::new(),
+            records: vec![
+                Record {
+                    name: format!("rack2.{}", SERVICE_TYPE),
+                    ttl: 120,
+                    data: RecordData::Srv {
+                        port: 8443,
+                        target: "rack2.local".to_string(),
+                    },
+                },
+                Record {
+                    name: format!("rack2.{}", SERVICE_TYPE),
+                    ttl: 120,
+                    data: RecordData::Txt(vec!["serial=abc".to_string()]),
+                },
+                Record {
+                    name: "rack2.local".to_string(),
+                    ttl: 120,
+                    data: RecordData::A(Ipv4Addr::new(10, 0, 0, 2)),
+                },
+                Record {
+                    name: "rack2.local".to_string(),
+                    ttl: 120,
+                    data: RecordData::Aaaa("fe80::2".parse().unwrap()),
+                },
+            ],
+        };
+
+        mdns.process_response(&message, u32::MAX);
+        let peers = mdns.peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].instance, "rack2");
+        assert_eq!(peers[0].port, 8443);
+        let addresses: Vec<String> = peers[0].addresses.iter().map(|a| a.to_string()).collect();
+        assert_eq!(
+            addresses,
+            ["10.0.0.2".to_string(), format!("fe80::2%{}", u32::MAX)]
+        );
+        assert_eq!(peers[0].txt.get("serial").unwrap(), "abc");
+
+        // goodbye
+        let mut goodbye = message.clone();
+        goodbye.records[0].ttl = 0;
+        mdns.process_response(&goodbye, 0);
+        assert!(mdns.peers().is_empty());
+    }
+}
@@ -0,0 +1,331 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Minimal DNS wire format (RFC 1035) support, limited to the record types
+//! used for DNS based service discovery.
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// mDNS uses the top bit of the class to flag cache-flush (records) or
+/// unicast-response (questions).
+const CLASS_MASK: u16 = 0x7fff;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const HEADER_SIZE: usize = 12;
+const MAX_POINTER_JUMPS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(String),
+    Txt(Vec<String>),
+    Srv { port: u16, target: String },
+    Other(u16),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub name: String,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Message {
+    pub response: bool,
+    pub questions: Vec<Question>,
+    /// answer, authority and additional records combined.
+    pub records: Vec<Record>,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(512);
+        let flags = if self.response {
+            FLAG_RESPONSE | FLAG_AUTHORITATIVE
+        } else {
+            0
+        };
+        for value in [
+            0,
+            flags,
+            self.questions.len() as u16,
+            self.records.len() as u16,
+            0,
+            0,
+        ] {
+            buf.extend_from_slice(&value.to_be_bytes());
+        }
+
+        for question in &self.questions {
+            write_name(&mut buf, &question.name);
+            buf.extend_from_slice(&question.qtype.to_be_bytes());
+            buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        }
+
+        for record in &self.records {
+            write_name(&mut buf, &record.name);
+            let mut rdata = Vec::new();
+            let rtype = match &record.data {
+                RecordData::A(ip) => {
+                    rdata.extend_from_slice(&ip.octets());
+                    TYPE_A
+                }
+                RecordData::Aaaa(ip) => {
+                    rdata.extend_from_slice(&ip.octets());
+                    TYPE_AAAA
+                }
+                RecordData::Ptr(name) => {
+                    write_name(&mut rdata, name);
+                    TYPE_PTR
+                }
+                RecordData::Txt(entries) => {
+                    for entry in entries {
+                        let entry = &entry.as_bytes()[..entry.len().min(255)];
+                        rdata.push(entry.len() as u8);
+                        rdata.extend_from_slice(entry);
+                    }
+                    TYPE_TXT
+                }
+                RecordData::Srv { port, target } => {
+                    rdata.extend_from_slice(&[0, 0, 0, 0]);
+                    rdata.extend_from_slice(&port.to_be_bytes());
+                    write_name(&mut rdata, target);
+                    TYPE_SRV
+                }
+                RecordData::Other(rtype) => *rtype,
+            };
+            buf.extend_from_slice(&rtype.to_be_bytes());
+            buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+            buf.extend_from_slice(&record.ttl.to_be_bytes());
+            buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&rdata);
+        }
+        buf
+    }
+
+    /// Returns `None` for malformed packets.
+    pub fn decode(packet: &[u8]) -> Option<Self> {
+        let mut reader = Reader { packet, pos: 0 };
+        let _id = reader.u16()?;
+        let flags = reader.u16()?;
+        let questions = reader.u16()?;
+        let records: usize = (0..3)
+            .map(|_| reader.u16().map(usize::from))
+            .sum::<Option<_>>()?;
+
+        let mut message = Message {
+            response: flags & FLAG_RESPONSE != 0,
+            ..Default::default()
+        };
+
+        for _ in 0..questions {
+            let name = reader.name()?;
+            let qtype = reader.u16()?;
+            let _class = reader.u16()? & CLASS_MASK;
+            message.questions.push(Question { name, qtype });
+        }
+
+        for _ in 0..records {
+            let name = reader.name()?;
+            let rtype = reader.u16()?;
+            let _class = reader.u16()? & CLASS_MASK;
+            let ttl = reader.u32()?;
+            let len = usize::from(reader.u16()?);
+            let end = reader.pos.checked_add(len).filter(|e| *e <= packet.len())?;
+
+            let data = match rtype {
+                TYPE_A => {
+                    let octets: [u8; 4] = packet.get(reader.pos..end)?.try_into().ok()?;
+                    RecordData::A(Ipv4Addr::from(octets))
+                }
+                TYPE_AAAA => {
+                    let octets: [u8; 16] = packet.get(reader.pos..end)?.try_into().ok()?;
+                    RecordData::Aaaa(Ipv6Addr::from(octets))
+                }
+                TYPE_PTR => RecordData::Ptr(reader.name()?),
+                TYPE_TXT => {
+                    let mut entries = Vec::new();
+                    let mut pos = reader.pos;
+                    while pos < end {
+                        let len = usize::from(packet[pos]);
+                        let entry = packet.get(pos + 1..pos + 1 + len)?;
+                        entries.push(String::from_utf8_lossy(entry).to_string());
+                        pos += 1 + len;
+                    }
+                    RecordData::Txt(entries)
+                }
+                TYPE_SRV => {
+                    reader.pos += 4;
+                    let port = reader.u16()?;
+                    RecordData::Srv {
+                        port,
+                        target: reader.name()?,
+                    }
+                }
+                other => RecordData::Other(other),
+            };
+            reader.pos = end;
+            message.records.push(Record { name, ttl, data });
+        }
+
+        Some(message)
+    }
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.trim_end_matches('.').split('.') {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label);
+    }
+    buf.push(0);
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.packet.get(self.pos..self.pos + 2)?;
+        self.pos += 2;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some((u32::from(self.u16()?) << 16) | u32::from(self.u16()?))
+    }
+
+    /// Reads a domain name, following compression pointers.
+    fn name(&mut self) -> Option<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut jumps = 0;
+
+        loop {
+            let len = *self.packet.get(pos)?;
+            match len {
+                0 => {
+                    if jumps == 0 {
+                        self.pos = pos + 1;
+                    }
+                    break;
+                }
+                len if len & 0xc0 == 0xc0 => {
+                    let offset =
+                        usize::from(u16::from_be_bytes([len & 0x3f, *self.packet.get(pos + 1)?]));
+                    if jumps == 0 {
+                        self.pos = pos + 2;
+                    }
+                    jumps += 1;
+                    if jumps > MAX_POINTER_JUMPS || offset < HEADER_SIZE {
+                        return None;
+                    }
+                    pos = offset;
+                }
+                len => {
+                    let label = self.packet.get(pos + 1..pos + 1 + usize::from(len))?;
+                    labels.push(String::from_utf8_lossy(label).to_string());
+                    pos += 1 + usize::from(len);
+                }
+            }
+        }
+
+        Some(labels.join("."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let message = Message {
+            response: true,
+            questions: vec![Question {
+                name: "_bmcd._tcp.local".to_string(),
+                qtype: TYPE_PTR,
+            }],
+            records: vec![
+                Record {
+                    name: "_bmcd._tcp.local".to_string(),
+                    ttl: 120,
+                    data: RecordData::Ptr("turingpi._bmcd._tcp.local".to_string()),
+                },
+                Record {
+                    name: "turingpi._bmcd._tcp.local".to_string(),
+                    ttl: 120,
+                    data: RecordData::Srv {
+                        port: 443,
+                        target: "turingpi.local".to_string(),
+                    },
+                },
+                Record {
+                    name: "turingpi._bmcd._tcp.local".to_string(),
+                    ttl: 120,
+                    data: RecordData::Txt(vec!["serial=1234".to_string()]),
+                },
+                Record {
+                    name: "turingpi.local".to_string(),
+                    ttl: 120,
+                    data: RecordData::A(Ipv4Addr::new(192, 168, 1, 10)),
+                },
+                Record {
+                    name: "turingpi.local".to_string(),
+                    ttl: 120,
+                    data: RecordData::Aaaa("fe80::1ff:fe23:4567:890a".parse().unwrap()),
+                },
+            ],
+        };
+
+        assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+    }
+
+    #[test]
+    fn compressed_names() {
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+        write_name(&mut packet, "_bmcd._tcp.local");
+        packet.extend_from_slice(&[0, 12, 0, 1, 0, 0, 0, 120, 0, 6]);
+        // "tpi" followed by a pointer to the owner name at offset 12
+        packet.extend_from_slice(&[3, b't', b'p', b'i', 0xc0, 12]);
+
+        let message = Message::decode(&packet).unwrap();
+        assert_eq!(
+            message.records[0].data,
+            RecordData::Ptr("tpi._bmcd._tcp.local".to_string())
+        );
+    }
+
+    #[test]
+    fn reject_pointer_loop() {
+        let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(&[0xc0, 12, 0, 12, 0, 1]);
+        assert!(Message::decode(&packet).is_none());
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Message catalog, so front-ends show the errors and states of the API in
+//! the language of their user instead of matching English messages.
+//!
+//! `errors` maps the `code` of an error response, see [`crate::error`], to a
+//! template in which `{name}` stands for the value of `name` in the `details`
+//! of the error. `states` maps the codes of states, e.g. the `state` of a job,
+//! to a label, by kind of state.
+//!
+//! English is built in, see `bmcd/messages/en.json`. A translation is a file
+//! of the same format in the configured directory, named after its language,
+//! e.g. `de.json`. Entries that it lacks fall back to English.
+use crate::error::BmcError;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::OnceLock;
+
+pub const DEFAULT_LANGUAGE: &str = "en";
+const ENGLISH: &str = include_str!("../../messages/en.json");
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Catalog {
+    pub errors: BTreeMap<String, String>,
+    pub states: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl Catalog {
+    /// Replaces the entries of `self` that `translation` has.
+    fn merge(&mut self, translation: Catalog) {
+        self.errors.extend(translation.errors);
+        for (kind, labels) in translation.states {
+            self.states.entry(kind).or_default().extend(labels);
+        }
+    }
+}
+
+pub fn english() -> &'static Catalog {
+    static ENGLISH_CATALOG: OnceLock<Catalog> = OnceLock::new();
+    ENGLISH_CATALOG
+        .get_or_init(|| serde_json::from_str(ENGLISH).expect("built-in catalog is valid"))
+}
+
+/// Languages of the catalog: English and the translations in `directory`.
+pub async fn languages(directory: &Path) -> Vec<String> {
+    let mut languages = vec![DEFAULT_LANGUAGE.to_string()];
+    if let Ok(mut entries) = tokio::fs::read_dir(directory).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if path.extension().is_some_and(|e| e == "json") {
+                if let Some(language) = path.file_stem().and_then(|s| s.to_str()) {
+                    if valid_language(language) && language != DEFAULT_LANGUAGE {
+                        languages.push(language.to_string());
+                    }
+                }
+            }
+        }
+    }
+    languages.sort();
+    languages
+}
+
+/// The catalog of `language`, completed with English.
+pub async fn catalog(directory: &Path, language: &str) -> anyhow::Result<Catalog> {
+    if !valid_language(language) {
+        return Err(BmcError::invalid_parameter(
+            "language",
+            "not a language tag, e.g. `de` or `pt-BR`",
+        )
+        .into());
+    }
+    let mut catalog = english().clone();
+    if language == DEFAULT_LANGUAGE {
+        return Ok(catalog);
+    }
+    let path = directory.join(format!("{}.json", language));
+    let text = match tokio::fs::read(&path).await {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(
+                BmcError::NotFound(format!("a translation to `{}`", language).into()).into(),
+            )
+        }
+        Err(e) => return Err(e).with_context(|| path.display().to_string()),
+    };
+    let translation =
+        serde_json::from_slice(&text).with_context(|| format!("{} is invalid", path.display()))?;
+    catalog.merge(translation);
+    Ok(catalog)
+}
+
+/// Language tags like `de` or `pt-BR`, which also keeps the file names of the
+/// translations inside their directory.
+fn valid_language(language: &str) -> bool {
+    (1..=35).contains(&language.len())
+        && language
+            .split('-')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{ApiError, ErrorCode};
+    use actix_web::http::StatusCode;
+    use serde_json::{json, Value};
+    use tempdir::TempDir;
+
+    /// Codes in the `fn code` bodies of the error types in the sources.
+    fn codes_in_sources() -> Vec<String> {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let mut directories = vec![src];
+        let mut codes = Vec::new();
+        while let Some(directory) = directories.pop() {
+            for entry in std::fs::read_dir(directory).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    directories.push(path);
+                    continue;
+                }
+                if path.extension().map_or(true, |e| e != "rs") {
+                    continue;
+                }
+                let source = std::fs::read_to_string(&path).unwrap();
+                let mut lines = source.lines();
+                while let Some(line) = lines.next() {
+                    if !line
+                        .trim_start()
+                        .starts_with("fn code(&self) -> &'static str {")
+                    {
+                        continue;
+                    }
+                    let indent = line.len() - line.trim_start().len();
+                    let end = format!("{}}}", " ".repeat(indent));
+                    for line in lines.by_ref().take_while(|l| *l != end) {
+                        let quoted: Vec<_> = line.split('"').collect();
+                        codes.extend(quoted.iter().skip(1).step_by(2).map(|c| c.to_string()));
+                    }
+                }
+            }
+        }
+        codes
+    }
+
+    fn render(template: &str, details: &Value) -> String {
+        let mut text = template.to_string();
+        if let Value::Object(details) = details {
+            for (name, value) in details {
+                let value = value
+                    .as_str()
+                    .map_or_else(|| value.to_string(), str::to_string);
+                text = text.replace(&format!("{{{}}}", name), &value);
+            }
+        }
+        text
+    }
+
+    #[test]
+    fn errors_have_messages() {
+        let catalog = english();
+        let codes = codes_in_sources();
+        assert!(codes.len() > 30, "found only {} codes", codes.len());
+        for code in codes {
+            assert!(
+                catalog.errors.contains_key(&code),
+                "`{}` has no message",
+                code
+            );
+        }
+        for status in 400..600 {
+            let Ok(status) = StatusCode::from_u16(status) else {
+                continue;
+            };
+            if status.canonical_reason().is_some() {
+                let error = ApiError::from_status(status, String::new());
+                assert!(
+                    catalog.errors.contains_key(error.code.as_ref()),
+                    "{}",
+                    status
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn templates_use_details() {
+        use crate::app::console_shares::ShareError;
+        use crate::app::kv_store::KvError;
+        use crate::hal::NodeId;
+
+        let errors: Vec<Box<dyn ErrorCode>> = vec![
+            Box::new(BmcError::NoSuchNode(NodeId::Node4)),
+            Box::new(BmcError::invalid_parameter("node", "must be 1 to 4")),
+            Box::new(BmcError::PinAccessDenied {
+                pin: "GPIO4".into(),
+                reason: "is reserved",
+            }),
+            Box::new(BmcError::QuotaExceeded {
+                feature: "kv",
+                quota: 1024,
+            }),
+            Box::new(BmcError::Device {
+                path: "/dev/i2c-1".into(),
+                source: std::io::Error::from(ErrorKind::PermissionDenied),
+            }),
+            Box::new(KvError::QuotaExceeded("ns".into())),
+            Box::new(KvError::InvalidName("a b".into())),
+            Box::new(KvError::TooManyNamespaces),
+            Box::new(ShareError::Disabled),
+            Box::new(ShareError::InvalidTtl(600)),
+        ];
+        for error in errors {
+            let template = &english().errors[error.code()];
+            let text = render(template, &error.details());
+            assert!(
+                !text.contains('{'),
+                "`{}` is left with {}",
+                error.code(),
+                text
+            );
+        }
+        assert_eq!(
+            render(&english().errors["no_such_node"], &json!({"node": 4})),
+            "Node 4 does not exist on this board"
+        );
+    }
+
+    #[test]
+    fn states_have_labels() {
+        use crate::app::activity::ActivityState;
+        use crate::app::inventory::Presence;
+        use crate::app::jobs::JobState;
+        use crate::app::selftest::Outcome;
+
+        let states = [
+            ("activity", json!(ActivityState::Off)),
+            ("activity", json!(ActivityState::NoLoad)),
+            ("activity", json!(ActivityState::Running)),
+            ("activity", json!(ActivityState::Stalled)),
+            ("activity", json!(ActivityState::Unknown)),
+            ("job", json!(JobState::Running)),
+            ("job", json!(JobState::Succeeded)),
+            ("job", json!(JobState::Failed)),
+            ("job", json!(JobState::Cancelled)),
+            ("presence", json!(Presence::Present)),
+            ("presence", json!(Presence::Absent)),
+            ("presence", json!(Presence::Unknown)),
+            ("selftest", json!(Outcome::Pass)),
+            ("selftest", json!(Outcome::Fail)),
+            ("selftest", json!(Outcome::Skipped)),
+        ];
+        for (kind, state) in states {
+            let state = state.as_str().unwrap();
+            assert!(
+                english().states[kind].contains_key(state),
+                "{} `{}` has no label",
+                kind,
+                state
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn translations() {
+        let dir = TempDir::new("messages").unwrap();
+        let translation = json!({
+            "errors": {"no_such_node": "Knoten {node} gibt es auf diesem Board nicht"},
+            "states": {"job": {"running": "Läuft"}},
+        });
+        std::fs::write(dir.path().join("de.json"), translation.to_string()).unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "").unwrap();
+
+        assert_eq!(languages(dir.path()).await, vec!["de", "en"]);
+        let catalog = catalog(dir.path(), "de").await.unwrap();
+        assert_eq!(
+            catalog.errors["no_such_node"],
+            "Knoten {node} gibt es auf diesem Board nicht"
+        );
+        assert_eq!(catalog.errors["busy"], english().errors["busy"]);
+        assert_eq!(catalog.states["job"]["running"], "Läuft");
+        assert_eq!(catalog.states["job"]["failed"], "Failed");
+
+        let not_found = super::catalog(dir.path(), "fr").await.unwrap_err();
+        assert_eq!(ApiError::from_anyhow(&not_found).code, "not_found");
+        let invalid = super::catalog(dir.path(), "../de").await.unwrap_err();
+        assert_eq!(ApiError::from_anyhow(&invalid).code, "invalid_parameter");
+        assert_eq!(
+            super::catalog(Path::new("/nonexistent"), "en")
+                .await
+                .unwrap(),
+            *english()
+        );
+    }
+}
@@ -0,0 +1,466 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Network block device server that exports images stored on the BMC to the
+//! nodes. Every node has at most one export, named `node1` to `node4`, which
+//! it can use as root or rescue disk. Only the fixed newstyle handshake of the
+//! NBD protocol is implemented.
+//!
+//! The protocol has no authentication, writable exports can be written by
+//! anyone who may connect. Only clients in `nbd.allow` are accepted.
+use super::bmc_application::BmcApplication;
+use crate::utils::{dual_stack_tcp, resolve, scoped_address, IpNetwork};
+use anyhow::{ensure, Context};
+use serde::{Deserialize, Serialize};
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+pub const NBD_EXPORTS_KEY: &str = "nbd_exports";
+const NBD_PORT: u16 = 10809;
+
+const NBDMAGIC: u64 = 0x4e42444d41474943;
+const IHAVEOPT: u64 = 0x49484156454f5054;
+const REPLY_MAGIC: u64 = 0x0003e889045565a9;
+const REQUEST_MAGIC: u32 = 0x25609513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1;
+const FLAG_NO_ZEROES: u16 = 2;
+const FLAG_HAS_FLAGS: u16 = 1;
+const FLAG_READ_ONLY: u16 = 2;
+const FLAG_SEND_FLUSH: u16 = 4;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+const REP_ACK: u32 = 1;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+const REP_ERR_UNKNOWN: u32 = (1 << 31) + 6;
+const INFO_EXPORT: u16 = 0;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const EPERM: u32 = 1;
+const EIO: u32 = 5;
+const EINVAL: u32 = 22;
+
+/// Largest option or request payload that is accepted.
+const MAX_PAYLOAD: u32 = 32 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NbdExport {
+    /// image path relative to the images directory
+    pub image: String,
+    pub read_only: bool,
+}
+
+/// Export of every node, indexed by slot number minus one.
+pub type NbdExports = [Option<NbdExport>; 4];
+
+/// An export after the handshake completed.
+struct OpenExport {
+    file: File,
+    size: u64,
+    read_only: bool,
+}
+
+pub struct NbdServer {
+    images_dir: PathBuf,
+    exports: RwLock<NbdExports>,
+}
+
+impl NbdServer {
+    pub async fn load(bmc: &BmcApplication, images_dir: PathBuf) -> Self {
+        Self {
+            images_dir,
+            exports: RwLock::new(bmc.app_db.get::<NbdExports>(NBD_EXPORTS_KEY).await),
+        }
+    }
+
+    pub fn exports(&self) -> NbdExports {
+        self.exports.read().expect("exports lock poisoned").clone()
+    }
+
+    /// Exports an image to a node, or removes the export of the node when
+    /// `export` is `None`. Clients that are connected keep using the image
+    /// they opened.
+    pub async fn set_export(
+        &self,
+        bmc: &BmcApplication,
+        node: u8,
+        export: Option<NbdExport>,
+    ) -> anyhow::Result<()> {
+        ensure!(
+            (1..=4).contains(&node),
+            "node {} is out of range 1..4",
+            node
+        );
+        if let Some(export) = &export {
+            let path = self.image_path(&export.image)?;
+            ensure!(path.is_file(), "{} does not exist", path.display());
+        }
+
+        let exports = {
+            let mut exports = self.exports.write().expect("exports lock poisoned");
+            exports[usize::from(node - 1)] = export;
+            exports.clone()
+        };
+        bmc.app_db.set(NBD_EXPORTS_KEY, exports).await;
+        Ok(())
+    }
+
+    /// Accepts NBD clients from the addresses or networks in `allow` in the
+    /// background.
+    pub async fn run(self: Arc<Self>, allow: &[String]) -> std::io::Result<()> {
+        let allow: Vec<IpNetwork> = allow
+            .iter()
+            .filter_map(|network| network.parse().ok())
+            .collect();
+        let listener = TcpListener::from_std(dual_stack_tcp(NBD_PORT)?)?;
+        tracing::info!("NBD server listening on port {}", NBD_PORT);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(client) => client,
+                    Err(e) => {
+                        tracing::warn!("NBD server: {}", e);
+                        continue;
+                    }
+                };
+                if !allow.iter().any(|network| network.contains(peer.ip())) {
+                    tracing::debug!("NBD server: refused {}", scoped_address(&peer));
+                    continue;
+                }
+
+                let server = self.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = server.handle_client(stream).await {
+                        tracing::debug!("NBD client {}: {:#}", scoped_address(&peer), e);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    fn image_path(&self, image: &str) -> anyhow::Result<PathBuf> {
+        resolve(&self.images_dir, image)
+            .with_context(|| format!("`{}` is outside of {}", image, self.images_dir.display()))
+    }
+
+    /// Looks up an export by its name, `node1` to `node4`.
+    async fn open_export(&self, name: &str) -> Option<OpenExport> {
+        let node: usize = name.strip_prefix("node")?.parse().ok()?;
+        let export = self
+            .exports
+            .read()
+            .expect("exports lock poisoned")
+            .get(node.checked_sub(1)?)?
+            .clone()?;
+
+        let path = self.image_path(&export.image).ok()?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!export.read_only)
+            .open(&path)
+            .await
+            .ok()?;
+        let size = file.metadata().await.ok()?.len();
+        tracing::info!("NBD export {} opened ({})", name, path.display());
+
+        Some(OpenExport {
+            file,
+            size,
+            read_only: export.read_only,
+        })
+    }
+
+    async fn handle_client<S>(&self, mut stream: S) -> anyhow::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        stream.write_u64(NBDMAGIC).await?;
+        stream.write_u64(IHAVEOPT).await?;
+        stream
+            .write_u16(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES)
+            .await?;
+        let client_flags = stream.read_u32().await?;
+        let no_zeroes = client_flags & u32::from(FLAG_NO_ZEROES) != 0;
+
+        let export = loop {
+            ensure!(stream.read_u64().await? == IHAVEOPT, "invalid option magic");
+            let option = stream.read_u32().await?;
+            let data = read_payload(&mut stream).await?;
+
+            match option {
+                OPT_EXPORT_NAME => {
+                    let name = String::from_utf8_lossy(&data);
+                    let export = self
+                        .open_export(&name)
+                        .await
+                        .with_context(|| format!("unknown export `{}`", name))?;
+                    stream.write_u64(export.size).await?;
+                    stream.write_u16(transmission_flags(&export)).await?;
+                    if !no_zeroes {
+                        stream.write_all(&[0u8; 124]).await?;
+                    }
+                    break export;
+                }
+                OPT_INFO | OPT_GO => {
+                    let name = parse_export_name(&data).unwrap_or_default();
+                    let Some(export) = self.open_export(&name).await else {
+                        write_option_reply(&mut stream, option, REP_ERR_UNKNOWN, &[]).await?;
+                        continue;
+                    };
+
+                    let mut info = INFO_EXPORT.to_be_bytes().to_vec();
+                    info.extend_from_slice(&export.size.to_be_bytes());
+                    info.extend_from_slice(&transmission_flags(&export).to_be_bytes());
+                    write_option_reply(&mut stream, option, REP_INFO, &info).await?;
+                    write_option_reply(&mut stream, option, REP_ACK, &[]).await?;
+                    if option == OPT_GO {
+                        break export;
+                    }
+                }
+                OPT_ABORT => {
+                    write_option_reply(&mut stream, option, REP_ACK, &[]).await?;
+                    return Ok(());
+                }
+                _ => write_option_reply(&mut stream, option, REP_ERR_UNSUP, &[]).await?,
+            }
+        };
+
+        transmission(&mut stream, export).await
+    }
+}
+
+fn transmission_flags(export: &OpenExport) -> u16 {
+    let mut flags = FLAG_HAS_FLAGS | FLAG_SEND_FLUSH;
+    if export.read_only {
+        flags |= FLAG_READ_ONLY;
+    }
+    flags
+}
+
+async fn read_payload<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<Vec<u8>> {
+    let len = stream.read_u32().await?;
+    ensure!(len <= MAX_PAYLOAD, "payload of {} bytes too large", len);
+    let mut data = vec![0u8; len as usize];
+    stream.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+/// Parses the export name of `NBD_OPT_INFO` and `NBD_OPT_GO` data.
+fn parse_export_name(data: &[u8]) -> Option<String> {
+    let len = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let name = data.get(4..4 + len)?;
+    Some(String::from_utf8_lossy(name).to_string())
+}
+
+async fn write_option_reply<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    option: u32,
+    reply: u32,
+    data: &[u8],
+) -> std::io::Result<()> {
+    stream.write_u64(REPLY_MAGIC).await?;
+    stream.write_u32(option).await?;
+    stream.write_u32(reply).await?;
+    stream.write_u32(data.len() as u32).await?;
+    stream.write_all(data).await?;
+    stream.flush().await
+}
+
+async fn write_simple_reply<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    error: u32,
+    handle: u64,
+    data: &[u8],
+) -> std::io::Result<()> {
+    stream.write_u32(SIMPLE_REPLY_MAGIC).await?;
+    stream.write_u32(error).await?;
+    stream.write_u64(handle).await?;
+    stream.write_all(data).await?;
+    stream.flush().await
+}
+
+async fn transmission<S>(stream: &mut S, mut export: OpenExport) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        ensure!(
+            stream.read_u32().await? == REQUEST_MAGIC,
+            "invalid request magic"
+        );
+        let _flags = stream.read_u16().await?;
+        let command = stream.read_u16().await?;
+        let handle = stream.read_u64().await?;
+        let offset = stream.read_u64().await?;
+        let length = stream.read_u32().await?;
+        ensure!(
+            length <= MAX_PAYLOAD,
+            "request of {} bytes too large",
+            length
+        );
+
+        let in_range = offset
+            .checked_add(u64::from(length))
+            .is_some_and(|end| end <= export.size);
+
+        match command {
+            CMD_READ => {
+                if !in_range {
+                    write_simple_reply(stream, EINVAL, handle, &[]).await?;
+                    continue;
+                }
+                let mut data = vec![0u8; length as usize];
+                let result = async {
+                    export.file.seek(SeekFrom::Start(offset)).await?;
+                    export.file.read_exact(&mut data).await
+                }
+                .await;
+                match result {
+                    Ok(_) => write_simple_reply(stream, 0, handle, &data).await?,
+                    Err(_) => write_simple_reply(stream, EIO, handle, &[]).await?,
+                }
+            }
+            CMD_WRITE => {
+                // the payload has to be consumed in any case
+                let mut data = vec![0u8; length as usize];
+                stream.read_exact(&mut data).await?;
+
+                let error = if export.read_only {
+                    EPERM
+                } else if !in_range {
+                    EINVAL
+                } else {
+                    let result = async {
+                        export.file.seek(SeekFrom::Start(offset)).await?;
+                        export.file.write_all(&data).await
+                    }
+                    .await;
+                    if result.is_ok() {
+                        0
+                    } else {
+                        EIO
+                    }
+                };
+                write_simple_reply(stream, error, handle, &[]).await?;
+            }
+            CMD_FLUSH => {
+                let error = if export.file.sync_data().await.is_ok() {
+                    0
+                } else {
+                    EIO
+                };
+                write_simple_reply(stream, error, handle, &[]).await?;
+            }
+            CMD_DISC => return Ok(()),
+            _ => write_simple_reply(stream, EINVAL, handle, &[]).await?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn export_name_handshake_and_read() {
+        let dir = TempDir::new("nbd").unwrap();
+        std::fs::write(
+            dir.path().join("rescue.img"),
+            (0u8..=255).collect::<Vec<_>>(),
+        )
+        .unwrap();
+        let server = NbdServer {
+            images_dir: dir.path().to_path_buf(),
+            exports: RwLock::new([
+                None,
+                Some(NbdExport {
+                    image: "rescue.img".to_string(),
+                    read_only: true,
+                }),
+                None,
+                None,
+            ]),
+        };
+
+        let (mut client, server_side) = tokio::io::duplex(4096);
+        let task = tokio::spawn(async move { server.handle_client(server_side).await });
+
+        assert_eq!(client.read_u64().await.unwrap(), NBDMAGIC);
+        assert_eq!(client.read_u64().await.unwrap(), IHAVEOPT);
+        client.read_u16().await.unwrap();
+        client.write_u32(u32::from(FLAG_NO_ZEROES)).await.unwrap();
+
+        client.write_u64(IHAVEOPT).await.unwrap();
+        client.write_u32(OPT_EXPORT_NAME).await.unwrap();
+        client.write_u32(5).await.unwrap();
+        client.write_all(b"node2").await.unwrap();
+        assert_eq!(client.read_u64().await.unwrap(), 256);
+        assert_ne!(client.read_u16().await.unwrap() & FLAG_READ_ONLY, 0);
+
+        // read 4 bytes at offset 16
+        client.write_u32(REQUEST_MAGIC).await.unwrap();
+        client.write_u16(0).await.unwrap();
+        client.write_u16(CMD_READ).await.unwrap();
+        client.write_u64(42).await.unwrap();
+        client.write_u64(16).await.unwrap();
+        client.write_u32(4).await.unwrap();
+        assert_eq!(client.read_u32().await.unwrap(), SIMPLE_REPLY_MAGIC);
+        assert_eq!(client.read_u32().await.unwrap(), 0);
+        assert_eq!(client.read_u64().await.unwrap(), 42);
+        let mut data = [0u8; 4];
+        client.read_exact(&mut data).await.unwrap();
+        assert_eq!(data, [16, 17, 18, 19]);
+
+        // writes are refused on a read-only export
+        client.write_u32(REQUEST_MAGIC).await.unwrap();
+        client.write_u16(0).await.unwrap();
+        client.write_u16(CMD_WRITE).await.unwrap();
+        client.write_u64(43).await.unwrap();
+        client.write_u64(0).await.unwrap();
+        client.write_u32(1).await.unwrap();
+        client.write_u8(0xff).await.unwrap();
+        client.read_u32().await.unwrap();
+        assert_eq!(client.read_u32().await.unwrap(), EPERM);
+        client.read_u64().await.unwrap();
+
+        client.write_u32(REQUEST_MAGIC).await.unwrap();
+        client.write_u16(0).await.unwrap();
+        client.write_u16(CMD_DISC).await.unwrap();
+        client.write_all(&[0u8; 20]).await.unwrap();
+        task.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn export_name_of_go_option() {
+        let mut data = 5u32.to_be_bytes().to_vec();
+        data.extend_from_slice(b"node1");
+        data.extend_from_slice(&0u16.to_be_bytes());
+        assert_eq!(parse_export_name(&data).unwrap(), "node1");
+        assert!(parse_export_name(&[0, 0, 0, 9, b'a']).is_none());
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Network boot of the nodes. Boot files are served from the storage of the
+//! BMC over TFTP, and optionally HTTP. The DHCP server tells every node which
+//! file it should boot.
+mod tftp;
+
+use super::bmc_application::BmcApplication;
+use crate::utils::resolve;
+use anyhow::{ensure, Context};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+pub use tftp::run_tftp_server;
+
+pub const NETBOOT_KEY: &str = "netboot";
+/// Boot file of every node, indexed by slot number minus one.
+pub type BootFiles = [Option<String>; 4];
+
+#[derive(Debug, Serialize)]
+pub struct NetbootStatus {
+    pub enabled: bool,
+    pub boot_files: BootFiles,
+    /// files available in the netboot directory
+    pub files: Vec<String>,
+}
+
+pub struct Netboot {
+    enabled: bool,
+    root: PathBuf,
+    boot_files: RwLock<BootFiles>,
+}
+
+impl Netboot {
+    pub async fn new(bmc: &BmcApplication, enabled: bool, root: PathBuf) -> Self {
+        Self {
+            enabled,
+            root,
+            boot_files: RwLock::new(bmc.app_db.get::<BootFiles>(NETBOOT_KEY).await),
+        }
+    }
+
+    /// Boot file of the given slot, `None` when netboot is disabled or no
+    /// file is assigned.
+    pub fn boot_file(&self, node: u8) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        let boot_files = self.boot_files.read().expect("boot files lock poisoned");
+        boot_files.get(usize::from(node).checked_sub(1)?)?.clone()
+    }
+
+    /// Boot files as assigned, also while netboot is disabled.
+    pub fn boot_files(&self) -> BootFiles {
+        self.boot_files
+            .read()
+            .expect("boot files lock poisoned")
+            .clone()
+    }
+
+    pub async fn status(&self) -> anyhow::Result<NetbootStatus> {
+        Ok(NetbootStatus {
+            enabled: self.enabled,
+            boot_files: self.boot_files(),
+            files: list_files(&self.root).await?,
+        })
+    }
+
+    /// Assigns a boot file, relative to the netboot directory, to a slot.
+    /// `None` disables netboot for the node.
+    pub async fn set_boot_file(
+        &self,
+        bmc: &BmcApplication,
+        node: u8,
+        boot_file: Option<String>,
+    ) -> anyhow::Result<()> {
+        ensure!(
+            (1..=4).contains(&node),
+            "node {} is out of range 1..4",
+            node
+        );
+        if let Some(file) = &boot_file {
+            let path = resolve(&self.root, file)
+                .with_context(|| format!("`{}` is outside the netboot directory", file))?;
+            ensure!(path.is_file(), "{} does not exist", path.display());
+        }
+
+        let boot_files = {
+            let mut boot_files = self.boot_files.write().expect("boot files lock poisoned");
+            boot_files[usize::from(node - 1)] = boot_file;
+            boot_files.clone()
+        };
+        bmc.app_db.set(NETBOOT_KEY, boot_files).await;
+        Ok(())
+    }
+}
+
+async fn list_files(root: &Path) -> anyhow::Result<Vec<String>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).context(dir.display().to_string()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if file_type.is_file() {
+                if let Ok(relative) = entry.path().strip_prefix(root) {
+                    files.push(relative.to_string_lossy().to_string());
+                }
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn list_nested_files() {
+        let dir = TempDir::new("netboot").unwrap();
+        std::fs::create_dir(dir.path().join("grub")).unwrap();
+        std::fs::write(dir.path().join("grub/grubx64.efi"), b"").unwrap();
+        std::fs::write(dir.path().join("pxelinux.0"), b"").unwrap();
+
+        assert_eq!(
+            list_files(dir.path()).await.unwrap(),
+            vec!["grub/grubx64.efi".to_string(), "pxelinux.0".to_string()]
+        );
+    }
+}
@@ -0,0 +1,234 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Read-only TFTP server (RFC 1350) with support for the block size and
+//! transfer size options (RFC 2348, RFC 2349) that PXE firmware relies on.
+use crate::utils::{dual_stack_udp, resolve, scoped_address};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio::net::UdpSocket;
+
+const TFTP_PORT: u16 = 69;
+const OPCODE_RRQ: u16 = 1;
+const OPCODE_DATA: u16 = 3;
+const OPCODE_ACK: u16 = 4;
+const OPCODE_ERROR: u16 = 5;
+const OPCODE_OACK: u16 = 6;
+const ERROR_NOT_FOUND: u16 = 1;
+const ERROR_ACCESS_VIOLATION: u16 = 2;
+const ERROR_ILLEGAL_OPERATION: u16 = 4;
+const DEFAULT_BLOCK_SIZE: usize = 512;
+const MAX_BLOCK_SIZE: usize = 1468;
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_RETRANSMITS: usize = 5;
+
+#[derive(Debug, PartialEq)]
+struct ReadRequest {
+    filename: String,
+    block_size: Option<usize>,
+    transfer_size: bool,
+}
+
+/// Starts serving the files below `root` to IPv4 and IPv6 clients in the
+/// background.
+pub fn run_tftp_server(root: PathBuf) -> std::io::Result<()> {
+    let socket = UdpSocket::from_std(dual_stack_udp(TFTP_PORT)?)?;
+    tracing::info!("TFTP server serving {}", root.display());
+
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let Ok((len, peer)) = socket.recv_from(&mut buf).await else {
+                continue;
+            };
+
+            let packet = buf[..len].to_vec();
+            let root = root.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve(&root, &packet, peer).await {
+                    tracing::debug!("TFTP transfer to {} failed: {}", scoped_address(&peer), e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn serve(root: &Path, packet: &[u8], peer: SocketAddr) -> std::io::Result<()> {
+    // every transfer uses its own port, as described in the RFC.
+    let local = match peer {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(peer).await?;
+
+    let Some(request) = parse_read_request(packet) else {
+        return send_error(
+            &socket,
+            ERROR_ILLEGAL_OPERATION,
+            "only read requests are supported",
+        )
+        .await;
+    };
+
+    let Some(path) = resolve(root, &request.filename) else {
+        return send_error(&socket, ERROR_ACCESS_VIOLATION, "access violation").await;
+    };
+
+    let mut file = match File::open(&path).await {
+        Ok(file) => file,
+        Err(_) => return send_error(&socket, ERROR_NOT_FOUND, "file not found").await,
+    };
+    tracing::info!("TFTP {} -> {}", path.display(), scoped_address(&peer));
+
+    let block_size = request
+        .block_size
+        .map_or(DEFAULT_BLOCK_SIZE, |s| s.clamp(8, MAX_BLOCK_SIZE));
+    if request.block_size.is_some() || request.transfer_size {
+        let mut oack = OPCODE_OACK.to_be_bytes().to_vec();
+        if request.block_size.is_some() {
+            append_option(&mut oack, "blksize", &block_size.to_string());
+        }
+        if request.transfer_size {
+            let size = file.metadata().await?.len();
+            append_option(&mut oack, "tsize", &size.to_string());
+        }
+        send_with_retry(&socket, &oack, 0).await?;
+    }
+
+    let mut block: u16 = 1;
+    let mut data = vec![0u8; block_size];
+    loop {
+        let len = read_full(&mut file, &mut data).await?;
+        let mut packet = OPCODE_DATA.to_be_bytes().to_vec();
+        packet.extend_from_slice(&block.to_be_bytes());
+        packet.extend_from_slice(&data[..len]);
+        send_with_retry(&socket, &packet, block).await?;
+
+        // a short block terminates the transfer
+        if len < block_size {
+            return Ok(());
+        }
+        block = block.wrapping_add(1);
+    }
+}
+
+/// Sends `packet` until it is acknowledged with `block`.
+async fn send_with_retry(socket: &UdpSocket, packet: &[u8], block: u16) -> std::io::Result<()> {
+    let mut buf = [0u8; 516];
+    for _ in 0..MAX_RETRANSMITS {
+        socket.send(packet).await?;
+        let deadline = tokio::time::Instant::now() + RETRANSMIT_TIMEOUT;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            let len = received?;
+            if len >= 4 && buf[..2] == OPCODE_ERROR.to_be_bytes() {
+                return Err(std::io::Error::other("transfer aborted by client"));
+            }
+            if len >= 4 && buf[..2] == OPCODE_ACK.to_be_bytes() && buf[2..4] == block.to_be_bytes()
+            {
+                return Ok(());
+            }
+        }
+    }
+    Err(std::io::ErrorKind::TimedOut.into())
+}
+
+async fn send_error(socket: &UdpSocket, code: u16, message: &str) -> std::io::Result<()> {
+    let mut packet = OPCODE_ERROR.to_be_bytes().to_vec();
+    packet.extend_from_slice(&code.to_be_bytes());
+    packet.extend_from_slice(message.as_bytes());
+    packet.push(0);
+    socket.send(&packet).await.map(|_| ())
+}
+
+async fn read_full(file: &mut File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..]).await? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+fn append_option(packet: &mut Vec<u8>, name: &str, value: &str) {
+    packet.extend_from_slice(name.as_bytes());
+    packet.push(0);
+    packet.extend_from_slice(value.as_bytes());
+    packet.push(0);
+}
+
+fn parse_read_request(packet: &[u8]) -> Option<ReadRequest> {
+    if packet.get(..2)? != OPCODE_RRQ.to_be_bytes() {
+        return None;
+    }
+
+    let mut fields = packet[2..]
+        .split(|b| *b == 0)
+        .map(|f| String::from_utf8_lossy(f).to_string());
+    let filename = fields.next().filter(|f| !f.is_empty())?;
+    let mode = fields.next()?;
+    if !mode.eq_ignore_ascii_case("octet") {
+        return None;
+    }
+
+    let mut request = ReadRequest {
+        filename,
+        block_size: None,
+        transfer_size: false,
+    };
+    while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+        match name.to_ascii_lowercase().as_str() {
+            "blksize" => request.block_size = value.parse().ok(),
+            "tsize" => request.transfer_size = true,
+            _ => {}
+        }
+    }
+    Some(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_request() {
+        let packet = b"\x00\x01pxelinux.0\x00octet\x00blksize\x001432\x00tsize\x000\x00";
+        assert_eq!(
+            parse_read_request(packet),
+            Some(ReadRequest {
+                filename: "pxelinux.0".to_string(),
+                block_size: Some(1432),
+                transfer_size: true,
+            })
+        );
+        assert!(parse_read_request(b"\x00\x01file\x00netascii\x00").is_none());
+        assert!(parse_read_request(b"\x00\x02file\x00octet\x00").is_none());
+    }
+
+    #[test]
+    fn resolve_within_root() {
+        let root = Path::new("/srv/netboot");
+        assert_eq!(
+            resolve(root, "/grub/grubx64.efi"),
+            Some(PathBuf::from("/srv/netboot/grub/grubx64.efi"))
+        );
+        assert!(resolve(root, "../etc/shadow").is_none());
+        assert!(resolve(root, "a/../../etc/shadow").is_none());
+    }
+}
@@ -0,0 +1,549 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Configuration of the network of the BMC itself. Settings are written to the
+//! ifupdown configuration of the firmware and applied by restarting the
+//! network service.
+use super::bmc_application::set_hostname;
+use crate::utils::{get_timestamp_unix, is_valid_hostname};
+use anyhow::{bail, ensure, Context};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+const INTERFACES_FILE: &str = "/etc/network/interfaces";
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const HOSTNAME_FILE: &str = "/proc/sys/kernel/hostname";
+const NETWORK_SERVICE: &str = "/etc/init.d/S40network";
+/// Interface that connects the BMC to the outside world.
+const BMC_INTERFACE: &str = "br0";
+pub const DEFAULT_ROLLBACK_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum Ipv4Settings {
+    Dhcp,
+    Static {
+        address: Ipv4Addr,
+        prefix_len: u8,
+        gateway: Option<Ipv4Addr>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetworkSettings {
+    pub ipv4: Ipv4Settings,
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub dns: Vec<IpAddr>,
+    /// When set, the BMC is reachable on this VLAN of the bridge.
+    pub vlan: Option<u16>,
+}
+
+impl NetworkSettings {
+    fn validate(&self) -> anyhow::Result<()> {
+        if let Ipv4Settings::Static { prefix_len, .. } = self.ipv4 {
+            ensure!(prefix_len <= 32, "prefix_len {} out of range", prefix_len);
+        }
+
+        if let Some(vlan) = self.vlan {
+            ensure!((1..=4094).contains(&vlan), "vlan {} out of range", vlan);
+        }
+
+        if let Some(hostname) = &self.hostname {
+            ensure!(
+                is_valid_hostname(hostname),
+                "`{}` is not a valid hostname",
+                hostname
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Contents of the network related files, used to restore a previous
+/// configuration.
+#[derive(Debug, Clone)]
+struct Snapshot {
+    interfaces: String,
+    resolv_conf: Option<String>,
+    hostname: String,
+}
+
+impl Snapshot {
+    async fn take() -> anyhow::Result<Self> {
+        Ok(Snapshot {
+            interfaces: tokio::fs::read_to_string(INTERFACES_FILE)
+                .await
+                .context(INTERFACES_FILE)?,
+            resolv_conf: tokio::fs::read_to_string(RESOLV_CONF).await.ok(),
+            hostname: read_hostname().await?,
+        })
+    }
+
+    async fn restore(&self) -> anyhow::Result<()> {
+        tokio::fs::write(INTERFACES_FILE, &self.interfaces).await?;
+        if let Some(resolv_conf) = &self.resolv_conf {
+            tokio::fs::write(RESOLV_CONF, resolv_conf).await?;
+        }
+        set_hostname(&self.hostname).await?;
+        restart_network().await
+    }
+}
+
+#[derive(Debug)]
+struct PendingChange {
+    confirm: oneshot::Sender<()>,
+    deadline: u64,
+}
+
+/// Applies network settings with a safety net: when a change is not confirmed
+/// within the rollback timeout, the previous configuration is restored. This
+/// prevents a client from locking itself out of the BMC.
+#[derive(Debug, Default)]
+pub struct NetworkConfigurator {
+    pending: Arc<Mutex<Option<PendingChange>>>,
+}
+
+impl NetworkConfigurator {
+    pub async fn current(&self) -> anyhow::Result<NetworkSettings> {
+        let interfaces = tokio::fs::read_to_string(INTERFACES_FILE)
+            .await
+            .context(INTERFACES_FILE)?;
+        let resolv_conf = tokio::fs::read_to_string(RESOLV_CONF)
+            .await
+            .unwrap_or_default();
+
+        let (ipv4, vlan) = Interfaces::parse(&interfaces).settings(BMC_INTERFACE);
+        Ok(NetworkSettings {
+            ipv4,
+            hostname: Some(read_hostname().await?),
+            dns: parse_nameservers(&resolv_conf),
+            vlan,
+        })
+    }
+
+    /// Unix timestamp at which a pending change gets rolled back, if any.
+    pub fn rollback_deadline(&self) -> Option<u64> {
+        self.pending
+            .lock()
+            .expect("pending lock poisoned")
+            .as_ref()
+            .map(|p| p.deadline)
+    }
+
+    pub async fn apply(
+        &self,
+        settings: NetworkSettings,
+        rollback_timeout: Duration,
+    ) -> anyhow::Result<()> {
+        settings.validate()?;
+        if self.rollback_deadline().is_some() {
+            bail!("a previous network change awaits confirmation");
+        }
+
+        let snapshot = Snapshot::take().await?;
+        let rollback = self.arm_rollback(snapshot.clone(), rollback_timeout);
+        if let Err(e) = write_settings(&snapshot, &settings).await {
+            let _ = rollback.send(());
+            return Err(e);
+        }
+
+        tracing::info!("applying network settings {:?}", settings);
+        restart_network().await
+    }
+
+    /// Arms the rollback to `snapshot` before any file is changed, so that
+    /// a change that fails halfway is undone as well. Sending on the returned
+    /// channel rolls back right away.
+    fn arm_rollback(&self, snapshot: Snapshot, rollback_timeout: Duration) -> oneshot::Sender<()> {
+        let (confirm, confirmed) = oneshot::channel();
+        let (rollback, rollback_now) = oneshot::channel();
+        let deadline = get_timestamp_unix().unwrap_or_default() + rollback_timeout.as_secs();
+        *self.pending.lock().expect("pending lock poisoned") =
+            Some(PendingChange { confirm, deadline });
+
+        let pending = self.pending.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = confirmed => {
+                    tracing::info!("network change confirmed");
+                    return;
+                }
+                Ok(()) = rollback_now => tracing::warn!("network change failed, rolling back"),
+                _ = tokio::time::sleep(rollback_timeout) => {
+                    tracing::warn!("network change not confirmed, rolling back")
+                }
+            }
+            if let Err(e) = snapshot.restore().await {
+                tracing::error!("network rollback failed: {:#}", e);
+            }
+            // no new change can be applied while this one is pending,
+            // so the pending change is still ours.
+            pending.lock().expect("pending lock poisoned").take();
+        });
+        rollback
+    }
+
+    /// Keeps the pending network change.
+    pub fn confirm(&self) -> anyhow::Result<()> {
+        let pending = self
+            .pending
+            .lock()
+            .expect("pending lock poisoned")
+            .take()
+            .context("no network change awaits confirmation")?;
+        let _ = pending.confirm.send(());
+        Ok(())
+    }
+}
+
+async fn write_settings(snapshot: &Snapshot, settings: &NetworkSettings) -> anyhow::Result<()> {
+    let mut interfaces = Interfaces::parse(&snapshot.interfaces);
+    interfaces.apply(BMC_INTERFACE, settings);
+    tokio::fs::write(INTERFACES_FILE, interfaces.to_string())
+        .await
+        .context(INTERFACES_FILE)?;
+    if !settings.dns.is_empty() {
+        tokio::fs::write(RESOLV_CONF, render_nameservers(&settings.dns))
+            .await
+            .context(RESOLV_CONF)?;
+    }
+    if let Some(hostname) = &settings.hostname {
+        set_hostname(hostname).await?;
+    }
+    Ok(())
+}
+
+async fn read_hostname() -> anyhow::Result<String> {
+    Ok(tokio::fs::read_to_string(HOSTNAME_FILE)
+        .await
+        .context(HOSTNAME_FILE)?
+        .trim_end_matches(['\0', '\n'])
+        .to_string())
+}
+
+async fn restart_network() -> anyhow::Result<()> {
+    let status = tokio::task::spawn_blocking(|| {
+        Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} restart", NETWORK_SERVICE))
+            .status()
+    })
+    .await??;
+    ensure!(status.success(), "network restart returned: {}", status);
+    Ok(())
+}
+
+fn parse_nameservers(resolv_conf: &str) -> Vec<IpAddr> {
+    resolv_conf
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix("nameserver"))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect()
+}
+
+fn render_nameservers(dns: &[IpAddr]) -> String {
+    dns.iter()
+        .map(|ip| format!("nameserver {}\n", ip))
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+enum Block {
+    /// `iface` stanza with its indented options.
+    Stanza {
+        name: String,
+        method: String,
+        options: Vec<String>,
+    },
+    /// Any other line is left as is.
+    Line(String),
+}
+
+/// Minimal model of an ifupdown `interfaces(5)` file. Only the stanzas that
+/// bmcd manages are changed, everything else is written back unaltered.
+#[derive(Debug, PartialEq)]
+struct Interfaces(Vec<Block>);
+
+const ADDRESS_OPTIONS: [&str; 3] = ["address", "netmask", "gateway"];
+
+impl Interfaces {
+    fn parse(content: &str) -> Self {
+        let mut blocks = Vec::new();
+        for line in content.lines() {
+            let is_option = line.starts_with(char::is_whitespace) && !line.trim().is_empty();
+            if let (true, Some(Block::Stanza { options, .. })) = (is_option, blocks.last_mut()) {
+                options.push(line.trim().to_string());
+                continue;
+            }
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["iface", name, "inet", method] => blocks.push(Block::Stanza {
+                    name: name.to_string(),
+                    method: method.to_string(),
+                    options: Vec::new(),
+                }),
+                _ => blocks.push(Block::Line(line.to_string())),
+            }
+        }
+        Interfaces(blocks)
+    }
+
+    fn stanza(&self, name: &str) -> Option<(&str, &[String])> {
+        self.0.iter().find_map(|b| match b {
+            Block::Stanza {
+                name: n,
+                method,
+                options,
+            } if n == name => Some((method.as_str(), options.as_slice())),
+            _ => None,
+        })
+    }
+
+    fn settings(&self, interface: &str) -> (Ipv4Settings, Option<u16>) {
+        let vlan = self.0.iter().find_map(|b| match b {
+            Block::Stanza { name, .. } => name
+                .strip_prefix(interface)
+                .and_then(|n| n.strip_prefix('.'))
+                .and_then(|id| id.parse::<u16>().ok()),
+            _ => None,
+        });
+
+        let name = vlan.map_or(interface.to_string(), |id| format!("{interface}.{id}"));
+        let ipv4 = match self.stanza(&name) {
+            Some(("static", options)) => {
+                let option = |key: &str| {
+                    options.iter().find_map(|o| {
+                        o.strip_prefix(key)
+                            .filter(|v| v.starts_with(' '))
+                            .map(str::trim)
+                    })
+                };
+                let address: Option<Ipv4Addr> = option("address")
+                    .and_then(|a| a.split('/').next())
+                    .and_then(|a| a.parse().ok());
+                let prefix_len = option("address")
+                    .and_then(|a| a.split_once('/'))
+                    .and_then(|(_, p)| p.parse().ok())
+                    .or_else(|| {
+                        option("netmask")
+                            .and_then(|m| m.parse::<Ipv4Addr>().ok())
+                            .map(|m| u32::from(m).count_ones() as u8)
+                    })
+                    .unwrap_or(24);
+                match address {
+                    Some(address) => Ipv4Settings::Static {
+                        address,
+                        prefix_len,
+                        gateway: option("gateway").and_then(|g| g.parse().ok()),
+                    },
+                    None => Ipv4Settings::Dhcp,
+                }
+            }
+            _ => Ipv4Settings::Dhcp,
+        };
+        (ipv4, vlan)
+    }
+
+    fn apply(&mut self, interface: &str, settings: &NetworkSettings) {
+        let vlan_prefix = format!("{interface}.");
+        // drop previously configured VLANs, including their `auto` lines
+        self.0.retain(|b| match b {
+            Block::Stanza { name, .. } => !name.starts_with(&vlan_prefix),
+            Block::Line(l) => !l.split_whitespace().any(|w| w.starts_with(&vlan_prefix)),
+        });
+
+        let (method, address_options) = match &settings.ipv4 {
+            Ipv4Settings::Dhcp => ("dhcp", Vec::new()),
+            Ipv4Settings::Static {
+                address,
+                prefix_len,
+                gateway,
+            } => {
+                let mut options = vec![
+                    format!("address {}", address),
+                    format!("netmask {}", prefix_to_netmask(*prefix_len)),
+                ];
+                if let Some(gateway) = gateway {
+                    options.push(format!("gateway {}", gateway));
+                }
+                ("static", options)
+            }
+        };
+
+        let (bridge_method, bridge_address) = match settings.vlan {
+            Some(_) => ("manual", Vec::new()),
+            None => (method, address_options.clone()),
+        };
+
+        let mut found = false;
+        for block in &mut self.0 {
+            if let Block::Stanza {
+                name,
+                method,
+                options,
+            } = block
+            {
+                if name == interface {
+                    found = true;
+                    *method = bridge_method.to_string();
+                    options.retain(|o| !is_address_option(o));
+                    options.extend(bridge_address.iter().cloned());
+                }
+            }
+        }
+
+        if !found {
+            self.0.push(Block::Line(format!("auto {interface}")));
+            self.0.push(Block::Stanza {
+                name: interface.to_string(),
+                method: bridge_method.to_string(),
+                options: bridge_address,
+            });
+        }
+
+        if let Some(id) = settings.vlan {
+            let name = format!("{interface}.{id}");
+            let mut options = vec![format!("vlan-raw-device {}", interface)];
+            options.extend(address_options);
+            if !matches!(self.0.last(), Some(Block::Line(l)) if l.is_empty()) {
+                self.0.push(Block::Line(String::new()));
+            }
+            self.0.push(Block::Line(format!("auto {name}")));
+            self.0.push(Block::Stanza {
+                name,
+                method: method.to_string(),
+                options,
+            });
+        }
+    }
+}
+
+impl std::fmt::Display for Interfaces {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for block in &self.0 {
+            match block {
+                Block::Line(line) => writeln!(f, "{}", line)?,
+                Block::Stanza {
+                    name,
+                    method,
+                    options,
+                } => {
+                    writeln!(f, "iface {} inet {}", name, method)?;
+                    for option in options {
+                        writeln!(f, "    {}", option)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_address_option(option: &str) -> bool {
+    option
+        .split_whitespace()
+        .next()
+        .is_some_and(|key| ADDRESS_OPTIONS.contains(&key))
+}
+
+fn prefix_to_netmask(prefix_len: u8) -> Ipv4Addr {
+    let mask = u32::MAX
+        .checked_shl(32 - u32::from(prefix_len))
+        .unwrap_or(0);
+    Ipv4Addr::from(mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERFACES: &str = "auto lo\n\
+        iface lo inet loopback\n\
+        \n\
+        auto br0\n\
+        iface br0 inet dhcp\n\
+        \x20   bridge_ports eth0 node1 node2\n";
+
+    fn settings(ipv4: Ipv4Settings, vlan: Option<u16>) -> NetworkSettings {
+        NetworkSettings {
+            ipv4,
+            hostname: None,
+            dns: Vec::new(),
+            vlan,
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let interfaces = Interfaces::parse(INTERFACES);
+        assert_eq!(interfaces.to_string(), INTERFACES);
+        assert_eq!(interfaces.settings("br0"), (Ipv4Settings::Dhcp, None));
+    }
+
+    #[test]
+    fn static_address() {
+        let ipv4 = Ipv4Settings::Static {
+            address: Ipv4Addr::new(192, 168, 1, 10),
+            prefix_len: 24,
+            gateway: Some(Ipv4Addr::new(192, 168, 1, 1)),
+        };
+        let mut interfaces = Interfaces::parse(INTERFACES);
+        interfaces.apply("br0", &settings(ipv4.clone(), None));
+
+        let rendered = interfaces.to_string();
+        assert!(rendered.contains("bridge_ports eth0 node1 node2"));
+        assert!(rendered.contains("netmask 255.255.255.0"));
+        assert_eq!(
+            Interfaces::parse(&rendered).settings("br0"),
+            (ipv4.clone(), None)
+        );
+
+        // switching back to DHCP removes the static options
+        interfaces.apply("br0", &settings(Ipv4Settings::Dhcp, None));
+        assert_eq!(interfaces.to_string(), INTERFACES);
+    }
+
+    #[test]
+    fn vlan() {
+        let mut interfaces = Interfaces::parse(INTERFACES);
+        interfaces.apply("br0", &settings(Ipv4Settings::Dhcp, Some(42)));
+        let rendered = interfaces.to_string();
+        assert!(rendered.contains("iface br0 inet manual"));
+        assert!(rendered.contains("auto br0.42\niface br0.42 inet dhcp"));
+        assert_eq!(
+            Interfaces::parse(&rendered).settings("br0"),
+            (Ipv4Settings::Dhcp, Some(42))
+        );
+
+        interfaces.apply("br0", &settings(Ipv4Settings::Dhcp, Some(7)));
+        assert!(!interfaces.to_string().contains("br0.42"));
+    }
+
+    #[test]
+    fn nameservers() {
+        let dns = parse_nameservers("# comment\nnameserver 1.1.1.1\nnameserver ::1\n");
+        assert_eq!(dns.len(), 2);
+        assert_eq!(
+            render_nameservers(&dns),
+            "nameserver 1.1.1.1\nnameserver ::1\n"
+        );
+        assert_eq!(prefix_to_netmask(0), Ipv4Addr::new(0, 0, 0, 0));
+        assert_eq!(prefix_to_netmask(20), Ipv4Addr::new(255, 255, 240, 0));
+    }
+}
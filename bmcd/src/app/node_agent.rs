@@ -0,0 +1,387 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Channel to an optional agent in the OS of a node, over the node UART. It
+//! tells bmcd the hostname, addresses and load of the node and shuts the node
+//! down cleanly, without depending on the network of the node.
+//!
+//! Messages are JSON objects in frames that are wrapped in an APC escape
+//! sequence, which terminals do not display, so frames do not disturb the
+//! console:
+//!
+//! ```text
+//! ESC _ tpi1 ; <json> ; <crc32> ESC \
+//! ```
+//!
+//! `<crc32>` is the CRC-32/ISO-HDLC of the JSON text, as 8 hexadecimal digits.
+//! Frames with a bad checksum are dropped. bmcd sends `{"type":"query","id":1}`
+//! and `{"type":"shutdown","id":2,"reboot":false}`. The agent answers a query
+//! with a `report` and a shutdown with an `ack` or an `error`, each with the
+//! `id` of the request:
+//!
+//! ```json
+//! {"type":"report","id":1,"hostname":"node1","addresses":["10.0.0.21"],
+//!  "load":[0.12,0.08,0.01],"uptime":3600}
+//! {"type":"error","id":2,"message":"shutdown inhibited"}
+//! ```
+//!
+//! Agents may also send reports without `id` on their own, e.g. at boot and
+//! every minute, which keeps the inventory current without requests.
+use crate::config;
+use crate::error::BmcError;
+use crate::hal::NodeId;
+use crate::serial_service::serial::SerialConnections;
+use anyhow::{anyhow, bail, ensure, Context};
+use bytes::Bytes;
+use crc::{Crc, CRC_32_ISO_HDLC};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+const START: &[u8] = b"\x1b_tpi1;";
+const END: &[u8] = b"\x1b\\";
+/// Longer frames are dropped.
+const MAX_FRAME: usize = 4096;
+static CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AgentInfo {
+    pub hostname: String,
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// load averages over 1, 5 and 15 minutes
+    #[serde(default)]
+    pub load: Option<[f32; 3]>,
+    /// seconds since boot
+    #[serde(default)]
+    pub uptime: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Query {
+        id: u32,
+    },
+    Shutdown {
+        id: u32,
+        #[serde(default)]
+        reboot: bool,
+    },
+    Report {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u32>,
+        #[serde(flatten)]
+        info: AgentInfo,
+    },
+    Ack {
+        id: u32,
+    },
+    Error {
+        id: u32,
+        message: String,
+    },
+}
+
+impl Message {
+    fn id(&self) -> Option<u32> {
+        match self {
+            Message::Query { id }
+            | Message::Shutdown { id, .. }
+            | Message::Ack { id }
+            | Message::Error { id, .. } => Some(*id),
+            Message::Report { id, .. } => *id,
+        }
+    }
+}
+
+pub fn encode(message: &Message) -> Bytes {
+    let json = serde_json::to_string(message).expect("messages serialize");
+    let mut frame = Vec::with_capacity(START.len() + json.len() + 9 + END.len());
+    frame.extend_from_slice(START);
+    frame.extend_from_slice(json.as_bytes());
+    frame.extend_from_slice(format!(";{:08x}", CRC32.checksum(json.as_bytes())).as_bytes());
+    frame.extend_from_slice(END);
+    frame.into()
+}
+
+fn decode(payload: &[u8]) -> anyhow::Result<Message> {
+    let split = payload
+        .iter()
+        .rposition(|b| *b == b';')
+        .context("missing checksum")?;
+    let (json, checksum) = (&payload[..split], &payload[split + 1..]);
+    let checksum = std::str::from_utf8(checksum)
+        .ok()
+        .filter(|c| c.len() == 8)
+        .and_then(|c| u32::from_str_radix(c, 16).ok())
+        .context("malformed checksum")?;
+    ensure!(CRC32.checksum(json) == checksum, "checksum mismatch");
+    Ok(serde_json::from_slice(json)?)
+}
+
+/// Picks frames out of console output, which may split them in any place.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    /// Returns the messages of the frames that `bytes` completed.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Message> {
+        self.buffer.extend_from_slice(bytes);
+        let mut messages = Vec::new();
+        loop {
+            let Some(start) = find(&self.buffer, START) else {
+                // keep what may be the beginning of the next frame
+                let keep = (1..START.len())
+                    .rev()
+                    .find(|len| self.buffer.ends_with(&START[..*len]))
+                    .unwrap_or(0);
+                self.buffer.drain(..self.buffer.len() - keep);
+                break;
+            };
+            self.buffer.drain(..start);
+            let Some(end) = find(&self.buffer[START.len()..], END) else {
+                if self.buffer.len() > MAX_FRAME {
+                    self.buffer.drain(..START.len());
+                    continue;
+                }
+                break;
+            };
+            let frame: Vec<u8> = self.buffer.drain(..START.len() + end + END.len()).collect();
+            match decode(&frame[START.len()..frame.len() - END.len()]) {
+                Ok(message) => messages.push(message),
+                Err(e) => tracing::debug!("dropping agent frame: {:#}", e),
+            }
+        }
+        messages
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentStatus {
+    #[serde(flatten)]
+    pub info: AgentInfo,
+    /// unix time of the last report
+    pub reported_at: u64,
+}
+
+pub struct NodeAgents {
+    config: config::NodeAgent,
+    serials: Arc<SerialConnections>,
+    status: Mutex<Vec<Option<AgentStatus>>>,
+    messages: broadcast::Sender<(NodeId, Message)>,
+    next_id: AtomicU32,
+}
+
+impl NodeAgents {
+    pub fn new(config: config::NodeAgent, serials: Arc<SerialConnections>, nodes: usize) -> Self {
+        NodeAgents {
+            config,
+            serials,
+            status: Mutex::new(vec![None; nodes]),
+            messages: broadcast::channel(16).0,
+            next_id: AtomicU32::new(1),
+        }
+    }
+
+    /// Listens for agent frames on the UARTs of all nodes.
+    pub fn run(self: &Arc<Self>) {
+        if !self.config.enabled {
+            return;
+        }
+        for node in self.nodes() {
+            let stream = match self.serials[node].open_channel() {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::warn!("not listening for the agent of {}: {}", node, e);
+                    continue;
+                }
+            };
+            let agents = self.clone();
+            tokio::spawn(async move { agents.listen(node, stream).await });
+        }
+    }
+
+    async fn listen(&self, node: NodeId, stream: impl Stream<Item = io::Result<Bytes>>) {
+        let mut decoder = FrameDecoder::default();
+        let mut stream = std::pin::pin!(stream);
+        while let Some(Ok(bytes)) = stream.next().await {
+            for message in decoder.push(&bytes) {
+                self.receive(node, message);
+            }
+        }
+        tracing::warn!("stopped listening for the agent of {}", node);
+    }
+
+    fn receive(&self, node: NodeId, message: Message) {
+        if let Message::Report { info, .. } = &message {
+            self.status.lock().unwrap()[node as usize] = Some(AgentStatus {
+                info: info.clone(),
+                reported_at: crate::utils::get_timestamp_unix().unwrap_or_default(),
+            });
+        }
+        // nobody waits for an answer when there are no receivers
+        let _ = self.messages.send((node, message));
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = NodeId> {
+        let count = self.status.lock().unwrap().len() as u8;
+        (0..count).filter_map(|idx| NodeId::try_from(idx).ok())
+    }
+
+    /// Last report of the agent of `node`, if it ever sent one.
+    pub fn status(&self, node: NodeId) -> Option<AgentStatus> {
+        self.status
+            .lock()
+            .unwrap()
+            .get(node as usize)
+            .cloned()
+            .flatten()
+    }
+
+    /// Asks the agent of `node` for a fresh report.
+    pub async fn query(&self, node: NodeId) -> anyhow::Result<AgentStatus> {
+        match self.request(node, |id| Message::Query { id }).await? {
+            Message::Report { .. } => self.status(node).context("report was not recorded"),
+            Message::Error { message, .. } => bail!("agent of {}: {}", node, message),
+            other => bail!("unexpected answer of the agent of {}: {:?}", node, other),
+        }
+    }
+
+    /// Asks the agent of `node` to shut the OS down, or to reboot it.
+    pub async fn shutdown(&self, node: NodeId, reboot: bool) -> anyhow::Result<()> {
+        match self
+            .request(node, |id| Message::Shutdown { id, reboot })
+            .await?
+        {
+            Message::Ack { .. } => Ok(()),
+            Message::Error { message, .. } => bail!("agent of {} refused: {}", node, message),
+            other => bail!("unexpected answer of the agent of {}: {:?}", node, other),
+        }
+    }
+
+    async fn request(
+        &self,
+        node: NodeId,
+        request: impl FnOnce(u32) -> Message,
+    ) -> anyhow::Result<Message> {
+        if !self.config.enabled {
+            return Err(BmcError::NotSupported("node agents are disabled".into()).into());
+        }
+        if !self.nodes().any(|n| n == node) {
+            return Err(BmcError::NoSuchNode(node).into());
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut messages = self.messages.subscribe();
+        self.serials[node]
+            .write(encode(&request(id)))
+            .await
+            .with_context(|| format!("writing to the UART of {}", node))?;
+
+        let answer = async {
+            loop {
+                match messages.recv().await {
+                    Ok((from, message)) if from == node && message.id() == Some(id) => {
+                        return Ok(message)
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => bail!("not listening for agents"),
+                }
+            }
+        };
+        tokio::time::timeout(self.config.timeout, answer)
+            .await
+            .map_err(|_| anyhow!("the agent of {} did not answer", node))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_in_console_output() {
+        let report = Message::Report {
+            id: None,
+            info: AgentInfo {
+                hostname: "node1".to_string(),
+                addresses: vec!["10.0.0.21".to_string()],
+                load: Some([0.5, 0.25, 0.0]),
+                uptime: Some(3600),
+            },
+        };
+        let ack = Message::Ack { id: 7 };
+        let mut output = b"login: ".to_vec();
+        output.extend_from_slice(&encode(&report));
+        output.extend_from_slice(b"\r\n");
+        output.extend_from_slice(&encode(&ack));
+
+        // split the output in every place a UART read could
+        for split in 0..output.len() {
+            let mut decoder = FrameDecoder::default();
+            let mut messages = decoder.push(&output[..split]);
+            messages.extend(decoder.push(&output[split..]));
+            assert_eq!(messages, vec![report.clone(), ack.clone()]);
+        }
+    }
+
+    #[test]
+    fn corrupted_frames_are_dropped() {
+        let mut frame = encode(&Message::Ack { id: 7 }).to_vec();
+        frame[START.len() + 2] = b'X';
+        let mut decoder = FrameDecoder::default();
+        assert!(decoder.push(&frame).is_empty());
+
+        let frame = b"\x1b_tpi1;{\"type\":\"ack\",\"id\":7}\x1b\\";
+        assert!(decoder.push(frame).is_empty());
+
+        let mut decoder = FrameDecoder::default();
+        assert!(decoder.push(START).is_empty());
+        assert!(decoder.push(&[b'a'; MAX_FRAME]).is_empty());
+        assert_eq!(
+            decoder.push(&encode(&Message::Ack { id: 8 })),
+            vec![Message::Ack { id: 8 }]
+        );
+    }
+
+    #[test]
+    fn wire_format() {
+        let frame = encode(&Message::Shutdown {
+            id: 2,
+            reboot: true,
+        });
+        let json = br#"{"type":"shutdown","id":2,"reboot":true}"#;
+        let mut expected = START.to_vec();
+        expected.extend_from_slice(json);
+        expected.extend_from_slice(format!(";{:08x}", CRC32.checksum(json)).as_bytes());
+        expected.extend_from_slice(END);
+        assert_eq!(frame, expected);
+
+        let report: Message = serde_json::from_str(
+            r#"{"type":"report","id":1,"hostname":"node1","load":[0.12,0.08,0.01]}"#,
+        )
+        .unwrap();
+        assert_eq!(report.id(), Some(1));
+    }
+}
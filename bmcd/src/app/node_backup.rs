@@ -0,0 +1,586 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Backups of the storage of a node, for archiving or cloning it to other
+//! nodes. The node is booted into its USB flashing mode like for a flash and
+//! its storage is streamed to the client as it is read. On slow uplinks the
+//! stream can be zstd compressed on the fly, which trades CPU time of the BMC
+//! for transfer time. Each backup is a job whose result holds the SHA-256 of
+//! the storage and of the transferred stream, so that the client can verify
+//! the download.
+//!
+//! A clone copies the storage of one node to another without the data leaving
+//! the board. As only one node can be in flashing mode at a time, the source is
+//! first read into a zstd compressed file in the backup directory, within the
+//! backups quota of `app::storage_manager`, which is then written to the
+//! target and verified like a flash. Both nodes end up with the
+//! same hostname and machine-id; changing them is up to the user.
+use super::bmc_application::BmcApplication;
+use super::flash_history::{self, FlashRecord};
+use super::jobs::{JobId, JobKind, Jobs, Outcome, Progress};
+use super::storage_manager::{ensure_room, Feature, QuotaWriter};
+use crate::config;
+use crate::error::BmcError;
+use crate::hal::{NodeId, UsbRoute};
+use crate::utils::{checksum_block_device, get_timestamp_unix, write_block_device, ChannelWriter};
+use anyhow::{bail, ensure};
+use async_compression::tokio::bufread::ZstdDecoder;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::BufReader;
+use tokio::sync::watch;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
+
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+/// Higher levels need more memory than the BMC can spare.
+pub const MAX_ZSTD_LEVEL: i32 = 19;
+const READ_SIZE: usize = 1024 * 1024;
+/// At most `CHUNK_SIZE * CHUNK_DEPTH` bytes wait for a slow client.
+const CHUNK_SIZE: usize = 256 * 1024;
+const CHUNK_DEPTH: usize = 4;
+/// The staging file is read back right away, so speed matters more than size.
+const CLONE_ZSTD_LEVEL: i32 = 1;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackupOptions {
+    pub compression: Compression,
+    /// zstd level, 1 to [`MAX_ZSTD_LEVEL`]
+    pub level: i32,
+    /// compute the SHA-256 of the storage and of the stream
+    pub hash: bool,
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        Self {
+            compression: Compression::None,
+            level: DEFAULT_ZSTD_LEVEL,
+            hash: true,
+        }
+    }
+}
+
+impl BackupOptions {
+    pub fn file_name(&self, node: NodeId) -> String {
+        let extension = match self.compression {
+            Compression::None => "img",
+            Compression::Zstd => "img.zst",
+        };
+        format!(
+            "node{}-{}.{}",
+            node as u8 + 1,
+            chrono::Local::now().format("%Y-%m-%d"),
+            extension
+        )
+    }
+}
+
+/// A running backup.
+pub struct Backup {
+    pub job: JobId,
+    /// size of the storage of the node
+    pub size: u64,
+    pub stream: ReceiverStream<io::Result<Bytes>>,
+}
+
+/// Only one node can be in flashing mode at a time, so backups and clones run
+/// one at a time as well.
+pub struct NodeBackups {
+    bmc: Arc<BmcApplication>,
+    jobs: Arc<Jobs>,
+    running: Arc<AtomicBool>,
+    storage: config::Storage,
+}
+
+impl NodeBackups {
+    pub fn new(bmc: Arc<BmcApplication>, jobs: Arc<Jobs>, storage: config::Storage) -> Self {
+        Self {
+            bmc,
+            jobs,
+            running: Arc::new(AtomicBool::new(false)),
+            storage,
+        }
+    }
+
+    /// Boots `node` into flashing mode and opens its storage. On failure the
+    /// node is restored and the running flag cleared.
+    async fn open_storage(&self, node: NodeId) -> anyhow::Result<(File, u64)> {
+        let opened = async {
+            let device = self.bmc.node_in_flash(node, UsbRoute::Bmc).await?;
+            let mut file = File::open(&device)?;
+            let size = file.seek(SeekFrom::End(0))?;
+            file.rewind()?;
+            anyhow::Ok((file, size))
+        }
+        .await;
+        if opened.is_err() {
+            restore_node(&self.bmc, node).await;
+            self.running.store(false, Ordering::SeqCst);
+        }
+        opened
+    }
+
+    /// Boots `node` into flashing mode and starts streaming its storage. The
+    /// node is powered off when the backup ends, whether it succeeded or not.
+    pub async fn start(&self, node: NodeId, options: BackupOptions) -> anyhow::Result<Backup> {
+        if !(1..=MAX_ZSTD_LEVEL).contains(&options.level) {
+            return Err(BmcError::invalid_parameter(
+                "level",
+                format!("must be 1 to {}", MAX_ZSTD_LEVEL),
+            )
+            .into());
+        }
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err(BmcError::Busy("another backup or clone".into()).into());
+        }
+
+        let (file, size) = self.open_storage(node).await?;
+
+        let id = self.jobs.next_id();
+        let cancel = CancellationToken::new();
+        let (progress, processed) = watch::channel(0u64);
+        self.jobs.add(
+            id,
+            JobKind::Backup,
+            format!("backup of the storage of {}", node),
+            cancel.clone(),
+            Some(Progress {
+                processed,
+                total: size,
+            }),
+        );
+
+        let (writer, stream) = ChannelWriter::new(CHUNK_SIZE, CHUNK_DEPTH);
+        let (bmc, jobs, running) = (self.bmc.clone(), self.jobs.clone(), self.running.clone());
+        tokio::spawn(async move {
+            let result = tokio::task::spawn_blocking(move || {
+                let mut output = HashingWriter::new(writer, options.hash);
+                let result = copy_storage(file, &mut output, options, &progress, &cancel);
+                match &result {
+                    Ok(_) => result.map(|summary| summary.with_output(&output)),
+                    Err(e) => {
+                        output.inner.fail(io::Error::new(e.kind(), e.to_string()));
+                        result
+                    }
+                }
+            })
+            .await;
+
+            let outcome = match result {
+                Ok(Ok(summary)) => {
+                    tracing::info!("backup of {} done, {} bytes", node, summary.size);
+                    Outcome::Succeeded(Some(json!({
+                        "file_name": options.file_name(node),
+                        "size": summary.size,
+                        "sha256": summary.sha256,
+                        "compression": options.compression,
+                        "transferred": summary.transferred,
+                        "transferred_sha256": summary.transferred_sha256,
+                    })))
+                }
+                Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => Outcome::Cancelled,
+                Ok(Err(e)) => Outcome::Failed(format!("{}", e)),
+                Err(e) => Outcome::Failed(format!("{}", e)),
+            };
+            restore_node(&bmc, node).await;
+            running.store(false, Ordering::SeqCst);
+            jobs.finish(id, outcome).await;
+        });
+
+        Ok(Backup {
+            job: id,
+            size,
+            stream,
+        })
+    }
+
+    /// Starts copying the storage of `source` to `target`, returning the id
+    /// of its job. The progress of the job covers reading the source and
+    /// writing the target, so its total is twice the size of the storage.
+    /// Both nodes are powered off when the clone ends.
+    pub async fn start_clone(&self, source: NodeId, target: NodeId) -> anyhow::Result<JobId> {
+        if source == target {
+            return Err(
+                BmcError::invalid_parameter("target", "must differ from the source").into(),
+            );
+        }
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err(BmcError::Busy("another backup or clone".into()).into());
+        }
+        // the compressed size is not known up front, the quota is enforced
+        // again while staging
+        if let Err(e) = ensure_room(&self.storage, Feature::Backups, 1) {
+            self.running.store(false, Ordering::SeqCst);
+            return Err(e);
+        }
+        let (file, size) = self.open_storage(source).await?;
+
+        let id = self.jobs.next_id();
+        let cancel = CancellationToken::new();
+        let (progress, processed) = watch::channel(0u64);
+        self.jobs.add(
+            id,
+            JobKind::Clone,
+            format!("clone of {} to {}", source, target),
+            cancel.clone(),
+            Some(Progress {
+                processed,
+                total: 2 * size,
+            }),
+        );
+
+        let staging = self
+            .storage
+            .backup_dir
+            .join(format!("bmcd-clone-{}.img.zst", id));
+        let storage = self.storage.clone();
+        let (bmc, jobs, running) = (self.bmc.clone(), self.jobs.clone(), self.running.clone());
+        tokio::spawn(async move {
+            let started = get_timestamp_unix().unwrap_or_default();
+            let start = Instant::now();
+            let staged = stage(file, &staging, &storage, &progress, &cancel).await;
+            restore_node(&bmc, source).await;
+
+            let result = match staged {
+                Ok(summary) => {
+                    let written =
+                        write_staged(&bmc, target, &staging, size, &progress, &cancel).await;
+                    restore_node(&bmc, target).await;
+                    flash_history::record(
+                        &bmc,
+                        FlashRecord {
+                            node: target as u8 + 1,
+                            file_name: format!("clone of node {}", source as u8 + 1),
+                            size,
+                            started,
+                            duration_ms: start.elapsed().as_millis() as u64,
+                            sha256: summary.sha256.clone(),
+                            expected: None,
+                            error: written.as_ref().err().map(|e| format!("{:#}", e)),
+                        },
+                    )
+                    .await;
+                    written.map(|_| summary)
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = tokio::fs::remove_file(&staging).await {
+                if e.kind() != io::ErrorKind::NotFound {
+                    tracing::warn!("removing {}: {}", staging.display(), e);
+                }
+            }
+
+            let outcome = match result {
+                Ok(summary) => {
+                    tracing::info!("cloned {} to {}, {} bytes", source, target, size);
+                    Outcome::Succeeded(Some(json!({
+                        "source": source as u8 + 1,
+                        "target": target as u8 + 1,
+                        "size": size,
+                        "sha256": summary.sha256,
+                        "staged_size": summary.transferred,
+                    })))
+                }
+                Err(_) if cancel.is_cancelled() => Outcome::Cancelled,
+                Err(e) => Outcome::Failed(format!("{:#}", e)),
+            };
+            running.store(false, Ordering::SeqCst);
+            jobs.finish(id, outcome).await;
+        });
+
+        Ok(id)
+    }
+}
+
+/// Reads `source` into a compressed file at `staging`.
+async fn stage(
+    source: File,
+    staging: &Path,
+    storage: &config::Storage,
+    progress: &watch::Sender<u64>,
+    cancel: &CancellationToken,
+) -> anyhow::Result<Summary> {
+    let (staging, progress, cancel) = (staging.to_owned(), progress.clone(), cancel.clone());
+    let storage = storage.clone();
+    tokio::task::spawn_blocking(move || {
+        if let Some(dir) = staging.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = File::create(&staging)
+            .map_err(|e| anyhow::anyhow!("creating {}: {}", staging.display(), e))?;
+        let file = QuotaWriter::new(BufWriter::new(file), &storage, Feature::Backups);
+        let mut output = HashingWriter::new(file, false);
+        let options = BackupOptions {
+            compression: Compression::Zstd,
+            level: CLONE_ZSTD_LEVEL,
+            hash: true,
+        };
+        let summary =
+            copy_storage(source, &mut output, options, &progress, &cancel)?.with_output(&output);
+        output.inner.inner.into_inner()?.sync_all()?;
+        Ok(summary)
+    })
+    .await?
+}
+
+/// Writes the staged copy to the storage of `target` and verifies it.
+/// Progress continues from `size`, where reading the source ended.
+async fn write_staged(
+    bmc: &BmcApplication,
+    target: NodeId,
+    staging: &Path,
+    size: u64,
+    progress: &watch::Sender<u64>,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    let device = bmc.node_in_flash(target, UsbRoute::Bmc).await?;
+    let capacity = File::open(&device)?.seek(SeekFrom::End(0))?;
+    ensure!(
+        capacity >= size,
+        "the storage of {} is smaller than that of the source ({} < {} bytes)",
+        target,
+        capacity,
+        size
+    );
+
+    let reader = ZstdDecoder::new(BufReader::new(tokio::fs::File::open(staging).await?));
+    let (written_tx, mut written_rx) = watch::channel(0u64);
+    let (throughput, _) = watch::channel(0u64);
+    let forward = {
+        let progress = progress.clone();
+        tokio::spawn(async move {
+            while written_rx.changed().await.is_ok() {
+                let written = *written_rx.borrow_and_update();
+                progress.send_replace(size + written);
+            }
+        })
+    };
+    let written = write_block_device(reader, &device, &written_tx, &throughput, cancel).await;
+    forward.abort();
+    let written = written?;
+    if written.bytes != size {
+        bail!("wrote {} of {} bytes", written.bytes, size);
+    }
+
+    let (verify_progress, _) = watch::channel(0u64);
+    let crc = checksum_block_device(&device, written.bytes, &verify_progress, cancel).await?;
+    ensure!(
+        crc == written.crc,
+        "the storage of {} does not verify",
+        target
+    );
+    Ok(())
+}
+
+/// Powers the node off and restores the USB mode, like after a flash.
+async fn restore_node(bmc: &BmcApplication, node: NodeId) {
+    let result = async {
+        bmc.activate_slot(node.to_inverse_bitfield(), node.to_bitfield())
+            .await?;
+        bmc.usb_boot(node, false).await?;
+        let (mode, _) = bmc.get_usb_mode().await;
+        bmc.configure_usb(mode).await
+    }
+    .await;
+    if let Err(e) = result {
+        tracing::error!("restoring {}: {:#}", node, e);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+    size: u64,
+    sha256: Option<String>,
+    transferred: u64,
+    transferred_sha256: Option<String>,
+}
+
+impl Summary {
+    fn with_output<W>(mut self, output: &HashingWriter<W>) -> Self {
+        self.transferred = output.written;
+        self.transferred_sha256 = output.hasher.clone().map(|h| hex::encode(h.finalize()));
+        self
+    }
+}
+
+/// Counts and optionally hashes what is written through it.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Option<Sha256>,
+    written: u64,
+}
+
+impl<W> HashingWriter<W> {
+    fn new(inner: W, hash: bool) -> Self {
+        Self {
+            inner,
+            hasher: hash.then(Sha256::new),
+            written: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..len]);
+        }
+        self.written += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Copies `source` to `output`, compressed as `options` say. Cancelling
+/// fails with [`io::ErrorKind::Interrupted`].
+fn copy_storage<W: Write>(
+    mut source: impl Read,
+    output: &mut HashingWriter<W>,
+    options: BackupOptions,
+    progress: &watch::Sender<u64>,
+    cancel: &CancellationToken,
+) -> io::Result<Summary> {
+    let mut hasher = options.hash.then(Sha256::new);
+    let mut copy = |sink: &mut dyn Write| {
+        let mut buffer = vec![0u8; READ_SIZE];
+        let mut size = 0u64;
+        loop {
+            if cancel.is_cancelled() {
+                return Err(io::Error::from(io::ErrorKind::Interrupted));
+            }
+            let n = match source.read(&mut buffer) {
+                Ok(0) => return Ok(size),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if let Some(hasher) = &mut hasher {
+                hasher.update(&buffer[..n]);
+            }
+            sink.write_all(&buffer[..n])?;
+            size += n as u64;
+            progress.send_replace(size);
+        }
+    };
+
+    let size = match options.compression {
+        Compression::None => copy(output)?,
+        Compression::Zstd => {
+            let mut encoder = zstd::Encoder::new(&mut *output, options.level)?;
+            let size = copy(&mut encoder)?;
+            encoder.finish()?;
+            size
+        }
+    };
+    output.flush()?;
+
+    Ok(Summary {
+        size,
+        sha256: hasher.map(|h| hex::encode(h.finalize())),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    fn copy(data: &[u8], options: BackupOptions) -> (Summary, Vec<u8>) {
+        let (progress, receiver) = watch::channel(0);
+        let mut output = HashingWriter::new(Vec::new(), options.hash);
+        let summary = copy_storage(
+            data,
+            &mut output,
+            options,
+            &progress,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        assert_eq!(*receiver.borrow(), data.len() as u64);
+        (summary.with_output(&output), output.inner)
+    }
+
+    #[test]
+    fn zstd_stream() {
+        // compressible, but not trivially
+        let mut data = vec![0u8; 3 * READ_SIZE + 17];
+        rand::rng().fill_bytes(&mut data[..READ_SIZE]);
+
+        let options = BackupOptions {
+            compression: Compression::Zstd,
+            ..Default::default()
+        };
+        let (summary, compressed) = copy(&data, options);
+        assert_eq!(summary.size, data.len() as u64);
+        assert_eq!(summary.transferred, compressed.len() as u64);
+        assert!(compressed.len() < data.len() / 2);
+        assert_eq!(zstd::decode_all(compressed.as_slice()).unwrap(), data);
+        assert_eq!(summary.sha256.unwrap(), hex::encode(Sha256::digest(&data)));
+        assert_eq!(
+            summary.transferred_sha256.unwrap(),
+            hex::encode(Sha256::digest(&compressed))
+        );
+    }
+
+    #[test]
+    fn plain_stream_without_hash() {
+        let data = vec![0x5au8; READ_SIZE + 1];
+        let options = BackupOptions {
+            hash: false,
+            ..Default::default()
+        };
+        let (summary, copied) = copy(&data, options);
+        assert_eq!(copied, data);
+        assert_eq!(summary.transferred, data.len() as u64);
+        assert!(summary.sha256.is_none());
+        assert!(summary.transferred_sha256.is_none());
+    }
+
+    #[test]
+    fn cancelled() {
+        let (progress, _) = watch::channel(0);
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let mut output = HashingWriter::new(Vec::new(), true);
+        let result = copy_storage(
+            &[0u8; 16][..],
+            &mut output,
+            BackupOptions::default(),
+            &progress,
+            &cancel,
+        );
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Interrupted);
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Sideband signals of the nodes next to their power, such as the USB boot
+//! straps, the presence detection and board specific sleep or wake lines.
+//! Which pins exist and what clients may do with them comes from the board
+//! profile, see [`BoardProfile::pins`].
+use super::bmc_application::BmcApplication;
+use crate::error::BmcError;
+use crate::hal::board_profile::{BoardProfile, NodePin, PinAccess};
+use crate::hal::NodeId;
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct PinState {
+    pub name: &'static str,
+    pub access: PinAccess,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Reads all pins of `node`. A pin that cannot be read is reported with the
+/// error instead of failing the whole list.
+pub fn list_pins(bmc: &BmcApplication, node: NodeId) -> anyhow::Result<Vec<PinState>> {
+    check_node(bmc.board(), node)?;
+    Ok(bmc
+        .board()
+        .pins()
+        .into_iter()
+        .map(|pin| {
+            let (value, error) = match bmc.read_pin(node, pin.name) {
+                Ok(value) => (Some(value), None),
+                Err(e) => (None, Some(format!("{:#}", e))),
+            };
+            PinState {
+                name: pin.name,
+                access: pin.access,
+                value,
+                error,
+            }
+        })
+        .collect())
+}
+
+/// Drives the pin called `name` of `node`, if its access policy allows it.
+pub async fn set_pin(
+    bmc: &BmcApplication,
+    node: NodeId,
+    name: &str,
+    value: bool,
+) -> anyhow::Result<()> {
+    check_node(bmc.board(), node)?;
+    let pin = find_pin(bmc.board(), name)?;
+    let powered = bmc.get_node_power(node).await?;
+    check_access(&pin, powered)?;
+    tracing::info!("setting pin {} of {} to {}", name, node, value);
+    bmc.write_pin(node, pin.name, value)
+}
+
+fn check_node(board: &BoardProfile, node: NodeId) -> Result<(), BmcError> {
+    if node as usize >= board.node_count {
+        return Err(BmcError::NoSuchNode(node));
+    }
+    Ok(())
+}
+
+fn find_pin(board: &BoardProfile, name: &str) -> Result<NodePin, BmcError> {
+    board
+        .pins()
+        .into_iter()
+        .find(|p| p.name == name)
+        .ok_or_else(|| BmcError::invalid_parameter("pin", format!("no pin `{}`", name)))
+}
+
+fn check_access(pin: &NodePin, powered: bool) -> Result<(), BmcError> {
+    let reason = match pin.access {
+        PinAccess::ReadOnly => "is read-only",
+        PinAccess::WhileOff if powered => "can only be driven while the node is off",
+        PinAccess::WhileOff | PinAccess::ReadWrite => return Ok(()),
+    };
+    Err(BmcError::PinAccessDenied {
+        pin: pin.name.to_string(),
+        reason,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+    use crate::hal::board_profile::TURING_PI_2_5;
+
+    #[test]
+    fn access_policies() {
+        let pin = find_pin(&TURING_PI_2_5, "usb_boot").unwrap();
+        assert!(check_access(&pin, false).is_ok());
+        let denied = check_access(&pin, true).unwrap_err();
+        assert_eq!(denied.code(), "pin_access_denied");
+        assert_eq!(
+            denied.to_string(),
+            "pin `usb_boot` can only be driven while the node is off"
+        );
+
+        let mut pin = pin;
+        pin.access = PinAccess::ReadOnly;
+        assert!(check_access(&pin, false).is_err());
+        pin.access = PinAccess::ReadWrite;
+        assert!(check_access(&pin, true).is_ok());
+
+        // the 2.5 board cannot detect modules
+        assert!(find_pin(&TURING_PI_2_5, "present").is_err());
+    }
+}
@@ -0,0 +1,332 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Declarative state of the nodes, for configuration management tools such
+//! as Ansible. A client sends the state it wants; bmcd compares it with the
+//! current state, changes only what differs, and reports for every field
+//! whether it changed. Sending the same document again changes nothing. In
+//! check mode nothing is changed, and the report tells what would change.
+//!
+//! Fields that are left out of the document are left as they are.
+use super::batch::{node_id, UsbSetting};
+use super::bmc_application::{BmcApplication, NodeInfo, UsbConfig, ACTIVATED_NODES_KEY};
+use crate::error::{ApiError, BmcError};
+use crate::hal::{NodeId, UsbRoute};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct State {
+    #[serde(default)]
+    pub nodes: Vec<NodeState>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usb: Option<UsbState>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeState {
+    /// node number, starting from 1
+    pub node: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub power: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UsbState {
+    pub node: u8,
+    pub mode: UsbSetting,
+    /// the USB bus is routed to the BMC instead of the USB-A port
+    #[serde(default)]
+    pub bmc: bool,
+}
+
+impl From<UsbConfig> for UsbState {
+    fn from(config: UsbConfig) -> Self {
+        let (node, mode, route) = match config {
+            UsbConfig::UsbA(node) => (node, UsbSetting::Device, UsbRoute::AlternativePort),
+            UsbConfig::Bmc(node) => (node, UsbSetting::Device, UsbRoute::Bmc),
+            UsbConfig::Node(node, route) => (node, UsbSetting::Host, route),
+            UsbConfig::Flashing(node, route) => (node, UsbSetting::Flash, route),
+        };
+        UsbState {
+            node: node as u8 + 1,
+            mode,
+            bmc: route == UsbRoute::Bmc,
+        }
+    }
+}
+
+impl State {
+    pub fn validate(&self, board_nodes: usize) -> Result<(), BmcError> {
+        let mut seen = 0u8;
+        for node in &self.nodes {
+            let bit = node_id(node.node, board_nodes)?.to_bitfield();
+            if seen & bit != 0 {
+                return Err(BmcError::invalid_parameter(
+                    "nodes",
+                    format!("node {} is listed twice", node.node),
+                ));
+            }
+            seen |= bit;
+        }
+        if let Some(usb) = &self.usb {
+            node_id(usb.node, board_nodes)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldStatus {
+    Unchanged,
+    Changed,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FieldResult {
+    /// e.g. `nodes.1.power` or `usb`
+    pub field: String,
+    pub status: FieldStatus,
+    pub from: Value,
+    pub to: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ApiError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    /// In check mode: whether anything would change.
+    pub changed: bool,
+    pub failed: bool,
+    pub check: bool,
+    pub fields: Vec<FieldResult>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Change {
+    Name(NodeId, String),
+    Usb(UsbConfig),
+    Power(NodeId, bool),
+}
+
+/// The state of all nodes, in the format of a desired state.
+pub async fn current_state(bmc: &BmcApplication) -> anyhow::Result<State> {
+    let infos = bmc.get_node_infos().await?;
+    let powered = bmc.app_db.get::<u8>(ACTIVATED_NODES_KEY).await;
+    let nodes = infos
+        .into_iter()
+        .take(bmc.board().node_count)
+        .enumerate()
+        .map(|(idx, info)| NodeState {
+            node: idx as u8 + 1,
+            power: Some(powered & (1 << idx) != 0),
+            name: info.name,
+        })
+        .collect();
+    let (usb, _) = bmc.get_usb_mode().await;
+    Ok(State {
+        nodes,
+        usb: Some(usb.into()),
+    })
+}
+
+/// Brings the nodes into the `desired` state, or only reports what would
+/// change when `check` is set. Call [`State::validate`] first. A field that
+/// fails to change does not stop the others.
+pub async fn reconcile(
+    bmc: &BmcApplication,
+    desired: &State,
+    check: bool,
+) -> anyhow::Result<Report> {
+    let current = current_state(bmc).await?;
+    let mut fields = Vec::new();
+    for (mut field, change) in diff(&current, desired) {
+        if let (Some(change), false) = (change, check) {
+            if let Err(e) = apply(bmc, change).await {
+                tracing::warn!("reconciling {}: {:#}", field.field, e);
+                field.status = FieldStatus::Failed;
+                field.error = Some(ApiError::from_anyhow(&e));
+            }
+        }
+        fields.push(field);
+    }
+    Ok(Report {
+        changed: fields.iter().any(|f| f.status == FieldStatus::Changed),
+        failed: fields.iter().any(|f| f.status == FieldStatus::Failed),
+        check,
+        fields,
+    })
+}
+
+async fn apply(bmc: &BmcApplication, change: Change) -> anyhow::Result<()> {
+    match change {
+        Change::Name(node, name) => {
+            let info = NodeInfo {
+                name: Some(name),
+                ..Default::default()
+            };
+            bmc.set_node_info(HashMap::from([(node, info)])).await
+        }
+        Change::Usb(config) => bmc.configure_usb(config).await,
+        Change::Power(node, on) => {
+            let bit = node.to_bitfield();
+            bmc.activate_slot(if on { bit } else { 0 }, bit).await
+        }
+    }
+}
+
+/// Compares the fields of `desired` with `current`. Names and the USB route
+/// are changed before the power, so a node boots with its new USB route.
+fn diff(current: &State, desired: &State) -> Vec<(FieldResult, Option<Change>)> {
+    let field = |field: String, from: Value, to: Value, change: Option<Change>| {
+        let status = match change {
+            Some(_) => FieldStatus::Changed,
+            None => FieldStatus::Unchanged,
+        };
+        let result = FieldResult {
+            field,
+            status,
+            from,
+            to,
+            error: None,
+        };
+        (result, change)
+    };
+    let current_node = |node: u8| current.nodes.iter().find(|n| n.node == node);
+    let mut fields = Vec::new();
+
+    for desired in &desired.nodes {
+        let Some(name) = &desired.name else { continue };
+        let id = NodeId::try_from(desired.node - 1).expect("validated node");
+        let from = current_node(desired.node).and_then(|n| n.name.clone());
+        let change = (from.as_ref() != Some(name)).then(|| Change::Name(id, name.clone()));
+        fields.push(field(
+            format!("nodes.{}.name", desired.node),
+            json!(from),
+            json!(name),
+            change,
+        ));
+    }
+
+    if let Some(usb) = &desired.usb {
+        let id = NodeId::try_from(usb.node - 1).expect("validated node");
+        let change = (current.usb.as_ref() != Some(usb))
+            .then(|| Change::Usb(usb.mode.usb_config(id, usb.bmc)));
+        fields.push(field(
+            "usb".to_string(),
+            json!(current.usb),
+            json!(usb),
+            change,
+        ));
+    }
+
+    for desired in &desired.nodes {
+        let Some(power) = desired.power else { continue };
+        let id = NodeId::try_from(desired.node - 1).expect("validated node");
+        let from = current_node(desired.node).and_then(|n| n.power);
+        let change = (from != Some(power)).then_some(Change::Power(id, power));
+        fields.push(field(
+            format!("nodes.{}.power", desired.node),
+            json!(from),
+            json!(power),
+            change,
+        ));
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> State {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn validation() {
+        let state = parse(r#"{"nodes": [{"node": 1, "power": true}, {"node": 1, "name": "a"}]}"#);
+        assert!(state.validate(4).unwrap_err().to_string().contains("twice"));
+        let state = parse(r#"{"usb": {"node": 4, "mode": "host"}}"#);
+        assert!(state.validate(4).is_ok());
+        assert!(state.validate(2).is_err());
+        assert!(
+            serde_json::from_str::<State>(r#"{"nodes": [{"node": 1, "powr": true}]}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn only_differences_change() {
+        let current = State {
+            nodes: vec![
+                NodeState {
+                    node: 1,
+                    power: Some(true),
+                    name: Some("rk1-a".to_string()),
+                },
+                NodeState {
+                    node: 2,
+                    power: Some(false),
+                    name: None,
+                },
+            ],
+            usb: Some(UsbConfig::UsbA(NodeId::Node1).into()),
+        };
+        let desired = parse(
+            r#"{"nodes": [
+                {"node": 1, "power": true, "name": "rk1-a"},
+                {"node": 2, "power": true, "name": "rk1-b"}
+            ], "usb": {"node": 2, "mode": "host", "bmc": true}}"#,
+        );
+
+        let fields: Vec<_> = diff(&current, &desired)
+            .into_iter()
+            .map(|(field, change)| (field.field, field.status, change))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("nodes.1.name".to_string(), FieldStatus::Unchanged, None),
+                (
+                    "nodes.2.name".to_string(),
+                    FieldStatus::Changed,
+                    Some(Change::Name(NodeId::Node2, "rk1-b".to_string()))
+                ),
+                (
+                    "usb".to_string(),
+                    FieldStatus::Changed,
+                    Some(Change::Usb(UsbConfig::Node(NodeId::Node2, UsbRoute::Bmc)))
+                ),
+                ("nodes.1.power".to_string(), FieldStatus::Unchanged, None),
+                (
+                    "nodes.2.power".to_string(),
+                    FieldStatus::Changed,
+                    Some(Change::Power(NodeId::Node2, true))
+                ),
+            ]
+        );
+
+        // the current state, as reported, is a desired state without changes
+        assert!(diff(&current, &current)
+            .iter()
+            .all(|(_, change)| change.is_none()));
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use super::request_trace::current_trace_id;
+use crate::config::NotificationTarget;
+use crate::utils::get_timestamp_unix;
+use crate::utils::memory::{memory, Pool};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{broadcast, RwLock};
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of notifications kept for diagnostics.
+const RECENT_CAPACITY: usize = 100;
+const EVENT_CAPACITY: usize = 32;
+
+/// Delivers notifications to the HTTP endpoints declared in the
+/// `notifications` section of the configuration. Delivery is best-effort:
+/// failures are logged and not retried.
+pub struct Notifier {
+    targets: RwLock<Vec<NotificationTarget>>,
+    client: reqwest::Client,
+    recent: Mutex<VecDeque<Value>>,
+    events: broadcast::Sender<Value>,
+}
+
+impl Notifier {
+    pub fn new(targets: Vec<NotificationTarget>) -> Self {
+        Self {
+            targets: RwLock::new(targets),
+            client: reqwest::Client::new(),
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_CAPACITY)),
+            events: broadcast::Sender::new(EVENT_CAPACITY),
+        }
+    }
+
+    pub async fn set_targets(&self, targets: Vec<NotificationTarget>) {
+        *self.targets.write().await = targets;
+    }
+
+    /// Sends `message` to every configured target. This call does not wait
+    /// for the deliveries to complete.
+    pub async fn notify(&self, event: &str, message: impl Into<String>) {
+        let mut body = json!({
+            "event": event,
+            "message": message.into(),
+            "timestamp": get_timestamp_unix(),
+        });
+        if let Some(trace_id) = current_trace_id() {
+            body["trace_id"] = trace_id.into();
+        }
+        self.remember(body.clone());
+        let _ = self.events.send(body.clone());
+
+        let size = body.to_string().len();
+        for target in self.targets.read().await.iter() {
+            // a target that does not keep up must not pile up deliveries
+            let Some(reservation) = memory().try_reserve(Pool::Events, size) else {
+                tracing::warn!(
+                    "event memory limit reached, dropped {} notification to {}",
+                    event,
+                    target.name
+                );
+                continue;
+            };
+            let request = self
+                .client
+                .post(&target.url)
+                .timeout(SEND_TIMEOUT)
+                .json(&body);
+            let name = target.name.clone();
+
+            tokio::spawn(async move {
+                match request.send().await.and_then(|r| r.error_for_status()) {
+                    Ok(_) => tracing::debug!("notification delivered to {}", name),
+                    Err(e) => tracing::warn!("notification to {} failed: {}", name, e),
+                }
+                drop(reservation);
+            });
+        }
+    }
+
+    /// The last notifications, oldest first. Also includes notifications
+    /// that were not delivered to any target.
+    pub fn recent(&self) -> Vec<Value> {
+        self.recent
+            .lock()
+            .expect("notifier lock poisoned")
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Notifications as they are sent, for reactions within bmcd.
+    pub fn subscribe(&self) -> broadcast::Receiver<Value> {
+        self.events.subscribe()
+    }
+
+    /// Like [`Self::recent`], but gives up when the list is locked. For the
+    /// panic hook, which may run while the lock is held.
+    pub fn try_recent(&self) -> Option<Vec<Value>> {
+        let recent = self.recent.try_lock().ok()?;
+        Some(recent.iter().cloned().collect())
+    }
+
+    fn remember(&self, notification: Value) {
+        let mut recent = self.recent.lock().expect("notifier lock poisoned");
+        if recent.len() == RECENT_CAPACITY {
+            recent.pop_front();
+        }
+        recent.push_back(notification);
+    }
+}
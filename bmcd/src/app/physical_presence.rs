@@ -0,0 +1,128 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Time the user has to press the button after the request was armed.
+pub const PRESS_WINDOW: Duration = Duration::from_secs(60);
+/// Time a confirmed physical presence stays valid.
+const GRANT_VALIDITY: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceState {
+    Idle,
+    /// waiting for a button press
+    Armed,
+    /// the button was pressed, the next privileged operation is allowed
+    Granted,
+}
+
+#[derive(Debug)]
+enum State {
+    Idle,
+    Armed(Instant),
+    Granted(Instant),
+}
+
+/// Proof that someone is physically at the board. A privileged operation is
+/// first armed through the API, after which the KEY1 button of the board has
+/// to be pressed within [`PRESS_WINDOW`]. The resulting grant can be used
+/// once.
+#[derive(Debug)]
+pub struct PhysicalPresence {
+    state: Mutex<State>,
+}
+
+impl Default for PhysicalPresence {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(State::Idle),
+        }
+    }
+}
+
+impl PhysicalPresence {
+    pub fn arm(&self) -> Duration {
+        *self.state.lock().expect("presence lock poisoned") =
+            State::Armed(Instant::now() + PRESS_WINDOW);
+        tracing::warn!("physical presence requested, press KEY1 to confirm");
+        PRESS_WINDOW
+    }
+
+    /// Called on a button press. Returns true when the press was consumed as
+    /// confirmation of physical presence.
+    pub fn press(&self) -> bool {
+        let mut state = self.state.lock().expect("presence lock poisoned");
+        match *state {
+            State::Armed(until) if until > Instant::now() => {
+                *state = State::Granted(Instant::now() + GRANT_VALIDITY);
+                tracing::warn!("physical presence confirmed");
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Consumes the grant. Returns true when physical presence was
+    /// confirmed and did not expire.
+    pub fn take_grant(&self) -> bool {
+        let mut state = self.state.lock().expect("presence lock poisoned");
+        let granted = matches!(*state, State::Granted(until) if until > Instant::now());
+        if granted {
+            *state = State::Idle;
+        }
+        granted
+    }
+
+    pub fn state(&self) -> PresenceState {
+        let now = Instant::now();
+        match *self.state.lock().expect("presence lock poisoned") {
+            State::Armed(until) if until > now => PresenceState::Armed,
+            State::Granted(until) if until > now => PresenceState::Granted,
+            _ => PresenceState::Idle,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn press_only_counts_when_armed() {
+        let presence = PhysicalPresence::default();
+        assert!(!presence.press());
+        assert!(!presence.take_grant());
+
+        presence.arm();
+        assert_eq!(presence.state(), PresenceState::Armed);
+        assert!(presence.press());
+        assert_eq!(presence.state(), PresenceState::Granted);
+
+        // a grant can be used once
+        assert!(presence.take_grant());
+        assert!(!presence.take_grant());
+    }
+
+    #[test]
+    fn armed_request_expires() {
+        let presence = PhysicalPresence::default();
+        *presence.state.lock().unwrap() = State::Armed(Instant::now() - Duration::from_secs(1));
+        assert!(!presence.press());
+        assert_eq!(presence.state(), PresenceState::Idle);
+    }
+}
@@ -0,0 +1,701 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Pipelines are ordered steps that run as a job, e.g. power a node off,
+//! route its USB, flash an image from a URL, power it on and wait for its
+//! login prompt. The job stops at the first step that fails; its result holds
+//! the state of every step, also while the job runs.
+//!
+//! Pipelines are defined with the API and kept in the persistency, or in the
+//! configuration for CI systems that start them with a webhook, e.g. "an
+//! image build finished" becomes "flash node 3 with the image, then power it
+//! on". Every configured pipeline has a route `/hooks/<name>` that accepts
+//! any JSON event whose body is signed with the secret of the pipeline, like
+//! GitHub and Gitea sign their webhooks:
+//!
+//! ```text
+//! X-Hub-Signature-256: sha256=<hex of HMAC-SHA256(secret, body)>
+//! ```
+//!
+//! Events that do not match the `when` conditions of the pipeline are
+//! accepted and ignored, so a CI system may send all of its events.
+use super::batch::node_id;
+use super::bmc_application::BmcApplication;
+use super::image_sharing::ImageSharing;
+use super::jobs::{Job, JobId, JobKind, JobState, Jobs, Outcome};
+use super::transfer_action::{InitializeTransfer, UpgradeCommand};
+use crate::config::{Pipeline, PipelineStep};
+use crate::hal::NodeId;
+use crate::serial_service::serial::SerialConnections;
+use crate::streaming_data_service::StreamingDataService;
+use crate::utils::{hmac_sha256, Checksum};
+use anyhow::{anyhow, bail, Context};
+use config::FileFormat;
+use futures::StreamExt;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_util::sync::CancellationToken;
+
+pub const SIGNATURE_HEADER: &str = "X-Hub-Signature-256";
+/// Persistency key of the pipelines defined with the API.
+pub const PIPELINES_KEY: &str = "pipelines";
+pub const MAX_PIPELINES: usize = 16;
+const MAX_STEPS: usize = 64;
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Stored definitions as JSON, as the persistency cannot hold the tagged
+/// steps.
+pub type StoredPipelines = BTreeMap<String, String>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PipelineDefinition {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    pub steps: Vec<PipelineStep>,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum PipelineError {
+    #[error("`{0}` is not a valid name. Use up to 64 of the characters [a-zA-Z0-9_.-]")]
+    InvalidName(String),
+    #[error("invalid pipeline: {0}")]
+    Invalid(String),
+    #[error("maximum of {MAX_PIPELINES} pipelines reached")]
+    TooManyPipelines,
+    #[error("`{0}` does not exist")]
+    NotFound(String),
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum HookError {
+    #[error("pipeline `{0}` does not exist")]
+    UnknownPipeline(String),
+    #[error("missing or invalid {SIGNATURE_HEADER} header")]
+    BadSignature,
+    #[error("invalid event: {0}")]
+    InvalidEvent(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Trigger {
+    /// the event did not match the conditions of the pipeline
+    Ignored,
+    Started(JobId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum StepState {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Serialize)]
+struct StepStatus<'a> {
+    #[serde(flatten)]
+    step: &'a PipelineStep,
+    state: StepState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Image that a `flash` step writes.
+#[derive(Debug, Clone, PartialEq)]
+struct Image {
+    url: Url,
+    checksum: Option<Checksum>,
+}
+
+impl Image {
+    fn new(url: &str, sha256: Option<&str>) -> Result<Self, String> {
+        let url = Url::parse(url).map_err(|e| format!("image URL `{}`: {}", url, e))?;
+        let checksum = match sha256 {
+            Some(sha256) => match hex::decode(sha256) {
+                Ok(bytes) if bytes.len() == 32 => Some(Checksum::Sha256(bytes.into())),
+                _ => return Err(format!("`{}` is not a SHA-256", sha256)),
+            },
+            None => None,
+        };
+        Ok(Self { url, checksum })
+    }
+}
+
+/// Pipelines defined with the API.
+pub async fn list_pipelines(bmc: &BmcApplication) -> BTreeMap<String, PipelineDefinition> {
+    bmc.app_db
+        .get::<StoredPipelines>(PIPELINES_KEY)
+        .await
+        .into_iter()
+        .filter_map(|(name, json)| match serde_json::from_str(&json) {
+            Ok(definition) => Some((name, definition)),
+            Err(e) => {
+                tracing::warn!("stored pipeline {} is invalid: {}", name, e);
+                None
+            }
+        })
+        .collect()
+}
+
+pub async fn get_pipeline(
+    bmc: &BmcApplication,
+    name: &str,
+) -> Result<PipelineDefinition, PipelineError> {
+    list_pipelines(bmc)
+        .await
+        .remove(name)
+        .ok_or_else(|| PipelineError::NotFound(name.to_string()))
+}
+
+pub async fn save_pipeline(
+    bmc: &BmcApplication,
+    name: &str,
+    definition: &PipelineDefinition,
+) -> Result<(), PipelineError> {
+    validate_name(name)?;
+    validate(definition, bmc.board().node_count)?;
+    let mut pipelines = bmc.app_db.get::<StoredPipelines>(PIPELINES_KEY).await;
+    if !pipelines.contains_key(name) && pipelines.len() >= MAX_PIPELINES {
+        return Err(PipelineError::TooManyPipelines);
+    }
+    let json = serde_json::to_string(definition).expect("pipelines serialize");
+    pipelines.insert(name.to_string(), json);
+    bmc.app_db.set(PIPELINES_KEY, pipelines).await;
+    Ok(())
+}
+
+pub async fn delete_pipeline(bmc: &BmcApplication, name: &str) -> Result<(), PipelineError> {
+    let mut pipelines = bmc.app_db.get::<StoredPipelines>(PIPELINES_KEY).await;
+    pipelines
+        .remove(name)
+        .ok_or_else(|| PipelineError::NotFound(name.to_string()))?;
+    bmc.app_db.set(PIPELINES_KEY, pipelines).await;
+    Ok(())
+}
+
+/// Parses a definition written in JSON or, with `yaml`, in YAML.
+pub fn parse_definition(body: &[u8], yaml: bool) -> Result<PipelineDefinition, PipelineError> {
+    let invalid = |e: &dyn std::fmt::Display| PipelineError::Invalid(e.to_string());
+    if !yaml {
+        return serde_json::from_slice(body).map_err(|e| invalid(&e));
+    }
+    let text = std::str::from_utf8(body).map_err(|e| invalid(&e))?;
+    config::Config::builder()
+        .add_source(config::File::from_str(text, FileFormat::Yaml))
+        .build()
+        .and_then(|c| c.try_deserialize())
+        .map_err(|e| invalid(&e))
+}
+
+fn validate_name(name: &str) -> Result<(), PipelineError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+    if valid {
+        Ok(())
+    } else {
+        Err(PipelineError::InvalidName(name.to_string()))
+    }
+}
+
+fn validate(definition: &PipelineDefinition, board_nodes: usize) -> Result<(), PipelineError> {
+    let steps = &definition.steps;
+    if steps.is_empty() || steps.len() > MAX_STEPS {
+        return Err(PipelineError::Invalid(format!(
+            "a pipeline has 1 to {} steps",
+            MAX_STEPS
+        )));
+    }
+    for (n, step) in steps.iter().enumerate() {
+        let check = || -> anyhow::Result<()> {
+            step.validate()?;
+            if let Some(node) = step.node() {
+                node_id(node, board_nodes)?;
+            }
+            if matches!(step, PipelineStep::Flash { url: None, .. }) {
+                bail!("flash needs a url");
+            }
+            Ok(())
+        };
+        check().map_err(|e| PipelineError::Invalid(format!("step {}: {:#}", n + 1, e)))?;
+    }
+    Ok(())
+}
+
+pub struct Pipelines {
+    bmc: Arc<BmcApplication>,
+    jobs: Arc<Jobs>,
+    streaming: Arc<StreamingDataService>,
+    sharing: Arc<ImageSharing>,
+    serials: Arc<SerialConnections>,
+}
+
+impl Pipelines {
+    pub fn new(
+        bmc: Arc<BmcApplication>,
+        jobs: Arc<Jobs>,
+        streaming: Arc<StreamingDataService>,
+        sharing: Arc<ImageSharing>,
+        serials: Arc<SerialConnections>,
+    ) -> Self {
+        Self {
+            bmc,
+            jobs,
+            streaming,
+            sharing,
+            serials,
+        }
+    }
+
+    /// Starts a job that runs the pipeline called `name` that was defined
+    /// with the API.
+    pub async fn run(self: &Arc<Self>, name: &str) -> Result<JobId, PipelineError> {
+        let definition = get_pipeline(&self.bmc, name).await?;
+        Ok(self.start(name, definition.steps, None))
+    }
+
+    /// Checks the signature of `body` and starts a job that runs the steps of
+    /// `pipeline` when the event matches its conditions.
+    pub fn trigger(
+        self: &Arc<Self>,
+        pipeline: &Pipeline,
+        body: &[u8],
+        signature: Option<&str>,
+    ) -> Result<Trigger, HookError> {
+        if !signature.is_some_and(|s| verify_signature(&pipeline.secret, body, s)) {
+            return Err(HookError::BadSignature);
+        }
+        let event: Value =
+            serde_json::from_slice(body).map_err(|e| HookError::InvalidEvent(e.to_string()))?;
+        if !matches(pipeline, &event) {
+            tracing::debug!("pipeline {}: event ignored", pipeline.name);
+            return Ok(Trigger::Ignored);
+        }
+        let image = if pipeline
+            .steps
+            .iter()
+            .any(|s| matches!(s, PipelineStep::Flash { url: None, .. }))
+        {
+            Some(event_image(pipeline, &event).map_err(HookError::InvalidEvent)?)
+        } else {
+            None
+        };
+        Ok(Trigger::Started(self.start(
+            &pipeline.name,
+            pipeline.steps.clone(),
+            image,
+        )))
+    }
+
+    /// `image` is flashed by `flash` steps without a URL of their own.
+    fn start(
+        self: &Arc<Self>,
+        name: &str,
+        steps: Vec<PipelineStep>,
+        image: Option<Image>,
+    ) -> JobId {
+        let id = self.jobs.next_id();
+        let cancel = CancellationToken::new();
+        self.jobs.add(
+            id,
+            JobKind::Pipeline,
+            format!("pipeline {}", name),
+            cancel.clone(),
+            None,
+        );
+        tracing::info!("pipeline {} started as job {}", name, id);
+
+        let this = self.clone();
+        let name = name.to_string();
+        tokio::spawn(async move {
+            let outcome = this
+                .execute(id, &name, &steps, image.as_ref(), &cancel)
+                .await;
+            this.jobs.finish(id, outcome).await;
+        });
+        id
+    }
+
+    async fn execute(
+        &self,
+        id: JobId,
+        name: &str,
+        steps: &[PipelineStep],
+        image: Option<&Image>,
+        cancel: &CancellationToken,
+    ) -> Outcome {
+        let mut status: Vec<_> = steps
+            .iter()
+            .map(|step| StepStatus {
+                step,
+                state: StepState::Pending,
+                error: None,
+            })
+            .collect();
+
+        for (n, step) in steps.iter().enumerate() {
+            status[n].state = StepState::Running;
+            self.jobs.report(id, json!({ "steps": status }));
+            tracing::info!("pipeline {}: step {} {:?}", name, n + 1, step);
+
+            let result = if cancel.is_cancelled() {
+                Err(anyhow!("cancelled"))
+            } else {
+                self.step(step, image, cancel).await
+            };
+            match result {
+                Ok(()) => status[n].state = StepState::Succeeded,
+                Err(_) if cancel.is_cancelled() => {
+                    status[n].state = StepState::Cancelled;
+                    self.jobs.report(id, json!({ "steps": status }));
+                    return Outcome::Cancelled;
+                }
+                Err(e) => {
+                    tracing::warn!("pipeline {}: step {}: {:#}", name, n + 1, e);
+                    status[n].state = StepState::Failed;
+                    status[n].error = Some(format!("{:#}", e));
+                    self.jobs.report(id, json!({ "steps": status }));
+                    return Outcome::Failed(format!("step {}: {:#}", n + 1, e));
+                }
+            }
+        }
+        Outcome::Succeeded(Some(json!({ "steps": status })))
+    }
+
+    async fn step(
+        &self,
+        step: &PipelineStep,
+        image: Option<&Image>,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        let board_nodes = self.bmc.board().node_count;
+        match step {
+            PipelineStep::Flash { node, url, sha256 } => {
+                let image = match url {
+                    Some(url) => Image::new(url, sha256.as_deref()).map_err(anyhow::Error::msg)?,
+                    None => image.context("the event has no image")?.clone(),
+                };
+                self.flash(node_id(*node, board_nodes)?, &image, cancel)
+                    .await
+            }
+            PipelineStep::Power { node, on } => {
+                let bit = 1 << (node_id(*node, board_nodes)? as u8);
+                self.bmc.activate_slot(if *on { bit } else { 0 }, bit).await
+            }
+            PipelineStep::Reset { node } => self.bmc.reset_node(node_id(*node, board_nodes)?).await,
+            PipelineStep::Usb { node, mode, bmc } => {
+                let node = node_id(*node, board_nodes)?;
+                self.bmc.configure_usb(mode.usb_config(node, *bmc)).await
+            }
+            PipelineStep::WaitConsole {
+                node,
+                pattern,
+                timeout,
+            } => {
+                let node = node_id(*node, board_nodes)?;
+                self.wait_console(node, pattern, Duration::from_secs(*timeout), cancel)
+                    .await
+            }
+            PipelineStep::Delay { ms } => {
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_millis(*ms)) => Ok(()),
+                    _ = cancel.cancelled() => bail!("cancelled"),
+                }
+            }
+        }
+    }
+
+    /// Flashes the image with the same transfer as the flash routes, and
+    /// waits for the flash job to finish.
+    async fn flash(
+        &self,
+        node: NodeId,
+        image: &Image,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        let transfer = self
+            .sharing
+            .transfer(image.url.clone(), image.checksum.clone())
+            .await?;
+        let mut events = self.jobs.subscribe();
+        let request = InitializeTransfer::new(
+            format!("{node} os install from pipeline"),
+            UpgradeCommand::Module(node, self.bmc.clone()),
+            transfer,
+            true,
+        );
+        let id = self.streaming.request_transfer(request.try_into()?).await?;
+
+        tokio::select! {
+            result = wait_for_job(&self.jobs, &mut events, id) => result,
+            _ = cancel.cancelled() => {
+                let _ = self.jobs.cancel(id);
+                bail!("cancelled")
+            }
+        }
+    }
+
+    /// Waits for `pattern` in the console output of `node`. Output from
+    /// before the step started does not count.
+    async fn wait_console(
+        &self,
+        node: NodeId,
+        pattern: &str,
+        timeout: Duration,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        let (stream, _) = self.serials[node].open_channel()?;
+        let mut stream = std::pin::pin!(stream);
+        let mut matcher = ConsoleMatcher::new(pattern);
+        let wait = async {
+            while let Some(bytes) = stream.next().await {
+                if matcher.push(&bytes?) {
+                    return Ok(());
+                }
+            }
+            bail!("console of {} closed", node)
+        };
+
+        tokio::select! {
+            result = tokio::time::timeout(timeout, wait) => result.map_err(|_| {
+                anyhow!("`{}` did not appear within {}s", pattern, timeout.as_secs())
+            })?,
+            _ = cancel.cancelled() => bail!("cancelled"),
+        }
+    }
+}
+
+/// Finds a pattern in console output that arrives in arbitrary chunks.
+struct ConsoleMatcher {
+    pattern: Vec<u8>,
+    tail: Vec<u8>,
+}
+
+impl ConsoleMatcher {
+    fn new(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.as_bytes().to_vec(),
+            tail: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> bool {
+        self.tail.extend_from_slice(bytes);
+        if self
+            .tail
+            .windows(self.pattern.len())
+            .any(|w| w == self.pattern)
+        {
+            return true;
+        }
+        // keep what may be the start of the pattern
+        let keep = self.pattern.len() - 1;
+        if self.tail.len() > keep {
+            self.tail.drain(..self.tail.len() - keep);
+        }
+        false
+    }
+}
+
+async fn wait_for_job(
+    jobs: &Jobs,
+    events: &mut broadcast::Receiver<Job>,
+    id: JobId,
+) -> anyhow::Result<()> {
+    loop {
+        let job = match events.recv().await {
+            Ok(job) if job.id == id => job,
+            Ok(_) => continue,
+            Err(RecvError::Lagged(_)) => jobs.get(id).context("flash job disappeared")?,
+            Err(RecvError::Closed) => bail!("job events closed"),
+        };
+        match job.state {
+            JobState::Running => continue,
+            JobState::Succeeded => return Ok(()),
+            JobState::Failed => bail!("flashing failed: {}", job.error.unwrap_or_default()),
+            JobState::Cancelled => bail!("flashing was cancelled"),
+        }
+    }
+}
+
+/// Checks a `sha256=<hex>` signature of `body` in constant time.
+fn verify_signature(secret: &str, body: &[u8], signature: &str) -> bool {
+    let Some(expected) = signature
+        .strip_prefix("sha256=")
+        .and_then(|hex| hex::decode(hex).ok())
+    else {
+        return false;
+    };
+    match hmac_sha256(secret.as_bytes(), body) {
+        Ok(actual) => actual.len() == expected.len() && openssl::memcmp::eq(&actual, &expected),
+        Err(e) => {
+            tracing::error!("computing webhook signature: {}", e);
+            false
+        }
+    }
+}
+
+fn matches(pipeline: &Pipeline, event: &Value) -> bool {
+    pipeline
+        .when
+        .iter()
+        .all(|c| event.pointer(&c.pointer) == Some(&c.equals))
+}
+
+fn event_image(pipeline: &Pipeline, event: &Value) -> Result<Image, String> {
+    let field = |pointer: &Option<String>| {
+        pointer
+            .as_deref()
+            .and_then(|p| event.pointer(p))
+            .and_then(Value::as_str)
+    };
+    let url = field(&pipeline.image_url).ok_or("the event has no image URL")?;
+    Image::new(url, field(&pipeline.image_sha256))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::batch::UsbSetting;
+    use crate::config::EventCondition;
+
+    fn pipeline() -> Pipeline {
+        Pipeline {
+            name: "k3s-image".to_string(),
+            secret: "It's a Secret to Everybody".to_string(),
+            image_url: Some("/artifact/url".to_string()),
+            image_sha256: Some("/artifact/sha256".to_string()),
+            when: vec![EventCondition {
+                pointer: "/status".to_string(),
+                equals: json!("success"),
+            }],
+            steps: vec![PipelineStep::Flash {
+                node: 3,
+                url: None,
+                sha256: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn signature() {
+        // example of the GitHub webhook documentation
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+        assert!(verify_signature(
+            "It's a Secret to Everybody",
+            b"Hello, World!",
+            signature
+        ));
+        assert!(!verify_signature("other", b"Hello, World!", signature));
+        assert!(!verify_signature(
+            "It's a Secret to Everybody",
+            b"Hello, World?",
+            signature
+        ));
+        assert!(!verify_signature(
+            "It's a Secret to Everybody",
+            b"Hello, World!",
+            "sha256=7571"
+        ));
+        assert!(!verify_signature(
+            "It's a Secret to Everybody",
+            b"Hello, World!",
+            &signature[7..]
+        ));
+    }
+
+    #[test]
+    fn event_values() {
+        let pipeline = pipeline();
+        let sha256 = "ab".repeat(32);
+        let event = json!({
+            "status": "success",
+            "artifact": {"url": "https://ci.lab/builds/42/k3s.img", "sha256": sha256},
+        });
+        assert!(matches(&pipeline, &event));
+        assert!(!matches(&pipeline, &json!({"status": "failure"})));
+        assert!(!matches(&pipeline, &json!({})));
+
+        let image = event_image(&pipeline, &event).unwrap();
+        assert_eq!(image.url.as_str(), "https://ci.lab/builds/42/k3s.img");
+        assert_eq!(
+            image.checksum,
+            Some(Checksum::Sha256(vec![0xab; 32].into()))
+        );
+
+        assert!(event_image(&pipeline, &json!({"artifact": {"url": "k3s.img"}})).is_err());
+        assert!(event_image(
+            &pipeline,
+            &json!({"artifact": {"url": "https://ci.lab/k3s.img", "sha256": "abc"}})
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn definitions() {
+        let yaml = "description: reinstall node 2\nsteps:\n  - op: power\n    node: 2\n    on: false\n  - op: usb\n    node: 2\n    mode: flash\n  - op: flash\n    node: 2\n    url: https://ci.lab/os.img\n  - op: wait_console\n    node: 2\n    pattern: \"login:\"\n";
+        let definition = parse_definition(yaml.as_bytes(), true).unwrap();
+        assert_eq!(
+            definition.steps[1],
+            PipelineStep::Usb {
+                node: 2,
+                mode: UsbSetting::Flash,
+                bmc: false
+            }
+        );
+        assert_eq!(
+            definition.steps[3],
+            PipelineStep::WaitConsole {
+                node: 2,
+                pattern: "login:".to_string(),
+                timeout: 300
+            }
+        );
+        validate(&definition, 4).unwrap();
+
+        let json = serde_json::to_vec(&definition).unwrap();
+        assert_eq!(parse_definition(&json, false).unwrap(), definition);
+
+        let flash_event = parse_definition(br#"{"steps": [{"op": "flash", "node": 1}]}"#, false);
+        assert!(validate(&flash_event.unwrap(), 4).is_err());
+        let unknown =
+            parse_definition(br#"{"steps": [{"op": "reset", "node": 1, "x": 0}]}"#, false);
+        assert!(unknown.is_err());
+        let node = parse_definition(br#"{"steps": [{"op": "reset", "node": 4}]}"#, false);
+        assert!(validate(&node.unwrap(), 2).is_err());
+    }
+
+    #[test]
+    fn console_pattern_across_chunks() {
+        let mut matcher = ConsoleMatcher::new("login:");
+        assert!(!matcher.push(b"Ubuntu 24.04 node2 ttyS0\r\n\r\nnode2 log"));
+        assert!(matcher.push(b"in: "));
+
+        let mut matcher = ConsoleMatcher::new("login:");
+        for byte in b"no log in here" {
+            assert!(!matcher.push(&[*byte]));
+        }
+        assert!(matcher.tail.len() < 6);
+    }
+}
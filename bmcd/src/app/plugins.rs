@@ -0,0 +1,701 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Plugins add protocol frontends, e.g. for IPMI or SNMP, and sensor backends
+//! to bmcd without patching it. A plugin is a program that bmcd starts,
+//! supervises and restarts with a backoff when it exits. It runs as its own
+//! user without the environment of bmcd, and can only make the requests that
+//! its permissions allow.
+//!
+//! Plugins talk to bmcd with JSON messages, one per line of at most 64 KiB,
+//! on their standard input and output; lines on standard error are logged.
+//! A plugin starts with a hello, which bmcd answers with a welcome:
+//!
+//! ```text
+//! → {"type": "hello", "protocol": 1}
+//! ← {"type": "welcome", "protocol": 1, "nodes": 4, "permissions": ["power"]}
+//! ```
+//!
+//! After that the plugin reports sensor readings and makes requests, which
+//! bmcd answers in order:
+//!
+//! ```text
+//! → {"type": "sensor", "name": "inlet", "value": 23.5, "unit": "celsius"}
+//! → {"type": "request", "id": 7, "method": "power_on", "params": {"node": 2}}
+//! ← {"type": "response", "id": 7, "result": null}
+//! ```
+//!
+//! Methods are `node_power`, which returns the power state of all nodes,
+//! `power_on`, `power_off` and `reset` with `{"node": n}` (permission
+//! `power`), and `usb` with `{"node": n, "mode": "host" | "device" | "flash",
+//! "bmc": bool}` (permission `usb`). Nodes count from 1. Failed requests are
+//! answered with an `error` instead of a `result`. Plugins with the `events`
+//! permission receive the notifications of bmcd as `{"type": "event", ...}`.
+//! Before bmcd stops a plugin it sends `{"type": "stop"}`, and kills the
+//! plugin when it did not exit within 5 seconds.
+use super::batch::{node_id, UsbSetting};
+use super::bmc_application::BmcApplication;
+use super::notifier::Notifier;
+use crate::config::{self, PluginPermission};
+use crate::error::BmcError;
+use crate::utils::get_timestamp_unix;
+use anyhow::{bail, ensure, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{ChildStderr, ChildStdin, Command};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, watch};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+const MAX_LINE: usize = 64 * 1024;
+const MAX_SENSORS: usize = 64;
+/// Messages to a plugin that it has not read yet.
+const OUTGOING_CAPACITY: usize = 64;
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+const STOP_GRACE: Duration = Duration::from_secs(5);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// A plugin that ran this long before it failed restarts with the shortest
+/// backoff.
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Incoming {
+    Hello {
+        protocol: u32,
+    },
+    Sensor {
+        name: String,
+        value: f64,
+        #[serde(default)]
+        unit: String,
+    },
+    Request {
+        id: u64,
+        #[serde(flatten)]
+        call: Call,
+    },
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+enum Call {
+    NodePower,
+    PowerOn {
+        node: u8,
+    },
+    PowerOff {
+        node: u8,
+    },
+    Reset {
+        node: u8,
+    },
+    Usb {
+        node: u8,
+        mode: UsbSetting,
+        #[serde(default)]
+        bmc: bool,
+    },
+}
+
+impl Call {
+    fn permission(&self) -> Option<PluginPermission> {
+        match self {
+            Call::NodePower => None,
+            Call::PowerOn { .. } | Call::PowerOff { .. } | Call::Reset { .. } => {
+                Some(PluginPermission::Power)
+            }
+            Call::Usb { .. } => Some(PluginPermission::Usb),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Outgoing<'a> {
+    Welcome {
+        protocol: u32,
+        nodes: usize,
+        permissions: &'a [PluginPermission],
+    },
+    Response {
+        id: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        result: Option<Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// a notification of bmcd
+    Event(&'a Value),
+    Stop,
+}
+
+impl Outgoing<'_> {
+    fn encode(&self) -> String {
+        let mut line = serde_json::to_string(self).expect("messages serialize");
+        line.push('\n');
+        line
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum PluginState {
+    Stopped,
+    Starting,
+    Running {
+        pid: Option<u32>,
+        /// unix timestamp in seconds
+        since: u64,
+    },
+    /// waiting to start the plugin again after it failed
+    Backoff {
+        error: String,
+        failures: u32,
+        retry_at: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Sensor {
+    pub value: f64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub unit: String,
+    /// unix timestamp in seconds
+    pub updated: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PluginStatus {
+    pub name: String,
+    #[serde(flatten)]
+    pub state: PluginState,
+    pub restarts: u32,
+    pub permissions: Vec<PluginPermission>,
+    pub sensors: BTreeMap<String, Sensor>,
+}
+
+/// Whether the plugin should run; a new generation restarts it.
+#[derive(Debug, Clone, Copy)]
+struct Control {
+    running: bool,
+    generation: u64,
+}
+
+struct Inner {
+    state: PluginState,
+    restarts: u32,
+    sensors: BTreeMap<String, Sensor>,
+}
+
+struct Supervised {
+    config: config::Plugin,
+    inner: Mutex<Inner>,
+    control: watch::Sender<Control>,
+}
+
+pub struct Plugins {
+    plugins: Vec<Arc<Supervised>>,
+}
+
+impl Plugins {
+    pub fn new(configs: Vec<config::Plugin>) -> Self {
+        let plugins = configs
+            .into_iter()
+            .map(|config| {
+                let control = watch::Sender::new(Control {
+                    running: config.autostart,
+                    generation: 0,
+                });
+                Arc::new(Supervised {
+                    config,
+                    inner: Mutex::new(Inner {
+                        state: PluginState::Stopped,
+                        restarts: 0,
+                        sensors: BTreeMap::new(),
+                    }),
+                    control,
+                })
+            })
+            .collect();
+        Self { plugins }
+    }
+
+    /// Spawns the supervisors of the plugins.
+    pub fn run(&self, bmc: Arc<BmcApplication>, notifier: Arc<Notifier>) {
+        for plugin in &self.plugins {
+            tokio::spawn(plugin.clone().supervise(bmc.clone(), notifier.clone()));
+        }
+    }
+
+    pub fn list(&self) -> Vec<PluginStatus> {
+        self.plugins.iter().map(|p| p.status()).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Result<PluginStatus, BmcError> {
+        self.find(name).map(|p| p.status())
+    }
+
+    pub fn start(&self, name: &str) -> Result<(), BmcError> {
+        self.find(name)?.control.send_if_modified(|c| {
+            let stopped = !c.running;
+            c.running = true;
+            stopped
+        });
+        Ok(())
+    }
+
+    pub fn stop(&self, name: &str) -> Result<(), BmcError> {
+        self.find(name)?.control.send_if_modified(|c| {
+            let running = c.running;
+            c.running = false;
+            running
+        });
+        Ok(())
+    }
+
+    /// Restarts the plugin right away, also when it waits for its backoff.
+    pub fn restart(&self, name: &str) -> Result<(), BmcError> {
+        self.find(name)?.control.send_modify(|c| {
+            c.running = true;
+            c.generation += 1;
+        });
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> Result<&Arc<Supervised>, BmcError> {
+        self.plugins
+            .iter()
+            .find(|p| p.config.name == name)
+            .ok_or_else(|| BmcError::NotFound(format!("plugin `{}` does not exist", name).into()))
+    }
+}
+
+impl Supervised {
+    fn status(&self) -> PluginStatus {
+        let inner = self.inner.lock().expect("plugin lock poisoned");
+        PluginStatus {
+            name: self.config.name.clone(),
+            state: inner.state.clone(),
+            restarts: inner.restarts,
+            permissions: self.config.permissions.clone(),
+            sensors: inner.sensors.clone(),
+        }
+    }
+
+    fn set_state(&self, state: PluginState) {
+        self.inner.lock().expect("plugin lock poisoned").state = state;
+    }
+
+    async fn supervise(self: Arc<Self>, bmc: Arc<BmcApplication>, notifier: Arc<Notifier>) {
+        let name = &self.config.name;
+        let mut control = self.control.subscribe();
+        let mut failures = 0;
+        loop {
+            if !control.borrow_and_update().running {
+                self.set_state(PluginState::Stopped);
+                if control.changed().await.is_err() {
+                    return;
+                }
+                continue;
+            }
+
+            self.set_state(PluginState::Starting);
+            let started = Instant::now();
+            let Err(error) = self.run_once(&bmc, &notifier, &mut control).await else {
+                // stopped or restarted on request
+                failures = 0;
+                continue;
+            };
+            if started.elapsed() >= STABLE_RUN {
+                failures = 0;
+            }
+            failures += 1;
+            let delay = backoff(failures);
+            tracing::warn!(
+                "plugin {}: {:#}, restarting in {}s",
+                name,
+                error,
+                delay.as_secs()
+            );
+            {
+                let mut inner = self.inner.lock().expect("plugin lock poisoned");
+                inner.restarts += 1;
+                inner.state = PluginState::Backoff {
+                    error: format!("{:#}", error),
+                    failures,
+                    retry_at: get_timestamp_unix().unwrap_or_default() + delay.as_secs(),
+                };
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                changed = control.changed() => if changed.is_err() {
+                    return;
+                },
+            }
+        }
+    }
+
+    /// Runs the plugin until it fails, which is an error, or until it is
+    /// stopped or restarted with the API.
+    async fn run_once(
+        &self,
+        bmc: &BmcApplication,
+        notifier: &Notifier,
+        control: &mut watch::Receiver<Control>,
+    ) -> anyhow::Result<()> {
+        let config = &self.config;
+        let mut child = Command::new(&config.command)
+            .args(&config.args)
+            .env_clear()
+            .envs(config.env.iter().filter_map(|pair| pair.split_once('=')))
+            .uid(config.uid)
+            .gid(config.gid)
+            .current_dir("/")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("starting {}", config.command.display()))?;
+        let mut stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        tokio::spawn(log_stderr(
+            config.name.clone(),
+            child.stderr.take().expect("stderr is piped"),
+        ));
+        let (out, receiver) = mpsc::channel(OUTGOING_CAPACITY);
+        tokio::spawn(write_lines(
+            child.stdin.take().expect("stdin is piped"),
+            receiver,
+        ));
+
+        let mut line = Vec::new();
+        let hello = tokio::time::timeout(HELLO_TIMEOUT, read_line(&mut stdout, &mut line))
+            .await
+            .context("no hello in time")??;
+        ensure!(hello, "exited before its hello");
+        match serde_json::from_slice(&line) {
+            Ok(Incoming::Hello { protocol }) => ensure!(
+                protocol == PROTOCOL_VERSION,
+                "speaks protocol {}, bmcd speaks {}",
+                protocol,
+                PROTOCOL_VERSION
+            ),
+            _ => bail!("did not start with a hello"),
+        }
+        line.clear();
+        send(
+            &out,
+            Outgoing::Welcome {
+                protocol: PROTOCOL_VERSION,
+                nodes: bmc.board().node_count,
+                permissions: &config.permissions,
+            },
+        )?;
+        tracing::info!("plugin {} running", config.name);
+        self.set_state(PluginState::Running {
+            pid: child.id(),
+            since: get_timestamp_unix().unwrap_or_default(),
+        });
+
+        let mut events = config
+            .permissions
+            .contains(&PluginPermission::Events)
+            .then(|| notifier.subscribe());
+        loop {
+            tokio::select! {
+                read = read_line(&mut stdout, &mut line) => {
+                    if !read? {
+                        let status = child.wait().await?;
+                        bail!("exited with {}", status);
+                    }
+                    self.handle(bmc, &line, &out).await?;
+                    line.clear();
+                }
+                status = child.wait() => bail!("exited with {}", status?),
+                Some(event) = next_event(&mut events) => send(&out, Outgoing::Event(&event))?,
+                _ = control.changed() => {
+                    tracing::info!("stopping plugin {}", config.name);
+                    let _ = send(&out, Outgoing::Stop);
+                    drop(out);
+                    if tokio::time::timeout(STOP_GRACE, child.wait()).await.is_err() {
+                        tracing::warn!("plugin {} did not stop in time", config.name);
+                        child.kill().await?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    async fn handle(
+        &self,
+        bmc: &BmcApplication,
+        line: &[u8],
+        out: &mpsc::Sender<String>,
+    ) -> anyhow::Result<()> {
+        let message = match serde_json::from_slice(line) {
+            Ok(message) => message,
+            Err(e) => {
+                tracing::warn!("plugin {}: invalid message: {}", self.config.name, e);
+                // answer requests that bmcd cannot make sense of
+                let message: Value = serde_json::from_slice(line).unwrap_or_default();
+                if let (Some("request"), Some(id)) =
+                    (message["type"].as_str(), message["id"].as_u64())
+                {
+                    let error = Some(format!("invalid request: {}", e));
+                    send(
+                        out,
+                        Outgoing::Response {
+                            id,
+                            result: None,
+                            error,
+                        },
+                    )?;
+                }
+                return Ok(());
+            }
+        };
+
+        match message {
+            Incoming::Hello { .. } => {
+                tracing::warn!("plugin {}: repeated hello", self.config.name)
+            }
+            Incoming::Sensor { name, value, unit } => {
+                let mut inner = self.inner.lock().expect("plugin lock poisoned");
+                if inner.sensors.len() >= MAX_SENSORS && !inner.sensors.contains_key(&name) {
+                    tracing::warn!(
+                        "plugin {}: more than {} sensors",
+                        self.config.name,
+                        MAX_SENSORS
+                    );
+                } else {
+                    let updated = get_timestamp_unix().unwrap_or_default();
+                    inner.sensors.insert(
+                        name,
+                        Sensor {
+                            value,
+                            unit,
+                            updated,
+                        },
+                    );
+                }
+            }
+            Incoming::Request { id, call } => {
+                let response = match self.call(bmc, call).await {
+                    Ok(result) => Outgoing::Response {
+                        id,
+                        result: Some(result),
+                        error: None,
+                    },
+                    Err(e) => Outgoing::Response {
+                        id,
+                        result: None,
+                        error: Some(format!("{:#}", e)),
+                    },
+                };
+                send(out, response)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn call(&self, bmc: &BmcApplication, call: Call) -> anyhow::Result<Value> {
+        if let Some(permission) = call.permission() {
+            ensure!(
+                self.config.permissions.contains(&permission),
+                "the plugin lacks the {:?} permission",
+                permission
+            );
+        }
+        let nodes = bmc.board().node_count;
+        match call {
+            Call::NodePower => {
+                let mut states = Vec::new();
+                for node in 1..=nodes as u8 {
+                    states.push(bmc.get_node_power(node_id(node, nodes)?).await?);
+                }
+                return Ok(json!(states));
+            }
+            Call::PowerOn { node } | Call::PowerOff { node } => {
+                let bit = 1 << node_id(node, nodes)? as u8;
+                let on = matches!(call, Call::PowerOn { .. });
+                bmc.activate_slot(if on { bit } else { 0 }, bit).await?
+            }
+            Call::Reset { node } => bmc.reset_node(node_id(node, nodes)?).await?,
+            Call::Usb {
+                node,
+                mode,
+                bmc: to_bmc,
+            } => {
+                let config = mode.usb_config(node_id(node, nodes)?, to_bmc);
+                bmc.configure_usb(config).await?
+            }
+        }
+        Ok(Value::Null)
+    }
+}
+
+/// Queues a message without waiting, so a plugin that does not read its
+/// input cannot hold up bmcd.
+fn send(out: &mpsc::Sender<String>, message: Outgoing) -> anyhow::Result<()> {
+    out.try_send(message.encode())
+        .map_err(|_| anyhow::anyhow!("does not read its input"))
+}
+
+async fn write_lines(mut stdin: ChildStdin, mut receiver: mpsc::Receiver<String>) {
+    while let Some(line) = receiver.recv().await {
+        if stdin.write_all(line.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn log_stderr(name: String, stderr: ChildStderr) {
+    let mut stderr = BufReader::new(stderr);
+    let mut line = Vec::new();
+    while let Ok(true) = read_line(&mut stderr, &mut line).await {
+        tracing::info!("plugin {}: {}", name, String::from_utf8_lossy(&line));
+        line.clear();
+    }
+}
+
+async fn next_event(events: &mut Option<broadcast::Receiver<Value>>) -> Option<Value> {
+    let Some(receiver) = events else {
+        return std::future::pending().await;
+    };
+    loop {
+        match receiver.recv().await {
+            Ok(event) => return Some(event),
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return std::future::pending().await,
+        }
+    }
+}
+
+/// Appends a line to `line`, without its newline. Returns false at the end
+/// of the stream. A partial line stays in `line` when the future is dropped,
+/// and is completed by the next call.
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut Vec<u8>,
+) -> anyhow::Result<bool> {
+    loop {
+        let limit = (MAX_LINE + 1).saturating_sub(line.len()) as u64;
+        let read = (&mut *reader).take(limit).read_until(b'\n', line).await?;
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            return Ok(true);
+        }
+        ensure!(
+            line.len() <= MAX_LINE,
+            "sent a line of more than {} bytes",
+            MAX_LINE
+        );
+        if read == 0 {
+            return Ok(false);
+        }
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    MIN_BACKOFF
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages() {
+        let parse = |line: &str| serde_json::from_str::<Incoming>(line);
+        assert_eq!(
+            parse(r#"{"type": "request", "id": 7, "method": "power_on", "params": {"node": 2}}"#)
+                .unwrap(),
+            Incoming::Request {
+                id: 7,
+                call: Call::PowerOn { node: 2 }
+            }
+        );
+        assert_eq!(
+            parse(r#"{"type": "request", "id": 8, "method": "node_power"}"#).unwrap(),
+            Incoming::Request {
+                id: 8,
+                call: Call::NodePower
+            }
+        );
+        assert_eq!(
+            parse(r#"{"type": "sensor", "name": "inlet", "value": 23.5}"#).unwrap(),
+            Incoming::Sensor {
+                name: "inlet".to_string(),
+                value: 23.5,
+                unit: String::new()
+            }
+        );
+        assert!(parse(r#"{"type": "request", "id": 9, "method": "format_disk"}"#).is_err());
+
+        assert_eq!(
+            Outgoing::Response {
+                id: 7,
+                result: Some(Value::Null),
+                error: None
+            }
+            .encode(),
+            "{\"type\":\"response\",\"id\":7,\"result\":null}\n"
+        );
+        let event = json!({"event": "brownout", "message": "12V low"});
+        assert_eq!(
+            Outgoing::Event(&event).encode(),
+            "{\"type\":\"event\",\"event\":\"brownout\",\"message\":\"12V low\"}\n"
+        );
+        assert_eq!(
+            Outgoing::Welcome {
+                protocol: 1,
+                nodes: 4,
+                permissions: &[PluginPermission::Power]
+            }
+            .encode(),
+            "{\"type\":\"welcome\",\"protocol\":1,\"nodes\":4,\"permissions\":[\"power\"]}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn line_limit() {
+        let mut reader = BufReader::new(&b"{\"type\":\"hello\"}\npartial"[..]);
+        let mut line = Vec::new();
+        assert!(read_line(&mut reader, &mut line).await.unwrap());
+        assert_eq!(line, b"{\"type\":\"hello\"}");
+        line.clear();
+        assert!(!read_line(&mut reader, &mut line).await.unwrap());
+
+        let input = "x".repeat(MAX_LINE + 10) + "\n";
+        let mut reader = BufReader::new(input.as_bytes());
+        let mut line = Vec::new();
+        assert!(read_line(&mut reader, &mut line).await.is_err());
+    }
+
+    #[test]
+    fn restart_backoff() {
+        assert_eq!(backoff(1), MIN_BACKOFF);
+        assert_eq!(backoff(3), Duration::from_secs(4));
+        assert_eq!(backoff(40), MAX_BACKOFF);
+    }
+}
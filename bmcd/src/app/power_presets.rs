@@ -0,0 +1,345 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Named power presets that bring up a cluster with one request, e.g. the
+//! storage nodes first and the compute nodes once those are powered. A preset
+//! lists nodes in the order they are powered on, with a delay before each
+//! node and the nodes it requires: a node whose requirements failed to power
+//! on is skipped. Presets are stored in the persistency and applied as a job.
+//!
+//! [`STAGGERED_PRESET`] is built in and powers on all nodes a few seconds
+//! apart, to spread their inrush current.
+use super::bmc_application::{BmcApplication, ACTIVATED_NODES_KEY};
+use super::jobs::{JobId, JobKind, Jobs, Outcome};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+
+/// Persistency key of the stored presets.
+pub const POWER_PRESETS_KEY: &str = "power_presets";
+pub const STAGGERED_PRESET: &str = "all_on_staggered";
+pub const MAX_PRESETS: usize = 16;
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_DELAY: Duration = Duration::from_secs(600);
+const STAGGER: Duration = Duration::from_secs(2);
+
+pub type PowerPresets = BTreeMap<String, PowerPreset>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PowerPreset {
+    pub steps: Vec<PresetStep>,
+    /// power off the powered nodes that no step powers on
+    #[serde(default)]
+    pub exclusive: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PresetStep {
+    /// node number, starting from 1
+    pub node: u8,
+    /// ms to wait before the node is powered on
+    #[serde(default)]
+    pub delay: u64,
+    /// node numbers of earlier steps that must have powered on
+    #[serde(default)]
+    pub requires: Vec<u8>,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum PresetError {
+    #[error("`{0}` is not a valid name. Use up to 64 of the characters [a-zA-Z0-9_.-]")]
+    InvalidName(String),
+    #[error("invalid preset: {0}")]
+    Invalid(String),
+    #[error("`{0}` is built in and cannot be changed")]
+    BuiltIn(String),
+    #[error("maximum of {MAX_PRESETS} presets reached")]
+    TooManyPresets,
+    #[error("`{0}` does not exist")]
+    NotFound(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum StepState {
+    PoweredOn,
+    AlreadyOn,
+    Skipped,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+struct StepResult {
+    node: u8,
+    state: StepState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Stored presets together with the built-in ones.
+pub async fn list_presets(bmc: &BmcApplication) -> PowerPresets {
+    let mut presets = bmc.app_db.get::<PowerPresets>(POWER_PRESETS_KEY).await;
+    presets.insert(
+        STAGGERED_PRESET.to_string(),
+        staggered(bmc.board().node_count),
+    );
+    presets
+}
+
+pub async fn get_preset(bmc: &BmcApplication, name: &str) -> Result<PowerPreset, PresetError> {
+    list_presets(bmc)
+        .await
+        .remove(name)
+        .ok_or_else(|| PresetError::NotFound(name.to_string()))
+}
+
+pub async fn save_preset(
+    bmc: &BmcApplication,
+    name: &str,
+    preset: PowerPreset,
+) -> Result<(), PresetError> {
+    validate_name(name)?;
+    validate(&preset, bmc.board().node_count)?;
+    let mut presets = bmc.app_db.get::<PowerPresets>(POWER_PRESETS_KEY).await;
+    if !presets.contains_key(name) && presets.len() >= MAX_PRESETS {
+        return Err(PresetError::TooManyPresets);
+    }
+    presets.insert(name.to_string(), preset);
+    bmc.app_db.set(POWER_PRESETS_KEY, presets).await;
+    Ok(())
+}
+
+pub async fn delete_preset(bmc: &BmcApplication, name: &str) -> Result<(), PresetError> {
+    if name == STAGGERED_PRESET {
+        return Err(PresetError::BuiltIn(name.to_string()));
+    }
+    let mut presets = bmc.app_db.get::<PowerPresets>(POWER_PRESETS_KEY).await;
+    presets
+        .remove(name)
+        .ok_or_else(|| PresetError::NotFound(name.to_string()))?;
+    bmc.app_db.set(POWER_PRESETS_KEY, presets).await;
+    Ok(())
+}
+
+/// Starts a job that applies the preset called `name`. The job reports the
+/// outcome of every step.
+pub async fn start_preset_job(
+    bmc: Arc<BmcApplication>,
+    jobs: Arc<Jobs>,
+    name: &str,
+) -> Result<JobId, PresetError> {
+    let preset = get_preset(&bmc, name).await?;
+    let id = jobs.next_id();
+    let cancel = CancellationToken::new();
+    jobs.add(
+        id,
+        JobKind::PowerPreset,
+        format!("power preset {}", name),
+        cancel.clone(),
+        None,
+    );
+
+    tokio::spawn(async move {
+        let mut results = Vec::new();
+        let outcome = tokio::select! {
+            _ = apply(&bmc, &preset, &mut results) => {
+                let failed: Vec<_> = results
+                    .iter()
+                    .filter(|r| r.state == StepState::Failed)
+                    .map(|r| r.node.to_string())
+                    .collect();
+                if failed.is_empty() {
+                    Outcome::Succeeded(Some(json!({ "steps": results })))
+                } else {
+                    Outcome::Failed(format!("nodes {} failed to power on", failed.join(", ")))
+                }
+            }
+            _ = cancel.cancelled() => Outcome::Cancelled,
+        };
+        jobs.finish(id, outcome).await;
+    });
+    Ok(id)
+}
+
+async fn apply(bmc: &BmcApplication, preset: &PowerPreset, results: &mut Vec<StepResult>) {
+    let activated = bmc.app_db.get::<u8>(ACTIVATED_NODES_KEY).await;
+    if preset.exclusive {
+        let others = preset
+            .steps
+            .iter()
+            .fold(activated, |mask, step| mask & !bit(step.node));
+        if others != 0 {
+            if let Err(e) = bmc.activate_slot(0, others).await {
+                tracing::warn!("powering off nodes outside of the preset: {:#}", e);
+            }
+        }
+    }
+
+    let mut powered = 0u8;
+    for step in &preset.steps {
+        let (state, error) = if step.requires.iter().any(|n| powered & bit(*n) == 0) {
+            (StepState::Skipped, None)
+        } else if activated & bit(step.node) != 0 {
+            (StepState::AlreadyOn, None)
+        } else {
+            tokio::time::sleep(Duration::from_millis(step.delay)).await;
+            match bmc.activate_slot(bit(step.node), bit(step.node)).await {
+                Ok(()) => (StepState::PoweredOn, None),
+                Err(e) => (StepState::Failed, Some(format!("{:#}", e))),
+            }
+        };
+        tracing::info!("power preset: node {} {:?}", step.node, state);
+        if matches!(state, StepState::PoweredOn | StepState::AlreadyOn) {
+            powered |= bit(step.node);
+        }
+        results.push(StepResult {
+            node: step.node,
+            state,
+            error,
+        });
+    }
+}
+
+fn bit(node: u8) -> u8 {
+    1 << (node - 1)
+}
+
+fn staggered(node_count: usize) -> PowerPreset {
+    PowerPreset {
+        steps: (1..=node_count as u8)
+            .map(|node| PresetStep {
+                node,
+                delay: if node == 1 {
+                    0
+                } else {
+                    STAGGER.as_millis() as u64
+                },
+                requires: Vec::new(),
+            })
+            .collect(),
+        exclusive: false,
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), PresetError> {
+    if name == STAGGERED_PRESET {
+        return Err(PresetError::BuiltIn(name.to_string()));
+    }
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+    if !valid {
+        return Err(PresetError::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+fn validate(preset: &PowerPreset, node_count: usize) -> Result<(), PresetError> {
+    let invalid = |reason: String| Err(PresetError::Invalid(reason));
+    if preset.steps.is_empty() {
+        return invalid("a preset needs at least one step".to_string());
+    }
+    let mut earlier = 0u8;
+    for step in &preset.steps {
+        if step.node == 0 || step.node as usize > node_count {
+            return invalid(format!("node {} does not exist", step.node));
+        }
+        if earlier & bit(step.node) != 0 {
+            return invalid(format!("node {} is listed more than once", step.node));
+        }
+        if Duration::from_millis(step.delay) > MAX_DELAY {
+            return invalid(format!("delay exceeds {} seconds", MAX_DELAY.as_secs()));
+        }
+        if let Some(node) = step
+            .requires
+            .iter()
+            .find(|n| **n == 0 || **n as usize > node_count || earlier & bit(**n) == 0)
+        {
+            return invalid(format!(
+                "node {} requires node {}, which is not powered on before it",
+                step.node, node
+            ));
+        }
+        earlier |= bit(step.node);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(node: u8, requires: &[u8]) -> PresetStep {
+        PresetStep {
+            node,
+            delay: 0,
+            requires: requires.to_vec(),
+        }
+    }
+
+    #[test]
+    fn validation() {
+        let preset = |steps| PowerPreset {
+            steps,
+            exclusive: false,
+        };
+        assert!(validate(&preset(vec![step(3, &[]), step(1, &[3])]), 4).is_ok());
+        assert!(validate(&preset(vec![]), 4).is_err());
+        assert!(validate(&preset(vec![step(5, &[])]), 4).is_err());
+        assert!(validate(&preset(vec![step(3, &[])]), 2).is_err());
+        assert!(validate(&preset(vec![step(1, &[]), step(1, &[])]), 4).is_err());
+        // requirements must be powered on by an earlier step
+        assert!(validate(&preset(vec![step(1, &[3]), step(3, &[])]), 4).is_err());
+        assert!(validate(&preset(vec![step(1, &[1])]), 4).is_err());
+        let mut slow = step(1, &[]);
+        slow.delay = 3_600_000;
+        assert!(validate(&preset(vec![slow]), 4).is_err());
+
+        assert!(validate(&staggered(4), 4).is_ok());
+        assert_eq!(staggered(2).steps[1].delay, 2000);
+    }
+
+    #[test]
+    fn names() {
+        assert!(validate_name("storage-first").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("compute only").is_err());
+        assert_eq!(
+            validate_name(STAGGERED_PRESET),
+            Err(PresetError::BuiltIn(STAGGERED_PRESET.to_string()))
+        );
+    }
+
+    #[test]
+    fn persisted_presets() {
+        let mut presets = PowerPresets::new();
+        presets.insert(
+            "storage_first".to_string(),
+            PowerPreset {
+                steps: vec![step(2, &[]), step(1, &[2])],
+                exclusive: true,
+            },
+        );
+        let bytes = bincode::serialize(&presets).unwrap();
+        assert_eq!(
+            bincode::deserialize::<PowerPresets>(&bytes).unwrap(),
+            presets
+        );
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Watches the supply of the board for brownouts: the ATX power supply
+//! dropping PS_OK while it is switched on, or the input voltage falling below
+//! `power.brownout.min_voltage`. A brownout is logged, reported through the
+//! notifier and handled as configured: the state is snapshotted first, as
+//! that is quick, and then nodes are powered off in `shutdown_order` to shed
+//! load before the rails collapse. The last brownout is written to
+//! [`LAST_BROWNOUT`], which is included in diagnostics bundles.
+use super::bmc_application::{BmcApplication, ACTIVATED_NODES_KEY};
+use super::lights_out::LightsOut;
+use super::notifier::Notifier;
+use crate::config::Brownout;
+use crate::hal::PsuState;
+use crate::utils::get_timestamp_unix;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::future;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::sleep;
+
+pub const LAST_BROWNOUT: &str = "/var/lib/bmcd/last_brownout.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BrownoutCause {
+    /// the power supply dropped PS_OK while switched on
+    PowerGood,
+    /// the input voltage fell below the configured minimum
+    Undervoltage,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BrownoutReport {
+    /// unix timestamp
+    pub timestamp: Option<u64>,
+    pub cause: BrownoutCause,
+    /// input voltage in mV, when a sensor is configured
+    pub voltage: Option<u32>,
+    /// bit-field of the nodes that were powered when the brownout hit
+    pub powered_nodes: u8,
+    /// nodes that were powered off in response
+    pub powered_off: Vec<u8>,
+}
+
+/// Checks the supply whenever PS_OK changes. The voltage sensor, and PS_OK
+/// on chips that cannot report its edges, are polled every `interval`, or
+/// less often while the lights are out as no node needs protecting then.
+pub fn run_power_monitor(
+    bmc: Arc<BmcApplication>,
+    notifier: Arc<Notifier>,
+    lights_out: Arc<LightsOut>,
+    config: Brownout,
+) {
+    let has_psu = match bmc.power_supply() {
+        Ok(state) => state.is_some(),
+        Err(e) => {
+            tracing::error!("reading power supply: {:#}", e);
+            false
+        }
+    };
+    if !has_psu && config.voltage_sensor.is_none() {
+        return;
+    }
+    let mut power_good = bmc.watch_power_good().filter(|_| has_psu);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.interval);
+        let mut in_brownout = false;
+        loop {
+            let psu = bmc.power_supply().unwrap_or_else(|e| {
+                tracing::debug!("reading power supply: {:#}", e);
+                None
+            });
+            let voltage = match &config.voltage_sensor {
+                Some(path) => read_voltage(path)
+                    .await
+                    .map_err(|e| tracing::debug!("reading input voltage: {:#}", e))
+                    .ok(),
+                None => None,
+            };
+            let cause = detect(psu, voltage, config.min_voltage);
+            match cause {
+                Some(cause) if !in_brownout => {
+                    handle_brownout(&bmc, &notifier, &config, cause, voltage).await
+                }
+                None if in_brownout => tracing::info!("supply recovered from brownout"),
+                _ => {}
+            }
+            in_brownout = cause.is_some();
+
+            let poll = (has_psu && power_good.is_none()) || config.voltage_sensor.is_some();
+            let tick = async {
+                if poll {
+                    lights_out.tick(&mut interval).await
+                } else {
+                    future::pending().await
+                }
+            };
+            let alive = tokio::select! {
+                alive = changed(&mut power_good) => alive,
+                _ = tick => true,
+            };
+            if !alive {
+                tracing::warn!("power good events stopped, polling the power supply");
+                power_good = None;
+            }
+        }
+    });
+}
+
+/// Waits for PS_OK to change, returns false when it is no longer reported.
+async fn changed(power_good: &mut Option<watch::Receiver<bool>>) -> bool {
+    match power_good {
+        Some(power_good) => power_good.changed().await.is_ok(),
+        None => future::pending().await,
+    }
+}
+
+fn detect(psu: Option<PsuState>, voltage: Option<u32>, min_voltage: u32) -> Option<BrownoutCause> {
+    if psu.is_some_and(|s| s.enabled && !s.power_good) {
+        Some(BrownoutCause::PowerGood)
+    } else if voltage.is_some_and(|v| v < min_voltage) {
+        Some(BrownoutCause::Undervoltage)
+    } else {
+        None
+    }
+}
+
+async fn read_voltage(path: &Path) -> anyhow::Result<u32> {
+    let value = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| path.display().to_string())?;
+    Ok(value.trim().parse()?)
+}
+
+async fn handle_brownout(
+    bmc: &BmcApplication,
+    notifier: &Notifier,
+    config: &Brownout,
+    cause: BrownoutCause,
+    voltage: Option<u32>,
+) {
+    let mut report = BrownoutReport {
+        timestamp: get_timestamp_unix(),
+        cause,
+        voltage,
+        powered_nodes: bmc.app_db.get::<u8>(ACTIVATED_NODES_KEY).await,
+        powered_off: Vec::new(),
+    };
+    let message = match voltage {
+        Some(mv) => format!("brownout ({:?}), input at {} mV", cause, mv),
+        None => format!("brownout ({:?})", cause),
+    };
+    tracing::error!("{}", message);
+
+    if config.snapshot {
+        if let Err(e) = bmc.app_db.sync_all().await {
+            tracing::error!("syncing persistency: {:#}", e);
+        }
+        write_report(&report).await;
+    }
+
+    for node in &config.shutdown_order {
+        let bit = 1u8 << (node - 1);
+        if report.powered_nodes & bit == 0 {
+            continue;
+        }
+        match bmc.cut_slot_power(bit).await {
+            Ok(()) => report.powered_off.push(*node),
+            Err(e) => tracing::error!("powering off node {}: {:#}", node, e),
+        }
+        sleep(config.shutdown_delay).await;
+    }
+    if !report.powered_off.is_empty() || !config.snapshot {
+        write_report(&report).await;
+    }
+
+    notifier.notify("brownout", message).await;
+}
+
+async fn write_report(report: &BrownoutReport) {
+    let result = async {
+        let content = serde_json::to_vec_pretty(report)?;
+        tokio::fs::write(LAST_BROWNOUT, content)
+            .await
+            .context(LAST_BROWNOUT)
+    };
+    if let Err(e) = result.await {
+        tracing::error!("writing brownout report: {:#}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brownout_causes() {
+        let on = |power_good| {
+            Some(PsuState {
+                enabled: true,
+                power_good,
+            })
+        };
+        assert_eq!(detect(on(true), Some(12_000), 11_400), None);
+        assert_eq!(
+            detect(on(false), Some(12_000), 11_400),
+            Some(BrownoutCause::PowerGood)
+        );
+        assert_eq!(
+            detect(on(true), Some(11_000), 11_400),
+            Some(BrownoutCause::Undervoltage)
+        );
+        // a supply that was switched off on purpose is not a brownout
+        let off = Some(PsuState {
+            enabled: false,
+            power_good: false,
+        });
+        assert_eq!(detect(off, None, 11_400), None);
+        assert_eq!(detect(None, None, 11_400), None);
+    }
+}
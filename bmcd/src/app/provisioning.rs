@@ -0,0 +1,275 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Provisioning mode, the first-boot setup of a BMC. The root file-system
+//! ships with a well-known root password, so a BMC that was never set up
+//! does not serve its API to the network until an administrator replaced
+//! that password. Until then, only the provisioning routes answer, and only
+//! to clients on a network that the BMC is attached to: they set the root
+//! password and optionally the hostname, time and network, after which
+//! [`Provisioning::complete`] unlocks the API.
+//!
+//! A BMC is unprovisioned when [`PROVISIONED_FLAG`] is missing and the
+//! accounts in the overlay are untouched, which holds for a new BMC and for
+//! one that had its accounts wiped by a factory reset. BMCs that were
+//! upgraded from firmware without provisioning mode have a changed
+//! `/etc/shadow` in the overlay, or run with the default password on
+//! purpose, in which case `provisioning.enabled` can be set to false.
+use super::bmc_application::{self, BmcApplication};
+use super::factory_reset::OVERLAY_UPPER;
+use super::network_config::{NetworkConfigurator, NetworkSettings, DEFAULT_ROLLBACK_TIMEOUT};
+use super::safe_mode::DEFAULT_PASSWORD;
+use super::time_sync::{self, TimeSettings};
+use super::users;
+use crate::utils::{is_link_local, is_valid_hostname, IpNetwork};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use thiserror::Error;
+
+/// Written once provisioning completed.
+pub const PROVISIONED_FLAG: &str = "/var/lib/bmcd/provisioned";
+const ADMIN_USER: &str = "root";
+const MIN_PASSWORD_LEN: usize = 8;
+
+#[derive(Debug, Error)]
+pub enum ProvisioningError {
+    #[error("the BMC is already provisioned")]
+    NotActive,
+    #[error("the root password must be set before provisioning completes")]
+    CredentialsMissing,
+    #[error("the password needs at least {MIN_PASSWORD_LEN} characters")]
+    PasswordTooShort,
+    #[error("the password that ships with the firmware cannot be used")]
+    DefaultPassword,
+    #[error("`{0}` is not a valid hostname")]
+    InvalidHostname(String),
+}
+
+/// Steps of the setup that were carried out.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Steps {
+    pub credentials: bool,
+    pub hostname: bool,
+    pub time: bool,
+    pub network: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProvisioningStatus {
+    pub active: bool,
+    pub steps: Steps,
+}
+
+#[derive(Debug)]
+pub struct Provisioning {
+    active: AtomicBool,
+    flag: PathBuf,
+    steps: Mutex<Steps>,
+}
+
+impl Provisioning {
+    pub fn detect(enabled: bool) -> Self {
+        Self::detect_in(
+            enabled,
+            PathBuf::from(PROVISIONED_FLAG),
+            Path::new(OVERLAY_UPPER),
+        )
+    }
+
+    fn detect_in(enabled: bool, flag: PathBuf, overlay: &Path) -> Self {
+        let active =
+            enabled && overlay.is_dir() && !flag.exists() && !overlay.join("etc/shadow").exists();
+        if active {
+            tracing::warn!("BMC is not provisioned, the API is locked until setup completes");
+        }
+        Self {
+            active: AtomicBool::new(active),
+            flag,
+            steps: Mutex::default(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn status(&self) -> ProvisioningStatus {
+        ProvisioningStatus {
+            active: self.is_active(),
+            steps: self.steps.lock().expect("steps lock poisoned").clone(),
+        }
+    }
+
+    /// Replaces the password of root, which is the one step that is required.
+    pub async fn set_credentials(&self, password: &str) -> anyhow::Result<()> {
+        self.ensure_active()?;
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Err(ProvisioningError::PasswordTooShort.into());
+        }
+        if password == DEFAULT_PASSWORD {
+            return Err(ProvisioningError::DefaultPassword.into());
+        }
+        users::set_password(ADMIN_USER, password, false).await?;
+        tracing::info!("provisioning: password of {} set", ADMIN_USER);
+        self.complete_step(|steps| steps.credentials = true);
+        Ok(())
+    }
+
+    pub async fn set_hostname(&self, hostname: &str) -> anyhow::Result<()> {
+        self.ensure_active()?;
+        if !is_valid_hostname(hostname) {
+            return Err(ProvisioningError::InvalidHostname(hostname.to_string()).into());
+        }
+        bmc_application::set_hostname(hostname).await?;
+        self.complete_step(|steps| steps.hostname = true);
+        Ok(())
+    }
+
+    /// Sets the clock, enables NTP with the given servers, or both.
+    pub async fn set_time(
+        &self,
+        bmc: &BmcApplication,
+        time: Option<DateTime<Utc>>,
+        ntp: Option<TimeSettings>,
+    ) -> anyhow::Result<()> {
+        self.ensure_active()?;
+        if let Some(time) = time {
+            time_sync::set_time(bmc, time).await?;
+        }
+        if let Some(ntp) = ntp {
+            time_sync::set_ntp(bmc, ntp).await?;
+        }
+        self.complete_step(|steps| steps.time = true);
+        Ok(())
+    }
+
+    /// Applies the network settings. They are rolled back as usual unless
+    /// provisioning completes within [`DEFAULT_ROLLBACK_TIMEOUT`], which
+    /// confirms them.
+    pub async fn set_network(
+        &self,
+        network: &NetworkConfigurator,
+        settings: NetworkSettings,
+    ) -> anyhow::Result<()> {
+        self.ensure_active()?;
+        network.apply(settings, DEFAULT_ROLLBACK_TIMEOUT).await?;
+        self.complete_step(|steps| steps.network = true);
+        Ok(())
+    }
+
+    /// Confirms a pending network change, writes [`PROVISIONED_FLAG`] and
+    /// unlocks the API.
+    pub async fn complete(&self, network: &NetworkConfigurator) -> anyhow::Result<()> {
+        self.ensure_active()?;
+        if !self.status().steps.credentials {
+            return Err(ProvisioningError::CredentialsMissing.into());
+        }
+        if network.rollback_deadline().is_some() {
+            network.confirm()?;
+        }
+        if let Some(parent) = self.flag.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&self.flag, b"")
+            .await
+            .map_err(|e| anyhow::anyhow!("{}: {}", self.flag.display(), e))?;
+        self.active.store(false, Ordering::Relaxed);
+        tracing::info!("provisioning completed, API unlocked");
+        Ok(())
+    }
+
+    fn ensure_active(&self) -> Result<(), ProvisioningError> {
+        if self.is_active() {
+            Ok(())
+        } else {
+            Err(ProvisioningError::NotActive)
+        }
+    }
+
+    fn complete_step(&self, step: impl FnOnce(&mut Steps)) {
+        step(&mut self.steps.lock().expect("steps lock poisoned"));
+    }
+}
+
+/// Whether a client may use the provisioning routes: the BMC itself, link-local
+/// addresses and addresses in one of the `local` networks of the BMC, which
+/// reach it without crossing a router.
+pub fn is_local_client(address: IpAddr, local: &[IpNetwork]) -> bool {
+    let link_local = match address {
+        IpAddr::V4(address) => address.is_link_local(),
+        IpAddr::V6(address) => is_link_local(&address),
+    };
+    address.is_loopback() || link_local || local.iter().any(|network| network.contains(address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn detection() {
+        let dir = TempDir::new("provisioning").unwrap();
+        let overlay = dir.path().join("upper");
+        let flag = dir.path().join("provisioned");
+
+        // no overlay, e.g. a development machine
+        assert!(!Provisioning::detect_in(true, flag.clone(), &overlay).is_active());
+
+        std::fs::create_dir_all(overlay.join("etc")).unwrap();
+        assert!(Provisioning::detect_in(true, flag.clone(), &overlay).is_active());
+        assert!(!Provisioning::detect_in(false, flag.clone(), &overlay).is_active());
+
+        std::fs::write(overlay.join("etc/shadow"), "").unwrap();
+        assert!(!Provisioning::detect_in(true, flag.clone(), &overlay).is_active());
+
+        std::fs::remove_file(overlay.join("etc/shadow")).unwrap();
+        std::fs::write(&flag, "").unwrap();
+        assert!(!Provisioning::detect_in(true, flag, &overlay).is_active());
+    }
+
+    #[test]
+    fn local_clients() {
+        let local = ["192.168.1.0/24".parse().unwrap()];
+        assert!(is_local_client("127.0.0.1".parse().unwrap(), &local));
+        assert!(is_local_client("::1".parse().unwrap(), &local));
+        assert!(is_local_client("169.254.10.2".parse().unwrap(), &local));
+        assert!(is_local_client("fe80::1".parse().unwrap(), &local));
+        assert!(is_local_client("192.168.1.20".parse().unwrap(), &local));
+        assert!(!is_local_client("192.168.2.20".parse().unwrap(), &local));
+        assert!(!is_local_client("2001:db8::1".parse().unwrap(), &local));
+    }
+
+    #[tokio::test]
+    async fn locked_once_completed() {
+        let dir = TempDir::new("provisioning").unwrap();
+        let provisioning = Provisioning {
+            active: AtomicBool::new(true),
+            flag: dir.path().join("bmcd/provisioned"),
+            steps: Mutex::default(),
+        };
+        let network = NetworkConfigurator::default();
+        assert!(provisioning.set_credentials("turing").await.is_err());
+        assert!(provisioning.complete(&network).await.is_err());
+
+        provisioning.complete_step(|steps| steps.credentials = true);
+        provisioning.complete(&network).await.unwrap();
+        assert!(dir.path().join("bmcd/provisioned").exists());
+        assert!(!provisioning.is_active());
+        assert!(provisioning.set_hostname("bmc").await.is_err());
+    }
+}
@@ -0,0 +1,178 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Tracks the subsystems that are initialized in the background after the
+//! API is up. Requests to a subsystem that is still pending may fail or see
+//! the state from before it was initialized; clients that need everything
+//! wait for [`Readiness::status`] to report ready.
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubsystemState {
+    Pending,
+    Ready,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Subsystem {
+    pub state: SubsystemState,
+    /// time from daemon start until the subsystem finished initializing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ready_after_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadinessStatus {
+    /// all subsystems are initialized
+    pub ready: bool,
+    pub subsystems: BTreeMap<&'static str, Subsystem>,
+}
+
+pub struct Readiness {
+    started: Instant,
+    subsystems: Mutex<BTreeMap<&'static str, Subsystem>>,
+    changed: Notify,
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            subsystems: Mutex::new(BTreeMap::new()),
+            changed: Notify::new(),
+        }
+    }
+}
+
+impl Readiness {
+    /// Runs `init` for the subsystem `name` in the background. A failed
+    /// initialization is logged and reported, it does not stop the daemon.
+    pub fn spawn<F>(self: &Arc<Self>, name: &'static str, init: F)
+    where
+        F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        self.update(name, SubsystemState::Pending, None);
+        let readiness = self.clone();
+        tokio::spawn(async move {
+            let result = init.await;
+            readiness.complete(name, result);
+        });
+    }
+
+    /// Records the outcome of a subsystem that was initialized in place.
+    pub fn complete(&self, name: &'static str, result: anyhow::Result<()>) {
+        match result {
+            Ok(()) => {
+                self.update(name, SubsystemState::Ready, None);
+                tracing::info!("{} initialized after {:?}", name, self.started.elapsed());
+            }
+            Err(e) => {
+                tracing::error!("initializing {}: {:#}", name, e);
+                self.update(name, SubsystemState::Failed, Some(format!("{:#}", e)));
+            }
+        }
+    }
+
+    fn update(&self, name: &'static str, state: SubsystemState, error: Option<String>) {
+        let ready_after_ms =
+            (state != SubsystemState::Pending).then(|| self.started.elapsed().as_millis() as u64);
+        self.subsystems
+            .lock()
+            .expect("readiness lock poisoned")
+            .insert(
+                name,
+                Subsystem {
+                    state,
+                    ready_after_ms,
+                    error,
+                },
+            );
+        self.changed.notify_waiters();
+    }
+
+    /// Waits until no subsystem is pending anymore, whether its
+    /// initialization succeeded or not.
+    pub async fn settled(&self) -> ReadinessStatus {
+        loop {
+            let changed = self.changed.notified();
+            let status = self.status();
+            if status
+                .subsystems
+                .values()
+                .all(|s| s.state != SubsystemState::Pending)
+            {
+                return status;
+            }
+            changed.await;
+        }
+    }
+
+    pub fn status(&self) -> ReadinessStatus {
+        let subsystems = self
+            .subsystems
+            .lock()
+            .expect("readiness lock poisoned")
+            .clone();
+        ReadinessStatus {
+            ready: subsystems
+                .values()
+                .all(|s| s.state == SubsystemState::Ready),
+            subsystems,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn background_initialization() {
+        let readiness = Arc::new(Readiness::default());
+        let (done, wait) = oneshot::channel::<()>();
+        readiness.spawn("usb", async move {
+            wait.await?;
+            Ok(())
+        });
+        readiness.complete("serial", Ok(()));
+
+        let status = readiness.status();
+        assert!(!status.ready);
+        assert_eq!(status.subsystems["usb"].state, SubsystemState::Pending);
+        assert!(status.subsystems["usb"].ready_after_ms.is_none());
+        assert_eq!(status.subsystems["serial"].state, SubsystemState::Ready);
+
+        done.send(()).unwrap();
+        assert!(readiness.settled().await.ready);
+
+        readiness.complete("cooling", Err(anyhow!("no fan")));
+        let status = readiness.status();
+        assert!(!status.ready);
+        assert_eq!(status.subsystems["cooling"].state, SubsystemState::Failed);
+        assert_eq!(
+            status.subsystems["cooling"].error.as_deref(),
+            Some("no fan")
+        );
+    }
+}
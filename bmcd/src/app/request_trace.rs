@@ -0,0 +1,293 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Keeps an in-memory timeline of the log events of recent API requests.
+//!
+//! Every request runs inside a [`REQUEST_SPAN`] span carrying its trace ID.
+//! Events logged within that span, including those of background tasks that
+//! were instrumented with it, are appended to the timeline of the request.
+//! This also captures failures that happen after the response was sent, such
+//! as a flash job that fails halfway.
+use crate::utils::get_timestamp_unix;
+use rand::Rng;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+use tracing_subscriber::{Layer, Registry};
+
+/// Name of the span that marks the scope of an API request.
+pub const REQUEST_SPAN: &str = "request";
+/// Number of requests kept in memory.
+const CAPACITY: usize = 64;
+/// Events kept per request, later events are counted but dropped.
+const MAX_EVENTS: usize = 256;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceEvent {
+    /// milliseconds since the start of the request
+    pub offset_ms: u64,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestTrace {
+    pub id: String,
+    pub method: String,
+    pub path: String,
+    pub started: Option<u64>,
+    /// `None` while the request is in flight
+    pub status: Option<u16>,
+    pub duration_ms: Option<u64>,
+    pub events: Vec<TraceEvent>,
+    pub dropped_events: usize,
+    #[serde(skip)]
+    start: Instant,
+}
+
+#[derive(Default)]
+pub struct RequestTraces {
+    traces: Mutex<VecDeque<RequestTrace>>,
+}
+
+impl RequestTraces {
+    pub fn begin(&self, id: &str, method: &str, path: &str) {
+        let mut traces = self.traces.lock().expect("trace lock poisoned");
+        if traces.len() == CAPACITY {
+            traces.pop_front();
+        }
+        traces.push_back(RequestTrace {
+            id: id.to_string(),
+            method: method.to_string(),
+            path: path.to_string(),
+            started: get_timestamp_unix(),
+            status: None,
+            duration_ms: None,
+            events: Vec::new(),
+            dropped_events: 0,
+            start: Instant::now(),
+        });
+    }
+
+    pub fn finish(&self, id: &str, status: u16) {
+        self.with_trace(id, |trace| {
+            trace.status = Some(status);
+            trace.duration_ms = Some(trace.start.elapsed().as_millis() as u64);
+        });
+    }
+
+    fn record(&self, id: &str, level: String, target: String, message: String) {
+        self.with_trace(id, |trace| {
+            if trace.events.len() == MAX_EVENTS {
+                trace.dropped_events += 1;
+                return;
+            }
+            trace.events.push(TraceEvent {
+                offset_ms: trace.start.elapsed().as_millis() as u64,
+                level,
+                target,
+                message,
+            });
+        });
+    }
+
+    fn with_trace(&self, id: &str, f: impl FnOnce(&mut RequestTrace)) {
+        let mut traces = self.traces.lock().expect("trace lock poisoned");
+        if let Some(trace) = traces.iter_mut().rev().find(|t| t.id == id) {
+            f(trace);
+        }
+    }
+
+    /// Recent requests, most recent first.
+    pub fn recent(&self) -> Vec<RequestTrace> {
+        let traces = self.traces.lock().expect("trace lock poisoned");
+        traces.iter().rev().cloned().collect()
+    }
+
+    /// Like [`Self::recent`], but gives up when the traces are locked.
+    pub fn try_recent(&self) -> Option<Vec<RequestTrace>> {
+        let traces = self.traces.try_lock().ok()?;
+        Some(traces.iter().rev().cloned().collect())
+    }
+
+    pub fn get(&self, id: &str) -> Option<RequestTrace> {
+        let traces = self.traces.lock().expect("trace lock poisoned");
+        traces.iter().rev().find(|t| t.id == id).cloned()
+    }
+}
+
+pub fn new_trace_id() -> String {
+    hex::encode(rand::rng().random::<[u8; 8]>())
+}
+
+/// Accepts trace IDs handed in by clients, as long as they are short and
+/// printable.
+pub fn valid_trace_id(id: &str) -> bool {
+    (1..=64).contains(&id.len())
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Stored in the extensions of request spans.
+struct TraceId(String);
+
+/// Trace ID of the request in whose scope `span` runs.
+pub fn trace_id_of<S>(span: &SpanRef<'_, S>) -> Option<String>
+where
+    S: for<'a> LookupSpan<'a>,
+{
+    span.scope()
+        .find_map(|s| s.extensions().get::<TraceId>().map(|t| t.0.clone()))
+}
+
+/// Trace ID of the request the calling code runs for, used to correlate
+/// notifications with requests.
+pub fn current_trace_id() -> Option<String> {
+    tracing::Span::current()
+        .with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<Registry>()?;
+            trace_id_of(&registry.span(id)?)
+        })
+        .flatten()
+}
+
+/// Records the events logged within request spans into [`RequestTraces`].
+pub struct TraceLayer {
+    traces: Arc<RequestTraces>,
+}
+
+impl TraceLayer {
+    pub fn new(traces: Arc<RequestTraces>) -> Self {
+        Self { traces }
+    }
+}
+
+impl<S> Layer<S> for TraceLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if attrs.metadata().name() != REQUEST_SPAN {
+            return;
+        }
+        let mut visitor = FieldVisitor::new("trace_id");
+        attrs.record(&mut visitor);
+        if let (Some(trace_id), Some(span)) = (visitor.value, ctx.span(id)) {
+            span.extensions_mut().insert(TraceId(trace_id));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(trace_id) = ctx.event_span(event).and_then(|s| trace_id_of(&s)) else {
+            return;
+        };
+        let mut visitor = FieldVisitor::new("message");
+        event.record(&mut visitor);
+        let metadata = event.metadata();
+        self.traces.record(
+            &trace_id,
+            metadata.level().to_string(),
+            metadata.target().to_string(),
+            visitor.value.unwrap_or_default(),
+        );
+    }
+}
+
+/// Extracts a single field as string.
+struct FieldVisitor {
+    name: &'static str,
+    value: Option<String>,
+}
+
+impl FieldVisitor {
+    fn new(name: &'static str) -> Self {
+        Self { name, value: None }
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == self.name {
+            self.value = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == self.name {
+            self.value = Some(format!("{:?}", value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Instrument;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[tokio::test]
+    async fn events_follow_request_span() {
+        let traces = Arc::new(RequestTraces::default());
+        let subscriber = tracing_subscriber::registry().with(TraceLayer::new(traces.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        traces.begin("abc", "POST", "/api/bmc");
+        let span = tracing::info_span!(REQUEST_SPAN, trace_id = "abc");
+        async {
+            tracing::info!("flashing node 1");
+            assert_eq!(current_trace_id().as_deref(), Some("abc"));
+        }
+        .instrument(span.clone())
+        .await;
+        traces.finish("abc", 200);
+
+        // a background task that outlives the request
+        async { tracing::error!("write failed") }
+            .instrument(span)
+            .await;
+        tracing::info!("unrelated");
+
+        let trace = traces.get("abc").unwrap();
+        assert_eq!(trace.status, Some(200));
+        let messages: Vec<_> = trace.events.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(messages, ["flashing node 1", "write failed"]);
+        assert!(current_trace_id().is_none());
+    }
+
+    #[test]
+    fn capacity_is_bounded() {
+        let traces = RequestTraces::default();
+        for i in 0..CAPACITY + 1 {
+            traces.begin(&i.to_string(), "GET", "/");
+        }
+        assert!(traces.get("0").is_none());
+        assert_eq!(traces.recent()[0].id, CAPACITY.to_string());
+    }
+
+    #[test]
+    fn client_trace_ids() {
+        assert!(valid_trace_id(&new_trace_id()));
+        assert!(valid_trace_id("req-42"));
+        assert!(!valid_trace_id(""));
+        assert!(!valid_trace_id("a b"));
+    }
+}
@@ -0,0 +1,145 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Concurrency control for the resource-style routes under `/resources`, with
+//! which a Terraform or OpenTofu provider manages a board. Every resource has
+//! an ETag, a hash of its representation. A write may send the ETag that the
+//! client last read in `If-Match`, and fails with `412 Precondition Failed`
+//! when the resource changed in the meantime; `If-None-Match: *` only creates
+//! a resource that does not exist yet. Writes to resources run one at a time,
+//! so of two clients that read the same ETag only the first one succeeds.
+use super::bmc_application::{BmcApplication, NodeInfos, NODE_INFO_KEY};
+use crate::error::BmcError;
+use crate::hal::NodeId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use tokio::sync::{Mutex, MutexGuard};
+
+/// Strong ETag of the representation `value`.
+pub fn etag(value: &impl Serialize) -> String {
+    let json = serde_json::to_vec(value).expect("resources serialize");
+    let digest = Sha256::digest(json);
+    format!("\"{}\"", hex::encode(&digest[..16]))
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Precondition {
+    #[default]
+    None,
+    /// the resource has one of the ETags, `*` matches any existing resource
+    IfMatch(Vec<String>),
+    /// the resource does not exist
+    IfNoneMatch,
+}
+
+impl Precondition {
+    /// Checks the precondition against the current ETag of `resource`,
+    /// `None` when the resource does not exist.
+    pub fn check(&self, resource: &str, current: Option<&str>) -> Result<(), BmcError> {
+        let satisfied = match self {
+            Precondition::None => true,
+            Precondition::IfMatch(tags) => {
+                current.is_some_and(|etag| tags.iter().any(|tag| tag == "*" || tag == etag))
+            }
+            Precondition::IfNoneMatch => current.is_none(),
+        };
+        if satisfied {
+            Ok(())
+        } else {
+            Err(BmcError::PreconditionFailed {
+                resource: Cow::Owned(resource.to_string()),
+                etag: current.map(str::to_string),
+            })
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Resources {
+    write_lock: Mutex<()>,
+}
+
+impl Resources {
+    /// Held from reading the current ETag until the write is done.
+    pub async fn lock(&self) -> MutexGuard<'_, ()> {
+        self.write_lock.lock().await
+    }
+}
+
+/// Meta-data of a node slot, as in the `nodes` section of the configuration.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeConfig {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub module_name: Option<String>,
+    #[serde(default)]
+    pub uart_baud: Option<u32>,
+}
+
+pub async fn node_config(bmc: &BmcApplication, node: NodeId) -> NodeConfig {
+    let info = &bmc.app_db.get::<NodeInfos>(NODE_INFO_KEY).await[node as usize];
+    NodeConfig {
+        name: info.name.clone(),
+        module_name: info.module_name.clone(),
+        uart_baud: info.uart_baud,
+    }
+}
+
+/// Replaces the meta-data of `node`; fields that are `None` are cleared.
+pub async fn set_node_config(bmc: &BmcApplication, node: NodeId, config: NodeConfig) {
+    let mut infos = bmc.app_db.get::<NodeInfos>(NODE_INFO_KEY).await;
+    let info = &mut infos[node as usize];
+    info.name = config.name;
+    info.module_name = config.module_name;
+    info.uart_baud = config.uart_baud;
+    bmc.app_db.set(NODE_INFO_KEY, infos).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+
+    #[test]
+    fn preconditions() {
+        let current = etag(&NodeConfig::default());
+        assert_eq!(current.len(), 34);
+        assert_ne!(
+            current,
+            etag(&NodeConfig {
+                name: Some("rk1-a".to_string()),
+                ..Default::default()
+            })
+        );
+
+        assert!(Precondition::None.check("node 1", None).is_ok());
+        let matching = Precondition::IfMatch(vec!["\"other\"".to_string(), current.clone()]);
+        assert!(matching.check("node 1", Some(&current)).is_ok());
+        assert!(matching.check("node 1", None).is_err());
+        let any = Precondition::IfMatch(vec!["*".to_string()]);
+        assert!(any.check("node 1", Some(&current)).is_ok());
+        assert!(Precondition::IfNoneMatch.check("node 1", None).is_ok());
+
+        let error = Precondition::IfMatch(vec!["\"stale\"".to_string()])
+            .check("node 1", Some(&current))
+            .unwrap_err();
+        assert_eq!(
+            error.status(),
+            actix_web::http::StatusCode::PRECONDITION_FAILED
+        );
+        assert_eq!(error.to_string(), "node 1 was changed by another client");
+    }
+}
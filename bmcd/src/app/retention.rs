@@ -0,0 +1,299 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Retention of the files that bmcd writes to flash. A reaper periodically
+//! rotates logs that are appended to, removes files older than the maximum age
+//! of their category and then the oldest files until the category fits its
+//! maximum size. Metrics are not listed; they are computed on request and
+//! never stored.
+use super::crash_report::CRASH_REPORT;
+use crate::config::{self, RetentionPolicy};
+use crate::utils::get_timestamp_unix;
+use serde::Serialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    AuditLogs,
+    CrashReports,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CategoryUsage {
+    pub category: Category,
+    pub path: PathBuf,
+    pub files: usize,
+    pub size: u64,
+    /// unix timestamp of the last change of the oldest file
+    pub oldest: Option<u64>,
+    /// 0 for no limit
+    pub max_size: u64,
+    /// in seconds
+    pub max_age: Option<u64>,
+}
+
+pub struct Reaper {
+    interval: Duration,
+    /// the files of a category are a file and its rotated copies next to it,
+    /// `<name>.<timestamp>`
+    categories: Vec<(Category, PathBuf, RetentionPolicy)>,
+}
+
+impl Reaper {
+    pub fn new(config: &config::Config) -> Self {
+        let retention = &config.retention;
+        Self {
+            interval: retention.interval,
+            categories: vec![
+                (
+                    Category::AuditLogs,
+                    config.i2c.audit_log.clone(),
+                    retention.audit_logs.clone(),
+                ),
+                (
+                    Category::CrashReports,
+                    PathBuf::from(CRASH_REPORT),
+                    retention.crash_reports.clone(),
+                ),
+            ],
+        }
+    }
+
+    pub fn usage(&self) -> Vec<CategoryUsage> {
+        self.categories
+            .iter()
+            .map(|(category, file, policy)| {
+                let files = log_files(file);
+                CategoryUsage {
+                    category: *category,
+                    path: file.clone(),
+                    files: files.len(),
+                    size: files.iter().map(|f| f.size).sum(),
+                    oldest: files
+                        .iter()
+                        .map(|f| f.modified)
+                        .min()
+                        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                        .map(|d| d.as_secs()),
+                    max_size: policy.max_size,
+                    max_age: policy.max_age.map(|age| age.as_secs()),
+                }
+            })
+            .collect()
+    }
+
+    /// Applies the policies of all categories once.
+    pub fn reap(&self) {
+        for (category, file, policy) in &self.categories {
+            if let Err(e) = apply(file, policy) {
+                tracing::warn!("retention of {:?}: {}", category, e);
+            }
+        }
+    }
+
+    pub fn run(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.interval);
+            loop {
+                interval.tick().await;
+                let this = self.clone();
+                let _ = tokio::task::spawn_blocking(move || this.reap()).await;
+            }
+        });
+    }
+}
+
+/// `file` and its rotated copies.
+fn log_files(file: &Path) -> Vec<FileInfo> {
+    let (Some(dir), Some(name)) = (file.parent(), file.file_name()) else {
+        return Vec::new();
+    };
+    let prefix = format!("{}.", name.to_string_lossy());
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter(|entry| {
+            let entry_name = entry.file_name();
+            entry_name == name || entry_name.to_string_lossy().starts_with(&prefix)
+        })
+        .filter_map(|entry| FileInfo::new(entry.path(), &entry.metadata().ok()?))
+        .collect()
+}
+
+#[derive(Debug)]
+struct FileInfo {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+impl FileInfo {
+    fn new(path: PathBuf, metadata: &std::fs::Metadata) -> Option<Self> {
+        metadata.is_file().then(|| Self {
+            path,
+            size: metadata.len(),
+            modified: metadata.modified().unwrap_or(UNIX_EPOCH),
+        })
+    }
+}
+
+fn apply(file: &Path, policy: &RetentionPolicy) -> io::Result<()> {
+    if policy.rotate_size > 0 {
+        rotate(file, policy.rotate_size)?;
+    }
+
+    let mut files = log_files(file);
+    files.sort_by_key(|f| f.modified);
+    if let Some(max_age) = policy.max_age {
+        let now = SystemTime::now();
+        let (expired, kept): (Vec<_>, Vec<_>) = files.into_iter().partition(|f| {
+            now.duration_since(f.modified)
+                .is_ok_and(|age| age > max_age)
+        });
+        for file in expired {
+            tracing::info!(
+                "removing {}, it is older than its retention",
+                file.path.display()
+            );
+            remove(&file.path)?;
+        }
+        files = kept;
+    }
+    if policy.max_size > 0 {
+        let mut total: u64 = files.iter().map(|f| f.size).sum();
+        for file in files {
+            if total <= policy.max_size {
+                break;
+            }
+            tracing::info!(
+                "removing {} to stay within its retention",
+                file.path.display()
+            );
+            remove(&file.path)?;
+            total -= file.size;
+        }
+    }
+    Ok(())
+}
+
+/// Moves `file` aside once it reaches `size`, the next append starts a new
+/// file.
+fn rotate(file: &Path, size: u64) -> io::Result<()> {
+    match std::fs::metadata(file) {
+        Ok(metadata) if metadata.len() >= size => {
+            let mut rotated = file.as_os_str().to_owned();
+            rotated.push(format!(".{}", get_timestamp_unix().unwrap_or_default()));
+            tracing::info!("rotating {}", file.display());
+            std::fs::rename(file, rotated)
+        }
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Size of the files below `dir`, 0 when it does not exist.
+pub fn dir_size(dir: &Path) -> u64 {
+    files(dir).iter().map(|f| f.size).sum()
+}
+
+/// Files below `dir`.
+fn files(dir: &Path) -> Vec<FileInfo> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else if let Some(file) = FileInfo::new(entry.path(), &metadata) {
+                files.push(file);
+            }
+        }
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn write(path: &Path, size: usize, age: u64) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, vec![b'x'; size]).unwrap();
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(age))
+            .unwrap();
+    }
+
+    #[test]
+    fn prunes_oldest_copies() {
+        let dir = TempDir::new("retention").unwrap();
+        let log = dir.path().join("audit.log");
+        write(&log, 40, 10);
+        write(&dir.path().join("audit.log.3"), 40, 30);
+        write(&dir.path().join("audit.log.2"), 40, 20);
+        write(&dir.path().join("audit.log.1"), 1, 1000);
+        write(&dir.path().join("other.log"), 40, 1000);
+        let policy = RetentionPolicy {
+            max_size: 100,
+            max_age: Some(Duration::from_secs(100)),
+            rotate_size: 0,
+        };
+        apply(&log, &policy).unwrap();
+        assert!(!dir.path().join("audit.log.1").exists());
+        assert!(!dir.path().join("audit.log.3").exists());
+        assert!(log.exists() && dir.path().join("audit.log.2").exists());
+        assert!(dir.path().join("other.log").exists());
+        assert_eq!(dir_size(dir.path()), 120);
+    }
+
+    #[test]
+    fn rotates_logs() {
+        let dir = TempDir::new("retention").unwrap();
+        let log = dir.path().join("audit.log");
+        write(&dir.path().join("audit.log.100"), 60, 20);
+        write(&dir.path().join("other.log"), 60, 20);
+        write(&log, 50, 0);
+        let policy = RetentionPolicy {
+            max_size: 100,
+            max_age: None,
+            rotate_size: 50,
+        };
+        apply(&log, &policy).unwrap();
+        assert!(!log.exists());
+        let files = log_files(&log);
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].size, 50);
+        assert!(dir.path().join("other.log").exists());
+    }
+}
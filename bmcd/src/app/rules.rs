@@ -0,0 +1,543 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Alert rules that react to telemetry and events without a client polling,
+//! e.g. "notify and power off node 2 when the SoC is above 80°C for 5 minutes
+//! while node 2 is on". A rule fires once all of its conditions have held for
+//! `hold` seconds, and fires again only after a condition stopped holding.
+//! Rules are stored in the persistency and evaluated every
+//! [`EVAL_INTERVAL`].
+use super::activity::ActivityMonitor;
+use super::bmc_application::{BmcApplication, ACTIVATED_NODES_KEY};
+use super::cooling_device::{thermal_root, thermal_zones};
+use super::notifier::Notifier;
+use crate::utils::get_timestamp_unix;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Persistency key of the stored rules.
+pub const RULES_KEY: &str = "rules";
+/// Event of the notifications of the `notify` action.
+pub const RULE_EVENT: &str = "rule";
+pub const MAX_RULES: usize = 32;
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_CONDITIONS: usize = 8;
+const MAX_ACTIONS: usize = 8;
+const EVAL_INTERVAL: Duration = Duration::from_secs(5);
+
+pub type StoredRules = BTreeMap<String, Rule>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rule {
+    /// all of them must hold
+    pub conditions: Vec<Condition>,
+    /// seconds the conditions must hold before the actions run
+    #[serde(default)]
+    pub hold: u64,
+    pub actions: Vec<Action>,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    /// a thermal zone, by its type such as `cpu-thermal` or its name such as
+    /// `thermal_zone0`, in °C
+    Temperature {
+        zone: String,
+        op: Comparison,
+        value: f64,
+    },
+    NodePower {
+        /// node number, starting from 1
+        node: u8,
+        on: bool,
+    },
+    /// current draw of a node in mA, as read by the activity monitor
+    NodeCurrent {
+        /// node number, starting from 1
+        node: u8,
+        op: Comparison,
+        value: f64,
+    },
+    /// a notification of `event` was sent in the last `within` seconds
+    Event { event: String, within: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Comparison {
+    #[serde(rename = ">")]
+    Above,
+    #[serde(rename = ">=")]
+    AtLeast,
+    #[serde(rename = "<")]
+    Below,
+    #[serde(rename = "<=")]
+    AtMost,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// sends a notification of [`RULE_EVENT`]
+    Notify {
+        message: String,
+    },
+    PowerOn {
+        node: u8,
+    },
+    PowerOff {
+        node: u8,
+    },
+    /// sets a cooling device, the system fan when `device` is omitted
+    SetFan {
+        #[serde(default)]
+        device: Option<String>,
+        speed: u64,
+    },
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum RuleError {
+    #[error("`{0}` is not a valid name. Use up to 64 of the characters [a-zA-Z0-9_.-]")]
+    InvalidName(String),
+    #[error("invalid rule: {0}")]
+    Invalid(String),
+    #[error("maximum of {MAX_RULES} rules reached")]
+    TooManyRules,
+    #[error("`{0}` does not exist")]
+    NotFound(String),
+}
+
+/// Evaluation state of a rule, kept in memory only.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RuleState {
+    /// unix timestamp since which all conditions hold
+    pub holding_since: Option<u64>,
+    pub fired: bool,
+    /// unix timestamp
+    pub last_fired: Option<u64>,
+    /// errors of the actions the last time the rule fired
+    pub errors: Vec<String>,
+    #[serde(skip)]
+    since: Option<Instant>,
+}
+
+impl RuleState {
+    /// Records whether the conditions hold at `now`, returns whether the rule
+    /// fires.
+    fn update(&mut self, holds: bool, now: Instant, hold: Duration) -> bool {
+        if !holds {
+            self.since = None;
+            self.holding_since = None;
+            self.fired = false;
+            return false;
+        }
+        let since = *self.since.get_or_insert(now);
+        if self.holding_since.is_none() {
+            self.holding_since = get_timestamp_unix();
+        }
+        if self.fired || now.duration_since(since) < hold {
+            return false;
+        }
+        self.fired = true;
+        self.last_fired = get_timestamp_unix();
+        true
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RuleStatus {
+    #[serde(flatten)]
+    pub rule: Rule,
+    pub state: RuleState,
+}
+
+/// Readings the conditions are evaluated against.
+#[derive(Debug, Default)]
+struct Telemetry {
+    /// °C by thermal zone type and name
+    temperatures: HashMap<String, f64>,
+    /// bit-field of the powered nodes
+    powered: u8,
+    /// mA by node
+    currents: HashMap<u8, u32>,
+    /// when each event was last sent
+    events: HashMap<String, Instant>,
+}
+
+impl Condition {
+    fn holds(&self, telemetry: &Telemetry, now: Instant) -> bool {
+        match self {
+            Condition::Temperature { zone, op, value } => telemetry
+                .temperatures
+                .get(zone)
+                .is_some_and(|t| op.compare(*t, *value)),
+            Condition::NodePower { node, on } => (telemetry.powered & bit(*node) != 0) == *on,
+            Condition::NodeCurrent { node, op, value } => telemetry
+                .currents
+                .get(node)
+                .is_some_and(|c| op.compare(f64::from(*c), *value)),
+            Condition::Event { event, within } => telemetry
+                .events
+                .get(event)
+                .is_some_and(|t| now.duration_since(*t) <= Duration::from_secs(*within)),
+        }
+    }
+
+    fn node(&self) -> Option<u8> {
+        match self {
+            Condition::NodePower { node, .. } | Condition::NodeCurrent { node, .. } => Some(*node),
+            _ => None,
+        }
+    }
+}
+
+impl Comparison {
+    fn compare(self, left: f64, right: f64) -> bool {
+        match self {
+            Comparison::Above => left > right,
+            Comparison::AtLeast => left >= right,
+            Comparison::Below => left < right,
+            Comparison::AtMost => left <= right,
+        }
+    }
+}
+
+pub struct Rules {
+    bmc: Arc<BmcApplication>,
+    notifier: Arc<Notifier>,
+    activity: Arc<ActivityMonitor>,
+    states: Mutex<HashMap<String, RuleState>>,
+    events: Mutex<HashMap<String, Instant>>,
+}
+
+impl Rules {
+    pub fn new(
+        bmc: Arc<BmcApplication>,
+        notifier: Arc<Notifier>,
+        activity: Arc<ActivityMonitor>,
+    ) -> Self {
+        Self {
+            bmc,
+            notifier,
+            activity,
+            states: Mutex::default(),
+            events: Mutex::default(),
+        }
+    }
+
+    pub async fn list(&self) -> BTreeMap<String, RuleStatus> {
+        let rules = self.bmc.app_db.get::<StoredRules>(RULES_KEY).await;
+        let states = self.lock_states();
+        rules
+            .into_iter()
+            .map(|(name, rule)| {
+                let state = states.get(&name).cloned().unwrap_or_default();
+                (name, RuleStatus { rule, state })
+            })
+            .collect()
+    }
+
+    pub async fn get(&self, name: &str) -> Result<RuleStatus, RuleError> {
+        self.list()
+            .await
+            .remove(name)
+            .ok_or_else(|| RuleError::NotFound(name.to_string()))
+    }
+
+    /// Adds or replaces a rule. A replaced rule starts over.
+    pub async fn save(&self, name: &str, rule: Rule) -> Result<(), RuleError> {
+        validate_name(name)?;
+        validate(&rule, self.bmc.board().node_count)?;
+        let mut rules = self.bmc.app_db.get::<StoredRules>(RULES_KEY).await;
+        if !rules.contains_key(name) && rules.len() >= MAX_RULES {
+            return Err(RuleError::TooManyRules);
+        }
+        rules.insert(name.to_string(), rule);
+        self.bmc.app_db.set(RULES_KEY, rules).await;
+        self.lock_states().remove(name);
+        tracing::info!("rule `{}` saved", name);
+        Ok(())
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<(), RuleError> {
+        let mut rules = self.bmc.app_db.get::<StoredRules>(RULES_KEY).await;
+        if rules.remove(name).is_none() {
+            return Err(RuleError::NotFound(name.to_string()));
+        }
+        self.bmc.app_db.set(RULES_KEY, rules).await;
+        self.lock_states().remove(name);
+        tracing::info!("rule `{}` deleted", name);
+        Ok(())
+    }
+
+    /// Records the events that are sent and evaluates the rules every
+    /// [`EVAL_INTERVAL`].
+    pub fn run(self: Arc<Self>) {
+        let mut events = self.notifier.subscribe();
+        let this = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        if let Some(name) = event["event"].as_str() {
+                            this.lock_events().insert(name.to_string(), Instant::now());
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(EVAL_INTERVAL);
+            loop {
+                interval.tick().await;
+                self.evaluate().await;
+            }
+        });
+    }
+
+    async fn evaluate(&self) {
+        let rules = self.bmc.app_db.get::<StoredRules>(RULES_KEY).await;
+        if rules.values().all(|rule| !rule.enabled) {
+            return;
+        }
+        let telemetry = Telemetry {
+            temperatures: read_temperatures(&thermal_root()),
+            powered: self.bmc.app_db.get::<u8>(ACTIVATED_NODES_KEY).await,
+            currents: self
+                .activity
+                .status()
+                .into_iter()
+                .filter_map(|a| Some((a.node, a.current?)))
+                .collect(),
+            events: self.lock_events().clone(),
+        };
+
+        let now = Instant::now();
+        let firing: Vec<_> = {
+            let mut states = self.lock_states();
+            states.retain(|name, _| rules.get(name).is_some_and(|r| r.enabled));
+            rules
+                .iter()
+                .filter(|(_, rule)| rule.enabled)
+                .filter(|(name, rule)| {
+                    let holds = rule.conditions.iter().all(|c| c.holds(&telemetry, now));
+                    let state = states.entry(name.to_string()).or_default();
+                    state.update(holds, now, Duration::from_secs(rule.hold))
+                })
+                .collect()
+        };
+
+        for (name, rule) in firing {
+            tracing::info!("rule `{}` fired", name);
+            let mut errors = Vec::new();
+            for action in &rule.actions {
+                if let Err(e) = self.execute(action).await {
+                    tracing::warn!("rule `{}`: {:?}: {:#}", name, action, e);
+                    errors.push(format!("{:#}", e));
+                }
+            }
+            if let Some(state) = self.lock_states().get_mut(name) {
+                state.errors = errors;
+            }
+        }
+    }
+
+    async fn execute(&self, action: &Action) -> anyhow::Result<()> {
+        match action {
+            Action::Notify { message } => {
+                self.notifier.notify(RULE_EVENT, message.clone()).await;
+                Ok(())
+            }
+            Action::PowerOn { node } => self.bmc.activate_slot(bit(*node), bit(*node)).await,
+            Action::PowerOff { node } => self.bmc.activate_slot(0, bit(*node)).await,
+            Action::SetFan { device, speed } => {
+                self.bmc
+                    .set_cooling_speed(device.as_deref().unwrap_or("system fan"), *speed as _)
+                    .await
+            }
+        }
+    }
+
+    fn lock_states(&self) -> std::sync::MutexGuard<'_, HashMap<String, RuleState>> {
+        self.states.lock().expect("rule states poisoned")
+    }
+
+    fn lock_events(&self) -> std::sync::MutexGuard<'_, HashMap<String, Instant>> {
+        self.events.lock().expect("rule events poisoned")
+    }
+}
+
+fn bit(node: u8) -> u8 {
+    1 << (node - 1)
+}
+
+/// Temperatures of the thermal zones below `root`, by their type and name.
+fn read_temperatures(root: &Path) -> HashMap<String, f64> {
+    let mut temperatures = HashMap::new();
+    for zone in thermal_zones(root) {
+        if let Some(zone_type) = zone.zone_type {
+            temperatures.insert(zone_type, zone.celsius);
+        }
+        temperatures.insert(zone.name, zone.celsius);
+    }
+    temperatures
+}
+
+fn validate_name(name: &str) -> Result<(), RuleError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+    if !valid {
+        return Err(RuleError::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+fn validate(rule: &Rule, node_count: usize) -> Result<(), RuleError> {
+    let invalid = |reason: String| Err(RuleError::Invalid(reason));
+    if rule.conditions.is_empty() || rule.conditions.len() > MAX_CONDITIONS {
+        return invalid(format!("a rule needs 1 to {} conditions", MAX_CONDITIONS));
+    }
+    if rule.actions.is_empty() || rule.actions.len() > MAX_ACTIONS {
+        return invalid(format!("a rule needs 1 to {} actions", MAX_ACTIONS));
+    }
+    let action_nodes = rule.actions.iter().filter_map(|action| match action {
+        Action::PowerOn { node } | Action::PowerOff { node } => Some(*node),
+        _ => None,
+    });
+    for node in rule
+        .conditions
+        .iter()
+        .filter_map(Condition::node)
+        .chain(action_nodes)
+    {
+        if !(1..=node_count).contains(&usize::from(node)) {
+            return invalid(format!("node {} is out of range 1..{}", node, node_count));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn overheating() -> Rule {
+        serde_json::from_value(json!({
+            "conditions": [
+                {"temperature": {"zone": "cpu-thermal", "op": ">", "value": 80}},
+                {"node_power": {"node": 2, "on": true}},
+            ],
+            "hold": 300,
+            "actions": [
+                {"notify": {"message": "node 2 overheats"}},
+                {"power_off": {"node": 2}},
+            ],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn conditions() {
+        let rule = overheating();
+        assert!(rule.enabled);
+        let now = Instant::now();
+        let mut telemetry = Telemetry {
+            temperatures: HashMap::from([("cpu-thermal".to_string(), 85.0)]),
+            powered: 0b0010,
+            ..Default::default()
+        };
+        assert!(rule.conditions.iter().all(|c| c.holds(&telemetry, now)));
+        telemetry.powered = 0b0001;
+        assert!(!rule.conditions.iter().all(|c| c.holds(&telemetry, now)));
+        // a zone that cannot be read does not hold
+        telemetry.temperatures.clear();
+        assert!(!rule.conditions[0].holds(&telemetry, now));
+
+        let event = Condition::Event {
+            event: "node_stalled".to_string(),
+            within: 60,
+        };
+        assert!(!event.holds(&telemetry, now));
+        telemetry.events.insert("node_stalled".to_string(), now);
+        assert!(event.holds(&telemetry, now + Duration::from_secs(60)));
+        assert!(!event.holds(&telemetry, now + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn fires_once_per_episode() {
+        let hold = Duration::from_secs(300);
+        let start = Instant::now();
+        let mut state = RuleState::default();
+        assert!(!state.update(true, start, hold));
+        assert!(state.holding_since.is_some());
+        assert!(!state.update(true, start + Duration::from_secs(299), hold));
+        assert!(state.update(true, start + hold, hold));
+        assert!(!state.update(true, start + 2 * hold, hold));
+        assert!(!state.update(false, start + 2 * hold, hold));
+        assert!(!state.fired && state.holding_since.is_none());
+        assert!(state.update(true, start + 2 * hold, Duration::ZERO));
+    }
+
+    #[test]
+    fn validation() {
+        assert_eq!(validate(&overheating(), 4), Ok(()));
+        assert!(matches!(
+            validate(&overheating(), 1),
+            Err(RuleError::Invalid(_))
+        ));
+        let mut rule = overheating();
+        rule.actions.clear();
+        assert!(validate(&rule, 4).is_err());
+        assert!(validate_name("hot node").is_err());
+    }
+
+    #[test]
+    fn persisted_rules() {
+        let rules = StoredRules::from([("hot".to_string(), overheating())]);
+        let bytes = bincode::serialize(&rules).unwrap();
+        assert_eq!(bincode::deserialize::<StoredRules>(&bytes).unwrap(), rules);
+    }
+
+    #[test]
+    fn temperatures() {
+        let dir = tempdir::TempDir::new("rules").unwrap();
+        let zone = dir.path().join("thermal_zone0");
+        std::fs::create_dir(&zone).unwrap();
+        std::fs::write(zone.join("type"), "cpu-thermal\n").unwrap();
+        std::fs::write(zone.join("temp"), "45500\n").unwrap();
+        std::fs::create_dir(dir.path().join("cooling_device0")).unwrap();
+        let temperatures = read_temperatures(dir.path());
+        assert_eq!(temperatures.len(), 2);
+        assert_eq!(temperatures["cpu-thermal"], 45.5);
+        assert_eq!(temperatures["thermal_zone0"], 45.5);
+    }
+}
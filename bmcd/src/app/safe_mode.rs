@@ -0,0 +1,280 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Safe mode, a minimal start of bmcd to recover from a configuration or
+//! application state that keeps the daemon from working. Only the API is
+//! served, with the built-in defaults on port 443 of all addresses. The
+//! configuration file and the persistency store are not loaded and the
+//! hardware is left alone. The only account is `root` with the default
+//! password `turing`.
+//!
+//! bmcd starts in safe mode when
+//! * it is started with `--safe-mode`,
+//! * the file [`SAFE_MODE_FLAG`] exists,
+//! * KEY1 is held while it starts, or
+//! * the last [`MAX_START_ATTEMPTS`] starts did not stay up for
+//!   [`HEALTHY_AFTER`].
+//!
+//! Leaving safe mode removes the flag file and resets the start counter, a
+//! daemon started with `--safe-mode` has to be started without it.
+use crate::config::Config;
+use crate::persistency::app_persistency::BIN_DATA;
+use crate::utils::{get_timestamp_unix, restart_daemon};
+use anyhow::Context;
+use evdev::KeyCode;
+use openssl::asn1::{Asn1Integer, Asn1Time};
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::x509::{X509NameBuilder, X509};
+use serde::Serialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub const SAFE_MODE_FLAG: &str = "/var/lib/bmcd/safe_mode";
+const START_ATTEMPTS: &str = "/var/lib/bmcd/start_attempts";
+pub const MAX_START_ATTEMPTS: u32 = 3;
+pub const HEALTHY_AFTER: Duration = Duration::from_secs(120);
+const DEFAULT_USER: &str = "root";
+/// Password of root in the root file-system that ships with the firmware.
+pub const DEFAULT_PASSWORD: &str = "turing";
+const BUTTONS: &str = "/dev/input/event0";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    Argument,
+    FlagFile,
+    Button,
+    RepeatedCrashes,
+}
+
+/// Counts this start and returns why bmcd has to start in safe mode, if it
+/// has to. Runs before the logger is set up.
+pub fn check(argument: bool) -> Option<Trigger> {
+    let attempts = count_start(Path::new(START_ATTEMPTS));
+    if argument {
+        Some(Trigger::Argument)
+    } else if Path::new(SAFE_MODE_FLAG).exists() {
+        Some(Trigger::FlagFile)
+    } else if button_held() {
+        Some(Trigger::Button)
+    } else if attempts > MAX_START_ATTEMPTS {
+        Some(Trigger::RepeatedCrashes)
+    } else {
+        None
+    }
+}
+
+/// Increments the number of consecutive starts stored in `path`.
+fn count_start(path: &Path) -> u32 {
+    let attempts = std::fs::read_to_string(path)
+        .ok()
+        .and_then(|s| s.trim().parse::<u32>().ok())
+        .unwrap_or(0)
+        .saturating_add(1);
+    // without a writable state directory crashes cannot be counted
+    let _ = std::fs::write(path, attempts.to_string());
+    attempts
+}
+
+/// Marks the start as successful, on an orderly exit or once bmcd has been
+/// up for [`HEALTHY_AFTER`].
+pub fn clear_start_attempts() {
+    match std::fs::remove_file(START_ATTEMPTS) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            tracing::warn!("{}: {}", START_ATTEMPTS, e);
+        }
+        _ => {}
+    }
+}
+
+fn button_held() -> bool {
+    evdev::Device::open(BUTTONS)
+        .and_then(|device| device.get_key_state())
+        .is_ok_and(|keys| keys.contains(KeyCode::KEY_1))
+}
+
+/// The single account of safe mode, as user name and crypt(3) hash.
+pub fn default_credentials() -> anyhow::Result<(String, String)> {
+    let hash = pwhash::sha512_crypt::hash(DEFAULT_PASSWORD)?;
+    Ok((DEFAULT_USER.to_string(), hash))
+}
+
+/// Used when the configured certificate cannot be loaded.
+pub fn self_signed_certificate() -> anyhow::Result<(PKey<Private>, X509)> {
+    let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", "bmcd safe mode")?;
+    let name = name.build();
+    let mut cert = X509::builder()?;
+    cert.set_version(2)?;
+    let serial = Asn1Integer::from_bn(BigNum::from_u32(1)?.as_ref())?;
+    cert.set_serial_number(serial.as_ref())?;
+    cert.set_subject_name(&name)?;
+    cert.set_issuer_name(&name)?;
+    cert.set_pubkey(&key)?;
+    cert.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+    cert.set_not_after(Asn1Time::days_from_now(365)?.as_ref())?;
+    cert.sign(&key, MessageDigest::sha256())?;
+    Ok((key, cert.build()))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SafeModeStatus {
+    pub trigger: Trigger,
+    /// unix timestamp
+    pub started_at: u64,
+    pub config_path: PathBuf,
+    /// why the configuration file cannot be loaded, if it cannot
+    pub config_error: Option<String>,
+}
+
+pub struct SafeMode {
+    status: Mutex<SafeModeStatus>,
+    store: PathBuf,
+}
+
+impl SafeMode {
+    pub fn new(trigger: Trigger, config_path: PathBuf) -> Self {
+        let config_error = Config::load(&config_path).err().map(|e| format!("{:#}", e));
+        Self {
+            status: Mutex::new(SafeModeStatus {
+                trigger,
+                started_at: get_timestamp_unix().unwrap_or_default(),
+                config_path,
+                config_error,
+            }),
+            store: PathBuf::from(BIN_DATA),
+        }
+    }
+
+    pub fn status(&self) -> SafeModeStatus {
+        self.status.lock().expect("safe mode lock poisoned").clone()
+    }
+
+    /// Content of the configuration file, empty when there is none.
+    pub async fn read_config(&self) -> anyhow::Result<String> {
+        let path = self.status().config_path;
+        match tokio::fs::read_to_string(&path).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+            result => result.with_context(|| path.display().to_string()),
+        }
+    }
+
+    /// Replaces the configuration file with `content` if it is valid. The
+    /// previous file is kept with a `.bak` suffix.
+    pub async fn write_config(&self, content: &str) -> anyhow::Result<()> {
+        let path = self.status().config_path;
+        let file_name = path
+            .file_name()
+            .context("configuration path has no file name")?
+            .to_string_lossy();
+        // keeps the extension, which selects the file format
+        let staged = path.with_file_name(format!(".new.{}", file_name));
+        tokio::fs::write(&staged, content)
+            .await
+            .with_context(|| staged.display().to_string())?;
+        if let Err(e) = Config::load(&staged) {
+            let _ = tokio::fs::remove_file(&staged).await;
+            return Err(e.context("invalid configuration"));
+        }
+        let backup = path.with_file_name(format!("{}.bak", file_name));
+        match tokio::fs::rename(&path, &backup).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| backup.display().to_string());
+            }
+            _ => {}
+        }
+        tokio::fs::rename(&staged, &path)
+            .await
+            .with_context(|| path.display().to_string())?;
+        self.status
+            .lock()
+            .expect("safe mode lock poisoned")
+            .config_error = None;
+        tracing::info!("replaced {}", path.display());
+        Ok(())
+    }
+
+    /// Moves the persistency store aside, so bmcd starts with an empty store.
+    /// Returns where the store was moved to.
+    pub async fn reset_store(&self) -> anyhow::Result<PathBuf> {
+        let backup = self.store.with_extension("bin.bak");
+        tokio::fs::rename(&self.store, &backup)
+            .await
+            .with_context(|| self.store.display().to_string())?;
+        tracing::info!("moved {} to {}", self.store.display(), backup.display());
+        Ok(backup)
+    }
+
+    /// Restarts bmcd in normal mode.
+    pub fn exit(&self) -> anyhow::Result<()> {
+        match std::fs::remove_file(SAFE_MODE_FLAG) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(e).context(SAFE_MODE_FLAG);
+            }
+            _ => {}
+        }
+        clear_start_attempts();
+        tracing::info!("leaving safe mode");
+        restart_daemon();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn start_attempts() {
+        let dir = TempDir::new("safe_mode").unwrap();
+        let path = dir.path().join("start_attempts");
+        assert_eq!(count_start(&path), 1);
+        assert_eq!(count_start(&path), 2);
+        std::fs::write(&path, "garbage").unwrap();
+        assert_eq!(count_start(&path), 1);
+    }
+
+    #[tokio::test]
+    async fn replace_config() {
+        let dir = TempDir::new("safe_mode").unwrap();
+        let path = dir.path().join("config.yaml");
+        std::fs::write(&path, "port: [not a port]\n").unwrap();
+        let safe_mode = SafeMode::new(Trigger::Argument, path.clone());
+        assert!(safe_mode.status().config_error.is_some());
+
+        assert!(safe_mode
+            .write_config("nodes:\n  - node: 9\n")
+            .await
+            .is_err());
+        assert_eq!(
+            safe_mode.read_config().await.unwrap(),
+            "port: [not a port]\n"
+        );
+
+        safe_mode.write_config("port: 8443\n").await.unwrap();
+        assert_eq!(Config::load(&path).unwrap().port, 8443);
+        assert!(safe_mode.status().config_error.is_none());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("config.yaml.bak")).unwrap(),
+            "port: [not a port]\n"
+        );
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,679 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Versioned JSON schemas of the API payloads, one document per area of the
+//! API in `bmcd/schemas`. Clients are generated from them, so a document
+//! only changes together with its `version`; `schemas.lock` records the
+//! digest of each published version and the tests fail on an unversioned
+//! change.
+//!
+//! `routes` of a document maps `METHOD /path`, relative to `/api/bmc`, to the
+//! `query`, `request` and `response` schemas of the route. `response` is the
+//! `result` of the envelope in `common`, unless the route answers with a
+//! `response_content_type` of its own.
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+
+const DOCUMENTS: &[(&str, &str)] = &[
+    ("activity", include_str!("../../schemas/activity.json")),
+    ("batch", include_str!("../../schemas/batch.json")),
+    ("cluster", include_str!("../../schemas/cluster.json")),
+    ("common", include_str!("../../schemas/common.json")),
+    (
+        "configuration",
+        include_str!("../../schemas/configuration.json"),
+    ),
+    (
+        "console_shares",
+        include_str!("../../schemas/console_shares.json"),
+    ),
+    (
+        "diagnostics",
+        include_str!("../../schemas/diagnostics.json"),
+    ),
+    ("discovery", include_str!("../../schemas/discovery.json")),
+    ("enrollment", include_str!("../../schemas/enrollment.json")),
+    ("expansion", include_str!("../../schemas/expansion.json")),
+    (
+        "factory_reset",
+        include_str!("../../schemas/factory_reset.json"),
+    ),
+    ("failover", include_str!("../../schemas/failover.json")),
+    ("firmware", include_str!("../../schemas/firmware.json")),
+    (
+        "flash_history",
+        include_str!("../../schemas/flash_history.json"),
+    ),
+    ("graphql", include_str!("../../schemas/graphql.json")),
+    ("i2c", include_str!("../../schemas/i2c.json")),
+    ("identify", include_str!("../../schemas/identify.json")),
+    ("identity", include_str!("../../schemas/identity.json")),
+    (
+        "image_cache",
+        include_str!("../../schemas/image_cache.json"),
+    ),
+    (
+        "image_inspection",
+        include_str!("../../schemas/image_inspection.json"),
+    ),
+    ("inventory", include_str!("../../schemas/inventory.json")),
+    ("jobs", include_str!("../../schemas/jobs.json")),
+    ("kv_store", include_str!("../../schemas/kv_store.json")),
+    ("kvm", include_str!("../../schemas/kvm.json")),
+    ("legacy", include_str!("../../schemas/legacy.json")),
+    ("lights_out", include_str!("../../schemas/lights_out.json")),
+    ("logging", include_str!("../../schemas/logging.json")),
+    ("messages", include_str!("../../schemas/messages.json")),
+    ("metrics", include_str!("../../schemas/metrics.json")),
+    ("nbd", include_str!("../../schemas/nbd.json")),
+    ("netboot", include_str!("../../schemas/netboot.json")),
+    ("network", include_str!("../../schemas/network.json")),
+    ("node_agent", include_str!("../../schemas/node_agent.json")),
+    (
+        "node_backup",
+        include_str!("../../schemas/node_backup.json"),
+    ),
+    ("node_pins", include_str!("../../schemas/node_pins.json")),
+    ("node_state", include_str!("../../schemas/node_state.json")),
+    ("pipelines", include_str!("../../schemas/pipelines.json")),
+    ("plugins", include_str!("../../schemas/plugins.json")),
+    (
+        "power_presets",
+        include_str!("../../schemas/power_presets.json"),
+    ),
+    (
+        "power_supply",
+        include_str!("../../schemas/power_supply.json"),
+    ),
+    (
+        "provisioning",
+        include_str!("../../schemas/provisioning.json"),
+    ),
+    ("readiness", include_str!("../../schemas/readiness.json")),
+    ("resources", include_str!("../../schemas/resources.json")),
+    ("retention", include_str!("../../schemas/retention.json")),
+    ("rtc", include_str!("../../schemas/rtc.json")),
+    ("rules", include_str!("../../schemas/rules.json")),
+    ("safe_mode", include_str!("../../schemas/safe_mode.json")),
+    ("schemas", include_str!("../../schemas/schemas.json")),
+    ("scripting", include_str!("../../schemas/scripting.json")),
+    ("sd_card", include_str!("../../schemas/sd_card.json")),
+    ("selftest", include_str!("../../schemas/selftest.json")),
+    ("serial", include_str!("../../schemas/serial.json")),
+    ("shutdown", include_str!("../../schemas/shutdown.json")),
+    (
+        "storage_health",
+        include_str!("../../schemas/storage_health.json"),
+    ),
+    (
+        "storage_manager",
+        include_str!("../../schemas/storage_manager.json"),
+    ),
+    ("time", include_str!("../../schemas/time.json")),
+    ("traces", include_str!("../../schemas/traces.json")),
+    ("updates", include_str!("../../schemas/updates.json")),
+    (
+        "usb_console",
+        include_str!("../../schemas/usb_console.json"),
+    ),
+    ("wifi", include_str!("../../schemas/wifi.json")),
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaInfo {
+    pub name: &'static str,
+    pub title: String,
+    pub version: u64,
+    /// hex SHA-256 of the compact document with sorted keys
+    pub sha256: String,
+}
+
+#[derive(Debug)]
+pub struct Schema {
+    pub info: SchemaInfo,
+    /// the document as shipped, with its formatting
+    pub text: &'static str,
+}
+
+fn schemas() -> &'static [Schema] {
+    static SCHEMAS: OnceLock<Vec<Schema>> = OnceLock::new();
+    SCHEMAS.get_or_init(|| {
+        DOCUMENTS
+            .iter()
+            .map(|(name, text)| {
+                let document: Value =
+                    serde_json::from_str(text).expect("schema documents are valid JSON");
+                let info = SchemaInfo {
+                    name,
+                    title: document["title"].as_str().unwrap_or_default().to_string(),
+                    version: document["version"].as_u64().unwrap_or_default(),
+                    sha256: digest(&document),
+                };
+                Schema { info, text }
+            })
+            .collect()
+    })
+}
+
+/// The serialization of a `Value` is canonical: its maps are sorted by key.
+fn digest(document: &Value) -> String {
+    hex::encode(Sha256::digest(document.to_string()))
+}
+
+pub fn index() -> Vec<SchemaInfo> {
+    schemas().iter().map(|schema| schema.info.clone()).collect()
+}
+
+pub fn get(name: &str) -> Option<&'static Schema> {
+    schemas().iter().find(|schema| schema.info.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::path::Path;
+
+    const LOCK: &str = include_str!("../../schemas/schemas.lock");
+    const METHODS: [&str; 5] = ["get", "post", "put", "delete", "patch"];
+
+    fn document(name: &str) -> Value {
+        serde_json::from_str(get(name).expect("document exists").text).unwrap()
+    }
+
+    /// Validates `value` against the keywords the documents use; others are
+    /// ignored. `$ref`s resolve within `doc` or, prefixed with a name, in
+    /// another document.
+    fn validate(doc: &str, schema: &Value, value: &Value, at: &str) -> Result<(), String> {
+        let Some(schema) = schema.as_object() else {
+            return Ok(());
+        };
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let (target, pointer) = reference.split_once('#').expect("refs have a pointer");
+            let target = if target.is_empty() { doc } else { target };
+            let resolved = document(target)
+                .pointer(pointer)
+                .cloned()
+                .ok_or_else(|| format!("{}: unresolved $ref {}", at, reference))?;
+            validate(target, &resolved, value, at)?;
+        }
+        if let Some(expected) = schema.get("type") {
+            let types: Vec<_> = match expected {
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                other => other.as_str().into_iter().collect(),
+            };
+            let matches = |ty: &str| match ty {
+                "null" => value.is_null(),
+                "boolean" => value.is_boolean(),
+                "integer" => value.is_i64() || value.is_u64(),
+                "number" => value.is_number(),
+                "string" => value.is_string(),
+                "array" => value.is_array(),
+                "object" => value.is_object(),
+                _ => false,
+            };
+            if !types.into_iter().any(matches) {
+                return Err(format!("{}: {} is not of type {}", at, value, expected));
+            }
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            if !values.contains(value) {
+                return Err(format!("{}: {} is not one of {:?}", at, value, values));
+            }
+        }
+        if let Some(expected) = schema.get("const") {
+            if expected != value {
+                return Err(format!("{}: {} is not {}", at, value, expected));
+            }
+        }
+        if let Some(number) = value.as_f64() {
+            if schema
+                .get("minimum")
+                .and_then(Value::as_f64)
+                .is_some_and(|m| number < m)
+            {
+                return Err(format!("{}: {} is below the minimum", at, value));
+            }
+            if schema
+                .get("maximum")
+                .and_then(Value::as_f64)
+                .is_some_and(|m| number > m)
+            {
+                return Err(format!("{}: {} is above the maximum", at, value));
+            }
+        }
+        if let Some(object) = value.as_object() {
+            for required in schema
+                .get("required")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+            {
+                let required = required.as_str().unwrap_or_default();
+                if !object.contains_key(required) {
+                    return Err(format!("{}: `{}` is missing", at, required));
+                }
+            }
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for (key, item) in object {
+                let at = format!("{}.{}", at, key);
+                match (
+                    properties.and_then(|p| p.get(key)),
+                    schema.get("additionalProperties"),
+                ) {
+                    (Some(property), _) => validate(doc, property, item, &at)?,
+                    (None, Some(Value::Bool(false))) => {
+                        return Err(format!("{}: unexpected property", at))
+                    }
+                    (None, Some(additional)) => validate(doc, additional, item, &at)?,
+                    (None, None) => {}
+                }
+            }
+        }
+        if let Some(items) = value.as_array() {
+            let len = items.len() as u64;
+            if schema
+                .get("minItems")
+                .and_then(Value::as_u64)
+                .is_some_and(|m| len < m)
+            {
+                return Err(format!("{}: fewer than minItems", at));
+            }
+            if schema
+                .get("maxItems")
+                .and_then(Value::as_u64)
+                .is_some_and(|m| len > m)
+            {
+                return Err(format!("{}: more than maxItems", at));
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate(doc, item_schema, item, &format!("{}[{}]", at, i))?;
+                }
+            }
+        }
+        let outcomes = |key: &str| -> Vec<Result<(), String>> {
+            let subschemas = schema.get(key).and_then(Value::as_array);
+            subschemas
+                .into_iter()
+                .flatten()
+                .map(|subschema| validate(doc, subschema, value, at))
+                .collect()
+        };
+        if let Some(error) = outcomes("allOf").into_iter().find_map(Result::err) {
+            return Err(error);
+        }
+        let any_of = outcomes("anyOf");
+        if !any_of.is_empty() && !any_of.iter().any(Result::is_ok) {
+            return Err(format!("{}: {} matches nothing of anyOf", at, value));
+        }
+        let one_of = outcomes("oneOf");
+        if !one_of.is_empty() && one_of.iter().filter(|o| o.is_ok()).count() != 1 {
+            let errors: Vec<_> = one_of.into_iter().filter_map(Result::err).collect();
+            return Err(format!(
+                "{}: {} does not match exactly one of oneOf {:?}",
+                at, value, errors
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks `value` against `part` (`query`, `request` or `response`) of a
+    /// route of document `doc`.
+    fn check(doc: &str, route: &str, part: &str, value: &Value) {
+        let document = document(doc);
+        let schema = &document["routes"][route][part];
+        assert!(!schema.is_null(), "{} has no {} of {}", doc, part, route);
+        if let Err(e) = validate(doc, schema, value, part) {
+            panic!("{} of {} in {}: {}", part, route, doc, e);
+        }
+    }
+
+    /// Checks that a request deserializes into `T` and that `T` serializes
+    /// into a valid request too.
+    fn round_trip<T>(doc: &str, route: &str, request: Value)
+    where
+        T: Serialize + serde::de::DeserializeOwned,
+    {
+        check(doc, route, "request", &request);
+        let parsed: T = serde_json::from_value(request).expect("request deserializes");
+        check(
+            doc,
+            route,
+            "request",
+            &serde_json::to_value(parsed).unwrap(),
+        );
+    }
+
+    fn references(value: &Value, found: &mut Vec<String>) {
+        match value {
+            Value::Object(map) => {
+                if let Some(reference) = map.get("$ref").and_then(Value::as_str) {
+                    found.push(reference.to_string());
+                }
+                map.values().for_each(|v| references(v, found));
+            }
+            Value::Array(items) => items.iter().for_each(|v| references(v, found)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn documents() {
+        for (name, _) in DOCUMENTS {
+            let doc = document(name);
+            assert_eq!(doc["$id"], json!(name), "$id of {}", name);
+            assert!(doc["title"].is_string(), "title of {}", name);
+            assert!(doc["version"].as_u64() >= Some(1), "version of {}", name);
+            for route in doc["routes"].as_object().expect("routes").keys() {
+                let (method, path) = route.split_once(' ').unwrap_or_default();
+                assert!(
+                    METHODS.contains(&method.to_lowercase().as_str()) && path.starts_with('/'),
+                    "route `{}` of {} is not `METHOD /path`",
+                    route,
+                    name
+                );
+            }
+            let mut found = Vec::new();
+            references(&doc, &mut found);
+            for reference in found {
+                let (target, pointer) = reference.split_once('#').unwrap_or_default();
+                let target = if target.is_empty() { *name } else { target };
+                let resolves =
+                    get(target).is_some_and(|_| document(target).pointer(pointer).is_some());
+                assert!(resolves, "{}: unresolved $ref {}", name, reference);
+            }
+        }
+    }
+
+    #[test]
+    fn versions_are_locked() {
+        let locked: BTreeMap<&str, (u64, &str)> = LOCK
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let fields: Vec<_> = line.split_whitespace().collect();
+                let version = fields[1].parse().expect("version is a number");
+                (fields[0], (version, fields[2]))
+            })
+            .collect();
+        for info in index() {
+            let Some((version, sha256)) = locked.get(info.name) else {
+                panic!(
+                    "add `{} {} {}` to schemas.lock",
+                    info.name, info.version, info.sha256
+                );
+            };
+            assert!(
+                info.version > *version || info.sha256 == *sha256,
+                "schema `{}` changed without a version bump: increment its `version`",
+                info.name
+            );
+            assert!(
+                info.version >= *version,
+                "schema `{}` went back to version {}",
+                info.name,
+                info.version
+            );
+            assert!(
+                info.version == *version,
+                "schema `{}` is at version {}, update schemas.lock to `{} {} {}`",
+                info.name,
+                info.version,
+                info.name,
+                info.version,
+                info.sha256
+            );
+        }
+        for name in locked.keys() {
+            assert!(get(name).is_some(), "schema `{}` was removed", name);
+        }
+    }
+
+    /// `(METHOD, path)` of the route macros in `source`, and the paths of
+    /// `web::resource`s, which take any method.
+    fn scan(source: &str, prefix: &str) -> (BTreeSet<(String, String)>, BTreeSet<String>) {
+        let normalize = |path: &str| {
+            let path = format!("{}{}", prefix, path);
+            // `{path:.*}` matches like `{path}`
+            let mut normalized = String::new();
+            let mut pattern = false;
+            for c in path.chars() {
+                match c {
+                    ':' if normalized.contains('{') && !normalized.ends_with('}') => pattern = true,
+                    '}' => {
+                        pattern = false;
+                        normalized.push(c);
+                    }
+                    _ if pattern => {}
+                    _ => normalized.push(c),
+                }
+            }
+            if normalized.is_empty() {
+                "/".to_string()
+            } else {
+                normalized
+            }
+        };
+        let quoted = |text: &str| text.split('"').nth(1).map(normalize);
+        let mut routes = BTreeSet::new();
+        let mut resources = BTreeSet::new();
+        for line in source.lines().map(str::trim) {
+            if let Some(rest) = line.strip_prefix("#[route(") {
+                let method = rest
+                    .split("method = ")
+                    .nth(1)
+                    .and_then(|m| m.split('"').nth(1));
+                if let (Some(path), Some(method)) = (quoted(rest), method) {
+                    routes.insert((method.to_uppercase(), path));
+                }
+            }
+            for method in METHODS {
+                if let Some(rest) = line.strip_prefix(&format!("#[{}(", method)) {
+                    if let Some(path) = quoted(rest) {
+                        routes.insert((method.to_uppercase(), path));
+                    }
+                }
+            }
+            if let Some((_, rest)) = line.split_once("web::resource(\"") {
+                resources.extend(quoted(&format!("\"{}", rest)));
+            }
+        }
+        (routes, resources)
+    }
+
+    #[test]
+    fn routes_are_documented() {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let mut files = vec![src.join("serial_service.rs")];
+        for entry in std::fs::read_dir(src.join("api")).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            // the mock and fault injection routes exist in development builds
+            // only
+            if name.ends_with(".rs") && !["mock.rs", "fault_injection.rs"].contains(&name.as_str())
+            {
+                files.push(path);
+            }
+        }
+        let mut routes = BTreeSet::new();
+        let mut resources = BTreeSet::new();
+        for file in files {
+            let source = std::fs::read_to_string(&file).unwrap();
+            let prefix = match file.file_name().unwrap().to_str() {
+                Some("provisioning.rs") => "/provisioning",
+                _ => "",
+            };
+            let (found_routes, found_resources) = scan(&source, prefix);
+            routes.extend(found_routes);
+            resources.extend(found_resources);
+        }
+        assert!(routes.len() > 100, "found only {} routes", routes.len());
+
+        let mut documented = BTreeSet::new();
+        for (name, _) in DOCUMENTS {
+            for route in document(name)["routes"].as_object().unwrap().keys() {
+                let (method, path) = route.split_once(' ').unwrap();
+                let route = (method.to_string(), path.to_string());
+                assert!(
+                    routes.contains(&route) || resources.contains(path),
+                    "{} documents `{}`, which does not exist",
+                    name,
+                    path
+                );
+                assert!(documented.insert(route), "`{}` is documented twice", path);
+            }
+        }
+        for (method, path) in &routes {
+            assert!(
+                documented.contains(&(method.clone(), path.clone())),
+                "`{} {}` has no schema, document it in bmcd/schemas",
+                method,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn payloads() {
+        use crate::app::activity::ActivityMonitor;
+        use crate::app::console_shares::ConsoleShares;
+        use crate::app::graphql::{FieldError, QueryResponse};
+        use crate::app::jobs::{JobKind, Jobs};
+        use crate::app::network_config::NetworkSettings;
+        use crate::app::notifier::Notifier;
+        use crate::app::pipelines::PipelineDefinition;
+        use crate::app::power_presets::PowerPreset;
+        use crate::app::rules::{Rule, RuleStatus};
+        use crate::app::wake_alarm::WakeAlarm;
+        use crate::config::{Activity, ConsoleSharing, CurrentSensor};
+        use crate::hal::NodeId;
+        use std::sync::Arc;
+        use std::time::Duration;
+        use tokio_util::sync::CancellationToken;
+
+        let monitor = ActivityMonitor::new(Activity {
+            sensors: vec![CurrentSensor {
+                node: 2,
+                path: "/dev/null".into(),
+            }],
+            ..Activity::default()
+        });
+        check(
+            "activity",
+            "GET /activity",
+            "response",
+            &json!(monitor.status()),
+        );
+
+        let jobs = Jobs::new(Duration::from_secs(60), Arc::new(Notifier::new(Vec::new())));
+        let token = CancellationToken::new();
+        jobs.add(7, JobKind::Backup, "backup".into(), token, None);
+        check("jobs", "GET /jobs", "response", &json!(jobs.list()));
+        check(
+            "messages",
+            "GET /messages/{language}",
+            "response",
+            &json!(crate::app::messages::english()),
+        );
+
+        let shares = ConsoleShares::default();
+        let share = shares
+            .create(&ConsoleSharing::default(), NodeId::Node2, None, None)
+            .unwrap();
+        let route = "POST /nodes/{node}/console/shares";
+        check("console_shares", route, "request", &json!({"ttl": 600}));
+        check("console_shares", route, "response", &json!(share));
+        check(
+            "console_shares",
+            "GET /console/shares",
+            "response",
+            &json!(shares.list()),
+        );
+
+        let response = QueryResponse {
+            data: Some(json!({"jobs": null})),
+            errors: vec![FieldError {
+                message: "`jobs` has no argument `node`".into(),
+                path: vec!["jobs".into()],
+            }],
+        };
+        check("graphql", "POST /graphql", "response", &json!(response));
+
+        let rule = json!({
+            "conditions": [
+                {"temperature": {"zone": "cpu-thermal", "op": ">", "value": 80}},
+                {"event": {"event": "node_stalled", "within": 600}},
+            ],
+            "hold": 30,
+            "actions": [
+                {"notify": {"message": "too hot"}},
+                {"set_fan": {"speed": 255}},
+            ],
+        });
+        round_trip::<Rule>("rules", "PUT /rules/{name}", rule.clone());
+        let status = RuleStatus {
+            rule: serde_json::from_value(rule).unwrap(),
+            state: Default::default(),
+        };
+        check("rules", "GET /rules/{name}", "response", &json!(status));
+
+        round_trip::<NetworkSettings>(
+            "network",
+            "POST /network",
+            json!({
+                "ipv4": {"mode": "static", "address": "10.0.0.2", "prefix_len": 24},
+                "dns": ["10.0.0.1"],
+            }),
+        );
+        round_trip::<PipelineDefinition>(
+            "pipelines",
+            "PUT /pipelines/{name}",
+            json!({
+                "steps": [
+                    {"op": "power", "node": 1, "on": false},
+                    {"op": "usb", "node": 1, "mode": "device"},
+                    {"op": "flash", "node": 1, "url": "http://server/image.img"},
+                    {"op": "wait_console", "node": 1, "pattern": "login:"},
+                    {"op": "delay", "ms": 500},
+                ],
+            }),
+        );
+        round_trip::<PowerPreset>(
+            "power_presets",
+            "PUT /power-presets/{name}",
+            json!({"steps": [{"node": 1}, {"node": 2, "delay": 10, "requires": [1]}]}),
+        );
+        round_trip::<WakeAlarm>(
+            "rtc",
+            "PUT /rtc/alarm",
+            json!({"time": "2024-06-01T06:00:00Z", "nodes": [1, 3]}),
+        );
+    }
+
+    #[test]
+    fn validation() {
+        let schema = json!({"$ref": "common#/$defs/Node"});
+        assert!(validate("rules", &schema, &json!(4), "").is_ok());
+        assert!(validate("rules", &schema, &json!(5), "").is_err());
+        assert!(validate("rules", &schema, &json!("1"), "").is_err());
+        let schema = json!({"type": "object", "required": ["a"], "additionalProperties": false,
+                            "properties": {"a": {"type": ["string", "null"]}}});
+        assert!(validate("common", &schema, &json!({"a": null}), "").is_ok());
+        assert!(validate("common", &schema, &json!({}), "").is_err());
+        assert!(validate("common", &schema, &json!({"a": "x", "b": 1}), "").is_err());
+    }
+}
@@ -0,0 +1,551 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Small [Rhai](https://rhai.rs) scripts that users install for behaviour
+//! that does not warrant a firmware change, e.g. power cycling a node that
+//! stalled. A script runs when it is invoked with the API, with the JSON body
+//! of the request as constant `args`, or when bmcd sends a notification of
+//! one of the script's `events`, with the notification as constant `event`.
+//!
+//! Scripts run in a sandbox: they cannot import modules or reach files or the
+//! network, and they are stopped after a number of operations and after a
+//! timeout. Besides computing, scripts can only use these functions, where
+//! nodes count from 1:
+//!
+//! ```text
+//! power_on(node)  power_off(node)  is_powered(node) -> bool  reset(node)
+//! usb(node, "host" | "device" | "flash")  usb(node, mode, to_bmc)
+//! notify(message)  sleep(ms)  print(value)  debug(value)
+//! ```
+//!
+//! Notifications that scripts send do not run scripts, so scripts cannot
+//! trigger each other in a loop.
+use super::batch::{node_id as board_node_id, UsbSetting};
+use super::bmc_application::BmcApplication;
+use super::notifier::Notifier;
+use crate::config;
+use crate::hal::NodeId;
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Dynamic, Engine, EvalAltResult, Scope};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Semaphore;
+
+/// Persistency key of the installed scripts.
+pub const SCRIPTS_KEY: &str = "scripts";
+/// Event of the notifications that scripts send.
+pub const SCRIPT_EVENT: &str = "script";
+pub const MAX_SCRIPTS: usize = 16;
+const MAX_SOURCE_LENGTH: usize = 16 * 1024;
+const MAX_EVENTS: usize = 8;
+const MAX_NAME_LENGTH: usize = 64;
+/// Scripts that run at the same time, each on a blocking thread.
+const MAX_RUNNING: usize = 2;
+const MAX_OUTPUT_LINES: usize = 100;
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_EXPR_DEPTH: usize = 64;
+const MAX_STRING_SIZE: usize = 64 * 1024;
+const MAX_COLLECTION_SIZE: usize = 4096;
+
+pub type StoredScripts = BTreeMap<String, Script>;
+
+type FnResult<T> = Result<T, Box<EvalAltResult>>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Script {
+    pub source: String,
+    /// events of the notifications that run the script, e.g. `node_stalled`
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ScriptError {
+    #[error("`{0}` is not a valid name. Use up to 64 of the characters [a-zA-Z0-9_.-]")]
+    InvalidName(String),
+    #[error("invalid script: {0}")]
+    Invalid(String),
+    #[error("maximum of {MAX_SCRIPTS} scripts reached")]
+    TooManyScripts,
+    #[error("`{0}` does not exist")]
+    NotFound(String),
+    #[error("scripting is disabled in the configuration")]
+    Disabled,
+    #[error("script failed: {0}")]
+    Failed(String),
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RunResult {
+    /// value of the last expression of the script
+    pub result: Value,
+    /// lines that the script printed
+    pub output: Vec<String>,
+}
+
+pub struct Scripts {
+    config: config::Scripting,
+    bmc: Arc<BmcApplication>,
+    notifier: Arc<Notifier>,
+    running: Semaphore,
+}
+
+impl Scripts {
+    pub fn new(
+        config: config::Scripting,
+        bmc: Arc<BmcApplication>,
+        notifier: Arc<Notifier>,
+    ) -> Self {
+        Self {
+            config,
+            bmc,
+            notifier,
+            running: Semaphore::new(MAX_RUNNING),
+        }
+    }
+
+    pub async fn list(&self) -> StoredScripts {
+        self.bmc.app_db.get::<StoredScripts>(SCRIPTS_KEY).await
+    }
+
+    pub async fn get(&self, name: &str) -> Result<Script, ScriptError> {
+        self.list()
+            .await
+            .remove(name)
+            .ok_or_else(|| ScriptError::NotFound(name.to_string()))
+    }
+
+    /// Installs the script after checking that it compiles.
+    pub async fn save(&self, name: &str, script: Script) -> Result<(), ScriptError> {
+        if !self.config.enabled {
+            return Err(ScriptError::Disabled);
+        }
+        validate_name(name)?;
+        validate(&self.config, &script)?;
+        let mut scripts = self.list().await;
+        if !scripts.contains_key(name) && scripts.len() >= MAX_SCRIPTS {
+            return Err(ScriptError::TooManyScripts);
+        }
+        scripts.insert(name.to_string(), script);
+        self.bmc.app_db.set(SCRIPTS_KEY, scripts).await;
+        Ok(())
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<(), ScriptError> {
+        let mut scripts = self.list().await;
+        scripts
+            .remove(name)
+            .ok_or_else(|| ScriptError::NotFound(name.to_string()))?;
+        self.bmc.app_db.set(SCRIPTS_KEY, scripts).await;
+        Ok(())
+    }
+
+    /// Runs the script called `name` with `args`.
+    pub async fn run(&self, name: &str, args: Value) -> Result<RunResult, ScriptError> {
+        let script = self.get(name).await?;
+        self.execute(name, script.source, "args", args).await
+    }
+
+    /// Runs the scripts that listen for the notifications that bmcd sends.
+    pub fn run_on_events(self: &Arc<Self>) {
+        if !self.config.enabled {
+            return;
+        }
+        let mut events = self.notifier.subscribe();
+        let scripts = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(notification) => scripts.dispatch(notification).await,
+                    Err(RecvError::Lagged(n)) => {
+                        tracing::warn!("scripts missed {} notifications", n)
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    async fn dispatch(&self, notification: Value) {
+        let Some(event) = notification["event"].as_str() else {
+            return;
+        };
+        if event == SCRIPT_EVENT {
+            return;
+        }
+        for (name, script) in self.list().await {
+            if !script.events.iter().any(|e| e == event) {
+                continue;
+            }
+            tracing::info!("running script {} on {}", name, event);
+            match self
+                .execute(&name, script.source, "event", notification.clone())
+                .await
+            {
+                Ok(run) => tracing::debug!("script {}: {:?}", name, run),
+                Err(e) => tracing::warn!("script {}: {}", name, e),
+            }
+        }
+    }
+
+    async fn execute(
+        &self,
+        name: &str,
+        source: String,
+        variable: &'static str,
+        value: Value,
+    ) -> Result<RunResult, ScriptError> {
+        if !self.config.enabled {
+            return Err(ScriptError::Disabled);
+        }
+        let _permit = self.running.acquire().await.expect("never closed");
+        let host = Arc::new(BmcHost {
+            bmc: self.bmc.clone(),
+            notifier: self.notifier.clone(),
+            runtime: Handle::current(),
+            script: name.to_string(),
+        });
+        let limits = self.config.clone();
+        tokio::task::spawn_blocking(move || run_script(&limits, host, &source, variable, value))
+            .await
+            .map_err(|e| ScriptError::Failed(e.to_string()))?
+    }
+}
+
+/// What scripts may do besides computing. Runs on the blocking thread of the
+/// script.
+trait Host: Send + Sync {
+    fn node_count(&self) -> usize;
+    fn power(&self, node: NodeId, on: bool) -> anyhow::Result<()>;
+    fn is_powered(&self, node: NodeId) -> anyhow::Result<bool>;
+    fn reset(&self, node: NodeId) -> anyhow::Result<()>;
+    fn usb(&self, node: NodeId, mode: UsbSetting, to_bmc: bool) -> anyhow::Result<()>;
+    fn notify(&self, message: String);
+}
+
+struct BmcHost {
+    bmc: Arc<BmcApplication>,
+    notifier: Arc<Notifier>,
+    runtime: Handle,
+    script: String,
+}
+
+impl Host for BmcHost {
+    fn node_count(&self) -> usize {
+        self.bmc.board().node_count
+    }
+
+    fn power(&self, node: NodeId, on: bool) -> anyhow::Result<()> {
+        let bit = 1 << node as u8;
+        self.runtime
+            .block_on(self.bmc.activate_slot(if on { bit } else { 0 }, bit))
+    }
+
+    fn is_powered(&self, node: NodeId) -> anyhow::Result<bool> {
+        self.runtime.block_on(self.bmc.get_node_power(node))
+    }
+
+    fn reset(&self, node: NodeId) -> anyhow::Result<()> {
+        self.runtime.block_on(self.bmc.reset_node(node))
+    }
+
+    fn usb(&self, node: NodeId, mode: UsbSetting, to_bmc: bool) -> anyhow::Result<()> {
+        self.runtime
+            .block_on(self.bmc.configure_usb(mode.usb_config(node, to_bmc)))
+    }
+
+    fn notify(&self, message: String) {
+        let message = format!("{}: {}", self.script, message);
+        self.runtime
+            .block_on(self.notifier.notify(SCRIPT_EVENT, message));
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), ScriptError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+    if valid {
+        Ok(())
+    } else {
+        Err(ScriptError::InvalidName(name.to_string()))
+    }
+}
+
+fn validate(limits: &config::Scripting, script: &Script) -> Result<(), ScriptError> {
+    if script.source.len() > MAX_SOURCE_LENGTH {
+        return Err(ScriptError::Invalid(format!(
+            "the source exceeds {} bytes",
+            MAX_SOURCE_LENGTH
+        )));
+    }
+    if script.events.len() > MAX_EVENTS
+        || script
+            .events
+            .iter()
+            .any(|e| e.is_empty() || e == SCRIPT_EVENT)
+    {
+        return Err(ScriptError::Invalid(format!(
+            "up to {} events other than `{}`",
+            MAX_EVENTS, SCRIPT_EVENT
+        )));
+    }
+    sandbox(limits)
+        .compile(&script.source)
+        .map_err(|e| ScriptError::Invalid(e.to_string()))?;
+    Ok(())
+}
+
+/// Engine that cannot load modules and stays within the limits of the
+/// configuration. Rhai has no access to files or the network of its own.
+fn sandbox(limits: &config::Scripting) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .set_max_operations(limits.max_operations)
+        .set_max_call_levels(MAX_CALL_LEVELS)
+        .set_max_expr_depths(MAX_EXPR_DEPTH, MAX_EXPR_DEPTH)
+        .set_max_string_size(MAX_STRING_SIZE)
+        .set_max_array_size(MAX_COLLECTION_SIZE)
+        .set_max_map_size(MAX_COLLECTION_SIZE);
+    engine
+}
+
+fn run_script(
+    limits: &config::Scripting,
+    host: Arc<dyn Host>,
+    source: &str,
+    variable: &str,
+    value: Value,
+) -> Result<RunResult, ScriptError> {
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let engine = engine(limits, host, output.clone());
+    let mut scope = Scope::new();
+    let value = rhai::serde::to_dynamic(value).map_err(|e| ScriptError::Failed(e.to_string()))?;
+    scope.push_constant_dynamic(variable, value);
+
+    let result = engine.eval_with_scope::<Dynamic>(&mut scope, source);
+    let output = std::mem::take(&mut *output.lock().expect("output lock poisoned"));
+    match result {
+        Ok(value) => Ok(RunResult {
+            result: rhai::serde::from_dynamic(&value)
+                .unwrap_or_else(|_| Value::String(value.to_string())),
+            output,
+        }),
+        Err(e) if matches!(*e, EvalAltResult::ErrorTerminated(..)) => Err(ScriptError::Failed(
+            format!("ran longer than {}s", limits.timeout.as_secs()),
+        )),
+        Err(e) => Err(ScriptError::Failed(e.to_string())),
+    }
+}
+
+fn engine(
+    limits: &config::Scripting,
+    host: Arc<dyn Host>,
+    output: Arc<Mutex<Vec<String>>>,
+) -> Engine {
+    let deadline = Instant::now() + limits.timeout;
+    let mut engine = sandbox(limits);
+    engine.on_progress(move |_| (Instant::now() >= deadline).then(|| Dynamic::from("timeout")));
+    let print = output.clone();
+    engine.on_print(move |line| push_output(&print, line));
+    engine.on_debug(move |line, _, _| push_output(&output, line));
+
+    let h = host.clone();
+    engine.register_fn("power_on", move |node: i64| -> FnResult<()> {
+        h.power(node_id(&*h, node)?, true).map_err(failed)
+    });
+    let h = host.clone();
+    engine.register_fn("power_off", move |node: i64| -> FnResult<()> {
+        h.power(node_id(&*h, node)?, false).map_err(failed)
+    });
+    let h = host.clone();
+    engine.register_fn("is_powered", move |node: i64| -> FnResult<bool> {
+        h.is_powered(node_id(&*h, node)?).map_err(failed)
+    });
+    let h = host.clone();
+    engine.register_fn("reset", move |node: i64| -> FnResult<()> {
+        h.reset(node_id(&*h, node)?).map_err(failed)
+    });
+    let h = host.clone();
+    engine.register_fn("usb", move |node: i64, mode: &str| -> FnResult<()> {
+        h.usb(node_id(&*h, node)?, usb_setting(mode)?, false)
+            .map_err(failed)
+    });
+    let h = host.clone();
+    engine.register_fn(
+        "usb",
+        move |node: i64, mode: &str, to_bmc: bool| -> FnResult<()> {
+            h.usb(node_id(&*h, node)?, usb_setting(mode)?, to_bmc)
+                .map_err(failed)
+        },
+    );
+    engine.register_fn("notify", move |message: &str| {
+        host.notify(message.to_string())
+    });
+    engine.register_fn("sleep", move |ms: i64| {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        std::thread::sleep(Duration::from_millis(ms.max(0) as u64).min(remaining));
+    });
+    engine
+}
+
+fn push_output(output: &Mutex<Vec<String>>, line: &str) {
+    let mut output = output.lock().expect("output lock poisoned");
+    if output.len() < MAX_OUTPUT_LINES {
+        output.push(line.to_string());
+    }
+}
+
+fn node_id(host: &dyn Host, node: i64) -> FnResult<NodeId> {
+    let number = u8::try_from(node).map_err(|_| failed(format!("node {} does not exist", node)))?;
+    board_node_id(number, host.node_count()).map_err(failed)
+}
+
+fn usb_setting(mode: &str) -> FnResult<UsbSetting> {
+    serde_json::from_value(Value::from(mode))
+        .map_err(|_| failed(format!("`{}` is not one of host, device or flash", mode)))
+}
+
+fn failed(error: impl std::fmt::Display) -> Box<EvalAltResult> {
+    format!("{:#}", error).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Default)]
+    struct FakeHost {
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl Host for FakeHost {
+        fn node_count(&self) -> usize {
+            4
+        }
+
+        fn power(&self, node: NodeId, on: bool) -> anyhow::Result<()> {
+            self.record(format!("power {:?} {}", node, on));
+            Ok(())
+        }
+
+        fn is_powered(&self, node: NodeId) -> anyhow::Result<bool> {
+            Ok(node == NodeId::Node1)
+        }
+
+        fn reset(&self, node: NodeId) -> anyhow::Result<()> {
+            anyhow::bail!("{:?} does not respond", node)
+        }
+
+        fn usb(&self, node: NodeId, mode: UsbSetting, to_bmc: bool) -> anyhow::Result<()> {
+            self.record(format!("usb {:?} {:?} {}", node, mode, to_bmc));
+            Ok(())
+        }
+
+        fn notify(&self, message: String) {
+            self.record(format!("notify {}", message));
+        }
+    }
+
+    impl FakeHost {
+        fn record(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
+        }
+    }
+
+    fn run(host: &Arc<FakeHost>, source: &str, args: Value) -> Result<RunResult, ScriptError> {
+        let limits = config::Scripting::default();
+        run_script(&limits, host.clone(), source, "args", args)
+    }
+
+    #[test]
+    fn host_functions() {
+        let host = Arc::new(FakeHost::default());
+        let source = r#"
+            for node in args.nodes {
+                if !is_powered(node) { power_on(node); }
+            }
+            usb(2, "flash", true);
+            print(`done with ${args.nodes.len()} nodes`);
+            notify("cluster is up");
+            #{ powered: args.nodes }
+        "#;
+        let result = run(&host, source, json!({"nodes": [1, 3]})).unwrap();
+        assert_eq!(result.result, json!({"powered": [1, 3]}));
+        assert_eq!(result.output, vec!["done with 2 nodes"]);
+        assert_eq!(
+            *host.calls.lock().unwrap(),
+            vec![
+                "power Node3 true",
+                "usb Node2 Flash true",
+                "notify cluster is up"
+            ]
+        );
+
+        let error = run(&host, "reset(2)", Value::Null).unwrap_err();
+        assert!(
+            error.to_string().contains("Node2 does not respond"),
+            "{}",
+            error
+        );
+        assert!(run(&host, "power_on(5)", Value::Null).is_err());
+        assert!(run(&host, r#"usb(1, "otg")"#, Value::Null).is_err());
+    }
+
+    #[test]
+    fn sandbox_limits() {
+        let host = Arc::new(FakeHost::default());
+        assert!(run(&host, r#"import "/etc/shadow" as s; 1"#, Value::Null).is_err());
+        assert!(run(&host, "loop {}", Value::Null).is_err());
+        assert!(
+            run(&host, "args = 1", Value::Null).is_err(),
+            "args is constant"
+        );
+
+        let limits = config::Scripting {
+            timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let error = run_script(
+            &limits,
+            host.clone(),
+            "sleep(10000); 1",
+            "args",
+            Value::Null,
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(error.is_err());
+
+        let script = |source: &str| Script {
+            source: source.to_string(),
+            events: vec!["node_stalled".to_string()],
+        };
+        let limits = config::Scripting::default();
+        validate(&limits, &script("power_off(event.node)")).unwrap();
+        assert!(validate(&limits, &script("power_off(")).is_err());
+        let mut looping = script("1");
+        looping.events.push(SCRIPT_EVENT.to_string());
+        assert!(validate(&limits, &looping).is_err());
+    }
+}
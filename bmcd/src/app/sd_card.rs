@@ -0,0 +1,94 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Routing of the microSD slot on boards that can connect it to a node, e.g.
+//! to boot a node from a card or to read a card that a node wrote. The BMC
+//! mounts the card at `/mnt/sdcard`, so it is only switched away while none of
+//! its partitions are mounted. Switching it away from a node that is powered
+//! on could corrupt the filesystem the node has open, which needs `force`.
+use super::bmc_application::BmcApplication;
+use crate::error::BmcError;
+use crate::hal::SdRoute;
+use crate::utils::{is_on_device, read_mounts};
+use serde::Serialize;
+
+pub const SD_CARD_DEVICE: &str = "/dev/mmcblk0";
+
+#[derive(Debug, Serialize)]
+pub struct SdCardStatus {
+    /// node that has the card, numbered from 1. `None` is the BMC.
+    pub node: Option<u8>,
+    /// where the BMC has the card mounted
+    pub mounts: Vec<String>,
+}
+
+pub fn status(bmc: &BmcApplication) -> anyhow::Result<SdCardStatus> {
+    let route = current_route(bmc)?;
+    let mounts = match route {
+        SdRoute::Bmc => card_mounts()?,
+        SdRoute::Node(_) => Vec::new(),
+    };
+    Ok(SdCardStatus {
+        node: match route {
+            SdRoute::Bmc => None,
+            SdRoute::Node(node) => Some(node as u8 + 1),
+        },
+        mounts,
+    })
+}
+
+/// Connects the card to `route`, after checking that whoever has it now is
+/// not using it.
+pub async fn select(bmc: &BmcApplication, route: SdRoute, force: bool) -> anyhow::Result<()> {
+    let current = current_route(bmc)?;
+    if current == route {
+        return Ok(());
+    }
+    match current {
+        SdRoute::Bmc => {
+            let mounts = card_mounts()?;
+            if !mounts.is_empty() {
+                return Err(BmcError::InUse {
+                    resource: "the sd card".into(),
+                    by: format!("the filesystem mounted at {}", mounts.join(", ")).into(),
+                }
+                .into());
+            }
+        }
+        SdRoute::Node(node) if !force && bmc.get_node_power(node).await? => {
+            return Err(BmcError::InUse {
+                resource: "the sd card".into(),
+                by: format!("{}, which is powered on", node).into(),
+            }
+            .into());
+        }
+        SdRoute::Node(_) => {}
+    }
+    tracing::info!("switching the sd card from {:?} to {:?}", current, route);
+    bmc.select_sd(route)
+}
+
+fn current_route(bmc: &BmcApplication) -> anyhow::Result<SdRoute> {
+    bmc.sd_route()?.ok_or_else(|| {
+        BmcError::NotSupported("this board cannot switch its microSD slot".into()).into()
+    })
+}
+
+/// Where the BMC has the card mounted.
+fn card_mounts() -> std::io::Result<Vec<String>> {
+    Ok(read_mounts()?
+        .into_iter()
+        .filter(|m| is_on_device(&m.source, SD_CARD_DEVICE))
+        .map(|m| m.path)
+        .collect())
+}
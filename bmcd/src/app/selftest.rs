@@ -0,0 +1,231 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Self-test of the hardware, for factory and field diagnostics. Every
+//! component is exercised without changing the state of the nodes: GPIO
+//! lines are read, LEDs are blinked and restored, and the node UARTs,
+//! sensors, RTC and expansion boards are read.
+use super::bmc_application::{BmcApplication, ACTIVATED_NODES_KEY};
+use super::cooling_device::{get_cooling_state, thermal_root};
+use super::identify::{Identify, IndicatorLed};
+use crate::hal::expansion::Expansions;
+use crate::hal::rtc::Rtc;
+use crate::serial_service::serial::SerialConnections;
+use crate::serial_service::serial_handler::HandlerState;
+use crate::utils::get_timestamp_unix;
+use anyhow::{bail, ensure, Context};
+use serde::Serialize;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// How long an LED is held in each state.
+const LED_HOLD: Duration = Duration::from_millis(300);
+/// Range of plausible temperatures, in m°C.
+const TEMPERATURE_RANGE: std::ops::RangeInclusive<i32> = -40_000..=125_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Pass,
+    Fail,
+    /// the component is not fitted, or cannot be tested right now
+    Skipped,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ComponentResult {
+    pub component: String,
+    pub outcome: Outcome,
+    pub detail: String,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SelfTestReport {
+    /// no component failed
+    pub passed: bool,
+    pub timestamp: Option<u64>,
+    pub components: Vec<ComponentResult>,
+}
+
+enum Finding {
+    Pass(String),
+    Skipped(String),
+}
+
+pub struct SelfTest<'a> {
+    pub bmc: &'a BmcApplication,
+    pub identify: &'a Identify,
+    pub serial: &'a SerialConnections,
+    pub rtc: &'a Rtc,
+    pub expansions: &'a Expansions,
+}
+
+impl SelfTest<'_> {
+    /// Tests the components one after the other.
+    pub async fn run(&self) -> SelfTestReport {
+        let mut components = Vec::new();
+        components.push(record("gpio", self.gpio()).await);
+        components.push(record("status_led", self.status_led()).await);
+        components.push(record("power_led", self.power_led()).await);
+        for (idx, state) in self.serial.get_state().into_iter().enumerate() {
+            let uart = async move {
+                match state {
+                    HandlerState::Running => Ok(Finding::Pass("open".to_string())),
+                    state => bail!("{:?}", state),
+                }
+            };
+            components.push(record(format!("uart_node{}", idx + 1), uart).await);
+        }
+        components.push(record("thermal", thermal()).await);
+        components.push(record("cooling", cooling()).await);
+        components.push(record("rtc", self.rtc()).await);
+        for module in self.expansions.modules() {
+            let status = async {
+                module.driver()?.status().await?;
+                Ok(Finding::Pass(module.header.product_name.clone()))
+            };
+            components.push(record(format!("expansion_{}", module.id), status).await);
+        }
+        SelfTestReport {
+            passed: components.iter().all(|c| c.outcome != Outcome::Fail),
+            timestamp: get_timestamp_unix(),
+            components,
+        }
+    }
+
+    async fn gpio(&self) -> anyhow::Result<Finding> {
+        self.bmc.check_hal().context("node power lines")?;
+        let detail = match self.bmc.slot_presence().context("presence lines")? {
+            Some(present) => format!("lines readable, modules in slots {:#06b}", present),
+            None => "lines readable".to_string(),
+        };
+        Ok(Finding::Pass(detail))
+    }
+
+    async fn status_led(&self) -> anyhow::Result<Finding> {
+        if self.identify.status().indicator_led == IndicatorLed::Blinking {
+            return Ok(Finding::Skipped("in use to identify a node".to_string()));
+        }
+        for on in [true, false] {
+            self.bmc.status_led(on).await?;
+            tokio::time::sleep(LED_HOLD).await;
+        }
+        Ok(Finding::Pass("blinked".to_string()))
+    }
+
+    async fn power_led(&self) -> anyhow::Result<Finding> {
+        let lit = self.bmc.app_db.get::<u8>(ACTIVATED_NODES_KEY).await != 0;
+        let blink = async {
+            self.bmc.power_led(!lit).await?;
+            tokio::time::sleep(LED_HOLD).await;
+            anyhow::Ok(())
+        };
+        let result = blink.await;
+        // restored even when toggling failed halfway
+        self.bmc.power_led(lit).await?;
+        result.map(|_| Finding::Pass("blinked".to_string()))
+    }
+
+    async fn rtc(&self) -> anyhow::Result<Finding> {
+        if !self.rtc.is_available() {
+            return Ok(Finding::Skipped("not fitted".to_string()));
+        }
+        let time = self.rtc.time()?;
+        Ok(Finding::Pass(time.to_rfc3339()))
+    }
+}
+
+async fn record(
+    component: impl Into<String>,
+    check: impl Future<Output = anyhow::Result<Finding>>,
+) -> ComponentResult {
+    let component = component.into();
+    let start = Instant::now();
+    let (outcome, detail) = match check.await {
+        Ok(Finding::Pass(detail)) => (Outcome::Pass, detail),
+        Ok(Finding::Skipped(detail)) => (Outcome::Skipped, detail),
+        Err(e) => {
+            tracing::warn!("self-test of {} failed: {:#}", component, e);
+            (Outcome::Fail, format!("{:#}", e))
+        }
+    };
+    ComponentResult {
+        component,
+        outcome,
+        detail,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// The SoC has at least one thermal zone, they are all read.
+async fn thermal() -> anyhow::Result<Finding> {
+    let root = thermal_root();
+    let mut dir = tokio::fs::read_dir(&root)
+        .await
+        .with_context(|| root.display().to_string())?;
+    let mut readings = Vec::new();
+    while let Some(entry) = dir.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with("thermal_zone") {
+            continue;
+        }
+        let path = entry.path().join("temp");
+        let milli_celsius: i32 = tokio::fs::read_to_string(&path)
+            .await
+            .with_context(|| path.display().to_string())?
+            .trim()
+            .parse()
+            .with_context(|| format!("{}: not a number", path.display()))?;
+        ensure!(
+            TEMPERATURE_RANGE.contains(&milli_celsius),
+            "{} reads an implausible {} m°C",
+            name,
+            milli_celsius
+        );
+        readings.push(format!("{} {:.1}°C", name, milli_celsius as f64 / 1000.0));
+    }
+    ensure!(!readings.is_empty(), "no thermal zone found");
+    Ok(Finding::Pass(readings.join(", ")))
+}
+
+async fn cooling() -> anyhow::Result<Finding> {
+    let devices = get_cooling_state().await;
+    if devices.is_empty() {
+        return Ok(Finding::Skipped("no cooling devices".to_string()));
+    }
+    let states: Vec<_> = devices
+        .iter()
+        .map(|d| format!("{} {}/{}", d.device, d.speed, d.max_speed))
+        .collect();
+    Ok(Finding::Pass(states.join(", ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn outcomes() {
+        let pass = record("a", async { Ok(Finding::Pass("ok".to_string())) }).await;
+        assert_eq!(
+            (pass.component.as_str(), pass.outcome),
+            ("a", Outcome::Pass)
+        );
+        let skipped = record("b", async { Ok(Finding::Skipped("absent".to_string())) }).await;
+        assert_eq!(skipped.outcome, Outcome::Skipped);
+        let failed = record("c", async { Err(anyhow::anyhow!("no reply")) }).await;
+        assert_eq!(failed.outcome, Outcome::Fail);
+        assert_eq!(failed.detail, "no reply");
+    }
+}
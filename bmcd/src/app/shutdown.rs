@@ -0,0 +1,178 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Orderly shutdown of the daemon. Draining refuses new mutating requests,
+//! gives a running flash time to finish, persists the application state and
+//! releases the USB resources that were handed to the nodes. The HTTP servers
+//! are stopped last, so the status can still be read while draining.
+use super::bmc_application::BmcApplication;
+use super::systemd;
+use crate::streaming_data_service::{StreamingDataService, StreamingState};
+use crate::utils::restart_daemon;
+use actix_web::dev::ServerHandle;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Identifies a server registered with [`Shutdown::add_server`].
+pub type ServerId = u64;
+
+pub struct Shutdown {
+    draining: AtomicBool,
+    /// held for the duration of a drain, so it runs only once
+    drained: tokio::sync::Mutex<bool>,
+    servers: Mutex<HashMap<ServerId, ServerHandle>>,
+    next_server: AtomicU64,
+    stopped: watch::Sender<bool>,
+    drain_timeout: Duration,
+    bmc: Arc<BmcApplication>,
+    streaming: Arc<StreamingDataService>,
+}
+
+impl Shutdown {
+    pub fn new(
+        drain_timeout: Duration,
+        bmc: Arc<BmcApplication>,
+        streaming: Arc<StreamingDataService>,
+    ) -> Self {
+        Self {
+            draining: AtomicBool::new(false),
+            drained: tokio::sync::Mutex::new(false),
+            servers: Mutex::new(HashMap::new()),
+            next_server: AtomicU64::new(0),
+            stopped: watch::Sender::new(false),
+            drain_timeout,
+            bmc,
+            streaming,
+        }
+    }
+
+    /// Registers a server that is stopped by [`Self::stop`].
+    pub fn add_server(&self, server: ServerHandle) -> ServerId {
+        let id = self.next_server.fetch_add(1, Ordering::Relaxed);
+        self.servers
+            .lock()
+            .expect("shutdown lock poisoned")
+            .insert(id, server);
+        id
+    }
+
+    /// Unregisters a server that is stopped before the daemon exits.
+    pub fn remove_server(&self, id: ServerId) -> Option<ServerHandle> {
+        self.servers
+            .lock()
+            .expect("shutdown lock poisoned")
+            .remove(&id)
+    }
+
+    /// Resolves once [`Self::stop`] stopped the servers.
+    pub async fn stopped(&self) {
+        let mut stopped = self.stopped.subscribe();
+        let _ = stopped.wait_for(|stopped| *stopped).await;
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    /// Brings the daemon to a state in which it can exit. A flash that does
+    /// not finish within the drain timeout is cancelled; an interrupted
+    /// firmware upgrade is recovered on the next start, a node image has to
+    /// be flashed again.
+    pub async fn drain(&self) {
+        let mut drained = self.drained.lock().await;
+        if *drained {
+            return;
+        }
+        self.draining.store(true, Ordering::Release);
+        systemd::notify("STOPPING=1\nSTATUS=draining");
+        tracing::info!("draining, new requests that change state are refused");
+
+        if !self.wait_for_transfer().await {
+            tracing::error!(
+                "flash did not finish within {:?}, cancelling it",
+                self.drain_timeout
+            );
+            self.streaming.cancel_all().await;
+        }
+        if let Err(e) = self.bmc.shutdown().await {
+            tracing::error!("releasing resources: {:#}", e);
+        }
+        *drained = true;
+        tracing::info!("drained");
+    }
+
+    /// Returns false when a transfer is still running after the timeout.
+    async fn wait_for_transfer(&self) -> bool {
+        let deadline = Instant::now() + self.drain_timeout;
+        let mut logged = false;
+        while matches!(
+            *self.streaming.status().await,
+            StreamingState::Transferring(_)
+        ) {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            if !logged {
+                tracing::info!("waiting for the running flash to finish");
+                logged = true;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        true
+    }
+
+    /// Drains and stops the HTTP servers, after which the daemon exits.
+    pub async fn stop(&self) {
+        self.drain().await;
+        let servers: Vec<_> = self
+            .servers
+            .lock()
+            .expect("shutdown lock poisoned")
+            .drain()
+            .map(|(_, server)| server)
+            .collect();
+        for server in servers {
+            server.stop(true).await;
+        }
+        self.stopped.send_replace(true);
+    }
+
+    /// Drains and restarts the daemon through its init script, which stops
+    /// this instance with SIGTERM.
+    pub async fn restart(&self) {
+        self.drain().await;
+        tracing::info!("restarting");
+        restart_daemon();
+    }
+
+    /// Stops the daemon when it receives SIGTERM or SIGINT.
+    pub fn stop_on_signals(self: Arc<Self>) -> std::io::Result<()> {
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = terminate.recv() => tracing::info!("SIGTERM received"),
+                _ = interrupt.recv() => tracing::info!("SIGINT received"),
+            }
+            self.stop().await;
+        });
+        Ok(())
+    }
+}
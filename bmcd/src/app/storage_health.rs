@@ -0,0 +1,352 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Wear of the storage of the BMC. eMMC devices estimate their used life time
+//! and report how much of their reserved blocks is consumed
+//! (`DEVICE_LIFE_TIME_EST_TYP_A/B` and `PRE_EOL_INFO` of JEDEC), which the
+//! kernel exposes in sysfs. SD cards have no standard wear registers and tend
+//! to die quietly, often by turning read-only, so for them the read-only
+//! state and the bytes written since boot are all there is. While the microSD
+//! slot is routed to a node, see `app::sd_card`, the card is listed with that
+//! node and without readings.
+//!
+//! The monitor reads the devices periodically and notifies once when a device
+//! crosses into a worse [`WearLevel`].
+use super::bmc_application::BmcApplication;
+use super::notifier::Notifier;
+use super::sd_card::SD_CARD_DEVICE;
+use crate::config;
+use crate::hal::SdRoute;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+const SYS_BLOCK: &str = "/sys/block";
+const SECTOR_SIZE: u64 = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageKind {
+    Emmc,
+    Sd,
+}
+
+/// Consumption of the reserved blocks of an eMMC device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PreEol {
+    Normal,
+    /// 80% consumed
+    Warning,
+    /// 90% consumed
+    Urgent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WearLevel {
+    Ok,
+    Warning,
+    Critical,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StorageHealth {
+    /// block device, e.g. `mmcblk0`
+    pub device: String,
+    pub kind: StorageKind,
+    /// node that has the microSD card, numbered from 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node: Option<u8>,
+    pub name: Option<String>,
+    /// manufacturing date, `MM/YYYY`
+    pub date: Option<String>,
+    /// Used life time of the SLC and the MLC area in percent. Devices report
+    /// it in steps of 10%, this is the upper bound of the step; 110 means the
+    /// estimate is exceeded.
+    pub life_time: Option<[u8; 2]>,
+    pub pre_eol: Option<PreEol>,
+    pub read_only: bool,
+    /// bytes written since boot
+    pub written: Option<u64>,
+    pub level: WearLevel,
+}
+
+impl StorageHealth {
+    fn level(&self, thresholds: &config::StorageHealth) -> WearLevel {
+        let used = self.life_time.map_or(0, |[a, b]| a.max(b));
+        if self.read_only || self.pre_eol == Some(PreEol::Urgent) || used >= thresholds.critical {
+            WearLevel::Critical
+        } else if self.pre_eol == Some(PreEol::Warning) || used >= thresholds.warning {
+            WearLevel::Warning
+        } else {
+            WearLevel::Ok
+        }
+    }
+}
+
+pub struct StorageMonitor {
+    config: config::StorageHealth,
+    /// last level of each device, to notify when it gets worse
+    levels: Mutex<HashMap<String, WearLevel>>,
+}
+
+impl StorageMonitor {
+    pub fn new(config: config::StorageHealth) -> Self {
+        Self {
+            config,
+            levels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reads the storage devices of the BMC.
+    pub fn read(&self, bmc: &BmcApplication) -> Vec<StorageHealth> {
+        let mut devices = read_devices(Path::new(SYS_BLOCK), &self.config);
+        if let Ok(Some(SdRoute::Node(node))) = bmc.sd_route() {
+            let device = SD_CARD_DEVICE.trim_start_matches("/dev/").to_string();
+            devices.retain(|d| d.device != device);
+            devices.push(StorageHealth {
+                device,
+                kind: StorageKind::Sd,
+                node: Some(node as u8 + 1),
+                name: None,
+                date: None,
+                life_time: None,
+                pre_eol: None,
+                read_only: false,
+                written: None,
+                level: WearLevel::Ok,
+            });
+        }
+        devices
+    }
+
+    pub fn run(self: Arc<Self>, bmc: Arc<BmcApplication>, notifier: Arc<Notifier>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.config.interval);
+            loop {
+                interval.tick().await;
+                for device in self.read(&bmc) {
+                    let Some(level) = self.record(&device) else {
+                        continue;
+                    };
+                    let event = match level {
+                        WearLevel::Critical => "storage_wear_critical",
+                        WearLevel::Warning => "storage_wear_warning",
+                        WearLevel::Ok => continue,
+                    };
+                    let message = describe(&device);
+                    tracing::warn!("{}", message);
+                    notifier.notify(event, message).await;
+                }
+            }
+        });
+    }
+
+    /// Remembers the level of `device`, returns it when it got worse.
+    fn record(&self, device: &StorageHealth) -> Option<WearLevel> {
+        let mut levels = self.levels.lock().expect("storage levels poisoned");
+        let previous = levels
+            .insert(device.device.clone(), device.level)
+            .unwrap_or(WearLevel::Ok);
+        (device.level > previous).then_some(device.level)
+    }
+}
+
+fn describe(device: &StorageHealth) -> String {
+    let mut reasons = Vec::new();
+    if device.read_only {
+        reasons.push("is read-only".to_string());
+    }
+    if let Some([a, b]) = device.life_time {
+        reasons.push(format!("used up to {}% of its life time", a.max(b)));
+    }
+    if let Some(pre_eol) = device.pre_eol.filter(|p| *p != PreEol::Normal) {
+        reasons.push(format!("reports {:?} reserved block consumption", pre_eol));
+    }
+    format!("storage {} {}", device.device, reasons.join(" and "))
+}
+
+/// Reads the MMC block devices below `root`, e.g. `/sys/block`.
+fn read_devices(root: &Path, thresholds: &config::StorageHealth) -> Vec<StorageHealth> {
+    let Ok(entries) = std::fs::read_dir(root) else {
+        return Vec::new();
+    };
+    let mut devices: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // skips the boot and RPMB partitions of eMMC devices
+            let index = name.strip_prefix("mmcblk")?;
+            if index.is_empty() || !index.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            read_device(&entry.path(), name, thresholds)
+        })
+        .collect();
+    devices.sort_by(|a, b| a.device.cmp(&b.device));
+    devices
+}
+
+fn read_device(
+    dir: &Path,
+    device: String,
+    thresholds: &config::StorageHealth,
+) -> Option<StorageHealth> {
+    let attribute = |name: &str| {
+        std::fs::read_to_string(dir.join(name))
+            .ok()
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+    let kind = match attribute("device/type")?.as_str() {
+        "MMC" => StorageKind::Emmc,
+        "SD" => StorageKind::Sd,
+        _ => return None,
+    };
+    let mut health = StorageHealth {
+        device,
+        kind,
+        node: None,
+        name: attribute("device/name"),
+        date: attribute("device/date"),
+        life_time: attribute("device/life_time").and_then(|v| parse_life_time(&v)),
+        pre_eol: attribute("device/pre_eol_info").and_then(|v| parse_pre_eol(&v)),
+        read_only: attribute("ro").is_some_and(|ro| ro == "1"),
+        written: attribute("stat").and_then(|stat| {
+            let sectors = stat.split_whitespace().nth(6)?.parse::<u64>().ok()?;
+            Some(sectors * SECTOR_SIZE)
+        }),
+        level: WearLevel::Ok,
+    };
+    health.level = health.level(thresholds);
+    Some(health)
+}
+
+fn parse_hex(value: &str) -> Option<u8> {
+    u8::from_str_radix(value.trim_start_matches("0x"), 16).ok()
+}
+
+/// Parses `life_time`, e.g. `0x01 0x02`. Step 0 means not defined.
+fn parse_life_time(value: &str) -> Option<[u8; 2]> {
+    let mut steps = value.split_whitespace().map(|step| {
+        parse_hex(step)
+            .filter(|s| (1..=0x0b).contains(s))
+            .map(|s| s * 10)
+    });
+    Some([steps.next()??, steps.next()??])
+}
+
+fn parse_pre_eol(value: &str) -> Option<PreEol> {
+    match parse_hex(value)? {
+        1 => Some(PreEol::Normal),
+        2 => Some(PreEol::Warning),
+        3 => Some(PreEol::Urgent),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn write_device(root: &Path, name: &str, attributes: &[(&str, &str)]) {
+        std::fs::create_dir_all(root.join(name).join("device")).unwrap();
+        for (attribute, value) in attributes {
+            std::fs::write(root.join(name).join(attribute), format!("{}\n", value)).unwrap();
+        }
+    }
+
+    #[test]
+    fn read_sysfs() {
+        let dir = TempDir::new("storage_health").unwrap();
+        write_device(
+            dir.path(),
+            "mmcblk1",
+            &[
+                ("device/type", "MMC"),
+                ("device/name", "8GTF4R"),
+                ("device/life_time", "0x02 0x08"),
+                ("device/pre_eol_info", "0x01"),
+                ("ro", "0"),
+                ("stat", "100 0 800 10 50 0 2048 20 0 30 30"),
+            ],
+        );
+        write_device(dir.path(), "mmcblk1boot0", &[("device/type", "MMC")]);
+        write_device(
+            dir.path(),
+            "mmcblk0",
+            &[
+                ("device/type", "SD"),
+                ("device/date", "05/2021"),
+                ("ro", "1"),
+            ],
+        );
+        write_device(dir.path(), "mmcblk2", &[("device/type", "SDIO")]);
+
+        let devices = read_devices(dir.path(), &config::StorageHealth::default());
+        assert_eq!(devices.len(), 2);
+        let sd = &devices[0];
+        assert_eq!(sd.kind, StorageKind::Sd);
+        assert_eq!(sd.date.as_deref(), Some("05/2021"));
+        assert!(sd.read_only);
+        assert_eq!(sd.level, WearLevel::Critical);
+        let emmc = &devices[1];
+        assert_eq!(emmc.device, "mmcblk1");
+        assert_eq!(emmc.life_time, Some([20, 80]));
+        assert_eq!(emmc.pre_eol, Some(PreEol::Normal));
+        assert_eq!(emmc.written, Some(2048 * 512));
+        assert_eq!(emmc.level, WearLevel::Warning);
+    }
+
+    #[test]
+    fn registers() {
+        assert_eq!(parse_life_time("0x01 0x0B"), Some([10, 110]));
+        assert_eq!(parse_life_time("0x00 0x01"), None);
+        assert_eq!(parse_life_time("0x01"), None);
+        assert_eq!(parse_pre_eol("0x03"), Some(PreEol::Urgent));
+        assert_eq!(parse_pre_eol("0x00"), None);
+    }
+
+    #[test]
+    fn notifies_once_per_level() {
+        let monitor = StorageMonitor::new(config::StorageHealth::default());
+        let mut device = StorageHealth {
+            device: "mmcblk0".into(),
+            kind: StorageKind::Emmc,
+            node: None,
+            name: None,
+            date: None,
+            life_time: Some([10, 10]),
+            pre_eol: Some(PreEol::Normal),
+            read_only: false,
+            written: None,
+            level: WearLevel::Ok,
+        };
+        assert_eq!(monitor.record(&device), None);
+        device.pre_eol = Some(PreEol::Warning);
+        device.level = device.level(&monitor.config);
+        assert_eq!(monitor.record(&device), Some(WearLevel::Warning));
+        assert_eq!(monitor.record(&device), None);
+        device.life_time = Some([100, 10]);
+        device.level = device.level(&monitor.config);
+        assert_eq!(monitor.record(&device), Some(WearLevel::Critical));
+        assert_eq!(
+            describe(&device),
+            "storage mmcblk0 used up to 100% of its life time and reports Warning \
+             reserved block consumption"
+        );
+    }
+}
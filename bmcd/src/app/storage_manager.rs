@@ -0,0 +1,529 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Storage devices attached to the BMC, such as the microSD card or a USB
+//! stick, and the space that the features of bmcd use on them. Devices can be
+//! listed, mounted and unmounted, and formatted after a confirmation like a
+//! factory reset. Each feature has a quota, so that e.g. backups cannot fill
+//! the storage that holds the images: writes beyond the quota are refused.
+use super::bmc_info::get_fs_stat;
+use super::factory_reset::IMAGES_DIR;
+use super::retention::dir_size;
+use crate::config;
+use crate::error::BmcError;
+use crate::utils::{is_on_device, read_mounts, resolve, Mount};
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::process::Command;
+
+const SYS_BLOCK: &str = "/sys/block";
+const MOUNT_ROOT: &str = "/mnt";
+const SECTOR_SIZE: u64 = 512;
+const TOKEN_EXPIRY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    Images,
+    Backups,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 2] = [Feature::Images, Feature::Backups];
+
+    fn name(self) -> &'static str {
+        match self {
+            Feature::Images => "images",
+            Feature::Backups => "backups",
+        }
+    }
+
+    pub fn dir(self, config: &config::Storage) -> &Path {
+        match self {
+            Feature::Images => Path::new(IMAGES_DIR),
+            Feature::Backups => &config.backup_dir,
+        }
+    }
+
+    pub fn quota(self, config: &config::Storage) -> u64 {
+        match self {
+            Feature::Images => config.quotas.images,
+            Feature::Backups => config.quotas.backups,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FsType {
+    Ext4,
+    Vfat,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StorageDevice {
+    /// e.g. `mmcblk0` or `sda`
+    pub name: String,
+    pub size: u64,
+    pub removable: bool,
+    pub read_only: bool,
+    pub model: Option<String>,
+    pub partitions: Vec<Partition>,
+    /// filesystems of the device and its partitions that are mounted
+    pub mounts: Vec<MountUsage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Partition {
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MountUsage {
+    #[serde(flatten)]
+    pub mount: Mount,
+    pub total: Option<u64>,
+    pub free: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FeatureUsage {
+    pub feature: Feature,
+    pub dir: PathBuf,
+    /// 0 for no limit
+    pub quota: u64,
+    pub used: u64,
+    /// free space of the filesystem of `dir`
+    pub free: Option<u64>,
+}
+
+#[derive(Error, Debug)]
+pub enum FormatError {
+    #[error("no format was requested")]
+    NotRequested,
+    #[error("confirmation token is invalid")]
+    InvalidToken,
+    #[error("confirmation token expired")]
+    Expired,
+}
+
+#[derive(Debug)]
+struct PendingFormat {
+    token: String,
+    device: String,
+    fs_type: FsType,
+    label: Option<String>,
+    expires: Instant,
+}
+
+pub struct StorageManager {
+    config: config::Storage,
+    pending: Mutex<Option<PendingFormat>>,
+}
+
+impl StorageManager {
+    pub fn new(config: config::Storage) -> Self {
+        Self {
+            config,
+            pending: Mutex::new(None),
+        }
+    }
+
+    pub fn devices(&self) -> io::Result<Vec<StorageDevice>> {
+        let mounts = read_mounts()?;
+        Ok(list_devices(Path::new(SYS_BLOCK), &mounts))
+    }
+
+    pub fn features(&self) -> Vec<FeatureUsage> {
+        Feature::ALL
+            .into_iter()
+            .map(|feature| {
+                let dir = feature.dir(&self.config);
+                FeatureUsage {
+                    feature,
+                    dir: dir.to_owned(),
+                    quota: feature.quota(&self.config),
+                    used: dir_size(dir),
+                    free: get_fs_stat(&dir.to_string_lossy())
+                        .ok()
+                        .map(|(_, free)| free),
+                }
+            })
+            .collect()
+    }
+
+    /// First step of formatting `device`. Returns the token that
+    /// [`Self::confirm_format`] needs.
+    pub fn request_format(
+        &self,
+        device: &str,
+        fs_type: FsType,
+        label: Option<String>,
+    ) -> anyhow::Result<(String, Duration)> {
+        if let Some(label) = &label {
+            let valid = (1..=11).contains(&label.len())
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid {
+                return Err(BmcError::invalid_parameter(
+                    "label",
+                    "must be 1 to 11 characters of [a-zA-Z0-9_-]",
+                )
+                .into());
+            }
+        }
+        let device = self.find(device)?;
+        ensure_unmounted(&device)?;
+
+        let token = hex::encode(rand::random::<[u8; 16]>());
+        tracing::warn!("format of {} as {:?} requested", device, fs_type);
+        *self.lock_pending() = Some(PendingFormat {
+            token: token.clone(),
+            device,
+            fs_type,
+            label,
+            expires: Instant::now() + TOKEN_EXPIRY,
+        });
+        Ok((token, TOKEN_EXPIRY))
+    }
+
+    /// Formats the device of the pending request, returns its name.
+    pub async fn confirm_format(&self, token: &str) -> anyhow::Result<String> {
+        let request = {
+            let mut pending = self.lock_pending();
+            let request = pending.as_ref().ok_or(FormatError::NotRequested)?;
+            if request.expires < Instant::now() {
+                *pending = None;
+                return Err(FormatError::Expired.into());
+            }
+            let (expected, token) = (request.token.as_bytes(), token.as_bytes());
+            if expected.len() != token.len() || !openssl::memcmp::eq(expected, token) {
+                return Err(FormatError::InvalidToken.into());
+            }
+            pending.take().expect("checked above")
+        };
+        // the device may have been mounted since the request
+        ensure_unmounted(&request.device)?;
+
+        let path = format!("/dev/{}", request.device);
+        let mut command = match request.fs_type {
+            FsType::Ext4 => {
+                let mut command = Command::new("mkfs.ext4");
+                command.args(["-F", "-q"]);
+                if let Some(label) = &request.label {
+                    command.args(["-L", label]);
+                }
+                command
+            }
+            FsType::Vfat => {
+                let mut command = Command::new("mkfs.vfat");
+                command.arg("-I");
+                if let Some(label) = &request.label {
+                    command.args(["-n", label]);
+                }
+                command
+            }
+        };
+        tracing::warn!("formatting {} as {:?}", path, request.fs_type);
+        run(command.arg(&path)).await?;
+        Ok(request.device)
+    }
+
+    /// Mounts `device` at `mount_point` below `/mnt`, which defaults to the
+    /// name of the device. Returns where it was mounted.
+    pub async fn mount(&self, device: &str, mount_point: Option<&str>) -> anyhow::Result<PathBuf> {
+        let device = self.find(device)?;
+        ensure_unmounted(&device)?;
+        let target = resolve(Path::new(MOUNT_ROOT), mount_point.unwrap_or(&device))
+            .filter(|p| p != Path::new(MOUNT_ROOT))
+            .ok_or_else(|| BmcError::invalid_parameter("mount_point", "must be below /mnt"))?;
+        if read_mounts()?.iter().any(|m| Path::new(&m.path) == target) {
+            return Err(BmcError::InUse {
+                resource: target.display().to_string().into(),
+                by: "another mount".into(),
+            }
+            .into());
+        }
+        tokio::fs::create_dir_all(&target).await?;
+        tracing::info!("mounting {} at {}", device, target.display());
+        run(Command::new("mount")
+            .args(["-o", "noatime"])
+            .arg(format!("/dev/{}", device))
+            .arg(&target))
+        .await?;
+        Ok(target)
+    }
+
+    /// Unmounts all filesystems of `device` and its partitions. Returns where
+    /// they were mounted.
+    pub async fn unmount(&self, device: &str) -> anyhow::Result<Vec<String>> {
+        let device = self.find(device)?;
+        let mounts = mounts_of(&read_mounts()?, &device);
+        for mount in &mounts {
+            tracing::info!("unmounting {}", mount.path);
+            run(Command::new("umount").arg(&mount.path)).await?;
+        }
+        Ok(mounts.into_iter().map(|m| m.path).collect())
+    }
+
+    /// Name of `device` if it is a listed device or one of their partitions.
+    fn find(&self, device: &str) -> anyhow::Result<String> {
+        let known = list_devices(Path::new(SYS_BLOCK), &[])
+            .into_iter()
+            .flat_map(|d| std::iter::once(d.name).chain(d.partitions.into_iter().map(|p| p.name)))
+            .any(|name| name == device);
+        if !known {
+            return Err(BmcError::NotFound(format!("storage device `{}`", device).into()).into());
+        }
+        Ok(device.to_string())
+    }
+
+    fn lock_pending(&self) -> std::sync::MutexGuard<'_, Option<PendingFormat>> {
+        self.pending.lock().expect("pending format poisoned")
+    }
+}
+
+/// Bytes that `feature` can still store, `None` when it has no quota.
+pub fn room(config: &config::Storage, feature: Feature) -> Option<u64> {
+    room_in(feature.dir(config), feature.quota(config))
+}
+
+/// Bytes that fit in `dir` before it holds `quota` bytes, `None` when
+/// `quota` is 0.
+pub fn room_in(dir: &Path, quota: u64) -> Option<u64> {
+    (quota > 0).then(|| quota.saturating_sub(dir_size(dir)))
+}
+
+/// Fails with [`BmcError::QuotaExceeded`] when `feature` cannot store `size`
+/// more bytes.
+pub fn ensure_room(config: &config::Storage, feature: Feature, size: u64) -> anyhow::Result<()> {
+    match room(config, feature) {
+        Some(room) if size > room => Err(BmcError::QuotaExceeded {
+            feature: feature.name(),
+            quota: feature.quota(config),
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Fails writes beyond `room` bytes, for data whose size is not known up
+/// front.
+pub struct QuotaWriter<W> {
+    pub inner: W,
+    room: Option<u64>,
+    feature: Feature,
+}
+
+impl<W> QuotaWriter<W> {
+    pub fn new(inner: W, config: &config::Storage, feature: Feature) -> Self {
+        Self {
+            inner,
+            room: room(config, feature),
+            feature,
+        }
+    }
+}
+
+impl<W: Write> Write for QuotaWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(room) = &mut self.room {
+            if buf.len() as u64 > *room {
+                return Err(io::Error::other(format!(
+                    "the {} quota is exhausted",
+                    self.feature.name()
+                )));
+            }
+        }
+        let len = self.inner.write(buf)?;
+        if let Some(room) = &mut self.room {
+            *room -= len as u64;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn ensure_unmounted(device: &str) -> anyhow::Result<()> {
+    let mounts = mounts_of(&read_mounts()?, device);
+    if !mounts.is_empty() {
+        let paths: Vec<_> = mounts.into_iter().map(|m| m.path).collect();
+        return Err(BmcError::InUse {
+            resource: device.to_string().into(),
+            by: format!("the filesystem mounted at {}", paths.join(", ")).into(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+fn mounts_of(mounts: &[Mount], device: &str) -> Vec<Mount> {
+    let path = format!("/dev/{}", device);
+    mounts
+        .iter()
+        .filter(|m| is_on_device(&m.source, &path))
+        .cloned()
+        .collect()
+}
+
+async fn run(command: &mut Command) -> anyhow::Result<()> {
+    let output = command.output().await.context("running command")?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
+
+/// MMC and SCSI disks below `root`, e.g. `/sys/block`. The flash of the BMC
+/// itself is an MTD device and not listed.
+fn list_devices(root: &Path, mounts: &[Mount]) -> Vec<StorageDevice> {
+    let Ok(entries) = std::fs::read_dir(root) else {
+        return Vec::new();
+    };
+    let attribute = |dir: &Path, name: &str| {
+        std::fs::read_to_string(dir.join(name))
+            .ok()
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+    let size = |dir: &Path| {
+        attribute(dir, "size")
+            .and_then(|s| s.parse::<u64>().ok())
+            .map_or(0, |sectors| sectors * SECTOR_SIZE)
+    };
+
+    let mut devices: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let is_disk = match name.strip_prefix("mmcblk") {
+                Some(index) => !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()),
+                None => name
+                    .strip_prefix("sd")
+                    .is_some_and(|s| !s.is_empty() && s.bytes().all(|b| b.is_ascii_lowercase())),
+            };
+            if !is_disk {
+                return None;
+            }
+            let dir = entry.path();
+            let mut partitions: Vec<_> = std::fs::read_dir(&dir)
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().join("partition").exists())
+                .map(|entry| Partition {
+                    name: entry.file_name().to_string_lossy().into_owned(),
+                    size: size(&entry.path()),
+                })
+                .collect();
+            partitions.sort_by(|a, b| a.name.cmp(&b.name));
+            Some(StorageDevice {
+                size: size(&dir),
+                removable: attribute(&dir, "removable").is_some_and(|r| r == "1"),
+                read_only: attribute(&dir, "ro").is_some_and(|r| r == "1"),
+                model: attribute(&dir, "device/model").or_else(|| attribute(&dir, "device/name")),
+                partitions,
+                mounts: mounts_of(mounts, &name)
+                    .into_iter()
+                    .map(|mount| {
+                        let stat = get_fs_stat(&mount.path).ok();
+                        MountUsage {
+                            mount,
+                            total: stat.map(|(total, _)| total),
+                            free: stat.map(|(_, free)| free),
+                        }
+                    })
+                    .collect(),
+                name,
+            })
+        })
+        .collect();
+    devices.sort_by(|a, b| a.name.cmp(&b.name));
+    devices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+    use tempdir::TempDir;
+
+    #[test]
+    fn lists_disks_and_partitions() {
+        let dir = TempDir::new("storage_manager").unwrap();
+        let root = dir.path();
+        let write = |path: &str, value: &str| {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, value).unwrap();
+        };
+        write("mmcblk0/size", "62333952\n");
+        write("mmcblk0/removable", "0\n");
+        write("mmcblk0/device/name", "SD32G\n");
+        write("mmcblk0/mmcblk0p1/partition", "1\n");
+        write("mmcblk0/mmcblk0p1/size", "2048\n");
+        write("mmcblk0boot0/size", "8192\n");
+        write("sda/size", "100\n");
+        write("sda/removable", "1\n");
+        write("mtdblock0/size", "100\n");
+        write("loop0/size", "100\n");
+
+        let mounts = crate::utils::parse_mounts("/dev/mmcblk0p1 /mnt/sdcard vfat rw 0 0\n");
+        let devices = list_devices(root, &mounts);
+        let names: Vec<_> = devices.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, ["mmcblk0", "sda"]);
+        let card = &devices[0];
+        assert_eq!(card.size, 62333952 * 512);
+        assert_eq!(card.model.as_deref(), Some("SD32G"));
+        assert_eq!(card.partitions.len(), 1);
+        assert_eq!(card.partitions[0].size, 2048 * 512);
+        assert_eq!(card.mounts[0].mount.path, "/mnt/sdcard");
+        assert!(devices[1].removable);
+        assert!(devices[1].mounts.is_empty());
+    }
+
+    #[test]
+    fn quotas() {
+        let dir = TempDir::new("storage_manager").unwrap();
+        let config = config::Storage {
+            backup_dir: dir.path().join("backups"),
+            quotas: config::Quotas {
+                images: 0,
+                backups: 10,
+            },
+        };
+        std::fs::create_dir_all(&config.backup_dir).unwrap();
+        std::fs::write(config.backup_dir.join("a"), [0u8; 4]).unwrap();
+        assert_eq!(room(&config, Feature::Backups), Some(6));
+        assert!(ensure_room(&config, Feature::Backups, 6).is_ok());
+        let error = ensure_room(&config, Feature::Backups, 7).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<BmcError>().unwrap().code(),
+            "quota_exceeded"
+        );
+
+        let mut writer = QuotaWriter::new(Vec::new(), &config, Feature::Backups);
+        writer.write_all(&[1; 6]).unwrap();
+        assert!(writer.write_all(&[1]).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Integration with systemd when bmcd runs as one of its services. Every
+//! function is a no-op when the environment variables that systemd passes
+//! to the service are missing, so the same binary runs under the init
+//! scripts of the BMC OS.
+//!
+//! * [`notify`] sends state changes to the service manager, for a unit with
+//!   `Type=notify`.
+//! * [`listeners`] takes over the sockets of a socket-activated unit.
+//! * [`watchdog_interval`] is the interval in which the service manager
+//!   expects `WATCHDOG=1`, for a unit with `WatchdogSec=`.
+use anyhow::Context;
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+/// First file descriptor passed by socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sends `state`, one or more newline separated `KEY=value` assignments, to
+/// the service manager. Failures are logged, they must not affect the
+/// daemon.
+pub fn notify(state: &str) {
+    if let Err(e) = try_notify(state) {
+        tracing::warn!("notifying systemd: {}", e);
+    }
+}
+
+fn try_notify(state: &str) -> io::Result<()> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let path = path.to_string_lossy();
+    let address = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path.as_ref())?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+/// TCP sockets passed to the daemon by socket activation, in the order of
+/// the `ListenStream=` entries of the socket unit. Empty when the daemon was
+/// not socket activated. The `api` health check of the watchdog still
+/// connects to the configured `port`, which the socket unit should listen on.
+pub fn listeners() -> anyhow::Result<Vec<TcpListener>> {
+    if !for_this_process("LISTEN_PID")? {
+        return Ok(Vec::new());
+    }
+    let count: RawFd = env::var("LISTEN_FDS")
+        .context("LISTEN_FDS not set")?
+        .parse()
+        .context("LISTEN_FDS")?;
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd hands over ownership of the passed sockets
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            let address = listener
+                .local_addr()
+                .with_context(|| format!("passed file descriptor {} is not a TCP socket", fd))?;
+            listener.set_nonblocking(true)?;
+            tracing::info!("listening on {} (socket activation)", address);
+            Ok(listener)
+        })
+        .collect()
+}
+
+/// Interval in which the service manager expects a `WATCHDOG=1`
+/// notification, `None` when the watchdog of the unit is disabled.
+pub fn watchdog_interval() -> anyhow::Result<Option<Duration>> {
+    // without WATCHDOG_PID the variable is meant for this process
+    if env::var_os("WATCHDOG_PID").is_some() && !for_this_process("WATCHDOG_PID")? {
+        return Ok(None);
+    }
+    let Ok(usec) = env::var("WATCHDOG_USEC") else {
+        return Ok(None);
+    };
+    let usec: u64 = usec.parse().context("WATCHDOG_USEC")?;
+    Ok((usec > 0).then(|| Duration::from_micros(usec)))
+}
+
+/// Whether the variable `name` holds the pid of this process. Variables
+/// meant for a parent process must be ignored.
+fn for_this_process(name: &str) -> anyhow::Result<bool> {
+    let Ok(pid) = env::var(name) else {
+        return Ok(false);
+    };
+    let pid: u32 = pid.parse().with_context(|| name.to_string())?;
+    Ok(pid == std::process::id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notify_socket() {
+        let dir = tempdir::TempDir::new("systemd").unwrap();
+        let path = dir.path().join("notify");
+        let manager = UnixDatagram::bind(&path).unwrap();
+
+        env::set_var("NOTIFY_SOCKET", &path);
+        try_notify("READY=1\nSTATUS=up").unwrap();
+        env::remove_var("NOTIFY_SOCKET");
+        let mut buf = [0u8; 64];
+        let len = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\nSTATUS=up");
+
+        // not running under systemd
+        try_notify("READY=1").unwrap();
+    }
+}
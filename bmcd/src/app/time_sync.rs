@@ -0,0 +1,235 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Time keeping of the BMC. The clock is either disciplined by the busybox
+//! `ntpd` or set by hand. The kernel time status, as reported by `adjtimex`,
+//! tells whether the clock is synchronized and how much it drifts.
+use super::bmc_application::BmcApplication;
+use anyhow::{ensure, Context};
+use chrono::{DateTime, Utc};
+use nix::sys::time::TimeSpec;
+use nix::time::{clock_settime, ClockId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::process::Command;
+
+pub const TIME_SETTINGS_KEY: &str = "time_settings";
+const NTP_CONF: &str = "/etc/ntp.conf";
+const NTP_SERVICE: &str = "/etc/init.d/S49ntp";
+/// kernel time status flag that is set while the clock is not synchronized.
+const STA_UNSYNC: u32 = 0x0040;
+const MAX_SERVERS: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimeSettings {
+    pub ntp_enabled: bool,
+    pub servers: Vec<String>,
+}
+
+impl Default for TimeSettings {
+    fn default() -> Self {
+        Self {
+            ntp_enabled: true,
+            servers: vec!["pool.ntp.org".to_string()],
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TimeStatus {
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub settings: TimeSettings,
+    pub synchronized: bool,
+    /// offset to the reference clock in microseconds
+    pub offset_us: i64,
+    /// frequency correction applied to the clock, in parts per million
+    pub drift_ppm: f64,
+    pub max_error_us: i64,
+    pub estimated_error_us: i64,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct KernelTimeStatus {
+    offset: i64,
+    frequency: i64,
+    max_error: i64,
+    est_error: i64,
+    status: u32,
+}
+
+pub async fn get_time_status(bmc: &BmcApplication) -> anyhow::Result<TimeStatus> {
+    let output = Command::new("adjtimex")
+        .output()
+        .await
+        .context("adjtimex")?;
+    ensure!(
+        output.status.success(),
+        "adjtimex returned {}",
+        output.status
+    );
+    let kernel = parse_adjtimex(&String::from_utf8_lossy(&output.stdout));
+
+    Ok(TimeStatus {
+        time: Utc::now(),
+        settings: bmc.app_db.get::<TimeSettings>(TIME_SETTINGS_KEY).await,
+        synchronized: kernel.status & STA_UNSYNC == 0,
+        offset_us: kernel.offset,
+        // frequency is expressed in ppm with a 16-bit fractional part
+        drift_ppm: kernel.frequency as f64 / 65536.0,
+        max_error_us: kernel.max_error,
+        estimated_error_us: kernel.est_error,
+    })
+}
+
+/// Stores the NTP settings and (re)starts or stops `ntpd` accordingly.
+pub async fn set_ntp(bmc: &BmcApplication, settings: TimeSettings) -> anyhow::Result<()> {
+    ensure!(
+        settings.servers.len() <= MAX_SERVERS,
+        "at most {} NTP servers are supported",
+        MAX_SERVERS
+    );
+    for server in &settings.servers {
+        ensure!(
+            !server.is_empty()
+                && server
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-.:".contains(c)),
+            "`{}` is not a valid NTP server",
+            server
+        );
+    }
+    ensure!(
+        !settings.ntp_enabled || !settings.servers.is_empty(),
+        "at least one NTP server is required"
+    );
+
+    bmc.app_db.set(TIME_SETTINGS_KEY, settings.clone()).await;
+    apply_time_settings(&settings).await
+}
+
+/// Applies the stored settings, called on start-up.
+pub async fn restore_time_settings(bmc: &BmcApplication) -> anyhow::Result<()> {
+    let settings = bmc.app_db.get::<TimeSettings>(TIME_SETTINGS_KEY).await;
+    apply_time_settings(&settings).await
+}
+
+async fn apply_time_settings(settings: &TimeSettings) -> anyhow::Result<()> {
+    tokio::fs::write(NTP_CONF, render_ntp_conf(&settings.servers))
+        .await
+        .context(NTP_CONF)?;
+
+    let action = if settings.ntp_enabled {
+        "restart"
+    } else {
+        "stop"
+    };
+    let status = Command::new(NTP_SERVICE).arg(action).status().await?;
+    ensure!(
+        status.success(),
+        "{} {} returned {}",
+        NTP_SERVICE,
+        action,
+        status
+    );
+    Ok(())
+}
+
+/// Sets the system clock and the RTC. Only permitted while NTP is disabled,
+/// as `ntpd` would undo the change otherwise.
+pub async fn set_time(bmc: &BmcApplication, time: DateTime<Utc>) -> anyhow::Result<()> {
+    let settings = bmc.app_db.get::<TimeSettings>(TIME_SETTINGS_KEY).await;
+    ensure!(
+        !settings.ntp_enabled,
+        "disable NTP before setting the time manually"
+    );
+
+    let timespec = TimeSpec::new(time.timestamp(), time.timestamp_subsec_nanos().into());
+    clock_settime(ClockId::CLOCK_REALTIME, timespec).context("set system clock")?;
+    tracing::info!("system time set to {}", time);
+
+    let status = Command::new("hwclock").args(["-w", "-u"]).status().await;
+    if !status.is_ok_and(|s| s.success()) {
+        tracing::warn!("could not write time to the RTC");
+    }
+    Ok(())
+}
+
+fn render_ntp_conf(servers: &[String]) -> String {
+    let mut conf = String::from("# generated by bmcd\n");
+    for server in servers {
+        conf.push_str(&format!("server {}\n", server));
+    }
+    conf
+}
+
+/// Parses the output of the busybox `adjtimex` applet.
+fn parse_adjtimex(output: &str) -> KernelTimeStatus {
+    let values: HashMap<&str, i64> = output
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            let key = key.split_whitespace().last()?;
+            let value = value.split_whitespace().next()?.parse().ok()?;
+            Some((key, value))
+        })
+        .collect();
+
+    KernelTimeStatus {
+        offset: values.get("offset").copied().unwrap_or_default(),
+        frequency: values.get("frequency").copied().unwrap_or_default(),
+        max_error: values.get("maxerror").copied().unwrap_or_default(),
+        est_error: values.get("esterror").copied().unwrap_or_default(),
+        status: values
+            .get("status")
+            .map_or(STA_UNSYNC, |s| u32::try_from(*s).unwrap_or(STA_UNSYNC)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adjtimex_output() {
+        let output = "    mode:         0\n\
+            -o  offset:       -1250 us\n\
+            -f  frequency:    655360\n\
+            \x20   maxerror:     16000\n\
+            \x20   esterror:     500\n\
+            \x20   status:       8193 ( PLL NANO )\n\
+            -p  timeconstant: 7\n";
+
+        assert_eq!(
+            parse_adjtimex(output),
+            KernelTimeStatus {
+                offset: -1250,
+                frequency: 655360,
+                max_error: 16000,
+                est_error: 500,
+                status: 8193,
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_status_is_unsynchronized() {
+        assert_eq!(parse_adjtimex("").status & STA_UNSYNC, STA_UNSYNC);
+    }
+
+    #[test]
+    fn ntp_conf() {
+        let conf = render_ntp_conf(&["0.pool.ntp.org".to_string(), "10.0.0.1".to_string()]);
+        assert!(conf.ends_with("server 0.pool.ntp.org\nserver 10.0.0.1\n"));
+    }
+}
@@ -12,7 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 use super::bmc_application::BmcApplication;
-use super::upgrade_worker::UpgradeWorker;
+use super::firmware_signature::FirmwareVerifier;
+use super::firmware_slots::FirmwareSlots;
+use super::jobs::JobKind;
+use super::upgrade_progress::UpgradeStatus;
+use super::upgrade_worker::{DryRunTarget, UpgradeWorker};
 use crate::hal::NodeId;
 use crate::streaming_data_service::data_transfer::DataTransfer;
 use crate::streaming_data_service::TransferRequest;
@@ -48,23 +52,31 @@ impl TryInto<TransferRequest> for InitializeTransfer {
     type Error = anyhow::Error;
 
     fn try_into(mut self) -> Result<TransferRequest, Self::Error> {
+        let kind = match self.upgrade_command {
+            UpgradeCommand::OsUpgrade { .. } => JobKind::FirmwareUpgrade,
+            UpgradeCommand::Module(..) | UpgradeCommand::DryRun(..) => JobKind::Flash,
+        };
         let size = self.data_transfer.size()?;
         let sender = self.data_transfer.sender_half();
         let cancel = CancellationToken::new();
         let cancel_child = cancel.child_token();
         let (written_sender, written_receiver) = watch::channel(0u64);
+        let (throughput_sender, throughput_receiver) = watch::channel(0u64);
         let worker = self.upgrade_command.run(UpgradeWorker::new(
             self.do_crc_validation,
             self.data_transfer,
             cancel_child,
             written_sender,
+            throughput_sender,
         ));
 
         Ok(TransferRequest {
+            kind,
             process_name: self.transfer_name,
             size,
             sender,
             progress_watcher: written_receiver,
+            throughput_watcher: throughput_receiver,
             worker,
             cancel,
         })
@@ -72,8 +84,17 @@ impl TryInto<TransferRequest> for InitializeTransfer {
 }
 
 pub enum UpgradeCommand {
-    OsUpgrade,
+    /// Upgrade of the BMC firmware, to the inactive slot when A/B slots are
+    /// configured. The image is only applied after its signature verified.
+    OsUpgrade {
+        verifier: Arc<FirmwareVerifier>,
+        slots: Option<Arc<FirmwareSlots>>,
+        progress: Arc<UpgradeStatus>,
+    },
     Module(NodeId, Arc<BmcApplication>),
+    /// Flash of a node that writes the image to a [`DryRunTarget`], to test
+    /// client tooling without wearing out the storage of the node.
+    DryRun(NodeId, DryRunTarget),
 }
 
 impl UpgradeCommand {
@@ -82,8 +103,15 @@ impl UpgradeCommand {
         upgrade_worker: UpgradeWorker,
     ) -> BoxFuture<'static, Result<(), anyhow::Error>> {
         match self {
-            UpgradeCommand::OsUpgrade => Box::pin(upgrade_worker.os_update()),
+            UpgradeCommand::OsUpgrade {
+                verifier,
+                slots,
+                progress,
+            } => Box::pin(upgrade_worker.os_update(verifier, slots, progress)),
             UpgradeCommand::Module(bmc, node) => Box::pin(upgrade_worker.flash_node(node, bmc)),
+            UpgradeCommand::DryRun(node, target) => {
+                Box::pin(upgrade_worker.flash_dry_run(node, target))
+            }
         }
     }
 }
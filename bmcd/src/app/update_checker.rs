@@ -0,0 +1,296 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Client of a release feed that announces new BMC firmware. The feed is a
+//! JSON document of the form:
+//!
+//! ```json
+//! { "releases": [ { "version": "2.1.0", "url": "https://..", "sha256": "..",
+//!                   "changelog": "..", "published": "2024-05-01" } ] }
+//! ```
+//!
+//! Updates are only downloaded ("staged") on request. Applying a staged
+//! update is left to the regular firmware upgrade routes.
+use crate::config::Updates;
+use crate::utils::get_timestamp_unix;
+use anyhow::{bail, ensure, Context};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+const FEED_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Release {
+    pub version: String,
+    pub url: String,
+    pub sha256: String,
+    #[serde(default)]
+    pub changelog: String,
+    #[serde(default)]
+    pub published: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReleaseFeed {
+    releases: Vec<Release>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UpdateStatus {
+    pub installed: Option<String>,
+    /// unix timestamp of the last successful check
+    pub checked_at: Option<u64>,
+    pub last_error: Option<String>,
+    /// releases newer than the installed firmware, newest first
+    pub available: Vec<Release>,
+    /// path of the downloaded update, ready to be applied
+    pub staged: Option<StagedUpdate>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StagedUpdate {
+    pub version: String,
+    pub path: PathBuf,
+}
+
+pub struct UpdateChecker {
+    config: Updates,
+    client: reqwest::Client,
+    status: RwLock<UpdateStatus>,
+}
+
+impl UpdateChecker {
+    pub fn new(config: Updates) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            status: RwLock::new(UpdateStatus::default()),
+        }
+    }
+
+    pub fn status(&self) -> UpdateStatus {
+        self.status.read().expect("update status poisoned").clone()
+    }
+
+    /// Queries the release feed and records the releases that are newer than
+    /// the installed firmware.
+    pub async fn check(&self) -> anyhow::Result<UpdateStatus> {
+        let installed = installed_version().await;
+        let result = self.fetch_feed().await;
+
+        let mut status = self.status.write().expect("update status poisoned");
+        status.installed.clone_from(&installed);
+        match result {
+            Ok(feed) => {
+                status.available = newer_releases(feed.releases, installed.as_deref());
+                status.checked_at = get_timestamp_unix();
+                status.last_error = None;
+                Ok(status.clone())
+            }
+            Err(e) => {
+                status.last_error = Some(format!("{:#}", e));
+                Err(e)
+            }
+        }
+    }
+
+    async fn fetch_feed(&self) -> anyhow::Result<ReleaseFeed> {
+        let feed = self
+            .client
+            .get(&self.config.feed)
+            .timeout(FEED_TIMEOUT)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .context("release feed")?
+            .json()
+            .await
+            .context("invalid release feed")?;
+        Ok(feed)
+    }
+
+    /// Downloads the image of `version` into the staging directory and
+    /// verifies its checksum. Only one update is staged at a time.
+    pub async fn stage(&self, version: &str) -> anyhow::Result<StagedUpdate> {
+        let Some(release) = self
+            .status()
+            .available
+            .into_iter()
+            .find(|r| r.version == version)
+        else {
+            bail!("version {} is not available", version);
+        };
+
+        let file_name = release
+            .url
+            .rsplit('/')
+            .next()
+            .filter(|n| !n.is_empty() && *n != "..")
+            .unwrap_or("update.img");
+        let path = self.config.staging_dir.join(file_name);
+        self.status.write().expect("update status poisoned").staged = None;
+        if tokio::fs::try_exists(&self.config.staging_dir).await? {
+            tokio::fs::remove_dir_all(&self.config.staging_dir).await?;
+        }
+        tokio::fs::create_dir_all(&self.config.staging_dir).await?;
+
+        tracing::info!("downloading firmware {} from {}", version, release.url);
+        if let Err(e) = download(&self.client, &release, &path).await {
+            let _ = tokio::fs::remove_file(&path).await;
+            return Err(e);
+        }
+
+        let staged = StagedUpdate {
+            version: release.version,
+            path,
+        };
+        self.status.write().expect("update status poisoned").staged = Some(staged.clone());
+        Ok(staged)
+    }
+
+    /// Removes the staged update, after it got applied.
+    pub async fn clear_staged(&self) {
+        self.status.write().expect("update status poisoned").staged = None;
+        if let Err(e) = tokio::fs::remove_dir_all(&self.config.staging_dir).await {
+            tracing::warn!("removing staged update: {}", e);
+        }
+    }
+
+    /// Checks the feed every configured interval.
+    pub fn run(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.config.interval);
+            loop {
+                interval.tick().await;
+                match self.check().await {
+                    Ok(status) if !status.available.is_empty() => {
+                        tracing::info!("BMC firmware {} is available", status.available[0].version)
+                    }
+                    Ok(_) => tracing::debug!("BMC firmware is up to date"),
+                    Err(e) => tracing::warn!("checking for updates: {:#}", e),
+                }
+            }
+        });
+    }
+}
+
+async fn download(
+    client: &reqwest::Client,
+    release: &Release,
+    path: &std::path::Path,
+) -> anyhow::Result<()> {
+    let response = client
+        .get(&release.url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())?;
+
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut hasher = Sha256::new();
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+    }
+    file.sync_all().await?;
+
+    let checksum = hex::encode(hasher.finalize());
+    ensure!(
+        checksum.eq_ignore_ascii_case(&release.sha256),
+        "sha256 checksum failed. Expected: {}, got: {}",
+        release.sha256,
+        checksum
+    );
+    Ok(())
+}
+
+/// Version of the installed firmware, as reported by `/etc/os-release`.
+async fn installed_version() -> Option<String> {
+    let os_release = tokio::fs::read_to_string("/etc/os-release").await.ok()?;
+    os_release
+        .lines()
+        .filter_map(|l| l.split_once('='))
+        .find(|(key, _)| *key == "VERSION")
+        .map(|(_, value)| value.trim_matches('"').to_string())
+}
+
+fn newer_releases(mut releases: Vec<Release>, installed: Option<&str>) -> Vec<Release> {
+    let installed = installed.map(version_key).unwrap_or_default();
+    releases.retain(|r| version_key(&r.version) > installed);
+    releases.sort_by_key(|r| std::cmp::Reverse(version_key(&r.version)));
+    releases
+}
+
+/// Numeric components of a version such as `v2.0.5-rc1`, suffixes are
+/// ignored.
+fn version_key(version: &str) -> Vec<u64> {
+    version
+        .trim_start_matches('v')
+        .split(['-', '+'])
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map(|c| c.parse().unwrap_or_default())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn release(version: &str) -> Release {
+        Release {
+            version: version.to_string(),
+            url: format!("https://example.com/{}.img", version),
+            sha256: String::new(),
+            changelog: String::new(),
+            published: None,
+        }
+    }
+
+    #[test]
+    fn compare_versions() {
+        assert!(version_key("v2.0.10") > version_key("2.0.9"));
+        assert!(version_key("2.1") > version_key("2.0.5-rc1"));
+        assert_eq!(version_key("2.0.5-rc1"), vec![2, 0, 5]);
+    }
+
+    #[test]
+    fn only_newer_releases() {
+        let releases = vec![release("2.0.4"), release("2.1.0"), release("2.0.6")];
+        let newer: Vec<_> = newer_releases(releases.clone(), Some("2.0.5"))
+            .into_iter()
+            .map(|r| r.version)
+            .collect();
+        assert_eq!(newer, ["2.1.0", "2.0.6"]);
+
+        assert_eq!(newer_releases(releases, None).len(), 3);
+    }
+
+    #[test]
+    fn parse_feed() {
+        let feed: ReleaseFeed = serde_json::from_str(
+            r#"{"releases": [{"version": "2.1.0", "url": "https://example.com/a.img",
+                "sha256": "00", "changelog": "fixes"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(feed.releases[0].changelog, "fixes");
+        assert_eq!(feed.releases[0].published, None);
+    }
+}
@@ -0,0 +1,225 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Applies staged BMC firmware updates during a daily maintenance window.
+use super::bmc_application::BmcApplication;
+use super::notifier::Notifier;
+use super::transfer_action::{InitializeTransfer, UpgradeCommand};
+use super::update_checker::{StagedUpdate, UpdateChecker};
+use crate::config::MaintenanceWindow;
+use crate::hal::NodeId;
+use crate::streaming_data_service::data_transfer::DataTransfer;
+use crate::streaming_data_service::{StreamingDataService, StreamingState};
+use anyhow::{bail, ensure};
+use chrono::{Local, NaiveDateTime, NaiveTime, TimeDelta};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often staged updates are looked for while a window is open.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// Longest sleep outside of a window, so the scheduler catches up with
+/// changes of the clock, e.g. when NTP sets it after boot.
+const MAX_IDLE: Duration = Duration::from_secs(60 * 60);
+
+/// Spawns the scheduler. `upgrade_command` creates the command that applies
+/// a firmware image, the same as used by the firmware upgrade route.
+pub fn run_update_scheduler(
+    window: MaintenanceWindow,
+    checker: Arc<UpdateChecker>,
+    bmc: Arc<BmcApplication>,
+    streaming: Arc<StreamingDataService>,
+    notifier: Arc<Notifier>,
+    upgrade_command: impl Fn() -> UpgradeCommand + Send + 'static,
+) -> anyhow::Result<()> {
+    let start = window.start_time()?;
+    let duration = TimeDelta::from_std(window.duration)?;
+
+    tokio::spawn(async move {
+        // window in which an update was applied or postponed
+        let mut handled: Option<NaiveDateTime> = None;
+        let mut postponed: Option<String> = None;
+
+        loop {
+            tokio::time::sleep(next_check(Local::now().naive_local(), start, duration)).await;
+            let current = window_start(Local::now().naive_local(), start, duration);
+
+            if current.is_none() || current != handled {
+                if let Some(reason) = postponed.take() {
+                    let message = format!("firmware update postponed: {}", reason);
+                    tracing::warn!("{}", message);
+                    notifier.notify("firmware_update_postponed", message).await;
+                }
+            }
+
+            let Some(current) = current else {
+                continue;
+            };
+            if handled == Some(current) && postponed.is_none() {
+                continue;
+            }
+            let Some(staged) = checker.status().staged else {
+                continue;
+            };
+
+            handled = Some(current);
+            if let Err(e) = precheck(&bmc, &streaming, window.require_nodes_off).await {
+                // checked again until the window closes
+                postponed = Some(format!("{:#}", e));
+                continue;
+            }
+            postponed = None;
+
+            let result = apply(&staged, &streaming, upgrade_command()).await;
+            checker.clear_staged().await;
+            match result {
+                Ok(()) => {
+                    notifier
+                        .notify(
+                            "firmware_update_applied",
+                            format!("firmware {} installed, rebooting", staged.version),
+                        )
+                        .await;
+                    if let Err(e) = bmc.reboot(false).await {
+                        tracing::error!("reboot after firmware update: {:#}", e);
+                    }
+                }
+                Err(e) => {
+                    let message = format!("firmware {} failed: {:#}", staged.version, e);
+                    tracing::error!("{}", message);
+                    notifier.notify("firmware_update_failed", message).await;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Start of the maintenance window that `now` falls in, if any.
+fn window_start(
+    now: NaiveDateTime,
+    start: NaiveTime,
+    duration: TimeDelta,
+) -> Option<NaiveDateTime> {
+    let today = now.date().and_time(start);
+    [today, today - TimeDelta::days(1)]
+        .into_iter()
+        .find(|begin| *begin <= now && now < *begin + duration)
+}
+
+/// Time to sleep before the next check. Outside of a window the scheduler
+/// has nothing to do until the next one opens.
+fn next_check(now: NaiveDateTime, start: NaiveTime, duration: TimeDelta) -> Duration {
+    if window_start(now, start, duration).is_some() {
+        return POLL_INTERVAL;
+    }
+    let mut next = now.date().and_time(start);
+    if next <= now {
+        next += TimeDelta::days(1);
+    }
+    (next - now)
+        .to_std()
+        .map_or(POLL_INTERVAL, |d| d.min(MAX_IDLE))
+}
+
+async fn precheck(
+    bmc: &BmcApplication,
+    streaming: &StreamingDataService,
+    require_nodes_off: bool,
+) -> anyhow::Result<()> {
+    ensure!(
+        !matches!(*streaming.status().await, StreamingState::Transferring(_)),
+        "a flash is in progress"
+    );
+
+    if require_nodes_off {
+        for id in 0..4u8 {
+            let node = NodeId::try_from(id).map_err(anyhow::Error::msg)?;
+            ensure!(!bmc.get_node_power(node).await?, "{:?} is powered on", node);
+        }
+    }
+    Ok(())
+}
+
+/// Runs the upgrade of the staged image and waits for it to finish.
+async fn apply(
+    staged: &StagedUpdate,
+    streaming: &StreamingDataService,
+    command: UpgradeCommand,
+) -> anyhow::Result<()> {
+    tracing::info!("applying firmware {} in maintenance window", staged.version);
+    let transfer = InitializeTransfer::new(
+        "scheduled firmware upgrade".to_string(),
+        command,
+        DataTransfer::local(staged.path.clone(), None),
+        true,
+    );
+    streaming.request_transfer(transfer.try_into()?).await?;
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        match &*streaming.status().await {
+            StreamingState::Transferring(_) => continue,
+            StreamingState::Done(_, _) => return Ok(()),
+            StreamingState::Error(e) => bail!("{}", e),
+            StreamingState::Ready => bail!("upgrade was cancelled"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(day: u32, hour: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 5, day)
+            .unwrap()
+            .and_hms_opt(hour, min, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn window_across_midnight() {
+        let start = NaiveTime::from_hms_opt(23, 30, 0).unwrap();
+        let duration = TimeDelta::hours(1);
+
+        assert_eq!(
+            window_start(at(2, 23, 45), start, duration),
+            Some(at(2, 23, 30))
+        );
+        assert_eq!(
+            window_start(at(3, 0, 15), start, duration),
+            Some(at(2, 23, 30))
+        );
+        assert_eq!(window_start(at(3, 0, 30), start, duration), None);
+        assert_eq!(window_start(at(3, 12, 0), start, duration), None);
+    }
+
+    #[test]
+    fn sleep_until_window() {
+        let start = NaiveTime::from_hms_opt(3, 0, 0).unwrap();
+        let duration = TimeDelta::hours(1);
+
+        assert_eq!(next_check(at(2, 3, 30), start, duration), POLL_INTERVAL);
+        assert_eq!(
+            next_check(at(2, 2, 45), start, duration),
+            Duration::from_secs(15 * 60)
+        );
+        assert_eq!(next_check(at(2, 12, 0), start, duration), MAX_IDLE);
+        assert_eq!(
+            next_check(at(2, 4, 0), start, duration),
+            MAX_IDLE,
+            "the next window is tomorrow"
+        );
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Journal of the BMC firmware write path. Every step of an upgrade is
+//! recorded on persistent storage before it is executed, so that an upgrade
+//! that got interrupted by a power cut or crash is detected at the next start
+//! of bmcd. Depending on the step and on whether the image is still present,
+//! the upgrade is then resumed or abandoned in favour of the running firmware.
+use super::firmware_slots::{FirmwareSlots, Slot};
+use crate::utils::get_timestamp_unix;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+use tokio::sync::watch;
+
+const JOURNAL_PATH: &str = "/var/lib/bmcd/upgrade.journal";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalStep {
+    /// the image is being written to flash
+    Writing,
+    /// the image is written, the bootloader is being switched to it
+    Activating,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub image: PathBuf,
+    pub sha256: String,
+    /// target slot, `None` for upgrades through `osupdate`
+    pub slot: Option<Slot>,
+    pub step: JournalStep,
+    pub started: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecoveryAction {
+    /// the interrupted upgrade was completed, a reboot activates it
+    Resumed,
+    /// the upgrade could not be completed, the running firmware stays active
+    RolledBack,
+    /// the upgrade cannot be recovered automatically
+    Abandoned,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecoveryReport {
+    pub action: RecoveryAction,
+    pub detail: String,
+    pub interrupted: Option<JournalEntry>,
+    pub timestamp: Option<u64>,
+}
+
+pub struct UpgradeJournal {
+    path: PathBuf,
+}
+
+impl Default for UpgradeJournal {
+    fn default() -> Self {
+        Self::new(PathBuf::from(JOURNAL_PATH))
+    }
+}
+
+impl UpgradeJournal {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Records the start of writing `image`.
+    pub async fn begin(&self, image: &Path, slot: Option<Slot>) -> anyhow::Result<()> {
+        let entry = JournalEntry {
+            image: image.to_path_buf(),
+            sha256: file_sha256(image).await?,
+            slot,
+            step: JournalStep::Writing,
+            started: get_timestamp_unix(),
+        };
+        self.store(&entry).await
+    }
+
+    pub async fn advance(&self, step: JournalStep) -> anyhow::Result<()> {
+        let mut entry = self.load().await?.context("no upgrade in progress")?;
+        entry.step = step;
+        self.store(&entry).await
+    }
+
+    /// Marks the upgrade as finished, successful or not.
+    pub async fn finish(&self) -> anyhow::Result<()> {
+        match tokio::fs::remove_file(&self.path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn load(&self) -> anyhow::Result<Option<JournalEntry>> {
+        match tokio::fs::read(&self.path).await {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the entry atomically, the previous entry stays intact when the
+    /// power is cut halfway.
+    async fn store(&self, entry: &JournalEntry) -> anyhow::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let temp = self.path.with_extension("tmp");
+        tokio::fs::write(&temp, serde_json::to_vec(entry)?).await?;
+        tokio::fs::File::open(&temp).await?.sync_all().await?;
+        tokio::fs::rename(&temp, &self.path).await?;
+        Ok(())
+    }
+
+    /// Inspects the journal for an interrupted upgrade and recovers from it.
+    /// Returns `None` when the last upgrade finished.
+    pub async fn recover(&self, slots: Option<&FirmwareSlots>) -> Option<RecoveryReport> {
+        let entry = match self.load().await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return None,
+            Err(e) => {
+                let _ = self.finish().await;
+                return Some(report(
+                    RecoveryAction::Abandoned,
+                    format!("unreadable upgrade journal: {:#}", e),
+                    None,
+                ));
+            }
+        };
+
+        tracing::warn!("detected interrupted firmware upgrade: {:?}", entry);
+        let (action, detail) = match (entry.slot, slots) {
+            (Some(slot), Some(slots)) => match resume(&entry, slot, slots).await {
+                Ok(detail) => (RecoveryAction::Resumed, detail),
+                Err(e) => (
+                    RecoveryAction::RolledBack,
+                    format!("staying on the running firmware: {:#}", e),
+                ),
+            },
+            _ => (
+                RecoveryAction::Abandoned,
+                "an interrupted `osupdate` cannot be recovered, upgrade the firmware again"
+                    .to_string(),
+            ),
+        };
+
+        if let Err(e) = self.finish().await {
+            tracing::error!("clearing upgrade journal: {:#}", e);
+        }
+        tracing::warn!("firmware upgrade recovery: {:?}, {}", action, detail);
+        Some(report(action, detail, Some(entry)))
+    }
+}
+
+async fn resume(entry: &JournalEntry, slot: Slot, slots: &FirmwareSlots) -> anyhow::Result<String> {
+    if entry.step == JournalStep::Writing {
+        // the slot holds a partial image, it can only be completed when the
+        // image survived
+        let sha256 = file_sha256(&entry.image)
+            .await
+            .context("image is no longer available")?;
+        anyhow::ensure!(sha256 == entry.sha256, "image changed since the upgrade");
+        slots
+            .write(slot, &entry.image, &watch::Sender::new(0))
+            .await?;
+    }
+    slots.activate(slot).await?;
+    Ok(format!(
+        "firmware written to slot {:?}, reboot to activate",
+        slot
+    ))
+}
+
+fn report(
+    action: RecoveryAction,
+    detail: String,
+    interrupted: Option<JournalEntry>,
+) -> RecoveryReport {
+    RecoveryReport {
+        action,
+        detail,
+        interrupted,
+        timestamp: get_timestamp_unix(),
+    }
+}
+
+async fn file_sha256(path: &Path) -> anyhow::Result<String> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| path.display().to_string())?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn journal_steps() {
+        let dir = TempDir::new("upgrade_journal").unwrap();
+        let image = dir.path().join("firmware.img");
+        std::fs::write(&image, b"firmware").unwrap();
+        let journal = UpgradeJournal::new(dir.path().join("journal"));
+
+        journal.begin(&image, Some(Slot::B)).await.unwrap();
+        journal.advance(JournalStep::Activating).await.unwrap();
+        let entry = journal.load().await.unwrap().unwrap();
+        assert_eq!(entry.step, JournalStep::Activating);
+        assert_eq!(entry.slot, Some(Slot::B));
+        assert_eq!(entry.sha256, hex::encode(Sha256::digest(b"firmware")));
+
+        journal.finish().await.unwrap();
+        assert!(journal.load().await.unwrap().is_none());
+        assert!(journal.recover(None).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn interrupted_osupdate_is_abandoned() {
+        let dir = TempDir::new("upgrade_journal").unwrap();
+        let image = dir.path().join("firmware.img");
+        std::fs::write(&image, b"firmware").unwrap();
+        let journal = UpgradeJournal::new(dir.path().join("journal"));
+
+        journal.begin(&image, None).await.unwrap();
+        let report = journal.recover(None).await.unwrap();
+        assert_eq!(report.action, RecoveryAction::Abandoned);
+        assert!(journal.load().await.unwrap().is_none());
+    }
+}
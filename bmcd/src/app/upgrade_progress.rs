@@ -0,0 +1,172 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use super::upgrade_journal::{RecoveryAction, RecoveryReport, UpgradeJournal};
+use serde::Serialize;
+use std::sync::RwLock;
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpgradePhase {
+    /// receiving the image
+    Downloading,
+    /// checking the signature of the image
+    Verifying,
+    /// writing the image to flash
+    Writing,
+    /// flushing caches to the flash
+    Syncing,
+    /// the upgrade is installed, a reboot activates it
+    RebootPending,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct UpgradeProgress {
+    pub phase: UpgradePhase,
+    /// progress within the phase, `None` when the phase cannot report it
+    pub percent: Option<u8>,
+}
+
+/// Publishes the progress of a BMC firmware upgrade. `None` when no upgrade
+/// is running or the last one failed. Also holds the journal of the write
+/// path and the outcome of the recovery of an interrupted upgrade.
+pub struct UpgradeStatus {
+    progress: watch::Sender<Option<UpgradeProgress>>,
+    journal: UpgradeJournal,
+    recovery: RwLock<Option<RecoveryReport>>,
+}
+
+impl Default for UpgradeStatus {
+    fn default() -> Self {
+        Self::new(UpgradeJournal::default())
+    }
+}
+
+impl UpgradeStatus {
+    pub fn new(journal: UpgradeJournal) -> Self {
+        Self {
+            progress: watch::Sender::new(None),
+            journal,
+            recovery: RwLock::new(None),
+        }
+    }
+
+    pub fn journal(&self) -> &UpgradeJournal {
+        &self.journal
+    }
+
+    pub fn recovery(&self) -> Option<RecoveryReport> {
+        self.recovery
+            .read()
+            .expect("recovery lock poisoned")
+            .clone()
+    }
+
+    pub fn set_recovery(&self, report: RecoveryReport) {
+        if report.action == RecoveryAction::Resumed {
+            self.enter(UpgradePhase::RebootPending, Some(100));
+        }
+        *self.recovery.write().expect("recovery lock poisoned") = Some(report);
+    }
+
+    pub fn current(&self) -> Option<UpgradeProgress> {
+        *self.progress.borrow()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Option<UpgradeProgress>> {
+        self.progress.subscribe()
+    }
+
+    pub fn enter(&self, phase: UpgradePhase, percent: Option<u8>) {
+        tracing::debug!("firmware upgrade: {:?}", phase);
+        self.progress
+            .send_replace(Some(UpgradeProgress { phase, percent }));
+    }
+
+    /// Enters `phase` and derives its percentage from the number of bytes
+    /// processed, as published by `processed`. Tracking stops as soon as
+    /// another phase is entered.
+    pub fn track(&self, phase: UpgradePhase, total: u64, mut processed: watch::Receiver<u64>) {
+        self.enter(phase, Some(0));
+        let sender = self.progress.clone();
+        tokio::spawn(async move {
+            while processed.changed().await.is_ok() {
+                let percent = percentage(*processed.borrow_and_update(), total);
+                let mut in_phase = true;
+                sender.send_if_modified(|progress| match progress {
+                    Some(p) if p.phase == phase => {
+                        let changed = p.percent != Some(percent);
+                        p.percent = Some(percent);
+                        changed
+                    }
+                    _ => {
+                        in_phase = false;
+                        false
+                    }
+                });
+                if !in_phase {
+                    break;
+                }
+            }
+        });
+    }
+
+    pub fn clear(&self) {
+        self.progress.send_replace(None);
+    }
+}
+
+fn percentage(processed: u64, total: u64) -> u8 {
+    if total == 0 {
+        return 100;
+    }
+    (processed.min(total) * 100 / total) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn track_bytes_until_next_phase() {
+        let status = UpgradeStatus::default();
+        let mut updates = status.subscribe();
+        let (bytes, processed) = watch::channel(0u64);
+
+        status.track(UpgradePhase::Downloading, 200, processed);
+        bytes.send_replace(50);
+        updates
+            .wait_for(|p| p.is_some_and(|p| p.percent == Some(25)))
+            .await
+            .unwrap();
+
+        status.enter(UpgradePhase::Verifying, None);
+        bytes.send_replace(200);
+        tokio::task::yield_now().await;
+        assert_eq!(
+            status.current(),
+            Some(UpgradeProgress {
+                phase: UpgradePhase::Verifying,
+                percent: None
+            })
+        );
+    }
+
+    #[test]
+    fn percentage_is_clamped() {
+        assert_eq!(percentage(0, 0), 100);
+        assert_eq!(percentage(300, 200), 100);
+        assert_eq!(percentage(1, 3), 33);
+    }
+}
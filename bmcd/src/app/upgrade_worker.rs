@@ -12,32 +12,42 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 use crate::app::bmc_application::BmcApplication;
+use crate::app::firmware_signature::FirmwareVerifier;
+use crate::app::firmware_slots::FirmwareSlots;
+use crate::app::flash_history::{self, FlashRecord};
+use crate::app::upgrade_journal::JournalStep;
+use crate::app::upgrade_progress::{UpgradePhase, UpgradeStatus};
 use crate::hal::{NodeId, UsbRoute};
 use crate::streaming_data_service::data_transfer::DataTransfer;
-use crate::utils::WriteMonitor;
+use crate::utils::{
+    checksum_block_device, get_timestamp_unix, write_block_device, TransferDigest, WriteMonitor,
+};
 use anyhow::bail;
 use crc::{Crc, CRC_64_REDIS};
 use humansize::{format_size, DECIMAL};
 use std::io::{Error, ErrorKind};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::fs::OpenOptions;
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncSeekExt;
-use tokio::io::BufStream;
-use tokio::io::{sink, AsyncRead};
+use tokio::io::AsyncRead;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::sync::watch;
 use tokio::task::spawn_blocking;
-use tokio::{
-    fs,
-    io::{self, AsyncWrite, AsyncWriteExt},
-};
 use tokio_util::sync::CancellationToken;
 
 const TMP_UPGRADE_DIR: &str = "/tmp/os_upgrade";
-const BLOCK_WRITE_SIZE: usize = BLOCK_READ_SIZE; // 512Kib
-const BLOCK_READ_SIZE: usize = 524288; // 512Kib
+const DRY_RUN_FILE: &str = "/tmp/bmcd-dry-run.img";
+
+/// Where a dry run of a node flash writes the image to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DryRunTarget {
+    /// `/dev/null`, the written image cannot be verified
+    Null,
+    /// a scratch file that is verified and removed afterwards
+    File,
+}
 
 // Contains collection of functions that execute some business flow in relation
 // to file transfers in the BMC. See `flash_node` and `os_update`.
@@ -46,6 +56,9 @@ pub struct UpgradeWorker {
     data_transfer: DataTransfer,
     cancel: CancellationToken,
     written_sender: watch::Sender<u64>,
+    /// write speed of the node's storage in bytes per second
+    throughput_sender: watch::Sender<u64>,
+    digest: TransferDigest,
 }
 
 impl UpgradeWorker {
@@ -54,12 +67,15 @@ impl UpgradeWorker {
         data_transfer: DataTransfer,
         cancel: CancellationToken,
         written_sender: watch::Sender<u64>,
+        throughput_sender: watch::Sender<u64>,
     ) -> Self {
         Self {
             do_crc_validation,
             data_transfer,
             cancel,
             written_sender,
+            throughput_sender,
+            digest: TransferDigest::default(),
         }
     }
 
@@ -75,29 +91,29 @@ impl UpgradeWorker {
     ) -> anyhow::Result<()> {
         let device = bmc.node_in_flash(node, UsbRoute::Bmc).await?;
 
-        let result = async move {
-            let reader = self.data_transfer.reader().await?;
-            let mut buf_stream =
-                BufStream::with_capacity(BLOCK_READ_SIZE, BLOCK_WRITE_SIZE, device);
-            let (bytes_written, written_crc) =
-                self.try_write_node(node, reader, &mut buf_stream).await?;
-
-            if self.do_crc_validation {
-                buf_stream.seek(std::io::SeekFrom::Start(0)).await?;
-                flush_file_caches().await?;
-                self.try_validate_crc(node, written_crc, buf_stream.take(bytes_written))
-                    .await?;
-            } else {
-                tracing::info!("user skipped crc check");
-            }
-
-            Ok::<(), anyhow::Error>(())
-        }
-        .await;
+        let started = get_timestamp_unix().unwrap_or_default();
+        let start = Instant::now();
+        let verify = self.do_crc_validation;
+        let result = self.write_image(node, &device, verify).await;
 
         if let Ok(()) = result {
             tracing::info!("Flashing {node} successful, restoring USB & power settings.");
         }
+        let record = FlashRecord {
+            node: node as u8 + 1,
+            file_name: self
+                .data_transfer
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            size: self.data_transfer.size().unwrap_or_default(),
+            started,
+            duration_ms: start.elapsed().as_millis() as u64,
+            sha256: self.digest.sha256(),
+            expected: self.data_transfer.checksum().map(ToString::to_string),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        };
+        flash_history::record(&bmc, record).await;
 
         // disregarding the result, set the BMC in the finalized state.
         bmc.activate_slot(node.to_inverse_bitfield(), node.to_bitfield())
@@ -108,56 +124,97 @@ impl UpgradeWorker {
         result
     }
 
-    async fn try_write_node(
+    /// Runs the pipeline of [`Self::flash_node`], from the transfer through
+    /// decompression to the checksum, without touching `node`. The image is
+    /// written to `target` instead of the storage of the node.
+    pub async fn flash_dry_run(mut self, node: NodeId, target: DryRunTarget) -> anyhow::Result<()> {
+        tracing::info!("dry run of flashing {node} to {:?}", target);
+        match target {
+            // there is nothing to read back from /dev/null
+            DryRunTarget::Null => self.write_image(node, Path::new("/dev/null"), false).await,
+            DryRunTarget::File => {
+                let path = Path::new(DRY_RUN_FILE);
+                let size = self.data_transfer.size()?;
+                let stat = nix::sys::statvfs::statvfs(path.parent().unwrap_or(Path::new("/")))?;
+                let available = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+                if size > available {
+                    bail!(
+                        "{} image does not fit in the {} available for a dry run",
+                        format_size(size, DECIMAL),
+                        format_size(available, DECIMAL)
+                    );
+                }
+                tokio::fs::File::create(path).await?;
+                let verify = self.do_crc_validation;
+                let result = self.write_image(node, path, verify).await;
+                let _ = tokio::fs::remove_file(path).await;
+                result
+            }
+        }
+    }
+
+    async fn write_image(
         &mut self,
         node: NodeId,
-        source_reader: impl AsyncRead + 'static + Unpin,
-        mut node_writer: &mut (impl AsyncWrite + 'static + Unpin),
-    ) -> anyhow::Result<(u64, u64)> {
+        device: &Path,
+        verify: bool,
+    ) -> anyhow::Result<()> {
+        let (reader, digest) = self.data_transfer.reader().await?;
+        self.digest = digest;
         tracing::info!("started writing to {node}");
-
-        let crc = Crc::<u64>::new(&CRC_64_REDIS);
-        let mut write_watcher = WriteMonitor::new(&mut node_writer, &mut self.written_sender, &crc);
-
-        let bytes_written = copy_or_cancel(source_reader, &mut write_watcher, &self.cancel).await?;
-        let crc = write_watcher.crc();
-
+        let start = Instant::now();
+        let written = write_block_device(
+            reader,
+            device,
+            &self.written_sender,
+            &self.throughput_sender,
+            &self.cancel,
+        )
+        .await?;
         tracing::info!(
-            "Wrote {}, crc: {}",
-            format_size(bytes_written, DECIMAL),
-            crc
+            "Wrote {} in {} ({}/s), crc: {}",
+            format_size(written.bytes, DECIMAL),
+            humantime::format_duration(round_to_millis(start.elapsed())),
+            format_size(throughput(written.bytes, start.elapsed()), DECIMAL),
+            written.crc
         );
 
-        Ok((bytes_written, crc))
+        if verify {
+            tracing::info!("Verifying checksum of data on node {node}");
+            let crc =
+                checksum_block_device(device, written.bytes, &self.written_sender, &self.cancel)
+                    .await?;
+            if written.crc != crc {
+                bail!("crc error. expected {}, calculated {}", written.crc, crc);
+            }
+        } else {
+            tracing::info!("skipped crc check");
+        }
+        Ok(())
     }
 
-    async fn try_validate_crc(
-        &mut self,
-        node: NodeId,
-        expected_crc: u64,
-        node_reader: impl AsyncRead + 'static + Unpin,
+    pub async fn os_update(
+        self,
+        verifier: Arc<FirmwareVerifier>,
+        slots: Option<Arc<FirmwareSlots>>,
+        progress: Arc<UpgradeStatus>,
     ) -> anyhow::Result<()> {
-        tracing::info!("Verifying checksum of data on node {node}");
-
-        let crc = Crc::<u64>::new(&CRC_64_REDIS);
-        let mut sink = WriteMonitor::new(sink(), &mut self.written_sender, &crc);
-        copy_or_cancel(node_reader, &mut sink, &self.cancel).await?;
-        let dev_checksum = sink.crc();
-
-        if expected_crc != dev_checksum {
-            bail!(
-                "crc error. expected {}, calculated {}",
-                expected_crc.to_string(),
-                dev_checksum.to_string()
-            );
+        let result = self.install_firmware(verifier, slots, &progress).await;
+        if result.is_err() {
+            progress.clear();
         }
-
-        Ok(())
+        result
     }
 
-    pub async fn os_update(mut self) -> anyhow::Result<()> {
+    async fn install_firmware(
+        mut self,
+        verifier: Arc<FirmwareVerifier>,
+        slots: Option<Arc<FirmwareSlots>>,
+        progress: &UpgradeStatus,
+    ) -> anyhow::Result<()> {
         let file_name = self.data_transfer.file_name()?.to_owned();
-        let source = self.data_transfer.reader().await?;
+        let size = self.data_transfer.size()?;
+        let (source, _) = self.data_transfer.reader().await?;
         tracing::info!("start firmware upgrade {}", file_name.to_string_lossy());
 
         let mut os_update_img = PathBuf::from(TMP_UPGRADE_DIR);
@@ -172,10 +229,62 @@ impl UpgradeWorker {
             .open(&os_update_img)
             .await?;
 
+        progress.track(
+            UpgradePhase::Downloading,
+            size,
+            self.written_sender.subscribe(),
+        );
         let crc = Crc::<u64>::new(&CRC_64_REDIS);
         let mut writer = WriteMonitor::new(&mut file, &mut self.written_sender, &crc);
         copy_or_cancel(source, &mut writer, &self.cancel).await?;
+        drop(file);
+
+        self.written_sender.send_replace(0);
+        let image_size = tokio::fs::metadata(&os_update_img).await?.len();
+        progress.track(
+            UpgradePhase::Verifying,
+            image_size,
+            self.written_sender.subscribe(),
+        );
+        if let Err(e) = verifier.verify(&os_update_img, &self.written_sender).await {
+            tokio::fs::remove_dir_all(TMP_UPGRADE_DIR).await?;
+            return Err(e.context("firmware rejected"));
+        }
 
+        // every step from here on is journaled, see `UpgradeJournal::recover`
+        let journal = progress.journal();
+        if let Some(slots) = slots {
+            let target = slots.upgrade_target().await?;
+            journal.begin(&os_update_img, Some(target)).await?;
+
+            self.written_sender.send_replace(0);
+            progress.track(
+                UpgradePhase::Writing,
+                tokio::fs::metadata(&os_update_img).await?.len(),
+                self.written_sender.subscribe(),
+            );
+            let result = async {
+                slots
+                    .write(target, &os_update_img, &self.written_sender)
+                    .await?;
+                progress.enter(UpgradePhase::Syncing, None);
+                spawn_blocking(nix::unistd::sync).await?;
+                journal.advance(JournalStep::Activating).await?;
+                slots.activate(target).await
+            }
+            .await;
+            journal.finish().await?;
+            tokio::fs::remove_dir_all(TMP_UPGRADE_DIR).await?;
+            result?;
+
+            tracing::info!("firmware written to slot {:?}, reboot to activate", target);
+            progress.enter(UpgradePhase::RebootPending, Some(100));
+            return Ok(());
+        }
+
+        // `osupdate` does not report its progress
+        progress.enter(UpgradePhase::Writing, None);
+        journal.begin(&os_update_img, None).await?;
         let result = spawn_blocking(move || {
             Command::new("sh")
                 .arg("-c")
@@ -184,6 +293,7 @@ impl UpgradeWorker {
         })
         .await?;
 
+        journal.finish().await?;
         tokio::fs::remove_dir_all(TMP_UPGRADE_DIR).await?;
 
         let success = result?;
@@ -191,6 +301,9 @@ impl UpgradeWorker {
             bail!("failed firmware upgrade ({})", success);
         }
 
+        progress.enter(UpgradePhase::Syncing, None);
+        spawn_blocking(nix::unistd::sync).await?;
+        progress.enter(UpgradePhase::RebootPending, Some(100));
         Ok(())
     }
 }
@@ -220,14 +333,12 @@ where
     Ok(bytes_copied)
 }
 
-async fn flush_file_caches() -> io::Result<()> {
-    let mut file = fs::OpenOptions::new()
-        .write(true)
-        .open("/proc/sys/vm/drop_caches")
-        .await?;
+fn throughput(bytes: u64, elapsed: Duration) -> u64 {
+    (bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64
+}
 
-    // Free reclaimable slab objects and page cache
-    file.write_u8(b'3').await
+fn round_to_millis(duration: Duration) -> Duration {
+    Duration::from_millis(duration.as_millis() as u64)
 }
 
 #[cfg(test)]
@@ -262,4 +373,27 @@ mod test {
         assert_eq!(&buffer, buf_writer.get_ref());
         assert_eq!(*receiver.borrow_and_update(), buffer.len() as u64);
     }
+
+    #[tokio::test]
+    async fn dry_runs() {
+        let dir = tempdir::TempDir::new("dry_run").unwrap();
+        let image = dir.path().join("image.img");
+        let data = random_array::<{ 3 * 1024 * 1024 + 17 }>();
+        std::fs::write(&image, &data).unwrap();
+
+        for target in [DryRunTarget::Null, DryRunTarget::File] {
+            let (written_sender, written) = watch::channel(0u64);
+            let (throughput_sender, _) = watch::channel(0u64);
+            let worker = UpgradeWorker::new(
+                true,
+                DataTransfer::local(image.clone(), None),
+                CancellationToken::new(),
+                written_sender,
+                throughput_sender,
+            );
+            worker.flash_dry_run(NodeId::Node2, target).await.unwrap();
+            assert_eq!(*written.borrow(), data.len() as u64);
+        }
+        assert!(!Path::new(DRY_RUN_FILE).exists());
+    }
 }
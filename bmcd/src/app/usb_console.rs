@@ -0,0 +1,191 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Console on the USB device port of the BMC. Next to the mass storage
+//! function, the gadget gets a CDC-ACM serial port with a login prompt and a
+//! CDC-ECM network interface. The laptop on the other end of the cable is
+//! handed an address over DHCP and reaches the API at the address of the BMC,
+//! see [`crate::config::Config::listeners`].
+use super::dhcp_server::DhcpServer;
+use super::usb_gadget::{add_gadget_function, remove_gadget_function};
+use crate::config::{self, Dhcp, DhcpLease};
+use anyhow::{bail, Context};
+use serde::Serialize;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::Duration;
+use tokio::process::Command;
+
+const SERIAL_FUNCTION: &str = "acm.usb0";
+const NETWORK_FUNCTION: &str = "ecm.usb0";
+const TTY: &str = "ttyGS0";
+const INTERFACE: &str = "usb0";
+/// Fixed MAC addresses, so the laptop always gets the same lease and the
+/// same network profile.
+const DEVICE_MAC: &str = "02:54:50:42:4d:01";
+const HOST_MAC: &str = "02:54:50:42:4d:02";
+const INTERFACE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Delay before the login prompt is started again after it exited.
+const RESPAWN_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UsbConsoleStatus {
+    /// serial device on the host side, e.g. `/dev/ttyACM0` on Linux
+    pub serial: Option<&'static str>,
+    pub interface: Option<&'static str>,
+    pub bmc_address: Option<Ipv4Addr>,
+    pub host_address: Option<Ipv4Addr>,
+}
+
+pub struct UsbConsole {
+    config: config::UsbConsole,
+}
+
+impl UsbConsole {
+    pub fn new(config: config::UsbConsole) -> Self {
+        Self { config }
+    }
+
+    pub fn status(&self) -> UsbConsoleStatus {
+        let network = self.config.network;
+        UsbConsoleStatus {
+            serial: self.config.serial.then_some(TTY),
+            interface: network.then_some(INTERFACE),
+            bmc_address: network.then_some(self.config.address),
+            host_address: network.then(|| host_address(self.config.address)),
+        }
+    }
+
+    /// Adds the functions to the gadget, configures the network on the cable
+    /// and keeps a login prompt running on the serial port.
+    pub async fn start(&self) -> anyhow::Result<()> {
+        if self.config.serial {
+            add_gadget_function(SERIAL_FUNCTION, &[])
+                .await
+                .context("serial console")?;
+            tokio::spawn(run_getty());
+        } else {
+            remove_gadget_function(SERIAL_FUNCTION).await?;
+        }
+
+        if self.config.network {
+            add_gadget_function(
+                NETWORK_FUNCTION,
+                &[
+                    ("dev_addr", DEVICE_MAC.as_bytes()),
+                    ("host_addr", HOST_MAC.as_bytes()),
+                ],
+            )
+            .await
+            .context("network function")?;
+            self.configure_network().await?;
+        } else {
+            remove_gadget_function(NETWORK_FUNCTION).await?;
+        }
+        Ok(())
+    }
+
+    async fn configure_network(&self) -> anyhow::Result<()> {
+        let sysfs = Path::new("/sys/class/net").join(INTERFACE);
+        let appeared = tokio::time::timeout(INTERFACE_TIMEOUT, async {
+            while !sysfs.exists() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await;
+        if appeared.is_err() {
+            bail!("{} did not appear", INTERFACE);
+        }
+
+        let address = format!("{}/30", self.config.address);
+        ip(&["addr", "replace", &address, "dev", INTERFACE]).await?;
+        ip(&["link", "set", INTERFACE, "up"]).await?;
+
+        let dhcp = Dhcp {
+            interface: INTERFACE.to_string(),
+            server_address: self.config.address,
+            netmask: Ipv4Addr::new(255, 255, 255, 252),
+            router: None,
+            dns: Vec::new(),
+            lease_time: Duration::from_secs(24 * 60 * 60),
+            leases: vec![DhcpLease {
+                node: 0,
+                mac: HOST_MAC.to_string(),
+                address: host_address(self.config.address),
+            }],
+        };
+        DhcpServer::new(dhcp)
+            .run()
+            .context("DHCP server on the USB network")?;
+        tracing::info!(
+            "USB console network up, the API is at https://{}",
+            self.config.address
+        );
+        Ok(())
+    }
+}
+
+fn host_address(bmc: Ipv4Addr) -> Ipv4Addr {
+    Ipv4Addr::from(u32::from(bmc) + 1)
+}
+
+async fn ip(args: &[&str]) -> anyhow::Result<()> {
+    let output = Command::new("ip").args(args).output().await?;
+    if !output.status.success() {
+        bail!(
+            "ip {}: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Keeps a login prompt on the serial port. `getty` exits when the user logs
+/// out or the cable is unplugged.
+async fn run_getty() {
+    loop {
+        let status = Command::new("getty")
+            .args(["-L", "115200", TTY, "vt100"])
+            .kill_on_drop(true)
+            .status()
+            .await;
+        match status {
+            Ok(status) => tracing::debug!("getty on {} exited: {}", TTY, status),
+            Err(e) => {
+                tracing::error!("login prompt on {} not started: {}", TTY, e);
+                return;
+            }
+        }
+        tokio::time::sleep(RESPAWN_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status() {
+        let console = UsbConsole::new(config::UsbConsole::default());
+        let status = console.status();
+        assert_eq!(status.serial, Some(TTY));
+        assert_eq!(status.host_address, Some(Ipv4Addr::new(172, 31, 255, 2)));
+
+        let console = UsbConsole::new(config::UsbConsole {
+            network: false,
+            ..Default::default()
+        });
+        assert_eq!(console.status().bmc_address, None);
+    }
+}
@@ -25,11 +25,26 @@ use tokio::{
 
 use crate::utils::logging_sink_stdio;
 
-const BMC_USB_OTG: &str = "/sys/kernel/config/usb_gadget/g1";
+pub(crate) const BMC_USB_OTG: &str = "/sys/kernel/config/usb_gadget/g1";
 
 pub async fn append_msd_config_to_usb_gadget(block_device: &Path) -> anyhow::Result<()> {
+    let block_device = block_device.to_str().ok_or(anyhow!(
+        "{} not convertable to string",
+        block_device.to_string_lossy()
+    ))?;
+    add_gadget_function("mass_storage.0", &[("lun.0/file", block_device.as_bytes())]).await
+}
+
+pub async fn remove_msd_function_from_usb_gadget() -> anyhow::Result<()> {
+    remove_gadget_function("mass_storage.0").await
+}
+
+/// Adds the function `name`, e.g. `acm.usb0`, with the given attributes to
+/// the gadget. The gadget is restarted, which briefly disconnects the other
+/// functions from the host.
+pub async fn add_gadget_function(name: &str, attributes: &[(&str, &[u8])]) -> anyhow::Result<()> {
     if is_gadget_running().await? {
-        remove_msd_function_from_usb_gadget().await?;
+        remove_gadget_function(name).await?;
         usb_gadget_service(GadgetCmd::Stop)
             .await
             .context("usb_gadget")?;
@@ -42,36 +57,29 @@ pub async fn append_msd_config_to_usb_gadget(block_device: &Path) -> anyhow::Res
 
     let config = usb_gadget.join("configs/c.1");
 
-    let mass_storage_function = usb_gadget.join("functions/mass_storage.0");
-    tokio::fs::create_dir_all(&mass_storage_function)
+    let function = usb_gadget.join("functions").join(name);
+    tokio::fs::create_dir_all(&function)
         .await
-        .with_context(|| mass_storage_function.to_string_lossy().to_string())?;
-
-    let lun0 = mass_storage_function.join("lun.0/file");
-    let mut file = OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .create(true)
-        .open(lun0)
-        .await?;
-
-    file.write_all(
-        block_device
-            .to_str()
-            .ok_or(anyhow!(
-                "{} not convertable to string",
-                block_device.to_str().unwrap_or_default()
-            ))?
-            .as_bytes(),
-    )
-    .await?;
-
-    symlink(&mass_storage_function, &config.join("mass_storage.0"))
+        .with_context(|| function.to_string_lossy().to_string())?;
+
+    for (attribute, value) in attributes {
+        let path = function.join(attribute);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .create(true)
+            .open(&path)
+            .await
+            .with_context(|| path.to_string_lossy().to_string())?;
+        file.write_all(value).await?;
+    }
+
+    symlink(&function, &config.join(name))
         .await
         .with_context(|| {
             format!(
                 "symlink {} to {}",
-                mass_storage_function.to_string_lossy(),
+                function.to_string_lossy(),
                 config.to_string_lossy()
             )
         })?;
@@ -83,13 +91,13 @@ pub async fn append_msd_config_to_usb_gadget(block_device: &Path) -> anyhow::Res
     Ok(())
 }
 
-pub async fn remove_msd_function_from_usb_gadget() -> anyhow::Result<()> {
-    let mass_storage_config = Path::new(BMC_USB_OTG).join("configs/c.1/mass_storage.0");
-    if mass_storage_config.exists() {
+pub async fn remove_gadget_function(name: &str) -> anyhow::Result<()> {
+    let function_config = Path::new(BMC_USB_OTG).join("configs/c.1").join(name);
+    if function_config.exists() {
         usb_gadget_service(GadgetCmd::Stop).await?;
-        tokio::fs::remove_file(&mass_storage_config)
+        tokio::fs::remove_file(&function_config)
             .await
-            .with_context(|| mass_storage_config.to_string_lossy().to_string())?;
+            .with_context(|| function_config.to_string_lossy().to_string())?;
         usb_gadget_service(GadgetCmd::Start).await?;
     }
     Ok(())
@@ -0,0 +1,180 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Linux accounts of the BMC, which are the accounts that log in to the API.
+//! Accounts are managed with the BusyBox `adduser`, `deluser` and `chpasswd`
+//! applets; the authenticator picks up changes to `/etc/shadow` by itself.
+//! Only root and regular accounts (uid 1000 and up) are listed, system
+//! accounts are left alone.
+use anyhow::{bail, ensure, Context};
+use serde::Serialize;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+const PASSWD_FILE: &str = "/etc/passwd";
+const SHADOW_FILE: &str = "/etc/shadow";
+const FIRST_REGULAR_UID: u32 = 1000;
+const NOBODY_UID: u32 = 65534;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Account {
+    pub name: String,
+    pub uid: u32,
+    /// the account can log in with a password
+    pub password_set: bool,
+}
+
+/// Accounts with the password hash of each, which goes into the ETag of an
+/// account so that a password change is noticed.
+pub async fn accounts() -> anyhow::Result<Vec<(Account, String)>> {
+    let passwd = tokio::fs::read_to_string(PASSWD_FILE)
+        .await
+        .context(PASSWD_FILE)?;
+    let shadow = tokio::fs::read_to_string(SHADOW_FILE)
+        .await
+        .context(SHADOW_FILE)?;
+    Ok(parse_accounts(&passwd, &shadow))
+}
+
+pub async fn account(name: &str) -> anyhow::Result<Option<(Account, String)>> {
+    Ok(accounts()
+        .await?
+        .into_iter()
+        .find(|(account, _)| account.name == name))
+}
+
+/// Creates the account when it does not exist and sets its password.
+pub async fn set_password(name: &str, password: &str, create: bool) -> anyhow::Result<()> {
+    validate_name(name)?;
+    ensure!(!password.is_empty(), "password is empty");
+    ensure!(
+        !password.contains(['\n', '\r']),
+        "password contains a line break"
+    );
+    if create {
+        run("adduser", &["-D", "-H", "-s", "/bin/sh", name], None).await?;
+    }
+    run("chpasswd", &[], Some(format!("{}:{}\n", name, password))).await
+}
+
+pub async fn delete(name: &str) -> anyhow::Result<()> {
+    ensure!(name != "root", "the root account cannot be deleted");
+    run("deluser", &[name], None).await
+}
+
+pub fn validate_name(name: &str) -> anyhow::Result<()> {
+    let mut chars = name.chars();
+    let valid = name.len() <= 32
+        && chars
+            .next()
+            .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+    ensure!(valid, "`{}` is not a valid account name", name);
+    Ok(())
+}
+
+async fn run(program: &str, args: &[&str], stdin: Option<String>) -> anyhow::Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(program.to_string())?;
+    let mut input = child.stdin.take().expect("stdin is piped");
+    if let Some(stdin) = stdin {
+        input.write_all(stdin.as_bytes()).await?;
+    }
+    drop(input);
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        bail!(
+            "{}: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+fn parse_accounts(passwd: &str, shadow: &str) -> Vec<(Account, String)> {
+    passwd
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let uid = fields.nth(1)?.parse::<u32>().ok()?;
+            let regular = uid == 0 || (FIRST_REGULAR_UID..NOBODY_UID).contains(&uid);
+            regular.then(|| (name.to_string(), uid))
+        })
+        .map(|(name, uid)| {
+            let hash = shadow
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(user, _)| *user == name)
+                .and_then(|(_, rest)| rest.split(':').next())
+                .unwrap_or_default()
+                .to_string();
+            let password_set = !hash.is_empty() && !hash.starts_with(['*', '!']);
+            let account = Account {
+                name,
+                uid,
+                password_set,
+            };
+            (account, hash)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regular_accounts() {
+        let passwd = "root:x:0:0:root:/root:/bin/sh\n\
+                      daemon:x:1:1:daemon:/usr/sbin:/bin/false\n\
+                      nobody:x:65534:65534:nobody:/home:/bin/false\n\
+                      alice:x:1000:1000:Linux User,,,:/home/alice:/bin/sh\n\
+                      bob:x:1001:1001:Linux User,,,:/home/bob:/bin/sh\n";
+        let shadow = "root:$6$salt$hash:19000:0:99999:7:::\n\
+                      daemon:*:10933:0:99999:7:::\n\
+                      alice:$5$salt$hash:19000:0:99999:7:::\n\
+                      bob:!:19000:0:99999:7:::\n";
+        let accounts = parse_accounts(passwd, shadow);
+        let summary: Vec<_> = accounts
+            .iter()
+            .map(|(a, _)| (a.name.as_str(), a.uid, a.password_set))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("root", 0, true),
+                ("alice", 1000, true),
+                ("bob", 1001, false)
+            ]
+        );
+        assert_eq!(accounts[1].1, "$5$salt$hash");
+    }
+
+    #[test]
+    fn account_names() {
+        assert!(validate_name("alice").is_ok());
+        assert!(validate_name("_ci-runner2").is_ok());
+        assert!(validate_name("Alice").is_err());
+        assert!(validate_name("1alice").is_err());
+        assert!(validate_name("al:ice").is_err());
+        assert!(validate_name("").is_err());
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Scheduled power-on through the wake alarm of the RTC. The alarm powers the
+//! board back on, after which bmcd powers on the nodes that were scheduled
+//! with the alarm. Together with a power-down of the BMC this allows
+//! schedules where the whole board is off, e.g. overnight. Recurring schedules
+//! share the RTC alarm, see `app::wake_schedule`.
+use super::bmc_application::BmcApplication;
+use super::wake_schedule::arm_rtc;
+use crate::hal::rtc::Rtc;
+use anyhow::{ensure, Context};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+pub const WAKE_ALARM_KEY: &str = "wake_alarm";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WakeAlarm {
+    pub time: DateTime<Utc>,
+    /// nodes, numbered from 1, that are powered on when the alarm fires
+    #[serde(default)]
+    pub nodes: Vec<u8>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WakeAlarmStatus {
+    #[serde(flatten)]
+    pub alarm: Option<WakeAlarm>,
+    /// whether the alarm is programmed in the RTC
+    pub armed: bool,
+}
+
+impl WakeAlarm {
+    fn node_mask(&self) -> u8 {
+        self.nodes
+            .iter()
+            .fold(0u8, |mask, node| mask | 1 << (node - 1))
+    }
+}
+
+pub async fn get_wake_alarm(bmc: &BmcApplication, rtc: &Rtc) -> anyhow::Result<WakeAlarmStatus> {
+    Ok(WakeAlarmStatus {
+        alarm: bmc.app_db.get::<Option<WakeAlarm>>(WAKE_ALARM_KEY).await,
+        armed: rtc.wake_alarm()?.is_some(),
+    })
+}
+
+pub async fn set_wake_alarm(
+    bmc: &BmcApplication,
+    rtc: &Rtc,
+    alarm: WakeAlarm,
+) -> anyhow::Result<()> {
+    for node in &alarm.nodes {
+        ensure!(
+            (1..=bmc.board().node_count).contains(&usize::from(*node)),
+            "node {} is out of range 1..{}",
+            node,
+            bmc.board().node_count
+        );
+    }
+    // the alarm fires when the RTC reaches it, compare to the RTC and not
+    // to the system clock
+    let now = rtc.time()?;
+    ensure!(
+        alarm.time > now,
+        "alarm time lies in the past of the RTC ({})",
+        now
+    );
+
+    tracing::info!("wake alarm set for {}, nodes {:?}", alarm.time, alarm.nodes);
+    bmc.app_db.set(WAKE_ALARM_KEY, Some(alarm)).await;
+    // a wake schedule that comes first keeps the RTC alarm
+    arm_rtc(bmc, rtc).await
+}
+
+/// Clears the alarm. The RTC stays armed for the wake schedules, if any.
+pub async fn clear_wake_alarm(bmc: &BmcApplication, rtc: &Rtc) -> anyhow::Result<()> {
+    bmc.app_db
+        .set::<Option<WakeAlarm>>(WAKE_ALARM_KEY, None)
+        .await;
+    tracing::info!("wake alarm cleared");
+    arm_rtc(bmc, rtc).await
+}
+
+/// Powers off the nodes and halts the BMC. The board stays off until the
+/// wake alarm fires.
+pub async fn power_down(bmc: &BmcApplication, rtc: &Rtc) -> anyhow::Result<()> {
+    ensure!(
+        rtc.wake_alarm()?.is_some(),
+        "refusing to power down without an armed wake alarm"
+    );
+    bmc.activate_slot(0, bmc.board().node_mask()).await?;
+    tracing::warn!("powering down until the wake alarm fires");
+    Command::new("shutdown")
+        .args(["-h", "now"])
+        .spawn()
+        .context("shutdown")?;
+    Ok(())
+}
+
+/// Called on start-up. When the stored alarm has passed, the board was woken
+/// by it and the scheduled nodes are powered on.
+pub async fn handle_wake_alarm(bmc: &BmcApplication) -> anyhow::Result<()> {
+    let Some(alarm) = bmc.app_db.get::<Option<WakeAlarm>>(WAKE_ALARM_KEY).await else {
+        return Ok(());
+    };
+    if alarm.time > Utc::now() {
+        return Ok(());
+    }
+
+    bmc.app_db
+        .set::<Option<WakeAlarm>>(WAKE_ALARM_KEY, None)
+        .await;
+    let mask = alarm.node_mask();
+    tracing::info!("woken by the alarm of {}", alarm.time);
+    if mask != 0 {
+        bmc.activate_slot(mask, mask).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_mask() {
+        let alarm = WakeAlarm {
+            time: Utc::now(),
+            nodes: vec![1, 3],
+        };
+        assert_eq!(alarm.node_mask(), 0b0101);
+    }
+
+    #[test]
+    fn persisted_alarm() {
+        let alarm = Some(WakeAlarm {
+            time: "2024-06-01T06:30:00Z".parse().unwrap(),
+            nodes: vec![2],
+        });
+        let bytes = bincode::serialize(&alarm).unwrap();
+        assert_eq!(
+            bincode::deserialize::<Option<WakeAlarm>>(&bytes).unwrap(),
+            alarm
+        );
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Recurring power-on of nodes, e.g. node 1 every day at 07:00. Schedules are
+//! kept in the persistency and checked while bmcd runs. The RTC alarm is kept
+//! armed for the next occurrence, or the wake alarm if that comes first, so a
+//! board that is powered down wakes up for it. An occurrence that was missed,
+//! e.g. during a power outage, still powers on the nodes when bmcd starts
+//! within the `catch_up` of the schedule.
+use super::bmc_application::BmcApplication;
+use super::wake_alarm::{WakeAlarm, WAKE_ALARM_KEY};
+use crate::error::BmcError;
+use crate::hal::rtc::Rtc;
+use anyhow::ensure;
+use chrono::{DateTime, Datelike, Local, NaiveTime, TimeDelta, TimeZone, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Persistency key of the stored schedules.
+pub const WAKE_SCHEDULES_KEY: &str = "wake_schedules";
+pub const MAX_SCHEDULES: usize = 16;
+pub const DEFAULT_CATCH_UP: u64 = 15 * 60;
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+pub type WakeSchedules = BTreeMap<u32, WakeSchedule>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WakeSchedule {
+    /// nodes, numbered from 1, that are powered on
+    pub nodes: Vec<u8>,
+    /// local time of day
+    pub time: NaiveTime,
+    /// days of the week, every day when empty
+    pub days: Vec<Weekday>,
+    /// seconds after an occurrence in which it still powers on the nodes,
+    /// e.g. when bmcd starts after a power outage
+    pub catch_up: u64,
+    /// occurrence that was last handled
+    pub last_run: Option<DateTime<Utc>>,
+}
+
+impl WakeSchedule {
+    fn node_mask(&self) -> u8 {
+        self.nodes
+            .iter()
+            .fold(0u8, |mask, node| mask | 1 << (node - 1))
+    }
+
+    /// Occurrences in the week before and after `now`, oldest first.
+    fn occurrences<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> Vec<DateTime<Tz>> {
+        let today = now.date_naive();
+        (-8..=8)
+            .filter_map(|offset| today.checked_add_signed(TimeDelta::days(offset)))
+            .filter(|date| self.days.is_empty() || self.days.contains(&date.weekday()))
+            // a time that a DST change skips does not occur that day
+            .filter_map(|date| {
+                now.timezone()
+                    .from_local_datetime(&date.and_time(self.time))
+                    .earliest()
+            })
+            .collect()
+    }
+
+    /// Latest occurrence at or before `now`.
+    pub fn previous<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        self.occurrences(now).into_iter().rev().find(|t| t <= now)
+    }
+
+    /// First occurrence after `now`.
+    pub fn next<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        self.occurrences(now).into_iter().find(|t| t > now)
+    }
+}
+
+pub async fn list_schedules(bmc: &BmcApplication) -> WakeSchedules {
+    bmc.app_db.get::<WakeSchedules>(WAKE_SCHEDULES_KEY).await
+}
+
+/// Stores a new schedule and returns its id. Occurrences before now do not
+/// count.
+pub async fn add_schedule(
+    bmc: &BmcApplication,
+    rtc: &Rtc,
+    mut schedule: WakeSchedule,
+) -> anyhow::Result<u32> {
+    ensure!(!schedule.nodes.is_empty(), "no nodes to power on");
+    for node in &schedule.nodes {
+        ensure!(
+            (1..=bmc.board().node_count).contains(&usize::from(*node)),
+            "node {} is out of range 1..{}",
+            node,
+            bmc.board().node_count
+        );
+    }
+    let mut schedules = list_schedules(bmc).await;
+    ensure!(
+        schedules.len() < MAX_SCHEDULES,
+        "maximum of {} schedules reached",
+        MAX_SCHEDULES
+    );
+    schedule.last_run = Some(Utc::now());
+    let id = schedules.keys().next_back().map_or(1, |id| id + 1);
+    tracing::info!(
+        "wake schedule {} added: nodes {:?} at {} on {:?}",
+        id,
+        schedule.nodes,
+        schedule.time,
+        schedule.days
+    );
+    schedules.insert(id, schedule);
+    bmc.app_db.set(WAKE_SCHEDULES_KEY, schedules).await;
+    arm_rtc(bmc, rtc).await?;
+    Ok(id)
+}
+
+pub async fn remove_schedule(bmc: &BmcApplication, rtc: &Rtc, id: u32) -> anyhow::Result<()> {
+    let mut schedules = list_schedules(bmc).await;
+    if schedules.remove(&id).is_none() {
+        return Err(BmcError::NotFound(format!("wake schedule {}", id).into()).into());
+    }
+    bmc.app_db.set(WAKE_SCHEDULES_KEY, schedules).await;
+    tracing::info!("wake schedule {} removed", id);
+    arm_rtc(bmc, rtc).await?;
+    Ok(())
+}
+
+/// Programs the RTC alarm for the earliest of the wake alarm and the next
+/// occurrence of the schedules, or disarms it when there is neither.
+pub async fn arm_rtc(bmc: &BmcApplication, rtc: &Rtc) -> anyhow::Result<()> {
+    if !rtc.is_available() {
+        return Ok(());
+    }
+    let alarm = bmc
+        .app_db
+        .get::<Option<WakeAlarm>>(WAKE_ALARM_KEY)
+        .await
+        .map(|alarm| alarm.time)
+        .filter(|time| *time > Utc::now());
+    let now = Local::now();
+    let next = list_schedules(bmc)
+        .await
+        .values()
+        .filter_map(|schedule| schedule.next(&now))
+        .map(|time| time.with_timezone(&Utc))
+        .chain(alarm)
+        .min();
+    if rtc.wake_alarm()? != next {
+        rtc.set_wake_alarm(next)?;
+        tracing::debug!("RTC alarm set to {:?}", next);
+    }
+    Ok(())
+}
+
+/// Powers on the nodes of the schedules whose last occurrence was not handled
+/// yet and lies within their `catch_up`.
+async fn run_due(bmc: &BmcApplication, now: DateTime<Local>) -> anyhow::Result<()> {
+    let mut schedules = list_schedules(bmc).await;
+    let mut mask = 0u8;
+    let mut changed = false;
+    for (id, schedule) in &mut schedules {
+        let Some(previous) = schedule.previous(&now).map(|t| t.with_timezone(&Utc)) else {
+            continue;
+        };
+        if schedule.last_run.is_some_and(|last| last >= previous) {
+            continue;
+        }
+        schedule.last_run = Some(previous);
+        changed = true;
+        let late = (now.with_timezone(&Utc) - previous).num_seconds();
+        if late > schedule.catch_up as i64 {
+            tracing::warn!("wake schedule {} of {} missed by {}s", id, previous, late);
+            continue;
+        }
+        tracing::info!("wake schedule {} powers on nodes {:?}", id, schedule.nodes);
+        mask |= schedule.node_mask();
+    }
+    if changed {
+        bmc.app_db.set(WAKE_SCHEDULES_KEY, schedules).await;
+    }
+    if mask != 0 {
+        bmc.activate_slot(mask, mask).await?;
+    }
+    Ok(())
+}
+
+/// Handles the schedules that became due while bmcd was not running, then
+/// checks them every [`CHECK_INTERVAL`]. Call after the power state was
+/// restored.
+pub fn run_wake_schedules(bmc: Arc<BmcApplication>, rtc: Arc<Rtc>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = run_due(&bmc, Local::now()).await {
+                tracing::error!("wake schedules: {:#}", e);
+            }
+            if let Err(e) = arm_rtc(&bmc, &rtc).await {
+                tracing::warn!("arming the RTC alarm: {:#}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(time: &str, days: Vec<Weekday>) -> WakeSchedule {
+        WakeSchedule {
+            nodes: vec![1, 3],
+            time: NaiveTime::parse_from_str(time, "%H:%M").unwrap(),
+            days,
+            catch_up: DEFAULT_CATCH_UP,
+            last_run: None,
+        }
+    }
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    #[test]
+    fn daily_occurrences() {
+        let daily = schedule("07:00", Vec::new());
+        assert_eq!(daily.node_mask(), 0b0101);
+        // Saturday morning
+        let now = utc("2024-06-01T06:00:00Z");
+        assert_eq!(daily.previous(&now), Some(utc("2024-05-31T07:00:00Z")));
+        assert_eq!(daily.next(&now), Some(utc("2024-06-01T07:00:00Z")));
+        let now = utc("2024-06-01T07:00:00Z");
+        assert_eq!(daily.previous(&now), Some(now));
+        assert_eq!(daily.next(&now), Some(utc("2024-06-02T07:00:00Z")));
+    }
+
+    #[test]
+    fn weekday_occurrences() {
+        let weekdays = schedule(
+            "07:30",
+            vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+            ],
+        );
+        let saturday = utc("2024-06-01T12:00:00Z");
+        assert_eq!(
+            weekdays.previous(&saturday),
+            Some(utc("2024-05-31T07:30:00Z"))
+        );
+        assert_eq!(weekdays.next(&saturday), Some(utc("2024-06-03T07:30:00Z")));
+    }
+
+    #[test]
+    fn persisted_schedules() {
+        let mut schedules = WakeSchedules::new();
+        schedules.insert(1, schedule("07:00", vec![Weekday::Sun]));
+        let bytes = bincode::serialize(&schedules).unwrap();
+        assert_eq!(
+            bincode::deserialize::<WakeSchedules>(&bytes).unwrap(),
+            schedules
+        );
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Feeds the hardware watchdog of the SoC as long as the subsystems that are
+//! required by the configuration are healthy. A hung daemon stops feeding,
+//! after which the watchdog resets the board. The watchdog of a systemd unit
+//! is fed with the same checks; it restarts only the daemon.
+use super::bmc_application::BmcApplication;
+use super::systemd;
+use crate::config::{Config, Watchdog, WatchdogCheck};
+use crate::streaming_data_service::StreamingDataService;
+use anyhow::{bail, ensure, Context};
+use std::ffi::c_int;
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+
+nix::ioctl_readwrite!(wdioc_settimeout, b'W', 6, c_int);
+
+/// Shortest time between two feeds, whatever the timeout.
+const MIN_FEED_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Subsystems the health checks run against.
+#[derive(Clone)]
+pub struct HealthChecks {
+    /// the API is probed on the listeners of the current configuration
+    pub config: watch::Receiver<Arc<Config>>,
+    pub streaming: Arc<StreamingDataService>,
+    pub bmc: Arc<BmcApplication>,
+}
+
+impl HealthChecks {
+    async fn check(&self, check: WatchdogCheck) -> anyhow::Result<()> {
+        match check {
+            WatchdogCheck::Api => {
+                let api = self.config.borrow().api_address()?;
+                TcpStream::connect(api).await?;
+            }
+            WatchdogCheck::FlashService => {
+                // a dead-locked flash service never releases its status
+                let _ = self.streaming.status().await;
+            }
+            WatchdogCheck::Hal => self.bmc.check_hal()?,
+        }
+        Ok(())
+    }
+}
+
+/// Opens the watchdog device and spawns the task that feeds it. Once opened,
+/// the watchdog cannot be stopped anymore.
+pub fn run_watchdog(config: Watchdog, checks: HealthChecks) -> anyhow::Result<()> {
+    let device = File::options()
+        .write(true)
+        .open(&config.device)
+        .with_context(|| config.device.display().to_string())?;
+
+    let mut timeout = c_int::try_from(config.timeout.as_secs())?;
+    // SAFETY: the ioctl only reads and writes `timeout`
+    unsafe { wdioc_settimeout(device.as_raw_fd(), &mut timeout) }
+        .context("setting watchdog timeout")?;
+    // the driver rounds the timeout to what the hardware supports
+    ensure!(timeout > 0, "watchdog driver set a timeout of {}s", timeout);
+    tracing::info!("watchdog armed, timeout {}s", timeout);
+
+    // feed several times per period, a single slow round must not reset
+    // the board
+    let interval = Duration::from_secs(timeout as u64) / 4;
+    feed_while_healthy("watchdog", interval, checks, config.require, move || {
+        (&device).write_all(b"\0")
+    });
+    Ok(())
+}
+
+/// Sends `WATCHDOG=1` to systemd while the `required` subsystems are
+/// healthy. `timeout` is the `WatchdogSec=` of the unit.
+pub fn run_systemd_watchdog(timeout: Duration, required: Vec<WatchdogCheck>, checks: HealthChecks) {
+    tracing::info!("systemd watchdog enabled, timeout {:?}", timeout);
+    feed_while_healthy("systemd watchdog", timeout / 4, checks, required, || {
+        systemd::notify("WATCHDOG=1");
+        Ok(())
+    });
+}
+
+fn feed_while_healthy<F>(
+    name: &'static str,
+    interval: Duration,
+    checks: HealthChecks,
+    required: Vec<WatchdogCheck>,
+    feed: F,
+) where
+    F: Fn() -> std::io::Result<()> + Send + Sync + 'static,
+{
+    let interval = interval.max(MIN_FEED_INTERVAL);
+    let feed = Arc::new(feed);
+    tokio::spawn(async move {
+        let mut failing = false;
+        loop {
+            tokio::time::sleep(interval).await;
+            match health(&checks, &required, interval).await {
+                Ok(()) => {
+                    if failing {
+                        tracing::info!("bmcd healthy again, feeding {}", name);
+                        failing = false;
+                    }
+                    // writes to the device may block
+                    let feed = feed.clone();
+                    match tokio::task::spawn_blocking(move || feed()).await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => tracing::error!("feeding {}: {}", name, e),
+                        Err(e) => tracing::error!("feeding {}: {}", name, e),
+                    }
+                }
+                Err(e) if !failing => {
+                    tracing::error!("{:#}, {} is no longer fed", e, name);
+                    failing = true;
+                }
+                Err(_) => {}
+            }
+        }
+    });
+}
+
+/// Runs the required checks, each must complete within `timeout`.
+async fn health(
+    checks: &HealthChecks,
+    required: &[WatchdogCheck],
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    for check in required {
+        match tokio::time::timeout(timeout, checks.check(*check)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return Err(e.context(format!("{:?} unhealthy", check))),
+            Err(_) => bail!("{:?} unresponsive", check),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,219 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Resolution of request paths to the files of the web UI. The UI is shipped
+//! in the firmware under `www`, which can point to another directory to serve
+//! a custom UI.
+//!
+//! Build tools put a content hash in the names of the assets they emit, e.g.
+//! `index-4f3a9c1b.js`. Those never change and are cached for a year, where
+//! `index.html`, which refers to them, is revalidated on every load.
+use crate::config;
+use crate::utils::resolve;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const INDEX: &str = "index.html";
+const IMMUTABLE_MAX_AGE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn token(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn suffix(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Asset {
+    /// file to send, possibly a precompressed variant
+    pub path: PathBuf,
+    /// extension of the requested file, determines the content type
+    pub extension: String,
+    pub encoding: Option<Encoding>,
+    /// value of the `Cache-Control` header
+    pub cache_control: String,
+}
+
+pub struct WebUi {
+    root: PathBuf,
+    config: config::WebUi,
+}
+
+impl WebUi {
+    pub fn new(root: PathBuf, config: config::WebUi) -> Self {
+        Self { root, config }
+    }
+
+    /// The file to answer a request for `path` with, `None` when there is
+    /// none. `accept_encoding` is the `Accept-Encoding` header of the request.
+    pub async fn asset(&self, path: &str, accept_encoding: &str) -> Option<Asset> {
+        let mut file = resolve(&self.root, path)?;
+        if is_dir(&file).await {
+            file.push(INDEX);
+        }
+        if !is_file(&file).await {
+            let route = Path::new(path).extension().is_none();
+            if !(self.config.spa_fallback && route) {
+                return None;
+            }
+            file = self.root.join(INDEX);
+            if !is_file(&file).await {
+                return None;
+            }
+        }
+
+        let name = file.file_name()?.to_string_lossy().to_string();
+        let extension = Path::new(&name)
+            .extension()
+            .map(|e| e.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let cache_control = if name == INDEX {
+            "no-cache".to_string()
+        } else if is_fingerprinted(&name) {
+            format!("public, max-age={}, immutable", IMMUTABLE_MAX_AGE.as_secs())
+        } else {
+            format!("public, max-age={}", self.config.max_age.as_secs())
+        };
+
+        let mut encoding = None;
+        if self.config.precompressed {
+            for candidate in [Encoding::Brotli, Encoding::Gzip] {
+                let compressed =
+                    PathBuf::from(format!("{}.{}", file.display(), candidate.suffix()));
+                if accepts(accept_encoding, candidate.token()) && is_file(&compressed).await {
+                    file = compressed;
+                    encoding = Some(candidate);
+                    break;
+                }
+            }
+        }
+
+        Some(Asset {
+            path: file,
+            extension,
+            encoding,
+            cache_control,
+        })
+    }
+}
+
+async fn is_file(path: &Path) -> bool {
+    tokio::fs::metadata(path).await.is_ok_and(|m| m.is_file())
+}
+
+async fn is_dir(path: &Path) -> bool {
+    tokio::fs::metadata(path).await.is_ok_and(|m| m.is_dir())
+}
+
+/// Whether a part of the file name, separated by `.` or `-`, looks like a
+/// content hash: eight or more characters that include a digit.
+fn is_fingerprinted(name: &str) -> bool {
+    let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+    stem.split(['.', '-', '_']).skip(1).any(|part| {
+        part.len() >= 8
+            && part.chars().all(|c| c.is_ascii_alphanumeric())
+            && part.chars().any(|c| c.is_ascii_digit())
+    })
+}
+
+/// Whether `accept_encoding` lists `token` (or `*`) with a non-zero quality.
+fn accepts(accept_encoding: &str, token: &str) -> bool {
+    accept_encoding.split(',').any(|entry| {
+        let mut params = entry.split(';').map(str::trim);
+        let coding = params.next().unwrap_or_default();
+        let refused = params
+            .filter_map(|p| p.strip_prefix("q="))
+            .any(|q| q.parse::<f32>().is_ok_and(|q| q == 0.0));
+        (coding.eq_ignore_ascii_case(token) || coding == "*") && !refused
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn fingerprints() {
+        assert!(is_fingerprinted("index-4f3a9c1b.js"));
+        assert!(is_fingerprinted("main.8d2e61f0a1.css"));
+        assert!(!is_fingerprinted("index.html"));
+        assert!(!is_fingerprinted("favicon.ico"));
+        assert!(!is_fingerprinted("turing-machines.svg"));
+    }
+
+    #[test]
+    fn accepted_encodings() {
+        assert!(accepts("gzip, deflate, br", "br"));
+        assert!(accepts("br;q=0.5", "br"));
+        assert!(!accepts("gzip, br;q=0", "br"));
+        assert!(accepts("*", "gzip"));
+        assert!(!accepts("", "gzip"));
+    }
+
+    #[tokio::test]
+    async fn assets() {
+        let dir = TempDir::new("web_ui").unwrap();
+        let root = dir.path();
+        std::fs::create_dir(root.join("assets")).unwrap();
+        for file in [
+            "index.html",
+            "assets/index-4f3a9c1b.js",
+            "assets/index-4f3a9c1b.js.br",
+            "logo.svg",
+        ] {
+            std::fs::write(root.join(file), file).unwrap();
+        }
+        let ui = WebUi::new(root.to_path_buf(), config::WebUi::default());
+
+        let asset = ui
+            .asset("/assets/index-4f3a9c1b.js", "gzip, br")
+            .await
+            .unwrap();
+        assert_eq!(asset.path, root.join("assets/index-4f3a9c1b.js.br"));
+        assert_eq!(asset.extension, "js");
+        assert_eq!(asset.encoding, Some(Encoding::Brotli));
+        assert!(asset.cache_control.ends_with("immutable"));
+
+        let asset = ui.asset("/assets/index-4f3a9c1b.js", "gzip").await.unwrap();
+        assert_eq!(asset.encoding, None);
+
+        let asset = ui.asset("/", "").await.unwrap();
+        assert_eq!(asset.path, root.join("index.html"));
+        assert_eq!(asset.cache_control, "no-cache");
+
+        // routes of the single-page application
+        let asset = ui.asset("/nodes/2", "").await.unwrap();
+        assert_eq!(asset.path, root.join("index.html"));
+        assert_eq!(ui.asset("/missing.png", "").await, None);
+        assert_eq!(ui.asset("/../etc/passwd", "").await, None);
+
+        let asset = ui.asset("/logo.svg", "").await.unwrap();
+        assert_eq!(asset.cache_control, "public, max-age=300");
+    }
+}
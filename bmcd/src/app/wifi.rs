@@ -0,0 +1,384 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Wi-Fi connectivity for board revisions that have a radio. Networks are
+//! managed through `wpa_supplicant`. Credentials are kept encrypted in the
+//! persistency of bmcd and are never written to the `wpa_supplicant`
+//! configuration file; they are handed to `wpa_supplicant` on every start.
+use super::bmc_application::BmcApplication;
+use anyhow::{bail, ensure, Context};
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+pub const WIFI_NETWORKS_KEY: &str = "wifi_networks";
+const WIFI_INTERFACE: &str = "wlan0";
+/// Key used to encrypt the stored credentials.
+const WIFI_KEY_FILE: &str = "/var/lib/bmcd/wifi.key";
+const KEY_SIZE: usize = 32;
+const IV_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+/// A network as stored in persistency. The passphrase is encrypted with
+/// AES-256-GCM, the SSID is used as additional authenticated data.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredNetwork {
+    pub ssid: String,
+    iv: Vec<u8>,
+    secret: Vec<u8>,
+    tag: Vec<u8>,
+}
+
+pub type StoredNetworks = Vec<StoredNetwork>;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScanResult {
+    pub ssid: String,
+    pub bssid: String,
+    pub frequency: u32,
+    /// signal level in dBm
+    pub signal: i32,
+    pub security: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WifiStatus {
+    pub state: String,
+    pub ssid: Option<String>,
+    pub ip_address: Option<String>,
+    /// signal level in dBm of the connected network
+    pub signal: Option<i32>,
+    pub saved_networks: Vec<String>,
+}
+
+pub struct WifiManager {
+    interface: String,
+    key_file: PathBuf,
+}
+
+impl Default for WifiManager {
+    fn default() -> Self {
+        Self {
+            interface: WIFI_INTERFACE.to_string(),
+            key_file: PathBuf::from(WIFI_KEY_FILE),
+        }
+    }
+}
+
+impl WifiManager {
+    /// Returns true when the board has a Wi-Fi radio.
+    pub fn is_available(&self) -> bool {
+        Path::new("/sys/class/net")
+            .join(&self.interface)
+            .join("wireless")
+            .exists()
+    }
+
+    pub async fn scan(&self) -> anyhow::Result<Vec<ScanResult>> {
+        self.wpa_cli(&["scan"]).await?;
+        // give the radio time to complete the scan
+        tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+        let output = self.wpa_cli(&["scan_results"]).await?;
+        Ok(parse_scan_results(&output))
+    }
+
+    pub async fn status(&self, bmc: &BmcApplication) -> anyhow::Result<WifiStatus> {
+        let status = parse_key_values(&self.wpa_cli(&["status"]).await?);
+        let signal = parse_key_values(&self.wpa_cli(&["signal_poll"]).await?)
+            .get("RSSI")
+            .and_then(|rssi| rssi.parse().ok());
+
+        Ok(WifiStatus {
+            state: status
+                .get("wpa_state")
+                .cloned()
+                .unwrap_or("UNKNOWN".to_string()),
+            ssid: status.get("ssid").cloned(),
+            ip_address: status.get("ip_address").cloned(),
+            signal,
+            saved_networks: stored_networks(bmc)
+                .await
+                .into_iter()
+                .map(|n| n.ssid)
+                .collect(),
+        })
+    }
+
+    /// Stores the credentials of a WPA2 or WPA3 network and connects to it.
+    pub async fn join(
+        &self,
+        bmc: &BmcApplication,
+        ssid: &str,
+        passphrase: &str,
+    ) -> anyhow::Result<()> {
+        ensure!(
+            !ssid.is_empty() && ssid.len() <= 32,
+            "SSID must be 1 to 32 bytes"
+        );
+        ensure!(
+            (8..=63).contains(&passphrase.len())
+                && passphrase.chars().all(|c| c.is_ascii() && !c.is_control()),
+            "passphrase must be 8 to 63 printable ASCII characters"
+        );
+
+        let key = self.load_key().await?;
+        let network = encrypt(&key, ssid, passphrase)?;
+        self.forget(bmc, ssid).await.ok();
+
+        let mut networks = stored_networks(bmc).await;
+        networks.push(network);
+        bmc.app_db.set(WIFI_NETWORKS_KEY, networks).await;
+
+        let id = self.add_network(ssid, passphrase).await?;
+        self.wpa_cli(&["select_network", &id]).await?;
+        tracing::info!("joining Wi-Fi network {}", ssid);
+        Ok(())
+    }
+
+    /// Removes a network from persistency and `wpa_supplicant`.
+    pub async fn forget(&self, bmc: &BmcApplication, ssid: &str) -> anyhow::Result<()> {
+        let mut networks = stored_networks(bmc).await;
+        let count = networks.len();
+        networks.retain(|n| n.ssid != ssid);
+        if networks.len() == count {
+            bail!("network `{}` is not saved", ssid);
+        }
+        bmc.app_db.set(WIFI_NETWORKS_KEY, networks).await;
+
+        let list = self.wpa_cli(&["list_networks"]).await?;
+        for id in network_ids(&list, ssid) {
+            self.wpa_cli(&["remove_network", &id]).await?;
+        }
+        Ok(())
+    }
+
+    /// Hands all stored networks to `wpa_supplicant`. Called on start-up.
+    pub async fn restore(&self, bmc: &BmcApplication) -> anyhow::Result<()> {
+        let networks = stored_networks(bmc).await;
+        if networks.is_empty() {
+            return Ok(());
+        }
+
+        let key = self.load_key().await?;
+        for network in networks {
+            match decrypt(&key, &network) {
+                Ok(passphrase) => {
+                    let id = self.add_network(&network.ssid, &passphrase).await?;
+                    self.wpa_cli(&["enable_network", &id]).await?;
+                }
+                Err(e) => tracing::error!("cannot restore Wi-Fi {}: {:#}", network.ssid, e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn add_network(&self, ssid: &str, passphrase: &str) -> anyhow::Result<String> {
+        let id = self.wpa_cli(&["add_network"]).await?.trim().to_string();
+        let ssid = hex::encode(ssid);
+        let psk = format!("\"{}\"", passphrase);
+        // accept both WPA2 and WPA3 (SAE) access points
+        for (name, value) in [
+            ("ssid", ssid.as_str()),
+            ("psk", psk.as_str()),
+            ("key_mgmt", "WPA-PSK WPA-PSK-SHA256 SAE"),
+            ("ieee80211w", "1"),
+        ] {
+            self.wpa_cli(&["set_network", &id, name, value]).await?;
+        }
+        Ok(id)
+    }
+
+    async fn wpa_cli(&self, args: &[&str]) -> anyhow::Result<String> {
+        let output = Command::new("wpa_cli")
+            .arg("-i")
+            .arg(&self.interface)
+            .args(args)
+            .output()
+            .await
+            .context("wpa_cli")?;
+        ensure!(output.status.success(), "wpa_cli {} failed", args[0]);
+
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        ensure!(
+            !stdout.starts_with("FAIL"),
+            "wpa_cli {} returned FAIL",
+            args[0]
+        );
+        Ok(stdout)
+    }
+
+    async fn load_key(&self) -> anyhow::Result<Vec<u8>> {
+        match tokio::fs::read(&self.key_file).await {
+            Ok(key) if key.len() == KEY_SIZE => Ok(key),
+            Ok(_) => bail!("{} is not a valid key", self.key_file.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let key: [u8; KEY_SIZE] = rand::random();
+                if let Some(parent) = self.key_file.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                // created owner-only, the key is never readable by others
+                let mut file = tokio::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(&self.key_file)
+                    .await
+                    .with_context(|| self.key_file.display().to_string())?;
+                file.write_all(&key).await?;
+                file.sync_all().await?;
+                Ok(key.to_vec())
+            }
+            Err(e) => Err(e).context(self.key_file.display().to_string()),
+        }
+    }
+}
+
+async fn stored_networks(bmc: &BmcApplication) -> StoredNetworks {
+    bmc.app_db.get::<StoredNetworks>(WIFI_NETWORKS_KEY).await
+}
+
+fn encrypt(key: &[u8], ssid: &str, passphrase: &str) -> anyhow::Result<StoredNetwork> {
+    let iv: [u8; IV_SIZE] = rand::random();
+    let mut tag = vec![0u8; TAG_SIZE];
+    let secret = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&iv),
+        ssid.as_bytes(),
+        passphrase.as_bytes(),
+        &mut tag,
+    )?;
+
+    Ok(StoredNetwork {
+        ssid: ssid.to_string(),
+        iv: iv.to_vec(),
+        secret,
+        tag,
+    })
+}
+
+fn decrypt(key: &[u8], network: &StoredNetwork) -> anyhow::Result<String> {
+    let plain = decrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&network.iv),
+        network.ssid.as_bytes(),
+        &network.secret,
+        &network.tag,
+    )
+    .context("credentials cannot be decrypted")?;
+    Ok(String::from_utf8(plain)?)
+}
+
+fn parse_key_values(output: &str) -> HashMap<String, String> {
+    output
+        .lines()
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+/// Parses the output of `wpa_cli scan_results`, strongest signal first.
+fn parse_scan_results(output: &str) -> Vec<ScanResult> {
+    let mut results: Vec<ScanResult> = output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let bssid = fields.next()?;
+            let frequency = fields.next()?.parse().ok()?;
+            let signal = fields.next()?.parse().ok()?;
+            let flags = fields.next()?;
+            let ssid = fields.next().unwrap_or_default();
+
+            let security = if flags.contains("SAE") {
+                "WPA3"
+            } else if flags.contains("WPA2") {
+                "WPA2"
+            } else if flags.contains("WPA") {
+                "WPA"
+            } else if flags.contains("WEP") {
+                "WEP"
+            } else {
+                "open"
+            };
+
+            Some(ScanResult {
+                ssid: ssid.to_string(),
+                bssid: bssid.to_string(),
+                frequency,
+                signal,
+                security,
+            })
+        })
+        .collect();
+    results.sort_by_key(|r| std::cmp::Reverse(r.signal));
+    results
+}
+
+/// Returns the ids of the networks in the output of `wpa_cli list_networks`
+/// that match `ssid`.
+fn network_ids(output: &str, ssid: &str) -> Vec<String> {
+    output
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let id = fields.next()?;
+            (fields.next()? == ssid).then(|| id.to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_roundtrip() {
+        let key = [7u8; KEY_SIZE];
+        let network = encrypt(&key, "lab", "correct horse").unwrap();
+        assert_ne!(network.secret, b"correct horse");
+        assert_eq!(decrypt(&key, &network).unwrap(), "correct horse");
+
+        let mut tampered = network.clone();
+        tampered.ssid = "other".to_string();
+        assert!(decrypt(&key, &tampered).is_err());
+        assert!(decrypt(&[8u8; KEY_SIZE], &network).is_err());
+    }
+
+    #[test]
+    fn scan_results() {
+        let output = "bssid / frequency / signal level / flags / ssid\n\
+            aa:bb:cc:dd:ee:01\t2412\t-70\t[WPA2-PSK-CCMP][ESS]\thome\n\
+            aa:bb:cc:dd:ee:02\t5180\t-40\t[WPA2-SAE-CCMP][ESS]\tlab\n\
+            aa:bb:cc:dd:ee:03\t2437\t-80\t[ESS]\t\n";
+        let results = parse_scan_results(output);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].ssid, "lab");
+        assert_eq!(results[0].security, "WPA3");
+        assert_eq!(results[1].security, "WPA2");
+        assert_eq!(results[2].security, "open");
+    }
+
+    #[test]
+    fn list_networks() {
+        let output = "network id / ssid / bssid / flags\n\
+            0\thome\tany\t[CURRENT]\n\
+            1\tlab\tany\t\n";
+        assert_eq!(network_ids(output, "lab"), vec!["1".to_string()]);
+        assert!(network_ids(output, "none").is_empty());
+    }
+}
@@ -16,6 +16,7 @@ use super::authentication_errors::SchemedAuthError;
 use super::ban_patrol::BanPatrol;
 use super::passwd_validator::PasswordValidator;
 use super::passwd_validator::UnixValidator;
+use super::roles::{AuthenticatedUser, Role};
 use base64::{engine::general_purpose, Engine as _};
 use rand::distr::Alphanumeric;
 use rand::rng;
@@ -23,6 +24,7 @@ use rand::Rng;
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::marker::PhantomData;
 use tokio::time::{Duration, Instant};
 
@@ -30,8 +32,10 @@ pub struct AuthenticationContext<P>
 where
     P: PasswordValidator + 'static,
 {
-    token_store: HashMap<String, Instant>,
+    token_store: HashMap<String, Token>,
     passwds: HashMap<String, String>,
+    allowed_users: HashSet<String>,
+    roles: HashMap<String, Role>,
     password_validator: PhantomData<P>,
     expire_timeout: Duration,
     ban_patrol: BanPatrol,
@@ -49,12 +53,34 @@ where
         AuthenticationContext::<UnixValidator> {
             token_store: HashMap::new(),
             passwds: HashMap::from_iter(password_entries),
+            allowed_users: HashSet::new(),
+            roles: HashMap::new(),
             password_validator: PhantomData::<UnixValidator>,
             expire_timeout,
             ban_patrol: BanPatrol::new(authentication_attempts),
         }
     }
 
+    /// Restrict authentication to the given users. An empty collection lifts
+    /// the restriction. Tokens that were already handed out stay valid until
+    /// they expire.
+    pub fn set_allowed_users(&mut self, users: impl IntoIterator<Item = String>) {
+        self.allowed_users = HashSet::from_iter(users);
+    }
+
+    /// Roles of users by name. Users without a role are operators. Roles
+    /// apply to tokens that were already handed out as well.
+    pub fn set_roles(&mut self, roles: impl IntoIterator<Item = (String, Role)>) {
+        self.roles = HashMap::from_iter(roles);
+    }
+
+    fn user(&self, name: &str) -> AuthenticatedUser {
+        AuthenticatedUser {
+            name: name.to_string(),
+            role: self.roles.get(name).copied().unwrap_or_default(),
+        }
+    }
+
     pub fn reload_password_cache(
         &mut self,
         password_entries: impl Iterator<Item = (String, String)>,
@@ -66,23 +92,29 @@ where
     /// request. This imposes a small penalty on each request. Its deemed not
     /// significant enough to justify optimization given the expected volume
     /// of incoming authentication requests.
-    async fn new_and_remove_expired_tokens(&mut self, key: String) {
-        self.token_store.retain(|_, last_access| {
-            let duration = Instant::now().saturating_duration_since(*last_access);
+    async fn new_and_remove_expired_tokens(&mut self, key: String, user: String) {
+        self.token_store.retain(|_, token| {
+            let duration = Instant::now().saturating_duration_since(token.last_access);
             duration <= self.expire_timeout
         });
 
-        self.token_store.insert(key, Instant::now());
+        self.token_store.insert(
+            key,
+            Token {
+                last_access: Instant::now(),
+                user,
+            },
+        );
     }
 
     async fn authorize_bearer(
         &mut self,
         peer: &str,
         token: &str,
-    ) -> Result<(), AuthenticationError> {
+    ) -> Result<AuthenticatedUser, AuthenticationError> {
         self.ban_patrol.patrole_ban(peer)?;
 
-        let Some(last_access) = self.token_store.get_mut(token) else {
+        let Some(entry) = self.token_store.get_mut(token) else {
             return Err(self
                 .ban_patrol
                 .penalize(peer)
@@ -90,12 +122,13 @@ where
                 .unwrap_or(AuthenticationError::NoMatch(token.to_string())));
         };
 
-        let instant = *last_access;
+        let instant = entry.last_access;
         let duration = Instant::now().saturating_duration_since(instant);
         if duration < self.expire_timeout {
-            *last_access = Instant::now();
+            entry.last_access = Instant::now();
+            let user = entry.user.clone();
             self.ban_patrol.clear_penalties(peer);
-            return Ok(());
+            return Ok(self.user(&user));
         }
 
         self.token_store.remove(token);
@@ -110,9 +143,11 @@ where
     ) -> Result<(), AuthenticationError> {
         self.ban_patrol.patrole_ban(peer)?;
 
+        let allowed = self.allowed_users.is_empty() || self.allowed_users.contains(username);
         match self
             .passwds
             .get(username)
+            .filter(|_| allowed)
             .ok_or(AuthenticationError::IncorrectCredentials)
             .and_then(|pass| P::validate(pass, password))
         {
@@ -134,7 +169,7 @@ where
         &mut self,
         peer: &str,
         credentials: &str,
-    ) -> Result<(), AuthenticationError> {
+    ) -> Result<AuthenticatedUser, AuthenticationError> {
         let decoded = general_purpose::STANDARD.decode(credentials)?;
         let utf8 = std::str::from_utf8(&decoded)?;
         let Some((user, pass)) = utf8.split_once(':') else {
@@ -143,14 +178,15 @@ where
             ));
         };
 
-        self.validate_credentials(peer, user, pass)
+        self.validate_credentials(peer, user, pass)?;
+        Ok(self.user(user))
     }
 
     pub async fn authorize_request(
         &mut self,
         peer: &str,
         http_authorization_line: &str,
-    ) -> Result<(), SchemedAuthError> {
+    ) -> Result<AuthenticatedUser, SchemedAuthError> {
         match http_authorization_line.split_once(' ') {
             Some(("Bearer", token)) => self
                 .authorize_bearer(peer, token)
@@ -184,7 +220,8 @@ where
             .take(64)
             .map(char::from)
             .collect();
-        self.new_and_remove_expired_tokens(token.clone()).await;
+        self.new_and_remove_expired_tokens(token.clone(), credentials.username.clone())
+            .await;
 
         Ok(Session {
             id: token, // according Redfish spec, id refers to the session id.
@@ -197,6 +234,11 @@ where
     }
 }
 
+struct Token {
+    last_access: Instant,
+    user: String,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Session {
     pub id: String,
@@ -216,6 +258,7 @@ pub mod tests {
     use super::*;
     use std::ops::Sub;
 
+    #[allow(dead_code)]
     pub struct DummyValidator {}
     impl PasswordValidator for DummyValidator {
         fn validate(
@@ -226,6 +269,7 @@ pub mod tests {
         }
     }
 
+    #[allow(dead_code)]
     pub struct FalseValidator {}
     impl PasswordValidator for FalseValidator {
         fn validate(
@@ -240,9 +284,15 @@ pub mod tests {
         token_data: impl IntoIterator<Item = (String, Instant)>,
         user_data: impl IntoIterator<Item = (String, String)>,
     ) -> AuthenticationContext<UnixValidator> {
+        let tokens = token_data.into_iter().map(|(token, last_access)| {
+            let user = "test_user".to_string();
+            (token, Token { last_access, user })
+        });
         AuthenticationContext {
-            token_store: HashMap::from_iter(token_data),
+            token_store: HashMap::from_iter(tokens),
             passwds: HashMap::from_iter(user_data),
+            allowed_users: HashSet::new(),
+            roles: HashMap::new(),
             password_validator: PhantomData::<UnixValidator>,
             expire_timeout: Duration::from_secs(20),
             ban_patrol: BanPatrol::new(10),
@@ -298,11 +348,27 @@ pub mod tests {
             Vec::new(),
         );
         assert_eq!(
-            Ok(()),
-            context.authorize_request("peer1", "Bearer 123").await
+            context
+                .authorize_request("peer1", "Bearer 123")
+                .await
+                .unwrap()
+                .role,
+            Role::Operator
         );
     }
 
+    #[actix_web::test]
+    async fn roles_apply_to_tokens() {
+        let mut context = build_test_context([("123".to_string(), Instant::now())], Vec::new());
+        context.set_roles([("test_user".to_string(), Role::Observer)]);
+        let user = context
+            .authorize_request("peer1", "Bearer 123")
+            .await
+            .unwrap();
+        assert_eq!(user.name, "test_user");
+        assert_eq!(user.role, Role::Observer);
+    }
+
     #[actix_web::test]
     async fn authentication_errors() {
         let mut context = build_test_context(
@@ -16,11 +16,12 @@ use super::{
     authentication_errors::{AuthenticationError, SchemedAuthError},
     passwd_validator::UnixValidator,
 };
+use crate::api::http_policy::client_address;
 use actix_web::{
     body::{EitherBody, MessageBody},
     dev::{Service, ServiceRequest, ServiceResponse},
     http::header::{self},
-    Error, HttpRequest, HttpResponse,
+    Error, HttpMessage, HttpRequest, HttpResponse,
 };
 use futures::future::LocalBoxFuture;
 use futures::StreamExt;
@@ -28,6 +29,10 @@ use serde::Serialize;
 use std::{rc::Rc, sync::Arc};
 use tokio::sync::Mutex;
 
+/// Connection data of requests that arrive on the local Unix socket. Only
+/// root can connect to it, so they are not authenticated.
+pub struct LocalConnection;
+
 /// This authentication service is designed to prepare for implementing "Redfish
 /// Session Login Authentication" as good as possible. Redfish is not yet
 /// implemented in this product, until then this session based, token
@@ -71,12 +76,11 @@ where
     fn call(&self, mut request: ServiceRequest) -> Self::Future {
         let service = self.service.clone();
 
-        // drop authentication for requests on loopback interface
-        if request
-            .head()
-            .peer_addr
-            .is_some_and(|addr| addr.ip().to_canonical().is_loopback())
-        {
+        // drop authentication for requests on loopback interface. A reverse
+        // proxy on the BMC itself is a trusted proxy, its clients are not local.
+        let local = request.conn_data::<LocalConnection>().is_some()
+            || client_address(request.request()).is_some_and(|addr| addr.is_loopback());
+        if local {
             return Box::pin(async move {
                 service
                     .call(request)
@@ -90,11 +94,9 @@ where
         let realm = self.realm;
 
         Box::pin(async move {
-            let peer = request
-                .connection_info()
-                .peer_addr()
-                .unwrap_or_default()
-                .to_string();
+            let peer = client_address(request.request())
+                .map(|address| address.to_string())
+                .unwrap_or_default();
             let mut context = context.lock().await;
 
             // handle authentication requests and return
@@ -109,13 +111,15 @@ where
                 }
             };
 
-            if let Err(e) = context.authorize_request(&peer, auth).await {
-                unauthorized_response(request.request(), e, realm)
-            } else {
-                service
-                    .call(request)
-                    .await
-                    .map(ServiceResponse::map_into_left_body)
+            match context.authorize_request(&peer, auth).await {
+                Ok(user) => {
+                    request.extensions_mut().insert(user);
+                    service
+                        .call(request)
+                        .await
+                        .map(ServiceResponse::map_into_left_body)
+                }
+                Err(e) => unauthorized_response(request.request(), e, realm),
             }
         })
     }
@@ -13,7 +13,7 @@
 // limitations under the License.
 use super::{
     authentication_context::AuthenticationContext, authentication_service::AuthenticationService,
-    passwd_validator::UnixValidator,
+    passwd_validator::UnixValidator, roles::Role,
 };
 use actix_web::{
     body::{EitherBody, MessageBody},
@@ -54,15 +54,13 @@ impl LinuxAuthenticator {
     ) -> io::Result<Self> {
         let password_entries = Self::parse_shadow_file().await?;
 
-        let instance = Self {
-            context: Arc::new(Mutex::new(LinuxContext::with_unix_validator(
-                password_entries,
-                authentication_token_duration,
-                authentication_attemps,
-            ))),
+        let instance = Self::with_password_entries(
+            password_entries,
             authentication_path,
             realm,
-        };
+            authentication_token_duration,
+            authentication_attemps,
+        );
 
         if let Err(e) = instance.auto_reload().await {
             tracing::warn!("auto reloading of password-cache disabled: {}", e);
@@ -70,9 +68,37 @@ impl LinuxAuthenticator {
 
         Ok(instance)
     }
+
+    /// Authenticator that only accepts `password_entries`, pairs of user name
+    /// and crypt(3) hash, instead of the accounts in the shadow file.
+    pub fn with_password_entries(
+        password_entries: impl Iterator<Item = (String, String)>,
+        authentication_path: &'static str,
+        realm: &'static str,
+        authentication_token_duration: Duration,
+        authentication_attemps: usize,
+    ) -> Self {
+        Self {
+            context: Arc::new(Mutex::new(LinuxContext::with_unix_validator(
+                password_entries,
+                authentication_token_duration,
+                authentication_attemps,
+            ))),
+            authentication_path,
+            realm,
+        }
+    }
 }
 
 impl LinuxAuthenticator {
+    pub async fn set_allowed_users(&self, users: Vec<String>) {
+        self.context.lock().await.set_allowed_users(users);
+    }
+
+    pub async fn set_roles(&self, roles: impl IntoIterator<Item = (String, Role)>) {
+        self.context.lock().await.set_roles(roles);
+    }
+
     /// Watches for any changes in the shadow file and reloads the password
     /// cache when a change is detected.
     async fn auto_reload(&self) -> std::io::Result<()> {
@@ -0,0 +1,69 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Roles of authenticated users. Roles restrict what a user may do on top of
+//! being permitted to use the API at all, see `Config::users`.
+use actix_web::{HttpMessage, HttpRequest};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// full access
+    #[default]
+    Operator,
+    /// watches the consoles of nodes, but cannot type on them
+    Observer,
+}
+
+impl Role {
+    pub fn can_write_console(self) -> bool {
+        self == Role::Operator
+    }
+
+    /// Sharing a console grants others access, which observers may not do.
+    pub fn can_share_console(self) -> bool {
+        self == Role::Operator
+    }
+
+    /// The configuration holds the accounts, roles and keys of the board.
+    /// Observers may not change it, nor read it to obtain these keys.
+    pub fn can_manage_config(self) -> bool {
+        self == Role::Operator
+    }
+}
+
+/// User of a request, stored in the request extensions by the
+/// authentication middleware.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthenticatedUser {
+    pub name: String,
+    pub role: Role,
+}
+
+/// Role of the user of `request`. Requests that were not authenticated are
+/// local, e.g. on the Unix socket, and have full access.
+pub fn role(request: &HttpRequest) -> Role {
+    request
+        .extensions()
+        .get::<AuthenticatedUser>()
+        .map_or(Role::Operator, |user| user.role)
+}
+
+/// Name of the user of `request`, `None` for local requests.
+pub fn user_name(request: &HttpRequest) -> Option<String> {
+    request
+        .extensions()
+        .get::<AuthenticatedUser>()
+        .map(|user| user.name.clone())
+}
@@ -0,0 +1,306 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Minimal HTTP/1.1 client for the API of bmcd, over the local Unix socket or
+//! over HTTPS. Every request opens a new connection, which is closed by the
+//! server after the response.
+use anyhow::{bail, ensure, Context};
+use base64::Engine;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use serde_json::Value;
+use std::path::PathBuf;
+use std::pin::Pin;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_openssl::SslStream;
+
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub type Connection = BufReader<Box<dyn Stream>>;
+
+pub enum Target {
+    Unix(PathBuf),
+    Https {
+        host: String,
+        port: u16,
+        insecure: bool,
+        /// value of the `Authorization` header
+        authorization: Option<String>,
+    },
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Unwraps the `{"response": [{"result": ..}]}` envelope of the API.
+    pub fn result(&self) -> anyhow::Result<Value> {
+        let is_json = self
+            .content_type
+            .as_deref()
+            .is_some_and(|t| t.starts_with("application/json"));
+        let value = if is_json {
+            let envelope: Value = serde_json::from_slice(&self.body)?;
+            match envelope.pointer("/response/0") {
+                Some(Value::Object(fields)) => fields
+                    .get("result")
+                    .or_else(|| fields.get("uart"))
+                    .cloned()
+                    .unwrap_or(Value::Null),
+                _ => envelope,
+            }
+        } else {
+            Value::String(String::from_utf8_lossy(&self.body).into_owned())
+        };
+        if !(200..300).contains(&self.status) {
+            match value {
+                Value::String(msg) if !msg.is_empty() => bail!("{} ({})", msg, self.status),
+                _ => bail!("request failed with status {}", self.status),
+            }
+        }
+        Ok(value)
+    }
+}
+
+pub struct Client {
+    target: Target,
+}
+
+impl Client {
+    pub fn new(target: Target) -> Self {
+        Self { target }
+    }
+
+    pub fn is_local(&self) -> bool {
+        matches!(self.target, Target::Unix(_))
+    }
+
+    pub async fn connect(&self) -> anyhow::Result<Connection> {
+        let stream: Box<dyn Stream> = match &self.target {
+            Target::Unix(path) => Box::new(
+                UnixStream::connect(path)
+                    .await
+                    .with_context(|| path.display().to_string())?,
+            ),
+            Target::Https {
+                host,
+                port,
+                insecure,
+                ..
+            } => {
+                let tcp = TcpStream::connect((host.as_str(), *port))
+                    .await
+                    .with_context(|| format!("{}:{}", host, port))?;
+                let mut connector = SslConnector::builder(SslMethod::tls())?;
+                if *insecure {
+                    connector.set_verify(SslVerifyMode::NONE);
+                }
+                let ssl = connector.build().configure()?.into_ssl(host)?;
+                let mut stream = SslStream::new(ssl, tcp)?;
+                Pin::new(&mut stream)
+                    .connect()
+                    .await
+                    .context("TLS handshake, use --insecure for a self-signed certificate")?;
+                Box::new(stream)
+            }
+        };
+        Ok(BufReader::new(stream))
+    }
+
+    /// Writes the request line and headers. `extra` headers are written as is.
+    pub async fn write_head(
+        &self,
+        connection: &mut Connection,
+        method: &str,
+        path: &str,
+        extra: &[(&str, String)],
+    ) -> anyhow::Result<()> {
+        let host = match &self.target {
+            Target::Unix(_) => "localhost".to_string(),
+            Target::Https { host, .. } => host.clone(),
+        };
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", method, path, host);
+        if let Target::Https {
+            authorization: Some(authorization),
+            ..
+        } = &self.target
+        {
+            head.push_str(&format!("Authorization: {}\r\n", authorization));
+        }
+        for (name, value) in extra {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        connection.get_mut().write_all(head.as_bytes()).await?;
+        Ok(())
+    }
+
+    pub async fn request(
+        &self,
+        method: &str,
+        path: &str,
+        content_type: Option<&str>,
+        body: &[u8],
+    ) -> anyhow::Result<Response> {
+        let mut connection = self.connect().await?;
+        let mut headers = vec![
+            ("Connection", "close".to_string()),
+            ("Content-Length", body.len().to_string()),
+        ];
+        if let Some(content_type) = content_type {
+            headers.push(("Content-Type", content_type.to_string()));
+        }
+        self.write_head(&mut connection, method, path, &headers)
+            .await?;
+        connection.get_mut().write_all(body).await?;
+        connection.get_mut().flush().await?;
+        read_response(&mut connection).await
+    }
+
+    pub async fn get(&self, path: &str) -> anyhow::Result<Value> {
+        self.request("GET", path, None, &[]).await?.result()
+    }
+
+    pub async fn post(&self, path: &str) -> anyhow::Result<Value> {
+        self.request("POST", path, None, &[]).await?.result()
+    }
+
+    /// Request to the legacy `/api/bmc?opt=..&type=..` route.
+    pub async fn legacy(&self, query: &str) -> anyhow::Result<Value> {
+        self.get(&format!("/api/bmc?{}", query)).await
+    }
+}
+
+pub fn basic_authorization(user: &str, password: &str) -> String {
+    let credentials =
+        base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
+    format!("Basic {}", credentials)
+}
+
+/// Reads the status line and headers, returns the status and the headers
+/// with lowercase names.
+pub async fn read_head(
+    connection: &mut Connection,
+) -> anyhow::Result<(u16, Vec<(String, String)>)> {
+    let mut line = String::new();
+    connection.read_line(&mut line).await?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .with_context(|| format!("invalid status line {:?}", line.trim_end()))?;
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        ensure!(
+            connection.read_line(&mut line).await? > 0,
+            "connection closed in response header"
+        );
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok((status, headers));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_str())
+}
+
+pub async fn read_response(connection: &mut Connection) -> anyhow::Result<Response> {
+    let (status, headers) = read_head(connection).await?;
+    let mut body = Vec::new();
+    if header(&headers, "transfer-encoding").is_some_and(|t| t.contains("chunked")) {
+        read_chunked(connection, &mut body).await?;
+    } else if let Some(length) = header(&headers, "content-length") {
+        body.resize(length.parse().context("content-length")?, 0);
+        connection.read_exact(&mut body).await?;
+    } else {
+        connection.read_to_end(&mut body).await?;
+    }
+    Ok(Response {
+        status,
+        content_type: header(&headers, "content-type").map(str::to_string),
+        body,
+    })
+}
+
+async fn read_chunked<R>(reader: &mut R, body: &mut Vec<u8>) -> anyhow::Result<()>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let mut line = String::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).await?;
+        let size = line.trim_end().split(';').next().unwrap_or_default();
+        let size = usize::from_str_radix(size, 16)
+            .with_context(|| format!("invalid chunk size {:?}", size))?;
+        if size == 0 {
+            // trailers end with an empty line
+            loop {
+                line.clear();
+                if reader.read_line(&mut line).await? == 0 || line.trim_end().is_empty() {
+                    return Ok(());
+                }
+            }
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).await?;
+        line.clear();
+        reader.read_line(&mut line).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn chunked_body() {
+        let mut reader: &[u8] = b"5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\n\r\n";
+        let mut body = Vec::new();
+        read_chunked(&mut reader, &mut body).await.unwrap();
+        assert_eq!(body, b"hello, world");
+    }
+
+    #[test]
+    fn result_envelope() {
+        let response = Response {
+            status: 400,
+            content_type: Some("application/json".to_string()),
+            body: br#"{"response":[{"result":"Missing `node` parameter"}]}"#.to_vec(),
+        };
+        let error = response.result().unwrap_err().to_string();
+        assert_eq!(error, "Missing `node` parameter (400)");
+
+        let response = Response {
+            status: 200,
+            content_type: Some("application/json".to_string()),
+            body: br#"{"response":[{"uart":"login: "}]}"#.to_vec(),
+        };
+        assert_eq!(response.result().unwrap(), Value::from("login: "));
+    }
+}
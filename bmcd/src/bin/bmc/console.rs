@@ -0,0 +1,173 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Attaches the terminal to the console of a node through the websocket route
+//! `/serial/ws`. Input is sent line by line, end it with Ctrl-D.
+use super::client::{read_head, Client, Connection};
+use anyhow::{bail, ensure};
+use base64::Engine;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+pub async fn attach(client: &Client, node: u8) -> anyhow::Result<()> {
+    let mut connection = open(client, node).await?;
+    let (mut reader, mut writer) = tokio::io::split(&mut connection);
+
+    let (input, mut input_rx) = mpsc::channel::<(u8, Vec<u8>)>(4);
+    tokio::spawn(async move {
+        let mut stdin = tokio::io::stdin();
+        let mut buffer = vec![0u8; 1024];
+        loop {
+            match stdin.read(&mut buffer).await {
+                Ok(0) | Err(_) => {
+                    let _ = input.send((CLOSE, Vec::new())).await;
+                    return;
+                }
+                Ok(n) => {
+                    if input.send((BINARY, buffer[..n].to_vec())).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+
+    let mut stdout = tokio::io::stdout();
+    loop {
+        tokio::select! {
+            frame = read_frame(&mut reader) => {
+                let (opcode, payload) = frame?;
+                match opcode {
+                    CONTINUATION | TEXT | BINARY => {
+                        stdout.write_all(&payload).await?;
+                        stdout.flush().await?;
+                    }
+                    PING => write_frame(&mut writer, PONG, &payload).await?,
+                    CLOSE => {
+                        if let Some(reason) = payload.get(2..).filter(|r| !r.is_empty()) {
+                            eprintln!("\nconsole closed: {}", String::from_utf8_lossy(reason));
+                        }
+                        return Ok(());
+                    }
+                    _ => {}
+                }
+            }
+            Some((opcode, payload)) = input_rx.recv() => {
+                write_frame(&mut writer, opcode, &payload).await?;
+                if opcode == CLOSE {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+async fn open(client: &Client, node: u8) -> anyhow::Result<Connection> {
+    let mut connection = client.connect().await?;
+    let key = base64::engine::general_purpose::STANDARD.encode(rand::random::<[u8; 16]>());
+    let headers = [
+        ("Connection", "Upgrade".to_string()),
+        ("Upgrade", "websocket".to_string()),
+        ("Sec-WebSocket-Version", "13".to_string()),
+        ("Sec-WebSocket-Key", key),
+    ];
+    let path = format!("/api/bmc/serial/ws?node={}", node);
+    client
+        .write_head(&mut connection, "GET", &path, &headers)
+        .await?;
+    let (status, headers) = read_head(&mut connection).await?;
+    if status != 101 {
+        bail!("console of node {} not available ({})", node + 1, status);
+    }
+    if headers
+        .iter()
+        .any(|(name, value)| name == "x-console-access" && value == "read-only")
+    {
+        eprintln!("the console is read-only, input closes the connection");
+    }
+    Ok(connection)
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header).await?;
+    let opcode = header[0] & 0x0f;
+    ensure!(header[1] & 0x80 == 0, "server sent a masked frame");
+    let len = match header[1] & 0x7f {
+        126 => u64::from(reader.read_u16().await?),
+        127 => reader.read_u64().await?,
+        len => u64::from(len),
+    };
+    let mut payload = vec![0u8; usize::try_from(len)?];
+    reader.read_exact(&mut payload).await?;
+    Ok((opcode, payload))
+}
+
+/// Writes a single, final frame. Frames of a client are masked.
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    opcode: u8,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(0x80 | len as u8),
+        len @ 126..=0xffff => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    let mask = rand::random::<[u8; 4]>();
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+    writer.write_all(&frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn masked_frames() {
+        let mut frame = Vec::new();
+        write_frame(&mut frame, BINARY, b"ls\n").await.unwrap();
+        assert_eq!(frame[..2], [0x82, 0x83]);
+        let mask = &frame[2..6];
+        let payload: Vec<u8> = frame[6..]
+            .iter()
+            .zip(mask.iter().cycle())
+            .map(|(b, m)| b ^ m)
+            .collect();
+        assert_eq!(payload, b"ls\n");
+
+        let mut long = vec![0x82, 126];
+        long.extend_from_slice(&300u16.to_be_bytes());
+        long.extend_from_slice(&[b'x'; 300]);
+        let (opcode, payload) = read_frame(&mut long.as_slice()).await.unwrap();
+        assert_eq!(opcode, BINARY);
+        assert_eq!(payload.len(), 300);
+    }
+}
@@ -0,0 +1,477 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! `bmc`, command line client of bmcd. It talks to the local Unix socket of
+//! bmcd when run on the BMC, or to the HTTPS API of a remote BMC with
+//! `--host`.
+#![deny(clippy::mod_module_files)]
+mod client;
+mod console;
+
+use anyhow::{bail, Context};
+use clap::{crate_version, value_parser, Arg, ArgAction, ArgMatches, Command};
+use client::{basic_authorization, Client, Target};
+use serde_json::Value;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+const DEFAULT_SOCKET: &str = "/var/run/bmcd.sock";
+const MULTIPART_BOUNDARY: &str = "bmc-cli-image-upload";
+
+fn node_arg(required: bool) -> Arg {
+    Arg::new("node")
+        .short('n')
+        .long("node")
+        .help("node 1 to 4")
+        .value_parser(value_parser!(u8).range(1..=4))
+        .required(required)
+}
+
+fn cli() -> Command {
+    Command::new("bmc")
+        .version(crate_version!())
+        .about("Control a Turing Pi board through bmcd")
+        .subcommand_required(true)
+        .arg(
+            Arg::new("socket")
+                .long("socket")
+                .help("Unix socket of bmcd")
+                .value_parser(value_parser!(PathBuf))
+                .default_value(DEFAULT_SOCKET)
+                .global(true),
+        )
+        .arg(
+            Arg::new("host")
+                .long("host")
+                .help("connect to the HTTPS API of this host instead of the socket")
+                .global(true),
+        )
+        .arg(
+            Arg::new("port")
+                .long("port")
+                .value_parser(value_parser!(u16))
+                .default_value("443")
+                .global(true),
+        )
+        .arg(
+            Arg::new("user")
+                .long("user")
+                .default_value("root")
+                .global(true),
+        )
+        .arg(
+            Arg::new("password")
+                .long("password")
+                .env("BMC_PASSWORD")
+                .hide_env_values(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("insecure")
+                .long("insecure")
+                .help("accept any TLS certificate, such as the self-signed one of a new board")
+                .action(ArgAction::SetTrue)
+                .global(true),
+        )
+        .subcommand(
+            Command::new("power")
+                .about("Power nodes on or off, reset them or show their state")
+                .arg(
+                    Arg::new("action")
+                        .value_parser(["on", "off", "reset", "status"])
+                        .default_value("status"),
+                )
+                .arg(node_arg(false).help("node 1 to 4, all nodes when omitted")),
+        )
+        .subcommand(
+            Command::new("usb")
+                .about("Route the USB port to a node or show the current route")
+                .arg(
+                    Arg::new("mode")
+                        .value_parser(["host", "device", "flash", "status"])
+                        .default_value("status"),
+                )
+                .arg(node_arg(false))
+                .arg(
+                    Arg::new("bmc")
+                        .long("bmc")
+                        .help("connect the node to the BMC instead of the USB-A port")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("flash")
+                .about("Write an OS image to a node")
+                .arg(node_arg(true))
+                .arg(
+                    Arg::new("image")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("sha256")
+                        .long("sha256")
+                        .help("expected SHA-256 of the image"),
+                )
+                .arg(
+                    Arg::new("crc32")
+                        .long("crc32")
+                        .conflicts_with("sha256")
+                        .help("expected CRC-32 of the image, as printed by `crc32`"),
+                )
+                .arg(
+                    Arg::new("skip-crc")
+                        .long("skip-crc")
+                        .help("do not verify the written image")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .value_name("TARGET")
+                        .value_parser(["null", "file"])
+                        .num_args(0..=1)
+                        .default_missing_value("null")
+                        .help(
+                            "run the whole flash without touching the node, writing the \
+                             image to /dev/null or to a scratch file that is verified",
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("console")
+                .about("Attach to the serial console of a node")
+                .arg(node_arg(true)),
+        )
+        .subcommand(
+            Command::new("config")
+                .about("Show, reload, export or import the configuration of bmcd")
+                .subcommand_required(true)
+                .subcommand(Command::new("status").about("State of the last (re)load"))
+                .subcommand(Command::new("reload").about("Reload the configuration file"))
+                .subcommand(
+                    Command::new("export")
+                        .about("Save a signed archive of all settings")
+                        .arg(
+                            Arg::new("file")
+                                .required(true)
+                                .value_parser(value_parser!(PathBuf)),
+                        ),
+                )
+                .subcommand(
+                    Command::new("import")
+                        .about("Restore an archive created by `export`, bmcd restarts")
+                        .arg(
+                            Arg::new("file")
+                                .required(true)
+                                .value_parser(value_parser!(PathBuf)),
+                        ),
+                ),
+        )
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    let args = cli().get_matches();
+    let client = Client::new(target(&args));
+    match args.subcommand().expect("subcommand required") {
+        ("power", args) => power(&client, args).await,
+        ("usb", args) => usb(&client, args).await,
+        ("flash", args) => flash(&client, args).await,
+        ("console", args) => console::attach(&client, node(args).expect("node required")).await,
+        ("config", args) => config(&client, args).await,
+        _ => unreachable!(),
+    }
+}
+
+fn target(args: &ArgMatches) -> Target {
+    let Some(host) = args.get_one::<String>("host") else {
+        let socket = args.get_one::<PathBuf>("socket").expect("has a default");
+        return Target::Unix(socket.clone());
+    };
+    let user = args.get_one::<String>("user").expect("has a default");
+    Target::Https {
+        host: host.clone(),
+        port: *args.get_one::<u16>("port").expect("has a default"),
+        insecure: args.get_flag("insecure"),
+        authorization: args
+            .get_one::<String>("password")
+            .map(|password| basic_authorization(user, password)),
+    }
+}
+
+/// Index of the node in the API, which counts from 0.
+fn node(args: &ArgMatches) -> Option<u8> {
+    args.get_one::<u8>("node").map(|n| n - 1)
+}
+
+async fn power(client: &Client, args: &ArgMatches) -> anyhow::Result<()> {
+    let action = args.get_one::<String>("action").expect("has a default");
+    let node = node(args);
+    match (action.as_str(), node) {
+        ("status", _) => {
+            let states = client.legacy("opt=get&type=power").await?;
+            let states = states
+                .get(0)
+                .and_then(Value::as_object)
+                .context("no power states")?;
+            for (name, state) in states {
+                let state = match state.as_str() {
+                    Some("1") => "on",
+                    Some("0") => "off",
+                    _ => "unknown",
+                };
+                println!("{}: {}", name, state);
+            }
+            return Ok(());
+        }
+        ("reset", Some(node)) => {
+            client
+                .legacy(&format!("opt=set&type=reset&node={}", node))
+                .await?;
+        }
+        ("reset", None) => bail!("reset needs a --node"),
+        (on_off, node) => {
+            let value = u8::from(on_off == "on");
+            let nodes = match node {
+                Some(node) => format!("node{}={}", node + 1, value),
+                None => (1..=4)
+                    .map(|n| format!("node{}={}", n, value))
+                    .collect::<Vec<_>>()
+                    .join("&"),
+            };
+            client
+                .legacy(&format!("opt=set&type=power&{}", nodes))
+                .await?;
+        }
+    }
+    println!("ok");
+    Ok(())
+}
+
+async fn usb(client: &Client, args: &ArgMatches) -> anyhow::Result<()> {
+    let mode = match args
+        .get_one::<String>("mode")
+        .expect("has a default")
+        .as_str()
+    {
+        "status" => {
+            let status = client.legacy("opt=get&type=usb").await?;
+            println!("{}", serde_json::to_string_pretty(&status)?);
+            return Ok(());
+        }
+        "host" => 0,
+        "device" => 1,
+        _ => 2,
+    };
+    let node = node(args).context("switching USB needs a --node")?;
+    // see the mode table of the legacy `type=usb` request
+    let mode = if args.get_flag("bmc") { mode | 4 } else { mode };
+    client
+        .legacy(&format!("opt=set&type=usb&node={}&mode={}", node, mode))
+        .await?;
+    println!("ok");
+    Ok(())
+}
+
+async fn flash(client: &Client, args: &ArgMatches) -> anyhow::Result<()> {
+    let node = node(args).expect("node required");
+    let image = args.get_one::<PathBuf>("image").expect("image required");
+    let image = image
+        .canonicalize()
+        .with_context(|| image.display().to_string())?;
+    let length = image.metadata()?.len();
+
+    let mut query = format!(
+        "opt=set&type=flash&node={}&file={}&length={}",
+        node,
+        encode(&image.display().to_string()),
+        length
+    );
+    // bmcd reads the image itself when it runs on the same machine
+    if client.is_local() {
+        query.push_str("&local");
+    }
+    if let Some(sha256) = args.get_one::<String>("sha256") {
+        query.push_str(&format!("&sha256={}", sha256));
+    }
+    if let Some(crc32) = args.get_one::<String>("crc32") {
+        query.push_str(&format!("&crc32={}", crc32));
+    }
+    if args.get_flag("skip-crc") {
+        query.push_str("&skip_crc");
+    }
+    if let Some(target) = args.get_one::<String>("dry-run") {
+        query.push_str(&format!("&dry_run={}", target));
+    }
+    let handle = nested_json(client.legacy(&query).await?);
+    let handle = handle["handle"].as_u64().context("no transfer handle")?;
+
+    if !client.is_local() {
+        let upload = upload(client, handle, &image, length);
+        tokio::select! {
+            result = upload => result?,
+            result = progress(client) => return result,
+        }
+    }
+    progress(client).await
+}
+
+/// Sends the image as multipart form, as the web UI does.
+async fn upload(client: &Client, handle: u64, image: &PathBuf, length: u64) -> anyhow::Result<()> {
+    let head = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"image\"\r\n\
+         Content-Type: application/octet-stream\r\n\r\n",
+        MULTIPART_BOUNDARY
+    );
+    let tail = format!("\r\n--{}--\r\n", MULTIPART_BOUNDARY);
+    let mut connection = client.connect().await?;
+    let headers = [
+        ("Connection", "close".to_string()),
+        (
+            "Content-Type",
+            format!("multipart/form-data; boundary={}", MULTIPART_BOUNDARY),
+        ),
+        (
+            "Content-Length",
+            (head.len() as u64 + length + tail.len() as u64).to_string(),
+        ),
+    ];
+    let path = format!("/api/bmc/upload/{}", handle);
+    client
+        .write_head(&mut connection, "POST", &path, &headers)
+        .await?;
+    let stream = connection.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    let mut file = tokio::fs::File::open(image).await?;
+    tokio::io::copy(&mut file, stream).await?;
+    stream.write_all(tail.as_bytes()).await?;
+    stream.flush().await?;
+    client::read_response(&mut connection).await?.result()?;
+    Ok(())
+}
+
+/// Prints the progress of the transfer until it is done.
+async fn progress(client: &Client) -> anyhow::Result<()> {
+    loop {
+        let state = nested_json(client.legacy("opt=get&type=flash").await?);
+        if let Some(transfer) = state.get("Transferring") {
+            let size = transfer["size"].as_u64().unwrap_or_default().max(1);
+            let written = transfer["bytes_written"].as_u64().unwrap_or_default();
+            eprint!("\rwritten {:>3}%", written * 100 / size);
+        } else if state.get("Done").is_some() {
+            eprintln!("\rwritten 100%");
+            println!("ok");
+            return Ok(());
+        } else if let Some(error) = state.get("Error") {
+            eprintln!();
+            bail!("flashing failed: {}", error.as_str().unwrap_or_default());
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn config(client: &Client, args: &ArgMatches) -> anyhow::Result<()> {
+    let file = |args: &ArgMatches| {
+        args.get_one::<PathBuf>("file")
+            .expect("file required")
+            .clone()
+    };
+    match args.subcommand().expect("subcommand required") {
+        ("status", _) => {
+            let status = client.get("/api/bmc/config").await?;
+            println!("{}", serde_json::to_string_pretty(&status)?);
+        }
+        ("reload", _) => {
+            let status = client.post("/api/bmc/config/reload").await?;
+            println!("{}", serde_json::to_string_pretty(&status)?);
+        }
+        ("export", args) => {
+            let response = client
+                .request("GET", "/api/bmc/config/export", None, &[])
+                .await?;
+            if response.content_type.as_deref() != Some("application/gzip") {
+                response.result()?;
+                bail!("unexpected response");
+            }
+            let path = file(args);
+            std::fs::write(&path, &response.body).with_context(|| path.display().to_string())?;
+            println!("saved {}", path.display());
+        }
+        ("import", args) => {
+            let path = file(args);
+            let archive = std::fs::read(&path).with_context(|| path.display().to_string())?;
+            let response = client
+                .request(
+                    "POST",
+                    "/api/bmc/config/import",
+                    Some("application/gzip"),
+                    &archive,
+                )
+                .await?;
+            println!("{}", serde_json::to_string_pretty(&response.result()?)?);
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Some legacy requests return JSON serialized into a string.
+fn nested_json(value: Value) -> Value {
+    match &value {
+        Value::String(s) => serde_json::from_str(s).unwrap_or(value),
+        _ => value,
+    }
+}
+
+/// Percent-encodes a query parameter value.
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line() {
+        cli().debug_assert();
+        let args = cli()
+            .try_get_matches_from(["bmc", "--host", "turingpi.local", "power", "on", "-n", "2"])
+            .unwrap();
+        assert!(matches!(target(&args), Target::Https { port: 443, .. }));
+        let (_, power) = args.subcommand().unwrap();
+        assert_eq!(node(power), Some(1));
+        assert!(cli()
+            .try_get_matches_from(["bmc", "power", "on", "-n", "5"])
+            .is_err());
+    }
+
+    #[test]
+    fn query_encoding() {
+        assert_eq!(
+            encode("/mnt/sdcard/my image.img"),
+            "/mnt/sdcard/my%20image.img"
+        );
+        assert_eq!(nested_json(Value::from(r#"{"handle":7}"#))["handle"], 7);
+    }
+}
@@ -11,18 +11,34 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+use crate::app::batch::UsbSetting;
+use crate::authentication::roles::Role;
+use crate::hal::board_profile::BoardProfile;
+use crate::utils::{is_valid_hostname, parse_mac_address, IpNetwork, ScopedIp};
+use anyhow::{ensure, Context};
+use chrono::NaiveTime;
 use config::FileFormat;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
-use serde_with::DurationSeconds;
+use serde_with::{DurationMilliSeconds, DurationSeconds};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::Path;
 use std::path::PathBuf;
 use std::time::Duration;
 
+/// Port of the legacy `redirect_http` listener.
+const HTTP_PORT: u16 = 80;
 const DEFAULT_YAML: &str = include_str!("../../default_config.yaml");
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Config {
+    /// Board profile, e.g. `turing_pi_2.4`. Detected from the device-tree
+    /// model when omitted.
+    pub board: Option<String>,
+    /// Board description file that adjusts the profile, see
+    /// [`crate::hal::board_profile::BoardDescription`].
+    pub board_description: Option<PathBuf>,
     pub tls: Tls,
     pub store: Store,
     pub authentication: Authentication,
@@ -30,46 +46,2561 @@ pub struct Config {
     pub port: u16,
     pub www: PathBuf,
     pub redirect_http: bool,
+    /// Addresses on which the API is served. When empty, HTTPS is served on
+    /// `host` and `port`, see [`Config::listeners`].
+    #[serde(default)]
+    pub listeners: Vec<Listener>,
+    /// Unix socket on which the API is served without authentication.
+    #[serde(default)]
+    pub unix_socket: Option<PathBuf>,
     pub log: Log,
+    /// Linux accounts that are permitted to use the API. An empty list permits
+    /// every account that has a password set in `/etc/shadow`.
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Roles of users by account name. Users without a role are operators.
+    #[serde(default)]
+    pub roles: Vec<UserRole>,
+    #[serde(default)]
+    pub nodes: Vec<NodeConfig>,
+    #[serde(default)]
+    pub power: Power,
+    #[serde(default)]
+    pub network: Network,
+    #[serde(default)]
+    pub notifications: Vec<NotificationTarget>,
+    pub backup: Backup,
+    pub mdns: Mdns,
+    /// DHCP server on the node network. Disabled when omitted.
+    pub dhcp: Option<Dhcp>,
+    pub netboot: Netboot,
+    pub nbd: Nbd,
+    /// A/B firmware slots. Upgrades are handed to `osupdate` when omitted.
+    pub firmware: Option<Firmware>,
+    pub firmware_signing: FirmwareSigning,
+    /// Periodic check for new BMC firmware. Disabled when omitted.
+    pub updates: Option<Updates>,
+    pub watchdog: Watchdog,
+    #[serde(default)]
+    pub i2c: I2c,
+    #[serde(default)]
+    pub memory: MemoryLimits,
+    #[serde(default)]
+    pub shutdown: Shutdown,
+    #[serde(default)]
+    pub legacy_api: LegacyApi,
+    #[serde(default)]
+    pub cluster: Cluster,
+    #[serde(default)]
+    pub jobs: Jobs,
+    #[serde(default)]
+    pub activity: Activity,
+    #[serde(default)]
+    pub http: Http,
+    #[serde(default)]
+    pub web_ui: WebUi,
+    /// Console on the USB device port of the BMC. Disabled when omitted.
+    pub usb_console: Option<UsbConsole>,
+    #[serde(default)]
+    pub image_cache: ImageCache,
+    #[serde(default)]
+    pub image_sharing: ImageSharing,
+    #[serde(default)]
+    pub node_agent: NodeAgent,
+    /// Kubernetes cluster that the nodes are part of. Disabled when omitted.
+    pub kubernetes: Option<Kubernetes>,
+    /// Pipelines that CI systems start with a signed webhook. Changes apply
+    /// without a restart.
+    #[serde(default)]
+    pub pipelines: Vec<Pipeline>,
+    #[serde(default)]
+    pub scripting: Scripting,
+    /// Out-of-tree integrations that run as processes next to bmcd.
+    #[serde(default)]
+    pub plugins: Vec<Plugin>,
+    /// Serial consoles of the nodes on plain TCP ports. Disabled when
+    /// omitted.
+    pub tcp_console: Option<TcpConsole>,
+    /// Console output of the nodes mirrored to UDP multicast groups.
+    /// Disabled when omitted.
+    pub console_multicast: Option<ConsoleMulticast>,
+    /// HDMI capture add-ons of the nodes. Disabled when omitted.
+    pub kvm: Option<Kvm>,
+    #[serde(default)]
+    pub provisioning: Provisioning,
+    /// Zero-touch enrollment with a management server. Disabled when
+    /// omitted.
+    pub enrollment: Option<Enrollment>,
+    #[serde(default)]
+    pub storage_health: StorageHealth,
+    #[serde(default)]
+    pub storage: Storage,
+    #[serde(default)]
+    pub retention: Retention,
+    #[serde(default)]
+    pub lights_out: LightsOut,
+    #[serde(default)]
+    pub failover: Failover,
+    #[serde(default)]
+    pub graphql: GraphQl,
+    #[serde(default)]
+    pub console_sharing: ConsoleSharing,
+    #[serde(default)]
+    pub messages: Messages,
 }
 
 #[serde_as]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Store {
     #[serde_as(as = "Option<DurationSeconds<u64>>")]
     pub write_timeout: Option<Duration>,
 }
 
 #[serde_as]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Authentication {
     pub authentication_attempts: usize,
     #[serde_as(as = "DurationSeconds<u64>")]
     pub token_expires: Duration,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct UserRole {
+    pub user: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Tls {
     pub private_key: PathBuf,
     pub certificate: PathBuf,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Listener {
+    /// IPv4 or IPv6 address, `::` accepts connections of both families on
+    /// all interfaces. Link-local addresses need a zone, e.g. `fe80::1%br0`.
+    pub address: ScopedIp,
+    pub port: u16,
+    #[serde(default)]
+    pub scheme: Scheme,
+    /// Certificate of this listener, the `tls` section when omitted.
+    #[serde(default)]
+    pub tls: Option<Tls>,
+    /// HTTPS port to which a `redirect` listener sends clients, the port of
+    /// the first `https` listener when omitted.
+    #[serde(default)]
+    pub redirect_port: Option<u16>,
+}
+
+impl Listener {
+    /// Address on which the BMC itself reaches this listener.
+    pub fn local_address(&self) -> std::io::Result<SocketAddr> {
+        match self.address.ip {
+            IpAddr::V4(address) if address.is_unspecified() => {
+                Ok(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), self.port))
+            }
+            IpAddr::V6(address) if address.is_unspecified() => {
+                Ok(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), self.port))
+            }
+            _ => self.address.socket_addr(self.port),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scheme {
+    #[default]
+    Https,
+    /// Plain HTTP, only sensible on a trusted management network.
+    Http,
+    /// Plain HTTP that redirects every request to HTTPS.
+    Redirect,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Log {
     pub stdout: bool,
     pub directive: String,
     pub coloring: bool,
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// human readable lines
+    #[default]
+    Text,
+    /// one JSON object per line, for log collectors
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Backup {
+    /// HMAC key used to sign and verify configuration archives. A new key is
+    /// generated when the file does not exist.
+    pub signing_key: PathBuf,
+    /// HMAC key shared between boards. When set, archives are signed with
+    /// this key instead, so that they can be imported on every board that
+    /// has it. Archives signed with `signing_key` are still accepted.
+    #[serde(default)]
+    pub shared_key: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Mdns {
+    /// Advertise the API as `_bmcd._tcp` service and discover other boards.
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Netboot {
+    /// Serve the files in `root` over TFTP to boot nodes from the network.
+    pub enabled: bool,
+    pub root: PathBuf,
+    /// Also serve the files over HTTP at `/netboot/`. These files are
+    /// accessible without authentication.
+    pub http: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Nbd {
+    /// Export images to the nodes over the network block device protocol.
+    pub enabled: bool,
+    /// Addresses or networks (`10.0.0.0/24`) that may connect. Exports are
+    /// not authenticated, so this list may not be empty when enabled.
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Firmware {
+    /// Devices of slot A and B. UBI volumes are written with `ubiupdatevol`,
+    /// other devices are written raw.
+    pub slots: [PathBuf; 2],
+    /// Time a new firmware gets to become healthy before it is rolled back.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_confirm_timeout")]
+    pub confirm_timeout: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct FirmwareSigning {
+    /// Directory with PEM encoded public keys. Firmware images must be signed
+    /// by one of these keys, unless the upgrade is confirmed by a button
+    /// press on the board.
+    pub trusted_keys: PathBuf,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Updates {
+    /// URL of the JSON release feed.
+    pub feed: String,
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_check_interval")]
+    pub interval: Duration,
+    /// Directory where downloaded updates are staged.
+    #[serde(default = "default_staging_dir")]
+    pub staging_dir: PathBuf,
+    /// Staged updates are applied automatically during this window. Staged
+    /// updates wait for a manual upgrade when omitted.
+    pub maintenance: Option<MaintenanceWindow>,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MaintenanceWindow {
+    /// Local time at which the window opens, formatted as `HH:MM`.
+    pub start: String,
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_maintenance_duration")]
+    pub duration: Duration,
+    /// Only apply updates when all nodes are powered off.
+    #[serde(default)]
+    pub require_nodes_off: bool,
+}
+
+impl MaintenanceWindow {
+    pub fn start_time(&self) -> anyhow::Result<NaiveTime> {
+        NaiveTime::parse_from_str(&self.start, "%H:%M")
+            .map_err(|e| anyhow::anyhow!("updates.maintenance.start `{}`: {}", self.start, e))
+    }
+}
+
+fn default_maintenance_duration() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+fn default_check_interval() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+fn default_staging_dir() -> PathBuf {
+    PathBuf::from("/var/lib/bmcd/updates")
+}
+
+fn default_confirm_timeout() -> Duration {
+    Duration::from_secs(300)
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Watchdog {
+    /// Feed the hardware watchdog while bmcd is healthy.
+    pub enabled: bool,
+    pub device: PathBuf,
+    /// The board resets when the watchdog is not fed within this time.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub timeout: Duration,
+    /// Subsystems that must be healthy for the watchdog to be fed.
+    pub require: Vec<WatchdogCheck>,
+}
+
+/// Devices that can be accessed through the `/i2c` routes.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct I2c {
+    /// An empty list disables the routes.
+    #[serde(default)]
+    pub allow: Vec<I2cDevice>,
+    /// Every write is appended to this file as JSON line.
+    #[serde(default = "default_i2c_audit_log")]
+    pub audit_log: PathBuf,
+}
+
+impl Default for I2c {
+    fn default() -> Self {
+        Self {
+            allow: Vec::new(),
+            audit_log: default_i2c_audit_log(),
+        }
+    }
+}
+
+fn default_i2c_audit_log() -> PathBuf {
+    PathBuf::from("/var/log/bmcd-i2c-audit.log")
+}
+
+/// Caps in bytes on the buffers that subsystems hold for clients, see
+/// [`crate::utils::memory`]. 0 disables a cap.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct MemoryLimits {
+    /// console output queued for websocket clients
+    pub console: usize,
+    /// notifications that are being delivered
+    pub events: usize,
+    /// buffers that flash images are written from
+    pub flash_staging: usize,
+}
+
+impl Default for MemoryLimits {
+    fn default() -> Self {
+        Self {
+            console: 2 * 1024 * 1024,
+            events: 512 * 1024,
+            flash_staging: 16 * 1024 * 1024,
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Shutdown {
+    /// How long a stop or restart waits for a running flash before it is
+    /// cancelled.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub drain_timeout: Duration,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            drain_timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Jobs {
+    /// How long a finished job and its result are kept.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub retention: Duration,
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        Self {
+            retention: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Inference of whether powered nodes are running from their current draw,
+/// see `app::activity`. Disabled when no sensors are listed.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Activity {
+    pub sensors: Vec<CurrentSensor>,
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub interval: Duration,
+    /// A node whose draw stays within `tolerance` for this long is considered
+    /// stalled.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub stall_after: Duration,
+    /// in mA
+    pub tolerance: u32,
+    /// Powered nodes that draw less than this, in mA, are not running.
+    pub min_running: u32,
+    /// Reset a node once when it stalls.
+    pub reset_stalled: bool,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self {
+            sensors: Vec::new(),
+            interval: Duration::from_secs(2),
+            stall_after: Duration::from_secs(120),
+            tolerance: 10,
+            min_running: 50,
+            reset_stalled: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CurrentSensor {
+    pub node: u8,
+    /// hwmon attribute that reads the current in mA, e.g.
+    /// `/sys/class/hwmon/hwmon3/curr1_input`.
+    pub path: PathBuf,
+}
+
+/// The query-parameter API of firmware <= 2.0.0, see `api::legacy`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct LegacyApi {
+    /// Requests are answered with `410 Gone` when false.
+    pub enabled: bool,
+}
+
+impl Default for LegacyApi {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+/// Settings for running behind a reverse proxy and for web applications on
+/// other origins.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Http {
+    /// Prefix under which a reverse proxy forwards requests without removing
+    /// it, e.g. `/bmc`. The prefix is stripped from request paths; requests
+    /// without it are served as well.
+    pub base_path: Option<String>,
+    /// Addresses or networks (`10.0.0.0/24`) of proxies whose `Forwarded` and
+    /// `X-Forwarded-For` headers are trusted to carry the client address.
+    pub trusted_proxies: Vec<String>,
+    pub cors: Cors,
+}
+
+/// Cross-origin requests to the API, refused by browsers unless the origin
+/// is listed.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Cors {
+    /// Origins such as `https://dashboard.lab:8443`, or `*` for any origin.
+    pub allowed_origins: Vec<String>,
+    /// Let browsers send the bearer token or basic credentials along.
+    pub allow_credentials: bool,
+    /// How long browsers may cache the answer to a preflight request.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub max_age: Duration,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allow_credentials: false,
+            max_age: Duration::from_secs(600),
+        }
+    }
+}
+
+/// The USB device port of the BMC as a composite gadget, to manage a board
+/// with broken networking from a laptop over a single cable.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct UsbConsole {
+    /// CDC-ACM serial port with a login prompt of the BMC.
+    pub serial: bool,
+    /// CDC-ECM network interface on which the API is served.
+    pub network: bool,
+    /// Address of the BMC in the /30 network on the cable. The laptop is
+    /// handed the next address over DHCP.
+    pub address: Ipv4Addr,
+}
+
+impl Default for UsbConsole {
+    fn default() -> Self {
+        Self {
+            serial: true,
+            network: true,
+            address: Ipv4Addr::new(172, 31, 255, 1),
+        }
+    }
+}
+
+/// Serving of the web UI in `www`.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct WebUi {
+    /// Serve `index.html` for paths without a file extension that do not
+    /// exist, so the routes of a single-page application can be reloaded.
+    pub spa_fallback: bool,
+    /// Serve `<file>.br` or `<file>.gz` next to a file to clients that accept
+    /// the encoding.
+    pub precompressed: bool,
+    /// How long browsers may cache assets without a content hash in their
+    /// name. Fingerprinted assets are cached for a year, `index.html` is
+    /// always revalidated.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub max_age: Duration,
+}
+
+impl Default for WebUi {
+    fn default() -> Self {
+        Self {
+            spa_fallback: true,
+            precompressed: true,
+            max_age: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Other boards that are managed through this one under `/cluster`.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Cluster {
+    pub peers: Vec<ClusterPeer>,
+    /// Timeout of the requests to the peers that are combined into a single
+    /// response.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub timeout: Duration,
+}
+
+impl Default for Cluster {
+    fn default() -> Self {
+        Self {
+            peers: Vec::new(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Node images kept on the BMC after they were flashed from a URL, see
+/// `app::image_cache`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ImageCache {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    /// Number of images kept at most, the least recently used are removed
+    /// first.
+    pub max_images: usize,
+    /// Total size of the images in bytes at most, 0 for no limit other than
+    /// the free space.
+    pub max_size: u64,
+}
+
+impl Default for ImageCache {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: PathBuf::from("/var/lib/bmcd/images/cache"),
+            max_images: 4,
+            max_size: 0,
+        }
+    }
+}
+
+/// Fetching node images from other boards on the network, see
+/// `app::image_sharing`.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ImageSharing {
+    /// Download images with a known SHA-256 from peers that have them, in
+    /// ranges of `chunk_size` bytes from up to `max_peers` peers at once.
+    pub fetch: bool,
+    /// Serve the images of the image cache to peers at
+    /// `/images/sha256/<digest>`, without authentication.
+    pub serve: bool,
+    pub chunk_size: u64,
+    pub max_peers: usize,
+    /// Time a peer gets to answer whether it has an image.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub lookup_timeout: Duration,
+}
+
+impl Default for ImageSharing {
+    fn default() -> Self {
+        Self {
+            fetch: false,
+            serve: false,
+            chunk_size: 2 * 1024 * 1024,
+            max_peers: 4,
+            lookup_timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+/// Agents running in the OS of the nodes that talk to bmcd over the node
+/// UART, see `app::node_agent`.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct NodeAgent {
+    /// Listen for agent frames on the node UARTs and allow requests to them.
+    pub enabled: bool,
+    /// Time an agent gets to answer a request.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub timeout: Duration,
+}
+
+impl Default for NodeAgent {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Cordoning and draining of the Kubernetes nodes that run on the node
+/// modules around power cycles, see `app::kubernetes`.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Kubernetes {
+    /// URL of the API server, e.g. `https://10.0.0.21:6443`.
+    pub api_server: String,
+    /// Bearer token of a service account that may patch nodes, list pods and
+    /// create evictions.
+    pub token: String,
+    /// PEM certificate of the cluster CA.
+    pub ca_certificate: Option<PathBuf>,
+    #[serde(default)]
+    pub insecure: bool,
+    /// Slots that run a Kubernetes node. Slots that are not listed are power
+    /// cycled as usual.
+    pub nodes: Vec<KubernetesNode>,
+    /// Time the pods of a node get to be evicted.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: Duration,
+    /// Power the node off anyway when draining fails.
+    #[serde(default)]
+    pub force: bool,
+    /// Time a node gets to report `Ready` after it was powered on, before it
+    /// is left cordoned.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_ready_timeout")]
+    pub ready_timeout: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct KubernetesNode {
+    /// slot number, 1 to 4.
+    pub node: u8,
+    /// name of the Kubernetes node
+    pub name: String,
+}
+
+/// Scripts that users install to react to notifications or to run with the
+/// API, see `app::scripting`.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Scripting {
+    pub enabled: bool,
+    /// Time a script may run, including its sleeps.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub timeout: Duration,
+    /// Operations a script may run, which bounds the CPU time it takes.
+    pub max_operations: u64,
+}
+
+impl Default for Scripting {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            timeout: Duration::from_secs(30),
+            max_operations: 1_000_000,
+        }
+    }
+}
+
+/// Program that adds a protocol frontend or a sensor backend without
+/// changes to bmcd. It talks to bmcd over its standard input and output, see
+/// `app::plugins`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Plugin {
+    pub name: String,
+    /// absolute path of the program
+    pub command: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// `NAME=value` pairs. Plugins inherit no environment of bmcd.
+    #[serde(default)]
+    pub env: Vec<String>,
+    /// User and group that the plugin runs as, `nobody` by default.
+    #[serde(default = "default_plugin_id")]
+    pub uid: u32,
+    #[serde(default = "default_plugin_id")]
+    pub gid: u32,
+    /// Requests the plugin may make besides reading the power state.
+    #[serde(default)]
+    pub permissions: Vec<PluginPermission>,
+    /// Start the plugin with bmcd, rather than with the API.
+    #[serde(default = "default_true")]
+    pub autostart: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PluginPermission {
+    /// power nodes on and off and reset them
+    Power,
+    /// route the USB bus of nodes
+    Usb,
+    /// receive the notifications that bmcd sends
+    Events,
+}
+
+fn default_plugin_id() -> u32 {
+    65534
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Raw TCP listeners that forward to the serial consoles of the nodes, for
+/// `nc`, `telnet` or conserver, see `serial_service::tcp_console`.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TcpConsole {
+    pub ports: Vec<TcpConsolePort>,
+    /// Wrap the connections in TLS with the certificate of the `tls`
+    /// section.
+    #[serde(default)]
+    pub tls: bool,
+    /// Addresses or networks (`10.0.0.0/24`) that may connect. The consoles
+    /// are not authenticated, so this list may not be empty.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Close connections without traffic in either direction for this
+    /// long, 0 to keep them open.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_console_idle_timeout")]
+    pub idle_timeout: Duration,
+    /// Clients that may be connected to the console of one node at once.
+    #[serde(default = "default_console_connections")]
+    pub max_connections: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TcpConsolePort {
+    /// node number, starting from 1
+    pub node: u8,
+    pub port: u16,
+}
+
+/// Mirrors the console output of the nodes to UDP multicast groups, for log
+/// collectors in lab networks, see `serial_service::console_multicast`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ConsoleMulticast {
+    pub groups: Vec<ConsoleMulticastGroup>,
+    /// Routers a datagram may cross, 1 keeps it on the local network.
+    #[serde(default = "default_multicast_ttl")]
+    pub ttl: u32,
+    /// Interface the datagrams leave on, by name. The routing table decides
+    /// when omitted.
+    #[serde(default)]
+    pub interface: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ConsoleMulticastGroup {
+    /// node number, starting from 1
+    pub node: u8,
+    /// multicast address and port, e.g. `239.255.42.1:5001`
+    pub group: SocketAddr,
+}
+
+fn default_multicast_ttl() -> u32 {
+    1
+}
+
+fn default_console_idle_timeout() -> Duration {
+    Duration::from_secs(3600)
+}
+
+fn default_console_connections() -> usize {
+    4
+}
+
+/// KVM over IP with USB HDMI capture dongles, see `app::kvm`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Kvm {
+    pub nodes: Vec<KvmNode>,
+    /// Program and arguments that write the MJPEG frames of the capture
+    /// device `{device}` to their standard output.
+    #[serde(default = "default_capture_command")]
+    pub capture_command: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct KvmNode {
+    /// node number, starting from 1
+    pub node: u8,
+    /// USB port of the capture dongle that is wired to the HDMI output of the
+    /// node, as named in `/sys/bus/usb/devices`, e.g. `1-1.2`
+    pub usb_port: String,
+}
+
+fn default_capture_command() -> Vec<String> {
+    [
+        "ffmpeg",
+        "-hide_banner",
+        "-loglevel",
+        "error",
+        "-f",
+        "v4l2",
+        "-input_format",
+        "mjpeg",
+        "-i",
+        "{device}",
+        "-c:v",
+        "copy",
+        "-f",
+        "mjpeg",
+        "-",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+/// First-boot setup, see `app::provisioning`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Provisioning {
+    /// When false, a BMC without a provisioned flag serves the API right
+    /// away with the credentials that shipped with the firmware.
+    pub enabled: bool,
+}
+
+impl Default for Provisioning {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+/// Enrollment of a new BMC with a management server, see `app::enrollment`.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Enrollment {
+    /// HTTPS URL to which the BMC posts its token, answered with its
+    /// configuration bundle.
+    pub url: String,
+    /// Files that may hold the one-time token, the first one that exists is
+    /// used. The defaults are a file on the SD card and the file that the
+    /// DHCP client hook writes.
+    #[serde(default = "default_token_files")]
+    pub token_files: Vec<PathBuf>,
+    /// PEM certificate of the CA that signed the certificate of the server,
+    /// trusted in addition to the system roots.
+    pub ca_certificate: Option<PathBuf>,
+    /// Time between attempts while no token is found or the server cannot
+    /// be reached.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_enrollment_retry")]
+    pub retry_interval: Duration,
+}
+
+fn default_token_files() -> Vec<PathBuf> {
+    vec![
+        PathBuf::from("/mnt/sdcard/bmcd/enrollment-token"),
+        PathBuf::from("/run/bmcd/enrollment-token"),
+    ]
+}
+
+fn default_enrollment_retry() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// Wear monitoring of the eMMC and SD card of the BMC, see
+/// `app::storage_health`.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct StorageHealth {
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub interval: Duration,
+    /// used life time in percent that raises a `storage_wear_warning`
+    pub warning: u8,
+    /// used life time in percent that raises a `storage_wear_critical`
+    pub critical: u8,
+}
+
+impl Default for StorageHealth {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(3600),
+            warning: 80,
+            critical: 100,
+        }
+    }
+}
+
+/// Storage of the BMC and the quotas of the features that keep data on it,
+/// see `app::storage_manager`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Storage {
+    /// backups, and the staging files of node clones
+    pub backup_dir: PathBuf,
+    pub quotas: Quotas,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            backup_dir: PathBuf::from("/mnt/sdcard/bmcd/backups"),
+            quotas: Quotas::default(),
+        }
+    }
+}
+
+/// In bytes, 0 for no limit other than the free space.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Quotas {
+    /// images in `/var/lib/bmcd/images`, including the image cache
+    pub images: u64,
+    pub backups: u64,
+}
+
+/// How long the files that bmcd writes to flash are kept, see
+/// `app::retention`.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Retention {
+    /// how often the policies are applied
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub interval: Duration,
+    /// the i2c audit log and its rotated files
+    pub audit_logs: RetentionPolicy,
+    pub crash_reports: RetentionPolicy,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        const DAY: u64 = 24 * 60 * 60;
+        Self {
+            interval: Duration::from_secs(600),
+            audit_logs: RetentionPolicy {
+                max_size: 4 * 1024 * 1024,
+                max_age: Some(Duration::from_secs(365 * DAY)),
+                rotate_size: 1024 * 1024,
+            },
+            crash_reports: RetentionPolicy::default(),
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// In bytes, 0 for no limit. The oldest files are removed first.
+    pub max_size: u64,
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub max_age: Option<Duration>,
+    /// Size in bytes at which a log that bmcd appends to is rotated, 0 to
+    /// never rotate it.
+    pub rotate_size: u64,
+}
+
+/// Low-power mode of the board while all nodes are off, see
+/// `app::lights_out`.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct LightsOut {
+    pub enabled: bool,
+    /// how long all nodes must be off without API requests
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub idle_after: Duration,
+    /// switches off all LEDs, including the ones driven by kernel triggers
+    pub leds: bool,
+    /// sysfs devices that are allowed to suspend, e.g. the USB hub or UARTs
+    /// that no node uses
+    pub devices: Vec<PathBuf>,
+    /// monitors poll this many times less often
+    pub poll_factor: u32,
+    /// requests that do not wake up the board, e.g. of a metrics scraper
+    pub passive_paths: Vec<String>,
+}
+
+impl Default for LightsOut {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            idle_after: Duration::from_secs(300),
+            leds: true,
+            devices: vec![PathBuf::from("/sys/bus/usb/devices/usb1")],
+            poll_factor: 10,
+            passive_paths: vec![
+                "/api/bmc/metrics".to_string(),
+                "/api/bmc/lights-out".to_string(),
+            ],
+        }
+    }
+}
+
+/// Recovery of nodes that stop answering health probes, see
+/// `app::failover`. Disabled when no nodes are listed.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Failover {
+    pub nodes: Vec<FailoverNode>,
+    /// time between two probes of a node
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub interval: Duration,
+    /// time a probe gets to succeed
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub timeout: Duration,
+}
+
+impl Default for Failover {
+    fn default() -> Self {
+        Self {
+            nodes: Vec::new(),
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct FailoverNode {
+    pub node: u8,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    pub probe: Probe,
+    /// Probes must fail this long before the next step of the playbook
+    /// runs. Must cover the time the node takes to boot.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_fail_after")]
+    pub fail_after: Duration,
+    /// Minimum time between the start of two recoveries of the node. A node
+    /// that fails again sooner is only reported.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_cooldown")]
+    pub cooldown: Duration,
+    /// Steps in order of escalation.
+    #[serde(default = "default_playbook")]
+    pub playbook: Vec<RecoveryStep>,
+}
+
+fn default_fail_after() -> Duration {
+    Duration::from_secs(120)
+}
+
+fn default_cooldown() -> Duration {
+    Duration::from_secs(1800)
+}
+
+fn default_playbook() -> Vec<RecoveryStep> {
+    vec![
+        RecoveryStep::Warn,
+        RecoveryStep::Shutdown,
+        RecoveryStep::PowerCycle,
+        RecoveryStep::PowerOff,
+    ]
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Probe {
+    /// the agent in the OS of the node answers a query, see
+    /// `app::node_agent`
+    Agent,
+    /// a TCP connection to `host:port` is accepted, e.g. to SSH of the node
+    Tcp(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecoveryStep {
+    /// sends a `node_unreachable` notification
+    Warn,
+    /// asks the node agent to shut the OS down cleanly
+    Shutdown,
+    PowerCycle,
+    /// powers the node off and leaves it off
+    PowerOff,
+}
+
+/// Read-only GraphQL queries over the inventory, sensors, events and jobs,
+/// see `app::graphql`. Changes apply without a restart.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct GraphQl {
+    pub enabled: bool,
+    /// Deepest nesting of selections, and of lists and objects in arguments
+    /// and variable types, in a query.
+    pub max_depth: usize,
+}
+
+impl Default for GraphQl {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_depth: 8,
+        }
+    }
+}
+
+/// Links that give read-only access to the console of one node without an
+/// account, see `app::console_shares`. Changes apply without a restart.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ConsoleSharing {
+    pub enabled: bool,
+    /// Lifetime of a link when the operator does not pick one.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub default_ttl: Duration,
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub max_ttl: Duration,
+    /// Links that may be active at once.
+    pub max_shares: usize,
+}
+
+impl Default for ConsoleSharing {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            default_ttl: Duration::from_secs(60 * 60),
+            max_ttl: Duration::from_secs(24 * 60 * 60),
+            max_shares: 16,
+        }
+    }
+}
+
+/// Translations of the message catalog, see `app::messages`. Changes apply
+/// without a restart.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Messages {
+    /// Directory of `<language>.json` files, e.g. `de.json`.
+    pub directory: PathBuf,
+}
+
+impl Default for Messages {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("/etc/bmcd/messages"),
+        }
+    }
+}
+
+/// Steps that run when a CI system posts an event to `/hooks/<name>`, see
+/// `app::pipelines`. Values of the event are addressed with JSON pointers,
+/// e.g. `/image/url`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Pipeline {
+    pub name: String,
+    /// Key of the HMAC-SHA256 of the request body that the CI system sends
+    /// as `X-Hub-Signature-256: sha256=<hex>`.
+    pub secret: String,
+    /// Pointer to the URL of the image that `flash` steps write.
+    pub image_url: Option<String>,
+    /// Pointer to the SHA-256 of the image, checked while flashing.
+    pub image_sha256: Option<String>,
+    /// Values the event must have; other events are accepted and ignored.
+    #[serde(default)]
+    pub when: Vec<EventCondition>,
+    pub steps: Vec<PipelineStep>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct EventCondition {
+    pub pointer: String,
+    pub equals: serde_json::Value,
+}
+
+/// Step of a pipeline.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum PipelineStep {
+    /// Writes the image at `url` to the node and waits for the flash to
+    /// finish. Webhook pipelines flash the image of their event when `url` is
+    /// omitted.
+    Flash {
+        /// node number, starting from 1
+        node: u8,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        url: Option<String>,
+        /// hex SHA-256 of the image, checked while flashing
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sha256: Option<String>,
+    },
+    Power {
+        /// node number, starting from 1
+        node: u8,
+        on: bool,
+    },
+    Reset {
+        /// node number, starting from 1
+        node: u8,
+    },
+    Usb {
+        /// node number, starting from 1
+        node: u8,
+        mode: UsbSetting,
+        /// route the USB bus to the BMC instead of the USB-A port
+        #[serde(default)]
+        bmc: bool,
+    },
+    /// Waits until the node prints `pattern` on its console, e.g. a login
+    /// prompt. Fails after `timeout` seconds.
+    WaitConsole {
+        /// node number, starting from 1
+        node: u8,
+        pattern: String,
+        #[serde(default = "default_console_timeout")]
+        timeout: u64,
+    },
+    /// Waits `ms` milliseconds, e.g. for a node to boot.
+    Delay { ms: u64 },
+}
+
+impl PipelineStep {
+    pub fn node(&self) -> Option<u8> {
+        match self {
+            PipelineStep::Flash { node, .. }
+            | PipelineStep::Power { node, .. }
+            | PipelineStep::Reset { node }
+            | PipelineStep::Usb { node, .. }
+            | PipelineStep::WaitConsole { node, .. } => Some(*node),
+            PipelineStep::Delay { .. } => None,
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(node) = self.node() {
+            ensure!((1..=4).contains(&node), "node {} does not exist", node);
+        }
+        match self {
+            PipelineStep::Flash { url, sha256, .. } => {
+                if let Some(url) = url {
+                    reqwest::Url::parse(url).with_context(|| format!("url `{}`", url))?;
+                }
+                if let Some(sha256) = sha256 {
+                    ensure!(
+                        sha256.len() == 64 && sha256.chars().all(|c| c.is_ascii_hexdigit()),
+                        "`{}` is not a SHA-256",
+                        sha256
+                    );
+                }
+            }
+            PipelineStep::WaitConsole {
+                pattern, timeout, ..
+            } => {
+                ensure!(!pattern.is_empty(), "wait_console has no pattern");
+                ensure!(*timeout > 0, "wait_console timeout must be greater than 0");
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn default_console_timeout() -> u64 {
+    300
+}
+
+fn default_drain_timeout() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_ready_timeout() -> Duration {
+    Duration::from_secs(600)
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ClusterPeer {
+    pub name: String,
+    /// Base URL of the peer, e.g. `https://10.0.0.12`.
+    pub url: String,
+    /// Account on the peer. Requests that are proxied to the peer run with
+    /// the permissions of this account.
+    pub user: String,
+    pub password: String,
+    /// Accept the self-signed certificate that boards ship with.
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct I2cDevice {
+    pub bus: u32,
+    pub address: u16,
+    /// Registers can only be read when false.
+    #[serde(default)]
+    pub writable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchdogCheck {
+    /// the HTTPS API accepts connections
+    Api,
+    /// the flash service responds
+    FlashService,
+    /// the GPIO lines of the board can be read
+    Hal,
+}
+
+/// Settings of the DHCP server that hands out fixed addresses to the nodes.
+/// Only clients listed in `leases` are answered.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Dhcp {
+    /// Network interface the server is bound to.
+    pub interface: String,
+    /// Address of the BMC on the node network.
+    pub server_address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub router: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub dns: Vec<Ipv4Addr>,
+    #[serde_as(as = "DurationSeconds<u64>")]
+    #[serde(default = "default_lease_time")]
+    pub lease_time: Duration,
+    pub leases: Vec<DhcpLease>,
+}
+
+fn default_lease_time() -> Duration {
+    Duration::from_secs(3600)
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DhcpLease {
+    /// slot number, 1 to 4.
+    pub node: u8,
+    pub mac: String,
+    pub address: Ipv4Addr,
+}
+
+/// Declarative description of the module inserted in a given slot. Values
+/// that are set overwrite the node info that is stored in persistency.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct NodeConfig {
+    /// slot number, 1 to 4.
+    pub node: u8,
+    pub name: Option<String>,
+    pub module_name: Option<String>,
+    pub uart_baud: Option<u32>,
+}
+
+/// Describes what happens to the node power states on startup of the daemon.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerRestorePolicy {
+    /// Restore the power states as they were before the BMC went down.
+    #[default]
+    Restore,
+    AlwaysOn,
+    AlwaysOff,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct Power {
+    #[serde(default)]
+    pub restore_policy: PowerRestorePolicy,
+    #[serde(default)]
+    pub brownout: Brownout,
+}
+
+/// What bmcd does when the supply of the board sags, detected by the ATX
+/// power supply dropping PS_OK or by an input voltage below `min_voltage`.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Brownout {
+    /// hwmon attribute with the input voltage in mV, e.g.
+    /// `/sys/class/hwmon/hwmon2/in1_input`
+    pub voltage_sensor: Option<PathBuf>,
+    /// in mV
+    pub min_voltage: u32,
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub interval: Duration,
+    /// Write the persistency to disk and record the node power states.
+    pub snapshot: bool,
+    /// Nodes to power off, first to last. Empty leaves the nodes on.
+    pub shutdown_order: Vec<u8>,
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub shutdown_delay: Duration,
+}
+
+impl Default for Brownout {
+    fn default() -> Self {
+        Self {
+            voltage_sensor: None,
+            min_voltage: 11_400,
+            interval: Duration::from_millis(100),
+            snapshot: true,
+            shutdown_order: Vec::new(),
+            shutdown_delay: Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct Network {
+    /// When set, the hostname of the BMC is changed to this value.
+    pub hostname: Option<String>,
+}
+
+/// A HTTP endpoint that receives a JSON POST for every notification bmcd
+/// emits.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct NotificationTarget {
+    pub name: String,
+    pub url: String,
+}
+
+impl Dhcp {
+    fn validate(&self) -> anyhow::Result<()> {
+        let network = u32::from(self.server_address) & u32::from(self.netmask);
+        let mut addresses = HashSet::new();
+        for lease in &self.leases {
+            ensure!(
+                (1..=4).contains(&lease.node),
+                "dhcp: node {} is out of range 1..4",
+                lease.node
+            );
+            ensure!(
+                parse_mac_address(&lease.mac).is_some(),
+                "dhcp: `{}` is not a valid MAC address",
+                lease.mac
+            );
+            ensure!(
+                u32::from(lease.address) & u32::from(self.netmask) == network
+                    && lease.address != self.server_address,
+                "dhcp: {} is not a valid address for node {}",
+                lease.address,
+                lease.node
+            );
+            ensure!(
+                addresses.insert(lease.address),
+                "dhcp: {} is assigned more than once",
+                lease.address
+            );
+        }
+        Ok(())
+    }
 }
 
 impl Config {
     pub fn load(config_file: &Path) -> anyhow::Result<Self> {
+        let format = match config_file.extension().and_then(|e| e.to_str()) {
+            Some("toml") => FileFormat::Toml,
+            _ => FileFormat::Yaml,
+        };
+
         let config = config::Config::builder()
             .add_source(config::File::from_str(DEFAULT_YAML, FileFormat::Yaml))
-            .add_source(
-                config::File::new(&config_file.to_string_lossy(), FileFormat::Yaml).required(false),
-            )
+            .add_source(config::File::new(&config_file.to_string_lossy(), format).required(false))
             .build()?;
 
+        let config: Config = config.try_deserialize()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The built-in defaults, without any configuration file.
+    pub fn defaults() -> anyhow::Result<Self> {
+        let config = config::Config::builder()
+            .add_source(config::File::from_str(DEFAULT_YAML, FileFormat::Yaml))
+            .build()?;
         Ok(config.try_deserialize()?)
     }
+
+    /// Semantic checks on top of the deserialization. An error here means the
+    /// configuration cannot be applied.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(board) = &self.board {
+            ensure!(
+                BoardProfile::by_name(board).is_some(),
+                "board: unknown board `{}`",
+                board
+            );
+        }
+        ensure!(
+            self.authentication.authentication_attempts > 0,
+            "authentication.authentication_attempts must be greater than 0"
+        );
+        let mut users = HashSet::new();
+        for role in &self.roles {
+            ensure!(
+                users.insert(&role.user),
+                "roles: user `{}` has more than one role",
+                role.user
+            );
+        }
+
+        let mut seen = HashSet::new();
+        for node in &self.nodes {
+            ensure!(
+                (1..=4).contains(&node.node),
+                "nodes: node {} is out of range 1..4",
+                node.node
+            );
+            ensure!(
+                seen.insert(node.node),
+                "nodes: node {} is declared more than once",
+                node.node
+            );
+        }
+
+        let brownout = &self.power.brownout;
+        ensure!(
+            !brownout.interval.is_zero(),
+            "power.brownout.interval must be greater than 0"
+        );
+        let mut seen = HashSet::new();
+        for node in &brownout.shutdown_order {
+            ensure!(
+                (1..=4).contains(node),
+                "power.brownout.shutdown_order: node {} is out of range 1..4",
+                node
+            );
+            ensure!(
+                seen.insert(*node),
+                "power.brownout.shutdown_order: node {} is listed more than once",
+                node
+            );
+        }
+
+        if let Some(base_path) = &self.http.base_path {
+            ensure!(
+                base_path.len() > 1 && base_path.starts_with('/') && !base_path.ends_with('/'),
+                "http.base_path must start and must not end with `/`"
+            );
+        }
+        for proxy in &self.http.trusted_proxies {
+            ensure!(
+                proxy.parse::<IpNetwork>().is_ok(),
+                "http.trusted_proxies: `{}` is not an address or network",
+                proxy
+            );
+        }
+        for origin in &self.http.cors.allowed_origins {
+            if origin == "*" {
+                ensure!(
+                    !self.http.cors.allow_credentials,
+                    "http.cors: credentials cannot be allowed for any origin"
+                );
+                continue;
+            }
+            let valid = reqwest::Url::parse(origin)
+                .is_ok_and(|url| url.origin().ascii_serialization() == *origin);
+            ensure!(
+                valid,
+                "http.cors: `{}` is not an origin such as `https://example.com`",
+                origin
+            );
+        }
+
+        if let Some(console) = &self.usb_console {
+            ensure!(
+                u32::from(console.address) & 0b11 == 1,
+                "usb_console.address must be the first address of a /30 network"
+            );
+        }
+
+        let sharing = &self.image_sharing;
+        ensure!(
+            sharing.chunk_size > 0 && sharing.max_peers > 0,
+            "image_sharing.chunk_size and max_peers must be greater than 0"
+        );
+        ensure!(
+            !sharing.serve || self.image_cache.enabled,
+            "image_sharing.serve requires the image cache to be enabled"
+        );
+        ensure!(
+            !self.node_agent.timeout.is_zero(),
+            "node_agent.timeout must be greater than 0"
+        );
+        if let Some(kubernetes) = &self.kubernetes {
+            reqwest::Url::parse(&kubernetes.api_server).context("kubernetes.api_server")?;
+            for node in &kubernetes.nodes {
+                ensure!(
+                    (1..=4).contains(&node.node),
+                    "kubernetes.nodes: node {} does not exist",
+                    node.node
+                );
+            }
+        }
+        ensure!(
+            !self.scripting.timeout.is_zero() && self.scripting.max_operations > 0,
+            "scripting.timeout and max_operations must be greater than 0"
+        );
+        let mut plugins = HashSet::new();
+        for plugin in &self.plugins {
+            ensure!(
+                !plugin.name.is_empty()
+                    && plugin
+                        .name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+                "plugins: `{}` is not a valid name, use [a-zA-Z0-9_-]",
+                plugin.name
+            );
+            ensure!(
+                plugins.insert(&plugin.name),
+                "plugins: `{}` is configured twice",
+                plugin.name
+            );
+            ensure!(
+                plugin.command.is_absolute(),
+                "plugins: `{}`: command must be an absolute path",
+                plugin.name
+            );
+            for pair in &plugin.env {
+                ensure!(
+                    pair.split_once('=')
+                        .is_some_and(|(name, _)| !name.is_empty()),
+                    "plugins: `{}`: `{}` is not NAME=value",
+                    plugin.name,
+                    pair
+                );
+            }
+        }
+        if self.nbd.enabled {
+            ensure!(
+                !self.nbd.allow.is_empty(),
+                "nbd.allow must list the clients that may connect"
+            );
+        }
+        for network in &self.nbd.allow {
+            ensure!(
+                network.parse::<IpNetwork>().is_ok(),
+                "nbd.allow: `{}` is not an address or network",
+                network
+            );
+        }
+        if let Some(console) = &self.tcp_console {
+            let mut ports = HashSet::new();
+            for port in &console.ports {
+                ensure!(
+                    (1..=4).contains(&port.node),
+                    "tcp_console: node {} does not exist",
+                    port.node
+                );
+                ensure!(
+                    port.port != 0 && ports.insert(port.port),
+                    "tcp_console: port {} is zero or configured twice",
+                    port.port
+                );
+            }
+            ensure!(
+                !console.allow.is_empty(),
+                "tcp_console.allow must list the clients that may connect"
+            );
+            for network in &console.allow {
+                ensure!(
+                    network.parse::<IpNetwork>().is_ok(),
+                    "tcp_console.allow: `{}` is not an address or network",
+                    network
+                );
+            }
+            ensure!(
+                console.max_connections > 0,
+                "tcp_console.max_connections must be greater than 0"
+            );
+        }
+        if let Some(multicast) = &self.console_multicast {
+            let mut groups = HashSet::new();
+            for group in &multicast.groups {
+                ensure!(
+                    (1..=4).contains(&group.node),
+                    "console_multicast: node {} does not exist",
+                    group.node
+                );
+                ensure!(
+                    group.group.ip().is_multicast() && group.group.port() != 0,
+                    "console_multicast: {} is not a multicast address and port",
+                    group.group
+                );
+                ensure!(
+                    groups.insert(group.group),
+                    "console_multicast: group {} is configured twice",
+                    group.group
+                );
+            }
+            ensure!(
+                (1..=255).contains(&multicast.ttl),
+                "console_multicast.ttl must be between 1 and 255"
+            );
+        }
+        if let Some(kvm) = &self.kvm {
+            let mut nodes = HashSet::new();
+            for node in &kvm.nodes {
+                ensure!(
+                    (1..=4).contains(&node.node) && nodes.insert(node.node),
+                    "kvm: node {} does not exist or is configured twice",
+                    node.node
+                );
+            }
+            ensure!(
+                kvm.capture_command
+                    .iter()
+                    .skip(1)
+                    .any(|arg| arg.contains("{device}")),
+                "kvm.capture_command needs a `{{device}}` argument"
+            );
+        }
+        let mut pipelines = HashSet::new();
+        for pipeline in &self.pipelines {
+            ensure!(
+                !pipeline.name.is_empty()
+                    && pipeline
+                        .name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+                "pipelines: `{}` is not a valid name, use [a-zA-Z0-9_-]",
+                pipeline.name
+            );
+            ensure!(
+                pipelines.insert(&pipeline.name),
+                "pipelines: `{}` is configured twice",
+                pipeline.name
+            );
+            ensure!(
+                !pipeline.secret.is_empty(),
+                "pipelines: `{}` has no secret",
+                pipeline.name
+            );
+            for step in &pipeline.steps {
+                step.validate()
+                    .with_context(|| format!("pipelines: `{}`", pipeline.name))?;
+                ensure!(
+                    !matches!(step, PipelineStep::Flash { url: None, .. })
+                        || pipeline.image_url.is_some(),
+                    "pipelines: `{}` flashes the image of the event but has no image_url",
+                    pipeline.name
+                );
+            }
+        }
+
+        let mut addresses = HashSet::new();
+        for listener in &self.listeners {
+            ensure!(
+                listener.port != 0,
+                "listeners: {} has no port",
+                listener.address
+            );
+            ensure!(
+                addresses.insert((&listener.address, listener.port)),
+                "listeners: {} port {} is listed more than once",
+                listener.address,
+                listener.port
+            );
+        }
+        ensure!(
+            self.listeners.is_empty()
+                || self.listeners.iter().any(|l| l.scheme != Scheme::Redirect),
+            "listeners: at least one listener must serve the API"
+        );
+        ensure!(
+            self.listeners.iter().all(|l| l.scheme != Scheme::Redirect
+                || l.redirect_port.is_some()
+                || self.listeners.iter().any(|l| l.scheme == Scheme::Https)),
+            "listeners: a redirect listener needs a `redirect_port` without an https listener"
+        );
+        self.listeners()?;
+
+        if let Some(hostname) = &self.network.hostname {
+            ensure!(
+                is_valid_hostname(hostname),
+                "network.hostname `{}` is not a valid hostname",
+                hostname
+            );
+        }
+
+        if let Some(dhcp) = &self.dhcp {
+            dhcp.validate()?;
+        }
+
+        for target in &self.notifications {
+            reqwest::Url::parse(&target.url)
+                .map_err(|e| anyhow::anyhow!("notifications: {}: {}", target.name, e))?;
+        }
+
+        let mut peers = HashSet::new();
+        for peer in &self.cluster.peers {
+            reqwest::Url::parse(&peer.url)
+                .map_err(|e| anyhow::anyhow!("cluster: {}: {}", peer.name, e))?;
+            ensure!(
+                peer.name != "local" && !peer.name.contains('/'),
+                "cluster: `{}` is not a valid peer name",
+                peer.name
+            );
+            ensure!(
+                peers.insert(&peer.name),
+                "cluster: peer {} is declared more than once",
+                peer.name
+            );
+        }
+
+        let mut sensors = HashSet::new();
+        for sensor in &self.activity.sensors {
+            ensure!(
+                (1..=4).contains(&sensor.node),
+                "activity: node {} is out of range 1..4",
+                sensor.node
+            );
+            ensure!(
+                sensors.insert(sensor.node),
+                "activity: node {} has more than one sensor",
+                sensor.node
+            );
+        }
+        ensure!(
+            self.activity.interval >= Duration::from_secs(1)
+                && self.activity.stall_after >= 2 * self.activity.interval,
+            "activity: stall_after must span at least two intervals of one second or more"
+        );
+
+        ensure!(
+            !self.watchdog.enabled || self.watchdog.timeout >= Duration::from_secs(5),
+            "watchdog.timeout must be at least 5 seconds"
+        );
+
+        let mut devices = HashSet::new();
+        for device in &self.i2c.allow {
+            ensure!(
+                device.address <= 0x7f,
+                "i2c: {:#x} is not a 7-bit address",
+                device.address
+            );
+            ensure!(
+                devices.insert((device.bus, device.address)),
+                "i2c: bus {} address {:#x} is listed more than once",
+                device.bus,
+                device.address
+            );
+        }
+
+        if let Some(enrollment) = &self.enrollment {
+            let url = reqwest::Url::parse(&enrollment.url)
+                .map_err(|e| anyhow::anyhow!("enrollment.url: {}", e))?;
+            ensure!(
+                url.scheme() == "https",
+                "enrollment.url must be a HTTPS URL, the bundle holds the keys of the board"
+            );
+            ensure!(
+                !enrollment.token_files.is_empty(),
+                "enrollment.token_files cannot be empty"
+            );
+            ensure!(
+                !enrollment.retry_interval.is_zero(),
+                "enrollment.retry_interval must be greater than 0"
+            );
+        }
+        ensure!(
+            !self.storage_health.interval.is_zero(),
+            "storage_health.interval must be greater than 0"
+        );
+        ensure!(
+            self.storage_health.warning <= self.storage_health.critical,
+            "storage_health.warning cannot be above storage_health.critical"
+        );
+        ensure!(
+            self.lights_out.poll_factor > 0,
+            "lights_out.poll_factor must be greater than 0"
+        );
+        ensure!(
+            !self.retention.interval.is_zero(),
+            "retention.interval must be greater than 0"
+        );
+        for (name, policy) in [
+            ("audit_logs", &self.retention.audit_logs),
+            ("crash_reports", &self.retention.crash_reports),
+        ] {
+            ensure!(
+                policy.max_size == 0 || policy.rotate_size <= policy.max_size,
+                "retention.{}.rotate_size cannot be above its max_size",
+                name
+            );
+        }
+
+        let mut failover_nodes = HashSet::new();
+        for node in &self.failover.nodes {
+            ensure!(
+                (1..=4).contains(&node.node),
+                "failover: node {} is out of range 1..4",
+                node.node
+            );
+            ensure!(
+                failover_nodes.insert(node.node),
+                "failover: node {} is listed more than once",
+                node.node
+            );
+            ensure!(
+                !node.playbook.is_empty(),
+                "failover: the playbook of node {} is empty",
+                node.node
+            );
+            ensure!(
+                node.fail_after >= 2 * self.failover.interval,
+                "failover: fail_after of node {} must span at least two intervals",
+                node.node
+            );
+        }
+        ensure!(
+            !self.failover.interval.is_zero() && !self.failover.timeout.is_zero(),
+            "failover: interval and timeout must be greater than 0"
+        );
+        ensure!(
+            self.graphql.max_depth > 0,
+            "graphql.max_depth must be greater than 0"
+        );
+        ensure!(
+            !self.console_sharing.default_ttl.is_zero()
+                && self.console_sharing.default_ttl <= self.console_sharing.max_ttl,
+            "console_sharing: default_ttl must be between 1 second and max_ttl"
+        );
+
+        if let Some(updates) = &self.updates {
+            reqwest::Url::parse(&updates.feed)
+                .map_err(|e| anyhow::anyhow!("updates.feed: {}", e))?;
+            if let Some(window) = &updates.maintenance {
+                window.start_time()?;
+                ensure!(
+                    window.duration <= Duration::from_secs(24 * 60 * 60),
+                    "updates.maintenance.duration must not exceed a day"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The listeners of the API with their TLS settings and redirect ports
+    /// resolved. Without a `listeners` section, HTTPS is served on `host` and
+    /// `port`, and with `redirect_http` plain HTTP on port 80 redirects there.
+    /// The network of the USB console is added when no listener covers all
+    /// addresses.
+    pub fn listeners(&self) -> anyhow::Result<Vec<Listener>> {
+        let mut listeners = self.listeners.clone();
+        if listeners.is_empty() {
+            let address: ScopedIp = self
+                .host
+                .parse()
+                .map_err(anyhow::Error::msg)
+                .context("host")?;
+            listeners.push(Listener {
+                address: address.clone(),
+                port: self.port,
+                scheme: Scheme::Https,
+                tls: None,
+                redirect_port: None,
+            });
+            if self.redirect_http {
+                listeners.push(Listener {
+                    address,
+                    port: HTTP_PORT,
+                    scheme: Scheme::Redirect,
+                    tls: None,
+                    redirect_port: None,
+                });
+            }
+        }
+        let https_port = listeners
+            .iter()
+            .find(|l| l.scheme == Scheme::Https)
+            .map(|l| l.port);
+        let console = self.usb_console.as_ref().filter(|c| c.network);
+        let everywhere = listeners
+            .iter()
+            .any(|l| l.scheme != Scheme::Redirect && l.address.ip.is_unspecified());
+        if let (Some(console), false) = (console, everywhere) {
+            listeners.push(Listener {
+                address: IpAddr::V4(console.address).into(),
+                port: https_port.unwrap_or(self.port),
+                scheme: Scheme::Https,
+                tls: None,
+                redirect_port: None,
+            });
+        }
+        for listener in &mut listeners {
+            match listener.scheme {
+                Scheme::Https => {
+                    listener.tls.get_or_insert_with(|| self.tls.clone());
+                }
+                Scheme::Http => {}
+                Scheme::Redirect => {
+                    listener.redirect_port = listener.redirect_port.or(https_port);
+                }
+            }
+        }
+        Ok(listeners)
+    }
+
+    /// Address on which the BMC reaches its own API, used by health checks.
+    pub fn api_address(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.api_listener()?.local_address()?)
+    }
+
+    /// The first listener that serves the API.
+    pub fn api_listener(&self) -> anyhow::Result<Listener> {
+        self.listeners()?
+            .into_iter()
+            .find(|l| l.scheme != Scheme::Redirect)
+            .context("no listener serves the API")
+    }
+
+    /// Returns the names of settings that differ between `self` and `other`
+    /// that only take effect after a restart of the daemon.
+    pub fn restart_required(&self, other: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.board != other.board {
+            changed.push("board");
+        }
+        if self.board_description != other.board_description {
+            changed.push("board_description");
+        }
+        if self.store != other.store {
+            changed.push("store");
+        }
+        if self.authentication != other.authentication {
+            changed.push("authentication");
+        }
+        if self.www != other.www {
+            changed.push("www");
+        }
+        if self.web_ui != other.web_ui {
+            changed.push("web_ui");
+        }
+        if self.usb_console != other.usb_console {
+            changed.push("usb_console");
+        }
+        if self.unix_socket != other.unix_socket {
+            changed.push("unix_socket");
+        }
+        if self.log != other.log {
+            changed.push("log");
+        }
+        if self.power != other.power {
+            changed.push("power");
+        }
+        if self.mdns != other.mdns {
+            changed.push("mdns");
+        }
+        if self.dhcp != other.dhcp {
+            changed.push("dhcp");
+        }
+        if self.netboot != other.netboot {
+            changed.push("netboot");
+        }
+        if self.nbd != other.nbd {
+            changed.push("nbd");
+        }
+        if self.firmware != other.firmware {
+            changed.push("firmware");
+        }
+        if self.firmware_signing != other.firmware_signing {
+            changed.push("firmware_signing");
+        }
+        if self.updates != other.updates {
+            changed.push("updates");
+        }
+        if self.watchdog != other.watchdog {
+            changed.push("watchdog");
+        }
+        if self.i2c != other.i2c {
+            changed.push("i2c");
+        }
+        if self.shutdown != other.shutdown {
+            changed.push("shutdown");
+        }
+        if self.legacy_api != other.legacy_api {
+            changed.push("legacy_api");
+        }
+        if self.http.base_path != other.http.base_path {
+            changed.push("http.base_path");
+        }
+        if self.jobs != other.jobs {
+            changed.push("jobs");
+        }
+        if self.activity != other.activity {
+            changed.push("activity");
+        }
+        if self.image_cache != other.image_cache {
+            changed.push("image_cache");
+        }
+        if self.image_sharing != other.image_sharing {
+            changed.push("image_sharing");
+        }
+        if self.node_agent != other.node_agent {
+            changed.push("node_agent");
+        }
+        if self.kubernetes != other.kubernetes {
+            changed.push("kubernetes");
+        }
+        if self.scripting != other.scripting {
+            changed.push("scripting");
+        }
+        if self.plugins != other.plugins {
+            changed.push("plugins");
+        }
+        if self.tcp_console != other.tcp_console {
+            changed.push("tcp_console");
+        }
+        if self.console_multicast != other.console_multicast {
+            changed.push("console_multicast");
+        }
+        if self.kvm != other.kvm {
+            changed.push("kvm");
+        }
+        if self.provisioning != other.provisioning {
+            changed.push("provisioning");
+        }
+        if self.enrollment != other.enrollment {
+            changed.push("enrollment");
+        }
+        if self.storage_health != other.storage_health {
+            changed.push("storage_health");
+        }
+        if self.storage != other.storage {
+            changed.push("storage");
+        }
+        if self.retention != other.retention {
+            changed.push("retention");
+        }
+        if self.lights_out != other.lights_out {
+            changed.push("lights_out");
+        }
+        if self.failover != other.failover {
+            changed.push("failover");
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    fn load_str(name: &str, content: &str) -> anyhow::Result<Config> {
+        let dir = TempDir::new("config_test").unwrap();
+        let path = dir.path().join(name);
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(content.as_bytes())
+            .unwrap();
+        Config::load(&path)
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let config = load_str("config.yaml", "").unwrap();
+        assert!(config.nodes.is_empty());
+        assert_eq!(config.power.restore_policy, PowerRestorePolicy::Restore);
+        let defaults = Config::defaults().unwrap();
+        defaults.validate().unwrap();
+        assert_eq!(defaults.port, config.port);
+    }
+
+    #[test]
+    fn toml_config() {
+        let config = load_str(
+            "config.toml",
+            "port = 8443\n[[nodes]]\nnode = 2\nname = \"storage\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.port, 8443);
+        assert_eq!(config.nodes[0].name.as_deref(), Some("storage"));
+    }
+
+    #[test]
+    fn invalid_configs() {
+        assert!(load_str("config.yaml", "nodes:\n  - node: 5\n").is_err());
+        assert!(load_str("config.yaml", "nodes:\n  - node: 1\n  - node: 1\n").is_err());
+        assert!(load_str("config.yaml", "network:\n  hostname: \"not valid\"\n").is_err());
+        assert!(load_str(
+            "config.yaml",
+            "notifications:\n  - name: hook\n    url: \"not a url\"\n"
+        )
+        .is_err());
+        let peer = "  - name: local\n    url: https://10.0.0.2\n    user: root\n    password: x\n";
+        assert!(load_str("config.yaml", &format!("cluster:\n  peers:\n{}", peer)).is_err());
+        let peer = peer.replace("local", "rack-a");
+        assert!(load_str("config.yaml", &format!("cluster:\n  peers:\n{}", peer)).is_ok());
+        assert!(load_str(
+            "config.yaml",
+            &format!("cluster:\n  peers:\n{}{}", peer, peer)
+        )
+        .is_err());
+        let sensor = "  - node: 2\n    path: /sys/class/hwmon/hwmon3/curr2_input\n";
+        assert!(load_str("config.yaml", &format!("activity:\n  sensors:\n{}", sensor)).is_ok());
+        assert!(load_str(
+            "config.yaml",
+            &format!("activity:\n  sensors:\n{}{}", sensor, sensor)
+        )
+        .is_err());
+        assert!(load_str(
+            "config.yaml",
+            "activity:\n  interval: 10\n  stall_after: 15\n"
+        )
+        .is_err());
+        let brownout = "power:\n  brownout:\n    shutdown_order: [4, 3, 2]\n";
+        let config = load_str("config.yaml", brownout).unwrap();
+        assert_eq!(config.power.brownout.shutdown_order, [4, 3, 2]);
+        assert_eq!(config.power.brownout.interval, Duration::from_millis(100));
+        assert!(load_str("config.yaml", &brownout.replace('2', "4")).is_err());
+        assert!(load_str("config.yaml", &brownout.replace('2', "5")).is_err());
+        let listener = "  - address: 10.0.0.2\n    port: 443\n";
+        assert!(load_str(
+            "config.yaml",
+            &format!("listeners:\n{}{}", listener, listener)
+        )
+        .is_err());
+        assert!(load_str(
+            "config.yaml",
+            "listeners:\n  - address: 10.0.0.2\n    port: 80\n    scheme: redirect\n"
+        )
+        .is_err());
+        assert!(load_str("config.yaml", "host: turingpi.local\n").is_err());
+        assert!(load_str("config.yaml", "http:\n  base_path: /bmc/\n").is_err());
+        assert!(load_str("config.yaml", "http:\n  trusted_proxies: [10.0.0.0/40]\n").is_err());
+        let cors = "http:\n  cors:\n    allow_credentials: true\n    allowed_origins:";
+        assert!(load_str("config.yaml", &format!("{} [\"https://lab:8443\"]\n", cors)).is_ok());
+        assert!(load_str("config.yaml", &format!("{} [\"https://lab/ui\"]\n", cors)).is_err());
+        assert!(load_str("config.yaml", &format!("{} [\"*\"]\n", cors)).is_err());
+        assert!(load_str(
+            "config.yaml",
+            "listeners:\n  - address: \"fd00::2%br0\"\n    port: 443\n"
+        )
+        .is_err());
+        assert!(load_str(
+            "config.yaml",
+            "listeners:\n  - address: \"fe80::2%br0\"\n    port: 443\n"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn effective_listeners() {
+        let config = load_str("config.yaml", "").unwrap();
+        let listeners = config.listeners().unwrap();
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[0].scheme, Scheme::Https);
+        assert_eq!(listeners[0].tls.as_ref(), Some(&config.tls));
+        assert_eq!(listeners[1].scheme, Scheme::Redirect);
+        assert_eq!(listeners[1].redirect_port, Some(config.port));
+        assert_eq!(
+            config.api_address().unwrap(),
+            "[::1]:443".parse::<SocketAddr>().unwrap()
+        );
+
+        let config = load_str(
+            "config.yaml",
+            "listeners:\n  - address: 10.0.0.2\n    port: 80\n    scheme: redirect\n  - address: fd00::2\n    port: 8443\n",
+        )
+        .unwrap();
+        let listeners = config.listeners().unwrap();
+        assert_eq!(listeners[0].redirect_port, Some(8443));
+        assert_eq!(
+            config.api_address().unwrap(),
+            "[fd00::2]:8443".parse::<SocketAddr>().unwrap()
+        );
+
+        // the USB console is reachable next to specific listeners
+        let config = load_str(
+            "config.yaml",
+            "usb_console: {}\nlisteners:\n  - address: 10.0.0.2\n    port: 443\n",
+        )
+        .unwrap();
+        let listeners = config.listeners().unwrap();
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[1].address.to_string(), "172.31.255.1");
+        assert_eq!(listeners[1].tls.as_ref(), Some(&config.tls));
+        let config = load_str("config.yaml", "usb_console: {}\n").unwrap();
+        assert_eq!(config.listeners().unwrap().len(), 2);
+        assert!(load_str("config.yaml", "usb_console:\n  address: 172.31.255.2\n").is_err());
+    }
+
+    #[test]
+    fn dhcp_leases() {
+        let dhcp = "dhcp:\n  interface: br0\n  server_address: 10.0.0.1\n  netmask: 255.255.255.0\n  leases:\n";
+        let config = load_str(
+            "config.yaml",
+            &format!(
+                "{dhcp}    - node: 1\n      mac: \"02:00:00:00:00:01\"\n      address: 10.0.0.11\n"
+            ),
+        )
+        .unwrap();
+        assert_eq!(config.dhcp.unwrap().lease_time, Duration::from_secs(3600));
+
+        assert!(load_str(
+            "config.yaml",
+            &format!(
+                "{dhcp}    - node: 1\n      mac: \"02:00:00:00:00:01\"\n      address: 10.1.0.11\n"
+            ),
+        )
+        .is_err());
+        assert!(load_str(
+            "config.yaml",
+            &format!("{dhcp}    - node: 1\n      mac: \"02:00:00\"\n      address: 10.0.0.11\n"),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn pipelines() {
+        let pipeline = "pipelines:\n  - name: k3s-image\n    secret: s3cret\n    image_url: /image/url\n    when:\n      - pointer: /status\n        equals: success\n    steps:\n";
+        let config = load_str(
+            "config.yaml",
+            &format!("{pipeline}      - op: flash\n        node: 3\n      - op: power\n        node: 3\n        on: true\n      - op: delay\n        ms: 500\n"),
+        )
+        .unwrap();
+        assert_eq!(
+            config.pipelines[0].steps,
+            vec![
+                PipelineStep::Flash {
+                    node: 3,
+                    url: None,
+                    sha256: None
+                },
+                PipelineStep::Power { node: 3, on: true },
+                PipelineStep::Delay { ms: 500 },
+            ]
+        );
+        assert_eq!(config.pipelines[0].when[0].equals, "success");
+
+        assert!(load_str(
+            "config.yaml",
+            &format!("{pipeline}      - op: reset\n        node: 5\n"),
+        )
+        .is_err());
+        assert!(load_str(
+            "config.yaml",
+            "pipelines:\n  - name: a\n    secret: s\n    steps:\n      - op: flash\n        node: 1\n",
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn nbd_allow() {
+        assert!(load_str("config.yaml", "nbd:\n  enabled: true\n").is_err());
+        let config = load_str(
+            "config.yaml",
+            "nbd:\n  enabled: true\n  allow: [10.0.0.0/24]\n",
+        )
+        .unwrap();
+        assert_eq!(config.nbd.allow, vec!["10.0.0.0/24".to_string()]);
+    }
+
+    #[test]
+    fn tcp_console() {
+        let config = load_str(
+            "config.yaml",
+            "tcp_console:\n  ports:\n    - node: 1\n      port: 2001\n  allow: [10.0.0.0/24]\n",
+        )
+        .unwrap();
+        let console = config.tcp_console.unwrap();
+        assert_eq!(console.ports[0].port, 2001);
+        assert_eq!(console.idle_timeout, Duration::from_secs(3600));
+        assert_eq!(console.max_connections, 4);
+
+        assert!(load_str(
+            "config.yaml",
+            "tcp_console:\n  ports:\n    - node: 1\n      port: 2001\n    - node: 2\n      port: 2001\n",
+        )
+        .is_err());
+        assert!(load_str(
+            "config.yaml",
+            "tcp_console:\n  ports:\n    - node: 5\n      port: 2005\n",
+        )
+        .is_err());
+        assert!(load_str(
+            "config.yaml",
+            "tcp_console:\n  ports:\n    - node: 1\n      port: 2001\n",
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn console_multicast() {
+        let config = load_str(
+            "config.yaml",
+            "console_multicast:\n  groups:\n    - node: 1\n      group: 239.255.42.1:5001\n    - node: 2\n      group: \"[ff15::42]:5002\"\n",
+        )
+        .unwrap();
+        let multicast = config.console_multicast.unwrap();
+        assert_eq!(multicast.groups[1].group.port(), 5002);
+        assert_eq!(multicast.ttl, 1);
+        assert_eq!(multicast.interface, None);
+
+        assert!(load_str(
+            "config.yaml",
+            "console_multicast:\n  groups:\n    - node: 1\n      group: 10.0.0.1:5001\n",
+        )
+        .is_err());
+        assert!(load_str(
+            "config.yaml",
+            "console_multicast:\n  groups:\n    - node: 1\n      group: 239.255.42.1:5001\n    - node: 2\n      group: 239.255.42.1:5001\n",
+        )
+        .is_err());
+        assert!(load_str(
+            "config.yaml",
+            "console_multicast:\n  groups:\n    - node: 1\n      group: 239.255.42.1:5001\n  ttl: 0\n",
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn roles() {
+        let config = load_str(
+            "config.yaml",
+            "roles:\n  - user: guest\n    role: observer\n",
+        )
+        .unwrap();
+        assert_eq!(config.roles[0].role, Role::Observer);
+
+        assert!(load_str(
+            "config.yaml",
+            "roles:\n  - user: guest\n    role: observer\n  - user: guest\n    role: operator\n",
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn kvm() {
+        let nodes = "kvm:\n  nodes:\n    - node: 2\n      usb_port: \"1-1.2\"\n";
+        let config = load_str("config.yaml", nodes).unwrap();
+        assert_eq!(config.kvm.unwrap().capture_command[0], "ffmpeg");
+
+        let command = format!("{nodes}  capture_command: [\"cat\", \"/dev/video0\"]\n");
+        assert!(load_str("config.yaml", &command).is_err());
+    }
+
+    #[test]
+    fn enrollment() {
+        let config = load_str(
+            "config.yaml",
+            "enrollment:\n  url: https://fleet.example.com/enroll\n",
+        )
+        .unwrap();
+        let enrollment = config.enrollment.unwrap();
+        assert_eq!(enrollment.token_files.len(), 2);
+        assert_eq!(enrollment.retry_interval, Duration::from_secs(60));
+
+        assert!(load_str("config.yaml", "enrollment:\n  url: fleet.example.com\n").is_err());
+        assert!(load_str(
+            "config.yaml",
+            "enrollment:\n  url: http://fleet.example.com/enroll\n"
+        )
+        .is_err());
+        assert!(load_str(
+            "config.yaml",
+            "enrollment:\n  url: https://fleet.example.com\n  token_files: []\n",
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn storage() {
+        let config = load_str("config.yaml", "").unwrap();
+        assert_eq!(config.storage, Storage::default());
+        let config = load_str(
+            "config.yaml",
+            "storage:\n  backup_dir: /mnt/usb/backups\n  quotas:\n    images: 1000\n",
+        )
+        .unwrap();
+        assert_eq!(config.storage.backup_dir, PathBuf::from("/mnt/usb/backups"));
+        assert_eq!(config.storage.quotas.images, 1000);
+        assert_eq!(config.storage.quotas.backups, 0);
+    }
+
+    #[test]
+    fn retention() {
+        let config = load_str("config.yaml", "").unwrap();
+        assert_eq!(config.retention.audit_logs.rotate_size, 1024 * 1024);
+        assert_eq!(config.retention.crash_reports.max_age, None);
+        let config = load_str(
+            "config.yaml",
+            "retention:\n  crash_reports:\n    max_age: 3600\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.retention.crash_reports.max_age,
+            Some(Duration::from_secs(3600))
+        );
+        assert_eq!(config.retention.audit_logs, Retention::default().audit_logs);
+        assert!(load_str(
+            "config.yaml",
+            "retention:\n  audit_logs:\n    max_size: 100\n    rotate_size: 200\n",
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn failover() {
+        let config = load_str(
+            "config.yaml",
+            "failover:\n  nodes:\n    - node: 2\n      probe: agent\n    - node: 3\n      probe:\n        tcp: 10.0.0.23:22\n      playbook: [warn, power_cycle]\n",
+        )
+        .unwrap();
+        let nodes = &config.failover.nodes;
+        assert!(nodes[0].enabled);
+        assert_eq!(nodes[0].playbook.len(), 4);
+        assert_eq!(nodes[1].probe, Probe::Tcp("10.0.0.23:22".to_string()));
+        assert_eq!(
+            nodes[1].playbook,
+            [RecoveryStep::Warn, RecoveryStep::PowerCycle]
+        );
+        assert!(load_str(
+            "config.yaml",
+            "failover:\n  nodes:\n    - node: 2\n      probe: agent\n      fail_after: 5\n",
+        )
+        .is_err());
+    }
 }
@@ -0,0 +1,270 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Errors as reported to API clients. Error responses carry, next to the
+//! message of the legacy `result` field, an `error` object:
+//!
+//! ```json
+//! {"code": "no_such_node", "message": "Node 4 does not exist on this board",
+//!  "details": {"node": 4}}
+//! ```
+//!
+//! `code` is stable across releases, so clients can match on it or show a
+//! translated message, where `message` is English and may change. The message
+//! catalog, see `app::messages`, has a template for every code, in which
+//! `{name}` stands for the value of `name` in `details`.
+//!
+//! Errors that implement [`ErrorCode`] report their own code. Errors raised as
+//! [`BmcError`] anywhere in the crate keep their code when they are wrapped in
+//! `anyhow` context; all other errors get a code derived from the HTTP status,
+//! such as `bad_request` or `internal_server_error`.
+use crate::hal::NodeId;
+use actix_web::http::StatusCode;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum BmcError {
+    #[error("{0} does not exist on this board")]
+    NoSuchNode(NodeId),
+    #[error("{0} has no module installed")]
+    EmptySlot(NodeId),
+    #[error("invalid parameter `{parameter}`: {reason}")]
+    InvalidParameter {
+        parameter: &'static str,
+        reason: Cow<'static, str>,
+    },
+    #[error("{0}")]
+    NotSupported(Cow<'static, str>),
+    #[error("{0} is in progress")]
+    Busy(Cow<'static, str>),
+    /// a resource that is held elsewhere, e.g. by a mounted filesystem
+    #[error("{resource} is in use by {by}")]
+    InUse {
+        resource: Cow<'static, str>,
+        by: Cow<'static, str>,
+    },
+    #[error("the power supply of the board is off")]
+    PowerSupplyOff,
+    #[error("pin `{pin}` {reason}")]
+    PinAccessDenied { pin: String, reason: &'static str },
+    #[error("{0} does not exist")]
+    NotFound(Cow<'static, str>),
+    #[error("{resource} was changed by another client")]
+    PreconditionFailed {
+        resource: Cow<'static, str>,
+        /// ETag of the resource as it is now, `None` when it does not exist
+        etag: Option<String>,
+    },
+    #[error("the {feature} storage is limited to {quota} bytes")]
+    QuotaExceeded { feature: &'static str, quota: u64 },
+    /// the mock hardware has no device files
+    #[cfg_attr(feature = "mock", allow(dead_code))]
+    #[error("{}: {source}", path.display())]
+    Device {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+impl BmcError {
+    pub fn invalid_parameter<S: Into<Cow<'static, str>>>(
+        parameter: &'static str,
+        reason: S,
+    ) -> Self {
+        BmcError::InvalidParameter {
+            parameter,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Errors with a code of their own, see the module documentation.
+pub trait ErrorCode: std::error::Error {
+    /// Stable snake case code, with a template in the message catalog.
+    fn code(&self) -> &'static str;
+
+    fn status(&self) -> StatusCode;
+
+    /// Values of the error that the template of the code refers to.
+    fn details(&self) -> Value {
+        Value::Null
+    }
+}
+
+impl ErrorCode for BmcError {
+    fn code(&self) -> &'static str {
+        match self {
+            BmcError::NoSuchNode(_) => "no_such_node",
+            BmcError::EmptySlot(_) => "empty_slot",
+            BmcError::InvalidParameter { .. } => "invalid_parameter",
+            BmcError::NotSupported(_) => "not_supported",
+            BmcError::Busy(_) => "busy",
+            BmcError::InUse { .. } => "in_use",
+            BmcError::PowerSupplyOff => "power_supply_off",
+            BmcError::PinAccessDenied { .. } => "pin_access_denied",
+            BmcError::NotFound(_) => "not_found",
+            BmcError::PreconditionFailed { .. } => "precondition_failed",
+            BmcError::QuotaExceeded { .. } => "quota_exceeded",
+            BmcError::Device { .. } => "device_error",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            BmcError::NoSuchNode(_) => StatusCode::NOT_FOUND,
+            BmcError::EmptySlot(_) => StatusCode::CONFLICT,
+            BmcError::InvalidParameter { .. } => StatusCode::BAD_REQUEST,
+            BmcError::NotSupported(_) => StatusCode::BAD_REQUEST,
+            BmcError::Busy(_) => StatusCode::CONFLICT,
+            BmcError::InUse { .. } => StatusCode::CONFLICT,
+            BmcError::PowerSupplyOff => StatusCode::CONFLICT,
+            BmcError::PinAccessDenied { .. } => StatusCode::FORBIDDEN,
+            BmcError::NotFound(_) => StatusCode::NOT_FOUND,
+            BmcError::PreconditionFailed { .. } => StatusCode::PRECONDITION_FAILED,
+            BmcError::QuotaExceeded { .. } => StatusCode::INSUFFICIENT_STORAGE,
+            BmcError::Device { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn details(&self) -> Value {
+        match self {
+            // nodes are numbered from 1 for users
+            BmcError::NoSuchNode(node) | BmcError::EmptySlot(node) => {
+                json!({ "node": *node as u8 + 1 })
+            }
+            BmcError::InvalidParameter { parameter, .. } => json!({ "parameter": parameter }),
+            BmcError::PinAccessDenied { pin, .. } => json!({ "pin": pin }),
+            BmcError::PreconditionFailed { etag, .. } => json!({ "etag": etag }),
+            BmcError::QuotaExceeded { feature, quota } => {
+                json!({ "feature": feature, "quota": quota })
+            }
+            BmcError::Device { path, source } => json!({
+                "path": path,
+                "os_error": source.raw_os_error(),
+            }),
+            BmcError::NotSupported(_)
+            | BmcError::Busy(_)
+            | BmcError::InUse { .. }
+            | BmcError::PowerSupplyOff
+            | BmcError::NotFound(_) => Value::Null,
+        }
+    }
+}
+
+/// The `error` object of an error response.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    pub status: StatusCode,
+    pub code: Cow<'static, str>,
+    pub message: String,
+    #[serde(skip_serializing_if = "Value::is_null")]
+    pub details: Value,
+}
+
+impl ApiError {
+    /// Error without a specific code, the code is the reason phrase of
+    /// `status` in snake case.
+    pub fn from_status(status: StatusCode, message: String) -> Self {
+        let code = status
+            .canonical_reason()
+            .unwrap_or("error")
+            .to_ascii_lowercase()
+            .replace([' ', '-'], "_");
+        ApiError {
+            status,
+            code: code.into(),
+            message,
+            details: Value::Null,
+        }
+    }
+
+    /// Reports the first [`BmcError`] in the chain of `error`, with the
+    /// context that was added to it in the message.
+    pub fn from_anyhow(error: &anyhow::Error) -> Self {
+        let message = format!("{:#}", error);
+        for cause in error.chain() {
+            if let Some(e) = cause.downcast_ref::<BmcError>() {
+                return ApiError {
+                    status: e.status(),
+                    code: e.code().into(),
+                    message,
+                    details: e.details(),
+                };
+            }
+            #[cfg(not(feature = "mock"))]
+            if let Some(e) = cause.downcast_ref::<crate::hal::PowerControllerError>() {
+                if let Some(e) = e.as_bmc_error() {
+                    return ApiError {
+                        message,
+                        ..ApiError::from(&e)
+                    };
+                }
+            }
+        }
+        ApiError::from_status(StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+}
+
+impl<E: ErrorCode> From<&E> for ApiError {
+    fn from(e: &E) -> Self {
+        ApiError {
+            status: e.status(),
+            code: e.code().into(),
+            message: e.to_string(),
+            details: e.details(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn codes_survive_context() {
+        let error = Err::<(), _>(BmcError::NoSuchNode(NodeId::Node4))
+            .context("set power state")
+            .unwrap_err();
+        let api_error = ApiError::from_anyhow(&error);
+        assert_eq!(api_error.status, StatusCode::NOT_FOUND);
+        assert_eq!(api_error.code, "no_such_node");
+        assert_eq!(
+            api_error.message,
+            "set power state: Node 4 does not exist on this board"
+        );
+        assert_eq!(api_error.details, json!({ "node": 4 }));
+
+        let error = anyhow::anyhow!("disk full").context("write image");
+        let api_error = ApiError::from_anyhow(&error);
+        assert_eq!(api_error.code, "internal_server_error");
+        assert_eq!(
+            serde_json::to_value(&api_error).unwrap(),
+            json!({ "code": "internal_server_error", "message": "write image: disk full" })
+        );
+    }
+
+    #[test]
+    fn status_codes() {
+        let error = ApiError::from_status(StatusCode::PAYLOAD_TOO_LARGE, String::new());
+        assert_eq!(error.code, "payload_too_large");
+        let error = ApiError::from_status(StatusCode::BAD_REQUEST, String::new());
+        assert_eq!(error.code, "bad_request");
+    }
+}
@@ -0,0 +1,379 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Artificial delays and failures for HAL operations and storage writes, so
+//! that client retry logic and error paths can be exercised against a real
+//! daemon. Only compiled with the `fault-injection` feature; the rules are
+//! managed at runtime through `/api/bmc/debug/faults`.
+use crate::hal::{
+    NodeId, PinControl, PowerControl, PsuState, SdRoute, UsbArchitecture, UsbMode, UsbRoute,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Longest delay a rule may add to an operation.
+pub const MAX_DELAY_MS: u64 = 60_000;
+
+static RULES: FaultRules = FaultRules::new();
+
+/// Group of operations a rule applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Site {
+    /// switching and reading back node power and the power supply
+    Power,
+    /// the front panel LEDs
+    Leds,
+    /// USB routing and the USB boot lines
+    Usb,
+    /// node pins, module presence and the microSD switch
+    Pins,
+    /// writes of the persistency store
+    Storage,
+}
+
+impl FromStr for Site {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+    }
+}
+
+impl Display for Site {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // serializing a unit variant cannot fail
+        let name = serde_json::to_value(self).unwrap_or_default();
+        f.write_str(name.as_str().unwrap_or_default())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaultRule {
+    /// added to every matching operation before it runs
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// fraction of the matching operations that fail, 0 to 1
+    #[serde(default)]
+    pub failure_rate: f64,
+    /// number of operations left before the rule expires, unlimited when
+    /// absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remaining: Option<u32>,
+}
+
+impl FaultRule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.delay_ms > MAX_DELAY_MS {
+            return Err(format!("delay_ms must be at most {}", MAX_DELAY_MS));
+        }
+        if !(0.0..=1.0).contains(&self.failure_rate) {
+            return Err("failure_rate must be between 0 and 1".to_string());
+        }
+        if self.remaining == Some(0) {
+            return Err("remaining must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("injected {site} fault in {operation}")]
+pub struct InjectedFault {
+    pub site: Site,
+    pub operation: &'static str,
+}
+
+/// What happens to a single operation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Effect {
+    delay: Duration,
+    fail: bool,
+}
+
+struct FaultRules(Mutex<BTreeMap<Site, FaultRule>>);
+
+impl FaultRules {
+    const fn new() -> Self {
+        Self(Mutex::new(BTreeMap::new()))
+    }
+
+    fn set(&self, site: Site, rule: FaultRule) {
+        self.0
+            .lock()
+            .expect("fault rules poisoned")
+            .insert(site, rule);
+    }
+
+    fn clear(&self, site: Option<Site>) {
+        let mut rules = self.0.lock().expect("fault rules poisoned");
+        match site {
+            Some(site) => {
+                rules.remove(&site);
+            }
+            None => rules.clear(),
+        }
+    }
+
+    fn rules(&self) -> BTreeMap<Site, FaultRule> {
+        self.0.lock().expect("fault rules poisoned").clone()
+    }
+
+    /// Consumes one operation of the rule of `site`. `roll` is a number in
+    /// `[0, 1)` that decides whether the operation fails.
+    fn take(&self, site: Site, roll: f64) -> Option<Effect> {
+        let mut rules = self.0.lock().expect("fault rules poisoned");
+        let rule = rules.get_mut(&site)?;
+        let effect = Effect {
+            delay: Duration::from_millis(rule.delay_ms),
+            fail: roll < rule.failure_rate,
+        };
+        match rule.remaining.as_mut() {
+            Some(1) => {
+                rules.remove(&site);
+            }
+            Some(remaining) => *remaining -= 1,
+            None => {}
+        }
+        Some(effect)
+    }
+}
+
+/// Installs `rule` for `site`, replacing the previous one.
+pub fn set(site: Site, rule: FaultRule) -> Result<(), String> {
+    rule.validate()?;
+    tracing::warn!("injecting {} faults: {:?}", site, rule);
+    RULES.set(site, rule);
+    Ok(())
+}
+
+/// Removes the rule of `site`, or all rules when `None`.
+pub fn clear(site: Option<Site>) {
+    RULES.clear(site);
+}
+
+pub fn rules() -> BTreeMap<Site, FaultRule> {
+    RULES.rules()
+}
+
+fn effect(site: Site, operation: &'static str) -> Option<Effect> {
+    let effect = RULES.take(site, rand::random())?;
+    tracing::debug!("{}: injecting {:?}", operation, effect);
+    Some(effect)
+}
+
+fn outcome(site: Site, operation: &'static str, fail: bool) -> anyhow::Result<()> {
+    if fail {
+        return Err(InjectedFault { site, operation }.into());
+    }
+    Ok(())
+}
+
+/// Applies the rule of `site`, if any, to an asynchronous operation.
+pub async fn intercept(site: Site, operation: &'static str) -> anyhow::Result<()> {
+    let Some(effect) = effect(site, operation) else {
+        return Ok(());
+    };
+    tokio::time::sleep(effect.delay).await;
+    outcome(site, operation, effect.fail)
+}
+
+/// Applies the rule of `site`, if any, to a synchronous operation. The delay
+/// blocks the calling thread, like slow hardware would.
+pub fn intercept_blocking(site: Site, operation: &'static str) -> anyhow::Result<()> {
+    let Some(effect) = effect(site, operation) else {
+        return Ok(());
+    };
+    std::thread::sleep(effect.delay);
+    outcome(site, operation, effect.fail)
+}
+
+/// Wraps the hardware controllers so that every operation passes the
+/// injection rules first.
+pub fn wrap(
+    pins: Box<dyn PinControl>,
+    power: Box<dyn PowerControl>,
+) -> (Box<dyn PinControl>, Box<dyn PowerControl>) {
+    tracing::warn!("fault injection is compiled in, see /api/bmc/debug/faults");
+    (
+        Box::new(FaultyPinControl(pins)),
+        Box::new(FaultyPowerControl(power)),
+    )
+}
+
+struct FaultyPowerControl(Box<dyn PowerControl>);
+
+#[async_trait]
+impl PowerControl for FaultyPowerControl {
+    async fn set_power_node(&self, node_states: u8, node_mask: u8) -> anyhow::Result<()> {
+        intercept(Site::Power, "set_power_node").await?;
+        self.0.set_power_node(node_states, node_mask).await
+    }
+
+    async fn reset_node(&self, node: NodeId) -> anyhow::Result<()> {
+        intercept(Site::Power, "reset_node").await?;
+        self.0.reset_node(node).await
+    }
+
+    fn read_node_states(&self) -> anyhow::Result<u8> {
+        intercept_blocking(Site::Power, "read_node_states")?;
+        self.0.read_node_states()
+    }
+
+    async fn power_led(&self, on: bool) -> anyhow::Result<()> {
+        intercept(Site::Leds, "power_led").await?;
+        self.0.power_led(on).await
+    }
+
+    async fn status_led(&self, on: bool) -> anyhow::Result<()> {
+        intercept(Site::Leds, "status_led").await?;
+        self.0.status_led(on).await
+    }
+
+    async fn set_psu(&self, on: bool) -> anyhow::Result<()> {
+        intercept(Site::Power, "set_psu").await?;
+        self.0.set_psu(on).await
+    }
+
+    fn read_psu(&self) -> anyhow::Result<Option<PsuState>> {
+        intercept_blocking(Site::Power, "read_psu")?;
+        self.0.read_psu()
+    }
+
+    fn watch_power_good(&self) -> Option<tokio::sync::watch::Receiver<bool>> {
+        self.0.watch_power_good()
+    }
+}
+
+struct FaultyPinControl(Box<dyn PinControl>);
+
+impl PinControl for FaultyPinControl {
+    fn select_usb(&self, node: NodeId, mode: UsbMode) -> anyhow::Result<()> {
+        intercept_blocking(Site::Usb, "select_usb")?;
+        self.0.select_usb(node, mode)
+    }
+
+    fn set_usb_route(&self, route: UsbRoute) -> anyhow::Result<()> {
+        intercept_blocking(Site::Usb, "set_usb_route")?;
+        self.0.set_usb_route(route)
+    }
+
+    fn set_usb_boot(&self, nodes_state: u8, nodes_mask: u8) -> anyhow::Result<()> {
+        intercept_blocking(Site::Usb, "set_usb_boot")?;
+        self.0.set_usb_boot(nodes_state, nodes_mask)
+    }
+
+    fn set_node1_usb_route(&self, alternative_port: bool) -> anyhow::Result<()> {
+        intercept_blocking(Site::Usb, "set_node1_usb_route")?;
+        self.0.set_node1_usb_route(alternative_port)
+    }
+
+    fn usb_bus_type(&self) -> UsbArchitecture {
+        self.0.usb_bus_type()
+    }
+
+    fn read_presence(&self) -> anyhow::Result<Option<u8>> {
+        intercept_blocking(Site::Pins, "read_presence")?;
+        self.0.read_presence()
+    }
+
+    fn read_pin(&self, node: NodeId, pin: &str) -> anyhow::Result<bool> {
+        intercept_blocking(Site::Pins, "read_pin")?;
+        self.0.read_pin(node, pin)
+    }
+
+    fn write_pin(&self, node: NodeId, pin: &str, value: bool) -> anyhow::Result<()> {
+        intercept_blocking(Site::Pins, "write_pin")?;
+        self.0.write_pin(node, pin, value)
+    }
+
+    fn select_sd(&self, route: SdRoute) -> anyhow::Result<()> {
+        intercept_blocking(Site::Pins, "select_sd")?;
+        self.0.select_sd(route)
+    }
+
+    fn read_sd_route(&self) -> anyhow::Result<Option<SdRoute>> {
+        intercept_blocking(Site::Pins, "read_sd_route")?;
+        self.0.read_sd_route()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(delay_ms: u64, failure_rate: f64, remaining: Option<u32>) -> FaultRule {
+        FaultRule {
+            delay_ms,
+            failure_rate,
+            remaining,
+        }
+    }
+
+    #[test]
+    fn validation() {
+        assert!(rule(0, 0.0, None).validate().is_ok());
+        assert!(rule(MAX_DELAY_MS, 1.0, Some(1)).validate().is_ok());
+        assert!(rule(MAX_DELAY_MS + 1, 0.0, None).validate().is_err());
+        assert!(rule(0, 1.5, None).validate().is_err());
+        assert!(rule(0, -0.1, None).validate().is_err());
+        assert!(rule(0, f64::NAN, None).validate().is_err());
+        assert!(rule(0, 0.0, Some(0)).validate().is_err());
+    }
+
+    #[test]
+    fn rules_apply_per_site() {
+        let rules = FaultRules::new();
+        assert_eq!(rules.take(Site::Power, 0.0), None);
+
+        rules.set(Site::Power, rule(250, 0.5, None));
+        assert_eq!(rules.take(Site::Usb, 0.0), None);
+        let effect = rules.take(Site::Power, 0.25).unwrap();
+        assert_eq!(effect.delay, Duration::from_millis(250));
+        assert!(effect.fail);
+        assert!(!rules.take(Site::Power, 0.5).unwrap().fail);
+
+        rules.set(Site::Storage, rule(0, 1.0, None));
+        rules.clear(Some(Site::Power));
+        assert_eq!(
+            rules.rules().keys().copied().collect::<Vec<_>>(),
+            [Site::Storage]
+        );
+        rules.clear(None);
+        assert!(rules.rules().is_empty());
+    }
+
+    #[test]
+    fn rules_expire() {
+        let rules = FaultRules::new();
+        rules.set(Site::Pins, rule(0, 1.0, Some(2)));
+        assert!(rules.take(Site::Pins, 0.0).unwrap().fail);
+        assert_eq!(rules.rules()[&Site::Pins].remaining, Some(1));
+        assert!(rules.take(Site::Pins, 0.0).unwrap().fail);
+        assert_eq!(rules.take(Site::Pins, 0.0), None);
+        assert!(rules.rules().is_empty());
+    }
+
+    #[test]
+    fn site_names() {
+        assert_eq!("storage".parse::<Site>().unwrap(), Site::Storage);
+        assert!("disk".parse::<Site>().is_err());
+        assert_eq!(Site::Leds.to_string(), "leds");
+    }
+}
@@ -11,7 +11,15 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+pub mod board_profile;
+pub mod eeprom;
+pub mod expansion;
+mod gpio_definitions;
 pub mod helpers;
+pub mod i2c;
+pub mod rtc;
+use async_trait::async_trait;
+use board_profile::BoardProfile;
 use std::fmt::Display;
 
 macro_rules! conditional_import {
@@ -24,8 +32,8 @@ macro_rules! conditional_import {
 }
 
 conditional_import! {
-    cfg(not(feature = "stubbed")),
-    mod gpio_definitions;
+    cfg(not(feature = "mock")),
+    pub mod gpio_sim;
     mod pin_controller;
     mod power_controller;
     pub use pin_controller::*;
@@ -33,9 +41,80 @@ conditional_import! {
 }
 
 conditional_import! {
-    cfg(feature = "stubbed"),
-    mod stub;
-    pub use stub::*;
+    cfg(feature = "mock"),
+    pub mod mock;
+    pub use mock::{PinController, PowerController};
+}
+
+/// Switches the power of the nodes and drives the front panel LEDs.
+#[async_trait]
+pub trait PowerControl: Send + Sync {
+    /// Powers the nodes selected by the bit-field `node_mask` on or off,
+    /// according to the bits in `node_states`.
+    async fn set_power_node(&self, node_states: u8, node_mask: u8) -> anyhow::Result<()>;
+    /// Power cycles a node.
+    async fn reset_node(&self, node: NodeId) -> anyhow::Result<()>;
+    /// Reads back the power states of the nodes, as bit-field.
+    fn read_node_states(&self) -> anyhow::Result<u8>;
+    async fn power_led(&self, on: bool) -> anyhow::Result<()>;
+    async fn status_led(&self, on: bool) -> anyhow::Result<()>;
+    /// Switches the ATX power supply and, when switching it on, waits for
+    /// PS_OK.
+    async fn set_psu(&self, on: bool) -> anyhow::Result<()>;
+    /// State of the ATX power supply, `None` when the board cannot switch it.
+    fn read_psu(&self) -> anyhow::Result<Option<PsuState>>;
+    /// Follows PS_OK as it changes. `None` when the board cannot report its
+    /// changes, [`Self::read_psu`] has to be polled then.
+    fn watch_power_good(&self) -> Option<tokio::sync::watch::Receiver<bool>> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct PsuState {
+    /// PS_ON is asserted
+    pub enabled: bool,
+    /// the supply reports its outputs in range
+    pub power_good: bool,
+}
+
+/// Routes the USB bus and controls the USB boot lines of the nodes.
+pub trait PinControl: Send + Sync {
+    /// Connects `node` to the USB bus in the given mode.
+    fn select_usb(&self, node: NodeId, mode: UsbMode) -> anyhow::Result<()>;
+    fn set_usb_route(&self, route: UsbRoute) -> anyhow::Result<()>;
+    fn set_usb_boot(&self, nodes_state: u8, nodes_mask: u8) -> anyhow::Result<()>;
+    fn set_node1_usb_route(&self, alternative_port: bool) -> anyhow::Result<()>;
+    fn usb_bus_type(&self) -> UsbArchitecture;
+    /// Bit-field of the slots that hold a module, `None` when the board
+    /// cannot detect modules.
+    fn read_presence(&self) -> anyhow::Result<Option<u8>>;
+    /// Reads the line of `node` of the pin called `pin`, see
+    /// [`BoardProfile::pins`].
+    fn read_pin(&self, node: NodeId, pin: &str) -> anyhow::Result<bool>;
+    /// Drives the line of `node` of the pin called `pin`. The access policy
+    /// of the pin is up to the caller.
+    fn write_pin(&self, node: NodeId, pin: &str, value: bool) -> anyhow::Result<()>;
+    /// Connects the microSD slot to the BMC or a node. Whether the card is
+    /// in use is up to the caller.
+    fn select_sd(&self, route: SdRoute) -> anyhow::Result<()>;
+    /// Where the microSD slot is connected to, `None` when the board cannot
+    /// switch it.
+    fn read_sd_route(&self) -> anyhow::Result<Option<SdRoute>>;
+}
+
+/// Creates the hardware controllers for the board described by `profile`.
+pub fn init(
+    profile: &'static BoardProfile,
+) -> anyhow::Result<(Box<dyn PinControl>, Box<dyn PowerControl>)> {
+    use anyhow::Context;
+    let pin_controller = PinController::new(profile).context("pin_controller")?;
+    let power_controller = PowerController::new(profile).context("power_controller")?;
+    let controllers: (Box<dyn PinControl>, Box<dyn PowerControl>) =
+        (Box::new(pin_controller), Box::new(power_controller));
+    #[cfg(feature = "fault-injection")]
+    let controllers = crate::fault_injection::wrap(controllers.0, controllers.1);
+    Ok(controllers)
 }
 
 #[repr(C)]
@@ -86,6 +165,7 @@ impl Display for NodeId {
 }
 
 #[repr(C)]
+#[allow(dead_code)]
 #[derive(Debug, Eq, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
 pub enum NodeType {
     RaspberryPi4,
@@ -106,6 +186,27 @@ pub enum UsbMode {
     Flash,
 }
 
+#[derive(Debug, Eq, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub enum SdRoute {
+    Bmc,
+    Node(NodeId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UsbArchitecture {
+    UsbHub,
+    UsbMux,
+}
+
+impl Display for UsbArchitecture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UsbArchitecture::UsbHub => f.write_str("Usb hub"),
+            UsbArchitecture::UsbMux => f.write_str("Single bus"),
+        }
+    }
+}
+
 impl UsbMode {
     pub fn from_api_mode(value: i32) -> Self {
         match value & 0b11 {
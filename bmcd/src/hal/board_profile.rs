@@ -0,0 +1,360 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Hardware description of the supported boards. A profile is selected once
+//! at startup, either by name from the configuration or by matching the
+//! device-tree model, and tells the HAL which GPIO chips, lines, LEDs and
+//! UARTs to use. New board revisions can adjust a profile with a
+//! [`BoardDescription`] in the device tree or in a file instead of adding a
+//! profile here.
+// the mock backend only uses the node layout of a profile
+#![cfg_attr(feature = "mock", allow(dead_code))]
+mod description;
+
+use super::gpio_definitions::*;
+use super::UsbArchitecture;
+use anyhow::{anyhow, Context};
+pub use description::BoardDescription;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+const DEVICE_TREE: &str = "/proc/device-tree";
+
+#[derive(Debug, Clone)]
+pub struct BoardProfile {
+    /// name used to select the profile in the configuration
+    pub name: &'static str,
+    /// selected when the device-tree model contains this string
+    pub model: Option<&'static str>,
+    pub node_count: usize,
+    /// chip that carries the per-node lines, which are looked up by name
+    pub node_chip: &'static str,
+    /// node power enable lines, in node order
+    pub node_enable: &'static [&'static str],
+    /// lines that put nodes in USB boot mode, in node order
+    pub node_usb_boot: &'static [&'static str],
+    /// sysfs state of the power supply of a node, `{}` is the node number
+    pub node_power_state: &'static str,
+    /// lines that read high while a module is seated in the slot, in node
+    /// order. Empty when the board cannot detect modules.
+    pub node_present: &'static [&'static str],
+    pub usb: UsbProfile,
+    /// ATX power supply control, `None` when the board cannot switch it
+    pub psu: Option<PsuProfile>,
+    /// candidates for the power LED, the first one that exists is used
+    pub power_led: &'static [&'static str],
+    /// candidates for the status LED, the first one that exists is used
+    pub status_led: &'static [&'static str],
+    /// console devices, in node order. `None` detects the mapping of the
+    /// running kernel.
+    pub node_uarts: Option<&'static [&'static str]>,
+    /// cooling device in `/sys/class/thermal` that is the system fan
+    pub system_fan: &'static str,
+    /// sideband lines next to the USB boot and presence lines, see
+    /// [`BoardProfile::pins`]
+    pub node_pins: &'static [NodePin],
+    /// multiplexer of the microSD slot, `None` when the slot is wired to
+    /// the BMC only
+    pub sd_mux: Option<SdMuxProfile>,
+}
+
+/// Name of the pin of the USB boot lines.
+pub const USB_BOOT_PIN: &str = "usb_boot";
+/// Name of the pin of the presence lines.
+pub const PRESENT_PIN: &str = "present";
+
+/// A sideband signal of the nodes, e.g. a sleep or wake line, that can be
+/// read and possibly driven through the API.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodePin {
+    pub name: &'static str,
+    /// one line per node, in node order
+    pub lines: &'static [&'static str],
+    pub access: PinAccess,
+}
+
+/// What API clients may do with a pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PinAccess {
+    /// an input of the BMC
+    ReadOnly,
+    /// driven only while the node is powered off, for straps that the node
+    /// samples at power on
+    WhileOff,
+    /// driven at any time
+    ReadWrite,
+}
+
+/// Layout of the USB switching hardware.
+#[derive(Debug, Clone)]
+pub enum UsbProfile {
+    /// a single USB bus multiplexed to one node at a time
+    Mux {
+        /// chip with the multiplexer lines, addressed by offset
+        chip: &'static str,
+        /// `[SEL1, OE1, SEL2, OE2]`
+        select: [u32; 4],
+        /// values of the select lines that connect a node, in node order
+        node_select: &'static [u8],
+        /// routes the bus to the BMC or the USB port
+        output_switch: u32,
+        /// per-node VBUS lines on the node chip, in node order
+        vbus: &'static [&'static str],
+        /// sysfs state of the power supply of the USB port
+        port_power: &'static str,
+    },
+    /// all nodes attached to a USB hub
+    Hub {
+        chip: &'static str,
+        output_switch: u32,
+        /// `[output switch, source switch]` of the USB-A port of node 1
+        node1_source: [u32; 2],
+    },
+}
+
+/// Lines of the ATX power supply, addressed by offset.
+#[derive(Debug, Clone)]
+pub struct PsuProfile {
+    pub chip: &'static str,
+    /// drives PS_ON of the supply, high switches the supply on
+    pub enable: u32,
+    /// PS_OK of the supply, high while its outputs are in range
+    pub power_good: u32,
+}
+
+/// Multiplexer that connects the microSD slot to the BMC or to one of the
+/// nodes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SdMuxProfile {
+    /// chip with the select lines, which are looked up by name
+    pub chip: &'static str,
+    /// select lines, the first one is the least significant bit
+    pub lines: &'static [&'static str],
+    /// value of the select lines that connects the BMC
+    pub bmc_select: u8,
+    /// values of the select lines that connect a node, in node order
+    pub node_select: &'static [u8],
+}
+
+impl UsbProfile {
+    pub fn architecture(&self) -> UsbArchitecture {
+        match self {
+            UsbProfile::Mux { .. } => UsbArchitecture::UsbMux,
+            UsbProfile::Hub { .. } => UsbArchitecture::UsbHub,
+        }
+    }
+}
+
+pub const TURING_PI_2_4: BoardProfile = BoardProfile {
+    name: "turing_pi_2.4",
+    model: Some("v2.4"),
+    node_count: 4,
+    node_chip: "/dev/gpiochip1",
+    node_enable: &["node1-en", "node2-en", "node3-en", "node4-en"],
+    node_usb_boot: &[
+        "node1-rpiboot",
+        "node2-rpiboot",
+        "node3-rpiboot",
+        "node4-rpiboot",
+    ],
+    node_power_state: "/sys/bus/platform/devices/node{}-power/state",
+    node_present: &[],
+    usb: UsbProfile::Mux {
+        chip: "/dev/gpiochip0",
+        select: [USB_SEL1, USB_OE1, USB_SEL2, USB_OE2],
+        node_select: &[0b1100, 0b1101, 0b0011, 0b0111],
+        output_switch: USB_SWITCH,
+        vbus: &[
+            "node1-usbotg-dev",
+            "node2-usbotg-dev",
+            "node3-usbotg-dev",
+            "node4-usbotg-dev",
+        ],
+        port_power: "/sys/bus/platform/devices/usb-port-power/state",
+    },
+    psu: Some(PsuProfile {
+        chip: "/dev/gpiochip0",
+        enable: POWER_BOARD,
+        power_good: POWER_DETECT,
+    }),
+    power_led: &[
+        "/sys/class/leds/fp::power/brightness",
+        "/sys/class/leds/fp:sys/brightness",
+    ],
+    status_led: &[
+        "/sys/class/leds/fp::status/brightness",
+        "/sys/class/leds/fp:reset/brightness",
+    ],
+    node_uarts: None,
+    system_fan: "cooling_device0",
+    node_pins: &[],
+    sd_mux: None,
+};
+
+pub const TURING_PI_2_5: BoardProfile = BoardProfile {
+    name: "turing_pi_2.5",
+    model: None,
+    node_count: 4,
+    node_chip: "/dev/gpiochip2",
+    node_enable: TURING_PI_2_4.node_enable,
+    node_usb_boot: TURING_PI_2_4.node_usb_boot,
+    node_power_state: TURING_PI_2_4.node_power_state,
+    node_present: TURING_PI_2_4.node_present,
+    usb: UsbProfile::Hub {
+        chip: "/dev/gpiochip0",
+        output_switch: USB_SWITCH_V2_5,
+        node1_source: [NODE1_OUTPUT_SWITCH_V2_5, NODE1_SOURCE_SWITCH_V2_5],
+    },
+    psu: TURING_PI_2_4.psu,
+    power_led: TURING_PI_2_4.power_led,
+    status_led: TURING_PI_2_4.status_led,
+    node_uarts: None,
+    system_fan: TURING_PI_2_4.system_fan,
+    node_pins: TURING_PI_2_4.node_pins,
+    sd_mux: None,
+};
+
+/// Known profiles. Detection picks the first profile whose model matches, the
+/// last profile is the default.
+pub static PROFILES: &[&BoardProfile] = &[&TURING_PI_2_4, &TURING_PI_2_5];
+
+impl BoardProfile {
+    pub fn by_name(name: &str) -> Option<&'static BoardProfile> {
+        PROFILES.iter().copied().find(|p| p.name == name)
+    }
+
+    /// Uses the profile named `name`, or detects the board when `None`.
+    pub fn select(name: Option<&str>) -> anyhow::Result<&'static BoardProfile> {
+        match name {
+            Some(name) => Self::by_name(name).ok_or_else(|| anyhow!("unknown board `{}`", name)),
+            None => {
+                let model = std::fs::read_to_string(Path::new(DEVICE_TREE).join("model"))
+                    .unwrap_or_default();
+                Ok(Self::detect(&model))
+            }
+        }
+    }
+
+    /// Selects a profile like [`Self::select`] and applies the description in
+    /// the device tree and then the one in `description_file`.
+    pub fn load(
+        name: Option<&str>,
+        description_file: Option<&Path>,
+    ) -> anyhow::Result<&'static BoardProfile> {
+        let mut profile = Self::select(name)?;
+        if let Some(description) = BoardDescription::from_device_tree(Path::new(DEVICE_TREE)) {
+            profile = description.apply(profile).context("device tree")?;
+        }
+        if let Some(path) = description_file {
+            profile = BoardDescription::from_file(path)?
+                .apply(profile)
+                .context("board description")?;
+        }
+        Ok(profile)
+    }
+
+    fn detect(model: &str) -> &'static BoardProfile {
+        PROFILES
+            .iter()
+            .copied()
+            .find(|p| p.model.is_some_and(|m| model.contains(m)))
+            .unwrap_or(PROFILES[PROFILES.len() - 1])
+    }
+
+    /// Bitfield with a bit set for every node of the board.
+    pub fn node_mask(&self) -> u8 {
+        ((1u16 << self.node_count) - 1) as u8
+    }
+
+    pub fn node_power_state(&self, node: usize) -> PathBuf {
+        PathBuf::from(self.node_power_state.replace("{}", &node.to_string()))
+    }
+
+    /// All pins of the nodes: the USB boot lines, the presence lines if the
+    /// board has them, and the [`Self::node_pins`].
+    pub fn pins(&self) -> Vec<NodePin> {
+        let mut pins = vec![NodePin {
+            name: USB_BOOT_PIN,
+            lines: self.node_usb_boot,
+            access: PinAccess::WhileOff,
+        }];
+        if !self.node_present.is_empty() {
+            pins.push(NodePin {
+                name: PRESENT_PIN,
+                lines: self.node_present,
+                access: PinAccess::ReadOnly,
+            });
+        }
+        pins.extend_from_slice(self.node_pins);
+        pins
+    }
+
+    pub fn power_led(&self) -> PathBuf {
+        first_existing(self.power_led)
+    }
+
+    pub fn status_led(&self) -> PathBuf {
+        first_existing(self.status_led)
+    }
+}
+
+fn first_existing(candidates: &[&str]) -> PathBuf {
+    let path = candidates
+        .iter()
+        .map(Path::new)
+        .find(|p| p.exists())
+        .unwrap_or(Path::new(candidates[0]));
+    path.to_path_buf()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_by_model() {
+        let profile = BoardProfile::detect("Turing Machines Turing Pi 2 v2.4\0");
+        assert_eq!(profile.name, "turing_pi_2.4");
+        let profile = BoardProfile::detect("Turing Machines Turing Pi 2 v2.5.1\0");
+        assert_eq!(profile.name, "turing_pi_2.5");
+        assert_eq!(BoardProfile::detect("").name, "turing_pi_2.5");
+    }
+
+    #[test]
+    fn profiles_are_consistent() {
+        for profile in PROFILES {
+            assert_eq!(profile.node_enable.len(), profile.node_count);
+            assert_eq!(profile.node_usb_boot.len(), profile.node_count);
+            assert!(
+                profile.node_present.is_empty() || profile.node_present.len() == profile.node_count
+            );
+            if let UsbProfile::Mux {
+                vbus, node_select, ..
+            } = &profile.usb
+            {
+                assert_eq!(vbus.len(), profile.node_count);
+                assert_eq!(node_select.len(), profile.node_count);
+            }
+            assert!(!profile.power_led.is_empty() && !profile.status_led.is_empty());
+            if let Some(mux) = &profile.sd_mux {
+                assert_eq!(mux.node_select.len(), profile.node_count);
+            }
+            for pin in profile.pins() {
+                assert_eq!(pin.lines.len(), profile.node_count, "{}", pin.name);
+            }
+        }
+        assert_eq!(TURING_PI_2_4.node_mask(), 0b1111);
+        assert!(BoardProfile::select(Some("turing_pi_3")).is_err());
+    }
+}
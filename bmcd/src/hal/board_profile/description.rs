@@ -0,0 +1,459 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Hardware described outside of bmcd, which overrides parts of a built-in
+//! profile. A description comes from the `bmcd` node in the device tree,
+//! typically added by an overlay, or from a board description file:
+//!
+//! ```dts
+//! bmcd {
+//!     board-name = "turing_pi_2.6";
+//!     node-enable-lines = "node1-en", "node2-en", "node3-en", "node4-en";
+//!     node-usb-boot-lines = "node1-rpiboot", "node2-rpiboot", ...;
+//!     node-present-lines = "node1-present", "node2-present", ...;
+//!     power-led = "fp::power";
+//!     status-led = "fp::status";
+//!     node-uarts = "serial1", "serial2", "serial3", "serial4";
+//!     system-fan = "cooling_device0";
+//!     node-pins = "sleep";
+//!     sleep-lines = "node1-sleep", "node2-sleep", ...;
+//!     sleep-access = "read_write";
+//!     sd-mux-lines = "sd-sel0", "sd-sel1", "sd-sel2";
+//!     sd-mux-select = <0 1 2 3 4>;
+//! };
+//! ```
+//!
+//! The first value of `sd-mux-select` connects the microSD slot to the BMC,
+//! the others to the nodes in node order. The file uses the same keys in
+//! snake case, with the pins as a list:
+//! `node_pins: [{name: sleep, lines: [...], access: read_write}]`, and the
+//! multiplexer as `sd_mux: {lines: [...], bmc_select: 0, node_select: [...]}`.
+//! GPIO lines
+//! are looked up by name, LEDs by their name in `/sys/class/leds` and UARTs by
+//! their `serialN` alias or device path.
+use super::{BoardProfile, NodePin, PinAccess, SdMuxProfile, UsbProfile};
+use anyhow::{ensure, Context};
+use serde::Deserialize;
+use std::path::Path;
+
+const DEVICE_TREE_NODE: &str = "bmcd";
+const LEDS: &str = "/sys/class/leds";
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BoardDescription {
+    pub name: Option<String>,
+    /// gpio chip of the node lines, all chips are searched when the lines
+    /// are not on the chip of the profile
+    pub node_chip: Option<String>,
+    pub node_enable_lines: Option<Vec<String>>,
+    pub node_usb_boot_lines: Option<Vec<String>>,
+    /// lines that detect whether a module is seated
+    pub node_present_lines: Option<Vec<String>>,
+    pub power_led: Option<String>,
+    pub status_led: Option<String>,
+    pub node_uarts: Option<Vec<String>>,
+    /// cooling device in `/sys/class/thermal` that is the system fan
+    pub system_fan: Option<String>,
+    /// sideband lines exposed through the API
+    pub node_pins: Option<Vec<PinDescription>>,
+    /// multiplexer of the microSD slot
+    pub sd_mux: Option<SdMuxDescription>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PinDescription {
+    pub name: String,
+    pub lines: Vec<String>,
+    #[serde(default = "read_only")]
+    pub access: PinAccess,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SdMuxDescription {
+    /// chip of the select lines, defaults to the chip of the node lines
+    pub chip: Option<String>,
+    pub lines: Vec<String>,
+    pub bmc_select: u8,
+    pub node_select: Vec<u8>,
+}
+
+fn read_only() -> PinAccess {
+    PinAccess::ReadOnly
+}
+
+impl BoardDescription {
+    /// Reads the `bmcd` node below `root`, e.g. `/proc/device-tree`. Returns
+    /// `None` when the device tree does not describe the board.
+    pub fn from_device_tree(root: &Path) -> Option<Self> {
+        let node = root.join(DEVICE_TREE_NODE);
+        if !node.is_dir() {
+            return None;
+        }
+
+        let strings = |property: &str| {
+            let value = std::fs::read(node.join(property)).ok()?;
+            Some(
+                value
+                    .split(|b| *b == 0)
+                    .filter(|s| !s.is_empty())
+                    .map(|s| String::from_utf8_lossy(s).into_owned())
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let string = |property: &str| strings(property).and_then(|s| s.into_iter().next());
+        let cells = |property: &str| {
+            let value = std::fs::read(node.join(property)).ok()?;
+            Some(
+                value
+                    .chunks_exact(4)
+                    .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        Some(BoardDescription {
+            // every node has a `name` property, which is the node name
+            name: string("board-name"),
+            node_chip: string("node-chip"),
+            node_enable_lines: strings("node-enable-lines"),
+            node_usb_boot_lines: strings("node-usb-boot-lines"),
+            node_present_lines: strings("node-present-lines"),
+            power_led: string("power-led"),
+            status_led: string("status-led"),
+            node_uarts: strings("node-uarts"),
+            system_fan: string("system-fan"),
+            node_pins: strings("node-pins").map(|names| {
+                names
+                    .into_iter()
+                    .map(|name| PinDescription {
+                        lines: strings(&format!("{}-lines", name)).unwrap_or_default(),
+                        access: string(&format!("{}-access", name))
+                            .and_then(|a| serde_json::from_value(a.into()).ok())
+                            .unwrap_or(PinAccess::ReadOnly),
+                        name,
+                    })
+                    .collect()
+            }),
+            sd_mux: strings("sd-mux-lines").and_then(|lines| {
+                let select = cells("sd-mux-select")?
+                    .into_iter()
+                    .map(|c| u8::try_from(c).ok())
+                    .collect::<Option<Vec<_>>>()?;
+                let (bmc_select, node_select) = select.split_first()?;
+                Some(SdMuxDescription {
+                    chip: string("sd-mux-chip"),
+                    lines,
+                    bmc_select: *bmc_select,
+                    node_select: node_select.to_vec(),
+                })
+            }),
+        })
+    }
+
+    /// Loads a board description file in YAML or TOML.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        ensure!(path.exists(), "{} does not exist", path.display());
+        config::Config::builder()
+            .add_source(config::File::from(path))
+            .build()
+            .and_then(|c| c.try_deserialize())
+            .with_context(|| path.display().to_string())
+    }
+
+    /// Creates a profile from `base` with the described parts replaced.
+    pub fn apply(self, base: &BoardProfile) -> anyhow::Result<&'static BoardProfile> {
+        let mut profile = base.clone();
+        if let Some(name) = self.name {
+            profile.name = leak(name);
+        }
+        if let Some(chip) = self.node_chip {
+            profile.node_chip = leak(chip);
+        }
+        if let Some(lines) = self.node_enable_lines {
+            ensure!(
+                (1..=4).contains(&lines.len()),
+                "a board has 1 to 4 nodes, {} enable lines given",
+                lines.len()
+            );
+            profile.node_count = lines.len();
+            profile.node_enable = leak_all(lines);
+        }
+        if let Some(lines) = self.node_usb_boot_lines {
+            profile.node_usb_boot = leak_all(lines);
+        }
+        if let Some(lines) = self.node_present_lines {
+            profile.node_present = leak_all(lines);
+        }
+        if let Some(led) = self.power_led {
+            profile.power_led = leak_all(vec![led_path(led)]);
+        }
+        if let Some(led) = self.status_led {
+            profile.status_led = leak_all(vec![led_path(led)]);
+        }
+        if let Some(uarts) = self.node_uarts {
+            profile.node_uarts = Some(leak_all(uarts.into_iter().map(uart_device).collect()));
+        }
+        if let Some(fan) = self.system_fan {
+            profile.system_fan = leak(fan);
+        }
+        if let Some(pins) = self.node_pins {
+            let pins = pins
+                .into_iter()
+                .map(|pin| NodePin {
+                    name: leak(pin.name),
+                    lines: leak_all(pin.lines),
+                    access: pin.access,
+                })
+                .collect::<Vec<_>>();
+            profile.node_pins = Box::leak(pins.into_boxed_slice());
+        }
+        if let Some(mux) = self.sd_mux {
+            ensure!(
+                (1..=8).contains(&mux.lines.len()),
+                "the sd multiplexer has 1 to 8 select lines, {} given",
+                mux.lines.len()
+            );
+            let max = (1u16 << mux.lines.len()) - 1;
+            ensure!(
+                mux.node_select
+                    .iter()
+                    .chain([&mux.bmc_select])
+                    .all(|v| u16::from(*v) <= max),
+                "sd multiplexer select values must fit in {} lines",
+                mux.lines.len()
+            );
+            profile.sd_mux = Some(SdMuxProfile {
+                chip: mux.chip.map(leak).unwrap_or(profile.node_chip),
+                lines: leak_all(mux.lines),
+                bmc_select: mux.bmc_select,
+                node_select: Box::leak(mux.node_select.into_boxed_slice()),
+            });
+        }
+
+        ensure!(
+            profile.node_usb_boot.len() == profile.node_count,
+            "expected {} usb boot lines",
+            profile.node_count
+        );
+        ensure!(
+            profile.node_present.is_empty() || profile.node_present.len() == profile.node_count,
+            "expected {} presence lines",
+            profile.node_count
+        );
+        if let UsbProfile::Mux { vbus, .. } = &profile.usb {
+            ensure!(
+                vbus.len() == profile.node_count,
+                "the usb multiplexer supports {} nodes",
+                vbus.len()
+            );
+        }
+        if let Some(uarts) = profile.node_uarts {
+            ensure!(
+                uarts.len() == profile.node_count,
+                "expected {} node uarts",
+                profile.node_count
+            );
+        }
+        if let Some(mux) = &profile.sd_mux {
+            ensure!(
+                mux.node_select.len() == profile.node_count,
+                "expected {} sd multiplexer select values",
+                profile.node_count
+            );
+        }
+        let pins = profile.pins();
+        for (idx, pin) in pins.iter().enumerate() {
+            ensure!(
+                pins[..idx].iter().all(|p| p.name != pin.name),
+                "pin `{}` is defined twice",
+                pin.name
+            );
+            ensure!(
+                pin.lines.len() == profile.node_count,
+                "expected {} lines for pin `{}`",
+                profile.node_count,
+                pin.name
+            );
+        }
+        Ok(Box::leak(Box::new(profile)))
+    }
+}
+
+/// Descriptions are applied once at startup, the profile lives as long as
+/// the process.
+fn leak(value: String) -> &'static str {
+    Box::leak(value.into_boxed_str())
+}
+
+fn leak_all(values: Vec<String>) -> &'static [&'static str] {
+    Box::leak(values.into_iter().map(leak).collect())
+}
+
+fn led_path(led: String) -> String {
+    if led.starts_with('/') {
+        led
+    } else {
+        format!("{}/{}/brightness", LEDS, led)
+    }
+}
+
+/// The 8250 driver numbers its ports after the `serialN` aliases.
+fn uart_device(uart: String) -> String {
+    if uart.starts_with('/') {
+        uart
+    } else if let Some(index) = uart.strip_prefix("serial") {
+        format!("/dev/ttyS{}", index)
+    } else {
+        format!("/dev/{}", uart)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::board_profile::{TURING_PI_2_4, TURING_PI_2_5};
+    use tempdir::TempDir;
+
+    #[test]
+    fn read_device_tree() {
+        let dir = TempDir::new("device_tree").unwrap();
+        assert_eq!(BoardDescription::from_device_tree(dir.path()), None);
+
+        let node = dir.path().join(DEVICE_TREE_NODE);
+        std::fs::create_dir(&node).unwrap();
+        std::fs::write(node.join("name"), b"bmcd\0").unwrap();
+        std::fs::write(node.join("board-name"), b"custom\0").unwrap();
+        std::fs::write(node.join("node-enable-lines"), b"n1\0n2\0").unwrap();
+        std::fs::write(node.join("node-usb-boot-lines"), b"b1\0b2\0").unwrap();
+        std::fs::write(node.join("node-present-lines"), b"p1\0p2\0").unwrap();
+        std::fs::write(node.join("power-led"), b"pwr\0").unwrap();
+        std::fs::write(node.join("node-uarts"), b"serial3\0ttyAMA0\0").unwrap();
+        std::fs::write(node.join("node-pins"), b"sleep\0").unwrap();
+        std::fs::write(node.join("sleep-lines"), b"s1\0s2\0").unwrap();
+        std::fs::write(node.join("sleep-access"), b"while_off\0").unwrap();
+        std::fs::write(node.join("sd-mux-lines"), b"sd0\0sd1\0").unwrap();
+        std::fs::write(
+            node.join("sd-mux-select"),
+            [0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 2],
+        )
+        .unwrap();
+
+        let description = BoardDescription::from_device_tree(dir.path()).unwrap();
+        assert_eq!(description.power_led.as_deref(), Some("pwr"));
+        let profile = description.apply(&TURING_PI_2_5).unwrap();
+        assert_eq!(profile.name, "custom");
+        assert_eq!(profile.node_count, 2);
+        assert_eq!(profile.node_enable, ["n1", "n2"]);
+        assert_eq!(profile.node_present, ["p1", "p2"]);
+        assert_eq!(profile.power_led, ["/sys/class/leds/pwr/brightness"]);
+        assert_eq!(profile.status_led, TURING_PI_2_5.status_led);
+        assert_eq!(
+            profile.node_uarts,
+            Some(&["/dev/ttyS3", "/dev/ttyAMA0"][..])
+        );
+        assert_eq!(profile.node_chip, TURING_PI_2_5.node_chip);
+        assert_eq!(
+            profile.node_pins,
+            [NodePin {
+                name: "sleep",
+                lines: &["s1", "s2"],
+                access: PinAccess::WhileOff,
+            }]
+        );
+        assert_eq!(
+            profile.sd_mux,
+            Some(SdMuxProfile {
+                chip: TURING_PI_2_5.node_chip,
+                lines: &["sd0", "sd1"],
+                bmc_select: 3,
+                node_select: &[1, 2],
+            })
+        );
+    }
+
+    #[test]
+    fn read_file() {
+        let dir = TempDir::new("board").unwrap();
+        let path = dir.path().join("board.yaml");
+        std::fs::write(
+            &path,
+            "name: custom\nstatus_led: /sys/class/leds/x/brightness\nsystem_fan: cooling_device1\n\
+             node_pins:\n  - name: wake\n    lines: [w1, w2, w3, w4]\n",
+        )
+        .unwrap();
+        let profile = BoardDescription::from_file(&path)
+            .unwrap()
+            .apply(&TURING_PI_2_4)
+            .unwrap();
+        assert_eq!(profile.name, "custom");
+        assert_eq!(profile.status_led, ["/sys/class/leds/x/brightness"]);
+        assert_eq!(profile.system_fan, "cooling_device1");
+        assert_eq!(profile.node_count, 4);
+        assert_eq!(profile.node_pins[0].name, "wake");
+        assert_eq!(profile.node_pins[0].access, PinAccess::ReadOnly);
+
+        std::fs::write(&path, "node_chip: [1, 2]\n").unwrap();
+        assert!(BoardDescription::from_file(&path).is_err());
+        std::fs::write(&path, "fan: cooling_device1\n").unwrap();
+        assert!(BoardDescription::from_file(&path).is_err());
+    }
+
+    #[test]
+    fn inconsistent_node_count() {
+        let description = BoardDescription {
+            node_enable_lines: Some(vec!["n1".into(), "n2".into()]),
+            ..Default::default()
+        };
+        assert!(description.apply(&TURING_PI_2_4).is_err());
+
+        let description = BoardDescription {
+            node_present_lines: Some(vec!["p1".into()]),
+            ..Default::default()
+        };
+        assert!(description.apply(&TURING_PI_2_5).is_err());
+
+        let pin = |name: &str| PinDescription {
+            name: name.into(),
+            lines: vec!["a".into(), "b".into(), "c".into(), "d".into()],
+            access: PinAccess::ReadWrite,
+        };
+        let description = BoardDescription {
+            node_pins: Some(vec![pin("usb_boot")]),
+            ..Default::default()
+        };
+        assert!(description.apply(&TURING_PI_2_5).is_err());
+        let mut wake = pin("wake");
+        wake.lines.pop();
+        let description = BoardDescription {
+            node_pins: Some(vec![pin("sleep"), wake]),
+            ..Default::default()
+        };
+        assert!(description.apply(&TURING_PI_2_5).is_err());
+
+        let sd_mux = |node_select: Vec<u8>| BoardDescription {
+            sd_mux: Some(SdMuxDescription {
+                chip: None,
+                lines: vec!["sd0".into(), "sd1".into()],
+                bmc_select: 0,
+                node_select,
+            }),
+            ..Default::default()
+        };
+        assert!(sd_mux(vec![1, 2, 3]).apply(&TURING_PI_2_5).is_err());
+        assert!(sd_mux(vec![1, 2, 3, 4]).apply(&TURING_PI_2_5).is_err());
+        assert!(sd_mux(vec![1, 2, 3, 3]).apply(&TURING_PI_2_5).is_ok());
+    }
+}
@@ -0,0 +1,321 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Identity of the board, read from its ID EEPROM. The first
+//! [`BOARDINFO_SIZE`] bytes are programmed in the factory and are read-only
+//! to bmcd. A user area further in the EEPROM holds fields that the owner of
+//! the board can set, such as an asset tag.
+//!
+//! # User area layout
+//!
+//! | offset | size | field                                |
+//! | -----: | ---: | :----------------------------------- |
+//! | 64     | 4    | magic `TPU1`                         |
+//! | 68     | 4    | crc32 (big endian) of the fields     |
+//! | 72     | 32   | asset tag, zero padded               |
+use anyhow::{ensure, Context};
+use board_info::{BoardInfo, BoardInfoAttribute, BOARDINFO_SIZE};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::BytesMut;
+use nix::fcntl::{Flock, FlockArg};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::Duration;
+
+const USER_AREA_OFFSET: usize = 64;
+const USER_MAGIC: &[u8; 4] = b"TPU1";
+const ASSET_TAG_LEN: usize = 32;
+const USER_AREA_SIZE: usize = 8 + ASSET_TAG_LEN;
+const EEPROM_SIZE: usize = USER_AREA_OFFSET + USER_AREA_SIZE;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Identity {
+    pub product_name: String,
+    pub hw_revision: String,
+    pub serial: String,
+    pub factory_date: String,
+    pub mac: String,
+    /// false when the factory data is corrupt or was never programmed
+    pub checksum_valid: bool,
+    #[serde(flatten)]
+    pub user: UserFields,
+}
+
+/// Fields of the EEPROM that can be written through the API.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserFields {
+    pub asset_tag: String,
+}
+
+impl UserFields {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        ensure!(
+            self.asset_tag.len() <= ASSET_TAG_LEN,
+            "asset tag exceeds {} characters",
+            ASSET_TAG_LEN
+        );
+        ensure!(
+            self.asset_tag
+                .chars()
+                .all(|c| c.is_ascii_graphic() || c == ' '),
+            "asset tag contains characters other than printable ASCII"
+        );
+        Ok(())
+    }
+
+    fn to_bytes(&self) -> [u8; USER_AREA_SIZE] {
+        let mut bytes = [0u8; USER_AREA_SIZE];
+        bytes[..4].copy_from_slice(USER_MAGIC);
+        bytes[8..8 + self.asset_tag.len()].copy_from_slice(self.asset_tag.as_bytes());
+        let crc = crc32fast::hash(&bytes[8..]);
+        BigEndian::write_u32(&mut bytes[4..8], crc);
+        bytes
+    }
+
+    /// An area that was never written reads as empty fields.
+    fn from_bytes(bytes: &[u8]) -> Self {
+        if bytes.len() < USER_AREA_SIZE || &bytes[..4] != USER_MAGIC {
+            return Self::default();
+        }
+        if BigEndian::read_u32(&bytes[4..8]) != crc32fast::hash(&bytes[8..USER_AREA_SIZE]) {
+            tracing::warn!("EEPROM user area has an invalid checksum");
+            return Self::default();
+        }
+        UserFields {
+            asset_tag: trimmed(&bytes[8..USER_AREA_SIZE]),
+        }
+    }
+}
+
+impl Identity {
+    fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        ensure!(
+            bytes.len() >= BOARDINFO_SIZE,
+            "EEPROM is smaller than {} bytes",
+            BOARDINFO_SIZE
+        );
+        let info = BoardInfo::from_bytes(BytesMut::from(&bytes[..BOARDINFO_SIZE]))?;
+        let user = bytes
+            .get(USER_AREA_OFFSET..)
+            .map(UserFields::from_bytes)
+            .unwrap_or_default();
+        Ok(Identity {
+            product_name: trimmed(info.value_of(&BoardInfoAttribute::ProductName).as_bytes()),
+            hw_revision: info.value_of(&BoardInfoAttribute::HwVersion),
+            serial: trimmed(info.value_of(&BoardInfoAttribute::FactorySerial).as_bytes()),
+            factory_date: info.value_of(&BoardInfoAttribute::FactoryDate),
+            mac: info.value_of(&BoardInfoAttribute::Mac),
+            checksum_valid: BoardInfo::checksum_valid(bytes),
+            user,
+        })
+    }
+}
+
+fn trimmed(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes)
+        .trim_end_matches(['\0', '\u{fffd}'])
+        .to_string()
+}
+
+/// Caches the identity read at startup and serializes writes to the user
+/// area. Writes take an exclusive `flock` on the EEPROM so they do not
+/// interleave with other tools that program it.
+pub struct BoardIdentity {
+    path: Option<PathBuf>,
+    identity: RwLock<Option<Identity>>,
+    write_lock: tokio::sync::Mutex<()>,
+}
+
+impl BoardIdentity {
+    pub fn load() -> Self {
+        #[cfg(not(feature = "mock"))]
+        let path = BoardInfo::find_i2c_device().ok();
+        #[cfg(feature = "mock")]
+        let path = Some(crate::hal::mock::eeprom_path());
+        Self::from_path(path)
+    }
+
+    pub fn from_path(path: Option<PathBuf>) -> Self {
+        let identity = match path.as_deref().map(read_identity) {
+            Some(Ok(identity)) => {
+                if !identity.checksum_valid {
+                    tracing::warn!("EEPROM factory data has an invalid checksum");
+                }
+                Some(identity)
+            }
+            Some(Err(e)) => {
+                tracing::error!("reading EEPROM: {:#}", e);
+                None
+            }
+            None => {
+                tracing::warn!("board has no ID EEPROM");
+                None
+            }
+        };
+
+        BoardIdentity {
+            path,
+            identity: RwLock::new(identity),
+            write_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn identity(&self) -> Option<Identity> {
+        self.identity
+            .read()
+            .expect("identity lock poisoned")
+            .clone()
+    }
+
+    /// Serial number programmed in the factory.
+    pub fn serial(&self) -> Option<String> {
+        self.identity()
+            .filter(|i| i.checksum_valid)
+            .map(|i| i.serial)
+    }
+
+    /// Writes `fields` to the user area and returns the identity as read back
+    /// from the EEPROM.
+    pub async fn set_user_fields(&self, fields: UserFields) -> anyhow::Result<Identity> {
+        fields.validate()?;
+        let path = self.path.clone().context("board has no ID EEPROM")?;
+
+        let _guard = self.write_lock.lock().await;
+        let identity = tokio::task::spawn_blocking(move || {
+            write_user_area(&path, &fields.to_bytes())?;
+            let identity = read_identity(&path)?;
+            ensure!(
+                identity.user == fields,
+                "EEPROM did not retain the written fields"
+            );
+            Ok(identity)
+        })
+        .await??;
+
+        *self.identity.write().expect("identity lock poisoned") = Some(identity.clone());
+        tracing::info!("EEPROM user fields set to {:?}", identity.user);
+        Ok(identity)
+    }
+}
+
+fn read_identity(path: &Path) -> anyhow::Result<Identity> {
+    let mut bytes = Vec::with_capacity(EEPROM_SIZE);
+    std::fs::File::open(path)
+        .and_then(|f| f.take(EEPROM_SIZE as u64).read_to_end(&mut bytes))
+        .with_context(|| path.display().to_string())?;
+    Identity::from_bytes(&bytes)
+}
+
+fn write_user_area(path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
+    let file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .with_context(|| path.display().to_string())?;
+    let mut file = Flock::lock(file, FlockArg::LockExclusiveNonblock)
+        .map_err(|(_, e)| e)
+        .context("EEPROM is locked by another process")?;
+
+    file.seek(SeekFrom::Start(USER_AREA_OFFSET as u64))?;
+    // same workaround for the i2c bus as `BoardInfo::write_back`
+    for byte in bytes {
+        file.write_all(&[*byte])?;
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    file.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn factory_data() -> Vec<u8> {
+        let mut info = BoardInfo::from_bytes(BytesMut::zeroed(BOARDINFO_SIZE)).unwrap();
+        info.product_name("Turing Pi 2");
+        info.factory_serial("TP2-0001");
+        info.hw_version((2 << 11) | (5 << 6) | 1);
+        let mut bytes = info.to_bytes().to_vec();
+        bytes.resize(EEPROM_SIZE, 0xff);
+        bytes
+    }
+
+    #[test]
+    fn parse_identity() {
+        let bytes = factory_data();
+        let identity = Identity::from_bytes(&bytes).unwrap();
+        assert_eq!(identity.product_name, "Turing Pi 2");
+        assert_eq!(identity.serial, "TP2-0001");
+        assert_eq!(identity.hw_revision, "v2.5.1");
+        assert!(identity.checksum_valid);
+        assert_eq!(identity.user, UserFields::default());
+
+        let mut corrupt = bytes.clone();
+        corrupt[20] ^= 1;
+        assert!(!Identity::from_bytes(&corrupt).unwrap().checksum_valid);
+        assert!(Identity::from_bytes(&bytes[..10]).is_err());
+    }
+
+    #[test]
+    fn user_fields() {
+        let fields = UserFields {
+            asset_tag: "rack 4 / unit 12".to_string(),
+        };
+        let mut bytes = factory_data();
+        bytes[USER_AREA_OFFSET..].copy_from_slice(&fields.to_bytes());
+        assert_eq!(Identity::from_bytes(&bytes).unwrap().user, fields);
+
+        bytes[USER_AREA_OFFSET + 9] ^= 1;
+        assert_eq!(
+            Identity::from_bytes(&bytes).unwrap().user,
+            UserFields::default()
+        );
+
+        let too_long = UserFields {
+            asset_tag: "x".repeat(ASSET_TAG_LEN + 1),
+        };
+        assert!(too_long.validate().is_err());
+        let control = UserFields {
+            asset_tag: "a\nb".to_string(),
+        };
+        assert!(control.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn write_user_fields() {
+        let dir = tempdir::TempDir::new("eeprom").unwrap();
+        let path = dir.path().join("eeprom");
+        std::fs::write(&path, factory_data()).unwrap();
+
+        let identity = BoardIdentity::from_path(Some(path.clone()));
+        assert_eq!(identity.serial().as_deref(), Some("TP2-0001"));
+        let fields = UserFields {
+            asset_tag: "asset-7".to_string(),
+        };
+        let updated = identity.set_user_fields(fields.clone()).await.unwrap();
+        assert_eq!(updated.user, fields);
+        assert_eq!(identity.identity().unwrap().user, fields);
+        // the factory data is left alone
+        assert_eq!(
+            std::fs::read(&path).unwrap()[..BOARDINFO_SIZE],
+            factory_data()[..BOARDINFO_SIZE]
+        );
+
+        let missing = BoardIdentity::from_path(None);
+        assert!(missing.identity().is_none());
+        assert!(missing.set_user_fields(fields).await.is_err());
+    }
+}
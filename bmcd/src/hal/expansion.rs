@@ -0,0 +1,339 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Boards attached to the expansion connector. Every expansion board carries
+//! an EEPROM at I2C address 0x50 of the bus it is attached to, which tells
+//! which [`ExpansionDriver`] handles it. The chips on the board itself are
+//! bound by kernel drivers through a device tree overlay, the expansion
+//! drivers operate them through sysfs.
+//!
+//! # EEPROM layout
+//!
+//! | offset | size | field                                  |
+//! | -----: | ---: | :------------------------------------- |
+//! | 0      | 4    | magic `TPEX`                           |
+//! | 4      | 2    | product id (little endian)             |
+//! | 6      | 2    | revision (little endian)               |
+//! | 8      | 24   | product name, zero padded              |
+//! | 32     | 4    | crc32 (big endian) of bytes 0..32      |
+//!
+//! Supporting a new expansion board means adding a driver to [`DRIVERS`].
+mod fan;
+mod rtc;
+mod sensor;
+
+use anyhow::{bail, ensure, Context};
+use async_trait::async_trait;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+const I2C_DEVICES: &str = "/sys/bus/i2c/devices";
+const EEPROM_ADDRESS: u16 = 0x50;
+const MAGIC: &[u8; 4] = b"TPEX";
+const HEADER_SIZE: usize = 36;
+
+/// Driver of one kind of expansion board.
+#[async_trait]
+pub trait ExpansionDriver: Send + Sync {
+    /// Current readings and settings of the board.
+    async fn status(&self) -> anyhow::Result<serde_json::Value>;
+    /// Names of the controls accepted by [`Self::set`].
+    fn controls(&self) -> &'static [&'static str];
+    async fn set(&self, control: &str, value: serde_json::Value) -> anyhow::Result<()>;
+}
+
+/// Locates the devices of an expansion board, which live on the same I2C bus
+/// as its EEPROM.
+pub struct Probe {
+    devices: PathBuf,
+    bus: u32,
+}
+
+impl Probe {
+    /// sysfs directory of the I2C device at `address`.
+    pub fn device(&self, address: u16) -> PathBuf {
+        self.devices.join(format!("{}-{:04x}", self.bus, address))
+    }
+
+    /// The directory in the `class` (e.g. `hwmon`) subdirectory of the device
+    /// at `address`, created when the kernel driver bound to it.
+    pub fn class_device(&self, address: u16, class: &str) -> anyhow::Result<PathBuf> {
+        let dir = self.device(address).join(class);
+        std::fs::read_dir(&dir)
+            .with_context(|| format!("no {} device at {}", class, dir.display()))?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .next()
+            .with_context(|| format!("no {} device at {}", class, dir.display()))
+    }
+}
+
+pub struct Driver {
+    pub product_id: u16,
+    pub name: &'static str,
+    pub probe: fn(&Probe) -> anyhow::Result<Box<dyn ExpansionDriver>>,
+}
+
+pub static DRIVERS: &[Driver] = &[
+    Driver {
+        product_id: 0x0001,
+        name: "fan",
+        probe: fan::probe,
+    },
+    Driver {
+        product_id: 0x0002,
+        name: "rtc",
+        probe: rtc::probe,
+    },
+    Driver {
+        product_id: 0x0003,
+        name: "sensor",
+        probe: sensor::probe,
+    },
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExpansionHeader {
+    pub product_id: u16,
+    pub revision: u16,
+    pub product_name: String,
+}
+
+impl ExpansionHeader {
+    fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        ensure!(bytes.len() >= HEADER_SIZE, "EEPROM is too small");
+        ensure!(&bytes[..4] == MAGIC, "not an expansion board EEPROM");
+        ensure!(
+            BigEndian::read_u32(&bytes[32..36]) == crc32fast::hash(&bytes[..32]),
+            "EEPROM checksum mismatch"
+        );
+        Ok(ExpansionHeader {
+            product_id: LittleEndian::read_u16(&bytes[4..6]),
+            revision: LittleEndian::read_u16(&bytes[6..8]),
+            product_name: String::from_utf8_lossy(&bytes[8..32])
+                .trim_end_matches('\0')
+                .to_string(),
+        })
+    }
+}
+
+pub struct ExpansionModule {
+    /// name of the I2C device of the EEPROM, e.g. `3-0050`
+    pub id: String,
+    pub header: ExpansionHeader,
+    driver: anyhow::Result<(&'static Driver, Box<dyn ExpansionDriver>)>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModuleInfo {
+    pub id: String,
+    #[serde(flatten)]
+    pub header: ExpansionHeader,
+    pub driver: Option<&'static str>,
+    pub controls: &'static [&'static str],
+    /// why the board cannot be used
+    pub error: Option<String>,
+}
+
+impl ExpansionModule {
+    pub fn info(&self) -> ModuleInfo {
+        let (driver, controls, error) = match &self.driver {
+            Ok((driver, instance)) => (Some(driver.name), instance.controls(), None),
+            Err(e) => (None, &[][..], Some(format!("{:#}", e))),
+        };
+        ModuleInfo {
+            id: self.id.clone(),
+            header: self.header.clone(),
+            driver,
+            controls,
+            error,
+        }
+    }
+
+    pub fn driver(&self) -> anyhow::Result<&dyn ExpansionDriver> {
+        match &self.driver {
+            Ok((_, driver)) => Ok(driver.as_ref()),
+            Err(e) => bail!("{}: {:#}", self.id, e),
+        }
+    }
+}
+
+/// Expansion boards found at startup.
+pub struct Expansions {
+    modules: Vec<ExpansionModule>,
+}
+
+impl Expansions {
+    pub fn detect() -> Self {
+        Self::detect_in(Path::new(I2C_DEVICES))
+    }
+
+    fn detect_in(devices: &Path) -> Self {
+        let suffix = format!("-{:04x}", EEPROM_ADDRESS);
+        let mut candidates: Vec<_> = std::fs::read_dir(devices)
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let bus = name.strip_suffix(&suffix)?.parse::<u32>().ok()?;
+                Some((name, bus))
+            })
+            .collect();
+        candidates.sort();
+
+        let mut modules = Vec::new();
+        for (id, bus) in candidates {
+            let eeprom = devices.join(&id).join("eeprom");
+            // other EEPROMs, such as the ID EEPROM of the board, are skipped
+            let Ok(header) = read_header(&eeprom) else {
+                continue;
+            };
+            let probe = Probe {
+                devices: devices.to_path_buf(),
+                bus,
+            };
+            let driver = load_driver(&header, &probe);
+            match &driver {
+                Ok((driver, _)) => tracing::info!(
+                    "expansion {}: {} rev {} ({} driver)",
+                    id,
+                    header.product_name,
+                    header.revision,
+                    driver.name
+                ),
+                Err(e) => tracing::warn!("expansion {}: {}: {:#}", id, header.product_name, e),
+            }
+            modules.push(ExpansionModule { id, header, driver });
+        }
+        Expansions { modules }
+    }
+
+    pub fn modules(&self) -> &[ExpansionModule] {
+        &self.modules
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ExpansionModule> {
+        self.modules.iter().find(|m| m.id == id)
+    }
+}
+
+fn read_header(eeprom: &Path) -> anyhow::Result<ExpansionHeader> {
+    use std::io::Read;
+    let mut bytes = Vec::with_capacity(HEADER_SIZE);
+    std::fs::File::open(eeprom)?
+        .take(HEADER_SIZE as u64)
+        .read_to_end(&mut bytes)?;
+    ExpansionHeader::from_bytes(&bytes)
+}
+
+fn load_driver(
+    header: &ExpansionHeader,
+    probe: &Probe,
+) -> anyhow::Result<(&'static Driver, Box<dyn ExpansionDriver>)> {
+    let driver = DRIVERS
+        .iter()
+        .find(|d| d.product_id == header.product_id)
+        .with_context(|| format!("no driver for product {:#06x}", header.product_id))?;
+    let instance = (driver.probe)(probe).with_context(|| format!("{} driver", driver.name))?;
+    Ok((driver, instance))
+}
+
+/// Reads a sysfs attribute and parses it.
+fn read_attribute<T: std::str::FromStr>(path: &Path) -> anyhow::Result<T> {
+    let value = std::fs::read_to_string(path).with_context(|| path.display().to_string())?;
+    value
+        .trim()
+        .parse()
+        .map_err(|_| anyhow::anyhow!("{}: invalid value `{}`", path.display(), value.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn header(product_id: u16, name: &str) -> Vec<u8> {
+        let mut bytes = vec![0u8; HEADER_SIZE];
+        bytes[..4].copy_from_slice(MAGIC);
+        LittleEndian::write_u16(&mut bytes[4..6], product_id);
+        LittleEndian::write_u16(&mut bytes[6..8], 2);
+        bytes[8..8 + name.len()].copy_from_slice(name.as_bytes());
+        let crc = crc32fast::hash(&bytes[..32]);
+        BigEndian::write_u32(&mut bytes[32..36], crc);
+        bytes
+    }
+
+    fn add_device(root: &Path, name: &str, files: &[(&str, &str)]) {
+        for (file, content) in files {
+            let path = root.join(name).join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn detect_boards() {
+        let dir = tempdir::TempDir::new("i2c").unwrap();
+        let root = dir.path();
+        // ID EEPROM of the board itself
+        std::fs::create_dir_all(root.join("0-0050")).unwrap();
+        std::fs::write(root.join("0-0050/eeprom"), [0xffu8; 64]).unwrap();
+        // fan board with its controller bound
+        std::fs::create_dir_all(root.join("3-0050")).unwrap();
+        std::fs::write(root.join("3-0050/eeprom"), header(0x0001, "Fan board")).unwrap();
+        add_device(
+            root,
+            "3-002f/hwmon/hwmon4",
+            &[("pwm1", "128\n"), ("fan1_input", "2400\n")],
+        );
+        // sensor board without its kernel driver
+        std::fs::create_dir_all(root.join("4-0050")).unwrap();
+        std::fs::write(root.join("4-0050/eeprom"), header(0x0003, "Sensor board")).unwrap();
+        // unknown board
+        std::fs::create_dir_all(root.join("5-0050")).unwrap();
+        std::fs::write(root.join("5-0050/eeprom"), header(0x0099, "Prototype")).unwrap();
+
+        let expansions = Expansions::detect_in(root);
+        let infos: Vec<_> = expansions.modules().iter().map(|m| m.info()).collect();
+        assert_eq!(infos.len(), 3);
+        assert_eq!(infos[0].id, "3-0050");
+        assert_eq!(infos[0].header.product_name, "Fan board");
+        assert_eq!(infos[0].driver, Some("fan"));
+        assert!(infos[1].error.as_ref().unwrap().contains("hwmon"));
+        assert!(infos[2].error.as_ref().unwrap().contains("no driver"));
+
+        let fan = expansions.get("3-0050").unwrap().driver().unwrap();
+        assert_eq!(
+            fan.status().await.unwrap(),
+            json!({"pwm": 128, "rpm": 2400})
+        );
+        fan.set("pwm", json!(200)).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("3-002f/hwmon/hwmon4/pwm1")).unwrap(),
+            "200"
+        );
+        assert!(fan.set("pwm", json!(256)).await.is_err());
+        assert!(expansions.get("4-0050").unwrap().driver().is_err());
+    }
+
+    #[test]
+    fn corrupt_header() {
+        let mut bytes = header(0x0002, "RTC board");
+        assert!(ExpansionHeader::from_bytes(&bytes).is_ok());
+        bytes[10] ^= 1;
+        assert!(ExpansionHeader::from_bytes(&bytes).is_err());
+        assert!(ExpansionHeader::from_bytes(&bytes[..20]).is_err());
+    }
+}
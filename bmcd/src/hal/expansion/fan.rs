@@ -0,0 +1,62 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Fan board with an EMC2301 fan controller, bound by the `emc2305` hwmon
+//! driver.
+use super::{read_attribute, ExpansionDriver, Probe};
+use anyhow::{ensure, Context};
+use async_trait::async_trait;
+use serde_json::json;
+use std::path::PathBuf;
+
+const CONTROLLER_ADDRESS: u16 = 0x2f;
+
+pub fn probe(probe: &Probe) -> anyhow::Result<Box<dyn ExpansionDriver>> {
+    let hwmon = probe.class_device(CONTROLLER_ADDRESS, "hwmon")?;
+    Ok(Box::new(FanBoard { hwmon }))
+}
+
+struct FanBoard {
+    hwmon: PathBuf,
+}
+
+#[async_trait]
+impl ExpansionDriver for FanBoard {
+    async fn status(&self) -> anyhow::Result<serde_json::Value> {
+        let pwm: u8 = read_attribute(&self.hwmon.join("pwm1"))?;
+        let rpm: u32 = read_attribute(&self.hwmon.join("fan1_input"))?;
+        Ok(json!({ "pwm": pwm, "rpm": rpm }))
+    }
+
+    fn controls(&self) -> &'static [&'static str] {
+        &["pwm"]
+    }
+
+    async fn set(&self, control: &str, value: serde_json::Value) -> anyhow::Result<()> {
+        ensure!(control == "pwm", "unknown control {}", control);
+        let pwm = value
+            .as_u64()
+            .filter(|pwm| *pwm <= u8::MAX as u64)
+            .context("pwm must be a number between 0 and 255")?;
+
+        // switch to manual control, when the driver supports automatic control
+        let enable = self.hwmon.join("pwm1_enable");
+        if enable.exists() {
+            tokio::fs::write(&enable, "1").await?;
+        }
+        let path = self.hwmon.join("pwm1");
+        tokio::fs::write(&path, pwm.to_string())
+            .await
+            .with_context(|| path.display().to_string())
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! RTC board with a battery backed DS3231, bound by the `rtc-ds1307` driver.
+use super::{ExpansionDriver, Probe};
+use anyhow::{ensure, Context};
+use async_trait::async_trait;
+use serde_json::json;
+use std::path::PathBuf;
+use tokio::process::Command;
+
+const RTC_ADDRESS: u16 = 0x68;
+
+pub fn probe(probe: &Probe) -> anyhow::Result<Box<dyn ExpansionDriver>> {
+    let rtc = probe.class_device(RTC_ADDRESS, "rtc")?;
+    Ok(Box::new(RtcBoard { rtc }))
+}
+
+struct RtcBoard {
+    rtc: PathBuf,
+}
+
+impl RtcBoard {
+    fn device(&self) -> String {
+        let name = self.rtc.file_name().unwrap_or_default().to_string_lossy();
+        format!("/dev/{}", name)
+    }
+
+    fn attribute(&self, name: &str) -> anyhow::Result<String> {
+        let path = self.rtc.join(name);
+        Ok(std::fs::read_to_string(&path)
+            .with_context(|| path.display().to_string())?
+            .trim()
+            .to_string())
+    }
+}
+
+#[async_trait]
+impl ExpansionDriver for RtcBoard {
+    async fn status(&self) -> anyhow::Result<serde_json::Value> {
+        Ok(json!({
+            "device": self.device(),
+            "date": self.attribute("date")?,
+            "time": self.attribute("time")?,
+        }))
+    }
+
+    fn controls(&self) -> &'static [&'static str] {
+        &["sync"]
+    }
+
+    /// `sync` writes the system time to the RTC.
+    async fn set(&self, control: &str, _value: serde_json::Value) -> anyhow::Result<()> {
+        ensure!(control == "sync", "unknown control {}", control);
+        let status = Command::new("hwclock")
+            .args(["-w", "-u", "-f"])
+            .arg(self.device())
+            .status()
+            .await?;
+        ensure!(status.success(), "hwclock exited with {}", status);
+        Ok(())
+    }
+}
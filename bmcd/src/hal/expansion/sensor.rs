@@ -0,0 +1,47 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Sensor board with a TMP102 temperature sensor, bound by the `tmp102`
+//! hwmon driver.
+use super::{read_attribute, ExpansionDriver, Probe};
+use anyhow::bail;
+use async_trait::async_trait;
+use serde_json::json;
+use std::path::PathBuf;
+
+const SENSOR_ADDRESS: u16 = 0x48;
+
+pub fn probe(probe: &Probe) -> anyhow::Result<Box<dyn ExpansionDriver>> {
+    let hwmon = probe.class_device(SENSOR_ADDRESS, "hwmon")?;
+    Ok(Box::new(SensorBoard { hwmon }))
+}
+
+struct SensorBoard {
+    hwmon: PathBuf,
+}
+
+#[async_trait]
+impl ExpansionDriver for SensorBoard {
+    async fn status(&self) -> anyhow::Result<serde_json::Value> {
+        let milli_celsius: i32 = read_attribute(&self.hwmon.join("temp1_input"))?;
+        Ok(json!({ "temperature": milli_celsius as f64 / 1000.0 }))
+    }
+
+    fn controls(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    async fn set(&self, control: &str, _value: serde_json::Value) -> anyhow::Result<()> {
+        bail!("unknown control {}", control)
+    }
+}
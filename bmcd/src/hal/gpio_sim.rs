@@ -0,0 +1,362 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Simulated GPIO chips created with the kernel's `gpio-sim` module. Unlike
+//! the `mock` feature, the real pin and power controllers drive the lines
+//! through the GPIO character device, so this exercises the same code that
+//! runs on the board.
+//!
+//! [`GpioSim::new`] creates a chip for the node lines and one for the USB
+//! lines of a profile and returns a profile that points to them. The sysfs
+//! files that the controllers write, such as LEDs and regulator states, are
+//! redirected to a scratch directory. Tests read back the line values through
+//! the sysfs attributes of `gpio-sim`.
+//!
+//! Requires root and `modprobe gpio-sim`. Everything is removed again when
+//! the `GpioSim` is dropped.
+use super::board_profile::{BoardProfile, UsbProfile};
+use anyhow::{ensure, Context};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const CONFIGFS: &str = "/sys/kernel/config/gpio-sim";
+
+struct Bank {
+    /// configfs directory of the bank
+    config: PathBuf,
+    /// line names by offset, unnamed lines are `None`
+    lines: Vec<Option<&'static str>>,
+}
+
+pub struct GpioSim {
+    device: PathBuf,
+    banks: Vec<Bank>,
+    scratch: PathBuf,
+    profile: &'static BoardProfile,
+}
+
+impl GpioSim {
+    /// Creates the simulated chips for `base` under the configfs name `name`.
+    pub fn new(name: &str, base: &'static BoardProfile) -> anyhow::Result<Self> {
+        ensure!(
+            Path::new(CONFIGFS).exists(),
+            "{} does not exist, is the gpio-sim module loaded?",
+            CONFIGFS
+        );
+        let device = Path::new(CONFIGFS).join(name);
+        fs::create_dir(&device).with_context(|| device.display().to_string())?;
+        let scratch = std::env::temp_dir().join(format!("bmcd-gpio-sim-{}", name));
+        fs::create_dir_all(&scratch)?;
+
+        let mut sim = GpioSim {
+            device,
+            banks: Vec::new(),
+            scratch,
+            profile: base,
+        };
+        sim.profile = sim.build(base)?;
+        Ok(sim)
+    }
+
+    fn build(&mut self, base: &BoardProfile) -> anyhow::Result<&'static BoardProfile> {
+        let mut node_lines: Vec<_> = base
+            .node_enable
+            .iter()
+            .chain(base.node_usb_boot)
+            .chain(base.node_present)
+            .chain(base.node_pins.iter().flat_map(|pin| pin.lines))
+            .map(|name| Some(*name))
+            .collect();
+        let mut usb_lines: Vec<Option<&'static str>> = Vec::new();
+        let mut use_offset = |offset: u32| {
+            let offset = offset as usize;
+            if usb_lines.len() <= offset {
+                usb_lines.resize(offset + 1, None);
+            }
+        };
+        match &base.usb {
+            UsbProfile::Mux {
+                select,
+                output_switch,
+                vbus,
+                ..
+            } => {
+                select.iter().for_each(|o| use_offset(*o));
+                use_offset(*output_switch);
+                node_lines.extend(vbus.iter().map(|name| Some(*name)));
+            }
+            UsbProfile::Hub {
+                output_switch,
+                node1_source,
+                ..
+            } => {
+                use_offset(*output_switch);
+                node1_source.iter().for_each(|o| use_offset(*o));
+            }
+        }
+
+        if let Some(psu) = &base.psu {
+            use_offset(psu.enable);
+            use_offset(psu.power_good);
+        }
+
+        self.add_bank("bmcd-nodes", node_lines)?;
+        self.add_bank("bmcd-usb", usb_lines)?;
+        write(&self.device.join("live"), "1")?;
+
+        let mut profile = base.clone();
+        profile.name = leak(format!("{} (gpio-sim)", base.name));
+        profile.node_chip = leak(self.chip_device(0)?);
+        let usb_chip = leak(self.chip_device(1)?);
+        match &mut profile.usb {
+            UsbProfile::Mux {
+                chip, port_power, ..
+            } => {
+                *chip = usb_chip;
+                *port_power = self.scratch_file("usb-port-power")?;
+            }
+            UsbProfile::Hub { chip, .. } => *chip = usb_chip,
+        }
+        if let Some(psu) = &mut profile.psu {
+            psu.chip = usb_chip;
+        }
+        profile.node_power_state = leak(self.scratch.join("node{}-power").display().to_string());
+        for node in 1..=base.node_count {
+            self.scratch_file(&format!("node{}-power", node))?;
+        }
+        profile.power_led = std::slice::from_ref(Box::leak(Box::new(
+            self.scratch_file("power-led")?,
+        )));
+        profile.status_led = std::slice::from_ref(Box::leak(Box::new(
+            self.scratch_file("status-led")?,
+        )));
+        Ok(Box::leak(Box::new(profile)))
+    }
+
+    fn add_bank(&mut self, label: &str, lines: Vec<Option<&'static str>>) -> anyhow::Result<()> {
+        let config = self.device.join(format!("bank{}", self.banks.len()));
+        fs::create_dir(&config)?;
+        // register the bank before it is complete so it is cleaned up
+        self.banks.push(Bank {
+            config: config.clone(),
+            lines: lines.clone(),
+        });
+        write(&config.join("label"), label)?;
+        write(&config.join("num_lines"), &lines.len().to_string())?;
+        for (offset, name) in lines.iter().enumerate() {
+            if let Some(name) = name {
+                let line = config.join(format!("line{}", offset));
+                fs::create_dir(&line)?;
+                write(&line.join("name"), name)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn chip_name(&self, bank: usize) -> anyhow::Result<String> {
+        read(&self.banks[bank].config.join("chip_name"))
+    }
+
+    fn chip_device(&self, bank: usize) -> anyhow::Result<String> {
+        Ok(format!("/dev/{}", self.chip_name(bank)?))
+    }
+
+    fn scratch_file(&self, name: &str) -> anyhow::Result<&'static str> {
+        let path = self.scratch.join(name);
+        fs::write(&path, "")?;
+        Ok(leak(path.display().to_string()))
+    }
+
+    /// Profile that drives the simulated chips.
+    pub fn profile(&self) -> &'static BoardProfile {
+        self.profile
+    }
+}
+
+#[cfg(test)]
+impl GpioSim {
+    fn line_attribute(&self, bank: usize, offset: usize, attribute: &str) -> anyhow::Result<PathBuf> {
+        let dev_name = read(&self.device.join("dev_name"))?;
+        Ok(Path::new("/sys/devices/platform")
+            .join(dev_name)
+            .join(self.chip_name(bank)?)
+            .join(format!("sim_gpio{}", offset))
+            .join(attribute))
+    }
+
+    fn find(&self, name: &str) -> anyhow::Result<(usize, usize)> {
+        self.banks
+            .iter()
+            .enumerate()
+            .find_map(|(bank, b)| {
+                b.lines
+                    .iter()
+                    .position(|l| *l == Some(name))
+                    .map(|offset| (bank, offset))
+            })
+            .with_context(|| format!("no simulated line {}", name))
+    }
+
+    /// Value that the controllers drive on the line called `name`.
+    pub fn value(&self, name: &str) -> anyhow::Result<bool> {
+        let (bank, offset) = self.find(name)?;
+        self.value_at(bank, offset)
+    }
+
+    /// Value of the line at `offset` in `bank`, for lines without a name.
+    pub fn value_at(&self, bank: usize, offset: usize) -> anyhow::Result<bool> {
+        Ok(read(&self.line_attribute(bank, offset, "value")?)? == "1")
+    }
+
+    /// Contents of a sysfs file that was redirected to the scratch directory.
+    pub fn scratch_value(&self, path: &str) -> anyhow::Result<String> {
+        read(Path::new(path))
+    }
+}
+
+impl Drop for GpioSim {
+    fn drop(&mut self) {
+        let _ = fs::write(self.device.join("live"), "0");
+        for bank in &self.banks {
+            for offset in 0..bank.lines.len() {
+                let _ = fs::remove_dir(bank.config.join(format!("line{}", offset)));
+            }
+            let _ = fs::remove_dir(&bank.config);
+        }
+        if let Err(e) = fs::remove_dir(&self.device) {
+            tracing::warn!("removing {}: {}", self.device.display(), e);
+        }
+        let _ = fs::remove_dir_all(&self.scratch);
+    }
+}
+
+fn read(path: &Path) -> anyhow::Result<String> {
+    Ok(fs::read_to_string(path)
+        .with_context(|| path.display().to_string())?
+        .trim()
+        .to_string())
+}
+
+fn write(path: &Path, value: &str) -> anyhow::Result<()> {
+    fs::write(path, value).with_context(|| path.display().to_string())
+}
+
+/// The simulation lives until the process exits, like the profiles it
+/// replaces.
+fn leak(value: String) -> &'static str {
+    Box::leak(value.into_boxed_str())
+}
+
+/// On-target tests of the power and USB sequencing. Run them as root with
+/// `modprobe gpio-sim && cargo test gpio_sim -- --ignored --test-threads 1`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::board_profile::{TURING_PI_2_4, TURING_PI_2_5};
+    use crate::hal::{
+        NodeId, PinControl, PinController, PowerControl, PowerController, UsbMode, UsbRoute,
+    };
+
+    #[tokio::test]
+    #[ignore = "needs root and the gpio-sim kernel module"]
+    async fn power_sequencing() {
+        let sim = GpioSim::new("bmcd-test-power", &TURING_PI_2_5).unwrap();
+        let profile = sim.profile();
+        let power = PowerController::new(profile).unwrap();
+
+        power.set_power_node(0b0101, 0b1111).await.unwrap();
+        assert!(sim.value("node1-en").unwrap());
+        assert!(!sim.value("node2-en").unwrap());
+        assert!(sim.value("node3-en").unwrap());
+        assert_eq!(power.read_node_states().unwrap(), 0b0101);
+        assert_eq!(
+            sim.scratch_value(&profile.node_power_state(2).display().to_string())
+                .unwrap(),
+            "disabled"
+        );
+
+        power.reset_node(NodeId::Node3).await.unwrap();
+        assert_eq!(power.read_node_states().unwrap(), 0b0101);
+
+        power.power_led(true).await.unwrap();
+        assert_eq!(sim.scratch_value(profile.power_led[0]).unwrap(), "1");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs root and the gpio-sim kernel module"]
+    async fn psu_switching() {
+        let sim = GpioSim::new("bmcd-test-psu", &TURING_PI_2_5).unwrap();
+        let power = PowerController::new(sim.profile()).unwrap();
+        let enable = TURING_PI_2_5.psu.as_ref().unwrap().enable as usize;
+
+        // requesting the lines must not switch the supply off
+        assert!(sim.value_at(1, enable).unwrap());
+        power.set_psu(false).await.unwrap();
+        assert!(!sim.value_at(1, enable).unwrap());
+        let state = power.read_psu().unwrap().unwrap();
+        assert!(!state.enabled && !state.power_good);
+        // nothing pulls the simulated PS_OK up
+        assert!(power.set_psu(true).await.is_err());
+        assert!(sim.value_at(1, enable).unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs root and the gpio-sim kernel module"]
+    async fn usb_mux_sequencing() {
+        let sim = GpioSim::new("bmcd-test-mux", &TURING_PI_2_4).unwrap();
+        let pins = PinController::new(sim.profile()).unwrap();
+        let UsbProfile::Mux {
+            select,
+            output_switch,
+            ..
+        } = &TURING_PI_2_4.usb
+        else {
+            unreachable!()
+        };
+        let select_value = || {
+            select.iter().enumerate().fold(0u8, |acc, (bit, offset)| {
+                acc | (u8::from(sim.value_at(1, *offset as usize).unwrap()) << bit)
+            })
+        };
+
+        pins.select_usb(NodeId::Node3, UsbMode::Flash).unwrap();
+        assert_eq!(select_value(), 0b0011);
+        assert!(sim.value("node3-rpiboot").unwrap());
+        assert!(sim.value("node3-usbotg-dev").unwrap());
+
+        pins.select_usb(NodeId::Node2, UsbMode::Host).unwrap();
+        assert_eq!(select_value(), 0b1101);
+        assert!(!sim.value("node3-rpiboot").unwrap());
+        assert!(!sim.value("node2-usbotg-dev").unwrap());
+        assert!(sim.value("node1-usbotg-dev").unwrap());
+
+        pins.set_usb_route(UsbRoute::Bmc).unwrap();
+        assert!(sim.value_at(1, *output_switch as usize).unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs root and the gpio-sim kernel module"]
+    async fn usb_hub_node1_route() {
+        let sim = GpioSim::new("bmcd-test-hub", &TURING_PI_2_5).unwrap();
+        let pins = PinController::new(sim.profile()).unwrap();
+        let UsbProfile::Hub { node1_source, .. } = &TURING_PI_2_5.usb else {
+            unreachable!()
+        };
+
+        pins.set_node1_usb_route(true).unwrap();
+        for offset in node1_source {
+            assert!(sim.value_at(1, *offset as usize).unwrap());
+        }
+        assert!(pins.select_usb(NodeId::Node1, UsbMode::Host).is_err());
+    }
+}
@@ -11,7 +11,9 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+#[cfg(not(feature = "mock"))]
 use std::collections::HashMap;
+
 const NODE_COUNT: u8 = 4;
 
 /// small helper macro which handles the code duplication of declaring gpio lines.
@@ -24,18 +26,6 @@ macro_rules! gpio_output_lines {
     };
 }
 
-/// uses [`gpio_output_lines`] to declare an array of `gpiod::Lines` objects
-#[macro_export]
-macro_rules! gpio_output_array {
-    ($chip:ident, $($pin:ident),+) => {
-       [
-           $(
-               $crate::gpio_output_lines!($chip, [$pin])
-           ),*
-       ]
-    };
-}
-
 /// Helper function that converts a bitfield + mask into an iterator. This
 /// iterator iterates over each bit, and skips the bits that are not set in the
 /// nodes_mask.
@@ -43,8 +33,8 @@ macro_rules! gpio_output_array {
 /// # Arguments
 ///
 /// * `node_states`     bit-field where each bit represents a node on the
-///     turing-pi board, if bit(n) = 1 equals 'select' and bit(n) = 0 equals
-///     'unselect'.
+///   turing-pi board, if bit(n) = 1 equals 'select' and bit(n) = 0 equals
+///   'unselect'.
 /// * `node_mask`       mask which bits to select.
 ///
 /// # Returns
@@ -58,10 +48,90 @@ pub fn bit_iterator(nodes_state: u8, nodes_mask: u8) -> impl Iterator<Item = (us
     })
 }
 
-pub fn load_lines(chip: &gpiod::Chip) -> HashMap<String, gpiod::LineId> {
+#[cfg(not(feature = "mock"))]
+fn load_lines(chip: &gpiod::Chip) -> HashMap<String, gpiod::LineId> {
     HashMap::from_iter((0..chip.num_lines()).filter_map(|i| {
         chip.line_info(i)
             .ok()
             .map(|info| (info.name, i as gpiod::LineId))
     }))
 }
+
+/// Looks up the offsets of the lines called `names`.
+#[cfg(not(feature = "mock"))]
+pub fn find_lines(chip: &gpiod::Chip, names: &[&str]) -> anyhow::Result<Vec<gpiod::LineId>> {
+    let lines = load_lines(chip);
+    names
+        .iter()
+        .map(|name| {
+            lines
+                .get(*name)
+                .copied()
+                .ok_or_else(|| anyhow::anyhow!("cannot find {} gpio", name))
+        })
+        .collect()
+}
+
+/// Opens the chip at `path` when it carries the lines called `names`,
+/// otherwise the first chip that does. Line names come from the device tree,
+/// the chip numbering can differ between kernels and board revisions.
+#[cfg(not(feature = "mock"))]
+pub fn open_chip_with_lines(path: &str, names: &[&str]) -> anyhow::Result<gpiod::Chip> {
+    use anyhow::Context;
+    let has_lines = |chip: &gpiod::Chip| {
+        let lines = load_lines(chip);
+        names.iter().all(|name| lines.contains_key(*name))
+    };
+
+    if let Ok(chip) = gpiod::Chip::new(path) {
+        if has_lines(&chip) {
+            return Ok(chip);
+        }
+    }
+
+    let mut chips = std::fs::read_dir("/dev")
+        .context("/dev")?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| p.to_string_lossy().starts_with("/dev/gpiochip"))
+        .collect::<Vec<_>>();
+    chips.sort();
+    chips
+        .into_iter()
+        .filter_map(|p| gpiod::Chip::new(p).ok())
+        .find(has_lines)
+        .ok_or_else(|| anyhow::anyhow!("no gpio chip has the lines {}", names.join(", ")))
+}
+
+/// Requests each line called `names` as separate output.
+#[cfg(not(feature = "mock"))]
+pub fn output_lines_by_name(
+    chip: &gpiod::Chip,
+    names: &[&str],
+) -> anyhow::Result<Vec<gpiod::Lines<gpiod::Output>>> {
+    use anyhow::Context;
+    find_lines(chip, names)?
+        .into_iter()
+        .zip(names)
+        .map(|(line, name)| {
+            chip.request_lines(gpiod::Options::output([line]))
+                .with_context(|| format!("error initializing pin {}", name))
+        })
+        .collect()
+}
+
+/// Requests each line called `names` as separate input.
+#[cfg(not(feature = "mock"))]
+pub fn input_lines_by_name(
+    chip: &gpiod::Chip,
+    names: &[&str],
+) -> anyhow::Result<Vec<gpiod::Lines<gpiod::Input>>> {
+    use anyhow::Context;
+    find_lines(chip, names)?
+        .into_iter()
+        .zip(names)
+        .map(|(line, name)| {
+            chip.request_lines(gpiod::Options::input([line]))
+                .with_context(|| format!("error initializing pin {}", name))
+        })
+        .collect()
+}
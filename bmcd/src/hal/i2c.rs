@@ -0,0 +1,116 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Register access to I2C devices through `/dev/i2c-N`. Registers are
+//! addressed with one byte, reads are a combined write-read transaction so
+//! no other master can move the register pointer in between.
+use anyhow::{ensure, Context};
+
+/// Upper bound of the bytes transferred in one request.
+pub const MAX_TRANSFER: usize = 32;
+
+#[cfg(not(feature = "mock"))]
+mod device {
+    use crate::error::BmcError;
+    use std::fs::OpenOptions;
+    use std::os::fd::AsRawFd;
+    use std::path::PathBuf;
+
+    const I2C_M_RD: u16 = 0x0001;
+
+    #[repr(C)]
+    struct I2cMsg {
+        addr: u16,
+        flags: u16,
+        len: u16,
+        buf: *mut u8,
+    }
+
+    #[repr(C)]
+    struct I2cRdwrIoctlData {
+        msgs: *mut I2cMsg,
+        nmsgs: u32,
+    }
+
+    nix::ioctl_write_ptr_bad!(i2c_rdwr, 0x0707, I2cRdwrIoctlData);
+
+    /// Runs `messages` as one combined transaction, `true` marks a read.
+    pub fn transfer(
+        bus: u32,
+        address: u16,
+        messages: &mut [(bool, &mut [u8])],
+    ) -> anyhow::Result<()> {
+        let path = PathBuf::from(format!("/dev/i2c-{}", bus));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .map_err(|source| BmcError::Device { path, source })?;
+        let mut msgs: Vec<I2cMsg> = messages
+            .iter_mut()
+            .map(|(read, buf)| I2cMsg {
+                addr: address,
+                flags: if *read { I2C_M_RD } else { 0 },
+                len: buf.len() as u16,
+                buf: buf.as_mut_ptr(),
+            })
+            .collect();
+        let data = I2cRdwrIoctlData {
+            msgs: msgs.as_mut_ptr(),
+            nmsgs: msgs.len() as u32,
+        };
+        // SAFETY: the buffers outlive the ioctl and their lengths are set in
+        // the messages
+        unsafe { i2c_rdwr(file.as_raw_fd(), &data) }?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "mock")]
+use crate::hal::mock::i2c_transfer as transfer;
+#[cfg(not(feature = "mock"))]
+use device::transfer;
+
+fn check(address: u16, len: usize) -> anyhow::Result<()> {
+    ensure!(address <= 0x7f, "{:#x} is not a 7-bit address", address);
+    ensure!(
+        (1..=MAX_TRANSFER).contains(&len),
+        "transfers are 1 to {} bytes",
+        MAX_TRANSFER
+    );
+    Ok(())
+}
+
+/// Reads `len` bytes starting at `register`.
+pub fn read_registers(bus: u32, address: u16, register: u8, len: usize) -> anyhow::Result<Vec<u8>> {
+    check(address, len)?;
+    let mut register = [register];
+    let mut data = vec![0u8; len];
+    transfer(
+        bus,
+        address,
+        &mut [(false, &mut register), (true, &mut data)],
+    )
+    .with_context(|| format!("i2c-{} {:#04x}: read", bus, address))?;
+    Ok(data)
+}
+
+/// Writes `data` starting at `register`.
+pub fn write_registers(bus: u32, address: u16, register: u8, data: &[u8]) -> anyhow::Result<()> {
+    check(address, data.len())?;
+    let mut buffer = Vec::with_capacity(data.len() + 1);
+    buffer.push(register);
+    buffer.extend_from_slice(data);
+    transfer(bus, address, &mut [(false, &mut buffer)])
+        .with_context(|| format!("i2c-{} {:#04x}: write", bus, address))
+}
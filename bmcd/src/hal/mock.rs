@@ -0,0 +1,385 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Simulated Turing Pi board, enabled with the `mock` feature. It lets bmcd
+//! run on a developer machine or CI runner without the board.
+//!
+//! The simulated board keeps the power, USB and LED state in memory, exposes
+//! the node consoles as pseudo terminals and fakes a thermal zone, a fan,
+//! I2C devices, an RTC and the ID EEPROM.
+//! Faults can be injected to test error paths, either at startup through the
+//! `BMCD_MOCK_FAULTS` environment variable (comma separated, e.g.
+//! `power,usb_mux`) or at runtime through the `/mock` API routes.
+mod eeprom;
+mod faults;
+mod i2c;
+mod rtc;
+mod sensors;
+mod serial;
+
+pub use eeprom::eeprom_path;
+pub use faults::*;
+pub use i2c::i2c_transfer;
+pub use rtc::rtc_class;
+pub use sensors::thermal_root;
+pub use serial::serial_devices;
+
+use super::board_profile::{BoardProfile, PRESENT_PIN, USB_BOOT_PIN};
+use crate::error::BmcError;
+use super::{
+    helpers::bit_iterator, NodeId, PinControl, PowerControl, PsuState, SdRoute, UsbArchitecture,
+    UsbMode, UsbRoute,
+};
+use async_trait::async_trait;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::debug;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BoardState {
+    /// bit-field of the powered nodes
+    pub power: u8,
+    /// bit-field of the nodes that boot from USB
+    pub usb_boot: u8,
+    /// node that is connected to the USB bus, and its mode
+    pub usb: Option<(NodeId, UsbMode)>,
+    pub usb_route: UsbRoute,
+    pub node1_alternative_port: bool,
+    pub power_led: bool,
+    pub status_led: bool,
+    /// PS_ON of the ATX power supply
+    pub psu: bool,
+    /// bit-field of the slots that hold a module
+    pub present: u8,
+    /// bit-fields of the other node pins, by name
+    pub pins: Vec<(&'static str, u8)>,
+    /// the simulated board always has a multiplexer on its microSD slot
+    pub sd_route: SdRoute,
+}
+
+static BOARD: Mutex<BoardState> = Mutex::new(BoardState {
+    power: 0,
+    usb_boot: 0,
+    usb: None,
+    usb_route: UsbRoute::Bmc,
+    node1_alternative_port: false,
+    power_led: false,
+    status_led: false,
+    psu: true,
+    present: 0b1111,
+    pins: Vec::new(),
+    sd_route: SdRoute::Bmc,
+});
+
+pub fn board_state() -> BoardState {
+    BOARD.lock().expect("mock board poisoned").clone()
+}
+
+/// Inserts or removes the module of `node`.
+pub fn set_present(node: NodeId, present: bool) {
+    update_board(|board| {
+        board.present = (board.present & !node.to_bitfield()) | (u8::from(present) << node as u8)
+    });
+}
+
+fn update_board<T>(f: impl FnOnce(&mut BoardState) -> T) -> T {
+    f(&mut BOARD.lock().expect("mock board poisoned"))
+}
+
+pub struct PinController {
+    architecture: UsbArchitecture,
+    profile: &'static BoardProfile,
+}
+
+impl PinController {
+    pub fn new(profile: &'static BoardProfile) -> anyhow::Result<Self> {
+        load_faults_from_env();
+        Ok(PinController {
+            architecture: profile.usb.architecture(),
+            profile,
+        })
+    }
+
+    fn check_pin(&self, node: NodeId, pin: &str) -> anyhow::Result<()> {
+        if node as usize >= self.profile.node_count {
+            return Err(BmcError::NoSuchNode(node).into());
+        }
+        if !self.profile.pins().iter().any(|p| p.name == pin) {
+            return Err(BmcError::invalid_parameter("pin", format!("no pin `{}`", pin)).into());
+        }
+        Ok(())
+    }
+}
+
+impl PinControl for PinController {
+    fn select_usb(&self, node: NodeId, mode: UsbMode) -> anyhow::Result<()> {
+        debug!("select USB for node {:?}, mode:{:?}", node, mode);
+        check(Fault::UsbMux)?;
+        if self.architecture == UsbArchitecture::UsbHub && mode == UsbMode::Host {
+            return Err(BmcError::NotSupported(
+                "Selecting one of the nodes as USB Host role \
+                is not supported by the current hardware"
+                    .into(),
+            )
+            .into());
+        }
+        update_board(|board| board.usb = Some((node, mode)));
+
+        if UsbMode::Flash == mode {
+            self.set_usb_boot(node.to_bitfield(), node.to_bitfield())
+        } else {
+            self.set_usb_boot(0, 0b1111)
+        }
+    }
+
+    fn set_usb_route(&self, route: UsbRoute) -> anyhow::Result<()> {
+        debug!("select USB route {:?}", route);
+        check(Fault::UsbMux)?;
+        update_board(|board| board.usb_route = route);
+        Ok(())
+    }
+
+    fn set_usb_boot(&self, nodes_state: u8, nodes_mask: u8) -> anyhow::Result<()> {
+        check(Fault::UsbBoot)?;
+        update_board(|board| {
+            for (idx, state) in bit_iterator(nodes_state, nodes_mask) {
+                board.usb_boot = (board.usb_boot & !(1 << idx)) | (state << idx);
+            }
+        });
+        Ok(())
+    }
+
+    fn set_node1_usb_route(&self, alternative_port: bool) -> anyhow::Result<()> {
+        if self.architecture == UsbArchitecture::UsbMux {
+            return Err(
+                BmcError::NotSupported("This command is only available on v2.5+ boards".into())
+                    .into(),
+            );
+        }
+        check(Fault::UsbMux)?;
+        update_board(|board| board.node1_alternative_port = alternative_port);
+        Ok(())
+    }
+
+    fn usb_bus_type(&self) -> UsbArchitecture {
+        self.architecture
+    }
+
+    fn read_presence(&self) -> anyhow::Result<Option<u8>> {
+        Ok(Some(board_state().present))
+    }
+
+    fn read_pin(&self, node: NodeId, pin: &str) -> anyhow::Result<bool> {
+        self.check_pin(node, pin)?;
+        let board = board_state();
+        let bits = match pin {
+            USB_BOOT_PIN => board.usb_boot,
+            PRESENT_PIN => board.present,
+            _ => board
+                .pins
+                .iter()
+                .find(|(name, _)| *name == pin)
+                .map_or(0, |(_, bits)| *bits),
+        };
+        Ok(bits & node.to_bitfield() != 0)
+    }
+
+    fn write_pin(&self, node: NodeId, pin: &str, value: bool) -> anyhow::Result<()> {
+        self.check_pin(node, pin)?;
+        let bit = node.to_bitfield();
+        if pin == USB_BOOT_PIN {
+            return self.set_usb_boot(if value { bit } else { 0 }, bit);
+        }
+        let pin = self
+            .profile
+            .node_pins
+            .iter()
+            .find(|p| p.name == pin)
+            .ok_or_else(|| BmcError::NotSupported(format!("pin `{}` is an input", pin).into()))?
+            .name;
+        update_board(|board| {
+            let bits = match board.pins.iter_mut().find(|(name, _)| *name == pin) {
+                Some((_, bits)) => bits,
+                None => {
+                    board.pins.push((pin, 0));
+                    &mut board.pins.last_mut().expect("just pushed").1
+                }
+            };
+            *bits = (*bits & !bit) | if value { bit } else { 0 };
+        });
+        Ok(())
+    }
+
+    fn select_sd(&self, route: SdRoute) -> anyhow::Result<()> {
+        if let SdRoute::Node(node) = route {
+            if node as usize >= self.profile.node_count {
+                return Err(BmcError::NoSuchNode(node).into());
+            }
+        }
+        debug!("select sd route {:?}", route);
+        update_board(|board| board.sd_route = route);
+        Ok(())
+    }
+
+    fn read_sd_route(&self) -> anyhow::Result<Option<SdRoute>> {
+        Ok(Some(board_state().sd_route))
+    }
+}
+
+impl std::fmt::Debug for PinController {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mock PinController")
+    }
+}
+
+pub struct PowerController {
+    node_count: usize,
+    has_psu: bool,
+}
+
+impl PowerController {
+    pub fn new(profile: &'static BoardProfile) -> anyhow::Result<Self> {
+        load_faults_from_env();
+        Ok(PowerController {
+            node_count: profile.node_count,
+            has_psu: profile.psu.is_some(),
+        })
+    }
+}
+
+#[async_trait]
+impl PowerControl for PowerController {
+    async fn set_power_node(&self, node_states: u8, node_mask: u8) -> anyhow::Result<()> {
+        for (idx, state) in bit_iterator(node_states, node_mask) {
+            debug!("setting power of node {}. state:{}", idx + 1, state);
+            if idx >= self.node_count {
+                let node = NodeId::try_from(idx as u8).expect("index of a node");
+                return Err(BmcError::NoSuchNode(node).into());
+            }
+            check(Fault::Power)?;
+            let was_on = update_board(|board| {
+                let was_on = board.power & (1 << idx) != 0;
+                board.power = (board.power & !(1 << idx)) | (state << idx);
+                was_on
+            });
+            if state != 0 && !was_on {
+                serial::boot_banner(idx);
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        sensors::update_temperature(board_state().power);
+        Ok(())
+    }
+
+    async fn reset_node(&self, node: NodeId) -> anyhow::Result<()> {
+        debug!("reset node {:?}", node);
+        let bits = node.to_bitfield();
+        self.set_power_node(0u8, bits).await?;
+        sleep(Duration::from_secs(1)).await;
+        self.set_power_node(bits, bits).await
+    }
+
+    fn read_node_states(&self) -> anyhow::Result<u8> {
+        check(Fault::Power)?;
+        Ok(board_state().power)
+    }
+
+    async fn power_led(&self, on: bool) -> anyhow::Result<()> {
+        check(Fault::Led)?;
+        update_board(|board| board.power_led = on);
+        Ok(())
+    }
+
+    async fn status_led(&self, on: bool) -> anyhow::Result<()> {
+        check(Fault::Led)?;
+        update_board(|board| board.status_led = on);
+        Ok(())
+    }
+
+    async fn set_psu(&self, on: bool) -> anyhow::Result<()> {
+        if !self.has_psu {
+            return Err(
+                BmcError::NotSupported("this board cannot switch its power supply".into()).into(),
+            );
+        }
+        update_board(|board| {
+            board.psu = on;
+            // the supply takes the nodes down with it
+            if !on {
+                board.power = 0;
+            }
+        });
+        if on && is_active(Fault::PowerGood) {
+            anyhow::bail!("power supply did not report power good");
+        }
+        Ok(())
+    }
+
+    fn read_psu(&self) -> anyhow::Result<Option<PsuState>> {
+        if !self.has_psu {
+            return Ok(None);
+        }
+        let enabled = board_state().psu;
+        Ok(Some(PsuState {
+            enabled,
+            power_good: enabled && !is_active(Fault::PowerGood),
+        }))
+    }
+}
+
+impl std::fmt::Debug for PowerController {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mock PowerController")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The board is global, all assertions live in one test so that they do
+    // not race with each other.
+    #[tokio::test]
+    async fn simulated_board() {
+        let profile = &crate::hal::board_profile::TURING_PI_2_5;
+        let power = PowerController::new(profile).unwrap();
+        let pins = PinController::new(profile).unwrap();
+
+        power.set_power_node(0b0101, 0b0111).await.unwrap();
+        assert_eq!(power.read_node_states().unwrap(), 0b0101);
+
+        pins.select_usb(NodeId::Node2, UsbMode::Flash).unwrap();
+        assert_eq!(board_state().usb_boot, 0b0010);
+        assert!(pins.select_usb(NodeId::Node2, UsbMode::Host).is_err());
+        assert!(pins.read_pin(NodeId::Node2, "usb_boot").unwrap());
+        pins.write_pin(NodeId::Node2, "usb_boot", false).unwrap();
+        assert!(!pins.read_pin(NodeId::Node2, "usb_boot").unwrap());
+        assert!(pins.read_pin(NodeId::Node2, "sleep").is_err());
+
+        inject(Fault::Power);
+        assert!(power.set_power_node(0, 0b1111).await.is_err());
+        assert!(power.read_node_states().is_err());
+        clear(Some(Fault::Power));
+        assert_eq!(power.read_node_states().unwrap(), 0b0101);
+
+        power.set_psu(false).await.unwrap();
+        assert_eq!(power.read_node_states().unwrap(), 0);
+        inject(Fault::PowerGood);
+        assert!(power.set_psu(true).await.is_err());
+        assert!(!power.read_psu().unwrap().unwrap().power_good);
+        clear(Some(Fault::PowerGood));
+        assert!(power.read_psu().unwrap().unwrap().power_good);
+    }
+}
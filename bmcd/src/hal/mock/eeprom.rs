@@ -0,0 +1,44 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! ID EEPROM backed by a file, programmed with factory data of a mock board.
+use board_info::{BoardInfo, BOARDINFO_SIZE};
+use bytes::BytesMut;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+const EEPROM_SIZE: usize = 256;
+
+pub fn eeprom_path() -> PathBuf {
+    static PATH: OnceLock<PathBuf> = OnceLock::new();
+    PATH.get_or_init(|| {
+        let path = std::env::temp_dir().join(format!("bmcd-mock-eeprom-{}", std::process::id()));
+        if let Err(e) = std::fs::write(&path, factory_data()) {
+            tracing::error!("mock: creating EEPROM: {}", e);
+        }
+        path
+    })
+    .clone()
+}
+
+fn factory_data() -> Vec<u8> {
+    let mut info = BoardInfo::from_bytes(BytesMut::zeroed(BOARDINFO_SIZE))
+        .expect("buffer has the size of the board info");
+    info.product_name("Turing Pi 2");
+    info.factory_serial("MOCK00000001");
+    info.hw_version((2 << 11) | (5 << 6) | 1);
+    let mut bytes = info.to_bytes().to_vec();
+    // erased EEPROM cells read as 0xff
+    bytes.resize(EEPROM_SIZE, 0xff);
+    bytes
+}
@@ -0,0 +1,100 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use std::sync::{Mutex, Once};
+
+const FAULTS_ENV: &str = "BMCD_MOCK_FAULTS";
+
+/// Parts of the simulated board that can be made to fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    /// node power lines fail to switch or read back
+    Power,
+    /// the USB multiplexer cannot be configured
+    UsbMux,
+    /// the USB boot lines cannot be set
+    UsbBoot,
+    /// the front panel LEDs cannot be written
+    Led,
+    /// node consoles stop responding
+    Serial,
+    /// thermal zone and fan cannot be read
+    Sensors,
+    /// the power supply drops PS_OK
+    PowerGood,
+}
+
+impl FromStr for Fault {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+    }
+}
+
+static FAULTS: Mutex<BTreeSet<Fault>> = Mutex::new(BTreeSet::new());
+
+pub fn inject(fault: Fault) {
+    tracing::warn!("mock: injecting {:?} fault", fault);
+    FAULTS.lock().expect("faults poisoned").insert(fault);
+}
+
+/// Clears `fault`, or all faults when `None`.
+pub fn clear(fault: Option<Fault>) {
+    let mut faults = FAULTS.lock().expect("faults poisoned");
+    match fault {
+        Some(fault) => {
+            faults.remove(&fault);
+        }
+        None => faults.clear(),
+    }
+}
+
+pub fn active_faults() -> Vec<Fault> {
+    FAULTS
+        .lock()
+        .expect("faults poisoned")
+        .iter()
+        .copied()
+        .collect()
+}
+
+pub fn is_active(fault: Fault) -> bool {
+    FAULTS.lock().expect("faults poisoned").contains(&fault)
+}
+
+pub(super) fn check(fault: Fault) -> std::io::Result<()> {
+    if is_active(fault) {
+        return Err(std::io::Error::other(format!("injected {:?} fault", fault)));
+    }
+    Ok(())
+}
+
+pub(super) fn load_faults_from_env() {
+    static LOADED: Once = Once::new();
+    LOADED.call_once(|| {
+        let Ok(faults) = std::env::var(FAULTS_ENV) else {
+            return;
+        };
+        for name in faults.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            match name.parse() {
+                Ok(fault) => inject(fault),
+                Err(_) => tracing::warn!("{}: unknown fault `{}`", FAULTS_ENV, name),
+            }
+        }
+    });
+}
@@ -0,0 +1,51 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! I2C devices with 256 byte register files, created on first access.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// register files by bus and address
+type Devices = HashMap<(u32, u16), [u8; 256]>;
+
+static DEVICES: Mutex<Option<Devices>> = Mutex::new(None);
+
+/// Simulates a combined transaction: a write sets the register pointer and
+/// stores the bytes that follow, a read continues at the register pointer.
+pub fn i2c_transfer(
+    bus: u32,
+    address: u16,
+    messages: &mut [(bool, &mut [u8])],
+) -> anyhow::Result<()> {
+    let mut devices = DEVICES.lock().expect("i2c lock poisoned");
+    let registers = devices
+        .get_or_insert_with(HashMap::new)
+        .entry((bus, address))
+        .or_insert([0u8; 256]);
+    let mut pointer = 0u8;
+    for (read, buffer) in messages.iter_mut() {
+        if *read {
+            for byte in buffer.iter_mut() {
+                *byte = registers[pointer as usize];
+                pointer = pointer.wrapping_add(1);
+            }
+        } else if let Some((register, data)) = buffer.split_first() {
+            pointer = *register;
+            for byte in data {
+                registers[pointer as usize] = *byte;
+                pointer = pointer.wrapping_add(1);
+            }
+        }
+    }
+    Ok(())
+}
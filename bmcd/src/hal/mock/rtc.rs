@@ -0,0 +1,37 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Fake rtc class device. Its clock is set to the system time when it is
+//! created and only changes when it is set.
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+pub fn rtc_class() -> PathBuf {
+    static CLASS: OnceLock<PathBuf> = OnceLock::new();
+    CLASS
+        .get_or_init(|| {
+            let class = std::env::temp_dir().join(format!("bmcd-mock-rtc-{}", std::process::id()));
+            if let Err(e) = populate(&class) {
+                tracing::error!("mock: creating RTC: {}", e);
+            }
+            class
+        })
+        .clone()
+}
+
+fn populate(class: &std::path::Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(class)?;
+    let now = chrono::Utc::now().timestamp();
+    std::fs::write(class.join("since_epoch"), format!("{}\n", now))?;
+    std::fs::write(class.join("wakealarm"), "\n")
+}
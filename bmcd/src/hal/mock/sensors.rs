@@ -0,0 +1,63 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Fake `/sys/class/thermal` tree with one thermal zone and one fan. The
+//! temperature rises with the number of powered nodes.
+use super::faults::{is_active, Fault};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+const IDLE_MILLI_CELSIUS: u32 = 38_000;
+const PER_NODE_MILLI_CELSIUS: u32 = 6_500;
+
+/// Root of the fake thermal class. When the `sensors` fault is injected, a
+/// path that does not exist is returned.
+pub fn thermal_root() -> PathBuf {
+    let root = root();
+    if is_active(Fault::Sensors) {
+        return root.join("faulted");
+    }
+    root.to_path_buf()
+}
+
+fn root() -> &'static Path {
+    static ROOT: OnceLock<PathBuf> = OnceLock::new();
+    ROOT.get_or_init(|| {
+        let root = std::env::temp_dir().join(format!("bmcd-mock-{}", std::process::id()));
+        if let Err(e) = populate(&root) {
+            tracing::error!("mock: creating thermal tree: {}", e);
+        }
+        root
+    })
+}
+
+fn populate(root: &Path) -> std::io::Result<()> {
+    let zone = root.join("thermal_zone0");
+    std::fs::create_dir_all(&zone)?;
+    std::fs::write(zone.join("type"), "cpu-thermal\n")?;
+    std::fs::write(zone.join("temp"), format!("{}\n", IDLE_MILLI_CELSIUS))?;
+
+    let fan = root.join("cooling_device0");
+    std::fs::create_dir_all(&fan)?;
+    std::fs::write(fan.join("type"), "pwm-fan\n")?;
+    std::fs::write(fan.join("cur_state"), "0\n")?;
+    std::fs::write(fan.join("max_state"), "4\n")
+}
+
+pub(super) fn update_temperature(powered: u8) {
+    let temp = IDLE_MILLI_CELSIUS + PER_NODE_MILLI_CELSIUS * powered.count_ones();
+    let path = root().join("thermal_zone0/temp");
+    if let Err(e) = std::fs::write(&path, format!("{}\n", temp)) {
+        tracing::debug!("mock: {}: {}", path.display(), e);
+    }
+}
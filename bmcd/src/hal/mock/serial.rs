@@ -0,0 +1,93 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Node consoles backed by pseudo terminals. The serial service opens the
+//! slave side like a UART, the simulated node on the master side echoes
+//! everything it receives and prints a banner when it powers on.
+use super::faults::{is_active, Fault};
+use nix::pty::openpty;
+use nix::unistd::ttyname;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::OwnedFd;
+use std::sync::OnceLock;
+
+struct Console {
+    path: &'static str,
+    master: File,
+    // the pty disappears when the last slave fd closes
+    _slave: OwnedFd,
+}
+
+fn consoles() -> &'static [Console] {
+    static CONSOLES: OnceLock<Vec<Console>> = OnceLock::new();
+    CONSOLES.get_or_init(|| {
+        (0..4)
+            .filter_map(|node| match open_console(node) {
+                Ok(console) => Some(console),
+                Err(e) => {
+                    tracing::error!("mock: console of node {}: {}", node + 1, e);
+                    None
+                }
+            })
+            .collect()
+    })
+}
+
+fn open_console(node: usize) -> anyhow::Result<Console> {
+    let pty = openpty(None, None)?;
+    let path = ttyname(&pty.slave)?.to_string_lossy().to_string();
+    let master = File::from(pty.master);
+
+    let mut reader = master.try_clone()?;
+    let mut writer = master.try_clone()?;
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 256];
+        while let Ok(n) = reader.read(&mut buffer) {
+            if n == 0 {
+                break;
+            }
+            if !is_active(Fault::Serial) {
+                let _ = writer.write_all(&buffer[..n]);
+            }
+        }
+    });
+
+    tracing::info!("mock: console of node {} at {}", node + 1, path);
+    Ok(Console {
+        path: Box::leak(path.into_boxed_str()),
+        master,
+        _slave: pty.slave,
+    })
+}
+
+/// Device paths of the node consoles, in node order.
+pub fn serial_devices() -> [&'static str; 4] {
+    let consoles = consoles();
+    std::array::from_fn(|node| consoles.get(node).map_or("/dev/null", |c| c.path))
+}
+
+pub(super) fn boot_banner(node: usize) {
+    if is_active(Fault::Serial) {
+        return;
+    }
+    let Some(console) = consoles().get(node) else {
+        return;
+    };
+    let banner = format!(
+        "\r\nTuring Pi mock node {}\r\nnode{} login: ",
+        node + 1,
+        node + 1
+    );
+    let _ = (&console.master).write_all(banner.as_bytes());
+}
@@ -11,33 +11,23 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use super::helpers::bit_iterator;
-use super::helpers::load_lines;
-use crate::gpio_output_array;
-use crate::gpio_output_lines;
-
-use super::gpio_definitions::*;
+use super::board_profile::{
+    BoardProfile, PinAccess, SdMuxProfile, UsbProfile, PRESENT_PIN, USB_BOOT_PIN,
+};
+use super::helpers::{bit_iterator, input_lines_by_name, open_chip_with_lines, output_lines_by_name};
 use super::NodeId;
+use super::PinControl;
+use super::SdRoute;
+use super::UsbArchitecture;
 use super::UsbMode;
 use super::UsbRoute;
+use crate::gpio_output_lines;
 use anyhow::Context;
-use gpiod::{Chip, Lines, Output};
-use std::fmt::Display;
+use gpiod::{Chip, Input, Lines, Output};
+use crate::error::BmcError;
 use thiserror::Error;
 use tracing::debug;
 
-const USB_PORT_POWER: &str = "/sys/bus/platform/devices/usb-port-power/state";
-
-const NODE1_USBOTG_DEV: &str = "node1-usbotg-dev";
-const NODE2_USBOTG_DEV: &str = "node2-usbotg-dev";
-const NODE3_USBOTG_DEV: &str = "node3-usbotg-dev";
-const NODE4_USBOTG_DEV: &str = "node4-usbotg-dev";
-
-const NODE1_RPIBOOT: &str = "node1-rpiboot";
-const NODE2_RPIBOOT: &str = "node2-rpiboot";
-const NODE3_RPIBOOT: &str = "node3-rpiboot";
-const NODE4_RPIBOOT: &str = "node4-rpiboot";
-
 /// This class is responsible for switching USB busses to the various "USB
 /// endpoints", e.g. a USB port on the bus or a connection to the BMC(t113). The
 /// hardware changed over time, and depending on which version of the board is
@@ -96,52 +86,103 @@ const NODE4_RPIBOOT: &str = "node4-rpiboot";
 /// The switches enable us to connect different USB ports on Node1 to the switch
 /// or USB-A. This way we can provide support to more devices devices.
 pub struct PinController {
+    architecture: UsbArchitecture,
     usb_switch: Box<dyn UsbConfiguration + Sync + Send>,
-    rpi_boot: [Lines<Output>; 4],
+    rpi_boot: Vec<Lines<Output>>,
+    present: Vec<Lines<Input>>,
+    /// lines of the [`BoardProfile::node_pins`], by pin name
+    sideband: Vec<(&'static str, Vec<SidebandLine>)>,
+    sd_mux: Option<(Lines<Output>, &'static SdMuxProfile)>,
+}
+
+enum SidebandLine {
+    Input(Lines<Input>),
+    Output(Lines<Output>),
+}
+
+impl SidebandLine {
+    fn get(&self) -> std::io::Result<bool> {
+        let [value] = match self {
+            SidebandLine::Input(line) => line.get_values([false; 1])?,
+            SidebandLine::Output(line) => line.get_values([false; 1])?,
+        };
+        Ok(value)
+    }
 }
 
 impl PinController {
     /// create a new Pin controller
-    pub fn new(has_usb_switch: bool) -> anyhow::Result<Self> {
-        let chip1 = if has_usb_switch {
-            "/dev/gpiochip1"
+    pub fn new(profile: &'static BoardProfile) -> anyhow::Result<Self> {
+        let node_chip = open_chip_with_lines(profile.node_chip, profile.node_usb_boot)?;
+        let rpi_boot = output_lines_by_name(&node_chip, profile.node_usb_boot)?;
+        let present = if profile.node_present.is_empty() {
+            Vec::new()
         } else {
-            "/dev/gpiochip2"
+            let chip = open_chip_with_lines(profile.node_chip, profile.node_present)?;
+            input_lines_by_name(&chip, profile.node_present)?
         };
 
-        let chip0 = Chip::new("/dev/gpiochip0").context("gpiod chip0")?;
-        let chip1 = Chip::new(chip1).context("gpiod chip1")?;
-        let chip1_lines = load_lines(&chip1);
-
-        let rpi1 = *chip1_lines
-            .get(NODE1_RPIBOOT)
-            .ok_or(anyhow::anyhow!("cannot find node1-rpiboot gpio"))?;
-        let rpi2 = *chip1_lines
-            .get(NODE2_RPIBOOT)
-            .ok_or(anyhow::anyhow!("cannot find node2-rpiboot gpio"))?;
-        let rpi3 = *chip1_lines
-            .get(NODE3_RPIBOOT)
-            .ok_or(anyhow::anyhow!("cannot find node3-rpiboot gpio"))?;
-        let rpi4 = *chip1_lines
-            .get(NODE4_RPIBOOT)
-            .ok_or(anyhow::anyhow!("cannot find node4-rpiboot gpio"))?;
-
-        let rpi_boot = gpio_output_array!(chip1, rpi1, rpi2, rpi3, rpi4);
-
-        let usb_switch = if has_usb_switch {
-            Box::new(UsbMuxSwitch::new(&chip0, &chip1)?) as Box<dyn UsbConfiguration + Send + Sync>
-        } else {
-            Box::new(UsbHub::new(&chip0)?)
+        let usb_switch = match &profile.usb {
+            UsbProfile::Mux { .. } => Box::new(UsbMuxSwitch::new(&profile.usb, &node_chip)?)
+                as Box<dyn UsbConfiguration + Send + Sync>,
+            UsbProfile::Hub { .. } => Box::new(UsbHub::new(&profile.usb)?),
+        };
+
+        let mut sideband = Vec::new();
+        for pin in profile.node_pins {
+            let chip = open_chip_with_lines(profile.node_chip, pin.lines)?;
+            let lines = if pin.access == PinAccess::ReadOnly {
+                input_lines_by_name(&chip, pin.lines)?
+                    .into_iter()
+                    .map(SidebandLine::Input)
+                    .collect()
+            } else {
+                output_lines_by_name(&chip, pin.lines)?
+                    .into_iter()
+                    .map(SidebandLine::Output)
+                    .collect()
+            };
+            sideband.push((pin.name, lines));
+        }
+
+        // the BMC keeps the card until it is switched through the API
+        let sd_mux = match &profile.sd_mux {
+            Some(mux) => {
+                let chip = open_chip_with_lines(mux.chip, mux.lines)?;
+                let offsets = super::helpers::find_lines(&chip, mux.lines)?;
+                let lines = chip
+                    .request_lines(gpiod::Options::output(offsets).values(mux.bmc_select))
+                    .context("error initializing sd multiplexer")?;
+                Some((lines, mux))
+            }
+            None => None,
         };
 
         Ok(Self {
+            architecture: profile.usb.architecture(),
             usb_switch,
             rpi_boot,
+            present,
+            sideband,
+            sd_mux,
         })
     }
 
+    fn sideband_line(&self, node: NodeId, pin: &str) -> anyhow::Result<&SidebandLine> {
+        let (_, lines) = self
+            .sideband
+            .iter()
+            .find(|(name, _)| *name == pin)
+            .ok_or_else(|| BmcError::invalid_parameter("pin", format!("no pin `{}`", pin)))?;
+        Ok(lines
+            .get(node as usize)
+            .ok_or(BmcError::NoSuchNode(node))?)
+    }
+}
+
+impl PinControl for PinController {
     /// Select which node is active in the multiplexer (see PORTx in `set_usb_route()`)
-    pub fn select_usb(&self, node: NodeId, mode: UsbMode) -> Result<(), PowerControllerError> {
+    fn select_usb(&self, node: NodeId, mode: UsbMode) -> anyhow::Result<()> {
         debug!("select USB for node {:?}, mode:{:?}", node, mode);
         self.usb_switch.configure_usb(node, mode)?;
 
@@ -156,111 +197,162 @@ impl PinController {
 
     /// Set which way the USB is routed: USB-A ↔ PORTx (`UsbRoute::AlternativePort`) or BMC ↔ PORTx
     /// (`UsbRoute::Bmc`)
-    pub fn set_usb_route(&self, route: UsbRoute) -> Result<(), PowerControllerError> {
+    fn set_usb_route(&self, route: UsbRoute) -> anyhow::Result<()> {
         debug!("select USB route {:?}", route);
-        self.usb_switch.set_usb_route(route)
+        Ok(self.usb_switch.set_usb_route(route)?)
     }
 
     /// Set given nodes into usb boot mode. When powering the node on with this mode enabled, the
     /// given node will boot into USB mode. Typically means that booting of eMMC is disabled.
-    pub fn set_usb_boot(
-        &self,
-        nodes_state: u8,
-        nodes_mask: u8,
-    ) -> Result<(), PowerControllerError> {
+    fn set_usb_boot(&self, nodes_state: u8, nodes_mask: u8) -> anyhow::Result<()> {
         let updates = bit_iterator(nodes_state, nodes_mask);
 
         for (idx, state) in updates {
+            let Some(line) = self.rpi_boot.get(idx) else {
+                continue;
+            };
             debug!(
                 "updating usb_boot state of node {} to {}",
                 idx + 1,
                 if state != 0 { "enable" } else { "disable" }
             );
-            self.rpi_boot[idx].set_values(state)?;
+            line.set_values(state)?;
         }
         Ok(())
     }
 
-    pub fn set_node1_usb_route(&self, alternative_port: bool) -> Result<(), PowerControllerError> {
+    fn set_node1_usb_route(&self, alternative_port: bool) -> anyhow::Result<()> {
         debug!("setting alternative port for Node 1 USB");
-        self.usb_switch.set_node1_usb_route(alternative_port)
+        Ok(self.usb_switch.set_node1_usb_route(alternative_port)?)
+    }
+
+    fn usb_bus_type(&self) -> UsbArchitecture {
+        self.architecture
+    }
+
+    fn read_presence(&self) -> anyhow::Result<Option<u8>> {
+        if self.present.is_empty() {
+            return Ok(None);
+        }
+        let mut present = 0u8;
+        for (idx, line) in self.present.iter().enumerate() {
+            let [seated] = line.get_values([false; 1])?;
+            present |= u8::from(seated) << idx;
+        }
+        Ok(Some(present))
+    }
+
+    fn read_pin(&self, node: NodeId, pin: &str) -> anyhow::Result<bool> {
+        let line = match pin {
+            USB_BOOT_PIN => self.rpi_boot.get(node as usize).map(|l| l.get_values([false; 1])),
+            PRESENT_PIN => self.present.get(node as usize).map(|l| l.get_values([false; 1])),
+            _ => return Ok(self.sideband_line(node, pin)?.get()?),
+        };
+        let [value] = line.ok_or(BmcError::NoSuchNode(node))??;
+        Ok(value)
+    }
+
+    fn write_pin(&self, node: NodeId, pin: &str, value: bool) -> anyhow::Result<()> {
+        debug!("setting pin {} of {} to {}", pin, node, value);
+        if pin == USB_BOOT_PIN {
+            let bit = node.to_bitfield();
+            return self.set_usb_boot(if value { bit } else { 0 }, bit);
+        }
+        match self.sideband_line(node, pin)? {
+            SidebandLine::Output(line) => Ok(line.set_values([value])?),
+            SidebandLine::Input(_) => Err(BmcError::NotSupported(
+                format!("pin `{}` is an input", pin).into(),
+            )
+            .into()),
+        }
+    }
+
+    fn select_sd(&self, route: SdRoute) -> anyhow::Result<()> {
+        let Some((lines, mux)) = &self.sd_mux else {
+            return Err(BmcError::NotSupported("this board has no sd multiplexer".into()).into());
+        };
+        let value = match route {
+            SdRoute::Bmc => mux.bmc_select,
+            SdRoute::Node(node) => *mux
+                .node_select
+                .get(node as usize)
+                .ok_or(BmcError::NoSuchNode(node))?,
+        };
+        debug!("select sd route {:?}", route);
+        Ok(lines.set_values(value)?)
     }
 
-    pub fn usb_bus_type(&self) -> UsbArchitecture {
-        self.usb_switch.architecture()
+    fn read_sd_route(&self) -> anyhow::Result<Option<SdRoute>> {
+        let Some((lines, mux)) = &self.sd_mux else {
+            return Ok(None);
+        };
+        let value = lines.get_values(0u8)?;
+        if value == mux.bmc_select {
+            return Ok(Some(SdRoute::Bmc));
+        }
+        let node = mux
+            .node_select
+            .iter()
+            .position(|v| *v == value)
+            .and_then(|n| NodeId::try_from(n as u8).ok())
+            .ok_or_else(|| anyhow::anyhow!("unknown sd multiplexer state {:#b}", value))?;
+        Ok(Some(SdRoute::Node(node)))
     }
 }
 
 trait UsbConfiguration {
-    fn architecture(&self) -> UsbArchitecture;
     fn set_usb_route(&self, route: UsbRoute) -> Result<(), PowerControllerError>;
     fn set_node1_usb_route(&self, alternative_port: bool) -> Result<(), PowerControllerError>;
     fn configure_usb(&self, node: NodeId, mode: UsbMode) -> Result<(), PowerControllerError>;
 }
 
-#[derive(Debug, PartialEq)]
-pub enum UsbArchitecture {
-    UsbHub,
-    UsbMux,
-}
-
-impl Display for UsbArchitecture {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            UsbArchitecture::UsbHub => f.write_str("Usb hub"),
-            UsbArchitecture::UsbMux => f.write_str("Single bus"),
-        }
-    }
-}
-
 struct UsbMuxSwitch {
     usb_mux: Lines<Output>,
     usb_vbus: Lines<Output>,
     output_switch: Lines<Output>,
+    node_select: &'static [u8],
+    port_power: &'static str,
 }
 
 impl UsbMuxSwitch {
-    pub fn new(chip0: &Chip, chip1: &Chip) -> Result<Self, PowerControllerError> {
-        let usb_mux = gpio_output_lines!(chip0, [USB_SEL1, USB_OE1, USB_SEL2, USB_OE2]);
-        let output_switch = gpio_output_lines!(chip0, [USB_SWITCH]);
-        let chip1_lines = load_lines(chip1);
-
-        let usb1 = *chip1_lines
-            .get(NODE1_USBOTG_DEV)
-            .ok_or(anyhow::anyhow!("cannot find node-1-usbotg-dev gpio"))?;
-        let usb2 = *chip1_lines
-            .get(NODE2_USBOTG_DEV)
-            .ok_or(anyhow::anyhow!("cannot find node-2-usbotg-dev gpio"))?;
-        let usb3 = *chip1_lines
-            .get(NODE3_USBOTG_DEV)
-            .ok_or(anyhow::anyhow!("cannot find node-3-usbotg-dev gpio"))?;
-        let usb4 = *chip1_lines
-            .get(NODE4_USBOTG_DEV)
-            .ok_or(anyhow::anyhow!("cannot find node-4-usbotg-dev gpio"))?;
-
-        let usb_vbus = gpio_output_lines!(chip1, [usb1, usb2, usb3, usb4]);
+    pub fn new(profile: &'static UsbProfile, node_chip: &Chip) -> anyhow::Result<Self> {
+        let UsbProfile::Mux {
+            chip,
+            select,
+            node_select,
+            output_switch,
+            vbus,
+            port_power,
+        } = profile
+        else {
+            anyhow::bail!("not a USB multiplexer profile");
+        };
+
+        let chip = Chip::new(*chip).context(*chip)?;
+        let usb_mux = gpio_output_lines!(chip, *select);
+        let output_switch = gpio_output_lines!(chip, [*output_switch]);
+        let vbus = super::helpers::find_lines(node_chip, vbus)?;
+        let usb_vbus = gpio_output_lines!(node_chip, vbus);
         Ok(Self {
             usb_mux,
             usb_vbus,
             output_switch,
+            node_select,
+            port_power,
         })
     }
 }
 
 impl UsbConfiguration for UsbMuxSwitch {
-    fn architecture(&self) -> UsbArchitecture {
-        UsbArchitecture::UsbMux
-    }
-
     fn set_usb_route(&self, route: UsbRoute) -> Result<(), PowerControllerError> {
         match route {
             UsbRoute::AlternativePort => {
                 self.output_switch.set_values(0_u8)?;
-                std::fs::write(USB_PORT_POWER, b"enabled")
+                std::fs::write(self.port_power, b"enabled")
             }
             UsbRoute::Bmc => {
                 self.output_switch.set_values(1_u8)?;
-                std::fs::write(USB_PORT_POWER, b"disabled")
+                std::fs::write(self.port_power, b"disabled")
             }
         }?;
 
@@ -268,12 +360,10 @@ impl UsbConfiguration for UsbMuxSwitch {
     }
 
     fn configure_usb(&self, node: NodeId, mode: UsbMode) -> Result<(), PowerControllerError> {
-        let values: u8 = match node {
-            NodeId::Node1 => 0b1100,
-            NodeId::Node2 => 0b1101,
-            NodeId::Node3 => 0b0011,
-            NodeId::Node4 => 0b0111,
-        };
+        let values = *self
+            .node_select
+            .get(node as usize)
+            .ok_or(PowerControllerError::NoSuchNode(node))?;
         self.usb_mux.set_values(values)?;
         let vbus = match mode {
             UsbMode::Host => node.to_inverse_bitfield(),
@@ -294,11 +384,19 @@ struct UsbHub {
 }
 
 impl UsbHub {
-    pub fn new(chip: &Chip) -> Result<Self, PowerControllerError> {
-        let node1_source =
-            gpio_output_lines!(chip, [NODE1_OUTPUT_SWITCH_V2_5, NODE1_SOURCE_SWITCH_V2_5]);
+    pub fn new(profile: &'static UsbProfile) -> anyhow::Result<Self> {
+        let UsbProfile::Hub {
+            chip,
+            output_switch,
+            node1_source,
+        } = profile
+        else {
+            anyhow::bail!("not a USB hub profile");
+        };
 
-        let output_switch = gpio_output_lines!(chip, [USB_SWITCH_V2_5]);
+        let chip = Chip::new(*chip).context(*chip)?;
+        let node1_source = gpio_output_lines!(chip, *node1_source);
+        let output_switch = gpio_output_lines!(chip, [*output_switch]);
         Ok(Self {
             output_switch,
             node1_source,
@@ -307,10 +405,6 @@ impl UsbHub {
 }
 
 impl UsbConfiguration for UsbHub {
-    fn architecture(&self) -> UsbArchitecture {
-        UsbArchitecture::UsbHub
-    }
-
     fn set_usb_route(&self, route: UsbRoute) -> Result<(), PowerControllerError> {
         match route {
             UsbRoute::AlternativePort => self.output_switch.set_values(0_u8),
@@ -344,8 +438,24 @@ pub enum PowerControllerError {
         is not supported by the current hardware"
     )]
     HostModeNotSupported,
+    #[error("{0} does not exist on this board")]
+    NoSuchNode(NodeId),
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error(transparent)]
     Anyhow(#[from] anyhow::Error),
 }
+
+impl PowerControllerError {
+    /// The error as reported to API clients, `None` for internal errors.
+    pub fn as_bmc_error(&self) -> Option<BmcError> {
+        match self {
+            PowerControllerError::Node1UsbNotApplicable
+            | PowerControllerError::HostModeNotSupported => {
+                Some(BmcError::NotSupported(self.to_string().into()))
+            }
+            PowerControllerError::NoSuchNode(node) => Some(BmcError::NoSuchNode(*node)),
+            PowerControllerError::Io(_) | PowerControllerError::Anyhow(_) => None,
+        }
+    }
+}
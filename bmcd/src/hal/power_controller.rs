@@ -11,99 +11,166 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+use super::board_profile::{BoardProfile, PsuProfile};
 use super::{
-    helpers::{bit_iterator, load_lines},
-    NodeId,
+    helpers::{bit_iterator, open_chip_with_lines, output_lines_by_name},
+    NodeId, PowerControl, PsuState,
 };
-use crate::gpio_output_array;
+use crate::error::BmcError;
 use anyhow::Context;
-use gpiod::{Chip, Lines, Output};
-use std::path::PathBuf;
-use std::{str::FromStr, time::Duration};
+use async_trait::async_trait;
+use gpiod::{Chip, EdgeDetect, Input, Lines, Options, Output};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::watch;
 use tokio::time::sleep;
 use tracing::{debug, trace};
 
-const SYS_LED: &str = "/sys/class/leds/fp::power/brightness";
-const SYS_LED_2_0_5: &str = "/sys/class/leds/fp:sys/brightness";
-const STATUS_LED: &str = "/sys/class/leds/fp::status/brightness";
-const STATUS_LED_2_0_5: &str = "/sys/class/leds/fp:reset/brightness";
-const PORT1_EN: &str = "node1-en";
-const PORT2_EN: &str = "node2-en";
-const PORT3_EN: &str = "node3-en";
-const PORT4_EN: &str = "node4-en";
+/// ATX allows 500ms between PS_ON and PS_OK, with some margin.
+const POWER_GOOD_TIMEOUT: Duration = Duration::from_secs(1);
 
 // This structure is a thin layer that abstracts away the interaction details
 // with Linux's power subsystem.
 pub struct PowerController {
-    enable: [Lines<Output>; 4],
+    profile: &'static BoardProfile,
+    enable: Vec<Lines<Output>>,
     sysfs_power: PathBuf,
     sysfs_reset: PathBuf,
+    psu: Option<Psu>,
 }
 
-impl PowerController {
-    pub fn new(is_latching_system: bool) -> anyhow::Result<Self> {
-        let chip1 = if is_latching_system {
-            "/dev/gpiochip1"
-        } else {
-            "/dev/gpiochip2"
+struct Psu {
+    enable: Lines<Output>,
+    power_good: PowerGood,
+}
+
+enum PowerGood {
+    /// level of PS_OK, updated on its edges
+    Events(watch::Receiver<bool>),
+    /// the chip cannot detect edges of PS_OK
+    Polled(Lines<Input>),
+}
+
+impl Psu {
+    /// The supply powers the nodes, it is requested switched on so that
+    /// starting bmcd does not cut their power.
+    fn new(profile: &PsuProfile) -> anyhow::Result<Self> {
+        let chip = Chip::new(profile.chip).context(profile.chip)?;
+        let enable = chip
+            .request_lines(Options::output([profile.enable]).values([true]))
+            .context("psu enable")?;
+        let edges = Options::input([profile.power_good]).edge(EdgeDetect::Both);
+        let power_good = match chip.request_lines(edges) {
+            Ok(lines) => PowerGood::Events(watch_edges(lines)?),
+            Err(e) => {
+                tracing::warn!("no edge events for psu power good, polling it: {}", e);
+                let lines = chip
+                    .request_lines(Options::input([profile.power_good]))
+                    .context("psu power good")?;
+                PowerGood::Polled(lines)
+            }
         };
+        Ok(Psu { enable, power_good })
+    }
+}
 
-        let chip1 = Chip::new(chip1).context(chip1)?;
-        let lines = load_lines(&chip1);
-        let port1 = *lines
-            .get(PORT1_EN)
-            .ok_or(anyhow::anyhow!("cannot find PORT1_EN"))?;
-        let port2 = *lines
-            .get(PORT2_EN)
-            .ok_or(anyhow::anyhow!("cannot find PORT2_EN"))?;
-        let port3 = *lines
-            .get(PORT3_EN)
-            .ok_or(anyhow::anyhow!("cannot find PORT3_EN"))?;
-        let port4 = *lines
-            .get(PORT4_EN)
-            .ok_or(anyhow::anyhow!("cannot find PORT4_EN"))?;
-
-        let enable = gpio_output_array!(chip1, port1, port2, port3, port4);
-
-        let sysfs_power = fallback_if_not_exist(SYS_LED, SYS_LED_2_0_5);
-        let sysfs_reset = fallback_if_not_exist(STATUS_LED, STATUS_LED_2_0_5);
+impl PowerGood {
+    fn read(&self) -> anyhow::Result<bool> {
+        match self {
+            PowerGood::Events(level) => Ok(*level.borrow()),
+            PowerGood::Polled(lines) => {
+                let [power_good] = lines.get_values([false; 1])?;
+                Ok(power_good)
+            }
+        }
+    }
+}
+
+/// Waits for edges of PS_OK on a thread of its own, as reading events
+/// blocks, and publishes the level of the line after each of them.
+fn watch_edges(mut lines: Lines<Input>) -> anyhow::Result<watch::Receiver<bool>> {
+    let [level] = lines.get_values([false; 1])?;
+    let (sender, receiver) = watch::channel(level);
+    std::thread::Builder::new()
+        .name("psu-power-good".into())
+        .spawn(move || loop {
+            let level = lines
+                .read_event()
+                .and_then(|_| lines.get_values([false; 1]));
+            match level {
+                Ok([level]) => {
+                    sender.send_replace(level);
+                }
+                Err(e) => {
+                    tracing::error!("reading psu power good events: {}", e);
+                    break;
+                }
+            }
+        })?;
+    Ok(receiver)
+}
+
+impl PowerController {
+    pub fn new(profile: &'static BoardProfile) -> anyhow::Result<Self> {
+        let chip = open_chip_with_lines(profile.node_chip, profile.node_enable)?;
+        let enable = output_lines_by_name(&chip, profile.node_enable)?;
+
+        let sysfs_power = profile.power_led();
+        let sysfs_reset = profile.status_led();
+        tracing::info!(
+            "{}: power led {}, status led {}",
+            profile.name,
+            sysfs_power.display(),
+            sysfs_reset.display()
+        );
+
+        let psu = profile.psu.as_ref().map(Psu::new).transpose()?;
 
         Ok(PowerController {
+            profile,
             enable,
             sysfs_power,
             sysfs_reset,
+            psu,
         })
     }
+}
 
+#[async_trait]
+impl PowerControl for PowerController {
     /// Function to power on/off given nodes. Powering of the nodes is controlled by
     /// the Linux subsystem.
     ///
     /// # Arguments
     ///
     /// * `node_states`     bit-field representing the nodes on the turing-pi board,
-    ///     where bit 1 is on and 0 equals off.
+    ///   where bit 1 is on and 0 equals off.
     /// * `node_mask`       bit-field to describe which nodes to control.
     ///
     /// # Returns
     ///
     /// * `Ok(())` when routine was executed successfully.
     /// * `Err(io error)` in the case there was a failure to write to the Linux
-    ///     subsystem that handles the node powering.
-    pub async fn set_power_node(&self, node_states: u8, node_mask: u8) -> anyhow::Result<()> {
+    ///   subsystem that handles the node powering.
+    async fn set_power_node(&self, node_states: u8, node_mask: u8) -> anyhow::Result<()> {
         let updates = bit_iterator(node_states, node_mask);
 
         for (idx, state) in updates {
             trace!("setting power of node {}. state:{}", idx + 1, state);
-            set_mode(idx + 1, state).await?;
+            let line = self
+                .enable
+                .get(idx)
+                .with_context(|| format!("node {} does not exist on this board", idx + 1))?;
+            set_mode(&self.profile.node_power_state(idx + 1), state).await?;
             sleep(Duration::from_millis(100)).await;
-            self.enable[idx].set_values(state)?;
+            line.set_values(state)?;
         }
 
         Ok(())
     }
 
     /// Reset a given node by setting the reset pin logically high for 1 second
-    pub async fn reset_node(&self, node: NodeId) -> anyhow::Result<()> {
+    async fn reset_node(&self, node: NodeId) -> anyhow::Result<()> {
         debug!("reset node {:?}", node);
         let bits = node.to_bitfield();
 
@@ -113,35 +180,77 @@ impl PowerController {
         Ok(())
     }
 
-    pub async fn power_led(&self, on: bool) -> anyhow::Result<()> {
+    /// Reads back the enable lines of the nodes, as bit-field.
+    fn read_node_states(&self) -> anyhow::Result<u8> {
+        let mut states = 0u8;
+        for (idx, line) in self.enable.iter().enumerate() {
+            let [on] = line.get_values([false; 1])?;
+            states |= u8::from(on) << idx;
+        }
+        Ok(states)
+    }
+
+    async fn power_led(&self, on: bool) -> anyhow::Result<()> {
         tokio::fs::write(&self.sysfs_power, if on { "1" } else { "0" })
             .await
-            .context(SYS_LED)
+            .with_context(|| self.sysfs_power.display().to_string())
     }
 
-    pub async fn status_led(&self, on: bool) -> anyhow::Result<()> {
+    async fn status_led(&self, on: bool) -> anyhow::Result<()> {
         tokio::fs::write(&self.sysfs_reset, if on { "1" } else { "0" })
             .await
-            .context(STATUS_LED)
+            .with_context(|| self.sysfs_reset.display().to_string())
+    }
+
+    async fn set_psu(&self, on: bool) -> anyhow::Result<()> {
+        let psu = self.psu.as_ref().ok_or_else(|| {
+            BmcError::NotSupported("this board cannot switch its power supply".into())
+        })?;
+        debug!("switching power supply {}", if on { "on" } else { "off" });
+        psu.enable.set_values([on])?;
+        if !on {
+            return Ok(());
+        }
+        let deadline = tokio::time::Instant::now() + POWER_GOOD_TIMEOUT;
+        loop {
+            if psu.power_good.read()? {
+                return Ok(());
+            }
+            if tokio::time::Instant::now() >= deadline {
+                anyhow::bail!(
+                    "power supply did not report power good within {:?}",
+                    POWER_GOOD_TIMEOUT
+                );
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    fn read_psu(&self) -> anyhow::Result<Option<PsuState>> {
+        let Some(psu) = &self.psu else {
+            return Ok(None);
+        };
+        let [enabled] = psu.enable.get_values([false; 1])?;
+        Ok(Some(PsuState {
+            enabled,
+            power_good: psu.power_good.read()?,
+        }))
+    }
+
+    fn watch_power_good(&self) -> Option<watch::Receiver<bool>> {
+        match &self.psu.as_ref()?.power_good {
+            PowerGood::Events(level) => Some(level.clone()),
+            PowerGood::Polled(_) => None,
+        }
     }
 }
 
-async fn set_mode(node_id: usize, node_state: u8) -> std::io::Result<()> {
+async fn set_mode(sys_path: &Path, node_state: u8) -> std::io::Result<()> {
     let node_value = if node_state > 0 {
         "enabled"
     } else {
         "disabled"
     };
 
-    let sys_path = format!("/sys/bus/platform/devices/node{}-power/state", node_id);
     tokio::fs::write(sys_path, node_value).await
 }
-
-fn fallback_if_not_exist(sysfs: &str, fallback: &str) -> PathBuf {
-    let mut sysfs = PathBuf::from_str(sysfs).expect("valid utf8 path");
-    if !sysfs.exists() {
-        sysfs = PathBuf::from_str(fallback).expect("valid utf8 path");
-        tracing::info!("power led: falling back to {}", fallback);
-    }
-    sysfs
-}
@@ -0,0 +1,170 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Real-time clock of the BMC. The time and the wake alarm are accessed
+//! through the attributes of the rtc class in sysfs. A wake alarm powers the
+//! board back on when it fires while the BMC is powered down.
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use std::path::{Path, PathBuf};
+
+#[cfg(not(feature = "mock"))]
+const RTC_CLASS: &str = "/sys/class/rtc/rtc0";
+
+#[cfg(not(feature = "mock"))]
+mod device {
+    use chrono::{DateTime, Datelike, Timelike, Utc};
+    use std::fs::OpenOptions;
+    use std::os::fd::AsRawFd;
+
+    /// `struct rtc_time` of `linux/rtc.h`
+    #[repr(C)]
+    pub struct RtcTime {
+        tm_sec: i32,
+        tm_min: i32,
+        tm_hour: i32,
+        tm_mday: i32,
+        tm_mon: i32,
+        tm_year: i32,
+        tm_wday: i32,
+        tm_yday: i32,
+        tm_isdst: i32,
+    }
+
+    nix::ioctl_write_ptr!(rtc_set_time, b'p', 0x0a, RtcTime);
+
+    pub fn set_time(device: &str, time: DateTime<Utc>) -> anyhow::Result<()> {
+        let rtc_time = RtcTime {
+            tm_sec: time.second() as i32,
+            tm_min: time.minute() as i32,
+            tm_hour: time.hour() as i32,
+            tm_mday: time.day() as i32,
+            tm_mon: time.month0() as i32,
+            tm_year: time.year() - 1900,
+            tm_wday: time.weekday().num_days_from_sunday() as i32,
+            tm_yday: time.ordinal0() as i32,
+            tm_isdst: 0,
+        };
+        let file = OpenOptions::new().write(true).open(device)?;
+        // SAFETY: `rtc_time` outlives the call and has the layout the ioctl
+        // expects.
+        unsafe { rtc_set_time(file.as_raw_fd(), &rtc_time) }?;
+        Ok(())
+    }
+}
+
+pub struct Rtc {
+    class: PathBuf,
+}
+
+impl Rtc {
+    pub fn open() -> Self {
+        #[cfg(not(feature = "mock"))]
+        let class = PathBuf::from(RTC_CLASS);
+        #[cfg(feature = "mock")]
+        let class = crate::hal::mock::rtc_class();
+        Self::from_class(class)
+    }
+
+    /// Uses the sysfs directory `class` of an rtc device.
+    pub fn from_class(class: PathBuf) -> Self {
+        Self { class }
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.class.join("since_epoch").exists()
+    }
+
+    /// Character device of the RTC, e.g. `/dev/rtc0`.
+    pub fn device(&self) -> String {
+        let name = self.class.file_name().unwrap_or_default().to_string_lossy();
+        format!("/dev/{}", name)
+    }
+
+    pub fn time(&self) -> anyhow::Result<DateTime<Utc>> {
+        let seconds = self
+            .attribute("since_epoch")?
+            .parse()
+            .context("since_epoch is not a number")?;
+        DateTime::from_timestamp(seconds, 0).context("RTC time is out of range")
+    }
+
+    /// Sets the RTC, which keeps UTC. Sub-second precision is dropped.
+    pub fn set_time(&self, time: DateTime<Utc>) -> anyhow::Result<()> {
+        #[cfg(not(feature = "mock"))]
+        device::set_time(&self.device(), time).with_context(|| self.device())?;
+        #[cfg(feature = "mock")]
+        write(
+            &self.class.join("since_epoch"),
+            &time.timestamp().to_string(),
+        )?;
+        tracing::info!("RTC set to {}", time);
+        Ok(())
+    }
+
+    /// Time at which the wake alarm fires, `None` when it is not armed.
+    pub fn wake_alarm(&self) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let value = self.attribute("wakealarm")?;
+        if value.is_empty() {
+            return Ok(None);
+        }
+        let seconds = value.parse().context("wakealarm is not a number")?;
+        Ok(DateTime::from_timestamp(seconds, 0).filter(|t| *t != DateTime::UNIX_EPOCH))
+    }
+
+    /// Arms the wake alarm at `time`, or disarms it when `None`.
+    pub fn set_wake_alarm(&self, time: Option<DateTime<Utc>>) -> anyhow::Result<()> {
+        let path = self.class.join("wakealarm");
+        // the kernel refuses to replace an alarm that is still armed
+        write(&path, "0")?;
+        if let Some(time) = time {
+            write(&path, &time.timestamp().to_string())?;
+        }
+        Ok(())
+    }
+
+    fn attribute(&self, name: &str) -> anyhow::Result<String> {
+        let path = self.class.join(name);
+        Ok(std::fs::read_to_string(&path)
+            .with_context(|| path.display().to_string())?
+            .trim()
+            .to_string())
+    }
+}
+
+fn write(path: &Path, value: &str) -> anyhow::Result<()> {
+    std::fs::write(path, value).with_context(|| path.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wake_alarm() {
+        let dir = tempdir::TempDir::new("rtc").unwrap();
+        std::fs::write(dir.path().join("since_epoch"), "1700000000\n").unwrap();
+        std::fs::write(dir.path().join("wakealarm"), "\n").unwrap();
+        let rtc = Rtc::from_class(dir.path().to_path_buf());
+
+        assert!(rtc.is_available());
+        assert_eq!(rtc.time().unwrap().timestamp(), 1_700_000_000);
+        assert_eq!(rtc.wake_alarm().unwrap(), None);
+
+        let time = DateTime::from_timestamp(1_700_003_600, 0).unwrap();
+        rtc.set_wake_alarm(Some(time)).unwrap();
+        assert_eq!(rtc.wake_alarm().unwrap(), Some(time));
+        rtc.set_wake_alarm(None).unwrap();
+        assert_eq!(rtc.wake_alarm().unwrap(), None);
+    }
+}
@@ -16,6 +16,9 @@ mod api;
 mod app;
 mod authentication;
 mod config;
+mod error;
+#[cfg(feature = "fault-injection")]
+mod fault_injection;
 mod hal;
 mod persistency;
 mod serial_service;
@@ -24,47 +27,297 @@ mod usb_boot;
 mod utils;
 
 use crate::config::Config;
-use crate::serial_service::{serial::SerialConnections, serial_config};
+use crate::serial_service::{
+    console_multicast::run_console_multicast, serial::SerialConnections, serial_config,
+    tcp_console::run_tcp_consoles,
+};
 use crate::{
-    api::legacy, api::legacy::info_config, authentication::linux_authenticator::LinuxAuthenticator,
-    streaming_data_service::StreamingDataService,
+    api::legacy, api::legacy::info_config, api::traces::RequestTracing,
+    authentication::authentication_service::LocalConnection,
+    authentication::linux_authenticator::LinuxAuthenticator,
+    streaming_data_service::StreamingDataService, utils::private_unix_listener,
 };
-use actix_files::{Files, NamedFile};
+use actix_files::Files;
 use actix_web::{
     http::{self, KeepAlive},
+    middleware::from_fn,
     web::{self, Data},
     App, HttpRequest, HttpResponse, HttpServer,
 };
 use anyhow::Context;
+use app::activity::ActivityMonitor;
+use app::capabilities::Capabilities;
+use app::cluster::Cluster;
+use app::config_service::{run_config_watcher, ConfigService};
+use app::console_shares::ConsoleShares;
+use app::crash_report::{CrashReporter, CRASH_REPORT};
+use app::dhcp_server::DhcpServer;
+use app::enrollment::Enrollment;
+use app::factory_reset::{FactoryReset, IMAGES_DIR};
+use app::failover::Failover;
+use app::firmware_signature::FirmwareVerifier;
+use app::firmware_slots::FirmwareSlots;
+use app::http_policy::HttpPolicy;
+use app::i2c_access::I2cAccess;
+use app::idempotency::IdempotencyCache;
+use app::identify::Identify;
+use app::image_cache::ImageCache;
+use app::image_sharing::{ImageSharing, MDNS_TXT_KEY};
+use app::jobs::Jobs;
+use app::kubernetes::Kubernetes;
+use app::kvm::Kvm;
+use app::lights_out::LightsOut;
+use app::listeners::{redirect_location, ApiListeners};
+use app::logging::{JsonFormat, LogControl};
+use app::mdns::Mdns;
+use app::nbd_server::NbdServer;
+use app::netboot::{run_tftp_server, Netboot};
+use app::network_config::NetworkConfigurator;
+use app::node_agent::NodeAgents;
+use app::node_backup::NodeBackups;
+use app::notifier::Notifier;
+use app::physical_presence::PhysicalPresence;
+use app::pipelines::Pipelines;
+use app::plugins::Plugins;
+use app::power_supply::run_power_monitor;
+use app::provisioning::Provisioning;
+use app::readiness::{Readiness, SubsystemState};
+use app::request_trace::{RequestTraces, TraceLayer};
+use app::resources::Resources;
+use app::retention::Reaper;
+use app::rules::Rules;
+use app::safe_mode::SafeMode;
+use app::scripting::Scripts;
+use app::shutdown::Shutdown;
+use app::storage_health::StorageMonitor;
+use app::storage_manager::StorageManager;
+use app::systemd;
+use app::time_sync::restore_time_settings;
+use app::transfer_action::UpgradeCommand;
+use app::update_checker::UpdateChecker;
+use app::update_scheduler::run_update_scheduler;
+use app::upgrade_journal::RecoveryAction;
+use app::upgrade_progress::UpgradeStatus;
+use app::usb_console::UsbConsole;
+use app::wake_alarm::handle_wake_alarm;
+use app::wake_schedule::run_wake_schedules;
+use app::watchdog::{run_systemd_watchdog, run_watchdog, HealthChecks};
+use app::web_ui::WebUi;
+use app::wifi::WifiManager;
 use app::{bmc_application::BmcApplication, event_application::run_event_listener};
-use clap::{command, value_parser, Arg};
-use config::Log;
-use futures::future::join_all;
+use clap::{command, value_parser, Arg, ArgAction};
+use config::{Listener, Log, LogFormat, Scheme, Tls};
+use hal::board_profile::BoardProfile;
+use hal::eeprom::BoardIdentity;
+use hal::expansion::Expansions;
+use hal::rtc::Rtc;
 use openssl::{
     pkey::{PKey, Private},
     ssl::{SslAcceptor, SslAcceptorBuilder, SslMethod},
     x509::X509,
 };
+use persistency::app_persistency::{check_store, StoreCheck, BIN_DATA};
 use std::{
     fs::OpenOptions,
     io::Read,
+    net::TcpListener,
     path::{Path, PathBuf},
     sync::Arc,
 };
 use tracing_appender::{non_blocking::WorkerGuard, rolling::Rotation};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
-
-const HTTP_PORT: u16 = 80;
+use tracing_subscriber::{
+    filter::LevelFilter, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer,
+};
 
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
-    let config = Config::load(&config_path()).context("Error parsing config file")?;
-    let _logger_lifetime = init_logger(&config.log);
+    let args = command!()
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_parser(value_parser!(PathBuf))
+                .required_unless_present("check-store"),
+        )
+        .arg(
+            Arg::new("check-store")
+                .long("check-store")
+                .help("validate the persistency store, repair it when needed and exit")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("gpio-sim")
+                .long("gpio-sim")
+                .help(
+                    "drive simulated GPIO chips of the gpio-sim kernel module instead of the board",
+                )
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("safe-mode")
+                .long("safe-mode")
+                .help("serve only the recovery API, without loading the configuration or store")
+                .action(ArgAction::SetTrue),
+        )
+        .get_matches();
+
+    if args.get_flag("check-store") {
+        return run_store_check().await;
+    }
+
+    let readiness = Arc::new(Readiness::default());
+    let config_path = args
+        .get_one::<PathBuf>("config")
+        .expect("`config` argument required")
+        .clone();
+    if let Some(trigger) = app::safe_mode::check(args.get_flag("safe-mode")) {
+        return run_safe_mode(SafeMode::new(trigger, config_path)).await;
+    }
+    let config = Config::load(&config_path).context("Error parsing config file")?;
+    let request_traces = Arc::new(RequestTraces::default());
+    let (_logger_lifetime, log_control) = init_logger(&config.log, request_traces.clone());
+    let crash_reporter = Arc::new(CrashReporter::new(
+        PathBuf::from(CRASH_REPORT),
+        request_traces.clone(),
+    ));
+    crash_reporter.install();
 
-    let tls = load_tls_config(&config)?;
-    let bmc = Data::new(BmcApplication::new(config.store.write_timeout).await?);
-    let serial_service = Data::new(SerialConnections::new());
-    let streaming_data_service = Data::new(StreamingDataService::new());
+    let api_address = config.api_address()?;
+    #[cfg_attr(feature = "mock", allow(unused_mut))]
+    let mut board =
+        BoardProfile::load(config.board.as_deref(), config.board_description.as_deref())?;
+    #[cfg(not(feature = "mock"))]
+    let _gpio_sim = if args.get_flag("gpio-sim") {
+        let sim = hal::gpio_sim::GpioSim::new("bmcd", board)?;
+        board = sim.profile();
+        Some(sim)
+    } else {
+        None
+    };
+    #[cfg(feature = "mock")]
+    anyhow::ensure!(
+        !args.get_flag("gpio-sim"),
+        "--gpio-sim is not available with the mock feature"
+    );
+    tracing::info!("board profile: {}", board.name);
+    let bmc = Data::new(
+        BmcApplication::new(
+            board,
+            config.store.write_timeout,
+            config.power.restore_policy,
+        )
+        .await?,
+    );
+    let rtc = Data::new(Rtc::open());
+    let power_bmc = bmc.clone();
+    let schedules_rtc = rtc.clone().into_inner();
+    readiness.spawn("power", async move {
+        power_bmc.initialize_power().await?;
+        // the alarm powers on nodes on top of the restored state
+        if let Err(e) = handle_wake_alarm(&power_bmc).await {
+            tracing::error!("powering on nodes after wake alarm: {:#}", e);
+        }
+        run_wake_schedules(power_bmc.into_inner(), schedules_rtc);
+        Ok(())
+    });
+    let cooling_bmc = bmc.clone();
+    readiness.spawn(
+        "cooling",
+        async move { cooling_bmc.initialize_cooling().await },
+    );
+    let notifier = Arc::new(Notifier::new(config.notifications.clone()));
+    crash_reporter.set_notifier(notifier.clone());
+    let lights_out = Arc::new(LightsOut::new(config.lights_out.clone()));
+    lights_out.clone().run(bmc.clone().into_inner());
+    run_power_monitor(
+        bmc.clone().into_inner(),
+        notifier.clone(),
+        lights_out.clone(),
+        config.power.brownout.clone(),
+    );
+    let cluster = Arc::new(Cluster::new(config.cluster.clone())?);
+    if let Some(kubernetes) = &config.kubernetes {
+        bmc.set_kubernetes(Arc::new(Kubernetes::new(kubernetes.clone())?));
+    }
+    let config_service = Arc::new(ConfigService::new(
+        config_path,
+        config.clone(),
+        notifier.clone(),
+    ));
+    let jobs = Arc::new(Jobs::new(config.jobs.retention, notifier.clone()));
+    let streaming_data_service = Data::new(StreamingDataService::new(jobs.clone()));
+    let factory_reset = Data::new(FactoryReset::default());
+    let network = Data::new(NetworkConfigurator::default());
+    let provisioning = Data::new(Provisioning::detect(config.provisioning.enabled));
+    let wifi = Data::new(WifiManager::default());
+    // the i2c devices and UARTs are probed side by side
+    let (identity, expansions, serial_service) = tokio::try_join!(
+        tokio::task::spawn_blocking(BoardIdentity::load),
+        tokio::task::spawn_blocking(Expansions::detect),
+        tokio::task::spawn_blocking(move || SerialConnections::new(board)),
+    )?;
+    readiness.complete("serial", serial_service.ensure_running());
+    let identity = Data::new(identity);
+    let expansions = Data::new(expansions);
+    let serial_service = Data::new(serial_service);
+    let node_agents = Arc::new(NodeAgents::new(
+        config.node_agent.clone(),
+        serial_service.clone().into_inner(),
+        board.node_count,
+    ));
+    node_agents.run();
+    if let Some(tcp_console) = &config.tcp_console {
+        let started = async {
+            let tls = if tcp_console.tls {
+                Some(load_tls_config(&config.tls)?.build())
+            } else {
+                None
+            };
+            run_tcp_consoles(
+                tcp_console.clone(),
+                tls,
+                serial_service.clone().into_inner(),
+            )
+            .await
+        };
+        if let Err(e) = started.await {
+            tracing::error!("TCP consoles not started: {:#}", e);
+        }
+    }
+    if let Some(multicast) = &config.console_multicast {
+        if let Err(e) =
+            run_console_multicast(multicast.clone(), serial_service.clone().into_inner()).await
+        {
+            tracing::error!("console multicast not started: {:#}", e);
+        }
+    }
+    let i2c_access = Data::new(I2cAccess::new(&config.i2c));
+    let capabilities = Data::new(Capabilities::detect(
+        &bmc,
+        &identity,
+        &rtc,
+        &wifi,
+        &expansions,
+        &config,
+    ));
+    let mut mdns = Mdns::new(api_address.port(), identity.serial());
+    if config.image_sharing.serve {
+        let scheme = match config.api_listener()?.scheme {
+            Scheme::Http => "http",
+            _ => "https",
+        };
+        mdns.advertise(MDNS_TXT_KEY, scheme);
+    }
+    let mdns = Arc::new(mdns);
+    let image_cache = Arc::new(ImageCache::new(
+        config.image_cache.clone(),
+        config.storage.quotas.images,
+    ));
+    let image_sharing = Data::new(ImageSharing::new(
+        config.image_sharing.clone(),
+        image_cache.clone(),
+        mdns.clone(),
+        cluster.clone(),
+    )?);
     let authentication = Arc::new(
         LinuxAuthenticator::new(
             "/api/bmc/authenticate",
@@ -75,64 +328,564 @@ async fn main() -> anyhow::Result<()> {
         .await?,
     );
 
-    run_event_listener(bmc.clone().into_inner())?;
+    let presence = Data::new(PhysicalPresence::default());
+    let firmware_verifier = Data::new(FirmwareVerifier::new(
+        config.firmware_signing.trusted_keys.clone(),
+        presence.clone().into_inner(),
+    ));
+    let upgrade_status = Data::new(UpgradeStatus::default());
+    run_event_listener(bmc.clone().into_inner(), presence.clone().into_inner())?;
+    let time_bmc = bmc.clone();
+    tokio::spawn(async move {
+        if let Err(e) = restore_time_settings(&time_bmc).await {
+            tracing::warn!("applying time settings: {:#}", e);
+        }
+    });
+    if wifi.is_available() {
+        let (wifi, bmc) = (wifi.clone(), bmc.clone());
+        tokio::spawn(async move {
+            if let Err(e) = wifi.restore(&bmc).await {
+                tracing::error!("restoring Wi-Fi networks: {:#}", e);
+            }
+        });
+    }
+    let http_policy = Arc::new(HttpPolicy::new(&config.http));
+    run_config_watcher(
+        config_service.subscribe(),
+        bmc.clone().into_inner(),
+        authentication.clone(),
+        notifier.clone(),
+        cluster.clone(),
+        http_policy.clone(),
+    );
+    config_service.clone().reload_on_sighup()?;
+    let netboot =
+        Arc::new(Netboot::new(&bmc, config.netboot.enabled, config.netboot.root.clone()).await);
+    if config.netboot.enabled {
+        if let Err(e) = run_tftp_server(config.netboot.root.clone()) {
+            tracing::error!("TFTP server not started: {}", e);
+        }
+    }
+    let nbd = Arc::new(NbdServer::load(&bmc, PathBuf::from(IMAGES_DIR)).await);
+    if config.nbd.enabled {
+        if let Err(e) = nbd.clone().run(&config.nbd.allow).await {
+            tracing::error!("NBD server not started: {}", e);
+        }
+    }
+    let firmware_slots = config
+        .firmware
+        .as_ref()
+        .map(|firmware| Arc::new(FirmwareSlots::new(firmware)));
+    // recover before the running firmware gets confirmed. A resumed upgrade
+    // re-arms the bootloader, which must not be mistaken for a pending
+    // confirmation of the running firmware.
+    let mut resumed = false;
+    if let Some(report) = upgrade_status
+        .journal()
+        .recover(firmware_slots.as_deref())
+        .await
+    {
+        resumed = report.action == RecoveryAction::Resumed;
+        upgrade_status.set_recovery(report);
+    }
+    let firmware_slots = firmware_slots.map(|slots| {
+        let confirm = slots.clone();
+        if !resumed {
+            tokio::spawn(async move {
+                if let Err(e) = confirm.confirm_when_healthy(api_address).await {
+                    tracing::error!("firmware confirmation: {:#}", e);
+                }
+            });
+        }
+        Data::from(slots)
+    });
+    let update_checker = config.updates.clone().map(|updates| {
+        let checker = Arc::new(UpdateChecker::new(updates.clone()));
+        checker.clone().run();
+        if let Some(window) = updates.maintenance {
+            let (verifier, slots, progress) = (
+                firmware_verifier.clone().into_inner(),
+                firmware_slots.clone().map(|s| s.into_inner()),
+                upgrade_status.clone().into_inner(),
+            );
+            let upgrade_command = move || UpgradeCommand::OsUpgrade {
+                verifier: verifier.clone(),
+                slots: slots.clone(),
+                progress: progress.clone(),
+            };
+            if let Err(e) = run_update_scheduler(
+                window,
+                checker.clone(),
+                bmc.clone().into_inner(),
+                streaming_data_service.clone().into_inner(),
+                notifier.clone(),
+                upgrade_command,
+            ) {
+                tracing::error!("update scheduler not started: {:#}", e);
+            }
+        }
+        Data::from(checker)
+    });
+    let netboot_http = config.netboot.http.then(|| config.netboot.root.clone());
+    let usb_console = config
+        .usb_console
+        .clone()
+        .map(|console| Data::new(UsbConsole::new(console)));
+    if let Some(console) = &usb_console {
+        if let Err(e) = console.start().await {
+            tracing::error!("USB console: {:#}", e);
+        }
+    }
+    if let Some(dhcp) = &config.dhcp {
+        let dhcp_server = DhcpServer::new(dhcp.clone()).with_netboot(netboot.clone());
+        if let Err(e) = dhcp_server.run() {
+            tracing::error!("DHCP server on {} not started: {}", dhcp.interface, e);
+        }
+    }
+    if config.mdns.enabled {
+        if let Err(e) = mdns.clone().run() {
+            tracing::warn!("mDNS advertisement disabled: {}", e);
+        }
+    }
+    let checks = HealthChecks {
+        config: config_service.subscribe(),
+        streaming: streaming_data_service.clone().into_inner(),
+        bmc: bmc.clone().into_inner(),
+    };
+    if config.watchdog.enabled {
+        if let Err(e) = run_watchdog(config.watchdog.clone(), checks.clone()) {
+            tracing::error!("watchdog not started: {:#}", e);
+        }
+    }
+    match systemd::watchdog_interval() {
+        Ok(Some(timeout)) => run_systemd_watchdog(timeout, config.watchdog.require.clone(), checks),
+        Ok(None) => {}
+        Err(e) => tracing::error!("systemd watchdog not started: {:#}", e),
+    }
+    tokio::spawn(async {
+        tokio::time::sleep(app::safe_mode::HEALTHY_AFTER).await;
+        app::safe_mode::clear_start_attempts();
+    });
+    let ready = readiness.clone();
+    tokio::spawn(async move {
+        let status = ready.settled().await;
+        let failed: Vec<_> = status
+            .subsystems
+            .iter()
+            .filter(|(_, s)| s.state == SubsystemState::Failed)
+            .map(|(name, _)| *name)
+            .collect();
+        if failed.is_empty() {
+            systemd::notify("READY=1\nSTATUS=ready");
+        } else {
+            systemd::notify(&format!("READY=1\nSTATUS=failed: {}", failed.join(", ")));
+        }
+    });
+    let mdns = Data::from(mdns);
+    let image_cache = Data::from(image_cache);
+    let failover = Arc::new(Failover::new(
+        config.failover.clone(),
+        bmc.clone().into_inner(),
+        node_agents.clone(),
+        notifier.clone(),
+    ));
+    failover.clone().run();
+    let failover = Data::from(failover);
+    let node_agents = Data::from(node_agents);
+    let resources = Data::new(Resources::default());
+    let scripts = Arc::new(Scripts::new(
+        config.scripting.clone(),
+        bmc.clone().into_inner(),
+        notifier.clone(),
+    ));
+    scripts.run_on_events();
+    let scripts = Data::from(scripts);
+    let plugins = Data::new(Plugins::new(config.plugins.clone()));
+    plugins.run(bmc.clone().into_inner(), notifier.clone());
+    let kvm = Data::new(Kvm::new(config.kvm.clone(), bmc.clone().into_inner()));
+    let node_backups = Data::new(NodeBackups::new(
+        bmc.clone().into_inner(),
+        jobs.clone(),
+        config.storage.clone(),
+    ));
+    let pipelines = Data::new(Pipelines::new(
+        bmc.clone().into_inner(),
+        jobs.clone(),
+        streaming_data_service.clone().into_inner(),
+        image_sharing.clone().into_inner(),
+        serial_service.clone().into_inner(),
+    ));
+    let netboot = Data::from(netboot);
+    let identify = Data::new(Identify::new(bmc.clone().into_inner()));
+    let activity = Arc::new(ActivityMonitor::new(config.activity.clone()));
+    activity.clone().run(
+        bmc.clone().into_inner(),
+        notifier.clone(),
+        lights_out.clone(),
+    );
+    let rules = Arc::new(Rules::new(
+        bmc.clone().into_inner(),
+        notifier.clone(),
+        activity.clone(),
+    ));
+    rules.clone().run();
+    let rules = Data::from(rules);
+    let activity = Data::from(activity);
+    let lights_out = Data::from(lights_out);
+    let storage_health = Arc::new(StorageMonitor::new(config.storage_health.clone()));
+    storage_health
+        .clone()
+        .run(bmc.clone().into_inner(), notifier.clone());
+    let storage_health = Data::from(storage_health);
+    let storage_manager = Data::new(StorageManager::new(config.storage.clone()));
+    let reaper = Arc::new(Reaper::new(&config));
+    reaper.clone().run();
+    let reaper = Data::from(reaper);
+    let nbd = Data::from(nbd);
+    let config_service = Data::from(config_service);
+    let notifier = Data::from(notifier);
+    let crash_reporter = Data::from(crash_reporter);
+    let cluster = Data::from(cluster);
+    let jobs = Data::from(jobs);
+    let log_control = Data::new(log_control);
+    let request_traces = Data::from(request_traces);
+    let readiness = Data::from(readiness);
+    let shutdown = Arc::new(Shutdown::new(
+        config.shutdown.drain_timeout,
+        bmc.clone().into_inner(),
+        streaming_data_service.clone().into_inner(),
+    ));
+    let shutdown_data = Data::from(shutdown.clone());
+    let enrollment = Arc::new(Enrollment::new(config.enrollment.clone()));
+    if let Err(e) = enrollment.clone().run(
+        config_service.status().path,
+        identity.serial(),
+        provisioning.clone().into_inner(),
+        network.clone().into_inner(),
+        shutdown.clone(),
+    ) {
+        tracing::error!("enrollment not started: {:#}", e);
+    }
+    let enrollment = Data::from(enrollment);
+    let idempotency = Data::new(IdempotencyCache::default());
+    let console_shares = Data::new(ConsoleShares::default());
 
-    let run_server = HttpServer::new(move || {
-        let www_root = config.www.clone();
+    let legacy_api = config.legacy_api.enabled;
+    let api_listeners = config.listeners()?;
+    let config_updates = config_service.subscribe();
+    let http_policy = Data::from(http_policy);
+    let web_ui = Data::new(WebUi::new(config.www.clone(), config.web_ui.clone()));
+    let app = move || {
         App::new()
+            .app_data(http_policy.clone())
+            .app_data(lights_out.clone())
+            .wrap(from_fn(api::lights_out::wake_on_request))
+            .wrap(from_fn(api::http_policy::apply_http_policy))
+            .service(
+                web::scope("/api/bmc/provisioning")
+                    .wrap(from_fn(api::provisioning::only_local_clients))
+                    .app_data(provisioning.clone())
+                    .app_data(bmc.clone())
+                    .app_data(network.clone())
+                    .configure(api::provisioning::config),
+            )
             .service(
                 web::scope("/api/bmc")
+                    .wrap(from_fn(api::idempotency::replay_idempotent))
                     .wrap(authentication.clone())
+                    .wrap(RequestTracing::new(request_traces.clone().into_inner()))
+                    .wrap(from_fn(api::shutdown::refuse_while_draining))
+                    .wrap(from_fn(api::provisioning::refuse_until_provisioned))
+                    .app_data(provisioning.clone())
+                    .app_data(enrollment.clone())
                     .app_data(bmc.clone())
+                    .app_data(identity.clone())
+                    .app_data(capabilities.clone())
+                    .app_data(identify.clone())
+                    .app_data(activity.clone())
+                    .app_data(storage_health.clone())
+                    .app_data(storage_manager.clone())
+                    .app_data(reaper.clone())
+                    .app_data(rules.clone())
+                    .app_data(expansions.clone())
+                    .app_data(i2c_access.clone())
+                    .app_data(rtc.clone())
                     .app_data(streaming_data_service.clone())
                     .app_data(serial_service.clone())
+                    .app_data(config_service.clone())
+                    .app_data(console_shares.clone())
+                    .app_data(factory_reset.clone())
+                    .app_data(network.clone())
+                    .app_data(wifi.clone())
+                    .app_data(mdns.clone())
+                    .app_data(netboot.clone())
+                    .app_data(nbd.clone())
+                    .app_data(presence.clone())
+                    .app_data(firmware_verifier.clone())
+                    .app_data(upgrade_status.clone())
+                    .app_data(notifier.clone())
+                    .app_data(crash_reporter.clone())
+                    .app_data(cluster.clone())
+                    .app_data(jobs.clone())
+                    .app_data(log_control.clone())
+                    .app_data(request_traces.clone())
+                    .app_data(readiness.clone())
+                    .app_data(shutdown_data.clone())
+                    .app_data(idempotency.clone())
+                    .app_data(image_cache.clone())
+                    .app_data(image_sharing.clone())
+                    .app_data(node_agents.clone())
+                    .app_data(failover.clone())
+                    .app_data(resources.clone())
+                    .app_data(pipelines.clone())
+                    .app_data(scripts.clone())
+                    .app_data(plugins.clone())
+                    .app_data(kvm.clone())
+                    .app_data(node_backups.clone())
+                    .configure(|cfg| {
+                        if let Some(slots) = &firmware_slots {
+                            cfg.app_data(slots.clone());
+                        }
+                        if let Some(checker) = &update_checker {
+                            cfg.app_data(checker.clone());
+                        }
+                        if let Some(console) = &usb_console {
+                            cfg.app_data(console.clone());
+                        }
+                    })
                     .configure(serial_config)
+                    .configure(api::activity::config)
+                    .configure(api::batch::config)
+                    .configure(api::cluster::config)
+                    .configure(api::configuration::config)
+                    .configure(api::console_shares::config)
+                    .configure(api::diagnostics::config)
+                    .configure(api::discovery::config)
+                    .configure(api::enrollment::config)
+                    .configure(api::expansion::config)
+                    .configure(api::factory_reset::config)
+                    .configure(api::failover::config)
+                    .configure(|_cfg| {
+                        #[cfg(feature = "fault-injection")]
+                        api::fault_injection::config(_cfg);
+                    })
+                    .configure(api::firmware::config)
+                    .configure(api::flash_history::config)
+                    .configure(api::graphql::config)
+                    .configure(api::i2c::config)
+                    .configure(api::identify::config)
+                    .configure(api::identity::config)
+                    .configure(api::image_cache::config)
+                    .configure(api::image_inspection::config)
+                    .configure(api::inventory::config)
+                    .configure(api::jobs::config)
+                    .configure(api::kv_store::config)
+                    .configure(api::kvm::config)
+                    .configure(api::lights_out::config)
+                    .configure(api::logging::config)
+                    .configure(api::messages::config)
+                    .configure(api::metrics::config)
+                    .configure(|_cfg| {
+                        #[cfg(feature = "mock")]
+                        api::mock::config(_cfg);
+                    })
+                    .configure(api::nbd::config)
+                    .configure(api::netboot::config)
+                    .configure(api::network::config)
+                    .configure(api::node_agent::config)
+                    .configure(api::node_backup::config)
+                    .configure(api::node_pins::config)
+                    .configure(api::node_state::config)
+                    .configure(api::pipelines::config)
+                    .configure(api::plugins::config)
+                    .configure(api::power_presets::config)
+                    .configure(api::power_supply::config)
+                    .configure(api::readiness::config)
+                    .configure(api::resources::config)
+                    .configure(api::retention::config)
+                    .configure(api::rules::config)
+                    .configure(api::rtc::config)
+                    .configure(api::schemas::config)
+                    .configure(api::scripting::config)
+                    .configure(api::sd_card::config)
+                    .configure(api::selftest::config)
+                    .configure(api::shutdown::config)
+                    .configure(api::storage_health::config)
+                    .configure(api::storage_manager::config)
+                    .configure(api::time::config)
+                    .configure(api::traces::config)
+                    .configure(api::updates::config)
+                    .configure(api::usb_console::config)
+                    .configure(api::wifi::config)
                     // Legacy API
-                    .configure(legacy::config),
+                    .configure(|cfg| legacy::config(cfg, legacy_api)),
             )
-            // Serve a static tree of files of the web UI. Must be the last item.
-            .service(Files::new("/", &config.www).index_file("index.html"))
-            .default_service(web::to(move || {
-                let www_index = www_root.join("index.html");
-                NamedFile::open_async(www_index)
-            }))
-    })
-    .bind_openssl((config.host.clone(), config.port), tls)?
-    .keep_alive(KeepAlive::Os)
-    .workers(2)
-    .run();
-
-    let mut futures = vec![run_server];
-    if config.redirect_http {
-        // redirect requests to 'HTTPS'
-        futures.push(
-            HttpServer::new(move || {
-                App::new()
-                    .app_data(Data::new(config.port))
-                    .configure(info_config)
-                    .default_service(web::route().to(redirect))
+            .configure(|cfg| {
+                if let Some(root) = &netboot_http {
+                    cfg.service(Files::new("/netboot", root));
+                }
+                if image_sharing.serves() {
+                    api::image_sharing::config(cfg, image_cache.clone());
+                }
+                api::pipelines::hooks_config(cfg, pipelines.clone(), config_service.clone());
+                api::console_shares::shared_config(
+                    cfg,
+                    console_shares.clone(),
+                    config_service.clone(),
+                    serial_service.clone(),
+                );
             })
-            .bind((config.host, HTTP_PORT))?
-            .run(),
-        );
+            // the web UI answers all requests that no route took
+            .app_data(web_ui.clone())
+            .default_service(web::to(api::web_ui::serve))
+    };
+    let start_listener = {
+        let app = app.clone();
+        move |listener: &Listener, socket: Option<TcpListener>| {
+            let address = listener.address.socket_addr(listener.port)?;
+            let server = match listener.scheme {
+                Scheme::Https => {
+                    let tls = listener.tls.as_ref().context("no TLS settings")?;
+                    let tls = load_tls_config(tls)?;
+                    let server = HttpServer::new(app.clone());
+                    match socket {
+                        Some(socket) => server.listen_openssl(socket, tls)?,
+                        None => server.bind_openssl(address, tls)?,
+                    }
+                    .keep_alive(KeepAlive::Os)
+                    .workers(2)
+                    .disable_signals()
+                    .run()
+                }
+                Scheme::Http => {
+                    let server = HttpServer::new(app.clone());
+                    match socket {
+                        Some(socket) => server.listen(socket)?,
+                        None => server.bind(address)?,
+                    }
+                    .keep_alive(KeepAlive::Os)
+                    .workers(2)
+                    .disable_signals()
+                    .run()
+                }
+                Scheme::Redirect => {
+                    // redirect requests to 'HTTPS'
+                    let port = listener.redirect_port.unwrap_or(config.port);
+                    let server = HttpServer::new(move || {
+                        App::new()
+                            .app_data(Data::new(port))
+                            .configure(info_config)
+                            .default_service(web::route().to(redirect))
+                    });
+                    match socket {
+                        Some(socket) => server.listen(socket)?,
+                        None => server.bind(address)?,
+                    }
+                    .workers(1)
+                    .disable_signals()
+                    .run()
+                }
+            };
+            Ok(server)
+        }
+    };
+    let listeners = Arc::new(ApiListeners::new(
+        Box::new(start_listener),
+        shutdown.clone(),
+    ));
+    // sockets of a socket-activated unit: HTTPS first, then HTTP
+    let activated = systemd::listeners()?;
+    if activated.is_empty() {
+        listeners.apply(api_listeners).await?;
+        listeners.follow(config_updates);
+    } else {
+        listeners.serve_activated(activated, &config.tls, config.port)?;
     }
 
-    // run server(s)
-    join_all(futures).await;
+    if let Some(path) = &config.unix_socket {
+        // serve the API unauthenticated to local tools, only root can connect
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| path.display().to_string());
+            }
+            _ => {}
+        }
+        let local_server = HttpServer::new(app)
+            .on_connect(|_, data| {
+                data.insert(LocalConnection);
+            })
+            .listen_uds(private_unix_listener(path).with_context(|| path.display().to_string())?)?
+            .workers(1)
+            .disable_signals()
+            .run();
+        tracing::info!("local API on {}", path.display());
+        shutdown.add_server(local_server.handle());
+        tokio::spawn(local_server);
+    }
+    shutdown.clone().stop_on_signals()?;
+
+    // run until the servers are stopped
+    shutdown.stopped().await;
+    if let Some(path) = &config.unix_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    app::safe_mode::clear_start_attempts();
     tracing::info!("exiting {}", env!("CARGO_PKG_NAME"));
     Ok(())
 }
 
+/// Serves the routes of [`SafeMode`] with the built-in defaults, see
+/// `app::safe_mode`.
+async fn run_safe_mode(safe_mode: SafeMode) -> anyhow::Result<()> {
+    let config = Config::defaults()?;
+    let (_logger_lifetime, _) = init_logger(&config.log, Arc::new(RequestTraces::default()));
+    tracing::warn!(
+        "starting in safe mode ({:?}), log in with the default credentials",
+        safe_mode.status().trigger
+    );
+    let tls = load_tls_config(&config.tls).or_else(|e| {
+        tracing::warn!("{:#}, using a self-signed certificate", e);
+        let (private_key, cert) = app::safe_mode::self_signed_certificate()?;
+        let mut tls = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+        tls.set_private_key(&private_key)?;
+        tls.set_certificate(&cert)?;
+        anyhow::Ok(tls)
+    })?;
+    let authentication = Arc::new(LinuxAuthenticator::with_password_entries(
+        std::iter::once(app::safe_mode::default_credentials()?),
+        "/api/bmc/authenticate",
+        "Access to Baseboard Management Controller (safe mode)",
+        config.authentication.token_expires,
+        config.authentication.authentication_attempts,
+    ));
+    let safe_mode = Data::new(safe_mode);
+    HttpServer::new(move || {
+        App::new().service(
+            web::scope("/api/bmc")
+                .wrap(authentication.clone())
+                .app_data(safe_mode.clone())
+                .configure(api::safe_mode::config),
+        )
+    })
+    .bind_openssl((config.host.clone(), config.port), tls)?
+    .workers(1)
+    .run()
+    .await?;
+    Ok(())
+}
+
 async fn redirect(request: HttpRequest, port: web::Data<u16>) -> HttpResponse {
-    let host = request.connection_info().host().to_string();
-    let path = request.uri().to_string();
-    let redirect_url = format!("https://{}:{}{}", host, port.get_ref(), path);
+    let redirect_url = redirect_location(
+        request.connection_info().host(),
+        *port.get_ref(),
+        &request.uri().to_string(),
+    );
     HttpResponse::PermanentRedirect()
         .append_header((http::header::LOCATION, redirect_url))
         .finish()
 }
 
-fn init_logger(log_config: &Log) -> WorkerGuard {
+fn init_logger(log_config: &Log, traces: Arc<RequestTraces>) -> (WorkerGuard, LogControl) {
     let file_appender = tracing_appender::rolling::Builder::new()
         .rotation(Rotation::HOURLY)
         .max_log_files(3)
@@ -142,38 +895,76 @@ fn init_logger(log_config: &Log) -> WorkerGuard {
         .expect("error setting up log rotation");
 
     let (bmcd_log, guard) = tracing_appender::non_blocking(file_appender);
-    let full_layer = tracing_subscriber::fmt::layer()
-        .with_writer(bmcd_log)
-        .with_ansi(log_config.coloring);
+    let full_layer = match log_config.format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_writer(bmcd_log)
+            .with_ansi(log_config.coloring)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .with_writer(bmcd_log)
+            .event_format(JsonFormat)
+            .boxed(),
+    };
 
     let filter = EnvFilter::builder().parse_lossy(log_config.directive.clone());
-    let stdout_layer = log_config.stdout.then_some(
-        tracing_subscriber::fmt::layer()
+    let (filter, filter_handle) = reload::Layer::new(filter);
+    let stdout_layer = log_config.stdout.then(|| match log_config.format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
             .without_time()
             .with_ansi(log_config.coloring)
             .with_writer(std::io::stdout)
-            .compact(),
-    );
+            .compact()
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stdout)
+            .event_format(JsonFormat)
+            .boxed(),
+    });
 
     let layers = full_layer.and_then(stdout_layer).with_filter(filter);
-    tracing_subscriber::registry().with(layers).init();
+    let trace_layer = TraceLayer::new(traces).with_filter(LevelFilter::DEBUG);
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(trace_layer)
+        .init();
 
     tracing::info!("Turing Pi 2 BMC Daemon v{}", env!("CARGO_PKG_VERSION"));
-    guard
+    (
+        guard,
+        LogControl::new(filter_handle, log_config.directive.clone()),
+    )
 }
 
-fn config_path() -> PathBuf {
-    command!()
-        .arg(
-            Arg::new("config")
-                .long("config")
-                .value_parser(value_parser!(PathBuf))
-                .required(true),
-        )
-        .get_matches()
-        .get_one::<PathBuf>("config")
-        .expect("`config` argument required")
-        .into()
+async fn run_store_check() -> anyhow::Result<()> {
+    let path = Path::new(BIN_DATA);
+    match check_store(path).await? {
+        StoreCheck::Missing => println!("{}: no store present", path.display()),
+        StoreCheck::Healthy(report) => println!(
+            "{}: ok, version {}, {} entries, {} bytes",
+            path.display(),
+            report.version,
+            report.entries,
+            report.data_size
+        ),
+        StoreCheck::Migrated { from, report } => println!(
+            "{}: migrated from version {} to {}, {} entries",
+            path.display(),
+            from,
+            report.version,
+            report.entries
+        ),
+        StoreCheck::RestoredBackup(report) => println!(
+            "{}: corrupt, restored backup with {} entries",
+            path.display(),
+            report.entries
+        ),
+        StoreCheck::Reset(error) => println!(
+            "{}: corrupt ({}) and no valid backup found. Moved store aside, bmcd starts with defaults",
+            path.display(),
+            error
+        ),
+    }
+    Ok(())
 }
 
 fn load_keys_from_pem<P: AsRef<Path>>(
@@ -198,10 +989,10 @@ fn load_keys_from_pem<P: AsRef<Path>>(
     Ok((rsa_key, x509))
 }
 
-fn load_tls_config(config: &Config) -> anyhow::Result<SslAcceptorBuilder> {
-    let (private_key, cert) = load_keys_from_pem(&config.tls.private_key, &config.tls.certificate)?;
+fn load_tls_config(tls: &Tls) -> anyhow::Result<SslAcceptorBuilder> {
+    let (private_key, cert) = load_keys_from_pem(&tls.private_key, &tls.certificate)?;
     let mut tls = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
     tls.set_private_key(&private_key)?;
-    tls.set_certificate_chain_file(&cert)?;
+    tls.set_certificate(&cert)?;
     Ok(tls)
 }
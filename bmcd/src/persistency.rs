@@ -17,6 +17,7 @@ use std::hash::Hasher;
 pub mod app_persistency;
 pub mod binary_persistency;
 pub mod error;
+pub mod migration;
 
 #[inline]
 fn default_hash<T: Hash>(item: T) -> u64 {
@@ -12,24 +12,34 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 use std::future;
-use std::io::Empty;
+use std::io::{Empty, ErrorKind};
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
-use super::binary_persistency::PersistencyStore;
+use super::binary_persistency::{PersistencyStore, StoreReport, BINARY_VERSION};
 use anyhow::Context;
 use futures::future::Either;
 use tokio::fs::{File, OpenOptions};
-use tokio::time::sleep_until;
+use tokio::time::{sleep_until, Instant};
 use tracing::warn;
-const BIN_DATA: &str = "/var/lib/bmcd/bmcd.bin";
+pub const BIN_DATA: &str = "/var/lib/bmcd/bmcd.bin";
+/// extension of a generation of the store that is being written.
+const PENDING_EXTENSION: &str = "new";
+/// extension of the previous generation of the store.
+const BACKUP_EXTENSION: &str = "bak";
+/// extension of a store restored from a configuration archive. It replaces the
+/// store on the next start, see [`staged_restore`].
+const RESTORE_EXTENSION: &str = "restore";
+/// minimum time between two attempts to write the store after a failure.
+const WRITE_RETRY_DELAY: Duration = Duration::from_secs(5);
 
 #[derive(Debug)]
 enum MonitorEvent {
     StoreChange,
     PersistencyWritten,
+    WriteFailed,
 }
 
 /// Builder to aid in configuring and setting up a [`ApplicationPersistency`], a
@@ -79,29 +89,52 @@ struct MonitorContext {
 }
 
 impl MonitorContext {
+    /// Writes the store crash-safe to the file-system. The data is written
+    /// and synced to a pending file first, which then atomically replaces the
+    /// store. The previous generation of the store is kept as backup. Each
+    /// generation carries a checksum, so that a torn write can be detected and
+    /// recovered from on the next start, see [`recover_store`].
     pub async fn commit_to_file(&self) -> anyhow::Result<MonitorEvent> {
         tracing::debug!("commiting persistency to disk");
         let Some(ref file) = self.file else {
             return Ok(MonitorEvent::PersistencyWritten);
         };
+        #[cfg(feature = "fault-injection")]
+        crate::fault_injection::intercept(crate::fault_injection::Site::Storage, "commit_to_file")
+            .await?;
 
-        let mut new = file.clone();
-        new.set_extension("new");
-
+        let pending_path = sibling(file, PENDING_EXTENSION);
         let pending = OpenOptions::new()
             .create(true)
             .truncate(true)
             .write(true)
-            .open(&new)
-            .await?;
-        self.inner.write(pending.into_std().await).await?;
+            .open(&pending_path)
+            .await?
+            .into_std()
+            .await;
+        self.inner.write(pending.try_clone()?).await?;
+        pending.sync_all()?;
+
+        let backup = sibling(file, BACKUP_EXTENSION);
+        let _ = tokio::fs::remove_file(&backup).await;
+        if let Err(e) = tokio::fs::hard_link(file, &backup).await {
+            if e.kind() != ErrorKind::NotFound {
+                tracing::warn!("could not backup persistency: {}", e);
+            }
+        }
+
+        tokio::fs::rename(&pending_path, &file)
+            .await
+            .with_context(|| {
+                format!(
+                    "error writing persistency binary. backup available at: {}",
+                    pending_path.to_string_lossy()
+                )
+            })?;
 
-        tokio::fs::rename(&new, &file).await.with_context(|| {
-            format!(
-                "error writing persistency binary. backup available at: {}",
-                new.to_string_lossy()
-            )
-        })?;
+        if let Some(parent) = file.parent() {
+            File::open(parent).await?.sync_all().await?;
+        }
 
         Ok(MonitorEvent::PersistencyWritten)
     }
@@ -109,11 +142,7 @@ impl MonitorContext {
     pub async fn sync_all(&self) -> anyhow::Result<()> {
         if self.inner.is_dirty() {
             self.commit_to_file().await?;
-            if let Some(f) = &self.file {
-                let file = File::open(&f).await?;
-                file.sync_all().await?;
-                tracing::info!("persistency synced");
-            }
+            tracing::info!("persistency synced");
         }
         Ok(())
     }
@@ -145,6 +174,9 @@ impl ApplicationPersistency {
             tokio::fs::create_dir_all(&parent).await?;
         }
 
+        apply_staged_restore(&path).await;
+        recover_store(&path).await;
+
         let can_write = !std::fs::metadata(&path)
             .map(|m| m.permissions().readonly())
             .unwrap_or_default();
@@ -174,6 +206,10 @@ impl ApplicationPersistency {
             inner,
         });
 
+        if can_write && context.inner.is_dirty() {
+            context.commit_to_file().await?;
+        }
+
         if can_write {
             let clone = context.clone();
             tokio::spawn(async move {
@@ -193,12 +229,18 @@ impl ApplicationPersistency {
         Ok(Self { context })
     }
 
+    /// Immediately writes pending changes to the file-system, instead of
+    /// waiting for the write timeout to expire.
+    pub async fn sync_all(&self) -> anyhow::Result<()> {
+        self.context.sync_all().await
+    }
+
     async fn filesystem_writer(
         write_timeout: Duration,
         context: Arc<MonitorContext>,
     ) -> anyhow::Result<()> {
         let mut watcher = context.inner.get_watcher().await;
-        let mut write_filesystem = Either::Left(future::pending());
+        let mut write_filesystem = Either::Left(future::pending::<anyhow::Result<MonitorEvent>>());
 
         loop {
             // Both items yield different `Result` types. Therefore use the
@@ -208,12 +250,16 @@ impl ApplicationPersistency {
                     result?;
                     MonitorEvent::StoreChange
                 },
-                result = write_filesystem => result?,
+                result = write_filesystem => result.unwrap_or_else(|e| {
+                    tracing::error!("{:#}", e);
+                    MonitorEvent::WriteFailed
+                }),
             };
 
-            let new_future = match event {
+            let new_deadline = match event {
                 MonitorEvent::PersistencyWritten => {
-                    Either::Left(future::pending::<anyhow::Result<MonitorEvent>>())
+                    write_filesystem = Either::Left(future::pending());
+                    continue;
                 }
                 MonitorEvent::StoreChange => {
                     // When there is a change in the key/value store. reload the
@@ -224,20 +270,164 @@ impl ApplicationPersistency {
                         .deref()
                         .checked_add(write_timeout)
                         .ok_or(anyhow::anyhow!("time structure internal error"))?;
-
-                    let clone = context.clone();
-                    Either::Right(async move {
-                        if !write_timeout.is_zero() {
-                            sleep_until(new_deadline).await;
-                        }
-                        clone.commit_to_file().await
-                    })
+                    Some(new_deadline).filter(|_| !write_timeout.is_zero())
+                }
+                // the store is still dirty, try again later
+                MonitorEvent::WriteFailed => {
+                    Some(Instant::now() + write_timeout.max(WRITE_RETRY_DELAY))
                 }
             };
 
-            write_filesystem = new_future;
+            let clone = context.clone();
+            write_filesystem = Either::Right(async move {
+                if let Some(deadline) = new_deadline {
+                    sleep_until(deadline).await;
+                }
+                clone.commit_to_file().await
+            });
+        }
+    }
+}
+
+fn sibling(path: &Path, extension: &str) -> PathBuf {
+    let mut sibling = path.to_path_buf();
+    sibling.set_extension(extension);
+    sibling
+}
+
+/// Path at which a store is staged to replace the store at `path`. Writing the
+/// store directly would race with the writer of the running daemon, instead
+/// the staged store is swapped in before the store is loaded on the next
+/// start.
+pub fn staged_restore(path: &Path) -> PathBuf {
+    sibling(path, RESTORE_EXTENSION)
+}
+
+/// Swaps in a store staged by [`staged_restore`]. A staged store that does not
+/// load is discarded.
+async fn apply_staged_restore(path: &Path) {
+    let staged = staged_restore(path);
+    match inspect_file(&staged) {
+        None => (),
+        Some(Err(e)) => {
+            tracing::error!("discarding restored persistency: {}", e);
+            let _ = tokio::fs::remove_file(&staged).await;
+        }
+        Some(Ok(_)) => match tokio::fs::rename(&staged, path).await {
+            Ok(()) => tracing::info!("restored persistency from {}", staged.to_string_lossy()),
+            Err(e) => tracing::error!("could not restore persistency: {}", e),
+        },
+    }
+}
+
+fn inspect_file(path: &Path) -> Option<Result<StoreReport, String>> {
+    let source = std::fs::File::open(path).ok()?;
+    Some(PersistencyStore::inspect(source).map_err(|e| e.to_string()))
+}
+
+/// Returns the first valid generation of the store that is left behind by an
+/// interrupted write, newest first.
+fn find_valid_generation(path: &Path) -> Option<PathBuf> {
+    [PENDING_EXTENSION, BACKUP_EXTENSION]
+        .into_iter()
+        .map(|ext| sibling(path, ext))
+        .find(|candidate| matches!(inspect_file(candidate), Some(Ok(_))))
+}
+
+/// Makes sure `path` contains a valid store when there is one available. When
+/// the store is missing or corrupt, the newest valid generation takes its
+/// place.
+async fn recover_store(path: &Path) {
+    let error = match inspect_file(path) {
+        Some(Ok(_)) => return,
+        Some(Err(e)) => Some(e),
+        None => None,
+    };
+
+    if let Some(e) = &error {
+        preserve_corrupt_store(path, e).await;
+    }
+
+    let Some(generation) = find_valid_generation(path) else {
+        return;
+    };
+
+    match tokio::fs::copy(&generation, path).await {
+        Ok(_) => tracing::warn!(
+            "recovered persistency from {}",
+            generation.to_string_lossy()
+        ),
+        Err(e) => tracing::error!("could not recover persistency: {}", e),
+    }
+}
+
+/// Keeps a copy of a store that could not be loaded, so that it is not lost when
+/// the store gets written with default values.
+async fn preserve_corrupt_store(path: &Path, error: impl std::fmt::Display) {
+    let corrupt = sibling(path, "corrupt");
+    tracing::error!(
+        "{} is corrupt ({}). A copy is kept at {}, run `bmcd --check-store` to repair",
+        path.to_string_lossy(),
+        error,
+        corrupt.to_string_lossy()
+    );
+    if let Err(e) = tokio::fs::copy(path, &corrupt).await {
+        tracing::error!("could not copy {}: {}", corrupt.to_string_lossy(), e);
+    }
+}
+
+/// Outcome of [`check_store`].
+#[derive(Debug)]
+pub enum StoreCheck {
+    /// There is no store on the file-system yet.
+    Missing,
+    Healthy(StoreReport),
+    /// The store was written in an older format and is upgraded in place.
+    Migrated {
+        from: u32,
+        report: StoreReport,
+    },
+    /// The store was corrupt and replaced by its backup.
+    RestoredBackup(StoreReport),
+    /// The store was corrupt and no valid backup exists. The store is moved
+    /// aside, bmcd starts with defaults on the next start.
+    Reset(String),
+}
+
+/// Validates the store at `path` and repairs it when possible.
+pub async fn check_store(path: &Path) -> anyhow::Result<StoreCheck> {
+    let Ok(mut source) = std::fs::File::open(path) else {
+        return Ok(StoreCheck::Missing);
+    };
+
+    let error = match PersistencyStore::inspect(&mut source) {
+        Ok(report) if report.version == BINARY_VERSION => return Ok(StoreCheck::Healthy(report)),
+        Ok(report) => {
+            let from = report.version;
+            rewrite_store(path, source).await?;
+            let report = PersistencyStore::inspect(std::fs::File::open(path)?)?;
+            return Ok(StoreCheck::Migrated { from, report });
         }
+        Err(e) => e.to_string(),
+    };
+
+    if let Some(generation) = find_valid_generation(path) {
+        rewrite_store(path, std::fs::File::open(generation)?).await?;
+        let report = PersistencyStore::inspect(std::fs::File::open(path)?)?;
+        return Ok(StoreCheck::RestoredBackup(report));
     }
+
+    tokio::fs::rename(path, sibling(path, "corrupt")).await?;
+    Ok(StoreCheck::Reset(error))
+}
+
+/// Writes the contents of `source` to `path` in the current binary format.
+async fn rewrite_store(path: &Path, source: std::fs::File) -> anyhow::Result<()> {
+    let store = PersistencyStore::new([], source)?;
+    let new = sibling(path, PENDING_EXTENSION);
+    store.write(std::fs::File::create(&new)?).await?;
+    tokio::fs::rename(&new, path).await?;
+    Ok(())
 }
 
 impl Deref for ApplicationPersistency {
@@ -292,6 +482,57 @@ mod tests {
         });
     }
 
+    #[tokio::test]
+    async fn swap_in_staged_restore() {
+        let tmp_dir = TempDir::new("persistency_test5").unwrap();
+        let bin_file = tmp_dir.path().join("bmcd.bin");
+        let keys_with_default = [("test", bincode::serialize(&123u128).unwrap())];
+
+        let persistency = ApplicationPersistency::new(keys_with_default.clone(), &bin_file, None)
+            .await
+            .unwrap();
+        persistency.set("test", &1u128).await;
+        persistency.sync_all().await.unwrap();
+        std::fs::copy(&bin_file, staged_restore(&bin_file)).unwrap();
+        persistency.set("test", &2u128).await;
+        persistency.sync_all().await.unwrap();
+        drop(persistency);
+
+        let persistency = ApplicationPersistency::new(keys_with_default, &bin_file, None)
+            .await
+            .unwrap();
+        assert_eq!(persistency.get::<u128>("test").await, 1u128);
+        assert!(!staged_restore(&bin_file).exists());
+    }
+
+    #[tokio::test]
+    async fn recover_previous_generation() {
+        let tmp_dir = TempDir::new("persistency_test4").unwrap();
+        let bin_file = tmp_dir.path().join("bmcd.bin");
+        let keys_with_default = [("test", bincode::serialize(&123u128).unwrap())];
+
+        let persistency = ApplicationPersistency::new(keys_with_default.clone(), &bin_file, None)
+            .await
+            .unwrap();
+        persistency.set("test", &1u128).await;
+        persistency.sync_all().await.unwrap();
+        persistency.set("test", &2u128).await;
+        persistency.sync_all().await.unwrap();
+        drop(persistency);
+
+        // simulate a torn write of the latest generation
+        let mut content = std::fs::read(&bin_file).unwrap();
+        let last = content.len() - 1;
+        content[last] ^= 0xff;
+        std::fs::write(&bin_file, content).unwrap();
+
+        let persistency = ApplicationPersistency::new(keys_with_default, &bin_file, None)
+            .await
+            .unwrap();
+        assert_eq!(persistency.get::<u128>("test").await, 1u128);
+        assert!(tmp_dir.path().join("bmcd.corrupt").exists());
+    }
+
     #[tokio::test]
     async fn persistency_monitor_test() {
         tokio::task::spawn_blocking(|| {
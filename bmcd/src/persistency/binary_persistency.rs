@@ -26,9 +26,17 @@ use tokio::{
 };
 
 use super::error::PersistencyError;
+use super::migration::{migrate, Data};
 use crate::persistency::default_hash;
 
-const BINARY_VERSION: u32 = 1;
+/// version 1:
+///
+/// * initial format
+///
+/// version 2:
+///
+/// * crc32 checksum of the data in the header
+pub const BINARY_VERSION: u32 = 2;
 const BINARY_MAGIC: &[u8; 7] = b"TMAPPDB";
 const LEB_SIZE: u32 = 252 * 1024;
 
@@ -38,6 +46,25 @@ struct PersistencyHeader {
     pub magic: [u8; 7],
     pub data_size: u32,
     pub data_offset: u16,
+    pub data_crc: u32,
+}
+
+/// Header as written by version 1 of the binary format.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+struct PersistencyHeaderV1 {
+    pub version: u32,
+    pub magic: [u8; 7],
+    pub data_size: u32,
+    pub data_offset: u16,
+}
+
+/// Result of a successful inspection of a persistency source, see
+/// [`PersistencyStore::inspect`].
+#[derive(Debug)]
+pub struct StoreReport {
+    pub version: u32,
+    pub entries: usize,
+    pub data_size: u32,
 }
 
 impl<'a> PersistencyHeader {
@@ -47,6 +74,7 @@ impl<'a> PersistencyHeader {
             magic: *BINARY_MAGIC,
             data_size: 0,
             data_offset: 0,
+            data_crc: 0,
         };
 
         header.data_offset = u16::try_from(
@@ -61,6 +89,34 @@ impl<'a> PersistencyHeader {
         bincode::serialized_size(&PersistencyHeader::new()?)
             .map_err(|e| PersistencyError::serialization("header size", e))
     }
+
+    /// Reads the header of any supported version of the binary format.
+    fn read(mut source: impl Read + Seek) -> Result<Self, PersistencyError<'a>> {
+        let (version, magic): (u32, [u8; 7]) = bincode::deserialize_from(&mut source)
+            .map_err(|e| PersistencyError::serialization("header deserialization", e))?;
+
+        if &magic != BINARY_MAGIC {
+            return Err(PersistencyError::UnknownFormat);
+        }
+
+        source.rewind()?;
+        match version {
+            1 => {
+                let header: PersistencyHeaderV1 = bincode::deserialize_from(&mut source)
+                    .map_err(|e| PersistencyError::serialization("header deserialization", e))?;
+                Ok(PersistencyHeader {
+                    version: header.version,
+                    magic: header.magic,
+                    data_size: header.data_size,
+                    data_offset: header.data_offset,
+                    data_crc: 0,
+                })
+            }
+            BINARY_VERSION => bincode::deserialize_from(&mut source)
+                .map_err(|e| PersistencyError::serialization("header deserialization", e)),
+            v => Err(PersistencyError::UnsupportedVersion(v)),
+        }
+    }
 }
 
 type Context = (HashMap<u64, Vec<u8>>, Option<Sender<Instant>>);
@@ -73,6 +129,7 @@ type Context = (HashMap<u64, Vec<u8>>, Option<Sender<Instant>>);
 #[derive(Debug)]
 pub struct PersistencyStore {
     cache: RwLock<Context>,
+    defaults: HashMap<u64, Vec<u8>>,
     dirty: AtomicBool,
 }
 
@@ -83,61 +140,93 @@ impl<'a> PersistencyStore {
         S: Read + Seek + 'a,
     {
         let iter = keys.into_iter().map(|(k, v)| (default_hash(k), v));
-        let mut cache = HashMap::from_iter(iter);
-
-        if let Err(e) = Self::try_deserialize_source(source, &mut cache) {
-            tracing::error!("coninue-ing without loading persistency: {}", e);
-        }
+        let defaults = HashMap::from_iter(iter);
+        let mut cache = defaults.clone();
+
+        // Data of an older format version is migrated in memory. Flag the
+        // store as dirty so that it gets written back in the current format.
+        let migrated = match Self::try_deserialize_source(source, &mut cache) {
+            Ok(version) => version.is_some_and(|v| v != BINARY_VERSION),
+            Err(e) => {
+                tracing::error!("coninue-ing without loading persistency: {}", e);
+                false
+            }
+        };
 
         Ok(Self {
             cache: RwLock::new((cache, None)),
-            dirty: AtomicBool::new(false),
+            defaults,
+            dirty: AtomicBool::new(migrated),
+        })
+    }
+
+    /// Validates the given source without loading it. Returns an error when the
+    /// source is corrupt or written in an unsupported format.
+    pub fn inspect(source: impl Read + Seek + 'a) -> Result<StoreReport, PersistencyError<'a>> {
+        let mut data = Data::new();
+        let version = Self::try_deserialize_source(source, &mut data)?;
+        Ok(StoreReport {
+            version: version.unwrap_or(BINARY_VERSION),
+            entries: data.len(),
+            data_size: bincode::serialized_size(&data)
+                .map_err(|e| PersistencyError::serialization("data size", e))?
+                as u32,
         })
     }
 
+    /// Loads the data of `source` into `destination`. Returns the version of
+    /// the binary format the source was written in, or `None` in the case of
+    /// an empty source.
     fn try_deserialize_source(
         mut source: impl Read + Seek + 'a,
         destination: &mut HashMap<u64, Vec<u8>>,
-    ) -> Result<(), PersistencyError<'a>> {
+    ) -> Result<Option<u32>, PersistencyError<'a>> {
         source.rewind()?;
         let size = source.seek(SeekFrom::End(0))?;
         let header_size = PersistencyHeader::serialized_size()?;
         match size {
             x if x >= header_size => {
                 source.rewind()?;
-                destination.extend(Self::try_load_data(source)?);
-                Ok(())
+                let (version, data) = Self::try_load_data(source)?;
+                destination.extend(data);
+                Ok(Some(version))
             }
             0 => {
                 tracing::info!("new storage");
-                Ok(())
+                Ok(None)
             }
             _ => Err(PersistencyError::UnknownFormat),
         }
     }
 
-    fn try_load_data(
-        mut source: impl Read + Seek,
-    ) -> Result<impl IntoIterator<Item = (u64, Vec<u8>)>, PersistencyError<'a>> {
-        let header: PersistencyHeader = bincode::deserialize_from(&mut source)
-            .map_err(|e| PersistencyError::serialization("header deserialization", e))?;
+    fn try_load_data(mut source: impl Read + Seek) -> Result<(u32, Data), PersistencyError<'a>> {
+        let header = PersistencyHeader::read(&mut source)?;
 
-        if &header.magic != BINARY_MAGIC {
-            return Err(PersistencyError::UnknownFormat);
+        if header.data_size > LEB_SIZE {
+            tracing::warn!("internal persistency grew over the size of one logical erase block");
         }
 
-        if header.version != BINARY_VERSION {
-            return Err(PersistencyError::UnsupportedVersion(header.version));
+        // no checksum covers the header, check the size before allocating
+        let size = source.seek(SeekFrom::End(0))?;
+        if u64::from(header.data_offset) + u64::from(header.data_size) > size {
+            return Err(PersistencyError::Truncated(header.data_size));
         }
 
-        if header.data_size > LEB_SIZE {
-            tracing::warn!("internal persistency grew over the size of one logical erase block");
+        source.seek(io::SeekFrom::Start(header.data_offset.into()))?;
+        let mut data: Data = if header.version >= 2 {
+            let mut bytes = vec![0u8; header.data_size as usize];
+            source.read_exact(&mut bytes)?;
+            if crc32fast::hash(&bytes) != header.data_crc {
+                return Err(PersistencyError::ChecksumMismatch);
+            }
+            bincode::deserialize(&bytes)
+        } else {
+            bincode::deserialize_from(source)
         }
+        .map_err(|e| PersistencyError::serialization("cache load", e))?;
 
-        source.seek(io::SeekFrom::Start(header.data_offset.into()))?;
-        let data: HashMap<u64, Vec<u8>> = bincode::deserialize_from(source)
-            .map_err(|e| PersistencyError::serialization("cache load", e))?;
-        Ok(data)
+        migrate(header.version, BINARY_VERSION, &mut data)?;
+        Ok((header.version, data))
     }
 
     pub async fn get_watcher(&self) -> Receiver<Instant> {
@@ -157,6 +246,7 @@ impl<'a> PersistencyStore {
         let mut header = PersistencyHeader::new()?;
         header.data_size =
             u32::try_from(data.len()).expect("persistency size > 4.2GB not supported");
+        header.data_crc = crc32fast::hash(&data);
 
         let header_bytes = bincode::serialize(&header)
             .map_err(|e| PersistencyError::SerializationError("header serialization".into(), e))?;
@@ -214,19 +304,65 @@ impl<'a> PersistencyStore {
         let previous = cache.0.insert(k, encoded.clone());
 
         if previous.as_ref() != Some(&encoded) {
-            self.dirty.store(true, Ordering::Relaxed);
-
-            if let Some(observer) = cache.1.as_ref() {
-                if observer.send(Instant::now()).is_err() {
-                    tracing::info!("persistency watcher dropped");
-                    cache.1 = None;
-                }
-            }
+            self.mark_dirty(&mut cache);
         }
 
         Ok(())
     }
 
+    /// Reads, modifies and writes back the value of `key` under one lock, so
+    /// that concurrent updates of the same key are not lost. Nothing is
+    /// written when `update` fails.
+    pub async fn update<T, R, E>(
+        &self,
+        key: &str,
+        update: impl FnOnce(&mut T) -> Result<R, E>,
+    ) -> Result<R, E>
+    where
+        for<'b> T: serde::Deserialize<'b> + serde::Serialize,
+    {
+        let mut cache = self.cache.write().await;
+
+        let k = default_hash(key);
+        let bytes = cache
+            .0
+            .get(&k)
+            .unwrap_or_else(|| panic!("unknown persistency key {}", key));
+        let mut value: T = bincode::deserialize(bytes)
+            .unwrap_or_else(|e| panic!("fatal deserialization error of {}: {}", key, e));
+        let result = update(&mut value)?;
+
+        let encoded = bincode::serialize(&value)
+            .unwrap_or_else(|e| panic!("fatal serialization error of {}: {}", key, e));
+        let previous = cache.0.insert(k, encoded.clone());
+        if previous.as_ref() != Some(&encoded) {
+            self.mark_dirty(&mut cache);
+        }
+
+        Ok(result)
+    }
+
+    /// Sets all registered keys back to the default value they were
+    /// registered with.
+    pub async fn reset_to_defaults(&self) {
+        let mut cache = self.cache.write().await;
+        if cache.0 != self.defaults {
+            cache.0.clone_from(&self.defaults);
+            self.mark_dirty(&mut cache);
+        }
+    }
+
+    fn mark_dirty(&self, cache: &mut Context) {
+        self.dirty.store(true, Ordering::Relaxed);
+
+        if let Some(observer) = cache.1.as_ref() {
+            if observer.send(Instant::now()).is_err() {
+                tracing::info!("persistency watcher dropped");
+                cache.1 = None;
+            }
+        }
+    }
+
     pub fn is_dirty(&self) -> bool {
         self.dirty.load(Ordering::Relaxed)
     }
@@ -284,14 +420,113 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_checksum_mismatch() {
+        let mut header = PersistencyHeader::new().unwrap();
+        let data = bincode::serialize(&Data::new()).unwrap();
+        header.data_size = data.len() as u32;
+        header.data_crc = crc32fast::hash(&data) ^ 1;
+        let mut vec = bincode::serialize(&header).unwrap();
+        vec.extend_from_slice(&data);
+        assert!(matches!(
+            PersistencyStore::inspect(Cursor::new(vec)),
+            Err(PersistencyError::ChecksumMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_data_size_beyond_file() {
+        let mut header = PersistencyHeader::new().unwrap();
+        let data = bincode::serialize(&Data::new()).unwrap();
+        header.data_size = u32::MAX;
+        header.data_crc = crc32fast::hash(&data);
+        let mut vec = bincode::serialize(&header).unwrap();
+        vec.extend_from_slice(&data);
+        assert!(matches!(
+            PersistencyStore::inspect(Cursor::new(vec)),
+            Err(PersistencyError::Truncated(u32::MAX))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_migrate_v1() {
+        #[derive(serde::Serialize)]
+        struct HeaderV1 {
+            version: u32,
+            magic: [u8; 7],
+            data_size: u32,
+            data_offset: u16,
+        }
+
+        let mut data = Data::new();
+        data.insert(default_hash("test"), bincode::serialize(&222u128).unwrap());
+        let data = bincode::serialize(&data).unwrap();
+        let header = HeaderV1 {
+            version: 1,
+            magic: *BINARY_MAGIC,
+            data_size: data.len() as u32,
+            data_offset: 17,
+        };
+        let mut vec = bincode::serialize(&header).unwrap();
+        vec.extend_from_slice(&data);
+
+        let report = PersistencyStore::inspect(Cursor::new(vec.clone())).unwrap();
+        assert_eq!(report.version, 1);
+        assert_eq!(report.entries, 1);
+
+        let store = PersistencyStore::new(
+            [("test", bincode::serialize(&123u128).unwrap())],
+            Cursor::new(vec),
+        )
+        .unwrap();
+        assert!(store.is_dirty());
+        assert_eq!(store.get::<u128>("test").await, 222u128);
+
+        let mut cursor = Cursor::new(Vec::new());
+        store.write(&mut cursor).await.unwrap();
+        let report = PersistencyStore::inspect(cursor).unwrap();
+        assert_eq!(report.version, BINARY_VERSION);
+    }
+
+    #[tokio::test]
+    async fn test_update() {
+        let store = PersistencyStore::new(
+            [("test", bincode::serialize(&1u128).unwrap())],
+            std::io::empty(),
+        )
+        .unwrap();
+
+        let result: Result<(), ()> = store
+            .update("test", |value: &mut u128| {
+                *value = 5;
+                Err(())
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(store.get::<u128>("test").await, 1u128);
+        assert!(!store.is_dirty());
+
+        let doubled = store
+            .update("test", |value: &mut u128| {
+                *value *= 2;
+                Ok::<_, ()>(*value)
+            })
+            .await;
+        assert_eq!(doubled, Ok(2));
+        assert_eq!(store.get::<u128>("test").await, 2u128);
+        assert!(store.is_dirty());
+    }
+
     #[tokio::test]
     async fn test_write_data() {
         let mut data = HashMap::<u64, Vec<u8>>::new();
         let mut header = PersistencyHeader::new().unwrap();
         data.insert(default_hash("test"), bincode::serialize(&222u128).unwrap());
-        header.data_size = bincode::serialized_size(&data).unwrap() as u32;
+        let mut data = bincode::serialize(&data).unwrap();
+        header.data_size = data.len() as u32;
+        header.data_crc = crc32fast::hash(&data);
         let mut vec = bincode::serialize(&header).unwrap();
-        vec.append(&mut bincode::serialize(&data).unwrap());
+        vec.append(&mut data);
 
         let mut cursor = Cursor::new(vec);
         let store = PersistencyStore::new(
@@ -336,4 +571,22 @@ mod tests {
         assert!(store.is_dirty());
         assert_eq!(store.get::<u128>("test").await, 333u128);
     }
+
+    #[tokio::test]
+    async fn reset_to_defaults_test() {
+        let store = PersistencyStore::new(
+            [("test", bincode::serialize(&123u128).unwrap())],
+            Cursor::new(Vec::new()),
+        )
+        .unwrap();
+
+        store.reset_to_defaults().await;
+        assert!(!store.is_dirty());
+
+        store.set("test", 333u128).await;
+        store.write(Cursor::new(Vec::new())).await.unwrap();
+        store.reset_to_defaults().await;
+        assert!(store.is_dirty());
+        assert_eq!(store.get::<u128>("test").await, 123u128);
+    }
 }
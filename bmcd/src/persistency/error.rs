@@ -24,6 +24,10 @@ pub enum PersistencyError<'a> {
     SerializationError(Cow<'a, str>, bincode::Error),
     #[error("IO Err:")]
     IoError(#[from] std::io::Error),
+    #[error("checksum of persistency data does not match")]
+    ChecksumMismatch,
+    #[error("persistency data of {0} bytes does not fit in the file")]
+    Truncated(u32),
     #[error("{0} is not registered in persistency storage")]
     UnknownKey(String),
 }
@@ -0,0 +1,87 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Upgrades persistency data written by older versions of bmcd to the current
+//! [`BINARY_VERSION`](super::binary_persistency::BINARY_VERSION).
+//!
+//! When the binary format changes, bump `BINARY_VERSION` and append a
+//! [`Migration`] to [`MIGRATIONS`] that converts the data of the previous
+//! version. Migrations are applied in order, so a store can be upgraded from
+//! any older version.
+use super::error::PersistencyError;
+use std::collections::HashMap;
+
+pub type Data = HashMap<u64, Vec<u8>>;
+
+pub struct Migration {
+    /// version of the data this migration accepts. The output of the migration
+    /// is of version `from + 1`.
+    pub from: u32,
+    pub description: &'static str,
+    pub apply: fn(&mut Data) -> Result<(), PersistencyError<'static>>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    from: 1,
+    description: "add checksum to header",
+    // only the header changed, which gets rewritten on the next write.
+    apply: |_| Ok(()),
+}];
+
+/// Applies all migrations required to bring `data` from `version` to
+/// `target`.
+pub fn migrate(
+    version: u32,
+    target: u32,
+    data: &mut Data,
+) -> Result<(), PersistencyError<'static>> {
+    if version > target {
+        return Err(PersistencyError::UnsupportedVersion(version));
+    }
+
+    for current in version..target {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|m| m.from == current)
+            .ok_or(PersistencyError::UnsupportedVersion(version))?;
+        tracing::info!(
+            "migrating persistency v{} to v{}: {}",
+            current,
+            current + 1,
+            migration.description
+        );
+        (migration.apply)(data)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migration_path() {
+        let mut data = Data::new();
+        assert!(migrate(1, 2, &mut data).is_ok());
+        assert!(migrate(2, 2, &mut data).is_ok());
+        assert!(matches!(
+            migrate(3, 2, &mut data),
+            Err(PersistencyError::UnsupportedVersion(3))
+        ));
+        assert!(matches!(
+            migrate(0, 2, &mut data),
+            Err(PersistencyError::UnsupportedVersion(0))
+        ));
+    }
+}
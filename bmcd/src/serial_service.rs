@@ -17,21 +17,38 @@ use crate::api::{
     get_node_param,
     into_legacy_response::{LegacyResponse, LegacyResult},
 };
+use crate::authentication::roles::role;
+use crate::hal::NodeId;
 use crate::serial_service::serial_websocket::run_websocket;
 use actix_web::{
+    get,
+    http::header::{
+        ContentDisposition, DispositionParam, DispositionType, HeaderName, HeaderValue,
+    },
     post, route,
     web::{self},
     HttpRequest, HttpResponse, Responder,
 };
 use bytes::BytesMut;
+use futures::StreamExt;
+use std::future::Future;
+/// Response header of the websocket handshake that tells whether the client
+/// may type on the console, `read-only` or `read-write`.
+pub const CONSOLE_ACCESS: &str = "x-console-access";
+
 type Query = web::Query<std::collections::HashMap<String, String>>;
 
+mod console_log;
+pub mod console_multicast;
 pub mod serial;
 pub mod serial_handler;
 mod serial_websocket;
+pub mod tcp_console;
 
 pub fn serial_config(cfg: &mut web::ServiceConfig) {
-    cfg.service(serial_status).service(handle_ws);
+    cfg.service(serial_status)
+        .service(serial_log)
+        .service(handle_ws);
 }
 
 #[post("/serial/status")]
@@ -39,6 +56,53 @@ async fn serial_status(serials: web::Data<SerialConnections>) -> impl Responder
     serde_json::to_string(&serials.get_state()).unwrap_or_else(|e| e.to_string())
 }
 
+/// Console output of a node as JSON lines, one record per line of output.
+/// `since` skips the lines before that sequence number, so a tool can poll
+/// for new lines.
+#[get("/serial/log")]
+async fn serial_log(serials: web::Data<SerialConnections>, query: Query) -> HttpResponse {
+    match read_log(&serials, &query) {
+        Ok((node, body)) => HttpResponse::Ok()
+            .content_type("application/x-ndjson")
+            .insert_header(ContentDisposition {
+                disposition: DispositionType::Attachment,
+                parameters: vec![DispositionParam::Filename(format!(
+                    "node{}-console.jsonl",
+                    node as u8 + 1
+                ))],
+            })
+            .body(body),
+        Err(e) => e.into(),
+    }
+}
+
+fn read_log(serials: &SerialConnections, query: &Query) -> LegacyResult<(NodeId, Vec<u8>)> {
+    let node = get_node_param(query)?;
+    Ok((node, console_log(serials, node, query)?))
+}
+
+/// JSON lines of the console output of `node`, from the `since` parameter of
+/// `query` on.
+pub fn console_log(
+    serials: &SerialConnections,
+    node: NodeId,
+    query: &Query,
+) -> LegacyResult<Vec<u8>> {
+    let since = match query.get("since") {
+        Some(since) => since
+            .parse()
+            .map_err(|_| LegacyResponse::bad_request("`since` is not a number"))?,
+        None => 0,
+    };
+
+    let mut body = Vec::new();
+    for line in serials[node].read_lines(since)? {
+        serde_json::to_writer(&mut body, &line).map_err(anyhow::Error::from)?;
+        body.push(b'\n');
+    }
+    Ok(body)
+}
+
 pub async fn legacy_serial_set_handler(
     serials: web::Data<SerialConnections>,
     query: Query,
@@ -101,10 +165,37 @@ async fn handle_ws(
     serials: web::Data<SerialConnections>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let node = get_node_param(&query)?;
-    let (res, session, msg_stream) = actix_ws::handle(&req, stream)?;
+    let read_only = !role(&req).can_write_console();
+    console_websocket(
+        &req,
+        stream,
+        &serials,
+        node,
+        read_only,
+        std::future::pending(),
+    )
+    .await
+}
+
+/// Connects a websocket to the console of `node` until either side closes it
+/// or `end` completes.
+pub async fn console_websocket(
+    req: &HttpRequest,
+    stream: web::Payload,
+    serials: &SerialConnections,
+    node: NodeId,
+    read_only: bool,
+    end: impl Future<Output = ()>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (mut res, session, msg_stream) = actix_ws::handle(req, stream)?;
+    let access = if read_only { "read-only" } else { "read-write" };
+    res.headers_mut().insert(
+        HeaderName::from_static(CONSOLE_ACCESS),
+        HeaderValue::from_static(access),
+    );
     match serials[node].open_channel() {
         Ok((stream, sink)) => {
-            run_websocket(session, msg_stream, stream, sink).await;
+            run_websocket(session, msg_stream, stream.take_until(end), sink, read_only).await;
             Ok(res)
         }
         Err(e) => Ok(HttpResponse::InternalServerError().body(e.to_string())),
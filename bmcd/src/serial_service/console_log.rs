@@ -0,0 +1,207 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Console output split into timestamped lines, for log analysis tools that
+//! want records rather than the raw bytes of the ring buffer.
+use chrono::{DateTime, Utc};
+use nix::time::{clock_gettime, ClockId};
+use serde::Serialize;
+use serde_with::{serde_as, DurationSecondsWithFrac};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Text of the lines that are kept per node, like the ring buffer.
+const LOG_SIZE: usize = 16 * 1024;
+/// Lines are broken at this length, so output without line endings still
+/// ends up in the log.
+const MAX_LINE: usize = 4096;
+
+/// When a line started.
+#[serde_as]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Stamp {
+    pub time: DateTime<Utc>,
+    /// `CLOCK_MONOTONIC` in seconds, which does not jump when the clock of
+    /// the BMC is set
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub monotonic: Duration,
+}
+
+impl Stamp {
+    pub fn now() -> Self {
+        let monotonic = clock_gettime(ClockId::CLOCK_MONOTONIC)
+            .map(Duration::from)
+            .unwrap_or_default();
+        Stamp {
+            time: Utc::now(),
+            monotonic,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConsoleLine {
+    /// counts the lines of a node since bmcd started
+    pub seq: u64,
+    #[serde(flatten)]
+    pub stamp: Stamp,
+    /// text without the line ending, invalid UTF-8 replaced
+    pub line: String,
+    /// the line did not end yet, e.g. a login prompt
+    pub partial: bool,
+}
+
+/// Splits console output into lines. `\r\n`, `\n` and a lone `\r` all end a
+/// line, also when they arrive in separate chunks.
+#[derive(Debug, Default)]
+pub struct LineFramer {
+    lines: VecDeque<ConsoleLine>,
+    size: usize,
+    next_seq: u64,
+    pending: Vec<u8>,
+    pending_since: Option<Stamp>,
+    after_cr: bool,
+}
+
+impl LineFramer {
+    pub fn push(&mut self, bytes: &[u8], now: Stamp) {
+        for &byte in bytes {
+            if self.after_cr {
+                match byte {
+                    b'\r' => continue,
+                    b'\n' => {
+                        self.after_cr = false;
+                        self.end_line(now);
+                        continue;
+                    }
+                    _ => {
+                        self.after_cr = false;
+                        self.end_line(now);
+                    }
+                }
+            }
+
+            match byte {
+                b'\r' => self.after_cr = true,
+                b'\n' => self.end_line(now),
+                _ => {
+                    self.pending_since.get_or_insert(now);
+                    self.pending.push(byte);
+                    if self.pending.len() >= MAX_LINE {
+                        self.end_line(now);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Lines with a sequence number from `since` on, followed by the line
+    /// that did not end yet.
+    pub fn lines(&self, since: u64) -> Vec<ConsoleLine> {
+        let mut lines: Vec<_> = self
+            .lines
+            .iter()
+            .filter(|line| line.seq >= since)
+            .cloned()
+            .collect();
+        if let Some(stamp) = self.pending_since {
+            if self.next_seq >= since {
+                lines.push(ConsoleLine {
+                    seq: self.next_seq,
+                    stamp,
+                    line: String::from_utf8_lossy(&self.pending).into_owned(),
+                    partial: true,
+                });
+            }
+        }
+        lines
+    }
+
+    fn end_line(&mut self, now: Stamp) {
+        let line = ConsoleLine {
+            seq: self.next_seq,
+            stamp: self.pending_since.take().unwrap_or(now),
+            line: String::from_utf8_lossy(&self.pending).into_owned(),
+            partial: false,
+        };
+        self.pending.clear();
+        self.next_seq += 1;
+
+        self.size += line.line.len();
+        self.lines.push_back(line);
+        while self.size > LOG_SIZE {
+            let Some(oldest) = self.lines.pop_front() else {
+                break;
+            };
+            self.size -= oldest.line.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(framer: &LineFramer) -> Vec<(String, bool)> {
+        framer
+            .lines(0)
+            .into_iter()
+            .map(|line| (line.line, line.partial))
+            .collect()
+    }
+
+    #[test]
+    fn normalizes_line_endings() {
+        let mut framer = LineFramer::default();
+        let now = Stamp::now();
+        framer.push(b"one\r", now);
+        framer.push(b"\ntwo\nthree\rfour\r\r\nlogin: ", now);
+        assert_eq!(
+            text(&framer),
+            vec![
+                ("one".to_string(), false),
+                ("two".to_string(), false),
+                ("three".to_string(), false),
+                ("four".to_string(), false),
+                ("login: ".to_string(), true),
+            ]
+        );
+        assert_eq!(framer.lines(3).len(), 2);
+    }
+
+    #[test]
+    fn stamps_the_start_of_a_line() {
+        let mut framer = LineFramer::default();
+        let first = Stamp::now();
+        let later = Stamp {
+            monotonic: first.monotonic + Duration::from_secs(1),
+            ..first
+        };
+        framer.push(b"boo", first);
+        framer.push(b"t\n", later);
+        assert_eq!(framer.lines(0)[0].stamp, first);
+    }
+
+    #[test]
+    fn keeps_recent_lines() {
+        let mut framer = LineFramer::default();
+        let line = [b'x'; 1000];
+        for _ in 0..40 {
+            framer.push(&line, Stamp::now());
+            framer.push(b"\n", Stamp::now());
+        }
+        let lines = framer.lines(0);
+        assert_eq!(lines.len(), LOG_SIZE / 1000);
+        assert_eq!(lines.last().unwrap().seq, 39);
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Mirrors the console output of the nodes to UDP multicast groups, one group
+//! per node. The datagrams carry the raw console bytes, so any number of log
+//! collectors can join a group, e.g. with
+//! `socat UDP4-RECV:5001,ip-add-membership=239.255.42.1:eth0 -`, without a
+//! connection to the BMC. Output is sent on a best-effort basis: datagrams are
+//! not acknowledged and nothing is resent.
+use super::serial::SerialConnections;
+use crate::app::batch::node_id;
+use crate::config::ConsoleMulticast;
+use crate::utils::interface_index;
+use anyhow::Context;
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+/// Largest payload of a datagram. It keeps the datagrams, headers included,
+/// within the MTU of an Ethernet network, so they are not fragmented.
+const MAX_PAYLOAD: usize = 1400;
+
+/// Opens a socket per configured group and mirrors the consoles in the
+/// background.
+pub async fn run_console_multicast(
+    config: ConsoleMulticast,
+    serials: Arc<SerialConnections>,
+) -> anyhow::Result<()> {
+    let board_nodes = serials.get_state().len();
+    for group in &config.groups {
+        let (number, node) = (group.node, node_id(group.node, board_nodes)?);
+        let socket = multicast_socket(group.group, config.ttl, config.interface.as_deref())
+            .with_context(|| format!("multicast group {}", group.group))?;
+        let (output, _input) = serials[node].open_channel()?;
+        tracing::info!(
+            "console of node {} mirrored to multicast group {}",
+            number,
+            group.group
+        );
+
+        let destination = group.group;
+        tokio::spawn(async move {
+            mirror(output, &socket).await;
+            tracing::warn!(
+                "console of node {} no longer mirrored to {}",
+                number,
+                destination
+            );
+        });
+    }
+    Ok(())
+}
+
+/// UDP socket that sends to `group`.
+fn multicast_socket(group: SocketAddr, ttl: u32, interface: Option<&str>) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(group), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_nonblocking(true)?;
+    match group.ip() {
+        IpAddr::V4(_) => {
+            socket.bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0).into())?;
+            socket.set_multicast_ttl_v4(ttl)?;
+            if let Some(interface) = interface {
+                socket.set_multicast_if_v4(&interface_v4(interface)?)?;
+            }
+        }
+        IpAddr::V6(_) => {
+            socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0).into())?;
+            socket.set_multicast_hops_v6(ttl)?;
+            if let Some(interface) = interface {
+                socket.set_multicast_if_v6(interface_index(interface)?)?;
+            }
+        }
+    }
+    socket.connect(&group.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// First IPv4 address of `interface`, which selects the interface of an IPv4
+/// multicast socket.
+fn interface_v4(interface: &str) -> io::Result<Ipv4Addr> {
+    if_addrs::get_if_addrs()?
+        .into_iter()
+        .filter(|i| i.name == interface)
+        .find_map(|i| match i.ip() {
+            IpAddr::V4(address) => Some(address),
+            IpAddr::V6(_) => None,
+        })
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("interface `{}` has no IPv4 address", interface),
+            )
+        })
+}
+
+/// Sends the console output to the group the socket is connected to until
+/// the console closes. Datagrams that cannot be sent are dropped.
+async fn mirror(output: impl Stream<Item = io::Result<Bytes>>, socket: &UdpSocket) {
+    tokio::pin!(output);
+    let mut failing = false;
+    while let Some(data) = output.next().await {
+        let Ok(data) = data else {
+            break;
+        };
+        for payload in data.chunks(MAX_PAYLOAD) {
+            match socket.send(payload).await {
+                Ok(_) => failing = false,
+                // log once per outage rather than for every datagram
+                Err(e) if !failing => {
+                    tracing::warn!("console multicast: {}", e);
+                    failing = true;
+                }
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+
+    #[tokio::test]
+    async fn mirrors_in_datagrams() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender
+            .connect(receiver.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let (output_tx, output) = mpsc::unbounded();
+        let task = tokio::spawn(async move { mirror(output, &sender).await });
+        output_tx
+            .unbounded_send(Ok(Bytes::from_static(b"login: ")))
+            .unwrap();
+        output_tx
+            .unbounded_send(Ok(Bytes::from(vec![b'x'; MAX_PAYLOAD + 10])))
+            .unwrap();
+        drop(output_tx);
+        task.await.unwrap();
+
+        let mut buffer = vec![0; 2 * MAX_PAYLOAD];
+        let mut sizes = Vec::new();
+        for _ in 0..3 {
+            sizes.push(receiver.recv(&mut buffer).await.unwrap());
+        }
+        assert_eq!(sizes, [7, MAX_PAYLOAD, 10]);
+    }
+
+    #[tokio::test]
+    async fn unknown_interface() {
+        let group = "239.255.42.1:5001".parse().unwrap();
+        assert!(multicast_socket(group, 1, Some("nonexistent0")).is_err());
+    }
+}
@@ -12,9 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 //! Handlers for UART connections to/from nodes
-use std::{ops::Index, path::PathBuf};
+use std::ops::Index;
 
 use super::serial_handler::Handler;
+use crate::hal::board_profile::BoardProfile;
 use crate::hal::NodeId;
 use crate::serial_service::serial_handler::HandlerState;
 use tokio_serial::{DataBits, Parity, StopBits};
@@ -27,8 +28,14 @@ pub struct SerialConnections {
 }
 
 impl SerialConnections {
-    pub fn new() -> Self {
-        let paths = get_serial_devices();
+    pub fn new(board: &BoardProfile) -> Self {
+        #[cfg(not(feature = "mock"))]
+        let paths = board
+            .node_uarts
+            .map(<[_]>::to_vec)
+            .unwrap_or_else(|| get_serial_devices().to_vec());
+        #[cfg(feature = "mock")]
+        let paths = crate::hal::mock::serial_devices()[..board.node_count].to_vec();
 
         let collection = paths.iter().enumerate().map(|(i, path)| {
             let mut handler = Handler::new(
@@ -55,6 +62,23 @@ impl SerialConnections {
     pub fn get_state(&self) -> Vec<HandlerState> {
         self.handlers.iter().map(Handler::get_state).collect()
     }
+
+    /// Fails when the UART of a node could not be opened.
+    pub fn ensure_running(&self) -> anyhow::Result<()> {
+        let stopped: Vec<_> = self
+            .get_state()
+            .iter()
+            .enumerate()
+            .filter(|(_, state)| !matches!(state, HandlerState::Running))
+            .map(|(i, _)| (i + 1).to_string())
+            .collect();
+        anyhow::ensure!(
+            stopped.is_empty(),
+            "UART of node {} is not running",
+            stopped.join(", ")
+        );
+        Ok(())
+    }
 }
 
 impl Index<NodeId> for SerialConnections {
@@ -62,8 +86,9 @@ impl Index<NodeId> for SerialConnections {
 
     fn index(&self, index: NodeId) -> &Self::Output {
         assert!(
-            self.handlers.len() == 4,
-            "Serial connections not initialized"
+            (index as usize) < self.handlers.len(),
+            "{} has no serial connection",
+            index
         );
         &self.handlers[index as usize]
     }
@@ -72,8 +97,9 @@ impl Index<NodeId> for SerialConnections {
 /// This is a quick and dirty way to detect which serial devices to load. At some point in time the
 /// mapping of ttySx devices to the uart ports changed to align them numerically with the nodes
 /// switched the numbering of
+#[cfg(not(feature = "mock"))]
 fn get_serial_devices() -> [&'static str; 4] {
-    let device_type = PathBuf::from("/sys/class/tty/ttyS3/device/of_node/device_type");
+    let device_type = std::path::PathBuf::from("/sys/class/tty/ttyS3/device/of_node/device_type");
     if matches!(std::fs::read_to_string(device_type), Ok(str) if str == "uart3") {
         ["/dev/ttyS2", "/dev/ttyS1", "/dev/ttyS4", "/dev/ttyS5"]
     } else {
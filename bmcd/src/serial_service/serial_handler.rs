@@ -13,11 +13,11 @@
 // limitations under the License.
 use bytes::Bytes;
 use circular_buffer::CircularBuffer;
-use futures::StreamExt;
+use futures::{future, StreamExt};
 use futures::{Sink, SinkExt, Stream};
 use serde::Serialize;
 use std::io::{self, ErrorKind, Write};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex as StdMutex};
 use thiserror::Error;
 use tokio::sync::{
     broadcast,
@@ -25,16 +25,22 @@ use tokio::sync::{
     Mutex,
 };
 use tokio_serial::{DataBits, Parity, SerialPortBuilderExt, StopBits};
-use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
 use tokio_util::codec::{BytesCodec, Decoder};
 use tokio_util::sync::PollSender;
 use tracing::trace;
 
+use super::console_log::{ConsoleLine, LineFramer, Stamp};
+use crate::utils::memory::{memory, Pool, Reservation};
 use crate::utils::{string_from_utf16, string_from_utf32};
 
 type RingBuffer = CircularBuffer<OUTPUT_BUF_SIZE, u8>;
 
 const OUTPUT_BUF_SIZE: usize = 16 * 1024;
+/// Chunks of console output that are queued per channel. A channel that falls
+/// further behind loses the oldest chunks rather than holding up the serial
+/// worker or the other channels.
+const CHANNEL_DEPTH: usize = 32;
 
 /// A [`Handler`] is an object that controls a single UART connection from the
 /// BMC to a predefined node. It has 2 functions to directly read and write the
@@ -51,7 +57,8 @@ pub struct Handler {
     stop_bits: StopBits,
     path: &'static str,
     ring_buffer: Arc<Mutex<Box<RingBuffer>>>,
-    worker_context: Option<(broadcast::Sender<Bytes>, mpsc::Sender<Bytes>)>,
+    line_log: Arc<StdMutex<LineFramer>>,
+    worker_context: Option<(broadcast::Sender<ConsoleChunk>, mpsc::Sender<Bytes>)>,
     writer: Option<WeakSender<Bytes>>,
 }
 
@@ -72,6 +79,7 @@ impl Handler {
             parity,
             stop_bits,
             ring_buffer: Arc::new(Mutex::new(RingBuffer::boxed())),
+            line_log: Arc::default(),
             worker_context: None,
             writer: None,
         }
@@ -87,12 +95,14 @@ impl Handler {
     }
 
     /// Opens a bi-directional asynchronous data-stream which can be used to
-    /// read and write bytes from and to the serial port.
+    /// read and write bytes from and to the serial port. Output that a slow
+    /// reader missed is skipped, see [`CHANNEL_DEPTH`]; the full history stays
+    /// available in the ring buffer.
     ///
     /// # Returns
     ///
     /// * `SerialError::NotStarted` when [`Self::run`] was not called
-    ///     successfully
+    ///   successfully
     pub fn open_channel(
         &self,
     ) -> Result<
@@ -108,8 +118,7 @@ impl Handler {
 
         let poll_sender = PollSender::new(write_sender.clone())
             .sink_map_err(|_| io::Error::from(ErrorKind::BrokenPipe));
-        let stream = BroadcastStream::new(read_sender.subscribe())
-            .map(|res| res.map_err(|e| io::Error::new(ErrorKind::BrokenPipe, e)));
+        let stream = skip_lagged(self.node, BroadcastStream::new(read_sender.subscribe()));
         Ok((stream, poll_sender))
     }
 
@@ -136,12 +145,23 @@ impl Handler {
         Ok(Bytes::copy_from_slice(rb.make_contiguous()))
     }
 
+    /// Returns the recent output as timestamped lines, see
+    /// [`LineFramer::lines`].
+    pub fn read_lines(&self, since: u64) -> Result<Vec<ConsoleLine>, SerialError> {
+        if self.worker_context.is_none() {
+            return Err(SerialError::NotStarted);
+        };
+
+        let log = self.line_log.lock().expect("line log lock poisoned");
+        Ok(log.lines(since))
+    }
+
     /// Serial write interface.
     ///
     /// # Returns
     ///
     /// * `SerialError::NotStarted` when [`Self::run`] was not called
-    ///     successfully.
+    ///   successfully.
     /// * `SerialError::Stopped` when the handler is not running anymore.
     ///
     pub async fn write(&self, bytes: Bytes) -> Result<(), SerialError> {
@@ -173,14 +193,16 @@ impl Handler {
             tracing::warn!("Unable to set exclusivity of port {}: {}", self.path, e);
         }
 
-        let (read_sender, _) = broadcast::channel::<Bytes>(8);
+        let (read_sender, _) = broadcast::channel::<ConsoleChunk>(CHANNEL_DEPTH);
         let (write_sender, mut write_receiver) = mpsc::channel::<Bytes>(8);
         self.worker_context = Some((read_sender.clone(), write_sender.clone()));
 
         let node = self.node;
         let buffer = self.ring_buffer.clone();
+        let line_log = self.line_log.clone();
         tokio::spawn(async move {
             tracing::info!("[node {}] serial started", &node);
+            let mut shedding = false;
             let (mut sink, mut stream) = BytesCodec::new().framed(port).split();
             loop {
                 tokio::select! {
@@ -211,12 +233,26 @@ impl Handler {
                             tracing::error!("Failed to write to buffer of node {}", node);
                             break;
                         };
+                        line_log
+                            .lock()
+                            .expect("line log lock poisoned")
+                            .push(&bytes, Stamp::now());
 
-                        if read_sender.receiver_count() > 0 {
-                            if let Err(e) = read_sender.send(bytes.into()) {
-                                tracing::error!("broadcast error: {:#}", e);
-                                break;
+                        if read_sender.receiver_count() == 0 {
+                            continue;
+                        }
+                        // output that does not fit is only kept in the ring buffer
+                        let Some(chunk) = ConsoleChunk::new(bytes.into()) else {
+                            if !shedding {
+                                tracing::warn!("console memory limit reached, skipping output of node {}", node);
+                                shedding = true;
                             }
+                            continue;
+                        };
+                        shedding = false;
+                        if let Err(e) = read_sender.send(chunk) {
+                            tracing::error!("broadcast error: {:#}", e);
+                            break;
                         }
                     },
                 }
@@ -229,6 +265,44 @@ impl Handler {
     }
 }
 
+/// Console output that is queued for the channels, accounted in the
+/// [`Pool::Console`] until every channel received it.
+#[derive(Debug, Clone)]
+struct ConsoleChunk {
+    bytes: Bytes,
+    _reservation: Arc<Reservation<'static>>,
+}
+
+impl ConsoleChunk {
+    fn new(bytes: Bytes) -> Option<Self> {
+        let reservation = memory().try_reserve(Pool::Console, bytes.len())?;
+        Some(ConsoleChunk {
+            bytes,
+            _reservation: Arc::new(reservation),
+        })
+    }
+}
+
+fn skip_lagged(
+    node: usize,
+    stream: BroadcastStream<ConsoleChunk>,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    stream.filter_map(move |res| {
+        let bytes = match res {
+            Ok(chunk) => Some(Ok(chunk.bytes)),
+            Err(BroadcastStreamRecvError::Lagged(chunks)) => {
+                tracing::warn!(
+                    "console reader of node {} fell behind, skipped {} chunks",
+                    node,
+                    chunks
+                );
+                None
+            }
+        };
+        future::ready(bytes)
+    })
+}
+
 #[derive(Error, Debug)]
 pub enum SerialError {
     #[error("serial worker not started")]
@@ -245,7 +319,7 @@ pub enum SerialError {
     IoError(#[from] std::io::Error),
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Serialize)]
 pub enum HandlerState {
     Initialized,
     Running,
@@ -258,3 +332,23 @@ pub enum Encoding {
     Utf16 { little_endian: bool },
     Utf32 { little_endian: bool },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lagging_reader_skips_output() {
+        let (sender, receiver) = broadcast::channel(2);
+        let stream = skip_lagged(1, BroadcastStream::new(receiver));
+        for chunk in ["a", "b", "c", "d"] {
+            sender
+                .send(ConsoleChunk::new(Bytes::from(chunk)).unwrap())
+                .unwrap();
+        }
+        drop(sender);
+
+        let received: Vec<_> = stream.map(Result::unwrap).collect().await;
+        assert_eq!(received, vec![Bytes::from("c"), Bytes::from("d")]);
+    }
+}
@@ -19,24 +19,30 @@ use futures::{Sink, SinkExt};
 use std::io;
 use std::time::{Duration, Instant};
 use tokio::pin;
-use tokio::time::interval;
+use tokio::time::{interval, timeout};
 use tokio_stream::StreamExt;
 
 /// How often heartbeat pings are sent
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 /// How long before lack of client response causes a timeout
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a send may wait for the client to drain the session queue. A
+/// client that stalls for longer is disconnected; console output that it
+/// misses in the meantime is skipped, see [`super::serial_handler::Handler::open_channel`].
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// This function is responsible for handling communication with a given
 /// client over a websocket. All data is send over the `bytes` type in the
 /// socket transport. A watchdog is running which monitors the heartbeat of the
 /// client. When no 'ping' response is seen from the client for more as
-/// [`CLIENT_TIMEOUT`] the server will close down the websocket.
+/// [`CLIENT_TIMEOUT`] the server will close down the websocket. A `read_only`
+/// client is disconnected when it sends console input.
 pub async fn run_websocket(
     mut session: actix_ws::Session,
     mut msg_stream: actix_ws::MessageStream,
     serial_stream: impl Stream<Item = io::Result<Bytes>>,
     serial_sink: impl Sink<bytes::Bytes, Error = io::Error>,
+    read_only: bool,
 ) {
     let mut last_heartbeat = Instant::now();
     let mut interval = interval(HEARTBEAT_INTERVAL);
@@ -59,7 +65,14 @@ pub async fn run_websocket(
             _ = tick => TaskResult::Tick,
         };
 
-        if let Err(e) = handle_task(task, &mut session, &mut last_heartbeat, &mut serial_sink).await
+        if let Err(e) = handle_task(
+            task,
+            &mut session,
+            &mut last_heartbeat,
+            &mut serial_sink,
+            read_only,
+        )
+        .await
         {
             break e;
         }
@@ -69,8 +82,11 @@ pub async fn run_websocket(
         tracing::error!("error flushing serial sink {}", e);
     }
 
-    if session.close(Some(close_reason)).await.is_err() {
-        tracing::warn!("could not close websocket session gracefully");
+    // a stalled client may not take the close frame either, dropping the
+    // session then ends the connection
+    match timeout(SEND_TIMEOUT, session.close(Some(close_reason))).await {
+        Ok(Ok(())) => {}
+        _ => tracing::warn!("could not close websocket session gracefully"),
     }
 }
 
@@ -79,10 +95,11 @@ async fn handle_task(
     session: &mut actix_ws::Session,
     last_heartbeat: &mut Instant,
     serial_sink: &mut (impl Sink<bytes::Bytes, Error = io::Error> + Unpin),
+    read_only: bool,
 ) -> Result<(), CloseReason> {
     match result {
         TaskResult::Command(msg) => {
-            message_stream_handler(last_heartbeat, session, msg, serial_sink).await
+            message_stream_handler(last_heartbeat, session, msg, serial_sink, read_only).await
         }
         TaskResult::Tick => verify_heartbeat(*last_heartbeat, session).await,
         TaskResult::Data(cmd) => handle_command(cmd, session).await,
@@ -108,7 +125,15 @@ async fn handle_command(
     session: &mut actix_ws::Session,
 ) -> Result<(), CloseReason> {
     let bytes = bytes.map_err(map_io_error)?;
-    session.binary(bytes).await.map_err(map_normal_close)
+    match timeout(SEND_TIMEOUT, session.binary(bytes)).await {
+        Ok(res) => res.map_err(map_normal_close),
+        Err(_) => Err(CloseReason {
+            code: CloseCode::Policy,
+            description: Some(format!(
+                "client did not read console output for {SEND_TIMEOUT:?}; disconnecting"
+            )),
+        }),
+    }
 }
 
 /// If no heartbeat ping/pong received recently, close the connection
@@ -136,6 +161,7 @@ async fn message_stream_handler(
     session: &mut actix_ws::Session,
     message: Result<Message, ProtocolError>,
     serial_sink: &mut (impl Sink<bytes::Bytes, Error = io::Error> + Unpin),
+    read_only: bool,
 ) -> Result<(), CloseReason> {
     let msg = message.map_err(|e| CloseReason {
         code: CloseCode::Protocol,
@@ -143,6 +169,12 @@ async fn message_stream_handler(
     })?;
 
     match msg {
+        actix_ws::Message::Text(_) | actix_ws::Message::Binary(_) if read_only => {
+            Err(CloseReason {
+                code: CloseCode::Policy,
+                description: Some("the console is read-only for observers".to_string()),
+            })
+        }
         actix_ws::Message::Text(text) => serial_sink
             .send(Bytes::copy_from_slice(text.as_bytes()))
             .await
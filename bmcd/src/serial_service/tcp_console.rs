@@ -0,0 +1,266 @@
+// Copyright 2024 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Raw TCP listeners for the serial consoles of the nodes. Every byte that a
+//! client sends goes to the UART of the node and all console output goes back
+//! to the client, so `nc bmc 2001` or a conserver `type host` console is all
+//! that is needed. This avoids the websocket framing of the HTTP path.
+//!
+//! The consoles are not authenticated, anyone who may connect can type on
+//! them. Only clients in `allow` are accepted.
+use super::serial::SerialConnections;
+use super::serial_handler::Handler;
+use crate::app::batch::node_id;
+use crate::config::TcpConsole;
+use crate::utils::{dual_stack_tcp, IpNetwork};
+use bytes::Bytes;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use openssl::ssl::{Ssl, SslAcceptor};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::time::{sleep_until, timeout, Instant};
+use tokio_openssl::SslStream;
+
+/// How long a client may take for the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a write may wait for the client to take console output. A client
+/// that stalls for longer is disconnected, like on the websocket path.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Binds the configured ports and accepts clients in the background.
+pub async fn run_tcp_consoles(
+    config: TcpConsole,
+    tls: Option<SslAcceptor>,
+    serials: Arc<SerialConnections>,
+) -> anyhow::Result<()> {
+    let allow: Arc<Vec<IpNetwork>> = Arc::new(
+        config
+            .allow
+            .iter()
+            .filter_map(|network| network.parse().ok())
+            .collect(),
+    );
+    let tls = tls.map(Arc::new);
+    let board_nodes = serials.get_state().len();
+
+    for port in &config.ports {
+        let (number, node) = (port.node, node_id(port.node, board_nodes)?);
+        let listener = TcpListener::from_std(dual_stack_tcp(port.port)?)?;
+        tracing::info!(
+            "console of node {} listening on TCP port {}",
+            port.node,
+            port.port
+        );
+
+        let limit = Arc::new(Semaphore::new(config.max_connections));
+        let idle_timeout = config.idle_timeout;
+        let (allow, tls, serials) = (allow.clone(), tls.clone(), serials.clone());
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(client) => client,
+                    Err(e) => {
+                        tracing::warn!("TCP console: {}", e);
+                        continue;
+                    }
+                };
+                if !is_allowed(&allow, &peer) {
+                    tracing::debug!("TCP console: refused {}", peer);
+                    continue;
+                }
+
+                let Ok(permit) = limit.clone().try_acquire_owned() else {
+                    tokio::spawn(refuse(stream, tls.clone()));
+                    continue;
+                };
+                let (tls, serials) = (tls.clone(), serials.clone());
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    tracing::info!("console of node {} opened by {}", number, peer);
+                    let result = match &tls {
+                        Some(acceptor) => match accept_tls(acceptor, stream).await {
+                            Ok(stream) => attach(stream, &serials[node], idle_timeout).await,
+                            Err(e) => Err(e),
+                        },
+                        None => attach(stream, &serials[node], idle_timeout).await,
+                    };
+                    match result {
+                        Ok(()) => tracing::info!("console of node {} closed by {}", number, peer),
+                        Err(e) => tracing::debug!("TCP console client {}: {}", peer, e),
+                    }
+                });
+            }
+        });
+    }
+    Ok(())
+}
+
+fn is_allowed(allow: &[IpNetwork], peer: &SocketAddr) -> bool {
+    allow.iter().any(|network| network.contains(peer.ip()))
+}
+
+/// Connects a client to the console. Only called once the client completed
+/// the TLS handshake, if any.
+async fn attach<S>(stream: S, serial: &Handler, idle_timeout: Duration) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (output, input) = serial.open_channel().map_err(io::Error::other)?;
+    forward(stream, output, input, idle_timeout).await
+}
+
+async fn accept_tls(acceptor: &SslAcceptor, stream: TcpStream) -> io::Result<SslStream<TcpStream>> {
+    let ssl = Ssl::new(acceptor.context()).map_err(io::Error::other)?;
+    let mut stream = SslStream::new(ssl, stream).map_err(io::Error::other)?;
+    timeout(HANDSHAKE_TIMEOUT, Pin::new(&mut stream).accept())
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))?
+        .map_err(io::Error::other)?;
+    Ok(stream)
+}
+
+/// Tells a client over the connection limit why it is disconnected.
+async fn refuse(stream: TcpStream, tls: Option<Arc<SslAcceptor>>) {
+    const MESSAGE: &[u8] = b"too many connections to this console\r\n";
+    match tls {
+        Some(acceptor) => {
+            if let Ok(mut stream) = accept_tls(&acceptor, stream).await {
+                let _ = timeout(SEND_TIMEOUT, stream.write_all(MESSAGE)).await;
+            }
+        }
+        None => {
+            let mut stream = stream;
+            let _ = timeout(SEND_TIMEOUT, stream.write_all(MESSAGE)).await;
+        }
+    }
+}
+
+/// Copies between a client and a console until either side closes, or until
+/// neither sends anything for `idle_timeout`.
+async fn forward<S>(
+    stream: S,
+    output: impl Stream<Item = io::Result<Bytes>>,
+    input: impl Sink<Bytes, Error = io::Error>,
+    idle_timeout: Duration,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    tokio::pin!(output);
+    tokio::pin!(input);
+    let mut buffer = vec![0; 4096];
+    let mut deadline = Instant::now() + idle_timeout;
+
+    loop {
+        let idle = async {
+            if idle_timeout.is_zero() {
+                std::future::pending().await
+            } else {
+                sleep_until(deadline).await
+            }
+        };
+        tokio::select! {
+            read = reader.read(&mut buffer) => {
+                let n = read?;
+                if n == 0 {
+                    break;
+                }
+                input.send(Bytes::copy_from_slice(&buffer[..n])).await?;
+            }
+            data = output.next() => {
+                let Some(data) = data else {
+                    break;
+                };
+                timeout(SEND_TIMEOUT, writer.write_all(&data?))
+                    .await
+                    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "client stalled"))??;
+            }
+            _ = idle => {
+                let _ = timeout(SEND_TIMEOUT, writer.write_all(b"\r\n[idle timeout]\r\n")).await;
+                break;
+            }
+        }
+        deadline = Instant::now() + idle_timeout;
+    }
+
+    let _ = timeout(SEND_TIMEOUT, writer.shutdown()).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+
+    fn console() -> (
+        mpsc::UnboundedSender<io::Result<Bytes>>,
+        impl Stream<Item = io::Result<Bytes>>,
+        mpsc::UnboundedReceiver<Bytes>,
+        impl Sink<Bytes, Error = io::Error>,
+    ) {
+        let (output_tx, output_rx) = mpsc::unbounded();
+        let (input_tx, input_rx) = mpsc::unbounded();
+        let input = input_tx.sink_map_err(io::Error::other);
+        (output_tx, output_rx, input_rx, input)
+    }
+
+    #[tokio::test]
+    async fn forwards_both_ways() {
+        let (output_tx, output, mut input_rx, input) = console();
+        let (mut client, server) = tokio::io::duplex(64);
+        let task = tokio::spawn(forward(server, output, input, Duration::ZERO));
+
+        client.write_all(b"root\n").await.unwrap();
+        assert_eq!(
+            input_rx.next().await.unwrap(),
+            Bytes::from_static(b"root\n")
+        );
+
+        output_tx
+            .unbounded_send(Ok(Bytes::from_static(b"login: ")))
+            .unwrap();
+        let mut read = [0; 7];
+        client.read_exact(&mut read).await.unwrap();
+        assert_eq!(&read, b"login: ");
+
+        drop(client);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn closes_idle_clients() {
+        let (_output_tx, output, _input_rx, input) = console();
+        let (mut client, server) = tokio::io::duplex(64);
+        let task = tokio::spawn(forward(server, output, input, Duration::from_millis(50)));
+
+        let mut read = String::new();
+        client.read_to_string(&mut read).await.unwrap();
+        assert!(read.contains("idle timeout"));
+        task.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn allow_list() {
+        let peer: SocketAddr = "[::ffff:10.0.0.7]:40000".parse().unwrap();
+        assert!(!is_allowed(&[], &peer));
+        assert!(is_allowed(&["10.0.0.0/24".parse().unwrap()], &peer));
+        assert!(!is_allowed(&["10.0.1.0/24".parse().unwrap()], &peer));
+    }
+}
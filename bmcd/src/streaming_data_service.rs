@@ -14,7 +14,8 @@
 pub mod data_transfer;
 pub mod transfer_context;
 
-use crate::api::into_legacy_response::LegacyResponse;
+use crate::app::jobs::{JobKind, Jobs, Outcome, Progress};
+use crate::error::ErrorCode;
 use crate::streaming_data_service::transfer_context::TransferContext;
 use actix_web::http::StatusCode;
 use bytes::Bytes;
@@ -23,6 +24,7 @@ use futures::Future;
 use humansize::{format_size, DECIMAL};
 use rand::Rng;
 use serde::Serialize;
+use serde_json::{json, Value};
 use std::fmt::{Debug, Display};
 use std::{
     ops::Deref,
@@ -33,15 +35,20 @@ use thiserror::Error;
 use tokio::sync::{mpsc, watch, Mutex};
 use tokio::time::sleep;
 use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
+/// Every transfer is registered as a job in [`Jobs`], with the handle of the
+/// transfer as job id.
 pub struct StreamingDataService {
     status: Arc<Mutex<StreamingState>>,
+    jobs: Arc<Jobs>,
 }
 
 impl StreamingDataService {
-    pub fn new() -> Self {
+    pub fn new(jobs: Arc<Jobs>) -> Self {
         Self {
             status: Arc::new(Mutex::new(StreamingState::Ready)),
+            jobs,
         }
     }
 
@@ -53,14 +60,25 @@ impl StreamingDataService {
         &self,
         request: TransferRequest,
     ) -> Result<u32, StreamingServiceError> {
-        let mut rng = rand::rng();
-        let id = rng.random();
+        let id = rand::rng().random();
+        let job_cancel = CancellationToken::new();
+        self.jobs.add(
+            id,
+            request.kind,
+            request.process_name.clone(),
+            job_cancel.clone(),
+            Some(Progress {
+                processed: request.progress_watcher.clone(),
+                total: request.size,
+            }),
+        );
 
         let context = TransferContext::new(
             id,
             request.process_name,
             request.size,
             request.progress_watcher,
+            request.throughput_watcher,
             request.sender,
             request.cancel,
         );
@@ -73,6 +91,7 @@ impl StreamingDataService {
         );
 
         self.execute_worker(&context, request.worker).await;
+        Self::cancel_on_job_cancel(self.status.clone(), &context, job_cancel);
         Self::cancel_request_on_timeout(self.status.clone());
         *self.status.lock().await = StreamingState::Transferring(context);
 
@@ -83,6 +102,28 @@ impl StreamingDataService {
         *self.status.lock().await = StreamingState::Error("cancelled by user".to_string());
     }
 
+    /// Cancels the transfer when its job is cancelled. Stops watching when
+    /// the transfer ends.
+    fn cancel_on_job_cancel(
+        status: Arc<Mutex<StreamingState>>,
+        context: &TransferContext,
+        job_cancel: CancellationToken,
+    ) {
+        let id = context.id;
+        let transfer = context.get_child_token();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = job_cancel.cancelled() => {
+                    let mut status = status.lock().await;
+                    if matches!(&*status, StreamingState::Transferring(ctx) if ctx.id == id) {
+                        *status = StreamingState::Error("cancelled by user".to_string());
+                    }
+                }
+                _ = transfer.cancelled() => {}
+            }
+        });
+    }
+
     fn cancel_request_on_timeout(status: Arc<Mutex<StreamingState>>) {
         tokio::spawn(async move {
             sleep(Duration::from_secs(10)).await;
@@ -118,45 +159,63 @@ impl StreamingDataService {
         let size = context.size;
         let start_time = Instant::now();
         let status = self.status.clone();
+        let jobs = self.jobs.clone();
 
-        tokio::spawn(async move {
-            tracing::debug!("starting streaming data service worker");
-            let (new_state, was_cancelled) = future.await.map_or_else(
-                |error| {
-                    tracing::error!("#{} stopped: {:#}.", id, error);
-                    (
-                        StreamingState::Error(error.to_string()),
-                        cancel.is_cancelled(),
-                    )
-                },
-                |_| {
-                    let duration = Instant::now().saturating_duration_since(start_time);
-                    tracing::info!(
-                        "worker done. took {} (#{})",
-                        humantime::format_duration(duration),
-                        id
-                    );
-
-                    (StreamingState::Done(duration, size), false)
-                },
-            );
+        // keeps the worker in the trace of the request that started it
+        let span = tracing::Span::current();
+        tokio::spawn(
+            async move {
+                tracing::debug!("starting streaming data service worker");
+                let (new_state, was_cancelled) = future.await.map_or_else(
+                    |error| {
+                        tracing::error!("#{} stopped: {:#}.", id, error);
+                        (
+                            StreamingState::Error(error.to_string()),
+                            cancel.is_cancelled(),
+                        )
+                    },
+                    |_| {
+                        let duration = Instant::now().saturating_duration_since(start_time);
+                        tracing::info!(
+                            "worker done. took {} (#{})",
+                            humantime::format_duration(duration),
+                            id
+                        );
 
-            // Ignore state changes due to cancellation. This only happens on a state transition
-            // from `StreamingState::Transferring` (see `TransferContext::drop()`). The state is
-            // already correct, therefore we omit a state transition in this scenario.
-            let mut status_unlocked = status.lock().await;
-            if let StreamingState::Transferring(ctx) = &*status_unlocked {
-                tracing::debug!(
-                    "last recorded transfer state: {:#?}",
-                    serde_json::to_string(ctx)
+                        (StreamingState::Done(duration, size), false)
+                    },
                 );
-                tracing::debug!("state={new_state}(cancelled={})", was_cancelled);
 
-                if !was_cancelled {
-                    *status_unlocked = new_state;
+                // Ignore state changes due to cancellation. This only happens on a state transition
+                // from `StreamingState::Transferring` (see `TransferContext::drop()`). The state is
+                // already correct, therefore we omit a state transition in this scenario.
+                let outcome = match &new_state {
+                    _ if was_cancelled => Outcome::Cancelled,
+                    StreamingState::Done(duration, size) => Outcome::Succeeded(Some(json!({
+                        "duration_secs": duration.as_secs(),
+                        "size": size,
+                    }))),
+                    StreamingState::Error(e) => Outcome::Failed(e.clone()),
+                    _ => Outcome::Cancelled,
+                };
+
+                let mut status_unlocked = status.lock().await;
+                if let StreamingState::Transferring(ctx) = &*status_unlocked {
+                    tracing::debug!(
+                        "last recorded transfer state: {:#?}",
+                        serde_json::to_string(ctx)
+                    );
+                    tracing::debug!("state={new_state}(cancelled={})", was_cancelled);
+
+                    if !was_cancelled {
+                        *status_unlocked = new_state;
+                    }
                 }
+                drop(status_unlocked);
+                jobs.finish(id, outcome).await;
             }
-        });
+            .instrument(span),
+        );
     }
 
     /// Write a chunk of bytes to the module that is selected for flashing.
@@ -166,9 +225,9 @@ impl StreamingDataService {
     /// This function returns:
     ///
     /// * 'Err(StreamingServiceError::WrongState)' if this function is called when
-    ///     ['StreamingDataService'] is not in 'Transferring' state.
+    ///   ['StreamingDataService'] is not in 'Transferring' state.
     /// * 'Err(StreamingServiceError::HandlesDoNotMatch)', the passed id is
-    ///     unknown
+    ///   unknown
     /// * 'Err(StreamingServiceError::SenderTaken(_)'
     /// * Ok(()) on success
     pub async fn take_sender(
@@ -233,15 +292,32 @@ pub enum StreamingServiceError {
     SenderTaken,
 }
 
-impl From<StreamingServiceError> for LegacyResponse {
-    fn from(value: StreamingServiceError) -> Self {
-        let status_code = match value {
+impl ErrorCode for StreamingServiceError {
+    fn code(&self) -> &'static str {
+        match self {
+            StreamingServiceError::WrongState(_, _) => "wrong_transfer_state",
+            StreamingServiceError::HandlesDoNotMatch => "invalid_handle",
+            StreamingServiceError::IoError(_) => "io_error",
+            StreamingServiceError::SenderTaken => "transfer_in_progress",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
             StreamingServiceError::WrongState(_, _) => StatusCode::BAD_REQUEST,
             StreamingServiceError::HandlesDoNotMatch => StatusCode::BAD_REQUEST,
             StreamingServiceError::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             StreamingServiceError::SenderTaken => StatusCode::BAD_REQUEST,
-        };
-        (status_code, value.to_string()).into()
+        }
+    }
+
+    fn details(&self) -> Value {
+        match self {
+            StreamingServiceError::WrongState(current, expected) => {
+                json!({ "current": current, "expected": expected })
+            }
+            _ => Value::Null,
+        }
     }
 }
 
@@ -276,10 +352,13 @@ impl Display for StreamingState {
 }
 
 pub struct TransferRequest {
+    pub kind: JobKind,
     pub process_name: String,
     pub size: u64,
     pub sender: Option<mpsc::Sender<bytes::Bytes>>,
     pub progress_watcher: watch::Receiver<u64>,
+    /// measured write throughput in bytes per second, 0 when unknown
+    pub throughput_watcher: watch::Receiver<u64>,
     pub worker: BoxFuture<'static, anyhow::Result<()>>,
     pub cancel: CancellationToken,
 }
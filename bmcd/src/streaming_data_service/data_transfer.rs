@@ -11,7 +11,7 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use crate::utils::Sha256StreamValidator;
+use crate::utils::{Checksum, ChecksumValidator, TransferDigest};
 use crate::Path;
 use anyhow::Context;
 use async_compression::tokio::bufread::XzDecoder;
@@ -28,92 +28,118 @@ use tokio::fs::OpenOptions;
 use tokio::io;
 use tokio::io::AsyncBufRead;
 use tokio::io::AsyncRead;
-use tokio::io::BufReader;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamExt;
-use tokio_util::io::StreamReader;
+use tokio_util::io::{ReaderStream, StreamReader};
 
 #[derive(Debug)]
 pub enum DataTransfer {
     Local {
         path: PathBuf,
+        checksum: Option<Checksum>,
     },
     Remote {
         file_name: PathBuf,
         size: u64,
-        sha256: Option<bytes::Bytes>,
+        checksum: Option<Checksum>,
         sender: Option<mpsc::Sender<bytes::Bytes>>,
         receiver: Option<mpsc::Receiver<bytes::Bytes>>,
     },
     Url {
         file_name: PathBuf,
-        sha256: Option<bytes::Bytes>,
+        checksum: Option<Checksum>,
         response: Option<reqwest::Response>,
     },
+    /// Data produced by another task, such as a download from several peers.
+    Stream {
+        file_name: PathBuf,
+        size: u64,
+        checksum: Option<Checksum>,
+        receiver: Option<mpsc::Receiver<io::Result<bytes::Bytes>>>,
+    },
 }
 
 impl DataTransfer {
-    pub fn local(path: PathBuf) -> Self {
-        Self::Local { path }
+    pub fn local(path: PathBuf, checksum: Option<Checksum>) -> Self {
+        Self::Local { path, checksum }
     }
 
     pub fn remote(
         file_name: PathBuf,
         size: u64,
         buffer_size: usize,
-        sha256: Option<bytes::Bytes>,
+        checksum: Option<Checksum>,
     ) -> Self {
         let (sender, receiver) = mpsc::channel(buffer_size);
 
         Self::Remote {
             file_name,
             size,
-            sha256,
+            checksum,
             sender: Some(sender),
             receiver: Some(receiver),
         }
     }
 
-    pub async fn url(url: Url, sha256: Option<bytes::Bytes>) -> anyhow::Result<Self> {
-        let file_name = url
-            .path_segments()
-            .and_then(|seg| seg.last())
-            .or_else(|| url.host_str())
-            .unwrap_or("http_file")
-            .into();
+    pub async fn url(url: Url, checksum: Option<Checksum>) -> anyhow::Result<Self> {
         Ok(Self::Url {
-            file_name,
-            sha256,
+            file_name: url_file_name(&url),
+            checksum,
             response: Some(reqwest::get(url).await.context("http file request error")?),
         })
     }
+
+    pub fn stream(
+        file_name: PathBuf,
+        size: u64,
+        checksum: Option<Checksum>,
+        receiver: mpsc::Receiver<io::Result<bytes::Bytes>>,
+    ) -> Self {
+        Self::Stream {
+            file_name,
+            size,
+            checksum,
+            receiver: Some(receiver),
+        }
+    }
+}
+
+/// Name of the file that `url` points to. The extension of the name decides
+/// whether the data gets decompressed.
+pub fn url_file_name(url: &Url) -> PathBuf {
+    url.path_segments()
+        .and_then(|mut seg| seg.next_back())
+        .or_else(|| url.host_str())
+        .unwrap_or("http_file")
+        .into()
 }
 
 impl DataTransfer {
     pub fn file_name(&self) -> anyhow::Result<&OsStr> {
         match self {
-            DataTransfer::Local { path } => Ok(path
+            DataTransfer::Local { path, .. } => Ok(path
                 .file_name()
                 .ok_or(std::io::Error::from(ErrorKind::InvalidInput))?),
             DataTransfer::Remote {
                 file_name,
                 size: _,
-                sha256: _,
+                checksum: _,
                 sender: _,
                 receiver: _,
             } => Ok(file_name.as_os_str()),
             DataTransfer::Url {
                 file_name,
-                sha256: _,
+                checksum: _,
                 response: _,
             } => Ok(file_name.as_os_str()),
+            DataTransfer::Stream { file_name, .. } => Ok(file_name.as_os_str()),
         }
     }
 
     pub fn size(&self) -> anyhow::Result<u64> {
         match self {
-            DataTransfer::Local { path } => {
+            DataTransfer::Local { path, .. } => {
                 let mut file = std::fs::OpenOptions::new()
                     .read(true)
                     .open(path)
@@ -123,13 +149,13 @@ impl DataTransfer {
             DataTransfer::Remote {
                 file_name: _,
                 size,
-                sha256: _,
+                checksum: _,
                 sender: _,
                 receiver: _,
             } => Ok(*size),
             DataTransfer::Url {
                 file_name: _,
-                sha256: _,
+                checksum: _,
                 response,
             } => response
                 .as_ref()
@@ -142,24 +168,36 @@ impl DataTransfer {
                     str.parse::<u64>()
                         .with_context(|| format!("cannot parse {str} to u64"))
                 }),
+            DataTransfer::Stream { size, .. } => Ok(*size),
         }
     }
 
-    pub async fn reader(&mut self) -> anyhow::Result<impl AsyncRead + Sync + Send + Unpin> {
+    /// Reader of the transferred data, decompressed when the file name says
+    /// so. The data is checked against the expected checksum, the returned
+    /// [`TransferDigest`] tells its SHA-256 once all data was read.
+    pub async fn reader(
+        &mut self,
+    ) -> anyhow::Result<(impl AsyncRead + Sync + Send + Unpin, TransferDigest)> {
+        let size = self.size().ok();
         match self {
-            DataTransfer::Local { path } => {
+            DataTransfer::Local { path, checksum } => {
                 let file = OpenOptions::new()
                     .read(true)
                     .open(&path)
                     .await
                     .with_context(|| path.to_string_lossy().to_string())?;
 
-                Ok(with_decompression_support(path, BufReader::new(file)))
+                Ok(build_reader_object(
+                    path,
+                    checksum.clone(),
+                    size,
+                    ReaderStream::new(file),
+                ))
             }
             DataTransfer::Remote {
                 file_name,
                 size: _,
-                sha256,
+                checksum,
                 sender: _,
                 receiver,
             } => {
@@ -168,31 +206,62 @@ impl DataTransfer {
                         .map(Ok::<bytes::Bytes, io::Error>);
                 Ok(build_reader_object(
                     file_name,
-                    sha256.clone(),
+                    checksum.clone(),
+                    size,
                     receiver_stream,
                 ))
             }
             DataTransfer::Url {
                 file_name,
-                sha256,
+                checksum,
                 response,
             } => {
                 let bytes_stream = response
                     .take()
                     .expect("request taken")
                     .bytes_stream()
-                    .map(|res| res.map_err(|e| std::io::Error::new(ErrorKind::Other, e)));
+                    .map(|res| res.map_err(std::io::Error::other));
 
-                Ok(build_reader_object(file_name, sha256.clone(), bytes_stream))
+                Ok(build_reader_object(
+                    file_name,
+                    checksum.clone(),
+                    size,
+                    bytes_stream,
+                ))
+            }
+            DataTransfer::Stream {
+                file_name,
+                checksum,
+                receiver,
+                ..
+            } => {
+                let stream =
+                    ReceiverStream::new(receiver.take().expect("cannot take reader twice"));
+                Ok(build_reader_object(
+                    file_name,
+                    checksum.clone(),
+                    size,
+                    stream,
+                ))
             }
         }
     }
 
+    /// Checksum that the client expects the data to have.
+    pub fn checksum(&self) -> Option<&Checksum> {
+        match self {
+            DataTransfer::Local { checksum, .. }
+            | DataTransfer::Remote { checksum, .. }
+            | DataTransfer::Url { checksum, .. }
+            | DataTransfer::Stream { checksum, .. } => checksum.as_ref(),
+        }
+    }
+
     pub fn sender_half(&mut self) -> Option<mpsc::Sender<Bytes>> {
         if let Self::Remote {
             file_name: _,
             size: _,
-            sha256: _,
+            checksum: _,
             sender,
             receiver: _,
         } = self
@@ -205,22 +274,16 @@ impl DataTransfer {
 
 fn build_reader_object(
     file_name: &Path,
-    sha256: Option<bytes::Bytes>,
+    checksum: Option<Checksum>,
+    size: Option<u64>,
     reader: impl Stream<Item = io::Result<bytes::Bytes>> + 'static + Send + Sync + Unpin,
-) -> Box<dyn AsyncRead + Send + Sync + Unpin> {
-    if let Some(sha) = sha256 {
-        tracing::info!(
-            "crc validator enabled. expects sha256: {}",
-            hex::encode(&sha)
-        );
-
-        with_decompression_support(
-            file_name,
-            StreamReader::new(Sha256StreamValidator::new(reader, sha)),
-        )
-    } else {
-        with_decompression_support(file_name, StreamReader::new(reader))
-    }
+) -> (Box<dyn AsyncRead + Send + Sync + Unpin>, TransferDigest) {
+    let validator = ChecksumValidator::new(reader, checksum, size);
+    let digest = validator.digest();
+    (
+        with_decompression_support(file_name, StreamReader::new(validator)),
+        digest,
+    )
 }
 
 fn with_decompression_support(
@@ -30,8 +30,11 @@ pub struct TransferContext {
     pub data_sender: Option<mpsc::Sender<Bytes>>,
     #[serde(serialize_with = "serialize_cancellation_token")]
     cancelled: CancellationToken,
-    #[serde(serialize_with = "serialize_written_bytes")]
+    #[serde(serialize_with = "serialize_watched_value")]
     bytes_written: watch::Receiver<u64>,
+    /// bytes per second
+    #[serde(serialize_with = "serialize_watched_value")]
+    write_throughput: watch::Receiver<u64>,
 }
 
 impl TransferContext {
@@ -40,6 +43,7 @@ impl TransferContext {
         process_name: String,
         size: u64,
         written_receiver: watch::Receiver<u64>,
+        throughput_receiver: watch::Receiver<u64>,
         data_sender: Option<mpsc::Sender<Bytes>>,
         cancel_token: CancellationToken,
     ) -> Self {
@@ -49,6 +53,7 @@ impl TransferContext {
             process_name,
             cancelled: cancel_token,
             bytes_written: written_receiver,
+            write_throughput: throughput_receiver,
             data_sender,
         }
     }
@@ -74,7 +79,7 @@ where
     s.serialize_bool(cancel_token.is_cancelled())
 }
 
-fn serialize_written_bytes<S>(receiver: &watch::Receiver<u64>, s: S) -> Result<S::Ok, S::Error>
+fn serialize_watched_value<S>(receiver: &watch::Receiver<u64>, s: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
@@ -16,13 +16,40 @@ mod rpiboot;
 use self::{rockusb::RockusbBoot, rpiboot::RpiBoot};
 use async_trait::async_trait;
 use rusb::GlobalContext;
-use std::{fmt::Display, path::PathBuf};
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, path::PathBuf, time::Duration};
 use thiserror::Error;
-use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite};
 use tracing::{info, warn};
 
-pub trait DataTransport: AsyncRead + AsyncWrite + AsyncSeek + Send + Unpin {}
-impl DataTransport for tokio::fs::File {}
+/// What the boot ROM of a compute module reveals about it. The memory size
+/// and board revision are not available before the module runs its own
+/// firmware.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModuleIdentity {
+    /// module family, e.g. `CM4`
+    pub module: String,
+    pub soc: String,
+    pub serial: Option<String>,
+    /// size of the on-module storage in bytes
+    pub storage: Option<u64>,
+}
+
+impl Display for ModuleIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.module, self.soc)?;
+        if let Some(storage) = self.storage {
+            write!(
+                f,
+                ", {} storage",
+                humansize::format_size(storage, humansize::DECIMAL)
+            )?;
+        }
+        if let Some(serial) = &self.serial {
+            write!(f, ", serial {}", serial)?;
+        }
+        Ok(())
+    }
+}
 
 #[async_trait]
 pub trait UsbBoot: 'static + Send + Sync + Display {
@@ -34,19 +61,23 @@ pub trait UsbBoot: 'static + Send + Sync + Display {
         Err(UsbBootError::NotSupported)
     }
 
-    async fn load_as_stream(
+    async fn identify(
         &self,
         device: &rusb::Device<GlobalContext>,
-    ) -> Result<Box<dyn DataTransport>, UsbBootError> {
-        let path = self.load_as_block_device(device).await?;
-        Ok(Box::new(
-            tokio::fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(&path)
-                .await?,
-        ) as Box<dyn DataTransport>)
-    }
+    ) -> Result<ModuleIdentity, UsbBootError>;
+}
+
+/// Serial number string of `device`, if the boot ROM reports one.
+fn serial_number(device: &rusb::Device<GlobalContext>) -> Option<String> {
+    let descriptor = device.device_descriptor().ok()?;
+    descriptor.serial_number_string_index()?;
+    let handle = device.open().ok()?;
+    let timeout = Duration::from_secs(1);
+    let language = *handle.read_languages(timeout).ok()?.first()?;
+    handle
+        .read_serial_number_string(language, &descriptor, timeout)
+        .ok()
+        .filter(|s| !s.is_empty())
 }
 
 pub struct NodeDrivers {
@@ -101,9 +132,9 @@ impl NodeDrivers {
         driver.load_as_block_device(&device).await
     }
 
-    pub async fn load_as_stream(&self) -> Result<Box<dyn DataTransport>, UsbBootError> {
+    pub async fn identify(&self) -> Result<ModuleIdentity, UsbBootError> {
         let (device, driver) = self.find_first()?;
-        driver.load_as_stream(&device).await
+        driver.identify(&device).await
     }
 }
 
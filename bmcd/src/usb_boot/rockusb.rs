@@ -13,7 +13,7 @@ use crate::utils::get_device_path;
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use super::{UsbBoot, UsbBootError};
+use super::{serial_number, ModuleIdentity, UsbBoot, UsbBootError};
 use async_trait::async_trait;
 use rockfile::boot::{
     RkBootEntry, RkBootEntryBytes, RkBootHeader, RkBootHeaderBytes, RkBootHeaderEntry,
@@ -49,6 +49,44 @@ impl UsbBoot for RockusbBoot {
             .await
             .map_err(UsbBootError::internal_error)
     }
+
+    /// The SoC is read in maskrom mode as well, the storage size only once
+    /// the usb-plug loader runs.
+    async fn identify(
+        &self,
+        device: &rusb::Device<GlobalContext>,
+    ) -> Result<ModuleIdentity, UsbBootError> {
+        let mut transport =
+            Transport::from_usb_device(device.open()?).map_err(UsbBootError::internal_error)?;
+        let soc = transport
+            .chip_info()
+            .ok()
+            .and_then(|info| soc_name(info.inner()))
+            .unwrap_or_else(|| "RK3588".to_string());
+        let storage = if BootMode::Loader == device.device_descriptor()?.into() {
+            transport.flash_info().ok().map(|info| info.size())
+        } else {
+            None
+        };
+        Ok(ModuleIdentity {
+            module: "RK1".to_string(),
+            soc,
+            serial: serial_number(device),
+            storage,
+        })
+    }
+}
+
+/// The chip info starts with the SoC number in reversed ASCII, e.g. `8853`
+/// for the RK3588.
+fn soc_name(chip_info: &[u8]) -> Option<String> {
+    let id = chip_info.get(..4)?;
+    id.iter().all(u8::is_ascii_alphanumeric).then(|| {
+        format!(
+            "RK{}",
+            id.iter().rev().map(|b| *b as char).collect::<String>()
+        )
+    })
 }
 
 impl Display for RockusbBoot {
@@ -179,3 +217,15 @@ fn requires_usb_plug(device: &rusb::Device<GlobalContext>) -> rusb::Result<bool>
             .is_empty();
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chip_info_soc() {
+        assert_eq!(soc_name(b"8853\0\0\0\0").as_deref(), Some("RK3588"));
+        assert_eq!(soc_name(&[0, 0, 0, 0]), None);
+        assert_eq!(soc_name(b"88"), None);
+    }
+}
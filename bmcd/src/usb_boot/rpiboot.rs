@@ -11,7 +11,7 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use super::UsbBoot;
+use super::{serial_number, ModuleIdentity, UsbBoot};
 use crate::{usb_boot::UsbBootError, utils::get_device_path};
 use async_trait::async_trait;
 use std::{fmt::Display, time::Duration};
@@ -37,6 +37,18 @@ impl UsbBoot for RpiBoot {
             .await
             .map_err(UsbBootError::internal_error)
     }
+
+    async fn identify(
+        &self,
+        device: &rusb::Device<rusb::GlobalContext>,
+    ) -> Result<ModuleIdentity, UsbBootError> {
+        Ok(ModuleIdentity {
+            module: "CM4".to_string(),
+            soc: "BCM2711".to_string(),
+            serial: serial_number(device),
+            storage: None,
+        })
+    }
 }
 
 impl Display for RpiBoot {
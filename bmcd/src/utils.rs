@@ -11,16 +11,26 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+mod block_device;
 mod event_listener;
 mod io;
+pub mod memory;
+mod mounts;
+mod net;
 
 use anyhow::bail;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+pub use block_device::*;
 #[doc(inline)]
 pub use event_listener::*;
 pub use io::*;
-use std::{path::PathBuf, process::Output};
+pub use mounts::*;
+pub use net::*;
+use std::{
+    path::{Component, Path, PathBuf},
+    process::{Command, Output},
+};
 use tokio::io::AsyncBufReadExt;
 
 pub fn string_from_utf16(bytes: &[u8], little_endian: bool) -> String {
@@ -109,6 +119,41 @@ pub async fn get_device_path(allowed_vendors: &[&str]) -> anyhow::Result<PathBuf
 }
 
 /// Get current time in seconds since Unix epoch. Returns `None` if current time is before epoch.
+/// Checks `hostname` against the rules of a single DNS label (RFC 1123).
+/// Maps a relative file name onto `root`. Returns `None` for names that
+/// escape the root directory.
+pub fn resolve(root: &Path, relative: &str) -> Option<PathBuf> {
+    let relative = Path::new(relative.trim_start_matches('/'));
+    relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+        .then(|| root.join(relative))
+}
+
+/// Parses a MAC address in the `aa:bb:cc:dd:ee:ff` notation.
+pub fn parse_mac_address(mac: &str) -> Option<[u8; 6]> {
+    let mut bytes = [0u8; 6];
+    let mut octets = mac.split(':');
+    for byte in bytes.iter_mut() {
+        let octet = octets.next()?;
+        if octet.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(octet, 16).ok()?;
+    }
+    octets.next().is_none().then_some(bytes)
+}
+
+pub fn is_valid_hostname(hostname: &str) -> bool {
+    !hostname.is_empty()
+        && hostname.len() <= 63
+        && !hostname.starts_with('-')
+        && !hostname.ends_with('-')
+        && hostname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
 pub fn get_timestamp_unix() -> Option<u64> {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -116,6 +161,14 @@ pub fn get_timestamp_unix() -> Option<u64> {
         .map(|x| x.as_secs())
 }
 
+/// HMAC-SHA256 of `data` with `key`. Compare the result against a received
+/// signature with `openssl::memcmp::eq`, in constant time.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, openssl::error::ErrorStack> {
+    let key = openssl::pkey::PKey::hmac(key)?;
+    let mut signer = openssl::sign::Signer::new(openssl::hash::MessageDigest::sha256(), &key)?;
+    signer.sign_oneshot_to_vec(data)
+}
+
 pub async fn logging_sink_stdio(output: &Output) -> std::io::Result<()> {
     let mut lines = output.stdout.lines();
     while let Some(line) = lines.next_line().await? {
@@ -128,3 +181,15 @@ pub async fn logging_sink_stdio(output: &Output) -> std::io::Result<()> {
     }
     Ok(())
 }
+
+/// Restarts bmcd through its init script. The restart is executed on a
+/// separate thread so that the caller can still finish its work, e.g. respond
+/// to a request.
+pub fn restart_daemon() {
+    tokio::task::spawn_blocking(move || {
+        Command::new("sh")
+            .arg("-c")
+            .arg("/etc/init.d/S94bmcd restart")
+            .status()
+    });
+}
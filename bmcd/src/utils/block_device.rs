@@ -0,0 +1,425 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Streaming of images to block devices with as few copies as possible.
+//!
+//! Data is read from the source once, straight into one of a small pool of
+//! page-aligned buffers. Filled buffers travel over a bounded channel to a
+//! blocking writer thread, which writes whatever is queued with one vectored
+//! write and hands the buffers back. The device is opened with `O_DIRECT`, so
+//! the kernel DMAs out of these buffers instead of copying them into the page
+//! cache. The pool bounds the memory in flight and applies back-pressure to
+//! the source when the device is the bottleneck.
+//!
+//! The size of the buffers and how many of them are in flight is tuned
+//! while writing, see [`write_tuner`].
+//!
+//! Devices or file systems that refuse `O_DIRECT` are written through the
+//! page cache instead.
+mod write_tuner;
+
+use self::write_tuner::{WriteTuner, MAX_CHUNK, MAX_DEPTH};
+use super::memory::{memory, Pool, Reservation};
+use crc::{Crc, CRC_64_REDIS};
+use nix::fcntl::{fcntl, posix_fadvise, FcntlArg, OFlag, PosixFadviseAdvice};
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, IoSlice, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+
+/// Alignment of buffers, offsets and lengths that `O_DIRECT` demands.
+const ALIGNMENT: usize = 4096;
+
+/// Heap buffer whose contents start at an [`ALIGNMENT`] boundary. Buffers
+/// are accounted in [`Pool::FlashStaging`].
+struct AlignedBuffer {
+    storage: Vec<u8>,
+    offset: usize,
+    capacity: usize,
+    len: usize,
+    _reservation: Reservation<'static>,
+}
+
+impl AlignedBuffer {
+    /// Fails when the buffer does not fit into the memory limit.
+    fn new(capacity: usize) -> io::Result<Self> {
+        let reservation = memory()
+            .try_reserve(Pool::FlashStaging, capacity + ALIGNMENT)
+            .ok_or_else(|| {
+                io::Error::new(ErrorKind::OutOfMemory, "flash staging memory limit reached")
+            })?;
+        let storage = vec![0u8; capacity + ALIGNMENT];
+        let offset = storage.as_ptr().align_offset(ALIGNMENT);
+        Ok(Self {
+            storage,
+            offset,
+            capacity,
+            len: 0,
+            _reservation: reservation,
+        })
+    }
+
+    /// Empties the buffer and resizes it to `capacity`. The buffer keeps its
+    /// size when the new one does not fit into the memory limit.
+    fn reset(&mut self, capacity: usize) {
+        if self.capacity != capacity {
+            if let Ok(buffer) = Self::new(capacity) {
+                *self = buffer;
+            }
+        }
+        self.len = 0;
+    }
+
+    fn filled(&self) -> &[u8] {
+        &self.storage[self.offset..self.offset + self.len]
+    }
+
+    fn spare(&mut self) -> &mut [u8] {
+        &mut self.storage[self.offset + self.len..self.offset + self.capacity]
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == self.capacity
+    }
+}
+
+/// Chunk size and queue depth, as tuned by the writer for the producer.
+struct Tuning {
+    chunk_size: AtomicUsize,
+    depth: AtomicUsize,
+}
+
+impl Tuning {
+    fn publish(&self, tuner: &WriteTuner) {
+        self.chunk_size.store(tuner.chunk_size(), Ordering::Relaxed);
+        self.depth.store(tuner.depth(), Ordering::Relaxed);
+    }
+}
+
+/// Outcome of [`write_block_device`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Written {
+    pub bytes: u64,
+    /// CRC-64/REDIS of the written data
+    pub crc: u64,
+}
+
+/// Opens `path` with `O_DIRECT` when possible. Returns whether it is.
+fn open(path: &Path, write: bool) -> io::Result<(File, bool)> {
+    let mut options = OpenOptions::new();
+    options.read(!write).write(write);
+    match options
+        .clone()
+        .custom_flags(OFlag::O_DIRECT.bits())
+        .open(path)
+    {
+        Ok(file) => Ok((file, true)),
+        Err(e) if e.raw_os_error() == Some(nix::libc::EINVAL) => {
+            tracing::debug!("{} does not support O_DIRECT", path.display());
+            Ok((options.open(path)?, false))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn disable_direct_io(file: &File) -> io::Result<()> {
+    let flags = OFlag::from_bits_truncate(fcntl(file.as_raw_fd(), FcntlArg::F_GETFL)?);
+    fcntl(
+        file.as_raw_fd(),
+        FcntlArg::F_SETFL(flags.difference(OFlag::O_DIRECT)),
+    )?;
+    Ok(())
+}
+
+fn write_all_vectored(file: &mut File, buffers: &[AlignedBuffer]) -> io::Result<()> {
+    // index of the first buffer that is not completely written, and the
+    // bytes that are written of it
+    let mut first = 0;
+    let mut written = 0;
+    loop {
+        while first < buffers.len() && written >= buffers[first].len {
+            written -= buffers[first].len;
+            first += 1;
+        }
+        if first == buffers.len() {
+            return Ok(());
+        }
+
+        let slices: Vec<IoSlice> = std::iter::once(&buffers[first].filled()[written..])
+            .chain(buffers[first + 1..].iter().map(AlignedBuffer::filled))
+            .map(IoSlice::new)
+            .collect();
+        match file.write_vectored(&slices) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => written += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Writes the buffers that arrive on `full` and returns them on `empty`.
+fn writer_thread(
+    mut file: File,
+    direct: bool,
+    mut full: mpsc::Receiver<AlignedBuffer>,
+    empty: mpsc::Sender<AlignedBuffer>,
+    tuning: Arc<Tuning>,
+    throughput: watch::Sender<u64>,
+) -> io::Result<()> {
+    let mut tuner = WriteTuner::new();
+    let mut batch = Vec::with_capacity(MAX_DEPTH);
+    let mut direct = direct;
+    while let Some(buffer) = full.blocking_recv() {
+        batch.push(buffer);
+        while let Ok(buffer) = full.try_recv() {
+            batch.push(buffer);
+        }
+
+        let start = Instant::now();
+        // only the last buffer of the stream is partially filled
+        let tail = batch.last().map_or(0, |b| b.len % ALIGNMENT);
+        if direct && tail != 0 {
+            let last = batch.pop().expect("batch is not empty");
+            write_all_vectored(&mut file, &batch)?;
+            let (aligned, rest) = last.filled().split_at(last.len - tail);
+            file.write_all(aligned)?;
+            disable_direct_io(&file)?;
+            direct = false;
+            file.write_all(rest)?;
+            batch.push(last);
+        } else {
+            write_all_vectored(&mut file, &batch)?;
+        }
+        tuner.record(batch.iter().map(|b| b.len).sum(), start.elapsed());
+        tuning.publish(&tuner);
+        throughput.send_replace(tuner.throughput());
+
+        for buffer in batch.drain(..) {
+            // the producer is gone when it was cancelled
+            let _ = empty.blocking_send(buffer);
+        }
+    }
+    match file.sync_all() {
+        // character devices, such as /dev/null for dry runs, cannot be synced
+        Err(e) if e.raw_os_error() == Some(nix::libc::EINVAL) => Ok(()),
+        result => result,
+    }
+}
+
+/// Streams `reader` to the block device at `path` until the reader is
+/// exhausted. `progress` is updated with the bytes that reached the device,
+/// `throughput` with the write speed of the device in bytes per second.
+/// Returns an `io::Error(Interrupted)` when `cancel` fires.
+pub async fn write_block_device(
+    mut reader: impl AsyncRead + Unpin,
+    path: &Path,
+    progress: &watch::Sender<u64>,
+    throughput: &watch::Sender<u64>,
+    cancel: &CancellationToken,
+) -> io::Result<Written> {
+    let (file, direct) = open(path, true)?;
+    let (full_sender, full_receiver) = mpsc::channel(MAX_DEPTH);
+    let (empty_sender, mut empty_receiver) = mpsc::channel(MAX_DEPTH);
+    let tuner = WriteTuner::new();
+    let tuning = Arc::new(Tuning {
+        chunk_size: AtomicUsize::new(tuner.chunk_size()),
+        depth: AtomicUsize::new(tuner.depth()),
+    });
+    let writer = {
+        let (tuning, throughput) = (tuning.clone(), throughput.clone());
+        tokio::task::spawn_blocking(move || {
+            writer_thread(
+                file,
+                direct,
+                full_receiver,
+                empty_sender,
+                tuning,
+                throughput,
+            )
+        })
+    };
+
+    let crc = Crc::<u64>::new(&CRC_64_REDIS);
+    let mut digest = crc.digest();
+    let mut read = 0u64;
+    let mut written = 0u64;
+    // buffers that are in the pool, filled, being written or empty
+    let mut allocated = 0;
+    let mut eof = false;
+    while !eof {
+        let chunk_size = tuning.chunk_size.load(Ordering::Relaxed).min(MAX_CHUNK);
+        let depth = tuning.depth.load(Ordering::Relaxed).min(MAX_DEPTH);
+        // at the memory limit the queue runs with the buffers it already has
+        let fresh = (allocated < depth).then(|| AlignedBuffer::new(chunk_size));
+        let mut buffer = match fresh {
+            Some(Ok(buffer)) => {
+                allocated += 1;
+                buffer
+            }
+            Some(Err(e)) if allocated == 0 => return Err(e),
+            _ => {
+                let buffer = tokio::select! {
+                    buffer = empty_receiver.recv() => buffer,
+                    _ = cancel.cancelled() => return Err(ErrorKind::Interrupted.into()),
+                };
+                // the writer hung up, its result tells why
+                let Some(buffer) = buffer else { break };
+                written += buffer.len as u64;
+                progress.send_replace(written);
+                if allocated > depth {
+                    allocated -= 1;
+                    continue;
+                }
+                buffer
+            }
+        };
+        buffer.reset(chunk_size);
+
+        while !buffer.is_full() {
+            let n = tokio::select! {
+                n = reader.read(buffer.spare()) => n?,
+                _ = cancel.cancelled() => return Err(ErrorKind::Interrupted.into()),
+            };
+            if n == 0 {
+                eof = true;
+                break;
+            }
+            buffer.len += n;
+        }
+        digest.update(buffer.filled());
+        read += buffer.len as u64;
+        if full_sender.send(buffer).await.is_err() {
+            break;
+        }
+    }
+    drop(full_sender);
+
+    writer.await??;
+    progress.send_replace(read);
+    Ok(Written {
+        bytes: read,
+        crc: digest.finalize(),
+    })
+}
+
+/// Computes the CRC-64/REDIS of the first `len` bytes of the device at
+/// `path`, as stored on the device rather than in the page cache.
+pub async fn checksum_block_device(
+    path: &Path,
+    len: u64,
+    progress: &watch::Sender<u64>,
+    cancel: &CancellationToken,
+) -> io::Result<u64> {
+    let (mut file, direct) = open(path, false)?;
+    if !direct {
+        posix_fadvise(
+            file.as_raw_fd(),
+            0,
+            0,
+            PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+        )?;
+    }
+    let progress = progress.clone();
+    let cancel = cancel.clone();
+    tokio::task::spawn_blocking(move || {
+        let crc = Crc::<u64>::new(&CRC_64_REDIS);
+        let mut digest = crc.digest();
+        let mut buffer = AlignedBuffer::new(MAX_CHUNK)?;
+        let mut remaining = len;
+        while remaining > 0 {
+            if cancel.is_cancelled() {
+                return Err(ErrorKind::Interrupted.into());
+            }
+            // O_DIRECT reads whole blocks, the excess is discarded
+            let n = match file.read(buffer.spare()) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let used = n.min(remaining as usize);
+            digest.update(&buffer.spare()[..used]);
+            remaining -= used as u64;
+            progress.send_replace(len - remaining);
+        }
+        Ok(digest.finalize())
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    fn random_data(len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        rand::rng().fill_bytes(&mut data);
+        data
+    }
+
+    #[tokio::test]
+    async fn write_and_verify() {
+        let dir = tempdir::TempDir::new("block_device").unwrap();
+        let path = dir.path().join("device");
+        // an unaligned tail exercises the switch away from O_DIRECT
+        let data = random_data(3 * MAX_CHUNK + MAX_CHUNK / 2 + 123);
+        std::fs::write(&path, vec![0xaa; data.len() + 1000]).unwrap();
+
+        let (progress, receiver) = watch::channel(0u64);
+        let (throughput, _) = watch::channel(0u64);
+        let cancel = CancellationToken::new();
+        let written = write_block_device(&data[..], &path, &progress, &throughput, &cancel)
+            .await
+            .unwrap();
+
+        let crc = Crc::<u64>::new(&CRC_64_REDIS).checksum(&data);
+        assert_eq!(
+            written,
+            Written {
+                bytes: data.len() as u64,
+                crc
+            }
+        );
+        assert_eq!(*receiver.borrow(), data.len() as u64);
+        assert_eq!(std::fs::read(&path).unwrap()[..data.len()], data[..]);
+
+        let verified = checksum_block_device(&path, written.bytes, &progress, &cancel)
+            .await
+            .unwrap();
+        assert_eq!(verified, crc);
+    }
+
+    #[tokio::test]
+    async fn cancelled_write() {
+        let dir = tempdir::TempDir::new("block_device").unwrap();
+        let path = dir.path().join("device");
+        std::fs::write(&path, []).unwrap();
+
+        let (progress, _) = watch::channel(0u64);
+        let (throughput, _) = watch::channel(0u64);
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let result =
+            write_block_device(tokio::io::repeat(0), &path, &progress, &throughput, &cancel).await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Interrupted);
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Tunes the chunk size and queue depth of the block device writer to the
+//! device at hand. eMMC parts differ a lot in the write size at which they
+//! peak, and some stall for long periods while they collect garbage.
+//!
+//! The tuner measures the throughput of the device over windows of
+//! [`WINDOW`] bytes. It first doubles the chunk size as long as that pays
+//! off, then grows the queue depth the same way. A step that does not pay off
+//! is undone. Once settled, writes that exceed [`LATENCY_LIMIT`] shrink the
+//! queue again, so that the device is not fed more than it can digest.
+use std::time::Duration;
+
+pub const MIN_CHUNK: usize = 128 * 1024;
+pub const MAX_CHUNK: usize = 2 * 1024 * 1024;
+const INITIAL_CHUNK: usize = 512 * 1024;
+pub const MIN_DEPTH: usize = 2;
+pub const MAX_DEPTH: usize = 6;
+const WINDOW: u64 = 16 * 1024 * 1024;
+const LATENCY_LIMIT: Duration = Duration::from_millis(400);
+/// relative gain that justifies a larger chunk or a deeper queue
+const GAIN: f64 = 1.05;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Chunk,
+    Depth,
+    Settled,
+}
+
+#[derive(Debug)]
+pub struct WriteTuner {
+    chunk_size: usize,
+    depth: usize,
+    phase: Phase,
+    /// throughput before the last step, in bytes per second
+    baseline: Option<f64>,
+    throughput: f64,
+    window_bytes: u64,
+    window_time: Duration,
+    window_max_latency: Duration,
+}
+
+impl WriteTuner {
+    pub fn new() -> Self {
+        Self {
+            chunk_size: INITIAL_CHUNK,
+            depth: MIN_DEPTH,
+            phase: Phase::Chunk,
+            baseline: None,
+            throughput: 0.0,
+            window_bytes: 0,
+            window_time: Duration::ZERO,
+            window_max_latency: Duration::ZERO,
+        }
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Throughput of the device over the last window, in bytes per second.
+    pub fn throughput(&self) -> u64 {
+        self.throughput as u64
+    }
+
+    /// Records a write of `bytes` that took `latency`.
+    pub fn record(&mut self, bytes: usize, latency: Duration) {
+        self.window_bytes += bytes as u64;
+        self.window_time += latency;
+        self.window_max_latency = self.window_max_latency.max(latency);
+        if self.window_bytes < WINDOW {
+            return;
+        }
+
+        self.throughput = self.window_bytes as f64 / self.window_time.as_secs_f64().max(1e-6);
+        let saturated = self.window_max_latency > LATENCY_LIMIT;
+        self.window_bytes = 0;
+        self.window_time = Duration::ZERO;
+        self.window_max_latency = Duration::ZERO;
+        self.adapt(saturated);
+    }
+
+    fn adapt(&mut self, saturated: bool) {
+        let improved = !saturated && self.baseline.map_or(true, |b| self.throughput > b * GAIN);
+        match self.phase {
+            Phase::Chunk if improved && self.chunk_size < MAX_CHUNK => {
+                self.baseline = Some(self.throughput);
+                self.chunk_size *= 2;
+            }
+            Phase::Chunk => {
+                if improved {
+                    self.baseline = Some(self.throughput);
+                } else {
+                    self.chunk_size = (self.chunk_size / 2).max(MIN_CHUNK);
+                }
+                // the baseline belongs to the chunk size that is kept
+                self.phase = Phase::Depth;
+                self.depth += 1;
+            }
+            Phase::Depth if improved && self.depth < MAX_DEPTH => {
+                self.baseline = Some(self.throughput);
+                self.depth += 1;
+            }
+            Phase::Depth => {
+                if !improved {
+                    self.depth -= 1;
+                }
+                self.phase = Phase::Settled;
+                tracing::debug!(
+                    "writer settled at {} KiB chunks, queue depth {}",
+                    self.chunk_size / 1024,
+                    self.depth
+                );
+            }
+            Phase::Settled => {
+                if saturated && self.depth > MIN_DEPTH {
+                    self.depth -= 1;
+                    tracing::debug!("device saturated, queue depth {}", self.depth);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `tuner` with writes to a device that needs `overhead` per write
+    /// and then moves `bandwidth` bytes per second.
+    fn simulate(tuner: &mut WriteTuner, overhead: Duration, bandwidth: f64, bytes: u64) {
+        let mut written = 0;
+        while written < bytes {
+            let batch = tuner.chunk_size() * tuner.depth();
+            let latency = overhead + Duration::from_secs_f64(batch as f64 / bandwidth);
+            tuner.record(batch, latency);
+            written += batch as u64;
+        }
+    }
+
+    #[test]
+    fn grows_when_writes_are_expensive() {
+        let mut tuner = WriteTuner::new();
+        simulate(&mut tuner, Duration::from_millis(20), 40e6, 1 << 30);
+        assert_eq!(tuner.chunk_size(), MAX_CHUNK);
+        assert!(tuner.depth() > MIN_DEPTH);
+        assert!(tuner.throughput() > 30_000_000);
+    }
+
+    #[test]
+    fn stays_small_when_size_does_not_matter() {
+        let mut tuner = WriteTuner::new();
+        simulate(&mut tuner, Duration::ZERO, 40e6, 1 << 30);
+        assert_eq!(tuner.chunk_size(), INITIAL_CHUNK);
+        assert_eq!(tuner.depth(), MIN_DEPTH);
+    }
+
+    #[test]
+    fn backs_off_when_saturated() {
+        let mut tuner = WriteTuner::new();
+        simulate(&mut tuner, Duration::from_millis(20), 40e6, 1 << 30);
+        let depth = tuner.depth();
+        simulate(&mut tuner, Duration::from_millis(500), 40e6, WINDOW);
+        assert_eq!(tuner.depth(), depth - 1);
+    }
+}
@@ -11,58 +11,120 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use crc::{Crc, Digest as CrcDigest};
-use futures::Stream;
+use bytes::{Bytes, BytesMut};
+use crc::{Crc, Digest as CrcDigest, CRC_32_ISO_HDLC};
+use futures::{ready, Stream};
 use sha2::{Digest, Sha256};
-use std::{io, pin::Pin, task::Poll};
-use tokio::{io::AsyncWrite, sync::watch};
+use std::sync::{Arc, OnceLock};
+use std::{fmt, io, pin::Pin, task::Poll};
+use tokio::{
+    io::AsyncWrite,
+    sync::{mpsc, watch},
+};
+use tokio_stream::wrappers::ReceiverStream;
 
-pub struct Sha256StreamValidator<T>
+static CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// Checksum that a client expects the transferred data to have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Checksum {
+    Sha256(Bytes),
+    /// CRC-32 as computed by zlib and the `crc32` tool
+    Crc32(u32),
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Checksum::Sha256(sha256) => write!(f, "sha256 {}", hex::encode(sha256)),
+            Checksum::Crc32(crc32) => write!(f, "crc32 {:08x}", crc32),
+        }
+    }
+}
+
+/// SHA-256 of transferred data, available once all data was received.
+#[derive(Debug, Clone, Default)]
+pub struct TransferDigest(Arc<OnceLock<Bytes>>);
+
+impl TransferDigest {
+    pub fn sha256(&self) -> Option<String> {
+        self.0.get().map(hex::encode)
+    }
+}
+
+/// Passes the data of a stream on while computing its digests. The stream
+/// fails as soon as it is certain that the data does not match the expected
+/// [`Checksum`] or size: when more data arrives than announced, when the
+/// stream ends early, or when the last byte arrived and the checksum differs.
+/// In the last case, the final chunk is withheld.
+pub struct ChecksumValidator<T>
 where
     T: Stream<Item = io::Result<bytes::Bytes>>,
 {
-    hasher: Option<Sha256>,
-    expected_sha: bytes::Bytes,
     stream: T,
+    expected: Option<Checksum>,
+    size: Option<u64>,
+    received: u64,
+    sha256: Sha256,
+    crc32: CrcDigest<'static, u32>,
+    digest: TransferDigest,
+    verified: bool,
 }
 
-impl<T> Sha256StreamValidator<T>
+impl<T> ChecksumValidator<T>
 where
     T: Stream<Item = io::Result<bytes::Bytes>>,
 {
-    pub fn new(stream: T, expected_sha: bytes::Bytes) -> Self {
+    pub fn new(stream: T, expected: Option<Checksum>, size: Option<u64>) -> Self {
+        if let Some(expected) = &expected {
+            tracing::info!("checksum validation enabled, expects {}", expected);
+        }
         Self {
             stream,
-            expected_sha,
-            hasher: Some(Sha256::new()),
+            expected,
+            size,
+            received: 0,
+            sha256: Sha256::new(),
+            crc32: CRC32.digest(),
+            digest: TransferDigest::default(),
+            verified: false,
         }
     }
 
-    pub fn verify_hash(&mut self) -> io::Result<()> {
-        let sha256 = self
-            .hasher
-            .replace(Sha256::new())
-            .expect("hasher cannot be None")
-            .finalize()
-            .to_vec();
-
-        if sha256 != self.expected_sha {
-            let got = hex::encode(sha256);
-            let expected = hex::encode(&self.expected_sha);
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "sha256 checksum failed. Expected: {}, got: {}",
-                    expected, got
-                ),
-            ));
+    pub fn digest(&self) -> TransferDigest {
+        self.digest.clone()
+    }
+
+    fn verify(&mut self) -> io::Result<()> {
+        self.verified = true;
+        if let Some(size) = self.size.filter(|size| *size != self.received) {
+            return Err(invalid_data(format!(
+                "transfer ended after {} of {} bytes",
+                self.received, size
+            )));
         }
 
-        Ok(())
+        let sha256 = Bytes::from(self.sha256.finalize_reset().to_vec());
+        let crc32 = std::mem::replace(&mut self.crc32, CRC32.digest()).finalize();
+        let _ = self.digest.0.set(sha256.clone());
+        let computed = match &self.expected {
+            Some(Checksum::Sha256(expected)) if *expected != sha256 => Checksum::Sha256(sha256),
+            Some(Checksum::Crc32(expected)) if *expected != crc32 => Checksum::Crc32(crc32),
+            _ => return Ok(()),
+        };
+        Err(invalid_data(format!(
+            "checksum failed. Expected: {}, got: {}",
+            self.expected.as_ref().expect("checked above"),
+            computed
+        )))
     }
 }
 
-impl<T> Stream for Sha256StreamValidator<T>
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl<T> Stream for ChecksumValidator<T>
 where
     T: Stream<Item = io::Result<bytes::Bytes>> + Unpin,
 {
@@ -73,28 +135,28 @@ where
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
         let me = Pin::get_mut(self);
-        let result = Pin::new(&mut me.stream).poll_next(cx);
-
-        match result {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(Some(Ok(bytes))) if !bytes.is_empty() => {
-                me.hasher
-                    .as_mut()
-                    .expect("hasher can never be None")
-                    .update(&bytes);
-                Poll::Ready(Some(Ok(bytes)))
-            }
-            Poll::Ready(Some(Err(_))) => result,
-            _ => {
-                // stream is exhausted. This means one of the following:
-                // * `Poll::Ready(Some(bytes))` was empty
-                // * `Poll::Ready(None)` end of stream signal.
-                if let Err(e) = me.verify_hash() {
-                    Poll::Ready(Some(Err(e)))
-                } else {
-                    Poll::Ready(None)
+        match ready!(Pin::new(&mut me.stream).poll_next(cx)) {
+            Some(Ok(bytes)) => {
+                me.received += bytes.len() as u64;
+                if let Some(size) = me.size.filter(|size| me.received > *size) {
+                    return Poll::Ready(Some(Err(invalid_data(format!(
+                        "received more than the announced {} bytes",
+                        size
+                    )))));
+                }
+                me.sha256.update(&bytes);
+                me.crc32.update(&bytes);
+                // with the last byte, the outcome is certain
+                if !me.verified && me.size == Some(me.received) {
+                    if let Err(e) = me.verify() {
+                        return Poll::Ready(Some(Err(e)));
+                    }
                 }
+                Poll::Ready(Some(Ok(bytes)))
             }
+            Some(Err(e)) => Poll::Ready(Some(Err(e))),
+            None if me.verified => Poll::Ready(None),
+            None => Poll::Ready(me.verify().err().map(Err)),
         }
     }
 }
@@ -122,12 +184,13 @@ where
         }
     }
 
+    #[cfg(test)]
     pub fn crc(self) -> u64 {
         self.digest.finalize()
     }
 }
 
-impl<'_, W> AsyncWrite for WriteMonitor<'_, W>
+impl<W> AsyncWrite for WriteMonitor<'_, W>
 where
     W: AsyncWrite + Unpin,
 {
@@ -164,6 +227,70 @@ where
     }
 }
 
+/// Blocking [`io::Write`] into a bounded channel, for producers such as the
+/// `tar` crate that run on a blocking thread while their output is streamed
+/// to a client. Writes are collected into chunks of `chunk_size` and block
+/// while `depth` chunks are waiting, so the producer never runs more than
+/// `chunk_size * depth` bytes ahead of a slow consumer.
+pub struct ChannelWriter {
+    sender: Option<mpsc::Sender<io::Result<Bytes>>>,
+    buffer: BytesMut,
+    chunk_size: usize,
+}
+
+impl ChannelWriter {
+    pub fn new(chunk_size: usize, depth: usize) -> (Self, ReceiverStream<io::Result<Bytes>>) {
+        let (sender, receiver) = mpsc::channel(depth);
+        let writer = ChannelWriter {
+            sender: Some(sender),
+            buffer: BytesMut::with_capacity(chunk_size),
+            chunk_size,
+        };
+        (writer, ReceiverStream::new(receiver))
+    }
+
+    /// Ends the stream with `error` so the consumer does not mistake the
+    /// output for complete. Subsequent writes fail.
+    pub fn fail(&mut self, error: io::Error) {
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.blocking_send(Err(error));
+        }
+    }
+
+    fn send_buffer(&mut self) -> io::Result<()> {
+        let sender = self
+            .sender
+            .as_ref()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        let chunk = self.buffer.split().freeze();
+        self.buffer.reserve(self.chunk_size);
+        sender
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+impl io::Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.sender.is_none() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let len = buf.len().min(self.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == self.chunk_size {
+            self.send_buffer()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.send_buffer()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -212,6 +339,45 @@ mod test {
         assert_eq!(expected_crc, writer.crc());
     }
 
+    #[tokio::test]
+    async fn channel_writer_test() {
+        use std::io::Write;
+        use tokio_stream::StreamExt;
+
+        let data = random_array::<{ 64 * 1024 + 7 }>();
+        let (mut writer, mut chunks) = ChannelWriter::new(4096, 2);
+        let expected = data.clone();
+        let producer = tokio::task::spawn_blocking(move || {
+            writer.write_all(&data).unwrap();
+            writer.flush().unwrap();
+            writer.fail(io::ErrorKind::Other.into());
+        });
+
+        let mut received = Vec::new();
+        while let Some(chunk) = chunks.next().await {
+            match chunk {
+                Ok(chunk) => {
+                    assert!(chunk.len() <= 4096);
+                    received.extend_from_slice(&chunk);
+                }
+                Err(e) => {
+                    assert_eq!(e.kind(), io::ErrorKind::Other);
+                    break;
+                }
+            }
+        }
+        producer.await.unwrap();
+        assert_eq!(received, expected);
+
+        // writes fail once the consumer is gone
+        let (mut writer, chunks) = ChannelWriter::new(16, 1);
+        drop(chunks);
+        let result = tokio::task::spawn_blocking(move || writer.write_all(&[0; 64]))
+            .await
+            .unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+
     //   #[tokio::test]
     //   async fn sha256_reader_test() {
     //       let mut buffer = random_array::<{ 1024 * 1024 + 23 }>();
@@ -231,4 +397,58 @@ mod test {
 
     //       assert_eq!(data, buffer);
     //   }
+
+    async fn validate(
+        chunks: &[&'static [u8]],
+        expected: Option<Checksum>,
+        size: Option<u64>,
+    ) -> (Vec<io::Result<Bytes>>, TransferDigest) {
+        use tokio_stream::StreamExt;
+        let stream = tokio_stream::iter(chunks.iter().map(|c| Ok(Bytes::from_static(c))));
+        let validator = ChecksumValidator::new(stream, expected, size);
+        let digest = validator.digest();
+        (validator.collect().await, digest)
+    }
+
+    #[tokio::test]
+    async fn checksum_validation() {
+        let sha256 = Bytes::from(Sha256::digest(b"hello world").to_vec());
+        let (items, digest) = validate(
+            &[b"hello ", b"world"],
+            Some(Checksum::Sha256(sha256.clone())),
+            Some(11),
+        )
+        .await;
+        assert_eq!(items.len(), 2);
+        assert!(items.iter().all(Result::is_ok));
+        assert_eq!(digest.sha256(), Some(hex::encode(&sha256)));
+
+        // the mismatch is reported with the last byte, instead of the last chunk
+        let crc32 = CRC32.checksum(b"hello world");
+        let (items, digest) = validate(
+            &[b"hello ", b"w0rld"],
+            Some(Checksum::Crc32(crc32)),
+            Some(11),
+        )
+        .await;
+        assert!(items[0].is_ok());
+        let error = items[1].as_ref().unwrap_err().to_string();
+        assert!(error.contains(&format!("crc32 {:08x}", crc32)), "{}", error);
+        assert!(digest.sha256().is_some());
+
+        let (items, _) = validate(&[b"hello ", b"world!"], None, Some(11)).await;
+        assert!(items[1]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("more than"));
+
+        let (items, digest) = validate(&[b"hello "], None, Some(11)).await;
+        assert!(items[1]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("6 of 11"));
+        assert_eq!(digest.sha256(), None);
+    }
 }
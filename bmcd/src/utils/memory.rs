@@ -0,0 +1,205 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Accounting of the memory that subsystems hold in buffers on behalf of
+//! clients, so that no single client can grow bmcd until the kernel kills
+//! it. Each [`Pool`] has a cap; a reservation that does not fit is refused
+//! and the subsystem sheds the work instead, by skipping console output,
+//! dropping a notification or writing a flash image with fewer buffers.
+use crate::config::MemoryLimits;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pool {
+    /// console output queued for websocket clients
+    Console,
+    /// notifications that are being delivered
+    Events,
+    /// buffers of the block device writer
+    FlashStaging,
+}
+
+impl Pool {
+    pub const ALL: [Pool; 3] = [Pool::Console, Pool::Events, Pool::FlashStaging];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Pool::Console => "console",
+            Pool::Events => "events",
+            Pool::FlashStaging => "flash_staging",
+        }
+    }
+}
+
+struct Account {
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    /// 0 means unlimited
+    limit: AtomicUsize,
+    shed: AtomicU64,
+}
+
+impl Account {
+    const fn new() -> Self {
+        Self {
+            used: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            limit: AtomicUsize::new(0),
+            shed: AtomicU64::new(0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolUsage {
+    pub pool: Pool,
+    pub used: usize,
+    pub peak: usize,
+    pub limit: Option<usize>,
+    /// reservations that were refused
+    pub shed: u64,
+}
+
+pub struct MemoryAccounting {
+    accounts: [Account; 3],
+}
+
+static MEMORY: MemoryAccounting = MemoryAccounting::new();
+
+/// Accounting of the daemon. Pools are unlimited until
+/// [`MemoryAccounting::set_limits`] applies the configuration.
+pub fn memory() -> &'static MemoryAccounting {
+    &MEMORY
+}
+
+impl MemoryAccounting {
+    const fn new() -> Self {
+        Self {
+            accounts: [Account::new(), Account::new(), Account::new()],
+        }
+    }
+
+    fn account(&self, pool: Pool) -> &Account {
+        &self.accounts[pool as usize]
+    }
+
+    pub fn set_limits(&self, limits: &MemoryLimits) {
+        for (pool, limit) in [
+            (Pool::Console, limits.console),
+            (Pool::Events, limits.events),
+            (Pool::FlashStaging, limits.flash_staging),
+        ] {
+            self.account(pool).limit.store(limit, Ordering::Relaxed);
+        }
+    }
+
+    /// Reserves `bytes` in `pool`, `None` when the pool has no room for them.
+    /// The bytes are released again when the [`Reservation`] is dropped.
+    pub fn try_reserve(&self, pool: Pool, bytes: usize) -> Option<Reservation<'_>> {
+        let account = self.account(pool);
+        let limit = account.limit.load(Ordering::Relaxed);
+        let reserved = account
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                let total = used.checked_add(bytes)?;
+                (limit == 0 || total <= limit).then_some(total)
+            });
+        match reserved {
+            Ok(used) => {
+                account.peak.fetch_max(used + bytes, Ordering::Relaxed);
+                Some(Reservation {
+                    accounting: self,
+                    pool,
+                    bytes,
+                })
+            }
+            Err(_) => {
+                account.shed.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn usage(&self) -> Vec<PoolUsage> {
+        Pool::ALL
+            .iter()
+            .map(|pool| {
+                let account = self.account(*pool);
+                let limit = account.limit.load(Ordering::Relaxed);
+                PoolUsage {
+                    pool: *pool,
+                    used: account.used.load(Ordering::Relaxed),
+                    peak: account.peak.load(Ordering::Relaxed),
+                    limit: (limit != 0).then_some(limit),
+                    shed: account.shed.load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Bytes held in a pool, released on drop.
+#[derive(Debug)]
+pub struct Reservation<'a> {
+    accounting: &'a MemoryAccounting,
+    pool: Pool,
+    bytes: usize,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.accounting
+            .account(self.pool)
+            .used
+            .fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+impl std::fmt::Debug for MemoryAccounting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.usage()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservations_respect_limits() {
+        let accounting = MemoryAccounting::new();
+        accounting.set_limits(&MemoryLimits {
+            console: 100,
+            events: 0,
+            flash_staging: 10,
+        });
+
+        let first = accounting.try_reserve(Pool::Console, 60).unwrap();
+        assert!(accounting.try_reserve(Pool::Console, 60).is_none());
+        let second = accounting.try_reserve(Pool::Console, 40).unwrap();
+        drop(first);
+        let third = accounting.try_reserve(Pool::Console, 60).unwrap();
+        assert!(accounting.try_reserve(Pool::Events, 1 << 30).is_some());
+
+        let console = &accounting.usage()[0];
+        assert_eq!(console.used, 100);
+        assert_eq!(console.peak, 100);
+        assert_eq!(console.limit, Some(100));
+        assert_eq!(console.shed, 1);
+        drop((second, third));
+        assert_eq!(accounting.usage()[0].used, 0);
+        assert_eq!(accounting.usage()[1].limit, None);
+    }
+}
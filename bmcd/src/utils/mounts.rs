@@ -0,0 +1,110 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Mounted filesystems as listed in `/proc/mounts`, and which block device
+//! they are on.
+use serde::Serialize;
+use std::io;
+
+pub const PROC_MOUNTS: &str = "/proc/mounts";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Mount {
+    /// e.g. `/dev/mmcblk0p1`
+    pub source: String,
+    pub path: String,
+    pub fs_type: String,
+    pub read_only: bool,
+}
+
+pub fn read_mounts() -> io::Result<Vec<Mount>> {
+    Ok(parse_mounts(&std::fs::read_to_string(PROC_MOUNTS)?))
+}
+
+pub fn parse_mounts(contents: &str) -> Vec<Mount> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let source = fields.next()?;
+            let path = fields.next()?;
+            let fs_type = fields.next()?;
+            let options = fields.next()?;
+            Some(Mount {
+                source: unescape(source),
+                path: unescape(path),
+                fs_type: fs_type.to_string(),
+                read_only: options.split(',').any(|o| o == "ro"),
+            })
+        })
+        .collect()
+}
+
+/// The kernel escapes space, tab, newline and backslash as octal.
+fn unescape(field: &str) -> String {
+    field
+        .replace("\\040", " ")
+        .replace("\\011", "\t")
+        .replace("\\012", "\n")
+        .replace("\\134", "\\")
+}
+
+/// Whether `source` is the block device `device` or one of its partitions,
+/// e.g. `/dev/mmcblk0p1` of `/dev/mmcblk0` or `/dev/sda1` of `/dev/sda`.
+pub fn is_on_device(source: &str, device: &str) -> bool {
+    let Some(partition) = source.strip_prefix(device) else {
+        return false;
+    };
+    // devices whose name ends in a digit separate the partition with a `p`
+    let number = if device.ends_with(|c: char| c.is_ascii_digit()) {
+        match partition.strip_prefix('p') {
+            Some(number) => number,
+            None => return partition.is_empty(),
+        }
+    } else {
+        partition
+    };
+    partition.is_empty() || (!number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mounts_on_devices() {
+        let mounts = parse_mounts(
+            "ubi0:rootfs / ubifs rw,relatime 0 0\n\
+             /dev/mmcblk0p1 /mnt/my\\040card vfat ro,relatime 0 0\n\
+             /dev/sda1 /mnt/usb ext4 rw 0 0\n",
+        );
+        assert_eq!(mounts.len(), 3);
+        assert_eq!(
+            mounts[1],
+            Mount {
+                source: "/dev/mmcblk0p1".into(),
+                path: "/mnt/my card".into(),
+                fs_type: "vfat".into(),
+                read_only: true,
+            }
+        );
+
+        assert!(is_on_device("/dev/mmcblk0p1", "/dev/mmcblk0"));
+        assert!(is_on_device("/dev/mmcblk0", "/dev/mmcblk0"));
+        assert!(!is_on_device("/dev/mmcblk0boot0", "/dev/mmcblk0"));
+        assert!(!is_on_device("/dev/mmcblk10p1", "/dev/mmcblk1"));
+        assert!(is_on_device("/dev/sda12", "/dev/sda"));
+        assert!(!is_on_device("/dev/sdab", "/dev/sda"));
+        assert!(!is_on_device("/dev/mmcblk0p", "/dev/mmcblk0"));
+    }
+}
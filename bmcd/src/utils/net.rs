@@ -0,0 +1,318 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Addressing shared by the network services. Services bind dual-stack
+//! sockets, so they serve IPv4 and IPv6 clients on one socket; IPv4 clients
+//! then show up with IPv4-mapped addresses. Link-local IPv6 addresses, as used
+//! on the node network, are only meaningful together with their interface,
+//! which is written as zone: `fe80::1%br0`.
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt::{self, Display};
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::str::FromStr;
+
+/// An IP address with an optional zone, parsed from and displayed as
+/// `192.168.1.2`, `fd00::2` or `fe80::1%br0`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, DeserializeFromStr, SerializeDisplay)]
+pub struct ScopedIp {
+    pub ip: IpAddr,
+    /// interface name or index of a link-local IPv6 address
+    pub zone: Option<String>,
+}
+
+impl ScopedIp {
+    /// Socket address on `port`, with the zone resolved to the index of its
+    /// interface.
+    pub fn socket_addr(&self, port: u16) -> io::Result<SocketAddr> {
+        match (self.ip, &self.zone) {
+            (IpAddr::V6(ip), Some(zone)) => Ok(SocketAddr::V6(SocketAddrV6::new(
+                ip,
+                port,
+                0,
+                interface_index(zone)?,
+            ))),
+            (ip, _) => Ok(SocketAddr::new(ip, port)),
+        }
+    }
+}
+
+impl From<IpAddr> for ScopedIp {
+    fn from(ip: IpAddr) -> Self {
+        Self { ip, zone: None }
+    }
+}
+
+impl FromStr for ScopedIp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, zone) = match s.split_once('%') {
+            Some((ip, zone)) => (ip, Some(zone)),
+            None => (s, None),
+        };
+        let ip: IpAddr = ip
+            .parse()
+            .map_err(|_| format!("`{}` is not an IP address", s))?;
+        if let Some(zone) = zone {
+            let link_local = matches!(ip, IpAddr::V6(ip) if is_link_local(&ip));
+            if !link_local || zone.is_empty() {
+                return Err(format!(
+                    "`{}`: a zone is only valid for link-local IPv6 addresses",
+                    s
+                ));
+            }
+        }
+        Ok(Self {
+            ip,
+            zone: zone.map(str::to_string),
+        })
+    }
+}
+
+impl Display for ScopedIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.zone {
+            Some(zone) => write!(f, "{}%{}", self.ip, zone),
+            None => write!(f, "{}", self.ip),
+        }
+    }
+}
+
+/// An address or a network in CIDR notation, e.g. `10.0.0.0/24` or
+/// `fd00::/64`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// IPv4-mapped addresses match IPv4 networks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let bits = |ip: IpAddr| match ip.to_canonical() {
+            IpAddr::V4(ip) => (u128::from(u32::from(ip)), 32),
+            IpAddr::V6(ip) => (u128::from(ip), 128),
+        };
+        let (network, width) = bits(self.address);
+        let (ip, ip_width) = bits(ip);
+        let shift = width - u32::from(self.prefix_len);
+        width == ip_width && network.checked_shr(shift) == ip.checked_shr(shift)
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("`{}` is not an address or network", s);
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, len)) => (address, Some(len)),
+            None => (s, None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| invalid())?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max)
+                .ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Self {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+/// The address of a socket as users write it: IPv4-mapped addresses as plain
+/// IPv4 and link-local IPv6 addresses with the name of their interface.
+pub fn scoped_address(peer: &SocketAddr) -> ScopedIp {
+    match peer {
+        SocketAddr::V4(peer) => IpAddr::V4(*peer.ip()).into(),
+        SocketAddr::V6(peer) => scoped(*peer.ip(), peer.scope_id()),
+    }
+}
+
+/// `ip` with the interface `scope_id` as zone when it is link-local.
+pub fn scoped(ip: Ipv6Addr, scope_id: u32) -> ScopedIp {
+    if let Some(ip) = ip.to_ipv4_mapped() {
+        return IpAddr::V4(ip).into();
+    }
+    let zone = (is_link_local(&ip) && scope_id != 0)
+        .then(|| interface_name(scope_id).unwrap_or_else(|| scope_id.to_string()));
+    ScopedIp {
+        ip: IpAddr::V6(ip),
+        zone,
+    }
+}
+
+/// `fe80::/10`, `Ipv6Addr::is_unicast_link_local` requires Rust 1.84.
+pub fn is_link_local(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Index of the interface `zone`, which is an interface name or an index.
+pub fn interface_index(zone: &str) -> io::Result<u32> {
+    if let Ok(index) = zone.parse() {
+        return Ok(index);
+    }
+    if_addrs::get_if_addrs()?
+        .into_iter()
+        .find(|i| i.name == zone)
+        .and_then(|i| i.index)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no interface `{}`", zone)))
+}
+
+pub fn interface_name(index: u32) -> Option<String> {
+    if_addrs::get_if_addrs()
+        .ok()?
+        .into_iter()
+        .find(|i| i.index == Some(index))
+        .map(|i| i.name)
+}
+
+/// UDP socket on `port` of all IPv4 and IPv6 addresses.
+pub fn dual_stack_udp(port: u16) -> io::Result<std::net::UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port).into())?;
+    Ok(socket.into())
+}
+
+/// Networks of the interfaces of the BMC, which it reaches without a router.
+pub fn local_networks() -> io::Result<Vec<IpNetwork>> {
+    Ok(if_addrs::get_if_addrs()?
+        .into_iter()
+        .map(|interface| match interface.addr {
+            if_addrs::IfAddr::V4(addr) => IpNetwork {
+                address: IpAddr::V4(addr.ip),
+                prefix_len: u32::from(addr.netmask).count_ones() as u8,
+            },
+            if_addrs::IfAddr::V6(addr) => IpNetwork {
+                address: IpAddr::V6(addr.ip),
+                prefix_len: u128::from(addr.netmask).count_ones() as u8,
+            },
+        })
+        .collect())
+}
+
+/// TCP listener on `port` of all IPv4 and IPv6 addresses.
+pub fn dual_stack_tcp(port: u16) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port).into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// Unix socket at `path` that only its owner can connect to. The socket is
+/// bound in a private directory and moved to `path` with its final
+/// permissions, so it is never reachable with the permissions of the umask.
+pub fn private_unix_listener(path: &Path) -> io::Result<UnixListener> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a socket path"))?;
+    let staging = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&staging);
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let bind = || {
+        let staged = staging.join(name);
+        let listener = UnixListener::bind(&staged)?;
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    };
+    let result = bind();
+    let _ = std::fs::remove_dir_all(&staging);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scoped_addresses() {
+        let ip: ScopedIp = "fe80::1%br0".parse().unwrap();
+        assert_eq!(ip.zone.as_deref(), Some("br0"));
+        assert_eq!(ip.to_string(), "fe80::1%br0");
+        assert_eq!("fd00::2".parse::<ScopedIp>().unwrap().zone, None);
+        assert!("fd00::2%br0".parse::<ScopedIp>().is_err());
+        assert!("10.0.0.2%br0".parse::<ScopedIp>().is_err());
+        assert!("fe80::1%".parse::<ScopedIp>().is_err());
+
+        let ip = "fe80::1%7".parse::<ScopedIp>().unwrap();
+        let SocketAddr::V6(address) = ip.socket_addr(443).unwrap() else {
+            panic!("not an IPv6 address");
+        };
+        assert_eq!(address.scope_id(), 7);
+    }
+
+    #[test]
+    fn networks() {
+        let network: IpNetwork = "10.0.0.0/24".parse().unwrap();
+        assert!(network.contains("10.0.0.7".parse().unwrap()));
+        assert!(network.contains("::ffff:10.0.0.7".parse().unwrap()));
+        assert!(!network.contains("10.0.1.7".parse().unwrap()));
+        let network: IpNetwork = "fd00::5".parse().unwrap();
+        assert!(network.contains("fd00::5".parse().unwrap()));
+        assert!(!network.contains("fd00::6".parse().unwrap()));
+        let any: IpNetwork = "::/0".parse().unwrap();
+        assert!(any.contains("fd00::6".parse().unwrap()));
+        assert!(!any.contains("10.0.0.1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn peer_addresses() {
+        let mapped: SocketAddr = "[::ffff:10.0.0.2]:1234".parse().unwrap();
+        assert_eq!(scoped_address(&mapped).to_string(), "10.0.0.2");
+        let global: SocketAddr = "[fd00::2]:1234".parse().unwrap();
+        assert_eq!(scoped_address(&global).to_string(), "fd00::2");
+        let link_local = SocketAddr::V6(SocketAddrV6::new(
+            "fe80::2".parse().unwrap(),
+            1234,
+            0,
+            u32::MAX,
+        ));
+        assert_eq!(
+            scoped_address(&link_local).to_string(),
+            format!("fe80::2%{}", u32::MAX)
+        );
+    }
+
+    #[test]
+    fn private_unix_socket() {
+        let dir = tempdir::TempDir::new("net").unwrap();
+        let path = dir.path().join("bmcd.sock");
+        let _listener = private_unix_listener(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(std::os::unix::net::UnixStream::connect(&path).is_ok());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
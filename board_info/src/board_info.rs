@@ -12,7 +12,7 @@ use std::{
     path::PathBuf,
 };
 
-const BOARDINFO_SIZE: usize = 50;
+pub const BOARDINFO_SIZE: usize = 50;
 const HEADER_VER: u16 = 1u16;
 
 pub struct BoardInfo {
@@ -85,7 +85,7 @@ impl BoardInfo {
         })
     }
 
-    fn find_i2c_device() -> io::Result<PathBuf> {
+    pub fn find_i2c_device() -> io::Result<PathBuf> {
         for entry in fs::read_dir("/sys/bus/i2c/devices/")? {
             let eeprom = entry?.path().join("eeprom");
             if eeprom.exists() {
@@ -132,6 +132,22 @@ impl BoardInfo {
         let mut file = OpenOptions::new().write(true).truncate(true).open(eeprom)?;
         file.seek(io::SeekFrom::Start(0))?;
 
+        let bytes = self.to_bytes();
+        println!(
+            "writing to eeprom:\n{:#?}",
+            BoardInfo::from_bytes(bytes.clone())
+        );
+
+        // workaround for buggy i2c bus
+        for byte in bytes {
+            file.write_all(&[byte])?;
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+
+    /// Serializes the board info and updates its checksum.
+    pub fn to_bytes(&mut self) -> BytesMut {
         let mut bytes = BytesMut::with_capacity(BOARDINFO_SIZE);
         bytes.put_u16(self._reserved);
         bytes.put_u32(self.crc32);
@@ -148,18 +164,18 @@ impl BoardInfo {
         hasher.update(&bytes[6..]);
         self.crc32 = hasher.finalize();
         BigEndian::write_u32(&mut bytes.as_mut()[2..6], self.crc32);
+        bytes
+    }
 
-        println!(
-            "writing to eeprom:\n{:#?}",
-            BoardInfo::from_bytes(bytes.clone())
-        );
-
-        // workaround for buggy i2c bus
-        for byte in bytes {
-            file.write_all(&[byte])?;
-            std::thread::sleep(Duration::from_millis(10));
+    /// Returns true when `bytes`, as read from the EEPROM, carry a valid
+    /// checksum.
+    pub fn checksum_valid(bytes: &[u8]) -> bool {
+        if bytes.len() < BOARDINFO_SIZE {
+            return false;
         }
-        Ok(())
+        let mut hasher = Hasher::new();
+        hasher.update(&bytes[6..BOARDINFO_SIZE]);
+        BigEndian::read_u32(&bytes[2..6]) == hasher.finalize()
     }
 
     pub fn value_of(&self, attribute: &BoardInfoAttribute) -> String {